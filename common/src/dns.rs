@@ -0,0 +1,450 @@
+//! Mikołaj Depta 328690
+//!
+//! Minimal DNS client resolving host names to addresses with A and AAAA queries over UDP.
+//!
+//! Standard library resolves names with the blocking `getaddrinfo`, which can stall the single
+//! threaded event loops for as long as the system configuration allows. `Resolver` asks the name
+//! servers from `/etc/resolv.conf` itself, so the time spent waiting is bounded by its timeout
+//! and number of attempts. Names listed in `/etc/hosts` are answered without any query.
+//!
+//! Only what the binaries need is supported: no search domains, no TCP fallback for truncated
+//! answers and no caching.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum ResolveError {
+    /// Host name cannot be put in a query.
+    InvalidName(String),
+    /// Name server answered that the name does not exist.
+    NotFound(String),
+    /// Name server answered with an error code other than `NXDOMAIN`.
+    ServerFailure(u8),
+    /// None of the name servers answered in time.
+    TimedOut,
+    Io(io::Error),
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidName(name) => write!(f, "invalid host name: {name}"),
+            Self::NotFound(name) => write!(f, "host not found: {name}"),
+            Self::ServerFailure(code) => write!(f, "name server failed with response code {code}"),
+            Self::TimedOut => write!(f, "name servers did not answer in time"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<io::Error> for ResolveError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ResolveError> for io::Error {
+    fn from(err: ResolveError) -> Self {
+        let kind = match &err {
+            ResolveError::InvalidName(_) => io::ErrorKind::InvalidInput,
+            ResolveError::NotFound(_) => io::ErrorKind::NotFound,
+            ResolveError::ServerFailure(_) => io::ErrorKind::Other,
+            ResolveError::TimedOut => io::ErrorKind::TimedOut,
+            ResolveError::Io(err) => return io::Error::new(err.kind(), err.to_string()),
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Type of the address records asked for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            Self::A => 1,
+            Self::Aaaa => 28,
+        }
+    }
+}
+
+// region Messages
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const RCODE_NAME_ERROR: u8 = 3;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 253;
+/// Longest answer accepted over UDP without EDNS.
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// Single question query asking for records of `record_type` for `name`, with recursion desired.
+pub fn encode_query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, ResolveError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let invalid = || ResolveError::InvalidName(name.to_owned());
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(invalid());
+    }
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    for field in [id, FLAG_RECURSION_DESIRED, 1, 0, 0, 0] {
+        query.extend(field.to_be_bytes());
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(invalid());
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(record_type.code().to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Addresses of `record_type` in the answer to the query with `id`, `None` if the message
+/// is not such an answer or is malformed. Records of other types, eg. `CNAME`s leading to the
+/// addresses, are skipped.
+pub fn decode_response(id: u16, name: &str, record_type: RecordType, message: &[u8]) -> Option<Result<Vec<IpAddr>, ResolveError>> {
+    let field = |offset: usize| Some(u16::from_be_bytes(message.get(offset..offset + 2)?.try_into().ok()?));
+    let flags = field(2)?;
+    if field(0)? != id || flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    match (flags & 0xf) as u8 {
+        0 => {}
+        RCODE_NAME_ERROR => return Some(Err(ResolveError::NotFound(name.to_owned()))),
+        code => return Some(Err(ResolveError::ServerFailure(code))),
+    }
+    let (questions, answers) = (field(4)?, field(6)?);
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let (kind, class, length) = (field(offset)?, field(offset + 2)?, field(offset + 8)? as usize);
+        let data = message.get(offset + 10..offset + 10 + length)?;
+        offset += 10 + length;
+        if class != CLASS_IN || kind != record_type.code() {
+            continue;
+        }
+        let address = match record_type {
+            RecordType::A => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?)),
+            RecordType::Aaaa => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?)),
+        };
+        addresses.push(address);
+    }
+    Some(Ok(addresses))
+}
+
+/// Offset just past the name starting at `offset`, which may end with a compression pointer.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(offset)? as usize;
+        match length {
+            0 => return Some(offset + 1),
+            _ if length & 0xc0 == 0xc0 => return Some(offset + 2),
+            _ => offset += 1 + length,
+        }
+    }
+}
+// endregion
+
+/// Resolves host names using the name servers from the resolver configuration.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    servers: Vec<SocketAddr>,
+    /// Time to wait for an answer from a single server.
+    timeout: Duration,
+    /// Number of rounds over all the servers.
+    attempts: usize,
+    hosts: Vec<(String, IpAddr)>,
+}
+
+impl Resolver {
+    pub const RESOLV_CONF_PATH: &'static str = "/etc/resolv.conf";
+    pub const HOSTS_PATH: &'static str = "/etc/hosts";
+    const DNS_PORT: u16 = 53;
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+    const DEFAULT_ATTEMPTS: usize = 2;
+    const MAX_ATTEMPTS: usize = 5;
+
+    pub fn new(servers: Vec<SocketAddr>) -> Self {
+        Self { servers, timeout: Self::DEFAULT_TIMEOUT, attempts: Self::DEFAULT_ATTEMPTS, hosts: Vec::new() }
+    }
+
+    /// Resolver configured like the system one, from `/etc/resolv.conf` and `/etc/hosts`.
+    /// Missing files leave the defaults - name server on the local host and no static entries.
+    pub fn from_system() -> Self {
+        let resolv_conf = fs::read_to_string(Self::RESOLV_CONF_PATH).unwrap_or_default();
+        let hosts = fs::read_to_string(Self::HOSTS_PATH).unwrap_or_default();
+        Self::from_resolv_conf(&resolv_conf).with_hosts(&hosts)
+    }
+
+    /// Resolver using the `nameserver` lines and the `timeout:n` and `attempts:n` options,
+    /// other lines are ignored. Without name servers the local host is asked, like glibc does.
+    pub fn from_resolv_conf(contents: &str) -> Self {
+        let mut resolver = Self::new(Vec::new());
+        for line in contents.lines().map(|line| line.split(['#', ';']).next().unwrap_or_default()) {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    if let Some(address) = words.next().and_then(|address| address.parse::<IpAddr>().ok()) {
+                        resolver.servers.push(SocketAddr::new(address, Self::DNS_PORT));
+                    }
+                }
+                Some("options") => {
+                    for option in words {
+                        match option.split_once(':') {
+                            Some(("timeout", seconds)) => if let Ok(seconds @ 1..) = seconds.parse() {
+                                resolver.timeout = Duration::from_secs(seconds);
+                            },
+                            Some(("attempts", attempts)) => if let Ok(attempts @ 1..) = attempts.parse::<usize>() {
+                                resolver.attempts = attempts.min(Self::MAX_ATTEMPTS);
+                            },
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if resolver.servers.is_empty() {
+            resolver.servers.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), Self::DNS_PORT));
+        }
+        resolver
+    }
+
+    /// Names from `contents` in the hosts file format are resolved without asking the servers.
+    pub fn with_hosts(mut self, contents: &str) -> Self {
+        for line in contents.lines().map(|line| line.split('#').next().unwrap_or_default()) {
+            let mut words = line.split_whitespace();
+            if let Some(Ok(address)) = words.next().map(str::parse::<IpAddr>) {
+                self.hosts.extend(words.map(|name| (name.to_ascii_lowercase(), address)));
+            }
+        }
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    /// IPv4 addresses of `host` followed by the IPv6 ones.
+    /// Address literals are returned as they are, IPv6 ones may be written in brackets.
+    pub fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        self.lookup(host, &[RecordType::A, RecordType::Aaaa])
+    }
+
+    /// IPv4 addresses of `host`.
+    pub fn resolve_ipv4(&self, host: &str) -> Result<Vec<Ipv4Addr>, ResolveError> {
+        let addresses = self.lookup(host, &[RecordType::A])?;
+        Ok(addresses.into_iter().filter_map(|address| match address {
+            IpAddr::V4(address) => Some(address),
+            IpAddr::V6(_) => None,
+        }).collect())
+    }
+
+    fn lookup(&self, host: &str, record_types: &[RecordType]) -> Result<Vec<IpAddr>, ResolveError> {
+        let literal = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        if let Ok(address) = literal.parse::<IpAddr>() {
+            return Ok(vec![address]);
+        }
+        let name = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+        let wanted = |address: &IpAddr| record_types.contains(&if address.is_ipv4() { RecordType::A } else { RecordType::Aaaa });
+        let static_addresses = self.hosts.iter()
+            .filter(|(entry, address)| *entry == name && wanted(address))
+            .map(|(_, address)| *address)
+            .collect::<Vec<_>>();
+        if !static_addresses.is_empty() {
+            return Ok(static_addresses);
+        }
+        let mut addresses = Vec::new();
+        let mut not_found = None;
+        for record_type in record_types {
+            match self.query(&name, *record_type) {
+                Ok(found) => addresses.extend(found),
+                Err(err @ ResolveError::NotFound(_)) => not_found = Some(err),
+                Err(err) => return Err(err),
+            }
+        }
+        match (addresses.is_empty(), not_found) {
+            (true, Some(err)) => Err(err),
+            (true, None) => Err(ResolveError::NotFound(name)),
+            (false, _) => Ok(addresses),
+        }
+    }
+
+    /// Asks the servers in turn until one answers, for at most `attempts` rounds.
+    fn query(&self, name: &str, record_type: RecordType) -> Result<Vec<IpAddr>, ResolveError> {
+        let id = next_query_id();
+        let query = encode_query(id, name, record_type)?;
+        let mut last_error = ResolveError::TimedOut;
+        for _ in 0..self.attempts {
+            for server in &self.servers {
+                match self.ask(*server, id, name, record_type, &query) {
+                    Ok(Some(answer)) => return answer,
+                    Ok(None) => last_error = ResolveError::TimedOut,
+                    Err(err) => last_error = ResolveError::Io(err),
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Answer of the `server`, `None` if it did not come in time.
+    /// Datagrams other than the answer to the query are ignored.
+    fn ask(&self, server: SocketAddr, id: u16, name: &str, record_type: RecordType, query: &[u8]) -> io::Result<Option<Result<Vec<IpAddr>, ResolveError>>> {
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        socket.send(query)?;
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = [0; MAX_MESSAGE_LEN];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            match socket.recv(&mut buffer) {
+                Ok(length) => if let Some(answer) = decode_response(id, name, record_type, &buffer[..length]) {
+                    return Ok(Some(answer));
+                },
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+                /* server port unreachable, try the next one */
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => return Err(err),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Query identifiers are sequential from a time based start, enough to tell stale answers apart.
+fn next_query_id() -> u16 {
+    static NEXT_ID: OnceLock<AtomicU16> = OnceLock::new();
+    NEXT_ID
+        .get_or_init(|| AtomicU16::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos() as u16))
+        .fetch_add(1, Ordering::Relaxed)
+}
+
+/// Resolver configured from the system files, read once on the first use.
+pub fn system() -> &'static Resolver {
+    static SYSTEM: OnceLock<Resolver> = OnceLock::new();
+    SYSTEM.get_or_init(Resolver::from_system)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Answer to `query` with the `addresses` as A records, name given by a compression pointer.
+    fn answer(query: &[u8], addresses: &[Ipv4Addr]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] |= 0x80;
+        response[6..8].copy_from_slice(&(addresses.len() as u16).to_be_bytes());
+        /* CNAME record which should be skipped */
+        response[7] += 1;
+        response.extend([0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        for address in addresses {
+            response.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+            response.extend(address.octets());
+        }
+        response
+    }
+
+    #[test]
+    fn test_query_encoding() {
+        let query = encode_query(0x1234, "example.com.", RecordType::Aaaa).unwrap();
+        assert_eq!(query, [
+            0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0, 28, 0, 1,
+        ]);
+        for name in ["", "a..b", &"a".repeat(64)] {
+            assert!(matches!(encode_query(1, name, RecordType::A), Err(ResolveError::InvalidName(_))), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_response_decoding() {
+        let query = encode_query(7, "example.com", RecordType::A).unwrap();
+        let response = answer(&query, &[Ipv4Addr::new(93, 184, 216, 34), Ipv4Addr::new(10, 0, 0, 1)]);
+        let addresses = decode_response(7, "example.com", RecordType::A, &response).unwrap().unwrap();
+        assert_eq!(addresses, ["93.184.216.34".parse::<IpAddr>().unwrap(), "10.0.0.1".parse().unwrap()]);
+        assert!(decode_response(8, "example.com", RecordType::A, &response).is_none());
+        assert!(decode_response(7, "example.com", RecordType::A, &query).is_none());
+        assert!(decode_response(7, "example.com", RecordType::A, &response[..response.len() - 1]).is_none());
+        let mut not_found = query.clone();
+        not_found[2] |= 0x80;
+        not_found[3] |= RCODE_NAME_ERROR;
+        assert!(matches!(decode_response(7, "example.com", RecordType::A, &not_found), Some(Err(ResolveError::NotFound(_)))));
+    }
+
+    #[test]
+    fn test_configuration_files() {
+        let resolver = Resolver::from_resolv_conf(
+            "# comment\nsearch lan\nnameserver 10.0.0.1\nnameserver ::1 ; local\nnameserver bogus\noptions ndots:2 timeout:1 attempts:9\n",
+        );
+        assert_eq!(resolver.servers(), ["10.0.0.1:53".parse().unwrap(), "[::1]:53".parse().unwrap()]);
+        assert_eq!((resolver.timeout, resolver.attempts), (Duration::from_secs(1), Resolver::MAX_ATTEMPTS));
+        assert_eq!(Resolver::from_resolv_conf("").servers(), ["127.0.0.1:53".parse().unwrap()]);
+
+        let resolver = resolver.with_hosts("127.0.0.1 localhost # loopback\n::1 localhost ip6-localhost\n10.1.1.1 Printer.lan\n");
+        assert_eq!(resolver.resolve("LOCALHOST").unwrap(), ["127.0.0.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+        assert_eq!(resolver.resolve_ipv4("printer.lan.").unwrap(), [Ipv4Addr::new(10, 1, 1, 1)]);
+        assert_eq!(resolver.resolve("[::2]").unwrap(), ["::2".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_servers_are_retried() {
+        /* first server never answers, second one answers only the second query it gets */
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let servers = vec![silent.local_addr().unwrap(), server.local_addr().unwrap()];
+        let answering = thread::spawn(move || {
+            let mut buffer = [0; MAX_MESSAGE_LEN];
+            server.recv_from(&mut buffer).unwrap();
+            let (length, client) = server.recv_from(&mut buffer).unwrap();
+            server.send_to(&answer(&buffer[..length], &[Ipv4Addr::new(192, 0, 2, 1)]), client).unwrap();
+        });
+        let resolver = Resolver::new(servers).with_timeout(Duration::from_millis(100)).with_attempts(2);
+        assert_eq!(resolver.resolve_ipv4("www.example.com").unwrap(), [Ipv4Addr::new(192, 0, 2, 1)]);
+        answering.join().unwrap();
+
+        let resolver = Resolver::new(vec![silent.local_addr().unwrap()]).with_timeout(Duration::from_millis(100));
+        let started = Instant::now();
+        assert!(matches!(resolver.resolve("www.example.com"), Err(ResolveError::TimedOut)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,440 @@
+//! Mikołaj Depta 328690
+//!
+//! Filesystem abstraction with support for fault injection.
+//!
+//! Production code is generic over `Fs` and defaults to `RealFs`. Tests can swap it for
+//! `FaultyFs` which forwards to another implementation but fails selected operations, so
+//! error paths like a full disk or a file disappearing between two calls can be exercised.
+
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, Metadata, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Filesystem operations required by the binaries.
+pub trait Fs {
+    type File: Read + Write;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Metadata of the `path` itself, symbolic links are not followed.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Self::File>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+}
+
+/// Filesystem of the operating system.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RealFs;
+
+impl Fs for RealFs {
+    type File = fs::File;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Self::File> {
+        options.open(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+// region Fault injection
+/// Filesystem operation a `Fault` can be attached to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Operation {
+    Read,
+    Metadata,
+    SymlinkMetadata,
+    Canonicalize,
+    Open,
+    Write,
+    /// Matched against the path being renamed.
+    Rename,
+    Remove,
+}
+
+/// Condition under which a `Fault` fires.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    /// Every matching call fails.
+    Always,
+    /// Only the n-th matching call fails (counting from 1).
+    Nth(usize),
+    /// First n matching calls succeed, every following call fails.
+    After(usize),
+    /// Writes succeed until the file reaches given size, then they fail - models a full disk.
+    /// Only meaningful for `Operation::Write`.
+    ByteLimit(usize),
+    /// Matching calls fail with given probability.
+    /// Decisions are taken from a seeded generator so test runs are reproducible.
+    Flaky(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Fault {
+    operation: Operation,
+    kind: io::ErrorKind,
+    path: Option<PathBuf>,
+    trigger: Trigger,
+}
+
+impl Fault {
+    pub fn new(operation: Operation, kind: io::ErrorKind) -> Self {
+        Self { operation, kind, path: None, trigger: Trigger::Always }
+    }
+
+    pub fn disk_full() -> Self {
+        Self::new(Operation::Write, io::ErrorKind::StorageFull)
+    }
+
+    pub fn permission_denied(operation: Operation) -> Self {
+        Self::new(operation, io::ErrorKind::PermissionDenied)
+    }
+
+    pub fn not_found(operation: Operation) -> Self {
+        Self::new(operation, io::ErrorKind::NotFound)
+    }
+
+    /// Restricts the fault to `path` and everything below it.
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn with_trigger(mut self, trigger: Trigger) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    fn matches(&self, operation: Operation, path: &Path) -> bool {
+        self.operation == operation
+            && self.path.as_ref().is_none_or(|prefix| path.starts_with(prefix))
+    }
+
+    fn error(&self) -> io::Error {
+        io::Error::new(self.kind, format!("injected fault: {:?} failed with {}", self.operation, self.kind))
+    }
+}
+
+#[derive(Debug)]
+struct FaultState {
+    faults: Vec<(Fault, usize)>,
+    rng_state: u64,
+    injected: usize,
+}
+
+impl FaultState {
+    /// xorshift64* - good enough to decide flaky faults, no dependencies needed.
+    fn next_random(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let value = self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn check(&mut self, operation: Operation, path: &Path) -> io::Result<()> {
+        let mut failure = None;
+        for index in 0..self.faults.len() {
+            let (fault, calls) = &mut self.faults[index];
+            if !fault.matches(operation, path) {
+                continue;
+            }
+            *calls += 1;
+            let fires = match fault.trigger {
+                Trigger::Always => true,
+                Trigger::Nth(n) => *calls == n,
+                Trigger::After(n) => *calls > n,
+                Trigger::ByteLimit(_) => false,
+                Trigger::Flaky(probability) => {
+                    let error = fault.error();
+                    if self.next_random() < probability {
+                        failure.get_or_insert(error);
+                    }
+                    false
+                }
+            };
+            if fires {
+                failure.get_or_insert_with(|| self.faults[index].0.error());
+            }
+        }
+        match failure {
+            Some(err) => {
+                self.injected += 1;
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Number of bytes that can still be written to a file of size `written`, if it is limited.
+    fn write_limit(&self, path: &Path, written: usize) -> Option<(usize, io::Error)> {
+        self.faults
+            .iter()
+            .filter(|(fault, _)| fault.matches(Operation::Write, path))
+            .filter_map(|(fault, _)| match fault.trigger {
+                Trigger::ByteLimit(limit) => Some((limit.saturating_sub(written), fault.error())),
+                _ => None,
+            })
+            .min_by_key(|(room, _)| *room)
+    }
+}
+
+/// `Fs` implementation that forwards calls to `inner` unless one of its faults fires.
+pub struct FaultyFs<F: Fs = RealFs> {
+    inner: F,
+    state: Rc<RefCell<FaultState>>,
+}
+
+impl FaultyFs<RealFs> {
+    pub fn new(faults: impl IntoIterator<Item=Fault>) -> Self {
+        Self::with_inner(RealFs, faults)
+    }
+}
+
+impl<F: Fs> FaultyFs<F> {
+    const DEFAULT_SEED: u64 = 0x5DEE_CE66_D1CE_5EED;
+
+    pub fn with_inner(inner: F, faults: impl IntoIterator<Item=Fault>) -> Self {
+        let state = FaultState {
+            faults: faults.into_iter().map(|fault| (fault, 0)).collect(),
+            rng_state: Self::DEFAULT_SEED,
+            injected: 0,
+        };
+        Self { inner, state: Rc::new(RefCell::new(state)) }
+    }
+
+    /// Reseeds generator used by `Trigger::Flaky` faults.
+    pub fn with_seed(self, seed: u64) -> Self {
+        // zero is a fixed point of xorshift
+        self.state.borrow_mut().rng_state = seed.max(1);
+        self
+    }
+
+    pub fn add_fault(&self, fault: Fault) {
+        self.state.borrow_mut().faults.push((fault, 0));
+    }
+
+    pub fn clear_faults(&self) {
+        self.state.borrow_mut().faults.clear();
+    }
+
+    /// Number of failures injected so far.
+    pub fn injected(&self) -> usize {
+        self.state.borrow().injected
+    }
+}
+
+impl<F: Fs> Fs for FaultyFs<F> {
+    type File = FaultyFile<F::File>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.state.borrow_mut().check(Operation::Read, path)?;
+        self.inner.read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.state.borrow_mut().check(Operation::Metadata, path)?;
+        self.inner.metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.state.borrow_mut().check(Operation::SymlinkMetadata, path)?;
+        self.inner.symlink_metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.state.borrow_mut().check(Operation::Canonicalize, path)?;
+        self.inner.canonicalize(path)
+    }
+
+    fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Self::File> {
+        self.state.borrow_mut().check(Operation::Open, path)?;
+        let inner = self.inner.open(path, options)?;
+        Ok(FaultyFile { inner, path: path.to_owned(), written: 0, state: self.state.clone() })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.state.borrow_mut().check(Operation::Rename, from)?;
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.state.borrow_mut().check(Operation::Remove, path)?;
+        self.inner.remove_file(path)
+    }
+}
+
+/// File opened through `FaultyFs`, writes to it are subject to `Operation::Write` faults.
+pub struct FaultyFile<T> {
+    inner: T,
+    path: PathBuf,
+    written: usize,
+    state: Rc<RefCell<FaultState>>,
+}
+
+impl<T: Read> Read for FaultyFile<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for FaultyFile<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.borrow_mut().check(Operation::Write, &self.path)?;
+        let limit = self.state.borrow().write_limit(&self.path, self.written);
+        let buf = match limit {
+            Some((0, err)) if !buf.is_empty() => {
+                self.state.borrow_mut().injected += 1;
+                return Err(err);
+            }
+            Some((room, _)) => &buf[..buf.len().min(room)],
+            None => buf,
+        };
+        let bytes_written = self.inner.write(buf)?;
+        self.written += bytes_written;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+// endregion
+
+/// Uniquely named directory in the system temporary directory, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(prefix: &str) -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("{}-{}-{}", prefix, process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = env::temp_dir().join(name);
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Creates file at `relative` path (with all missing parent directories) with given content.
+    pub fn create_file(&self, relative: impl AsRef<Path>, content: &[u8]) -> io::Result<PathBuf> {
+        let path = self.0.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_real_fs_passthrough() {
+        let dir = TempDir::new("fs-passthrough").unwrap();
+        let path = dir.create_file("a/b.txt", b"content").unwrap();
+        let fs = FaultyFs::new([]);
+        assert_eq!(fs.read(&path).unwrap(), b"content");
+        assert!(fs.metadata(&path).unwrap().is_file());
+        assert_eq!(fs.injected(), 0);
+    }
+
+    #[test]
+    fn test_fault_restricted_to_path() {
+        let dir = TempDir::new("fs-path").unwrap();
+        let secret = dir.create_file("secret/key", b"1").unwrap();
+        let public = dir.create_file("public/index.html", b"2").unwrap();
+        let fs = FaultyFs::new([
+            Fault::permission_denied(Operation::Read).with_path(dir.path().join("secret")),
+        ]);
+        assert_eq!(fs.read(&secret).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(fs.read(&public).is_ok());
+        assert_eq!(fs.injected(), 1);
+    }
+
+    #[test]
+    fn test_nth_and_after_triggers() {
+        let dir = TempDir::new("fs-triggers").unwrap();
+        let path = dir.create_file("file", b"").unwrap();
+        let fs = FaultyFs::new([Fault::not_found(Operation::Metadata).with_trigger(Trigger::Nth(2))]);
+        assert!(fs.metadata(&path).is_ok());
+        assert_eq!(fs.metadata(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(fs.metadata(&path).is_ok());
+
+        let fs = FaultyFs::new([Fault::not_found(Operation::Canonicalize).with_trigger(Trigger::After(1))]);
+        assert!(fs.canonicalize(&path).is_ok());
+        assert!(fs.canonicalize(&path).is_err());
+        assert!(fs.canonicalize(&path).is_err());
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let dir = TempDir::new("fs-disk-full").unwrap();
+        let path = dir.path().join("out");
+        let fs = FaultyFs::new([Fault::disk_full().with_trigger(Trigger::ByteLimit(10))]);
+        let mut file = fs.open(&path, OpenOptions::new().write(true).create_new(true)).unwrap();
+        assert_eq!(file.write(b"0123456").unwrap(), 7);
+        assert_eq!(file.write(b"0123456").unwrap(), 3);
+        assert_eq!(file.write(b"0").unwrap_err().kind(), io::ErrorKind::StorageFull);
+        assert_eq!(fs::read(&path).unwrap(), b"0123456012");
+    }
+
+    #[test]
+    fn test_flaky_is_reproducible() {
+        let dir = TempDir::new("fs-flaky").unwrap();
+        let path = dir.create_file("file", b"").unwrap();
+        let outcomes = |seed| {
+            let fs = FaultyFs::new([Fault::new(Operation::Read, io::ErrorKind::Interrupted)
+                .with_trigger(Trigger::Flaky(0.5))])
+                .with_seed(seed);
+            (0..64).map(|_| fs.read(&path).is_ok()).collect::<Vec<_>>()
+        };
+        let first = outcomes(7);
+        assert_eq!(first, outcomes(7));
+        assert!(first.iter().any(|ok| *ok));
+        assert!(first.iter().any(|ok| !*ok));
+    }
+}
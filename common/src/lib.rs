@@ -0,0 +1,7 @@
+//! Mikołaj Depta 328690
+//!
+//! Facilities shared by the server, transport and router binaries.
+
+pub mod dns;
+pub mod fs;
+pub mod units;
@@ -0,0 +1,210 @@
+//! Mikołaj Depta 328690
+//!
+//! Parsing of human-friendly durations and sizes used in command line arguments
+//! and environment variables, eg. `500ms`, `2s`, `1.5m`, `64KiB` or `1M`.
+//!
+//! Number may have a fractional part, the result is truncated to whole nanoseconds or bytes.
+//! Unit follows the number, optionally separated with whitespace. Size without a unit is
+//! a number of bytes, duration without a unit is rejected since there is no obvious default.
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseUnitError {
+    Empty,
+    InvalidNumber(String),
+    MissingUnit(String),
+    UnknownUnit(String),
+    Overflow(String),
+}
+
+impl Display for ParseUnitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "value is empty"),
+            Self::InvalidNumber(repr) => write!(f, "invalid number: {repr}"),
+            Self::MissingUnit(repr) => write!(f, "unit missing in {repr}, eg. {repr}ms or {repr}s"),
+            Self::UnknownUnit(unit) => write!(f, "unknown unit: {unit}"),
+            Self::Overflow(repr) => write!(f, "value too large: {repr}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseUnitError {}
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// Duration units and their length in nanoseconds.
+const DURATION_UNITS: [(&str, u128); 6] = [
+    ("ns", 1),
+    ("us", 1_000),
+    ("ms", 1_000_000),
+    ("s", NANOS_PER_SECOND),
+    ("m", 60 * NANOS_PER_SECOND),
+    ("h", 3600 * NANOS_PER_SECOND),
+];
+
+/// Size units and their length in bytes. Decimal prefixes are powers of 1000, binary ones of 1024.
+/// Units are matched case-insensitively, so `k`, `KB` and `kb` all mean a thousand bytes.
+const SIZE_UNITS: [(&str, u128); 13] = [
+    ("b", 1),
+    ("k", 1_000),
+    ("kb", 1_000),
+    ("ki", 1 << 10),
+    ("kib", 1 << 10),
+    ("m", 1_000_000),
+    ("mb", 1_000_000),
+    ("mi", 1 << 20),
+    ("mib", 1 << 20),
+    ("g", 1_000_000_000),
+    ("gb", 1_000_000_000),
+    ("gi", 1 << 30),
+    ("gib", 1 << 30),
+];
+
+/// Splits `repr` into the numeric part and the unit.
+fn split(repr: &str) -> Result<(&str, &str), ParseUnitError> {
+    let repr = repr.trim();
+    if repr.is_empty() {
+        return Err(ParseUnitError::Empty);
+    }
+    let unit_start = repr
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(repr.len());
+    if unit_start == 0 {
+        return Err(ParseUnitError::InvalidNumber(repr.to_owned()));
+    }
+    Ok((&repr[..unit_start], repr[unit_start..].trim_start()))
+}
+
+/// Value of decimal `number` multiplied by `scale`, fractional part of the result is dropped.
+fn scale(number: &str, scale: u128) -> Result<u128, ParseUnitError> {
+    let invalid = || ParseUnitError::InvalidNumber(number.to_owned());
+    let overflow = || ParseUnitError::Overflow(number.to_owned());
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let all_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if whole.is_empty() && fraction.is_empty() || !all_digits(whole) || !all_digits(fraction) {
+        return Err(invalid());
+    }
+    let whole = match whole {
+        "" => 0,
+        whole => whole.parse::<u128>().map_err(|_| overflow())?,
+    };
+    /* digits past the 18th are worth less than a millionth of a nanosecond or byte in every unit */
+    let fraction = &fraction[..fraction.len().min(18)];
+    let fraction_value = match fraction {
+        "" => 0,
+        fraction => fraction.parse::<u128>().map_err(|_| invalid())?,
+    };
+    let denominator = 10u128.pow(fraction.len() as u32);
+    whole
+        .checked_mul(scale)
+        .and_then(|value| value.checked_add(fraction_value.checked_mul(scale)? / denominator))
+        .ok_or_else(overflow)
+}
+
+fn lookup(units: &[(&str, u128)], unit: &str) -> Result<u128, ParseUnitError> {
+    units
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        .map(|(_, scale)| *scale)
+        .ok_or_else(|| ParseUnitError::UnknownUnit(unit.to_owned()))
+}
+
+/// Parses duration such as `250ms`, `2s`, `1.5m` or `1h`.
+pub fn parse_duration(repr: &str) -> Result<Duration, ParseUnitError> {
+    let (number, unit) = split(repr)?;
+    if unit.is_empty() {
+        return Err(ParseUnitError::MissingUnit(number.to_owned()));
+    }
+    let nanos = scale(number, lookup(&DURATION_UNITS, unit)?)?;
+    let seconds = u64::try_from(nanos / NANOS_PER_SECOND)
+        .map_err(|_| ParseUnitError::Overflow(repr.trim().to_owned()))?;
+    Ok(Duration::new(seconds, (nanos % NANOS_PER_SECOND) as u32))
+}
+
+/// Parses size in bytes such as `512`, `64KiB`, `1M` or `1.5 GiB`.
+pub fn parse_size(repr: &str) -> Result<usize, ParseUnitError> {
+    let (number, unit) = split(repr)?;
+    let bytes = match unit {
+        "" => scale(number, 1)?,
+        unit => scale(number, lookup(&SIZE_UNITS, unit)?)?,
+    };
+    usize::try_from(bytes).map_err(|_| ParseUnitError::Overflow(repr.trim().to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("250us"), Ok(Duration::from_micros(250)));
+        assert_eq!(parse_duration("7ns"), Ok(Duration::from_nanos(7)));
+        assert_eq!(parse_duration(" 10 MS "), Ok(Duration::from_millis(10)));
+        assert_eq!(parse_duration(".25s"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_duration_fraction_is_truncated() {
+        assert_eq!(parse_duration("1.0000000019s"), Ok(Duration::new(1, 1)));
+        assert_eq!(parse_duration("0.1ns"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_invalid_durations() {
+        assert_eq!(parse_duration(""), Err(ParseUnitError::Empty));
+        assert_eq!(parse_duration("   "), Err(ParseUnitError::Empty));
+        assert_eq!(parse_duration("30"), Err(ParseUnitError::MissingUnit("30".to_owned())));
+        assert_eq!(parse_duration("2d"), Err(ParseUnitError::UnknownUnit("d".to_owned())));
+        assert_eq!(parse_duration("2KiB"), Err(ParseUnitError::UnknownUnit("KiB".to_owned())));
+        assert_eq!(parse_duration("ms"), Err(ParseUnitError::InvalidNumber("ms".to_owned())));
+        assert_eq!(parse_duration(".s"), Err(ParseUnitError::InvalidNumber(".".to_owned())));
+        assert_eq!(parse_duration("1.2.3s"), Err(ParseUnitError::InvalidNumber("1.2.3".to_owned())));
+        assert_eq!(parse_duration("-1s"), Err(ParseUnitError::InvalidNumber("-1s".to_owned())));
+        assert!(matches!(parse_duration("99999999999999999999999h"), Err(ParseUnitError::Overflow(_))));
+    }
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("64KiB"), Ok(64 * 1024));
+        assert_eq!(parse_size("64Ki"), Ok(64 * 1024));
+        assert_eq!(parse_size("2k"), Ok(2000));
+        assert_eq!(parse_size("2KB"), Ok(2000));
+        assert_eq!(parse_size("1M"), Ok(1_000_000));
+        assert_eq!(parse_size("1MiB"), Ok(1 << 20));
+        assert_eq!(parse_size("1.5 GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("3G"), Ok(3_000_000_000));
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn test_size_fraction_is_truncated() {
+        assert_eq!(parse_size("1.5"), Ok(1));
+        assert_eq!(parse_size("0.001KiB"), Ok(1));
+    }
+
+    #[test]
+    fn test_invalid_sizes() {
+        assert_eq!(parse_size(""), Err(ParseUnitError::Empty));
+        assert_eq!(parse_size("KiB"), Err(ParseUnitError::InvalidNumber("KiB".to_owned())));
+        assert_eq!(parse_size("1TB"), Err(ParseUnitError::UnknownUnit("TB".to_owned())));
+        assert_eq!(parse_size("1s"), Err(ParseUnitError::UnknownUnit("s".to_owned())));
+        assert_eq!(parse_size("1,5M"), Err(ParseUnitError::UnknownUnit(",5M".to_owned())));
+        assert!(matches!(parse_size("340282366920938463463374607431768211456"), Err(ParseUnitError::Overflow(_))));
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(parse_duration("5").unwrap_err().to_string(), "unit missing in 5, eg. 5ms or 5s");
+        assert_eq!(parse_size("5x").unwrap_err().to_string(), "unknown unit: x");
+    }
+}
@@ -0,0 +1,187 @@
+//! Mikołaj Depta 328690
+//!
+//! Epoll event backend, used by default.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use libc::epoll_event;
+
+use crate::registry::{Event, EventType, TimeoutDuration, Token, Trigger};
+use crate::syscall;
+
+impl EventType {
+    /// Flags the interest is registered with, readers are told when the peer stops writing.
+    const fn epoll_flags(&self) -> u32 {
+        match &self {
+            EventType::Read => (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            EventType::Write => libc::EPOLLOUT as u32,
+        }
+    }
+}
+
+impl Event {
+    /// Readiness of the interest with the `token` among the `flags` reported for its descriptor.
+    fn from_epoll_flags(token: Token, event_type: EventType, flags: u32) -> Option<Self> {
+        let (ready, hangup) = match event_type {
+            EventType::Read => (libc::EPOLLIN, libc::EPOLLHUP | libc::EPOLLRDHUP),
+            EventType::Write => (libc::EPOLLOUT, libc::EPOLLHUP),
+        };
+        let is_set = |flag: libc::c_int| flags & flag as u32 != 0;
+        Self::new(token, event_type, is_set(ready), is_set(hangup), is_set(libc::EPOLLERR))
+    }
+}
+
+impl Trigger {
+    const fn epoll_flags(&self) -> u32 {
+        match &self {
+            Trigger::Level => 0,
+            Trigger::OneShot => (libc::EPOLLET | libc::EPOLLONESHOT) as u32,
+        }
+    }
+}
+
+/// Interests of a single descriptor registered with epoll.
+#[derive(Default)]
+struct Registration {
+    tokens: HashMap<EventType, Token>,
+    trigger: Trigger,
+}
+
+impl Registration {
+    fn flags(&self) -> u32 {
+        self.tokens.keys().fold(self.trigger.epoll_flags(), |flags, event_type| flags | event_type.epoll_flags())
+    }
+}
+
+/// Descriptors are registered once, with the union of their interests,
+/// and carry their number as the event data.
+pub(crate) struct Epoll {
+    epoll_fd: RawFd,
+    events: Vec<epoll_event>,
+    registered: HashMap<RawFd, Registration>,
+}
+
+impl Epoll {
+    const MAX_EVENTS: usize = 64;
+
+    pub(crate) fn new() -> io::Result<Self> {
+        let epoll_fd = syscall!(epoll_create1(libc::O_CLOEXEC))?;
+        Ok(Self { epoll_fd, events: Vec::with_capacity(Self::MAX_EVENTS), registered: HashMap::new() })
+    }
+
+    /// Descriptor that is already registered has its registration modified to include the interest.
+    pub(crate) fn add_interest(&mut self, token: Token, event_type: EventType, fd: RawFd, trigger: Trigger) -> io::Result<()> {
+        let (operation, flags) = match self.registered.get(&fd) {
+            Some(registration) => (libc::EPOLL_CTL_MOD, registration.flags()),
+            None => (libc::EPOLL_CTL_ADD, trigger.epoll_flags()),
+        };
+        let mut event = epoll_event { events: flags | event_type.epoll_flags(), u64: fd as u64 };
+        match syscall!(epoll_ctl(self.epoll_fd, operation, fd, &mut event)) {
+            /* registered with the kernel, but not by us, eg. by a duplicate of the descriptor that was closed since */
+            Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {
+                syscall!(epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut event))?;
+            }
+            result => {
+                result?;
+            }
+        }
+        let registration = self.registered.entry(fd).or_default();
+        registration.trigger = trigger;
+        registration.tokens.insert(event_type, token);
+        Ok(())
+    }
+
+    pub(crate) fn modify_interest(&mut self, event_type: EventType, fd: RawFd, new_event_type: EventType) -> io::Result<()> {
+        let Some(registration) = self.registered.get_mut(&fd) else {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        };
+        let token = registration.tokens.remove(&event_type).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        registration.tokens.insert(new_event_type, token);
+        let mut event = epoll_event { events: registration.flags(), u64: fd as u64 };
+        if let Err(err) = syscall!(epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut event)) {
+            registration.tokens.remove(&new_event_type);
+            registration.tokens.insert(event_type, token);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_interest(&mut self, event_type: EventType, fd: RawFd) -> io::Result<()> {
+        let Some(registration) = self.registered.get_mut(&fd) else {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        };
+        registration.tokens.remove(&event_type);
+        if registration.tokens.is_empty() {
+            self.registered.remove(&fd);
+            syscall!(epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()))?;
+        } else {
+            let mut event = epoll_event { events: registration.flags(), u64: fd as u64 };
+            syscall!(epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut event))?;
+        }
+        Ok(())
+    }
+
+    /// One-shot descriptors are disabled once reported, modifying them enables them again.
+    pub(crate) fn rearm(&mut self, fd: RawFd) -> io::Result<()> {
+        let registration = self.registered.get(&fd).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let mut event = epoll_event { events: registration.flags(), u64: fd as u64 };
+        syscall!(epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut event))?;
+        Ok(())
+    }
+
+    /// Errors and hang-ups are reported whatever the interests are, they are passed on to all of them.
+    pub(crate) fn wait(&mut self, timeout: &TimeoutDuration) -> io::Result<Vec<Event>> {
+        self.events.clear();
+        let epoll_timeout = timeout.as_millis();
+
+        let res = syscall!(
+            epoll_wait(
+                self.epoll_fd,
+                self.events.as_mut_ptr(),
+                Self::MAX_EVENTS as libc::c_int,
+                epoll_timeout,
+            )
+        )?;
+        // safety: since events was empty before epoll_wait syscall the length of self.events
+        // after should be exactly res (assuming kernel is correct).
+        unsafe { self.events.set_len(res as usize); }
+        let mut ready = Vec::new();
+        for event in &self.events {
+            let (flags, fd) = (event.events, event.u64 as RawFd);
+            let Some(registration) = self.registered.get(&fd) else { continue };
+            let events = registration.tokens.iter().filter_map(|(event_type, token)| Event::from_epoll_flags(*token, *event_type, flags));
+            ready.extend(events);
+        }
+        Ok(ready)
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.epoll_fd); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    #[test]
+    fn test_descriptors_registered_elsewhere_are_taken_over() {
+        let mut epoll = Epoll::new().unwrap();
+        let (_first, second) = UnixStream::pair().unwrap();
+        let fd = second.as_raw_fd();
+        let mut event = epoll_event { events: libc::EPOLLIN as u32, u64: fd as u64 };
+        syscall!(epoll_ctl(epoll.epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event)).unwrap();
+
+        let token = Token(7);
+        epoll.add_interest(token, EventType::Write, fd, Trigger::Level).unwrap();
+        let ready = epoll.wait(&TimeoutDuration::Finite(Duration::from_secs(5))).unwrap();
+        assert_eq!(ready.iter().map(|event| (event.token, event.writable)).collect::<Vec<_>>(), [(token, true)]);
+    }
+}
@@ -0,0 +1,48 @@
+//! Mikołaj Depta 328690
+//!
+//! Readiness notifications shared by the server, transport and router binaries.
+//!
+//! `Registry` watches descriptors for reading and writing and tells their interests apart
+//! with tokens. It waits with epoll, with poll(2) where epoll is not available, or with
+//! io_uring when built with the `io-uring` feature. On Linux periodic timers and signals are
+//! watched the same way, see `Registry::add_timer` and `Registry::add_signals`. `Registry::run`
+//! is a minimal loop that serves ready interests until it is told to stop. Besides readiness, events
+//! tell when the peer hung up or an error is pending, see `Event`. Sockets may also be accepted on,
+//! received from and sent to through the registry, see `Registry::accept`, with io_uring these
+//! operations are submitted to the ring and completed by the kernel.
+
+/* `syscall!` evaluates its arguments inside the unsafe block, the same way a direct call would */
+#![allow(clippy::macro_metavars_in_unsafe)]
+
+#[cfg(target_os = "linux")]
+mod epoll;
+#[cfg(target_os = "linux")]
+mod operations;
+mod poll;
+mod registry;
+#[cfg(target_os = "linux")]
+mod signals;
+#[cfg(target_os = "linux")]
+mod timer;
+#[cfg(feature = "io-uring")]
+mod uring;
+
+pub use registry::{Event, EventType, Notification, Registry, TimeoutDuration, Token, Trigger};
+#[cfg(target_os = "linux")]
+pub use signals::Signal;
+#[cfg(target_os = "linux")]
+pub use timer::Timer;
+
+#[doc(hidden)]
+pub use libc;
+
+/// Calls a libc function, turns its `-1` into the error from `errno`.
+#[macro_export]
+macro_rules! syscall {
+    ($func_name: ident ( $($arg: expr),* $(,)* ) ) => {
+        {
+            let result = unsafe { $crate::libc::$func_name($($arg,)* ) };
+            if result == -1 { Err(std::io::Error::last_os_error()) } else { Ok(result) }
+        }
+    }
+}
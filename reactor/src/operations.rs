@@ -0,0 +1,77 @@
+//! Mikołaj Depta 328690
+//!
+//! Socket operations carried out to completion, see `Registry::accept`, `Registry::recv`,
+//! `Registry::send` and `Registry::send_file`.
+//!
+//! With io_uring the operations themselves are submitted to the ring, see `uring`. Other backends
+//! make the system call and, whenever it would block, wait with poll(2) for that one descriptor,
+//! so interests registered for it are neither reported nor disturbed by the wait.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::Instant;
+
+use crate::registry::{EventType, TimeoutDuration};
+use crate::syscall;
+
+pub(crate) enum Operation<'a> {
+    /// Accepts a connection, the result is its descriptor, closed on exec.
+    Accept,
+    Recv(&'a mut [u8]),
+    /// Sending to a connection the peer closed fails with `EPIPE` instead of raising `SIGPIPE`.
+    Send(&'a [u8]),
+    /// Sends up to `count` bytes of the `file` from the `offset` on, position of the file stays where it was.
+    SendFile { file: RawFd, offset: u64, count: usize },
+}
+
+impl Operation<'_> {
+    /// Readiness the operation waits for when it would block.
+    pub(crate) fn event_type(&self) -> EventType {
+        match self {
+            Operation::Accept | Operation::Recv(_) => EventType::Read,
+            Operation::Send(_) | Operation::SendFile { .. } => EventType::Write,
+        }
+    }
+
+    /// Makes the system call once. Sockets are read from and written to without blocking even if
+    /// they are blocking, accepting and sending files blocks on blocking descriptors.
+    fn call(&mut self, fd: RawFd) -> io::Result<usize> {
+        let result = match self {
+            Operation::Accept => syscall!(accept4(fd, ptr::null_mut(), ptr::null_mut(), libc::SOCK_CLOEXEC))? as isize,
+            Operation::Recv(buffer) => syscall!(recv(fd, buffer.as_mut_ptr().cast(), buffer.len(), libc::MSG_DONTWAIT))?,
+            Operation::Send(buffer) => {
+                syscall!(send(fd, buffer.as_ptr().cast(), buffer.len(), libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL))?
+            }
+            Operation::SendFile { file, offset, count } => {
+                let mut offset = *offset as libc::off_t;
+                syscall!(sendfile(fd, *file, &mut offset, *count))?
+            }
+        };
+        Ok(result as usize)
+    }
+}
+
+/// Carries out the `operation` on `fd`, waiting for readiness whenever it would block.
+/// Fails with `TimedOut` if it is not done within the `timeout`.
+pub(crate) fn complete(fd: RawFd, mut operation: Operation, timeout: &TimeoutDuration) -> io::Result<usize> {
+    let started = Instant::now();
+    loop {
+        match operation.call(fd) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_over() {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        let mut pollfd = libc::pollfd { fd, events: operation.event_type().poll_flags(), revents: 0 };
+        match syscall!(poll(&mut pollfd, 1, remaining.as_millis())) {
+            /* the call tells whether the descriptor got ready, failed or the wait ended early */
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Mikołaj Depta 328690
+//!
+//! poll(2) event backend, used where epoll is not available, eg. on WSL1 or outside of Linux.
+//!
+//! Every interest is passed to the kernel as its own entry, one-shot interests are left out
+//! from the moment they are reported until they are re-armed.
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use crate::registry::{Event, EventType, TimeoutDuration, Token, Trigger};
+use crate::syscall;
+
+/// Reported once the peer stops writing, only Linux tells it apart from `POLLIN`.
+#[cfg(target_os = "linux")]
+const READ_HANGUP: libc::c_short = libc::POLLRDHUP;
+#[cfg(not(target_os = "linux"))]
+const READ_HANGUP: libc::c_short = 0;
+
+impl EventType {
+    /// Flags the interest is polled with, readers are told when the peer stops writing.
+    pub(crate) const fn poll_flags(&self) -> libc::c_short {
+        match &self {
+            EventType::Read => libc::POLLIN | READ_HANGUP,
+            EventType::Write => libc::POLLOUT,
+        }
+    }
+}
+
+impl Event {
+    /// Readiness of the interest with the `token` among the `revents` reported for its descriptor.
+    /// Errors and hang-ups are reported whatever the interest is.
+    pub(crate) fn from_poll_flags(token: Token, event_type: EventType, revents: libc::c_short) -> Option<Self> {
+        let (ready, hangup) = match event_type {
+            EventType::Read => (libc::POLLIN, libc::POLLHUP | READ_HANGUP),
+            EventType::Write => (libc::POLLOUT, libc::POLLHUP),
+        };
+        let is_set = |flags: libc::c_short| revents & flags != 0;
+        Self::new(token, event_type, is_set(ready), is_set(hangup), is_set(libc::POLLERR | libc::POLLNVAL))
+    }
+}
+
+struct Interest {
+    token: Token,
+    fd: RawFd,
+    event_type: EventType,
+    trigger: Trigger,
+    /// Interest is passed to the kernel on the next wait.
+    armed: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct Poll {
+    interests: Vec<Interest>,
+    /// Entries passed to the kernel on the last wait.
+    fds: Vec<libc::pollfd>,
+    /// Indices of the interests the entries were made for.
+    indices: Vec<usize>,
+}
+
+impl Poll {
+    pub(crate) fn add_interest(&mut self, token: Token, event_type: EventType, fd: RawFd, trigger: Trigger) {
+        self.interests.push(Interest { token, fd, event_type, trigger, armed: true });
+    }
+
+    pub(crate) fn delete_interest(&mut self, token: Token) -> io::Result<()> {
+        let index = self.position(token)?;
+        self.interests.swap_remove(index);
+        Ok(())
+    }
+
+    pub(crate) fn modify_interest(&mut self, token: Token, event_type: EventType) -> io::Result<()> {
+        let index = self.position(token)?;
+        let interest = &mut self.interests[index];
+        interest.event_type = event_type;
+        interest.armed = true;
+        Ok(())
+    }
+
+    pub(crate) fn rearm(&mut self, token: Token) -> io::Result<()> {
+        let index = self.position(token)?;
+        self.interests[index].armed = true;
+        Ok(())
+    }
+
+    fn position(&self, token: Token) -> io::Result<usize> {
+        self.interests
+            .iter()
+            .position(|interest| interest.token == token)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    pub(crate) fn wait(&mut self, timeout: &TimeoutDuration) -> io::Result<Vec<Event>> {
+        self.fds.clear();
+        self.indices.clear();
+        for (index, interest) in self.interests.iter().enumerate().filter(|(_, interest)| interest.armed) {
+            self.fds.push(libc::pollfd { fd: interest.fd, events: interest.event_type.poll_flags(), revents: 0 });
+            self.indices.push(index);
+        }
+        syscall!(poll(self.fds.as_mut_ptr(), self.fds.len() as libc::nfds_t, timeout.as_millis()))?;
+        let mut ready = Vec::new();
+        for (pollfd, index) in self.fds.iter().zip(&self.indices) {
+            let interest = &mut self.interests[*index];
+            if let Some(event) = Event::from_poll_flags(interest.token, interest.event_type, pollfd.revents) {
+                ready.push(event);
+                interest.armed = interest.trigger == Trigger::Level;
+            }
+        }
+        Ok(ready)
+    }
+}
@@ -0,0 +1,898 @@
+//! Mikołaj Depta 328690
+//!
+//! This module exposes the event backends in a form of registry.
+
+use std::collections::HashMap;
+use std::io;
+use std::ops::ControlFlow;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use crate::epoll::Epoll;
+#[cfg(target_os = "linux")]
+use crate::operations::{self, Operation};
+use crate::poll::Poll;
+#[cfg(target_os = "linux")]
+use crate::signals::{Signal, SignalFd};
+#[cfg(target_os = "linux")]
+use crate::timer::Timer;
+
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum EventType {
+    Read,
+    Write,
+}
+
+/// How readiness of an interest is reported, see `Registry::add_interest_with`.
+#[derive(Debug, Copy, Clone, Default, Hash, Eq, PartialEq)]
+pub enum Trigger {
+    /// Reported on every wait for as long as the descriptor is ready.
+    #[default]
+    Level,
+    /// Reported once, then not until the interest is re-armed with `Registry::rearm`.
+    /// Meant for reading or writing until `WouldBlock` before waiting again,
+    /// so a descriptor is not reported over and over while it is being served.
+    OneShot,
+}
+
+/// Identifies a single interest of a single descriptor, see `Registry::add_interest`.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Token(pub(crate) u64);
+
+impl Token {
+    pub fn get(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Readiness of a single interest, as reported by `Registry::await_events`.
+///
+/// Interest that is not ready for its own kind of event may still be reported, once its peer
+/// hangs up or an error is pending, so that it can be dropped without waiting for it to fail.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct Event {
+    pub token: Token,
+    /// Interest is in reading and the descriptor can be read from without blocking.
+    pub readable: bool,
+    /// Interest is in writing and the descriptor can be written to without blocking.
+    pub writable: bool,
+    /// Peer closed the connection, or its writing half when the interest is in reading.
+    /// Data sent before may still be waiting to be read.
+    pub hangup: bool,
+    /// Error is pending on the descriptor, eg. the connection was reset by the peer.
+    pub error: bool,
+}
+
+impl Event {
+    /// Readiness of the interest with the `token`, `None` if nothing happened to it.
+    pub(crate) fn new(token: Token, event_type: EventType, ready: bool, hangup: bool, error: bool) -> Option<Self> {
+        let event = Self {
+            token,
+            readable: ready && event_type == EventType::Read,
+            writable: ready && event_type == EventType::Write,
+            hangup,
+            error,
+        };
+        (ready || hangup || error).then_some(event)
+    }
+}
+
+pub enum Notification {
+    Timeout,
+    /// First of the ready interests and time spent waiting for it.
+    Event(EventType, Duration),
+}
+
+impl Notification {
+    /// Part of the `timeout` the wait ended with left, so the next wait can be made for the rest of it.
+    /// Nothing is left once the wait timed out.
+    pub fn remaining(&self, timeout: &TimeoutDuration) -> TimeoutDuration {
+        match self {
+            Notification::Timeout => TimeoutDuration::Finite(Duration::ZERO),
+            Notification::Event(_, waited) => timeout.saturating_sub(*waited),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TimeoutDuration {
+    Infinite,
+    Finite(Duration)
+}
+
+impl TimeoutDuration {
+    /// Part of the timeout left once `elapsed` has passed, an infinite one never runs out.
+    pub fn saturating_sub(&self, elapsed: Duration) -> TimeoutDuration {
+        match self {
+            TimeoutDuration::Infinite => TimeoutDuration::Infinite,
+            TimeoutDuration::Finite(duration) => TimeoutDuration::Finite(duration.saturating_sub(elapsed)),
+        }
+    }
+
+    /// Timeout is finite and nothing is left of it.
+    pub fn is_over(&self) -> bool {
+        matches!(self, TimeoutDuration::Finite(duration) if duration.is_zero())
+    }
+
+    /// Timeout as taken by epoll_wait(2) and poll(2), rounded down to milliseconds.
+    pub(crate) fn as_millis(&self) -> libc::c_int {
+        match self {
+            TimeoutDuration::Infinite => -1,
+            TimeoutDuration::Finite(duration) => duration.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+        }
+    }
+}
+
+/// Mechanism used to wait for readiness of the registered file descriptors.
+enum Backend {
+    #[cfg(target_os = "linux")]
+    Epoll(Epoll),
+    Poll(Poll),
+    #[cfg(feature = "io-uring")]
+    IoUring(Box<crate::uring::Uring>),
+}
+
+impl Backend {
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::Epoll(_) => "epoll",
+            Self::Poll(_) => "poll",
+            #[cfg(feature = "io-uring")]
+            Self::IoUring(_) => "io_uring",
+        }
+    }
+}
+
+pub struct Registry {
+    backend: Backend,
+    /// Registered interests by their tokens.
+    interests: HashMap<Token, (EventType, RawFd, Trigger)>,
+    next_token: u64,
+    /// Timers added with `add_timer`, by the tokens of their interests.
+    #[cfg(target_os = "linux")]
+    timers: HashMap<Token, Timer>,
+    /// Signals watched with `add_signals`, by the tokens of their interests.
+    #[cfg(target_os = "linux")]
+    signals: HashMap<Token, SignalFd>,
+}
+
+impl Registry {
+    /// Registry waiting with epoll if the kernel supports it, with poll otherwise.
+    pub fn new() -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        if let Ok(registry) = Self::with_epoll() {
+            return Ok(registry);
+        }
+        Ok(Self::with_poll())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn with_epoll() -> io::Result<Self> {
+        Ok(Self::with_backend(Backend::Epoll(Epoll::new()?)))
+    }
+
+    /// Registry that waits with poll(2) even if epoll is available.
+    pub fn with_poll() -> Self {
+        Self::with_backend(Backend::Poll(Poll::default()))
+    }
+
+    /// Registry waiting with one-shot io_uring polls, fails where io_uring is not available.
+    #[cfg(feature = "io-uring")]
+    pub fn with_io_uring() -> io::Result<Self> {
+        Ok(Self::with_backend(Backend::IoUring(Box::new(crate::uring::Uring::new()?))))
+    }
+
+    fn with_backend(backend: Backend) -> Self {
+        Self {
+            backend,
+            interests: HashMap::new(),
+            next_token: 0,
+            #[cfg(target_os = "linux")]
+            timers: HashMap::new(),
+            #[cfg(target_os = "linux")]
+            signals: HashMap::new(),
+        }
+    }
+
+    /// Name of the mechanism used to wait for events, `epoll`, `poll` or `io_uring`.
+    pub fn backend(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    /// Registers interest in `event_type` for `fd`, readiness is reported with the returned token.
+    /// Descriptor may be watched for reading and writing at once, each interest has its own token.
+    pub fn add_interest(&mut self, event_type: EventType, fd: impl AsRawFd) -> io::Result<Token> {
+        self.add_interest_with(event_type, fd, Trigger::Level)
+    }
+
+    /// Same as `add_interest`, with readiness reported as the `trigger` says.
+    /// All interests of a descriptor have to use the same trigger.
+    pub fn add_interest_with(&mut self, event_type: EventType, fd: impl AsRawFd, trigger: Trigger) -> io::Result<Token> {
+        let fd = fd.as_raw_fd();
+        if self.token(event_type, fd).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        if self.interests.values().any(|interest| interest.1 == fd && interest.2 != trigger) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let token = Token(self.next_token);
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Epoll(epoll) => epoll.add_interest(token, event_type, fd, trigger)?,
+            Backend::Poll(poll) => poll.add_interest(token, event_type, fd, trigger),
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring(uring) => uring.add_interest(token, event_type, fd, trigger)?,
+        }
+        self.next_token += 1;
+        self.interests.insert(token, (event_type, fd, trigger));
+        Ok(token)
+    }
+
+    /// Lets the one-shot interest with the `token` be reported again, at once if its descriptor is still ready.
+    /// With epoll interests of a descriptor are armed together, re-arming one re-arms all of them.
+    /// Interests reported on every wait need no re-arming, for them it does nothing.
+    pub fn rearm(&mut self, token: Token) -> io::Result<()> {
+        let (_, fd, trigger) = *self.interests.get(&token).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        if trigger == Trigger::Level {
+            return Ok(());
+        }
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Epoll(epoll) => epoll.rearm(fd),
+            Backend::Poll(poll) => poll.rearm(token),
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring(uring) => uring.rearm(token),
+        }
+    }
+
+    /// Switches the interest in `event_type` for `fd` to `new_event_type`, eg. once a connection
+    /// has read a request and waits to write the response. Interest keeps its token and trigger,
+    /// a one-shot interest is armed again. Fails with `EEXIST` if the descriptor is already
+    /// watched for `new_event_type`.
+    pub fn modify_interest(&mut self, event_type: EventType, fd: impl AsRawFd, new_event_type: EventType) -> io::Result<()> {
+        let fd = fd.as_raw_fd();
+        let token = self.token(event_type, fd).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        if new_event_type != event_type && self.token(new_event_type, fd).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Epoll(epoll) => epoll.modify_interest(event_type, fd, new_event_type)?,
+            Backend::Poll(poll) => poll.modify_interest(token, new_event_type)?,
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring(uring) => uring.modify_interest(token, new_event_type)?,
+        }
+        if let Some(interest) = self.interests.get_mut(&token) {
+            interest.0 = new_event_type;
+        }
+        Ok(())
+    }
+
+    /// Removes interest in `event_type` for `fd`, other interests of the descriptor stay.
+    pub fn delete_interest(&mut self, event_type: EventType, fd: impl AsRawFd) -> io::Result<()> {
+        let fd = fd.as_raw_fd();
+        let token = self.token(event_type, fd).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        self.interests.remove(&token);
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Epoll(epoll) => epoll.delete_interest(event_type, fd),
+            Backend::Poll(poll) => poll.delete_interest(token),
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring(uring) => uring.delete_interest(token),
+        }
+    }
+
+    /// Starts a timer that expires every `interval`, each expiration is reported as readiness
+    /// for reading with the returned token. Registry acknowledges expirations as it reports them,
+    /// so ones that were missed while nobody waited are reported once.
+    #[cfg(target_os = "linux")]
+    pub fn add_timer(&mut self, interval: Duration) -> io::Result<Token> {
+        let timer = Timer::periodic(interval)?;
+        let token = self.add_interest(EventType::Read, timer.as_raw_fd())?;
+        self.timers.insert(token, timer);
+        Ok(token)
+    }
+
+    /// Stops the timer started with `add_timer`.
+    #[cfg(target_os = "linux")]
+    pub fn delete_timer(&mut self, token: Token) -> io::Result<()> {
+        let timer = self.timers.remove(&token).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        self.delete_interest(EventType::Read, timer.as_raw_fd())
+    }
+
+    /// Starts watching the `signals`, once any of them is received it is reported as readiness
+    /// for reading with the returned token, until `received_signals` takes it. Signals are blocked
+    /// in the calling thread and the threads it starts later, handlers do not see them anymore.
+    #[cfg(target_os = "linux")]
+    pub fn add_signals(&mut self, signals: &[Signal]) -> io::Result<Token> {
+        let signal_fd = SignalFd::new(signals)?;
+        let token = self.add_interest(EventType::Read, signal_fd.as_raw_fd())?;
+        self.signals.insert(token, signal_fd);
+        Ok(token)
+    }
+
+    /// Signals watched with the `token` that were received since the last call.
+    #[cfg(target_os = "linux")]
+    pub fn received_signals(&mut self, token: Token) -> io::Result<Vec<Signal>> {
+        let signal_fd = self.signals.get(&token).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        signal_fd.received()
+    }
+
+    /// Stops watching the signals added with `add_signals`. The ones `add_signals` blocked are
+    /// unblocked again in the calling thread, signals pending meanwhile are delivered as usual.
+    #[cfg(target_os = "linux")]
+    pub fn delete_signals(&mut self, token: Token) -> io::Result<()> {
+        let signal_fd = self.signals.remove(&token).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        self.delete_interest(EventType::Read, signal_fd.as_raw_fd())
+    }
+
+    /// Accepts a connection on the listening socket `fd`, failing with `TimedOut` if none comes within
+    /// the `timeout`. Accepted descriptor is blocking and closed on exec.
+    ///
+    /// This and the other operations are carried out to completion by the registry. With io_uring
+    /// they are submitted to the ring, other backends wait for readiness of the descriptor between
+    /// attempts. Either way interests registered for the descriptor are not reported by them.
+    /// Blocking listeners and sockets files are sent to may block past the `timeout` without io_uring.
+    #[cfg(target_os = "linux")]
+    pub fn accept(&mut self, fd: impl AsRawFd, timeout: &TimeoutDuration) -> io::Result<OwnedFd> {
+        let fd = self.complete(fd.as_raw_fd(), Operation::Accept, timeout)?;
+        // safety: accepted descriptor is owned by nothing else.
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+
+    /// Receives data from the socket `fd` into the `buffer`, waiting at most `timeout` for any to come.
+    /// Result is the number of bytes received, 0 once the peer stopped writing.
+    #[cfg(target_os = "linux")]
+    pub fn recv(&mut self, fd: impl AsRawFd, buffer: &mut [u8], timeout: &TimeoutDuration) -> io::Result<usize> {
+        self.complete(fd.as_raw_fd(), Operation::Recv(buffer), timeout)
+    }
+
+    /// Sends a part of the `buffer` to the socket `fd`, waiting at most `timeout` for room to send it.
+    /// Result is the number of bytes sent, sending to a closed connection fails with `BrokenPipe`.
+    #[cfg(target_os = "linux")]
+    pub fn send(&mut self, fd: impl AsRawFd, buffer: &[u8], timeout: &TimeoutDuration) -> io::Result<usize> {
+        self.complete(fd.as_raw_fd(), Operation::Send(buffer), timeout)
+    }
+
+    /// Sends up to `count` bytes of the `file` from the `offset` on to the socket `fd`, waiting at most
+    /// `timeout` for room to send them. Result is the number of bytes sent, 0 past the end of the file.
+    /// Position of the file is not changed.
+    #[cfg(target_os = "linux")]
+    pub fn send_file(
+        &mut self,
+        fd: impl AsRawFd,
+        file: impl AsRawFd,
+        offset: u64,
+        count: usize,
+        timeout: &TimeoutDuration,
+    ) -> io::Result<usize> {
+        let operation = Operation::SendFile { file: file.as_raw_fd(), offset, count };
+        self.complete(fd.as_raw_fd(), operation, timeout)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn complete(&mut self, fd: RawFd, operation: Operation, timeout: &TimeoutDuration) -> io::Result<usize> {
+        match &mut self.backend {
+            Backend::Epoll(_) => operations::complete(fd, operation, timeout),
+            Backend::Poll(_) => operations::complete(fd, operation, timeout),
+            #[cfg(feature = "io-uring")]
+            Backend::IoUring(uring) => uring.complete(fd, operation, timeout),
+        }
+    }
+
+    /// Interest the `token` was returned for, `None` once it was deleted.
+    pub fn interest(&self, token: Token) -> Option<(EventType, RawFd)> {
+        self.interests.get(&token).map(|(event_type, fd, _)| (*event_type, *fd))
+    }
+
+    fn token(&self, event_type: EventType, fd: RawFd) -> Option<Token> {
+        self.interests
+            .iter()
+            .find(|(_, interest)| (interest.0, interest.1) == (event_type, fd))
+            .map(|(token, _)| *token)
+    }
+
+    pub fn await_indefinitely(&mut self) -> io::Result<EventType> {
+        loop {
+            if let Notification::Event(event, _) = self.await_event(&TimeoutDuration::Infinite)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Waits for the first registered descriptor to become ready, reports how long it took,
+    /// see `Notification::remaining` for the part of the `timeout` that is left.
+    pub fn await_event(&mut self, timeout: &TimeoutDuration) -> io::Result<Notification> {
+        let sleep_start_time = Instant::now();
+        let event = self.await_ready(timeout)?;
+        let sleep_duration = sleep_start_time.elapsed();
+        match event {
+            None => Ok(Notification::Timeout),
+            Some((event, _)) => Ok(Notification::Event(event, sleep_duration)),
+        }
+    }
+
+    /// Waits for the first registered descriptor to become ready, `None` on timeout.
+    /// Lets a single registry watch several descriptors of the same kind, eg. listening sockets.
+    pub fn await_ready(&mut self, timeout: &TimeoutDuration) -> io::Result<Option<(EventType, RawFd)>> {
+        let ready = self.await_events(timeout)?;
+        Ok(ready.first().and_then(|event| self.interest(event.token)))
+    }
+
+    /// Waits for registered descriptors to become ready, returns all interests that are, none on timeout.
+    /// Wait interrupted by a signal is resumed for the rest of the `timeout`, so is one that ended early.
+    pub fn await_events(&mut self, timeout: &TimeoutDuration) -> io::Result<Vec<Event>> {
+        let sleep_start_time = Instant::now();
+        let mut remaining = timeout.clone();
+        loop {
+            let ready = match &mut self.backend {
+                #[cfg(target_os = "linux")]
+                Backend::Epoll(epoll) => epoll.wait(&remaining),
+                Backend::Poll(poll) => poll.wait(&remaining),
+                #[cfg(feature = "io-uring")]
+                Backend::IoUring(uring) => uring.wait(&remaining),
+            };
+            remaining = timeout.saturating_sub(sleep_start_time.elapsed());
+            match ready {
+                Ok(ready) if !ready.is_empty() => {
+                    #[cfg(target_os = "linux")]
+                    for timer in ready.iter().filter_map(|event| self.timers.get(&event.token)) {
+                        timer.acknowledge()?;
+                    }
+                    return Ok(ready);
+                }
+                Ok(_) if remaining.is_over() => return Ok(Vec::new()),
+                Err(err) if err.kind() != io::ErrorKind::Interrupted => return Err(err),
+                /* interrupted, or woken up early since waits are rounded down to milliseconds */
+                _ => {}
+            }
+        }
+    }
+
+    /// Serves ready interests one by one until `serve` breaks, waiting at most `timeout` at a time.
+    /// `serve` is called with `None` when a wait times out. It is given the registry, so it may add
+    /// and delete interests, readiness of interests deleted in the meantime is not passed on.
+    pub fn run<B>(
+        &mut self,
+        timeout: &TimeoutDuration,
+        mut serve: impl FnMut(&mut Self, Option<Event>) -> ControlFlow<B>,
+    ) -> io::Result<B> {
+        loop {
+            let ready = self.await_events(timeout)?;
+            if ready.is_empty() {
+                if let ControlFlow::Break(result) = serve(self, None) {
+                    return Ok(result);
+                }
+            }
+            for event in ready {
+                if !self.interests.contains_key(&event.token) {
+                    continue;
+                }
+                if let ControlFlow::Break(result) = serve(self, Some(event)) {
+                    return Ok(result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream, UdpSocket};
+    use std::os::unix::net::UnixStream;
+
+    /// Event of an interest that is ready for its own kind of event and nothing else.
+    fn ready(token: Token, event_type: EventType) -> Event {
+        Event::new(token, event_type, true, false, false).unwrap()
+    }
+
+    fn assert_reports_readiness(mut registry: Registry) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let short = TimeoutDuration::Finite(Duration::from_millis(10));
+        registry.add_interest(EventType::Read, server.as_raw_fd()).unwrap();
+        let notification = registry.await_event(&short).unwrap();
+        assert!(matches!(notification, Notification::Timeout));
+        assert!(notification.remaining(&short).is_over());
+        client.write_all(b"ping").unwrap();
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        let notification = registry.await_event(&long).unwrap();
+        assert!(matches!(notification, Notification::Event(EventType::Read, _)));
+        let TimeoutDuration::Finite(remaining) = notification.remaining(&long) else { panic!("finite timeout has no end") };
+        assert!(remaining > Duration::from_secs(4), "{remaining:?}");
+        assert!(matches!(notification.remaining(&TimeoutDuration::Infinite), TimeoutDuration::Infinite));
+        /* data was not read, readiness is reported again */
+        assert!(matches!(registry.await_event(&long).unwrap(), Notification::Event(EventType::Read, _)));
+        assert_eq!(registry.await_ready(&long).unwrap(), Some((EventType::Read, server.as_raw_fd())));
+        registry.delete_interest(EventType::Read, server.as_raw_fd()).unwrap();
+        registry.add_interest(EventType::Write, server.as_raw_fd()).unwrap();
+        assert!(matches!(registry.await_event(&long).unwrap(), Notification::Event(EventType::Write, _)));
+        registry.delete_interest(EventType::Write, server.as_raw_fd()).unwrap();
+        assert!(matches!(registry.await_event(&short).unwrap(), Notification::Timeout));
+    }
+
+    /// Every ready interest is reported with its own token, even if they share a descriptor.
+    fn assert_tells_interests_apart(mut registry: Registry) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut first = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (first_server, _) = listener.accept().unwrap();
+        let _second = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (second_server, _) = listener.accept().unwrap();
+        let reading_first = registry.add_interest(EventType::Read, first_server.as_raw_fd()).unwrap();
+        let reading_second = registry.add_interest(EventType::Read, second_server.as_raw_fd()).unwrap();
+        let writing_first = registry.add_interest(EventType::Write, first_server.as_raw_fd()).unwrap();
+        assert_ne!(reading_first, writing_first);
+        assert_eq!(registry.interest(reading_second), Some((EventType::Read, second_server.as_raw_fd())));
+        assert_eq!(registry.add_interest(EventType::Read, first_server.as_raw_fd()).unwrap_err().raw_os_error(), Some(libc::EEXIST));
+
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(writing_first, EventType::Write)]);
+        first.write_all(b"ping").unwrap();
+        /* readiness may be reported over several waits */
+        let mut reported = HashSet::new();
+        while reported.len() < 2 {
+            reported.extend(registry.await_events(&long).unwrap());
+        }
+        assert_eq!(reported, HashSet::from([ready(reading_first, EventType::Read), ready(writing_first, EventType::Write)]));
+
+        registry.delete_interest(EventType::Write, first_server.as_raw_fd()).unwrap();
+        assert_eq!(registry.interest(writing_first), None);
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(reading_first, EventType::Read)]);
+        registry.delete_interest(EventType::Read, first_server.as_raw_fd()).unwrap();
+        assert!(registry.delete_interest(EventType::Read, first_server.as_raw_fd()).is_err());
+        let short = TimeoutDuration::Finite(Duration::from_millis(10));
+        assert!(registry.await_events(&short).unwrap().is_empty());
+    }
+
+    /// One-shot interest is reported once and again only after it is re-armed.
+    fn assert_reports_once_until_rearmed(mut registry: Registry) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let short = TimeoutDuration::Finite(Duration::from_millis(10));
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        let reading = registry.add_interest_with(EventType::Read, server.as_raw_fd(), Trigger::OneShot).unwrap();
+        assert_eq!(registry.add_interest(EventType::Write, server.as_raw_fd()).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+
+        client.write_all(b"ping").unwrap();
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(reading, EventType::Read)]);
+        /* data was not read, yet it is not reported again */
+        assert!(registry.await_events(&short).unwrap().is_empty());
+        registry.rearm(reading).unwrap();
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(reading, EventType::Read)]);
+
+        let mut buffer = [0; 16];
+        assert_eq!(server.read(&mut buffer).unwrap(), 4);
+        assert_eq!(server.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        registry.rearm(reading).unwrap();
+        assert!(registry.await_events(&short).unwrap().is_empty());
+        client.write_all(b"pong").unwrap();
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(reading, EventType::Read)]);
+
+        registry.delete_interest(EventType::Read, server.as_raw_fd()).unwrap();
+        assert_eq!(registry.rearm(reading).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    }
+
+    /// Peer that stops writing is reported to the reader as a hang-up, along with the end of data it can read.
+    /// Reset connection is reported to every interest of the descriptor, as an error and a hang-up.
+    #[cfg(target_os = "linux")]
+    fn assert_reports_hangups(mut registry: Registry) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        let reading = registry.add_interest(EventType::Read, server.as_raw_fd()).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let hangup = Event { hangup: true, ..ready(reading, EventType::Read) };
+        assert_eq!(registry.await_events(&long).unwrap(), vec![hangup]);
+
+        let writing = registry.add_interest(EventType::Write, server.as_raw_fd()).unwrap();
+        /* closing a socket with zero linger time resets its connection */
+        let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+        let size = std::mem::size_of::<libc::linger>() as libc::socklen_t;
+        crate::syscall!(setsockopt(client.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER, &linger as *const libc::linger as *const libc::c_void, size)).unwrap();
+        drop(client);
+        let mut failed = HashSet::new();
+        while failed.len() < 2 {
+            let events = registry.await_events(&long).unwrap();
+            failed.extend(events.into_iter().filter(|event| event.error && event.hangup).map(|event| event.token));
+        }
+        assert_eq!(failed, HashSet::from([reading, writing]));
+    }
+
+    /// Modified interest keeps its token and is reported for its new kind of event only.
+    fn assert_modifies_interests(mut registry: Registry) {
+        let (mut first, second) = UnixStream::pair().unwrap();
+        let short = TimeoutDuration::Finite(Duration::from_millis(10));
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        let token = registry.add_interest(EventType::Read, second.as_raw_fd()).unwrap();
+        assert!(registry.await_events(&short).unwrap().is_empty());
+        registry.modify_interest(EventType::Read, second.as_raw_fd(), EventType::Write).unwrap();
+        assert_eq!(registry.interest(token), Some((EventType::Write, second.as_raw_fd())));
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(token, EventType::Write)]);
+        first.write_all(b"ping").unwrap();
+        /* data waits to be read, but nobody is interested in reading it */
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(token, EventType::Write)]);
+        registry.modify_interest(EventType::Write, second.as_raw_fd(), EventType::Read).unwrap();
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(token, EventType::Read)]);
+
+        let writing = registry.add_interest(EventType::Write, second.as_raw_fd()).unwrap();
+        let modified = registry.modify_interest(EventType::Read, second.as_raw_fd(), EventType::Write);
+        assert_eq!(modified.unwrap_err().raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(registry.interest(writing), Some((EventType::Write, second.as_raw_fd())));
+        let modified = registry.modify_interest(EventType::Read, first.as_raw_fd(), EventType::Write);
+        assert_eq!(modified.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        registry.delete_interest(EventType::Write, second.as_raw_fd()).unwrap();
+        registry.delete_interest(EventType::Read, second.as_raw_fd()).unwrap();
+
+        /* modifying a one-shot interest arms it again */
+        let (mut first, second) = UnixStream::pair().unwrap();
+        let reading = registry.add_interest_with(EventType::Read, second.as_raw_fd(), Trigger::OneShot).unwrap();
+        first.write_all(b"ping").unwrap();
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(reading, EventType::Read)]);
+        registry.modify_interest(EventType::Read, second.as_raw_fd(), EventType::Write).unwrap();
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(reading, EventType::Write)]);
+        assert!(registry.await_events(&short).unwrap().is_empty());
+    }
+
+    /// Loop serves datagrams as they come, deletes the interest once it is done and stops on timeout.
+    fn assert_runs_until_broken(mut registry: Registry) {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let reading = registry.add_interest(EventType::Read, receiver.as_raw_fd()).unwrap();
+        for message in [b"one", b"two"] {
+            sender.send_to(message, receiver.local_addr().unwrap()).unwrap();
+        }
+        let mut received = Vec::new();
+        let served = registry.run(&TimeoutDuration::Finite(Duration::from_millis(50)), |registry, event| {
+            let Some(Event { token, readable: true, .. }) = event else {
+                return ControlFlow::Break(received.len());
+            };
+            assert_eq!(token, reading);
+            let mut buffer = [0; 16];
+            let (size, _) = receiver.recv_from(&mut buffer).unwrap();
+            received.push(buffer[..size].to_vec());
+            if received.len() == 2 {
+                registry.delete_interest(EventType::Read, receiver.as_raw_fd()).unwrap();
+            }
+            ControlFlow::Continue(())
+        });
+        assert_eq!(served.unwrap(), 2);
+        assert_eq!(received, [b"one".to_vec(), b"two".to_vec()]);
+    }
+
+    /// Timer is reported once per wait, however many times it expired, and not at all once deleted.
+    #[cfg(target_os = "linux")]
+    fn assert_reports_timers(mut registry: Registry) {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let reading = registry.add_interest(EventType::Read, receiver.as_raw_fd()).unwrap();
+        let timer = registry.add_timer(Duration::from_millis(20)).unwrap();
+        assert_ne!(timer, reading);
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        let started = Instant::now();
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(timer, EventType::Read)]);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(timer, EventType::Read)]);
+        assert!(registry.await_events(&TimeoutDuration::Finite(Duration::from_millis(5))).unwrap().is_empty());
+
+        registry.delete_timer(timer).unwrap();
+        assert_eq!(registry.delete_timer(timer).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        assert!(registry.await_events(&TimeoutDuration::Finite(Duration::from_millis(50))).unwrap().is_empty());
+    }
+
+    /// Signals sent to the thread are reported and told apart, the ones not watched are left pending.
+    #[cfg(target_os = "linux")]
+    fn assert_reports_signals(mut registry: Registry) {
+        let signals = registry.add_signals(&[Signal::User1, Signal::User2]).unwrap();
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        let short = TimeoutDuration::Finite(Duration::from_millis(10));
+        assert!(registry.await_events(&short).unwrap().is_empty());
+        let raise = |signal: Signal| assert_eq!(unsafe { libc::pthread_kill(libc::pthread_self(), signal.number()) }, 0);
+        raise(Signal::User2);
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(signals, EventType::Read)]);
+        /* readiness is reported until the signal is taken */
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(signals, EventType::Read)]);
+        assert_eq!(registry.received_signals(signals).unwrap(), [Signal::User2]);
+        assert!(registry.await_events(&short).unwrap().is_empty());
+
+        raise(Signal::User1);
+        raise(Signal::User2);
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(signals, EventType::Read)]);
+        assert_eq!(registry.received_signals(signals).unwrap(), [Signal::User1, Signal::User2]);
+        registry.delete_signals(signals).unwrap();
+        assert_eq!(registry.received_signals(signals).unwrap_err().raw_os_error(), Some(libc::ENOENT));
+
+        /* only the signals blocked by watching them are unblocked */
+        let is_blocked = |signal: Signal| {
+            let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask) }, 0);
+            unsafe { libc::sigismember(&mask, signal.number()) == 1 }
+        };
+        assert!(!is_blocked(Signal::User1) && !is_blocked(Signal::User2));
+        let _outer = registry.add_signals(&[Signal::User1]).unwrap();
+        let inner = registry.add_signals(&[Signal::User1, Signal::User2]).unwrap();
+        registry.delete_signals(inner).unwrap();
+        assert!(is_blocked(Signal::User1) && !is_blocked(Signal::User2));
+        drop(registry);
+        assert!(!is_blocked(Signal::User1));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_signals_blocked_up_front_stay_blocked() {
+        let is_blocked = |signal: Signal| {
+            let mut mask: libc::sigset_t = unsafe { std::mem::zeroed() };
+            assert_eq!(unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask) }, 0);
+            unsafe { libc::sigismember(&mask, signal.number()) == 1 }
+        };
+        Signal::block(&[Signal::Hangup]).unwrap();
+        /* threads started afterwards do not receive the signal either */
+        assert!(std::thread::spawn(move || is_blocked(Signal::Hangup)).join().unwrap());
+        let mut registry = Registry::new().unwrap();
+        let signals = registry.add_signals(&[Signal::Hangup]).unwrap();
+        registry.delete_signals(signals).unwrap();
+        assert!(is_blocked(Signal::Hangup));
+    }
+
+    /// File of `len` bytes counting up from 0, removed once the returned handle is dropped.
+    #[cfg(target_os = "linux")]
+    fn numbered_file(name: &str, len: usize) -> (std::fs::File, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("reactor-{}-{name}", std::process::id()));
+        let content = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &content).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (file, content)
+    }
+
+    /// Operations wait for their descriptors and are cancelled once their timeout passes,
+    /// readiness of interests reported meanwhile is not lost.
+    #[cfg(target_os = "linux")]
+    fn assert_completes_operations(mut registry: Registry) {
+        use std::io::Seek;
+        let short = TimeoutDuration::Finite(Duration::from_millis(20));
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        let timed_out = |result: io::Result<usize>| matches!(result, Err(err) if err.kind() == io::ErrorKind::TimedOut);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(registry.accept(listener.as_raw_fd(), &short).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        let address = listener.local_addr().unwrap();
+        let connecting = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            TcpStream::connect(address).unwrap()
+        });
+        let server = TcpStream::from(registry.accept(listener.as_raw_fd(), &long).unwrap());
+        let mut client = connecting.join().unwrap();
+
+        let (mut first, second) = UnixStream::pair().unwrap();
+        let reading = registry.add_interest(EventType::Read, second.as_raw_fd()).unwrap();
+        let mut buffer = [0; 16];
+        assert!(timed_out(registry.recv(server.as_raw_fd(), &mut buffer, &short)));
+        first.write_all(b"ping").unwrap();
+        client.write_all(b"ping").unwrap();
+        assert_eq!(registry.recv(server.as_raw_fd(), &mut buffer, &long).unwrap(), 4);
+        assert_eq!(&buffer[..4], b"ping");
+        assert_eq!(registry.await_events(&long).unwrap(), vec![ready(reading, EventType::Read)]);
+        assert_eq!(registry.send(server.as_raw_fd(), b"pong", &long).unwrap(), 4);
+        client.read_exact(&mut buffer[..4]).unwrap();
+        assert_eq!(&buffer[..4], b"pong");
+
+        let (mut file, content) = numbered_file(registry.backend(), 300_000);
+        let receiving = std::thread::spawn(move || {
+            let mut received = vec![0; 300_000 - 1000];
+            client.read_exact(&mut received).unwrap();
+            (client, received)
+        });
+        let mut offset = 1000;
+        while offset < content.len() {
+            offset += registry.send_file(server.as_raw_fd(), file.as_raw_fd(), offset as u64, content.len() - offset, &long).unwrap();
+        }
+        assert_eq!(registry.send_file(server.as_raw_fd(), file.as_raw_fd(), offset as u64, 1, &long).unwrap(), 0);
+        let (client, received) = receiving.join().unwrap();
+        assert!(received == content[1000..], "file was not sent as it is");
+        assert_eq!(file.stream_position().unwrap(), 0);
+
+        /* once nobody reads, sending fails with the timeout, non-blocking socket or not */
+        server.set_nonblocking(true).unwrap();
+        while !timed_out(registry.send(server.as_raw_fd(), &[0; 65536], &short)) {}
+        while !timed_out(registry.send_file(server.as_raw_fd(), file.as_raw_fd(), 0, content.len(), &short)) {}
+        drop(client);
+    }
+
+    fn assert_backend(new: fn() -> Registry) {
+        assert_reports_readiness(new());
+        assert_tells_interests_apart(new());
+        assert_reports_once_until_rearmed(new());
+        assert_modifies_interests(new());
+        assert_runs_until_broken(new());
+        #[cfg(target_os = "linux")]
+        assert_reports_hangups(new());
+        #[cfg(target_os = "linux")]
+        assert_reports_timers(new());
+        #[cfg(target_os = "linux")]
+        assert_reports_signals(new());
+        #[cfg(target_os = "linux")]
+        assert_completes_operations(new());
+    }
+
+    #[test]
+    fn test_default_backend() {
+        assert_backend(|| Registry::new().unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_epoll_backend() {
+        assert_eq!(Registry::with_epoll().unwrap().backend(), "epoll");
+        assert_backend(|| Registry::with_epoll().unwrap());
+    }
+
+    #[test]
+    fn test_poll_backend() {
+        assert_eq!(Registry::with_poll().backend(), "poll");
+        assert_backend(Registry::with_poll);
+    }
+
+    #[cfg(feature = "io-uring")]
+    #[test]
+    fn test_io_uring_backend() {
+        assert_eq!(Registry::with_io_uring().unwrap().backend(), "io_uring");
+        assert_backend(|| Registry::with_io_uring().unwrap());
+    }
+
+    /// Static files served to one client after another, each request accepted, read and answered
+    /// with a head and the file. Run with `cargo test --release --features io-uring -- --ignored --nocapture bench_`.
+    #[cfg(feature = "io-uring")]
+    #[test]
+    #[ignore]
+    fn bench_static_files_epoll_vs_io_uring() {
+        const REQUESTS: usize = 2_000;
+        const FILE_LEN: usize = 256 * 1024;
+        let long = TimeoutDuration::Finite(Duration::from_secs(5));
+        for mut registry in [Registry::with_epoll().unwrap(), Registry::with_io_uring().unwrap()] {
+            let (file, _) = numbered_file(registry.backend(), FILE_LEN);
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let clients = std::thread::spawn(move || {
+                let mut response = Vec::with_capacity(FILE_LEN + 64);
+                for _ in 0..REQUESTS {
+                    let mut client = TcpStream::connect(address).unwrap();
+                    client.write_all(b"GET /file HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+                    response.clear();
+                    client.read_to_end(&mut response).unwrap();
+                    assert!(response.len() > FILE_LEN);
+                }
+            });
+            let started = Instant::now();
+            let mut request = [0; 1024];
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {FILE_LEN}\r\nConnection: close\r\n\r\n");
+            for _ in 0..REQUESTS {
+                let connection = registry.accept(listener.as_raw_fd(), &long).unwrap();
+                registry.recv(connection.as_raw_fd(), &mut request, &long).unwrap();
+                let mut sent = 0;
+                while sent < head.len() {
+                    sent += registry.send(connection.as_raw_fd(), &head.as_bytes()[sent..], &long).unwrap();
+                }
+                let mut offset = 0;
+                while offset < FILE_LEN {
+                    offset += registry.send_file(connection.as_raw_fd(), file.as_raw_fd(), offset as u64, FILE_LEN - offset, &long).unwrap();
+                }
+            }
+            clients.join().unwrap();
+            let elapsed = started.elapsed();
+            let rate = REQUESTS as f64 / elapsed.as_secs_f64();
+            println!("{}: {REQUESTS} files served in {elapsed:?}, {rate:.0} requests/s", registry.backend());
+        }
+    }
+}
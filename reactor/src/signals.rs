@@ -0,0 +1,139 @@
+//! Mikołaj Depta 328690
+//!
+//! Signals received through a descriptor instead of a handler, see `signalfd(2)`.
+//!
+//! Signals read this way have to be blocked, otherwise they are delivered as usual. They are
+//! blocked in the thread that starts watching them, threads it starts later inherit its mask.
+//! Threads started earlier do not, signals sent to the process may still reach them, so programs
+//! block the signals with `Signal::block` before they start any threads. Once the descriptor is
+//! dropped, signals it blocked are unblocked in the thread that drops it, the ones that were
+//! blocked already stay blocked.
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::syscall;
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum Signal {
+    Hangup,
+    Interrupt,
+    Terminate,
+    User1,
+    User2,
+}
+
+impl Signal {
+    const ALL: [Signal; 5] = [Signal::Hangup, Signal::Interrupt, Signal::Terminate, Signal::User1, Signal::User2];
+
+    pub const fn number(&self) -> libc::c_int {
+        match self {
+            Signal::Hangup => libc::SIGHUP,
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+            Signal::User1 => libc::SIGUSR1,
+            Signal::User2 => libc::SIGUSR2,
+        }
+    }
+
+    fn from_number(number: libc::c_int) -> Option<Self> {
+        Self::ALL.into_iter().find(|signal| signal.number() == number)
+    }
+
+    /// Blocks the `signals` in the calling thread, threads it starts later inherit the mask.
+    pub fn block(signals: &[Signal]) -> io::Result<()> {
+        SignalFd::mask(libc::SIG_BLOCK, &Self::set(signals)?, std::ptr::null_mut())
+    }
+
+    fn set(signals: &[Signal]) -> io::Result<libc::sigset_t> {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        syscall!(sigemptyset(&mut set))?;
+        for signal in signals {
+            syscall!(sigaddset(&mut set, signal.number()))?;
+        }
+        Ok(set)
+    }
+}
+
+/// Descriptor that becomes readable once any of its signals is pending.
+#[derive(Debug)]
+pub(crate) struct SignalFd {
+    fd: OwnedFd,
+    /// Signals that were not blocked before, they are unblocked on drop.
+    blocked: Vec<Signal>,
+}
+
+impl SignalFd {
+    /// Blocks the `signals` in the calling thread and starts watching them.
+    pub(crate) fn new(signals: &[Signal]) -> io::Result<Self> {
+        let set = Signal::set(signals)?;
+        let mut previous: libc::sigset_t = unsafe { mem::zeroed() };
+        Self::mask(libc::SIG_BLOCK, &set, &mut previous)?;
+        let blocked = signals
+            .iter()
+            .copied()
+            .filter(|signal| unsafe { libc::sigismember(&previous, signal.number()) } == 0)
+            .collect::<Vec<_>>();
+        let fd = match syscall!(signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)) {
+            Ok(fd) => fd,
+            Err(err) => {
+                Self::unblock(&blocked);
+                return Err(err);
+            }
+        };
+        // safety: descriptor was just created and is owned by nothing else.
+        Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(fd) }, blocked })
+    }
+
+    /// Changes the signal mask of the calling thread with `how`, the previous one is stored in `previous`.
+    fn mask(how: libc::c_int, set: &libc::sigset_t, previous: *mut libc::sigset_t) -> io::Result<()> {
+        /* unlike the other calls pthread_sigmask returns the error instead of setting errno */
+        let result = unsafe { libc::pthread_sigmask(how, set, previous) };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::from_raw_os_error(result)),
+        }
+    }
+
+    /// Unblocks the `signals` in the calling thread, signals pending meanwhile are delivered as usual.
+    fn unblock(signals: &[Signal]) {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        for signal in signals {
+            unsafe { libc::sigaddset(&mut set, signal.number()) };
+        }
+        /* signal numbers are valid, so this cannot fail */
+        let _ = Self::mask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+    }
+
+    /// Signals pending since the last call, in the order they were received.
+    pub(crate) fn received(&self) -> io::Result<Vec<Signal>> {
+        let mut signals = Vec::new();
+        loop {
+            let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+            let read = syscall!(read(
+                self.fd.as_raw_fd(),
+                &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                mem::size_of::<libc::signalfd_siginfo>(),
+            ));
+            match read {
+                Ok(_) => signals.extend(Signal::from_number(info.ssi_signo as libc::c_int)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(signals),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        Self::unblock(&self.blocked);
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,82 @@
+//! Mikołaj Depta 328690
+//!
+//! Timers whose descriptors are watched like any other, see `timerfd_create(2)`.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+use crate::syscall;
+
+/// Timer whose descriptor becomes readable every time it expires.
+#[derive(Debug)]
+pub struct Timer {
+    fd: OwnedFd,
+}
+
+impl Timer {
+    /// Timer whose descriptor becomes readable every `interval`.
+    pub fn periodic(interval: Duration) -> io::Result<Self> {
+        /* zero would disarm the timer instead of expiring it right away */
+        let interval = interval.max(Duration::from_nanos(1));
+        Self::new(interval, interval)
+    }
+
+    /// Timer whose descriptor becomes readable once, after `delay`.
+    pub fn once(delay: Duration) -> io::Result<Self> {
+        Self::new(delay.max(Duration::from_nanos(1)), Duration::ZERO)
+    }
+
+    fn new(first: Duration, interval: Duration) -> io::Result<Self> {
+        let fd = syscall!(timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_NONBLOCK | libc::TFD_CLOEXEC))?;
+        // safety: descriptor was just created and is owned by nothing else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let timespec = |duration: Duration| libc::timespec {
+            tv_sec: duration.as_secs() as libc::time_t,
+            tv_nsec: duration.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec { it_interval: timespec(interval), it_value: timespec(first) };
+        syscall!(timerfd_settime(fd.as_raw_fd(), 0, &spec, std::ptr::null_mut()))?;
+        Ok(Self { fd })
+    }
+
+    /// Consumes the expirations since the last call, returns how many there were.
+    pub fn acknowledge(&self) -> io::Result<u64> {
+        let mut expirations = 0u64;
+        let read = syscall!(read(
+            self.fd.as_raw_fd(),
+            &mut expirations as *mut u64 as *mut libc::c_void,
+            std::mem::size_of::<u64>(),
+        ));
+        match read {
+            Ok(_) => Ok(expirations),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_timer_expires_periodically() {
+        let timer = Timer::periodic(Duration::from_millis(10)).unwrap();
+        assert_eq!(timer.acknowledge().unwrap(), 0);
+        thread::sleep(Duration::from_millis(35));
+        assert!(timer.acknowledge().unwrap() >= 2);
+        assert_eq!(timer.acknowledge().unwrap(), 0);
+
+        let timer = Timer::once(Duration::from_millis(10)).unwrap();
+        thread::sleep(Duration::from_millis(35));
+        assert_eq!(timer.acknowledge().unwrap(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+//! Mikołaj Depta 328690
+//!
+//! io_uring event backend, enabled with the `io-uring` feature.
+//!
+//! Readiness is awaited with one-shot `POLL_ADD` operations, one per registered interest.
+//! Completed polls are submitted again on the next wait, so a descriptor that is still
+//! ready is reported again, the same way level-triggered epoll does. Polls of one-shot
+//! interests are only submitted again once they are re-armed.
+//!
+//! Accepting, receiving, sending and sending files, see `Registry::accept` and the rest, are submitted
+//! to the ring as operations of their own and the registry waits for them to complete. Each one
+//! is linked with a timeout that cancels it. Cancelled operation is still waited for until its
+//! completion is reaped, the kernel may be writing into the caller's buffer until then. Files are sent
+//! by splicing them to a pipe and from the pipe to the socket, the same way sendfile(2) does.
+//! Descriptors that are non-blocking make operations fail with `EAGAIN` rather than wait,
+//! for them the descriptor is polled through the ring and the operation submitted again.
+//! Polls of interests that complete in the meantime are kept for the next wait.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::time::Instant;
+
+use io_uring::{opcode, squeue, types, IoUring};
+use crate::operations::Operation;
+use crate::registry::{Event, EventType, TimeoutDuration, Token, Trigger};
+use crate::syscall;
+
+struct Interest {
+    token: Token,
+    fd: RawFd,
+    event_type: EventType,
+    trigger: Trigger,
+    /// Poll for the interest is submitted and has not completed yet.
+    armed: bool,
+    /// Polls cancelled by modifying the interest, their completions are still to come and are ignored.
+    cancelled: usize,
+}
+
+pub(crate) struct Uring {
+    ring: IoUring,
+    /// Interests by the user data of their poll operations, the values of their tokens.
+    interests: HashMap<u64, Interest>,
+    /// Interests whose polls completed while an operation was awaited, reported by the next wait.
+    ready: Vec<Event>,
+    /// Result of the operation being awaited, once it completed.
+    completed: Option<i32>,
+    /// Reading and writing end of the pipe files are spliced through, made by the first `send_file`.
+    pipe: Option<(OwnedFd, OwnedFd)>,
+}
+
+impl Uring {
+    const ENTRIES: u32 = 64;
+    /// User data of operations cancelling polls and of timeouts of operations, their completions are ignored.
+    const CANCEL_KEY: u64 = u64::MAX;
+    /// User data of the operation being awaited, tokens do not get anywhere near it.
+    const OPERATION_KEY: u64 = u64::MAX - 1;
+    /// Default capacity of a pipe, the most a file is spliced in one go.
+    const PIPE_CAPACITY: usize = 64 * 1024;
+
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Self {
+            ring: IoUring::new(Self::ENTRIES)?,
+            interests: HashMap::new(),
+            ready: Vec::new(),
+            completed: None,
+            pipe: None,
+        })
+    }
+
+    pub(crate) fn add_interest(&mut self, token: Token, event_type: EventType, fd: RawFd, trigger: Trigger) -> io::Result<()> {
+        let key = token.get();
+        self.interests.insert(key, Interest { token, fd, event_type, trigger, armed: false, cancelled: 0 });
+        self.arm(key)
+    }
+
+    /// Submits the poll of a one-shot interest again, unless it is still pending.
+    pub(crate) fn rearm(&mut self, token: Token) -> io::Result<()> {
+        let key = token.get();
+        let interest = self.interests.get(&key).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        match interest.armed {
+            true => Ok(()),
+            false => self.arm(key),
+        }
+    }
+
+    /// Cancels the pending poll of the interest and submits one for the `event_type` instead.
+    /// Poll being cancelled still completes with the same user data, before the new one does.
+    pub(crate) fn modify_interest(&mut self, token: Token, event_type: EventType) -> io::Result<()> {
+        let key = token.get();
+        let interest = self.interests.get_mut(&key).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        interest.event_type = event_type;
+        if interest.armed {
+            interest.cancelled += 1;
+            let cancel = opcode::PollRemove::new(key).build().user_data(Self::CANCEL_KEY);
+            self.push(&cancel)?;
+        }
+        self.arm(key)
+    }
+
+    pub(crate) fn delete_interest(&mut self, token: Token) -> io::Result<()> {
+        let key = token.get();
+        let interest = self.interests.remove(&key).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        if interest.armed {
+            let cancel = opcode::PollRemove::new(key).build().user_data(Self::CANCEL_KEY);
+            self.push(&cancel)?;
+        }
+        /* cancellation has to reach the kernel before the descriptor is closed by the caller */
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Submits the `operation` on `fd` and waits for it to complete, see `operations::complete`.
+    pub(crate) fn complete(&mut self, fd: RawFd, operation: Operation, timeout: &TimeoutDuration) -> io::Result<usize> {
+        let started = Instant::now();
+        let event_type = operation.event_type();
+        let target = types::Fd(fd);
+        match operation {
+            Operation::Accept => {
+                let accept = || opcode::Accept::new(target, ptr::null_mut(), ptr::null_mut()).flags(libc::SOCK_CLOEXEC).build();
+                self.perform(fd, event_type, accept, timeout, started)
+            }
+            Operation::Recv(buffer) => {
+                let (data, len) = (buffer.as_mut_ptr(), Self::operation_len(buffer.len()));
+                self.perform(fd, event_type, || opcode::Recv::new(target, data, len).build(), timeout, started)
+            }
+            Operation::Send(buffer) => {
+                let (data, len) = (buffer.as_ptr(), Self::operation_len(buffer.len()));
+                let send = || opcode::Send::new(target, data, len).flags(libc::MSG_NOSIGNAL).build();
+                self.perform(fd, event_type, send, timeout, started)
+            }
+            Operation::SendFile { file, offset, count } => self.splice_file(fd, file, offset, count, timeout, started),
+        }
+    }
+
+    /// Splices up to `count` bytes of the `file` into the pipe, then all of them from the pipe to the socket `fd`.
+    /// Once a part of the file was sent its length is the result, even if the rest failed or timed out.
+    fn splice_file(
+        &mut self,
+        fd: RawFd,
+        file: RawFd,
+        offset: u64,
+        count: usize,
+        timeout: &TimeoutDuration,
+        started: Instant,
+    ) -> io::Result<usize> {
+        let (pipe_out, pipe_in) = match &self.pipe {
+            Some((pipe_out, pipe_in)) => (pipe_out.as_raw_fd(), pipe_in.as_raw_fd()),
+            None => {
+                let mut fds = [0; 2];
+                syscall!(pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC))?;
+                // safety: both ends of the pipe were just made and are owned by nothing else.
+                let pipe = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+                self.pipe = Some(pipe);
+                (fds[0], fds[1])
+            }
+        };
+        let len = count.min(Self::PIPE_CAPACITY) as u32;
+        let fill = || opcode::Splice::new(types::Fd(file), offset as i64, types::Fd(pipe_in), -1, len).build();
+        let filled = self.perform(pipe_in, EventType::Write, fill, timeout, started)?;
+        let mut sent = 0;
+        while sent < filled {
+            let len = (filled - sent) as u32;
+            let drain = || opcode::Splice::new(types::Fd(pipe_out), -1, types::Fd(fd), -1, len).build();
+            match self.perform(fd, EventType::Write, drain, timeout, started) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(count) => sent += count,
+                Err(err) => {
+                    /* what is left in the pipe would be sent ahead of the next file */
+                    self.pipe = None;
+                    return if sent > 0 { Ok(sent) } else { Err(err) };
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Submits the operation made by `entry` until it stops failing with `EAGAIN`, in between polls `fd`
+    /// for `event_type`. Gives up with `TimedOut` once the `timeout` since `started` is over.
+    fn perform(
+        &mut self,
+        fd: RawFd,
+        event_type: EventType,
+        entry: impl Fn() -> squeue::Entry,
+        timeout: &TimeoutDuration,
+        started: Instant,
+    ) -> io::Result<usize> {
+        loop {
+            let remaining = timeout.saturating_sub(started.elapsed());
+            match self.submit_operation(entry(), &remaining)? {
+                result if result >= 0 => return Ok(result as usize),
+                result if -result == libc::EINTR => {}
+                result if -result == libc::EAGAIN => {
+                    let poll = opcode::PollAdd::new(types::Fd(fd), event_type.poll_flags() as u32).build();
+                    let polled = self.submit_operation(poll, &remaining)?;
+                    if polled < 0 {
+                        return Err(Self::operation_error(polled));
+                    }
+                }
+                result => return Err(Self::operation_error(result)),
+            }
+        }
+    }
+
+    /// Submits the `entry` linked with a timeout and waits for it to complete, cancelled or not.
+    /// Result is the one the operation completed with, a negated errno if it failed.
+    fn submit_operation(&mut self, entry: squeue::Entry, timeout: &TimeoutDuration) -> io::Result<i32> {
+        let entry = entry.user_data(Self::OPERATION_KEY);
+        /* the kernel reads the timespec when the timeout is submitted, below */
+        let timespec;
+        match timeout {
+            TimeoutDuration::Infinite => self.push_all(&[entry])?,
+            TimeoutDuration::Finite(duration) => {
+                timespec = types::Timespec::from(*duration);
+                let timeout = opcode::LinkTimeout::new(&timespec).build().user_data(Self::CANCEL_KEY);
+                self.push_all(&[entry.flags(squeue::Flags::IO_LINK), timeout])?;
+            }
+        }
+        self.completed = None;
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(err) if matches!(err.raw_os_error(), Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)) => {}
+                /* returning would free the buffer the kernel may still be writing into */
+                Err(_) => std::process::abort(),
+            }
+            self.reap();
+            if let Some(result) = self.completed.take() {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Operations are cancelled only by their timeouts.
+    fn operation_error(result: i32) -> io::Error {
+        match -result {
+            libc::ECANCELED => io::Error::from(io::ErrorKind::TimedOut),
+            errno => io::Error::from_raw_os_error(errno),
+        }
+    }
+
+    /// Length of a buffer as operations take it, longer ones are transferred in part.
+    fn operation_len(len: usize) -> u32 {
+        len.min(u32::MAX as usize) as u32
+    }
+
+    /// Re-arms completed polls and waits for at least one of them to complete.
+    /// Completions of cancelled or failed polls do not end the wait.
+    pub(crate) fn wait(&mut self, timeout: &TimeoutDuration) -> io::Result<Vec<Event>> {
+        let deadline = match timeout {
+            TimeoutDuration::Infinite => None,
+            TimeoutDuration::Finite(duration) => Some(Instant::now() + *duration),
+        };
+        loop {
+            if !self.ready.is_empty() {
+                return Ok(mem::take(&mut self.ready));
+            }
+            self.arm_completed()?;
+            let submitted = match deadline {
+                None => self.ring.submit_and_wait(1),
+                Some(deadline) => {
+                    let timespec = types::Timespec::from(deadline.saturating_duration_since(Instant::now()));
+                    let args = types::SubmitArgs::new().timespec(&timespec);
+                    self.ring.submitter().submit_with_args(1, &args)
+                }
+            };
+            match submitted {
+                Ok(_) => {}
+                Err(err) if err.raw_os_error() == Some(libc::ETIME) => return Ok(Vec::new()),
+                Err(err) => return Err(err),
+            }
+            self.reap();
+            if !self.ready.is_empty() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(mem::take(&mut self.ready));
+            }
+        }
+    }
+
+    fn arm_completed(&mut self) -> io::Result<()> {
+        let disarmed = self.interests
+            .iter()
+            .filter(|(_, interest)| !interest.armed && interest.trigger == Trigger::Level)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in disarmed {
+            self.arm(key)?;
+        }
+        Ok(())
+    }
+
+    /// Marks completed polls as disarmed, keeps the interests that became ready and the result of the operation.
+    /// Result of a completed poll is the mask of events that ended it, the same as poll(2) reports.
+    fn reap(&mut self) {
+        for completion in self.ring.completion() {
+            if completion.user_data() == Self::OPERATION_KEY {
+                self.completed = Some(completion.result());
+                continue;
+            }
+            let Some(interest) = self.interests.get_mut(&completion.user_data()) else { continue };
+            if interest.cancelled > 0 {
+                interest.cancelled -= 1;
+                continue;
+            }
+            interest.armed = false;
+            if completion.result() >= 0 {
+                self.ready.extend(Event::from_poll_flags(interest.token, interest.event_type, completion.result() as libc::c_short));
+            }
+        }
+    }
+
+    fn arm(&mut self, key: u64) -> io::Result<()> {
+        let interest = self.interests.get_mut(&key).expect("interest is registered");
+        let poll = opcode::PollAdd::new(types::Fd(interest.fd), interest.event_type.poll_flags() as u32)
+            .build()
+            .user_data(key);
+        interest.armed = true;
+        self.push(&poll)
+    }
+
+    fn push(&mut self, entry: &squeue::Entry) -> io::Result<()> {
+        self.push_all(std::slice::from_ref(entry))
+    }
+
+    /// Queues the `entries` together, so linked ones are submitted at once.
+    fn push_all(&mut self, entries: &[squeue::Entry]) -> io::Result<()> {
+        let room = {
+            let queue = self.ring.submission();
+            queue.capacity() - queue.len()
+        };
+        if room < entries.len() {
+            self.ring.submit()?;
+        }
+        // safety: polls reference no memory of the process, operations reference buffers their callers
+        // borrow until the operation completes, see `submit_operation`.
+        unsafe { self.ring.submission().push_multiple(entries) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))
+    }
+}
@@ -0,0 +1,709 @@
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::num::ParseIntError;
+
+use crate::network::ParseNetworkError;
+use crate::route::{Distance, Network, ParseRouteError, Route};
+use crate::routing_table::{ConnectionType, RoutingTable};
+
+/// Single address assigned to the network interface together with the directly connected
+/// network it belongs to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InterfaceAddress {
+    pub address: Ipv4Addr,
+    pub network: Network,
+    pub distance: Distance,
+}
+
+/// Expected input format:
+/// <ipv4 address>/<integer from range 0..=32> distance <integer distance value>
+impl TryFrom<&[&str]> for InterfaceAddress {
+    type Error = ParseRouteError;
+
+    fn try_from(tokens: &[&str]) -> Result<Self, Self::Error> {
+        let (network_repr, distance_repr) = match tokens {
+            [network_repr, "distance", distance_repr] => (*network_repr, *distance_repr),
+            _ => return Err(ParseRouteError::InvalidFormat(tokens.join(" "))),
+        };
+        let network = Network::try_from(network_repr)?;
+        let (address_repr, _) = network_repr
+            .split_once('/')
+            .ok_or_else(|| ParseNetworkError::SubNetMaskMissing(network_repr.to_owned()))?;
+        let address = address_repr.parse().map_err(ParseNetworkError::from)?;
+        let distance = Distance::try_from(distance_repr)?;
+        Ok(Self { address, network, distance })
+    }
+}
+
+/// Configuration of a single network interface.
+///
+/// Interface has at least one address. Every address on the line attaches another
+/// directly connected network to the same interface.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InterfaceConfig {
+    addresses: Vec<InterfaceAddress>,
+}
+
+impl InterfaceConfig {
+    pub fn addresses(&self) -> &[InterfaceAddress] {
+        &self.addresses
+    }
+}
+
+/// Expected input format - one or more address groups separated with whitespace:
+/// <ipv4 address>/<mask> distance <distance> [<ipv4 address>/<mask> distance <distance> ...]
+impl TryFrom<&str> for InterfaceConfig {
+    type Error = ParseRouteError;
+
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if tokens.is_empty() || tokens.len() % Self::GROUP_LEN != 0 {
+            return Err(ParseRouteError::InvalidFormat(line.to_owned()));
+        }
+        let addresses = tokens
+            .chunks(Self::GROUP_LEN)
+            .map(InterfaceAddress::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { addresses })
+    }
+}
+
+impl InterfaceConfig {
+    const GROUP_LEN: usize = 3;
+}
+
+/// Route configured by the administrator instead of being learned from the neighbours.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StaticRoute {
+    pub network: Network,
+    pub connection_type: ConnectionType,
+}
+
+impl StaticRoute {
+    const BLACK_HOLE_KEYWORD: &'static str = "blackhole";
+    const REJECT_KEYWORD: &'static str = "reject";
+
+    fn is_static_route(line: &str) -> bool {
+        matches!(
+            line.split_whitespace().next(),
+            Some(Self::BLACK_HOLE_KEYWORD | Self::REJECT_KEYWORD)
+        )
+    }
+}
+
+/// Expected input format:
+/// blackhole <ipv4 address>/<mask>
+/// reject <ipv4 address>/<mask>
+impl TryFrom<&str> for StaticRoute {
+    type Error = ParseRouteError;
+
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let (connection_type, network_repr) = match tokens.as_slice() {
+            [Self::BLACK_HOLE_KEYWORD, network_repr] => (ConnectionType::BlackHole, *network_repr),
+            [Self::REJECT_KEYWORD, network_repr] => (ConnectionType::Reject, *network_repr),
+            _ => return Err(ParseRouteError::InvalidFormat(line.to_owned())),
+        };
+        let network = Network::try_from(network_repr)?;
+        Ok(Self { network, connection_type })
+    }
+}
+
+impl Display for StaticRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let keyword = match self.connection_type {
+            ConnectionType::Reject => Self::REJECT_KEYWORD,
+            _ => Self::BLACK_HOLE_KEYWORD,
+        };
+        write!(f, "{} {}", keyword, self.network)
+    }
+}
+
+/// Route learned from a neighbour in a previous run, as written by the `export-routes` control command.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LearnedRoute {
+    pub network: Network,
+    pub distance: Distance,
+    pub next_hop: Ipv4Addr,
+}
+
+impl LearnedRoute {
+    const KEYWORD: &'static str = "route";
+    const VIA_KEYWORD: &'static str = "via";
+
+    fn is_learned_route(line: &str) -> bool {
+        line.split_whitespace().next() == Some(Self::KEYWORD)
+    }
+}
+
+/// Expected input format:
+/// route <ipv4 address>/<mask> distance <distance> via <next hop ipv4 address>
+impl TryFrom<&str> for LearnedRoute {
+    type Error = ParseRouteError;
+
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let [Self::KEYWORD, network_repr, "distance", distance_repr, Self::VIA_KEYWORD, next_hop_repr] = tokens.as_slice() else {
+            return Err(ParseRouteError::InvalidFormat(line.to_owned()));
+        };
+        let network = Network::try_from(*network_repr)?;
+        let distance = Distance::try_from(*distance_repr)?;
+        let next_hop = next_hop_repr.parse().map_err(ParseNetworkError::from)?;
+        Ok(Self { network, distance, next_hop })
+    }
+}
+
+impl Display for LearnedRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{} {} distance {} {} {}",
+            Self::KEYWORD, self.network, u32::from(self.distance), Self::VIA_KEYWORD, self.next_hop
+        )
+    }
+}
+
+/// Penalty added to the distance of every route learned from the neighbour.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MetricOffset {
+    pub neighbour: Ipv4Addr,
+    pub offset: u32,
+}
+
+impl MetricOffset {
+    const KEYWORD: &'static str = "offset";
+
+    fn is_metric_offset(line: &str) -> bool {
+        line.split_whitespace().next() == Some(Self::KEYWORD)
+    }
+}
+
+/// Expected input format:
+/// offset <neighbour ipv4 address> <non-negative integer, optionally prefixed with +>
+impl TryFrom<&str> for MetricOffset {
+    type Error = ParseRouteError;
+
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        let invalid = || ParseRouteError::InvalidFormat(line.to_owned());
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let [Self::KEYWORD, neighbour_repr, offset_repr] = tokens.as_slice() else {
+            return Err(invalid());
+        };
+        let neighbour = neighbour_repr.parse().map_err(ParseNetworkError::from)?;
+        let offset = offset_repr.parse().map_err(|_| invalid())?;
+        Ok(Self { neighbour, offset })
+    }
+}
+
+/// Validated router configuration.
+#[derive(Debug, Default)]
+pub struct RouterConfig {
+    interfaces: Vec<InterfaceConfig>,
+    static_routes: Vec<StaticRoute>,
+    learned_routes: Vec<LearnedRoute>,
+    metric_offsets: Vec<MetricOffset>,
+}
+
+impl RouterConfig {
+    pub fn interfaces(&self) -> &[InterfaceConfig] {
+        &self.interfaces
+    }
+
+    pub fn static_routes(&self) -> &[StaticRoute] {
+        &self.static_routes
+    }
+
+    pub fn learned_routes(&self) -> &[LearnedRoute] {
+        &self.learned_routes
+    }
+
+    pub fn metric_offsets(&self) -> &[MetricOffset] {
+        &self.metric_offsets
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item=&InterfaceAddress> + '_ {
+        self.interfaces.iter().flat_map(InterfaceConfig::addresses)
+    }
+
+    /// Routes to all directly connected networks.
+    pub fn direct_routes(&self) -> Vec<Route> {
+        self.addresses()
+            .map(|interface| Route::new(interface.network, interface.distance))
+            .collect()
+    }
+
+    /// Cross validation of already parsed interfaces, routes and metric offsets.
+    /// All are paired with their line numbers.
+    fn validate(
+        interfaces: &[(usize, InterfaceConfig)],
+        static_routes: &[(usize, StaticRoute)],
+        learned_routes: &[(usize, LearnedRoute)],
+        metric_offsets: &[(usize, MetricOffset)],
+        errors: &mut Vec<ConfigError>,
+    ) {
+        for (line, interface) in interfaces {
+            for &InterfaceAddress { address, network, .. } in interface.addresses() {
+                if !network.is_host_address(address) {
+                    errors.push(ConfigError::AddressOutsideNetwork { line: *line, address, network });
+                }
+            }
+        }
+
+        let networks = interfaces
+            .iter()
+            .flat_map(|(line, interface)| {
+                interface.addresses().iter().map(move |address| (*line, address.network))
+            })
+            .chain(static_routes.iter().map(|(line, route)| (*line, route.network)));
+        let mut seen: Vec<(usize, Network)> = Vec::new();
+        for (line, network) in networks {
+            match seen.iter().find(|(_, other)| other.overlaps(&network)) {
+                Some(&(first_line, other)) if other == network => {
+                    errors.push(ConfigError::DuplicateNetwork { line, network, first_line });
+                }
+                Some(&(other_line, other)) => {
+                    errors.push(ConfigError::OverlappingNetworks { line, network, other_line, other });
+                }
+                None => seen.push((line, network)),
+            }
+        }
+
+        let is_neighbour = |neighbour: Ipv4Addr| interfaces
+            .iter()
+            .flat_map(|(_, interface)| interface.addresses())
+            .any(|address| address.network.is_host_address(neighbour) && address.address != neighbour);
+
+        /* learned networks may lie within the configured ones, only the same network twice is an error */
+        for &(line, LearnedRoute { network, next_hop, .. }) in learned_routes {
+            if !is_neighbour(next_hop) {
+                errors.push(ConfigError::UnknownNeighbour { line, neighbour: next_hop });
+            }
+            if let Some(&(first_line, _)) = seen.iter().find(|(_, other)| *other == network) {
+                errors.push(ConfigError::DuplicateNetwork { line, network, first_line });
+            } else {
+                seen.push((line, network));
+            }
+        }
+
+        for (index, &(line, MetricOffset { neighbour, .. })) in metric_offsets.iter().enumerate() {
+            if !is_neighbour(neighbour) {
+                errors.push(ConfigError::UnknownNeighbour { line, neighbour });
+            }
+            if let Some(&(first_line, _)) = metric_offsets[..index].iter().find(|(_, other)| other.neighbour == neighbour) {
+                errors.push(ConfigError::DuplicateMetricOffset { line, neighbour, first_line });
+            }
+        }
+    }
+}
+
+/// Expected input format:
+/// ```text
+/// <interface count>
+/// <interface configuration>
+/// ...
+/// [<static route>
+/// ...]
+/// [<learned route>
+/// ...]
+/// [<metric offset>
+/// ...]
+/// ```
+/// Static routes, learned routes and metric offsets are not included in the interface count
+/// and may appear on any line after it.
+/// Parsing does not stop at the first problem, all of them are reported together.
+impl TryFrom<&str> for RouterConfig {
+    type Error = ParseRouterConfigError;
+
+    fn try_from(repr: &str) -> Result<Self, Self::Error> {
+        let mut errors = Vec::new();
+        let mut lines = repr
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line))
+            .filter(|(_, line)| !line.trim().is_empty());
+
+        let declared_count = match lines.next() {
+            Some((_, line)) => match line.trim().parse::<usize>() {
+                Ok(count) => Some(count),
+                Err(err) => {
+                    errors.push(ConfigError::InvalidInterfaceCount(err));
+                    None
+                }
+            },
+            None => {
+                errors.push(ConfigError::InterfaceCountMissing);
+                None
+            }
+        };
+
+        let mut interfaces = Vec::new();
+        let mut static_routes = Vec::new();
+        let mut learned_routes = Vec::new();
+        let mut metric_offsets = Vec::new();
+        let mut found = 0;
+        for (line, repr) in lines {
+            if StaticRoute::is_static_route(repr) {
+                match StaticRoute::try_from(repr) {
+                    Ok(route) => static_routes.push((line, route)),
+                    Err(err) => errors.push(ConfigError::InvalidStaticRoute { line, err }),
+                }
+                continue;
+            }
+            if LearnedRoute::is_learned_route(repr) {
+                match LearnedRoute::try_from(repr) {
+                    Ok(route) => learned_routes.push((line, route)),
+                    Err(err) => errors.push(ConfigError::InvalidLearnedRoute { line, err }),
+                }
+                continue;
+            }
+            if MetricOffset::is_metric_offset(repr) {
+                match MetricOffset::try_from(repr) {
+                    Ok(offset) => metric_offsets.push((line, offset)),
+                    Err(err) => errors.push(ConfigError::InvalidMetricOffset { line, err }),
+                }
+                continue;
+            }
+            found += 1;
+            match InterfaceConfig::try_from(repr) {
+                Ok(interface) => interfaces.push((line, interface)),
+                Err(err) => errors.push(ConfigError::InvalidInterface { line, err }),
+            }
+        }
+        if let Some(declared) = declared_count {
+            if declared != found {
+                errors.push(ConfigError::InterfaceCountMismatch { declared, found });
+            }
+        }
+
+        Self::validate(&interfaces, &static_routes, &learned_routes, &metric_offsets, &mut errors);
+        if errors.is_empty() {
+            Ok(Self {
+                interfaces: interfaces.into_iter().map(|(_, interface)| interface).collect(),
+                static_routes: static_routes.into_iter().map(|(_, route)| route).collect(),
+                learned_routes: learned_routes.into_iter().map(|(_, route)| route).collect(),
+                metric_offsets: metric_offsets.into_iter().map(|(_, offset)| offset).collect(),
+            })
+        } else {
+            Err(ParseRouterConfigError(errors))
+        }
+    }
+}
+
+/// Static and learned routes of the routing table, in the format of the configuration lines.
+///
+/// Table exported from one run can seed the next one, either through the control socket or appended
+/// to the configuration, and exports of different runs can be compared with standard tools.
+/// Directly connected networks are left out, they follow from the interfaces of each run, and so are
+/// unreachable learned routes, which are on their way out of the table.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct TableRoutes {
+    static_routes: Vec<StaticRoute>,
+    learned_routes: Vec<LearnedRoute>,
+}
+
+impl TableRoutes {
+    /// Routes of the `table`, ordered by network so exports are stable.
+    pub fn of(table: &RoutingTable) -> Self {
+        let mut routes = Self::default();
+        for (network, distance, connection_type) in table.routes() {
+            match connection_type {
+                ConnectionType::BlackHole | ConnectionType::Reject => {
+                    routes.static_routes.push(StaticRoute { network, connection_type })
+                }
+                ConnectionType::Via(next_hop) if distance != Distance::Infinite => {
+                    routes.learned_routes.push(LearnedRoute { network, distance, next_hop })
+                }
+                _ => {}
+            }
+        }
+        let key = |network: &Network| (u32::from(network.prefix()), u8::from(network.subnet_mask()));
+        routes.static_routes.sort_by_key(|route| key(&route.network));
+        routes.learned_routes.sort_by_key(|route| key(&route.network));
+        routes
+    }
+
+    pub fn static_routes(&self) -> &[StaticRoute] {
+        &self.static_routes
+    }
+
+    pub fn learned_routes(&self) -> &[LearnedRoute] {
+        &self.learned_routes
+    }
+
+    pub fn len(&self) -> usize {
+        self.static_routes.len() + self.learned_routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Expected input format - static and learned route lines of the configuration in any order,
+/// empty lines are skipped. Every network can appear only once.
+impl TryFrom<&str> for TableRoutes {
+    type Error = ParseRouterConfigError;
+
+    fn try_from(repr: &str) -> Result<Self, Self::Error> {
+        let mut errors = Vec::new();
+        let mut routes = Self::default();
+        let mut seen: Vec<(usize, Network)> = Vec::new();
+        let lines = repr
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line))
+            .filter(|(_, line)| !line.trim().is_empty());
+        for (line, repr) in lines {
+            let network = if StaticRoute::is_static_route(repr) {
+                match StaticRoute::try_from(repr) {
+                    Ok(route) => {
+                        routes.static_routes.push(route);
+                        route.network
+                    }
+                    Err(err) => {
+                        errors.push(ConfigError::InvalidStaticRoute { line, err });
+                        continue;
+                    }
+                }
+            } else if LearnedRoute::is_learned_route(repr) {
+                match LearnedRoute::try_from(repr) {
+                    Ok(route) => {
+                        routes.learned_routes.push(route);
+                        route.network
+                    }
+                    Err(err) => {
+                        errors.push(ConfigError::InvalidLearnedRoute { line, err });
+                        continue;
+                    }
+                }
+            } else {
+                errors.push(ConfigError::NotARoute { line });
+                continue;
+            };
+            match seen.iter().find(|(_, other)| *other == network) {
+                Some(&(first_line, _)) => errors.push(ConfigError::DuplicateNetwork { line, network, first_line }),
+                None => seen.push((line, network)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(routes)
+        } else {
+            Err(ParseRouterConfigError(errors))
+        }
+    }
+}
+
+impl Display for TableRoutes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for route in &self.static_routes {
+            writeln!(f, "{}", route)?;
+        }
+        for route in &self.learned_routes {
+            writeln!(f, "{}", route)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    InterfaceCountMissing,
+    InvalidInterfaceCount(ParseIntError),
+    InterfaceCountMismatch { declared: usize, found: usize },
+    InvalidInterface { line: usize, err: ParseRouteError },
+    InvalidStaticRoute { line: usize, err: ParseRouteError },
+    InvalidLearnedRoute { line: usize, err: ParseRouteError },
+    InvalidMetricOffset { line: usize, err: ParseRouteError },
+    NotARoute { line: usize },
+    AddressOutsideNetwork { line: usize, address: Ipv4Addr, network: Network },
+    DuplicateNetwork { line: usize, network: Network, first_line: usize },
+    OverlappingNetworks { line: usize, network: Network, other_line: usize, other: Network },
+    UnknownNeighbour { line: usize, neighbour: Ipv4Addr },
+    DuplicateMetricOffset { line: usize, neighbour: Ipv4Addr, first_line: usize },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::InterfaceCountMissing => {
+                write!(f, "router configuration line missing: no network interface count specified")
+            }
+            ConfigError::InvalidInterfaceCount(err) => {
+                write!(f, "invalid network interface count: {}", err)
+            }
+            ConfigError::InterfaceCountMismatch { declared, found } => {
+                write!(f, "declared {} network interfaces, found {}", declared, found)
+            }
+            ConfigError::InvalidInterface { line, err }
+            | ConfigError::InvalidStaticRoute { line, err }
+            | ConfigError::InvalidLearnedRoute { line, err }
+            | ConfigError::InvalidMetricOffset { line, err } => {
+                write!(f, "line {}: {}", line, err)
+            }
+            ConfigError::NotARoute { line } => {
+                write!(f, "line {}: expected a static or learned route", line)
+            }
+            ConfigError::AddressOutsideNetwork { line, address, network } => {
+                write!(f, "line {}: {} is not a host address in network {}", line, address, network)
+            }
+            ConfigError::DuplicateNetwork { line, network, first_line } => {
+                write!(f, "line {}: network {} already declared in line {}", line, network, first_line)
+            }
+            ConfigError::OverlappingNetworks { line, network, other_line, other } => {
+                write!(f, "line {}: network {} overlaps with {} declared in line {}", line, network, other, other_line)
+            }
+            ConfigError::UnknownNeighbour { line, neighbour } => {
+                write!(f, "line {}: {} is not a neighbour in any directly connected network", line, neighbour)
+            }
+            ConfigError::DuplicateMetricOffset { line, neighbour, first_line } => {
+                write!(f, "line {}: metric offset for {} already declared in line {}", line, neighbour, first_line)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseRouterConfigError(Vec<ConfigError>);
+
+impl ParseRouterConfigError {
+    pub fn errors(&self) -> &[ConfigError] {
+        &self.0
+    }
+}
+
+impl Display for ParseRouterConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid router configuration ({} errors):", self.0.len())?;
+        for err in &self.0 {
+            write!(f, "\n  {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_config() {
+        let config = RouterConfig::try_from(
+            "3\n10.0.1.1/8 distance 3\n192.168.5.43/24 distance 2\n192.168.2.1/24 distance 4 172.16.0.1/16 distance 1\n"
+        ).unwrap();
+        assert_eq!(config.interfaces().len(), 3);
+        assert_eq!(config.interfaces()[2].addresses().len(), 2);
+        assert_eq!(config.direct_routes().len(), 4);
+        assert_eq!(config.addresses().next().unwrap().address, Ipv4Addr::new(10, 0, 1, 1));
+    }
+
+    #[test]
+    fn test_errors_are_aggregated() {
+        let err = RouterConfig::try_from(
+            "4\n10.0.0.0/8 distance 3\nfoo distance 2\n10.0.1.1/8 distance 1\n10.1.0.1/16 distance 1 192.168.0.1/24\n"
+        ).unwrap_err();
+        let errors = err.errors();
+        assert_eq!(errors.len(), 4);
+        assert!(matches!(errors[0], ConfigError::InvalidInterface { line: 3, .. }));
+        assert!(matches!(errors[1], ConfigError::InvalidInterface { line: 5, .. }));
+        assert!(matches!(errors[2], ConfigError::AddressOutsideNetwork { line: 2, .. }));
+        assert!(matches!(errors[3], ConfigError::DuplicateNetwork { line: 4, first_line: 2, .. }));
+    }
+
+    #[test]
+    fn test_overlapping_networks() {
+        let err = RouterConfig::try_from("2\n10.0.1.1/8 distance 3\n10.1.0.1/16 distance 1\n").unwrap_err();
+        assert!(matches!(err.errors(), [ConfigError::OverlappingNetworks { line: 3, other_line: 2, .. }]));
+    }
+
+    #[test]
+    fn test_interface_count() {
+        let err = RouterConfig::try_from("2\n10.0.1.1/8 distance 3\n").unwrap_err();
+        assert!(matches!(err.errors(), [ConfigError::InterfaceCountMismatch { declared: 2, found: 1 }]));
+        let err = RouterConfig::try_from("").unwrap_err();
+        assert!(matches!(err.errors(), [ConfigError::InterfaceCountMissing]));
+    }
+
+    #[test]
+    fn test_static_routes() {
+        let config = RouterConfig::try_from(
+            "1\n10.0.1.1/8 distance 3\nblackhole 192.168.0.0/16\nreject 172.16.5.4/24\n"
+        ).unwrap();
+        assert_eq!(config.interfaces().len(), 1);
+        assert_eq!(config.static_routes()[0].connection_type, ConnectionType::BlackHole);
+        assert_eq!(config.static_routes()[1].network.to_string(), "172.16.5.0/24");
+        assert_eq!(config.static_routes()[1].connection_type, ConnectionType::Reject);
+
+        let err = RouterConfig::try_from("1\n10.0.1.1/8 distance 3\nreject 10.5.0.0/16\nblackhole\n").unwrap_err();
+        assert!(matches!(err.errors(), [
+            ConfigError::InvalidStaticRoute { line: 4, .. },
+            ConfigError::OverlappingNetworks { line: 3, other_line: 2, .. },
+        ]));
+    }
+
+    #[test]
+    fn test_metric_offsets() {
+        let config = RouterConfig::try_from(
+            "2\noffset 10.0.1.2 +5\n10.0.1.1/8 distance 3\n192.168.5.43/24 distance 2\noffset 192.168.5.5 2\n"
+        ).unwrap();
+        assert_eq!(config.interfaces().len(), 2);
+        assert_eq!(config.metric_offsets(), [
+            MetricOffset { neighbour: Ipv4Addr::new(10, 0, 1, 2), offset: 5 },
+            MetricOffset { neighbour: Ipv4Addr::new(192, 168, 5, 5), offset: 2 },
+        ]);
+
+        let err = RouterConfig::try_from(
+            "1\n10.0.1.1/8 distance 3\noffset 10.0.1.2 -1\noffset 172.16.0.1 1\noffset 10.0.1.1 1\noffset 10.0.1.3 1\noffset 10.0.1.3 2\n"
+        ).unwrap_err();
+        assert!(matches!(err.errors(), [
+            ConfigError::InvalidMetricOffset { line: 3, .. },
+            ConfigError::UnknownNeighbour { line: 4, .. },
+            ConfigError::UnknownNeighbour { line: 5, .. },
+            ConfigError::DuplicateMetricOffset { line: 7, first_line: 6, .. },
+        ]));
+    }
+
+    #[test]
+    fn test_learned_routes() {
+        let config = RouterConfig::try_from(
+            "1\n10.0.1.1/8 distance 3\nroute 172.16.0.0/16 distance 5 via 10.0.1.2\nroute 10.5.0.0/16 distance 4 via 10.0.1.3\n"
+        ).unwrap();
+        assert_eq!(config.learned_routes(), [
+            LearnedRoute { network: Network::try_from("172.16.0.0/16").unwrap(), distance: Distance::new(5), next_hop: Ipv4Addr::new(10, 0, 1, 2) },
+            LearnedRoute { network: Network::try_from("10.5.0.0/16").unwrap(), distance: Distance::new(4), next_hop: Ipv4Addr::new(10, 0, 1, 3) },
+        ]);
+
+        let err = RouterConfig::try_from(
+            "1\n10.0.1.1/8 distance 3\nroute 172.16.0.0/16 distance 5\nroute 192.168.0.0/24 distance 2 via 172.16.0.1\nroute 10.0.0.0/8 distance 2 via 10.0.1.2\n"
+        ).unwrap_err();
+        assert!(matches!(err.errors(), [
+            ConfigError::InvalidLearnedRoute { line: 3, .. },
+            ConfigError::UnknownNeighbour { line: 4, .. },
+            ConfigError::DuplicateNetwork { line: 5, first_line: 2, .. },
+        ]));
+    }
+
+    #[test]
+    fn test_table_routes_round_trip() {
+        let mut table = RoutingTable::new(vec![Route::new(Network::try_from("10.0.0.0/8").unwrap(), Distance::new(1))]);
+        table.add_static_route(Network::try_from("192.168.0.0/16").unwrap(), ConnectionType::Reject).unwrap();
+        table.update(Network::try_from("172.16.0.0/16").unwrap(), Distance::new(4), Ipv4Addr::new(10, 0, 0, 2));
+        table.update(Network::try_from("172.17.0.0/16").unwrap(), Distance::new(2), Ipv4Addr::new(10, 0, 0, 3));
+        table.neighbour_lost(Ipv4Addr::new(10, 0, 0, 3));
+        let exported = TableRoutes::of(&table).to_string();
+        assert_eq!(exported, "reject 192.168.0.0/16\nroute 172.16.0.0/16 distance 4 via 10.0.0.2\n");
+        assert_eq!(TableRoutes::try_from(exported.as_str()).unwrap(), TableRoutes::of(&table));
+
+        let err = TableRoutes::try_from("3\nreject 192.168.0.0/16\n\nroute 192.168.0.0/16 distance 1 via 10.0.0.2\n").unwrap_err();
+        assert!(matches!(err.errors(), [
+            ConfigError::NotARoute { line: 1 },
+            ConfigError::DuplicateNetwork { line: 4, first_line: 2, .. },
+        ]));
+    }
+
+    #[test]
+    fn test_missing_distance_keyword() {
+        assert!(matches!(
+            InterfaceConfig::try_from("10.0.1.1/8 dist 3"),
+            Err(ParseRouteError::InvalidFormat(_))
+        ));
+    }
+}
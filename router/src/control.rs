@@ -0,0 +1,347 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::TableRoutes;
+use crate::history::RouteChange;
+use crate::network::ParseNetworkError;
+use crate::route::{Distance, Network};
+use crate::routing_table::{ConnectionType, RoutingTable};
+use crate::topology::Topology;
+
+/// Queries accepted on the control socket, one per connection.
+///
+/// Expected input format - single line:
+/// ```text
+/// history [<network>]
+/// show topology [dot]
+/// stats
+/// export-routes
+/// import-routes
+/// subscribe
+/// ```
+/// `import-routes` is followed by the routes, until the client stops sending.
+/// After `subscribe` the connection stays open and every change of the table is sent as a line of JSON,
+/// see `RouteChange::to_json`.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ControlCommand {
+    /// History of the route to a single network or of all routes.
+    History(Option<Network>),
+    /// Topology reconstructed from the routing table, as a tree or in graphviz DOT format.
+    ShowTopology { dot: bool },
+    /// Size of the table and churn of its entries, see `TableStatistics`.
+    Stats,
+    /// Static and learned routes in the configuration format, see `TableRoutes`.
+    ExportRoutes,
+    /// Routes in the format of `ExportRoutes` are added to the table, see `RoutingTable::import`.
+    ImportRoutes,
+    /// Feed of routing table changes, see `ControlSocket::publish`.
+    Subscribe,
+}
+
+impl ControlCommand {
+    const HISTORY_KEYWORD: &'static str = "history";
+    const SHOW_KEYWORD: &'static str = "show";
+    const TOPOLOGY_KEYWORD: &'static str = "topology";
+    const DOT_KEYWORD: &'static str = "dot";
+    const STATS_KEYWORD: &'static str = "stats";
+    const EXPORT_ROUTES_KEYWORD: &'static str = "export-routes";
+    const IMPORT_ROUTES_KEYWORD: &'static str = "import-routes";
+    const SUBSCRIBE_KEYWORD: &'static str = "subscribe";
+
+    /// Command takes the rest of the client input as its argument.
+    pub fn reads_input(&self) -> bool {
+        matches!(self, ControlCommand::ImportRoutes)
+    }
+
+    /// `input` is the rest of the client input for commands that read it, empty otherwise.
+    pub fn execute(&self, table: &mut RoutingTable, input: &str) -> String {
+        match self {
+            ControlCommand::History(Some(network)) => Self::history(table, network),
+            ControlCommand::History(None) => {
+                let mut networks = table.history().networks().collect::<Vec<_>>();
+                networks.sort_by_key(|network| (u32::from(network.prefix()), u8::from(network.subnet_mask())));
+                networks.into_iter().map(|network| Self::history(table, network)).collect()
+            }
+            ControlCommand::ShowTopology { dot: false } => Topology::of(table).to_string(),
+            ControlCommand::ShowTopology { dot: true } => Topology::of(table).to_dot(),
+            ControlCommand::Stats => table.statistics().to_string(),
+            ControlCommand::ExportRoutes => TableRoutes::of(table).to_string(),
+            ControlCommand::ImportRoutes => Self::import_routes(table, input),
+            /* changes are sent later, as they happen */
+            ControlCommand::Subscribe => String::new(),
+        }
+    }
+
+    /// Nothing is imported if any of the routes is invalid.
+    fn import_routes(table: &mut RoutingTable, input: &str) -> String {
+        let routes = match TableRoutes::try_from(input) {
+            Ok(routes) => routes,
+            Err(err) => return format!("error: {err}\n"),
+        };
+        let static_routes = routes.static_routes().iter().map(|route| (route.network, Distance::Infinite, route.connection_type));
+        let learned_routes = routes.learned_routes().iter().map(|route| (route.network, route.distance, ConnectionType::Via(route.next_hop)));
+        let imported = static_routes
+            .chain(learned_routes)
+            .filter(|&(network, distance, connection_type)| table.import(network, distance, connection_type))
+            .count();
+        format!("imported {} of {} routes\n", imported, routes.len())
+    }
+
+    fn history(table: &RoutingTable, network: &Network) -> String {
+        let mut report = format!("{}\n", network);
+        for change in table.history().of(network) {
+            report.push_str(&format!("  {}\n", change));
+        }
+        report
+    }
+}
+
+impl TryFrom<&str> for ControlCommand {
+    type Error = ParseControlCommandError;
+
+    fn try_from(line: &str) -> Result<Self, Self::Error> {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        match tokens.as_slice() {
+            [Self::HISTORY_KEYWORD] => Ok(ControlCommand::History(None)),
+            [Self::HISTORY_KEYWORD, network] => Ok(ControlCommand::History(Some(Network::try_from(*network)?))),
+            [Self::SHOW_KEYWORD, Self::TOPOLOGY_KEYWORD] => Ok(ControlCommand::ShowTopology { dot: false }),
+            [Self::SHOW_KEYWORD, Self::TOPOLOGY_KEYWORD, Self::DOT_KEYWORD] => Ok(ControlCommand::ShowTopology { dot: true }),
+            [Self::STATS_KEYWORD] => Ok(ControlCommand::Stats),
+            [Self::EXPORT_ROUTES_KEYWORD] => Ok(ControlCommand::ExportRoutes),
+            [Self::IMPORT_ROUTES_KEYWORD] => Ok(ControlCommand::ImportRoutes),
+            [Self::SUBSCRIBE_KEYWORD] => Ok(ControlCommand::Subscribe),
+            _ => Err(ParseControlCommandError::UnknownCommand(line.trim().to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseControlCommandError {
+    UnknownCommand(String),
+    InvalidNetwork(ParseNetworkError),
+}
+
+impl From<ParseNetworkError> for ParseControlCommandError {
+    fn from(err: ParseNetworkError) -> Self {
+        Self::InvalidNetwork(err)
+    }
+}
+
+impl Display for ParseControlCommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseControlCommandError::UnknownCommand(command) => {
+                write!(
+                    f, "unknown command '{}', expected 'history [<network>]', 'show topology [dot]', 'stats', \
+                        'export-routes', 'import-routes' or 'subscribe'",
+                    command
+                )
+            }
+            ParseControlCommandError::InvalidNetwork(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Unix domain socket for querying the state of a running router, eg. with
+/// `echo "history 10.0.0.0/8" | nc -U <path>`. Routes are imported with
+/// `(echo import-routes; cat <file>) | nc -NU <path>`. Changes are followed with
+/// `echo subscribe | nc -U <path>`.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    subscribers: Vec<UnixStream>,
+}
+
+impl ControlSocket {
+    const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Socket left over by a previous instance is replaced.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, path: path.to_owned(), subscribers: Vec::new() })
+    }
+
+    /// Answers all clients that are already waiting, does not block otherwise.
+    pub fn serve_pending(&mut self, table: &mut RoutingTable) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match Self::serve(stream, table) {
+                    Ok(Some(subscriber)) => self.subscribers.push(subscriber),
+                    Ok(None) => {}
+                    Err(err) => eprintln!("control socket: {err}"),
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("control socket: {err}");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Sends `changes` to the subscribers. Subscribers that disconnected or do not keep up
+    /// with the feed are dropped, so that they never stall the router.
+    pub fn publish(&mut self, changes: &[(Network, RouteChange)]) {
+        if changes.is_empty() || self.subscribers.is_empty() {
+            return;
+        }
+        let events = changes
+            .iter()
+            .map(|(network, change)| change.to_json(network) + "\n")
+            .collect::<String>();
+        self.subscribers.retain(|mut subscriber| subscriber.write_all(events.as_bytes()).is_ok());
+    }
+
+    /// Stream of the client is returned if it subscribed to changes.
+    fn serve(stream: UnixStream, table: &mut RoutingTable) -> io::Result<Option<UnixStream>> {
+        /* accepted socket inherits nonblocking mode from the listener */
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Self::CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(Self::CLIENT_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let response = match ControlCommand::try_from(line.as_str()) {
+            Ok(ControlCommand::Subscribe) => {
+                drop(reader);
+                stream.set_nonblocking(true)?;
+                table.enable_change_feed();
+                return Ok(Some(stream));
+            }
+            Ok(command) => {
+                let mut input = String::new();
+                if command.reads_input() {
+                    reader.read_to_string(&mut input)?;
+                }
+                command.execute(table, &input)
+            }
+            Err(err) => format!("error: {err}\n"),
+        };
+        (&stream).write_all(response.as_bytes())?;
+        Ok(None)
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Descriptor of the listening socket, readable when a client is waiting.
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::Distance;
+    use std::env;
+    use std::io::Read;
+    use std::net::{Ipv4Addr, Shutdown};
+    use std::process;
+
+    #[test]
+    fn test_parse_command() {
+        let network = Network::try_from("10.0.0.0/8").unwrap();
+        assert_eq!(ControlCommand::try_from("history\n").unwrap(), ControlCommand::History(None));
+        assert_eq!(ControlCommand::try_from(" history 10.0.0.0/8 ").unwrap(), ControlCommand::History(Some(network)));
+        assert_eq!(ControlCommand::try_from("show topology").unwrap(), ControlCommand::ShowTopology { dot: false });
+        assert_eq!(ControlCommand::try_from("show topology dot\n").unwrap(), ControlCommand::ShowTopology { dot: true });
+        assert_eq!(ControlCommand::try_from("stats\n").unwrap(), ControlCommand::Stats);
+        assert_eq!(ControlCommand::try_from("export-routes\n").unwrap(), ControlCommand::ExportRoutes);
+        assert_eq!(ControlCommand::try_from("import-routes\n").unwrap(), ControlCommand::ImportRoutes);
+        assert_eq!(ControlCommand::try_from("subscribe\n").unwrap(), ControlCommand::Subscribe);
+        assert!(matches!(ControlCommand::try_from("routes"), Err(ParseControlCommandError::UnknownCommand(_))));
+        assert!(matches!(ControlCommand::try_from("show routes"), Err(ParseControlCommandError::UnknownCommand(_))));
+        assert!(matches!(ControlCommand::try_from("history 10.0.0.0"), Err(ParseControlCommandError::InvalidNetwork(_))));
+    }
+
+    #[test]
+    fn test_query_history() {
+        let path = env::temp_dir().join(format!("router-control-{}", process::id()));
+        let mut socket = ControlSocket::bind(&path).unwrap();
+        let network = Network::try_from("172.16.0.0/16").unwrap();
+        let mut table = RoutingTable::default();
+        table.update(network, Distance::new(4), Ipv4Addr::new(10, 0, 0, 2));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"history 172.16.0.0/16\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        socket.serve_pending(&mut table);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("172.16.0.0/16\n"), "{response}");
+        assert!(response.contains("none -> distance 4 via 10.0.0.2 (learned new network)"), "{response}");
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_export_then_import_routes() {
+        let path = env::temp_dir().join(format!("router-control-import-{}", process::id()));
+        let mut socket = ControlSocket::bind(&path).unwrap();
+        let mut converged = RoutingTable::default();
+        converged.update(Network::try_from("172.16.0.0/16").unwrap(), Distance::new(4), Ipv4Addr::new(10, 0, 0, 2));
+        converged.add_static_route(Network::try_from("192.168.0.0/16").unwrap(), ConnectionType::BlackHole).unwrap();
+        let exported = ControlCommand::ExportRoutes.execute(&mut converged, "");
+        assert_eq!(exported, "blackhole 192.168.0.0/16\nroute 172.16.0.0/16 distance 4 via 10.0.0.2\n");
+
+        let mut table = RoutingTable::default();
+        let mut client = UnixStream::connect(&path).unwrap();
+        write!(client, "import-routes\n{exported}").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        socket.serve_pending(&mut table);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "imported 2 of 2 routes\n");
+        assert_eq!(ControlCommand::ExportRoutes.execute(&mut table, ""), exported);
+
+        let response = ControlCommand::ImportRoutes.execute(&mut table, "route 10.0.0.0/8 distance 1\n");
+        assert!(response.starts_with("error: invalid router configuration (1 errors):\n  line 1: "), "{response}");
+    }
+
+    #[test]
+    fn test_subscribers_receive_changes() {
+        let path = env::temp_dir().join(format!("router-control-subscribe-{}", process::id()));
+        let mut socket = ControlSocket::bind(&path).unwrap();
+        let mut table = RoutingTable::default();
+        let network = Network::try_from("172.16.0.0/16").unwrap();
+        /* changes made before anyone subscribed are not sent */
+        table.update(Network::try_from("10.0.0.0/8").unwrap(), Distance::new(2), Ipv4Addr::new(10, 0, 0, 2));
+
+        let subscriber = UnixStream::connect(&path).unwrap();
+        (&subscriber).write_all(b"subscribe\n").unwrap();
+        let gone = UnixStream::connect(&path).unwrap();
+        (&gone).write_all(b"subscribe\n").unwrap();
+        socket.serve_pending(&mut table);
+        drop(gone);
+        table.update(network, Distance::new(4), Ipv4Addr::new(10, 0, 0, 2));
+        table.update(network, Distance::new(3), Ipv4Addr::new(10, 0, 0, 3));
+        socket.publish(&table.take_changes());
+        assert_eq!(socket.subscribers.len(), 1);
+        assert!(table.take_changes().is_empty());
+
+        subscriber.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut lines = BufReader::new(&subscriber).lines();
+        let learned = lines.next().unwrap().unwrap();
+        assert!(learned.contains(r#""network":"172.16.0.0/16","old":null,"new":{"distance":4,"connection":"via 10.0.0.2"},"reason":"learned new network"}"#), "{learned}");
+        let better = lines.next().unwrap().unwrap();
+        assert!(better.contains(r#""new":{"distance":3,"connection":"via 10.0.0.3"},"reason":"shorter path advertised"}"#), "{better}");
+    }
+}
@@ -18,6 +18,14 @@ impl Distance {
             _ => Self::Infinite,
         }
     }
+
+    /// Distance increased by `offset`, becomes infinite once it exceeds the maximum.
+    pub fn offset(self, offset: u32) -> Self {
+        match self {
+            Self::Finite(dist) => Self::new(dist.saturating_add(offset)),
+            Self::Infinite => Self::Infinite,
+        }
+    }
 }
 
 impl TryFrom<&str> for Distance {
@@ -97,6 +105,14 @@ mod tests {
         assert!(matches!(dist, Distance::Infinite));
     }
 
+    #[test]
+    fn test_offset() {
+        assert_eq!(Distance::new(3).offset(5), Distance::new(8));
+        assert_eq!(Distance::new(Distance::MAX_DISTANCE).offset(1), Distance::Infinite);
+        assert_eq!(Distance::new(1).offset(u32::MAX), Distance::Infinite);
+        assert_eq!(Distance::Infinite.offset(0), Distance::Infinite);
+    }
+
     #[test]
     fn test_from_u32_infinite_2() {
         let dist = Distance::new(Distance::INFINITY_ENCODING);
@@ -0,0 +1,206 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::route::{Distance, Network};
+use crate::routing_table::ConnectionType;
+
+/// Why the routing table entry changed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChangeReason {
+    /// Route comes from the configuration.
+    Configured,
+    /// Neighbour advertised a network that was not in the table.
+    Learned,
+    /// Current next hop advertised a different distance.
+    NextHopAdvertisement,
+    /// Another neighbour advertised a shorter path.
+    BetterPath,
+    /// Next hop stopped sending hellos, the route is advertised as unreachable until it expires.
+    NeighbourLost,
+    /// Route was imported through the control socket.
+    Imported,
+    Removed,
+}
+
+impl Display for ChangeReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let repr = match self {
+            ChangeReason::Configured => "configured",
+            ChangeReason::Learned => "learned new network",
+            ChangeReason::NextHopAdvertisement => "advertised by current next hop",
+            ChangeReason::BetterPath => "shorter path advertised",
+            ChangeReason::NeighbourLost => "next hop lost",
+            ChangeReason::Imported => "imported",
+            ChangeReason::Removed => "removed",
+        };
+        write!(f, "{repr}")
+    }
+}
+
+/// Single transition of the route to a network. `None` means there was no route.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RouteChange {
+    pub at: SystemTime,
+    pub old: Option<(Distance, ConnectionType)>,
+    pub new: Option<(Distance, ConnectionType)>,
+    pub reason: ChangeReason,
+}
+
+impl Display for RouteChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let describe = |route: &Option<(Distance, ConnectionType)>| match route {
+            Some((distance, connection_type)) => format!("{} {}", distance, connection_type),
+            None => "none".to_owned(),
+        };
+        write!(
+            f, "{} {} -> {} ({})",
+            Timestamp(self.at), describe(&self.old), describe(&self.new), self.reason
+        )
+    }
+}
+
+impl RouteChange {
+    /// Single line JSON object describing the change of the route to `network`, eg.
+    /// `{"at":1700000000,"network":"10.0.0.0/8","old":null,"new":{"distance":4,"connection":"via 10.0.0.1"},"reason":"learned new network"}`.
+    /// Distance of unreachable routes is `null`.
+    pub fn to_json(self, network: &Network) -> String {
+        let describe = |route: &Option<(Distance, ConnectionType)>| match route {
+            Some((Distance::Finite(distance), connection_type)) => {
+                format!(r#"{{"distance":{},"connection":"{}"}}"#, distance, connection_type)
+            }
+            Some((Distance::Infinite, connection_type)) => format!(r#"{{"distance":null,"connection":"{}"}}"#, connection_type),
+            None => "null".to_owned(),
+        };
+        let at = self.at.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        format!(
+            r#"{{"at":{},"network":"{}","old":{},"new":{},"reason":"{}"}}"#,
+            at, network, describe(&self.old), describe(&self.new), self.reason
+        )
+    }
+}
+
+/// UTC time in `YYYY-MM-DD HH:MM:SS` format.
+struct Timestamp(SystemTime);
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+        let seconds = self.0.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let (days, time_of_day) = (seconds / SECONDS_PER_DAY, seconds % SECONDS_PER_DAY);
+        /* conversion of days since epoch to the civil date, see http://howardhinnant.github.io/date_algorithms.html */
+        let days = days as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        write!(
+            f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, time_of_day / 3600, time_of_day / 60 % 60, time_of_day % 60
+        )
+    }
+}
+
+/// Last `capacity` changes of every route, older ones are dropped.
+#[derive(Debug)]
+pub struct RouteHistory {
+    capacity: usize,
+    changes: HashMap<Network, VecDeque<RouteChange>>,
+}
+
+impl RouteHistory {
+    pub const DEFAULT_CAPACITY: usize = 16;
+
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, changes: HashMap::new() }
+    }
+
+    pub fn record(&mut self, network: Network, change: RouteChange) {
+        if self.capacity == 0 {
+            return;
+        }
+        let ring = self.changes.entry(network).or_default();
+        if ring.len() == self.capacity {
+            ring.pop_front();
+        }
+        ring.push_back(change);
+    }
+
+    /// Changes of the route to `network`, oldest first.
+    pub fn of(&self, network: &Network) -> impl Iterator<Item=&RouteChange> {
+        self.changes.get(network).into_iter().flatten()
+    }
+
+    pub fn networks(&self) -> impl Iterator<Item=&Network> {
+        self.changes.keys()
+    }
+
+    /// Memory held by the recorded changes, allocator overhead not included.
+    pub fn approximate_bytes(&self) -> usize {
+        let rings = self.changes.capacity() * mem::size_of::<(Network, VecDeque<RouteChange>)>();
+        let changes = self.changes.values().map(VecDeque::capacity).sum::<usize>() * mem::size_of::<RouteChange>();
+        rings + changes
+    }
+}
+
+impl Default for RouteHistory {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    fn change(distance: u32, reason: ChangeReason) -> RouteChange {
+        RouteChange {
+            at: UNIX_EPOCH,
+            old: None,
+            new: Some((Distance::new(distance), ConnectionType::Via(Ipv4Addr::new(10, 0, 0, 1)))),
+            reason,
+        }
+    }
+
+    #[test]
+    fn test_ring_is_bounded() {
+        let network = Network::try_from("10.0.0.0/8").unwrap();
+        let mut history = RouteHistory::new(2);
+        history.record(network, change(1, ChangeReason::Learned));
+        history.record(network, change(2, ChangeReason::NextHopAdvertisement));
+        history.record(network, change(3, ChangeReason::BetterPath));
+        let reasons = history.of(&network).map(|change| change.reason).collect::<Vec<_>>();
+        assert_eq!(reasons, [ChangeReason::NextHopAdvertisement, ChangeReason::BetterPath]);
+        assert_eq!(history.of(&Network::try_from("192.168.0.0/16").unwrap()).count(), 0);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut change = change(4, ChangeReason::Learned);
+        change.at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(change.to_string(), "2023-11-14 22:13:20 none -> distance 4 via 10.0.0.1 (learned new network)");
+    }
+
+    #[test]
+    fn test_json() {
+        let network = Network::try_from("10.0.0.0/8").unwrap();
+        let mut change = change(4, ChangeReason::Learned);
+        change.at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            change.to_json(&network),
+            r#"{"at":1700000000,"network":"10.0.0.0/8","old":null,"new":{"distance":4,"connection":"via 10.0.0.1"},"reason":"learned new network"}"#
+        );
+        change.old = change.new;
+        change.new = Some((Distance::Infinite, ConnectionType::Via(Ipv4Addr::new(10, 0, 0, 1))));
+        change.reason = ChangeReason::NeighbourLost;
+        assert!(change.to_json(&network).contains(r#""new":{"distance":null,"connection":"via 10.0.0.1"},"reason":"next hop lost""#));
+    }
+}
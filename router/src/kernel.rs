@@ -0,0 +1,198 @@
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::history::RouteChange;
+use crate::route::{Distance, Network};
+use crate::routing_table::ConnectionType;
+
+/// What is done with the routes learned from the neighbours.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum KernelMode {
+    /// Routes are installed into the kernel routing table with `ip route`.
+    Apply,
+    /// Changes the router would make are printed as a diff, the kernel table is left alone.
+    DryRun,
+}
+
+impl FromStr for KernelMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apply" => Ok(Self::Apply),
+            "dry-run" => Ok(Self::DryRun),
+            other => Err(format!("unknown kernel routes mode {other}, expected apply or dry-run")),
+        }
+    }
+}
+
+/// Route the kernel forwards packets along, the distance becomes its metric.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KernelRoute {
+    pub network: Network,
+    pub gateway: Ipv4Addr,
+    pub metric: u32,
+}
+
+impl KernelRoute {
+    /// Only reachable routes via a neighbour are installed. Directly connected networks are
+    /// already in the kernel table and the static ones are left to the system configuration.
+    pub fn of(network: Network, route: Option<(Distance, ConnectionType)>) -> Option<Self> {
+        match route {
+            Some((Distance::Finite(metric), ConnectionType::Via(gateway))) => Some(Self { network, gateway, metric }),
+            _ => None,
+        }
+    }
+
+    fn args(&self) -> [String; 5] {
+        [self.network.to_string(), "via".to_owned(), self.gateway.to_string(), "metric".to_owned(), self.metric.to_string()]
+    }
+}
+
+impl Display for KernelRoute {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} via {} metric {}", self.network, self.gateway, self.metric)
+    }
+}
+
+/// Single modification of the kernel table, displayed as a line of a diff.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RouteCommand {
+    Add(KernelRoute),
+    Delete(KernelRoute),
+}
+
+impl RouteCommand {
+    /// Commands bringing the kernel table from the old route to `network` to the new one.
+    /// Kernel keeps routes with different metrics apart, so the new route is added before
+    /// the old one is deleted and the network stays reachable in between.
+    pub fn for_change(network: Network, change: &RouteChange) -> Vec<Self> {
+        match (KernelRoute::of(network, change.old), KernelRoute::of(network, change.new)) {
+            (Some(old), Some(new)) if old.metric == new.metric => vec![Self::Add(new)],
+            (Some(old), Some(new)) => vec![Self::Add(new), Self::Delete(old)],
+            (Some(old), None) => vec![Self::Delete(old)],
+            (None, Some(new)) => vec![Self::Add(new)],
+            (None, None) => Vec::new(),
+        }
+    }
+
+    /// `replace` is used to add routes, so a route left behind by a previous run is taken over.
+    fn to_command(self) -> Command {
+        let (verb, route) = match self {
+            Self::Add(route) => ("replace", route),
+            Self::Delete(route) => ("del", route),
+        };
+        let mut command = Command::new("ip");
+        command.args(["route", verb]).args(route.args());
+        command
+    }
+}
+
+impl Display for RouteCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Add(route) => write!(f, "+ {route}"),
+            Self::Delete(route) => write!(f, "- {route}"),
+        }
+    }
+}
+
+/// Mirrors learned routes of the routing table in the kernel one, so the router actually forwards
+/// traffic. Installing routes requires `CAP_NET_ADMIN`. Routes stay installed after the router exits.
+#[derive(Debug)]
+pub struct KernelRoutes {
+    mode: KernelMode,
+}
+
+impl KernelRoutes {
+    pub fn new(mode: KernelMode) -> Self {
+        Self { mode }
+    }
+
+    /// Installs routes already in the table, eg. the ones learned before the previous run ended.
+    pub fn install(&self, routes: impl Iterator<Item=(Network, Distance, ConnectionType)>) -> Vec<RouteCommand> {
+        let commands = routes
+            .filter_map(|(network, distance, connection_type)| KernelRoute::of(network, Some((distance, connection_type))))
+            .map(RouteCommand::Add)
+            .collect();
+        self.run(commands)
+    }
+
+    /// Applies changes of the routing table, commands are returned in the order they were run.
+    pub fn apply(&self, changes: &[(Network, RouteChange)]) -> Vec<RouteCommand> {
+        let commands = changes
+            .iter()
+            .flat_map(|(network, change)| RouteCommand::for_change(*network, change))
+            .collect();
+        self.run(commands)
+    }
+
+    fn run(&self, commands: Vec<RouteCommand>) -> Vec<RouteCommand> {
+        for command in &commands {
+            match self.mode {
+                KernelMode::DryRun => println!("{command}"),
+                KernelMode::Apply => match command.to_command().status() {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => eprintln!("warning: could not apply {command} to the kernel table: ip exited with {status}"),
+                    Err(err) => eprintln!("warning: could not apply {command} to the kernel table: {err}"),
+                },
+            }
+        }
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::ChangeReason;
+    use std::time::SystemTime;
+
+    fn change(old: Option<(Distance, ConnectionType)>, new: Option<(Distance, ConnectionType)>) -> RouteChange {
+        RouteChange { at: SystemTime::now(), old, new, reason: ChangeReason::Learned }
+    }
+
+    #[test]
+    fn test_changes_become_route_commands() {
+        let network = Network::try_from("10.0.0.0/8").unwrap();
+        let via = |address: [u8; 4], metric| Some((Distance::Finite(metric), ConnectionType::Via(Ipv4Addr::from(address))));
+        let route = |address: [u8; 4], metric| KernelRoute { network, gateway: Ipv4Addr::from(address), metric };
+        let render = |commands: Vec<RouteCommand>| commands.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(RouteCommand::for_change(network, &change(None, via([192, 168, 0, 2], 3))), [RouteCommand::Add(route([192, 168, 0, 2], 3))]);
+        assert_eq!(
+            render(RouteCommand::for_change(network, &change(via([192, 168, 0, 2], 3), via([192, 168, 1, 2], 2)))),
+            ["+ 10.0.0.0/8 via 192.168.1.2 metric 2", "- 10.0.0.0/8 via 192.168.0.2 metric 3"]
+        );
+        assert_eq!(
+            RouteCommand::for_change(network, &change(via([192, 168, 0, 2], 3), via([192, 168, 1, 2], 3))),
+            [RouteCommand::Add(route([192, 168, 1, 2], 3))]
+        );
+        let poisoned = Some((Distance::Infinite, ConnectionType::Via(Ipv4Addr::new(192, 168, 0, 2))));
+        assert_eq!(RouteCommand::for_change(network, &change(via([192, 168, 0, 2], 3), poisoned)), [RouteCommand::Delete(route([192, 168, 0, 2], 3))]);
+        let direct = Some((Distance::Finite(1), ConnectionType::Direct));
+        assert!(RouteCommand::for_change(network, &change(None, direct)).is_empty());
+
+        assert_eq!(
+            route([192, 168, 0, 2], 3).args().join(" "),
+            "10.0.0.0/8 via 192.168.0.2 metric 3"
+        );
+    }
+
+    #[test]
+    fn test_dry_run_reports_diff() {
+        let network = Network::try_from("10.0.0.0/8").unwrap();
+        let kernel = KernelRoutes::new(KernelMode::DryRun);
+        let routes = [
+            (network, Distance::Finite(4), ConnectionType::Via(Ipv4Addr::new(192, 168, 0, 2))),
+            (Network::try_from("192.168.0.0/24").unwrap(), Distance::Finite(1), ConnectionType::Direct),
+        ];
+        assert_eq!(kernel.install(routes.into_iter()).len(), 1);
+        let lost = change(Some(routes[0].1).zip(Some(routes[0].2)), None);
+        assert_eq!(kernel.apply(&[(network, lost)]).iter().map(ToString::to_string).collect::<Vec<_>>(), ["- 10.0.0.0/8 via 192.168.0.2 metric 4"]);
+        assert_eq!("dry-run".parse(), Ok(KernelMode::DryRun));
+        assert!("forward".parse::<KernelMode>().is_err());
+    }
+}
@@ -1,22 +1,140 @@
-#[allow(dead_code, unused)]
+#![allow(dead_code, unused)]
 
+mod config;
+mod control;
 mod distance;
+mod history;
+#[cfg(feature = "kernel-routes")]
+mod kernel;
+mod neighbours;
 mod network;
 mod route;
 mod routing_table;
+mod stats;
 mod subnet_mask;
+mod topology;
 mod router;
 
+use std::env;
 use std::io;
 use std::io::Read;
+use std::path::Path;
+use std::process;
+use common::units;
+use crate::config::RouterConfig;
 use crate::router::Router;
+use crate::topology::Topology;
 
+/// Usage: `router [--control-socket <path>] [--turn-interval <duration>] [--hello-interval <duration>] [--churn-warning <count>] [--kernel-routes apply|dry-run] [--dot] < config`
+///
+/// With `--dot` the topology known from the configuration is printed in graphviz DOT format.
+/// Turn and hello intervals are durations such as `30s` or `500ms`, see `common::units`.
+/// Routes are advertised once per turn, hellos are sent every hello interval so a lost
+/// neighbour is noticed within three of them.
+/// With `--churn-warning` a warning is printed after every turn in which more than `count`
+/// entries were added to or removed from the routing table.
+/// With `--kernel-routes apply` learned routes are installed into the kernel routing table,
+/// `dry-run` only prints them as a diff. Available when built with the `kernel-routes` feature.
 fn main() -> std::io::Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+    let control_socket = args.iter().position(|arg| arg == "--control-socket").map(|index| {
+        args.get(index + 1).unwrap_or_else(|| {
+            eprintln!("--control-socket requires a path");
+            process::exit(1)
+        })
+    });
+
+    let turn_interval = args.iter().position(|arg| arg == "--turn-interval").map(|index| {
+        let repr = args.get(index + 1).unwrap_or_else(|| {
+            eprintln!("--turn-interval requires a duration");
+            process::exit(1)
+        });
+        units::parse_duration(repr).unwrap_or_else(|err| {
+            eprintln!("invalid turn interval {repr}: {err}");
+            process::exit(1)
+        })
+    });
+
+    let hello_interval = args.iter().position(|arg| arg == "--hello-interval").map(|index| {
+        let repr = args.get(index + 1).unwrap_or_else(|| {
+            eprintln!("--hello-interval requires a duration");
+            process::exit(1)
+        });
+        match units::parse_duration(repr) {
+            Ok(interval) if !interval.is_zero() => interval,
+            Ok(_) => {
+                eprintln!("hello interval must be positive");
+                process::exit(1)
+            }
+            Err(err) => {
+                eprintln!("invalid hello interval {repr}: {err}");
+                process::exit(1)
+            }
+        }
+    });
+
+    let churn_warning = args.iter().position(|arg| arg == "--churn-warning").map(|index| {
+        let repr = args.get(index + 1).unwrap_or_else(|| {
+            eprintln!("--churn-warning requires a number of entries");
+            process::exit(1)
+        });
+        repr.parse::<usize>().unwrap_or_else(|err| {
+            eprintln!("invalid churn warning threshold {repr}: {err}");
+            process::exit(1)
+        })
+    });
+
+    let kernel_routes = args.iter().position(|arg| arg == "--kernel-routes").map(|index| {
+        args.get(index + 1).unwrap_or_else(|| {
+            eprintln!("--kernel-routes requires a mode, apply or dry-run");
+            process::exit(1)
+        })
+    });
+
+    let dot = args.iter().any(|arg| arg == "--dot");
+
     let mut handle = io::stdin();
     let mut buffer = String::new();
     handle.read_to_string(&mut buffer)?;
 
-    let router = Router::from(buffer.as_str());
-    println!("{router}");
+    let config = RouterConfig::try_from(buffer.as_str()).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1)
+    });
+    let mut router = Router::from(config)
+        .with_turn_duration(turn_interval.unwrap_or(Router::RIP_TURN_WAIT_DURATION))
+        .with_hello_interval(hello_interval.unwrap_or(Router::HELLO_INTERVAL));
+    if let Some(threshold) = churn_warning {
+        router = router.with_churn_warning(threshold);
+    }
+    if let Some(mode) = kernel_routes {
+        router = with_kernel_routes(router, mode);
+    }
+    if let Some(path) = control_socket {
+        router = router.with_control_socket(Path::new(path)).unwrap_or_else(|err| {
+            eprintln!("could not bind control socket {path}: {err}");
+            process::exit(1)
+        });
+    }
+    if dot {
+        print!("{}", Topology::of(router.routing_table()).to_dot());
+    } else {
+        println!("{router}");
+    }
     Ok(())
 }
+
+#[cfg(feature = "kernel-routes")]
+fn with_kernel_routes(router: Router, mode: &str) -> Router {
+    let mode = mode.parse().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(1)
+    });
+    router.with_kernel_routes(mode)
+}
+
+#[cfg(not(feature = "kernel-routes"))]
+fn with_kernel_routes(_router: Router, _mode: &str) -> Router {
+    eprintln!("--kernel-routes requires the router to be built with the kernel-routes feature");
+    process::exit(1)
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Announcement that the sender is alive, sent more often than the routes.
+///
+/// # Hello binary format specification
+///
+/// First 4 bytes are the ASCII characters `HELO`. Together with the length, one byte shorter
+/// than a route packet, they tell hellos and routes apart.
+///
+/// Bytes 5 to 8 (4 bytes total) is an unsigned integer containing the hold time in milliseconds -
+/// for how long the receivers should consider the sender alive if no other hello arrives.
+/// Byte order should be Big Endian.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HelloPacket {
+    hold_time: Duration,
+}
+
+impl HelloPacket {
+    pub const LEN: usize = 8;
+    const MAGIC: &'static [u8] = b"HELO";
+    const MAGIC_BYTES: Range<usize> = 0..4;
+    const HOLD_TIME_BYTES: Range<usize> = 4..8;
+
+    pub fn new(hold_time: Duration) -> Self {
+        Self { hold_time }
+    }
+
+    pub fn hold_time(&self) -> Duration {
+        self.hold_time
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut buffer = [0; Self::LEN];
+        let hold_time = u32::try_from(self.hold_time.as_millis()).unwrap_or(u32::MAX);
+        buffer[Self::MAGIC_BYTES].copy_from_slice(Self::MAGIC);
+        buffer[Self::HOLD_TIME_BYTES].copy_from_slice(&hold_time.to_be_bytes());
+        buffer
+    }
+}
+
+impl TryFrom<&[u8]> for HelloPacket {
+    type Error = ParseHelloPacketError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != Self::LEN {
+            return Err(ParseHelloPacketError::InvalidLength(bytes.len()));
+        }
+        if &bytes[Self::MAGIC_BYTES] != Self::MAGIC {
+            return Err(ParseHelloPacketError::InvalidMagic);
+        }
+        let hold_time = u32::from_be_bytes(bytes[Self::HOLD_TIME_BYTES].try_into().unwrap());
+        Ok(Self::new(Duration::from_millis(hold_time as u64)))
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseHelloPacketError {
+    InvalidLength(usize),
+    InvalidMagic,
+}
+
+impl Display for ParseHelloPacketError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseHelloPacketError::InvalidLength(len) => {
+                write!(f, "invalid hello packet: expected {} bytes, got {}", HelloPacket::LEN, len)
+            }
+            ParseHelloPacketError::InvalidMagic => {
+                write!(f, "invalid hello packet: missing HELO marker")
+            }
+        }
+    }
+}
+
+/// Neighbours that said hello, each with the moment it is considered lost unless it says hello again.
+///
+/// Neighbours that never sent a hello are not tracked, routes learned from them
+/// are kept until they are replaced, as before.
+#[derive(Debug, Default)]
+pub struct Neighbours {
+    deadlines: HashMap<Ipv4Addr, Instant>,
+}
+
+impl Neighbours {
+    /// Returns `true` if the neighbour was not known to be alive.
+    pub fn hello_received(&mut self, neighbour: Ipv4Addr, hello: &HelloPacket, now: Instant) -> bool {
+        self.deadlines.insert(neighbour, now + hello.hold_time()).is_none()
+    }
+
+    /// Forgets the neighbours whose hold time passed and returns them.
+    pub fn expire(&mut self, now: Instant) -> Vec<Ipv4Addr> {
+        let lost = self.deadlines
+            .iter()
+            .filter(|(_, deadline)| now >= **deadline)
+            .map(|(neighbour, _)| *neighbour)
+            .collect::<Vec<_>>();
+        for neighbour in &lost {
+            self.deadlines.remove(neighbour);
+        }
+        lost
+    }
+
+    pub fn is_alive(&self, neighbour: Ipv4Addr) -> bool {
+        self.deadlines.contains_key(&neighbour)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_packet_encoding() {
+        let hello = HelloPacket::new(Duration::from_millis(15_000));
+        let bytes = hello.to_bytes();
+        assert_eq!(bytes, [b'H', b'E', b'L', b'O', 0, 0, 0x3a, 0x98]);
+        assert_eq!(HelloPacket::try_from(&bytes[..]), Ok(hello));
+        assert_eq!(HelloPacket::try_from(&bytes[..7]), Err(ParseHelloPacketError::InvalidLength(7)));
+        assert_eq!(HelloPacket::try_from(&[0; 8][..]), Err(ParseHelloPacketError::InvalidMagic));
+    }
+
+    #[test]
+    fn test_neighbours_expire_after_hold_time() {
+        let start = Instant::now();
+        let (first, second) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+        let hello = HelloPacket::new(Duration::from_secs(3));
+        let mut neighbours = Neighbours::default();
+        assert!(neighbours.hello_received(first, &hello, start));
+        assert!(neighbours.hello_received(second, &HelloPacket::new(Duration::from_secs(10)), start));
+        assert!(!neighbours.hello_received(first, &hello, start + Duration::from_secs(2)));
+        /* hold time counts from the last hello */
+        assert!(neighbours.expire(start + Duration::from_secs(4)).is_empty());
+        assert_eq!(neighbours.expire(start + Duration::from_secs(5)), [first]);
+        assert!(!neighbours.is_alive(first));
+        assert!(neighbours.is_alive(second));
+        assert!(neighbours.hello_received(first, &hello, start + Duration::from_secs(6)));
+    }
+}
@@ -23,8 +23,7 @@ impl Network {
     }
 
     pub fn with_prefix_masking(prefix: Ipv4Addr, subnet_mask: SubNetMask) -> Self {
-        let address_bytes = u32::from(prefix);
-        let masked_prefix = Ipv4Addr::from(address_bytes & !((1 << (32 - subnet_mask.value() as u32)) - 1u32));
+        let masked_prefix = Ipv4Addr::from(u32::from(prefix) & subnet_mask.bits());
         Self {
             prefix: masked_prefix,
             subnet_mask,
@@ -32,9 +31,28 @@ impl Network {
     }
 
     pub fn contains(&self, address: Ipv4Addr) -> bool {
-        let network_address = u32::from(self.prefix);
-        let final_address = network_address + self.subnet_mask.address_range();
-        (network_address..=final_address).contains(&address.into())
+        let mask = self.subnet_mask.bits();
+        u32::from(address) & mask == u32::from(self.prefix) & mask
+    }
+
+    /// Checks if `address` can be assigned to a host in this network.
+    ///
+    /// Network and broadcast addresses are reserved, except for /31 and /32 networks
+    /// which have no room for them (RFC 3021).
+    pub fn is_host_address(&self, address: Ipv4Addr) -> bool {
+        if !self.contains(address) {
+            return false;
+        }
+        if self.subnet_mask.value() >= 31 {
+            return true;
+        }
+        let host_bits = u32::from(address) & self.subnet_mask.address_range();
+        host_bits != 0 && host_bits != self.subnet_mask.address_range()
+    }
+
+    /// Checks if the address ranges of both networks intersect.
+    pub fn overlaps(&self, other: &Network) -> bool {
+        self.contains(other.prefix) || other.contains(self.prefix)
     }
 
     pub fn prefix(&self) -> Ipv4Addr {
@@ -46,7 +64,7 @@ impl Network {
     }
 
     pub fn broadcast_address(&self) -> Ipv4Addr {
-        Ipv4Addr::from(u32::from(self.prefix) | (1 << 32 - self.subnet_mask.value() as u32) - 1)
+        Ipv4Addr::from(u32::from(self.prefix) | self.subnet_mask.address_range())
     }
 }
 
@@ -55,10 +73,9 @@ impl TryFrom<(u32, u8)> for Network {
 
     fn try_from(prefix_mask_pair: (u32, u8)) -> Result<Self, Self::Error> {
         let (prefix, mask) = prefix_mask_pair;
-        let prefix = Ipv4Addr::from(prefix & mask as u32 - 1);
         let subnet_mask =
-            SubNetMask::new(mask).ok_or_else(|| Self::Error::ValueOutOfRange(mask))?;
-        Ok(Self::new(prefix, subnet_mask))
+            SubNetMask::new(mask).ok_or(Self::Error::ValueOutOfRange(mask))?;
+        Ok(Self::with_prefix_masking(Ipv4Addr::from(prefix), subnet_mask))
     }
 }
 
@@ -66,13 +83,9 @@ impl TryFrom<&str> for Network {
     type Error = ParseNetworkError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let mut data_iter = value.split('/');
-        let ipv4_address_repr = data_iter
-            .next()
-            .expect("invalid network str representation: ipv4 not found");
-        let subnet_mask_repr = data_iter
-            .next()
-            .expect("invalid network str representation: subnet mask not found");
+        let (ipv4_address_repr, subnet_mask_repr) = value
+            .split_once('/')
+            .ok_or_else(|| Self::Error::SubNetMaskMissing(value.to_owned()))?;
 
         let ipv4_address = Ipv4Addr::from_str(ipv4_address_repr).map_err(Self::Error::from)?;
         let subnet_mask = SubNetMask::try_from(subnet_mask_repr).map_err(Self::Error::from)?;
@@ -90,6 +103,7 @@ impl Display for Network {
 
 #[derive(Debug)]
 pub enum ParseNetworkError {
+    SubNetMaskMissing(String),
     Ipv4EncodingErr(AddrParseError),
     ParseSubNetMaskError(ParseSubNetMaskError),
 }
@@ -109,6 +123,9 @@ impl From<ParseSubNetMaskError> for ParseNetworkError {
 impl Display for ParseNetworkError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            ParseNetworkError::SubNetMaskMissing(repr) => {
+                write!(f, "invalid Network encoding: subnet mask missing in {}", repr)
+            }
             ParseNetworkError::Ipv4EncodingErr(err) => {
                 write!(f, "invalid Network encoding: {}", err)
             }
@@ -147,4 +164,38 @@ mod tests {
         let network = Network::new(ip, subnet_mask);
         assert_eq!(network.broadcast_address(), Ipv4Addr::from_str("192.168.255.255").unwrap());
     }
+
+    #[test]
+    fn test_contains() {
+        let network = Network::try_from("192.168.1.0/24").unwrap();
+        assert!(network.contains(Ipv4Addr::new(192, 168, 1, 77)));
+        assert!(!network.contains(Ipv4Addr::new(192, 168, 2, 1)));
+        assert!(Network::try_from("0.0.0.0/0").unwrap().contains(Ipv4Addr::new(8, 8, 8, 8)));
+    }
+
+    #[test]
+    fn test_host_address() {
+        let network = Network::try_from("10.0.0.0/8").unwrap();
+        assert!(network.is_host_address(Ipv4Addr::new(10, 0, 1, 1)));
+        assert!(!network.is_host_address(Ipv4Addr::new(10, 0, 0, 0)));
+        assert!(!network.is_host_address(Ipv4Addr::new(10, 255, 255, 255)));
+        assert!(!network.is_host_address(Ipv4Addr::new(11, 0, 0, 1)));
+        let point_to_point = Network::try_from("10.0.0.0/31").unwrap();
+        assert!(point_to_point.is_host_address(Ipv4Addr::new(10, 0, 0, 0)));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let wide = Network::try_from("10.0.0.0/8").unwrap();
+        let narrow = Network::try_from("10.1.0.0/16").unwrap();
+        let other = Network::try_from("172.16.0.0/16").unwrap();
+        assert!(wide.overlaps(&narrow));
+        assert!(narrow.overlaps(&wide));
+        assert!(!wide.overlaps(&other));
+    }
+
+    #[test]
+    fn test_missing_subnet_mask() {
+        assert!(matches!(Network::try_from("10.0.0.1"), Err(ParseNetworkError::SubNetMaskMissing(_))));
+    }
 }
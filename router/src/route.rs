@@ -64,10 +64,10 @@ impl TryFrom<&str> for Route {
         let mut data_iter = str_encoding.split_whitespace();
         let network_repr = data_iter
             .next()
-            .expect("invalid route str representation: network data not found");
+            .ok_or_else(|| ParseRouteError::InvalidFormat(str_encoding.to_owned()))?;
         let distance_repr = data_iter
             .nth(1)
-            .expect("invalid route str representation: distance missing");
+            .ok_or_else(|| ParseRouteError::InvalidFormat(str_encoding.to_owned()))?;
 
         let network = Network::try_from(network_repr).map_err(ParseRouteError::from)?;
         let distance = Distance::try_from(distance_repr).map_err(ParseRouteError::from)?;
@@ -95,6 +95,7 @@ impl Display for Route {
 
 #[derive(Debug)]
 pub enum ParseRouteError {
+    InvalidFormat(String),
     ParseNetworkError(ParseNetworkError),
     ParseDistanceError(ParseDistanceError),
 }
@@ -114,6 +115,9 @@ impl From<ParseDistanceError> for ParseRouteError {
 impl Display for ParseRouteError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            ParseRouteError::InvalidFormat(repr) => {
+                write!(f, "invalid route str representation: {}", repr)
+            }
             ParseRouteError::ParseNetworkError(err) => {
                 write!(f, "{}", err)
             }
@@ -127,10 +131,11 @@ impl Display for ParseRouteError {
 
 type RouteUdpPacketBuffer = [u8; 9];
 
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
+#[derive(Hash, Eq, PartialEq, Copy, Clone, Default)]
 pub struct RouteUdpPacket(RouteUdpPacketBuffer);
 
 impl RouteUdpPacket {
+    pub const LEN: usize = Self::DISTANCE_BYTES.end;
     /* Route binary format parameters */
     const ADDRESS_BYTES_LEN: usize = 4;
     const DISTANCE_BYTES_LEN: usize = 4;
@@ -176,12 +181,6 @@ impl AsMut<[u8]> for RouteUdpPacket {
     fn as_mut(&mut self) -> &mut [u8] { &mut self.0 }
 }
 
-impl Default for RouteUdpPacket {
-    fn default() -> Self {
-        Self(Default::default())
-    }
-}
-
 impl From<&Route> for RouteUdpPacket {
     fn from(route: &Route) -> Self {
         let mut buffer: RouteUdpPacketBuffer = Default::default();
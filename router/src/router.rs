@@ -1,12 +1,22 @@
 use std::fmt::{Display, Formatter};
+use std::io;
 use std::io::{ErrorKind};
 use std::net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket};
-use std::time::Duration;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::mem;
+use std::time::{Duration, Instant};
 use std::thread;
-use std::str::FromStr;
 
-use crate::route::Network;
-use crate::routing_table::{Route, RouteUdpPacket, RoutingTable, RoutingTableEntry};
+use reactor::{EventType, Registry, TimeoutDuration, Token};
+
+use crate::config::RouterConfig;
+use crate::control::ControlSocket;
+#[cfg(feature = "kernel-routes")]
+use crate::kernel::{KernelMode, KernelRoutes};
+use crate::neighbours::{HelloPacket, Neighbours};
+use crate::route::{Network, Route};
+use crate::routing_table::{RouteUdpPacket, RoutingTable};
 
 
 #[allow(unused_macros)]
@@ -23,18 +33,30 @@ macro_rules! syscall {
 
 const RIP_PORT_NUMBER: u16 = 54321;
 
+/// Packets exchanged by the routers, told apart by their length.
+pub enum Packet {
+    Route(RouteUdpPacket),
+    Hello(HelloPacket),
+}
+
 pub struct Nic {
     socket: UdpSocket,
     ip_address: Ipv4Addr,
+    /// Network the interface is attached to, hellos are broadcast there.
+    network: Network,
 }
 
 impl Nic {
-    pub fn new(ip_address: Ipv4Addr) -> Self {
+    pub fn new(ip_address: Ipv4Addr, network: Network) -> Self {
         let socket_address = SocketAddrV4::new(ip_address, RIP_PORT_NUMBER);
         let socket = UdpSocket::bind(socket_address).unwrap();
-        // socket.set_nonblocking(true).unwrap();
+        socket.set_nonblocking(true).unwrap();
         socket.set_broadcast(true).unwrap();
-        Self { socket, ip_address }
+        Self { socket, ip_address, network }
+    }
+
+    pub fn ip_address(&self) -> Ipv4Addr {
+        self.ip_address
     }
 
     pub fn broadcast(&self, dest_net: &Network, packet: &[u8]) {
@@ -45,36 +67,48 @@ impl Nic {
         };
     }
 
-    /// Note: Socket should be set to non blocking mode so this call does not hang.
-    pub fn collect_route_packets_packets(&mut self) -> Vec<(RouteUdpPacket, Ipv4Addr)> {
+    pub fn say_hello(&self, hello: &HelloPacket) {
+        self.broadcast(&self.network, &hello.to_bytes());
+    }
+
+    /// Packets received since the last call, with their senders. Malformed packets are skipped.
+    pub fn receive(&mut self) -> Vec<(Packet, Ipv4Addr)> {
         let mut packets = Vec::new();
+        /* one spare byte, so longer packets are not mistaken for shorter ones when truncated */
+        let mut buffer = [0; RouteUdpPacket::LEN + 1];
         loop {
-            let mut udp_packet = RouteUdpPacket::default();
-            let (bytes_received, sender) = self.socket.recv_from(udp_packet.as_mut()).unwrap();
-            if bytes_received > 0 {
-                if let IpAddr::V4(address) = sender.ip() {
-                    packets.push((udp_packet, address));
-                } else {
-                    panic!("invalid ip address type")
+            let (bytes_received, sender) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("warning: could not receive on {}: {}", self.ip_address, err);
+                    break;
                 }
-            } else {
-                break
-            }
+            };
+            let IpAddr::V4(sender) = sender.ip() else { continue };
+            let packet = match bytes_received {
+                RouteUdpPacket::LEN => {
+                    let mut route = RouteUdpPacket::default();
+                    route.as_mut().copy_from_slice(&buffer[..RouteUdpPacket::LEN]);
+                    Packet::Route(route)
+                }
+                _ => match HelloPacket::try_from(&buffer[..bytes_received]) {
+                    Ok(hello) => Packet::Hello(hello),
+                    Err(err) => {
+                        eprintln!("warning: packet from {sender} ignored: {err}");
+                        continue;
+                    }
+                },
+            };
+            packets.push((packet, sender));
         }
         packets
     }
 }
 
-impl TryFrom<&str> for Nic {
-    type Error = <Ipv4Addr as FromStr>::Err;
-
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let address_repr = value
-            .split_whitespace()
-            .next().expect("missing network")
-            .split("/")
-            .next().expect("incorrect representation");
-        Ok(Self::new(Ipv4Addr::from_str(dbg!(address_repr))?))
+impl AsRawFd for Nic {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
     }
 }
 
@@ -87,25 +121,193 @@ impl Display for Nic {
 pub struct Router {
     network_interfaces: Vec<Nic>,
     routing_table: RoutingTable,
+    control_socket: Option<ControlSocket>,
+    /// Watches the interfaces, the control socket and the timers of the turns and hellos.
+    registry: Registry,
+    turn_duration: Duration,
+    /// Warning is printed when more entries are added and removed in a single turn.
+    churn_threshold: Option<usize>,
+    hello_interval: Duration,
+    /// Expires every hello interval, started with the first turn.
+    hello_timer: Option<Token>,
+    neighbours: Neighbours,
+    /// Routes received during the turn, applied to the table when it ends.
+    received_routes: Vec<(RouteUdpPacket, Ipv4Addr)>,
+    #[cfg(feature = "kernel-routes")]
+    kernel_routes: Option<KernelRoutes>,
 }
 
 impl Router {
-    const RIP_TURN_WAIT_DURATION: Duration = Duration::from_secs(30);
+    pub const RIP_TURN_WAIT_DURATION: Duration = Duration::from_secs(30);
+    pub const HELLO_INTERVAL: Duration = Duration::from_secs(5);
+    /// Neighbour is lost after this many hellos in a row did not arrive.
+    const HOLD_MULTIPLIER: u32 = 3;
+    /// Longest wait for packets, neighbours that went silent are noticed at least this often.
+    const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
     pub fn new(network_interfaces: Vec<Nic>, routing_table: RoutingTable) -> Self {
-        Self { network_interfaces, routing_table }
+        let mut registry = Registry::new().unwrap();
+        for nic in &network_interfaces {
+            registry.add_interest(EventType::Read, nic.as_raw_fd()).unwrap();
+        }
+        Self {
+            network_interfaces,
+            routing_table,
+            control_socket: None,
+            registry,
+            turn_duration: Self::RIP_TURN_WAIT_DURATION,
+            churn_threshold: None,
+            hello_interval: Self::HELLO_INTERVAL,
+            hello_timer: None,
+            neighbours: Neighbours::default(),
+            received_routes: Vec::new(),
+            #[cfg(feature = "kernel-routes")]
+            kernel_routes: None,
+        }
+    }
+
+    /// Time spent collecting routes from the neighbours in every turn.
+    pub fn with_turn_duration(mut self, duration: Duration) -> Self {
+        self.turn_duration = duration;
+        self
+    }
+
+    /// Warns when more than `threshold` entries are added or removed in a single turn,
+    /// which usually means routes flap somewhere in the topology.
+    pub fn with_churn_warning(mut self, threshold: usize) -> Self {
+        self.churn_threshold = Some(threshold);
+        self
+    }
+
+    /// Time between hellos. Neighbours consider the router lost after three intervals without one,
+    /// independently of the turn duration.
+    pub fn with_hello_interval(mut self, interval: Duration) -> Self {
+        self.hello_interval = interval;
+        self
+    }
+
+    /// Answers queries about the routing table on a unix socket at `path`, see `ControlCommand`.
+    pub fn with_control_socket(mut self, path: &Path) -> io::Result<Self> {
+        let control_socket = ControlSocket::bind(path)?;
+        self.registry.add_interest(EventType::Read, control_socket.as_raw_fd())?;
+        self.control_socket = Some(control_socket);
+        Ok(self)
+    }
+
+    /// Mirrors learned routes in the kernel routing table, or only prints how it would change it.
+    /// Routes already in the table are installed right away.
+    #[cfg(feature = "kernel-routes")]
+    pub fn with_kernel_routes(mut self, mode: KernelMode) -> Self {
+        let kernel_routes = KernelRoutes::new(mode);
+        kernel_routes.install(self.routing_table.routes());
+        self.routing_table.enable_change_feed();
+        self.kernel_routes = Some(kernel_routes);
+        self
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.routing_table
     }
 
     pub fn execute_rip_turn(&mut self) {
         self.broadcast_routes();
-        thread::sleep(Router::RIP_TURN_WAIT_DURATION);
+        self.wait(self.turn_duration);
+        for (packet, sender) in mem::take(&mut self.received_routes) {
+            let (network, distance) = packet.into();
+            self.routing_table.update(network, distance, sender);
+        }
+        let churn = self.routing_table.end_turn();
+        self.publish_changes();
+        if self.churn_threshold.is_some_and(|threshold| churn.total() > threshold) {
+            eprintln!("warning: high routing table churn in the last turn: {churn}");
+        }
+    }
+
+    /// Waits for `duration` exchanging hellos with the neighbours and collecting their routes.
+    /// Control socket queries are answered in the meantime.
+    fn wait(&mut self, duration: Duration) {
+        let turn = self.registry.add_timer(duration).unwrap_or_else(|err| panic!("could not time the turn: {err}"));
+        let hellos = match self.hello_timer {
+            Some(hellos) => hellos,
+            None => {
+                self.say_hello();
+                let hellos = self.registry.add_timer(self.hello_interval)
+                    .unwrap_or_else(|err| panic!("could not time the hellos: {err}"));
+                *self.hello_timer.insert(hellos)
+            }
+        };
+        let mut turn_over = false;
+        loop {
+            if let Some(control_socket) = &mut self.control_socket {
+                control_socket.serve_pending(&mut self.routing_table);
+            }
+            self.receive_packets(Instant::now());
+            self.publish_changes();
+            if turn_over {
+                break;
+            }
+            let ready = match self.registry.await_events(&TimeoutDuration::Finite(Self::EXPIRY_CHECK_INTERVAL)) {
+                Ok(ready) => ready,
+                Err(err) => {
+                    eprintln!("warning: could not wait for packets: {err}");
+                    thread::sleep(Self::EXPIRY_CHECK_INTERVAL);
+                    continue;
+                }
+            };
+            if ready.iter().any(|event| event.token == hellos) {
+                self.say_hello();
+            }
+            turn_over = ready.iter().any(|event| event.token == turn);
+        }
+        if let Err(err) = self.registry.delete_timer(turn) {
+            eprintln!("warning: could not stop the turn timer: {err}");
+        }
+    }
+
+    /// Sends changes of the table to the control socket subscribers and to the kernel table.
+    fn publish_changes(&mut self) {
+        let changes = self.routing_table.take_changes();
+        #[cfg(feature = "kernel-routes")]
+        if let Some(kernel_routes) = &self.kernel_routes {
+            kernel_routes.apply(&changes);
+        }
+        if let Some(control_socket) = &mut self.control_socket {
+            control_socket.publish(&changes);
+        }
+    }
+
+    fn say_hello(&self) {
+        let hello = HelloPacket::new(self.hello_interval * Self::HOLD_MULTIPLIER);
+        for nic in &self.network_interfaces {
+            nic.say_hello(&hello);
+        }
+    }
+
+    /// Receives pending packets and poisons routes through the neighbours that went silent
+    /// for longer than their hold time.
+    fn receive_packets(&mut self, now: Instant) {
+        let own_addresses = self.network_interfaces.iter().map(Nic::ip_address).collect::<Vec<_>>();
         for nic in &mut self.network_interfaces {
-            let packets = nic.collect_route_packets_packets();
-            for (packet, sender) in packets {
-                let (network, distance) = packet.into();
-                self.routing_table.update(network, distance, sender);
+            for (packet, sender) in nic.receive() {
+                /* broadcasts come back to the sender */
+                if own_addresses.contains(&sender) {
+                    continue;
+                }
+                match packet {
+                    Packet::Route(route) => self.received_routes.push((route, sender)),
+                    Packet::Hello(hello) => {
+                        if self.neighbours.hello_received(sender, &hello, now) {
+                            eprintln!("neighbour {sender} is up");
+                        }
+                    }
+                }
             }
         }
+        for neighbour in self.neighbours.expire(now) {
+            eprintln!("warning: neighbour {neighbour} stopped sending hellos, its routes are unreachable");
+            self.received_routes.retain(|(_, sender)| *sender != neighbour);
+            self.routing_table.neighbour_lost(neighbour);
+        }
     }
 
     fn broadcast_routes(&self) {
@@ -120,18 +322,22 @@ impl Router {
     }
 }
 
-impl From<&str> for Router {
-    fn from(repr: &str) -> Self {
-        let mut lines = repr.lines();
-        lines.next().expect("router configuration line missing: no network interface count specified");
+impl From<RouterConfig> for Router {
+    fn from(config: RouterConfig) -> Self {
         let network_interfaces = Vec::from_iter(
-            lines.clone().map(|line| Nic::try_from(line).unwrap())
-        );
-        let routes = Vec::from_iter(
-            lines.map(|line| Route::try_from(line).unwrap() )
+            config.addresses().map(|interface| Nic::new(interface.address, interface.network))
         );
-
-        let routing_table = RoutingTable::new(routes);
+        let mut routing_table = RoutingTable::new(config.direct_routes());
+        for route in config.static_routes() {
+            /* configuration validation guarantees networks are unique */
+            routing_table.add_static_route(route.network, route.connection_type).unwrap();
+        }
+        for route in config.learned_routes() {
+            routing_table.add_route_with_indirect_connection(Route::new(route.network, route.distance), route.next_hop).unwrap();
+        }
+        for offset in config.metric_offsets() {
+            routing_table.set_metric_offset(offset.neighbour, offset.offset);
+        }
         Self::new(network_interfaces, routing_table)
     }
 }
@@ -1,25 +1,40 @@
 use std::collections::{HashMap, HashSet};
-use std::collections::hash_map::Entry;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::hash::Hash;
 use std::io::{BufRead, BufReader};
+use std::mem;
 use std::net::Ipv4Addr;
 use std::ops::{AddAssign, SubAssign};
+use std::time::SystemTime;
 
 pub use crate::route::{Route, Distance, Network, RouteUdpPacket, SubNetMask};
 use crate::route::ParseRouteError;
 use crate::network::ParseNetworkError;
 use crate::distance::ParseDistanceError;
+use crate::history::{ChangeReason, RouteChange, RouteHistory};
+use crate::stats::{Churn, TableStatistics};
 use crate::routing_table::ConnectionType::Via;
 
 /// Possible network connection types.
 /// Routing rules can specify that the router is either *directly connected* to the
 /// destination network or that the packet should be forwarded further *Via* some other router.
+///
+/// Packets to *BlackHole* networks are silently dropped and the route is not advertised.
+/// *Reject* networks are advertised as unreachable so the neighbours stop sending traffic there.
+/// Neither of them is ever replaced by a learned route.
 #[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
 pub enum ConnectionType {
     Direct,
     Via(Ipv4Addr),
+    BlackHole,
+    Reject,
+}
+
+impl ConnectionType {
+    pub fn is_static(&self) -> bool {
+        matches!(self, ConnectionType::BlackHole | ConnectionType::Reject)
+    }
 }
 
 impl Display for ConnectionType {
@@ -31,6 +46,12 @@ impl Display for ConnectionType {
             ConnectionType::Via(addr) => {
                 write!(f, "via {}", addr)
             }
+            ConnectionType::BlackHole => {
+                write!(f, "black hole")
+            }
+            ConnectionType::Reject => {
+                write!(f, "rejected")
+            }
         }
     }
 }
@@ -137,6 +158,7 @@ impl TryFrom<&str> for DirectConnectionEntry {
 struct ConnectionErrorRegistry(HashMap<Network, u8>);
 
 impl ConnectionErrorRegistry {
+    /// Number of turns unreachable learned routes are advertised before they are removed.
     const MAX_STALL_TURNS: usize = 3;
 }
 
@@ -195,6 +217,14 @@ There is an immense terminological chaos on the internet when it comes to this t
 pub struct RoutingTable {
     entries: HashMap<Network, (Distance, ConnectionType)>,
     connection_error_registry: ConnectionErrorRegistry,
+    history: RouteHistory,
+    /// Changes not yet taken with `take_changes`, `None` unless the feed was enabled.
+    change_feed: Option<Vec<(Network, RouteChange)>>,
+    /// Added to the distance of every route learned from the neighbour.
+    metric_offsets: HashMap<Ipv4Addr, u32>,
+    churn: Churn,
+    last_churn: Churn,
+    turns: u64,
 }
 
 /*
@@ -215,22 +245,98 @@ impl RoutingTable {
         direct_connections: Vec<Route>,
     ) -> Self
     {
-        let entries = HashMap::from_iter(
-            direct_connections.into_iter()
-                .map(|route| {
-                    let Route { network, distance } = route;
-                    (network, (distance, ConnectionType::Direct))
-                })
-        );
-        Self { entries, ..Self::default() }
+        let mut table = Self::default();
+        for Route { network, distance } in direct_connections {
+            table.entries.insert(network, (distance, ConnectionType::Direct));
+            table.record(network, None, Some((distance, ConnectionType::Direct)), ChangeReason::Configured);
+        }
+        table
+    }
+
+    /// Keeps last `capacity` changes of every route, see `history`.
+    pub fn with_history_capacity(mut self, capacity: usize) -> Self {
+        let mut history = RouteHistory::new(capacity);
+        for network in self.history.networks() {
+            for change in self.history.of(network) {
+                history.record(*network, *change);
+            }
+        }
+        self.history = history;
+        self
+    }
+
+    /// Penalizes routes learned from `neighbour` by `offset`.
+    pub fn set_metric_offset(&mut self, neighbour: Ipv4Addr, offset: u32) {
+        self.metric_offsets.insert(neighbour, offset);
+    }
+
+    pub fn metric_offset(&self, neighbour: Ipv4Addr) -> u32 {
+        self.metric_offsets.get(&neighbour).copied().unwrap_or_default()
+    }
+
+    pub fn history(&self) -> &RouteHistory {
+        &self.history
+    }
+
+    /// Starts collecting changes for `take_changes`, until then they are only kept in the history.
+    pub fn enable_change_feed(&mut self) {
+        self.change_feed.get_or_insert_with(Vec::new);
+    }
+
+    /// Changes applied since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<(Network, RouteChange)> {
+        self.change_feed.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    /// Closes the current turn, returns the churn of entries during it.
+    /// Learned routes that stayed unreachable for `MAX_STALL_TURNS` turns are removed.
+    pub fn end_turn(&mut self) -> Churn {
+        self.expire_stalled_routes();
+        self.last_churn = mem::take(&mut self.churn);
+        self.turns += 1;
+        self.last_churn
+    }
+
+    pub fn statistics(&self) -> TableStatistics {
+        TableStatistics {
+            entries: self.entries.len(),
+            approximate_bytes: self.approximate_bytes(),
+            turns: self.turns,
+            last_turn: self.last_churn,
+            current_turn: self.churn,
+        }
+    }
+
+    fn approximate_bytes(&self) -> usize {
+        self.entries.capacity() * mem::size_of::<(Network, (Distance, ConnectionType))>()
+            + self.metric_offsets.capacity() * mem::size_of::<(Ipv4Addr, u32)>()
+            + self.history.approximate_bytes()
+    }
+
+    fn record(
+        &mut self,
+        network: Network,
+        old: Option<(Distance, ConnectionType)>,
+        new: Option<(Distance, ConnectionType)>,
+        reason: ChangeReason,
+    ) {
+        if old != new {
+            self.churn.count(&old, &new);
+            let change = RouteChange { at: SystemTime::now(), old, new, reason };
+            self.history.record(network, change);
+            if let Some(feed) = &mut self.change_feed {
+                feed.push((network, change));
+            }
+        }
     }
 
     /// Result if route already exits.
     fn add_route_with_connection(&mut self, route: Route, connection_type: ConnectionType) -> Result<(), String> {
         let Route { network, distance } = route;
-        if let Some(_) = self.entries.insert(network, (distance, connection_type)) {
+        if self.entries.insert(network, (distance, connection_type)).is_some() {
             Err(format!("Routing table rule already exists for network: {network}"))
         } else {
+            self.record(network, None, Some((distance, connection_type)), ChangeReason::Configured);
             Ok(())
         }
     }
@@ -240,6 +346,13 @@ impl RoutingTable {
         self.add_route_with_connection(route, ConnectionType::Direct)
     }
 
+    /// Adds black hole or reject route, they are always considered unreachable.
+    /// Result if route already exits.
+    pub fn add_static_route(&mut self, network: Network, connection_type: ConnectionType) -> Result<(), String> {
+        debug_assert!(connection_type.is_static());
+        self.add_route_with_connection(Route::new(network, Distance::Infinite), connection_type)
+    }
+
     /// Result if route already exits.
     pub fn add_route_with_indirect_connection(&mut self, route: Route, next_hop: Ipv4Addr) -> Result<(), String> {
         self.add_route_with_connection(route, ConnectionType::Via(next_hop))
@@ -247,42 +360,110 @@ impl RoutingTable {
 
     /// removes connection, todo: maybe result when no matching entry exists.
     fn remove(&mut self, route: &Route) -> Result<(), String> {
-        if let Some(_) = self.entries.remove(route.network()) {
-            Err(String::from(format!("No rule for network: {}", route.network())))
-        } else {
-            Ok(())
+        match self.entries.remove(route.network()) {
+            None => Err(format!("No rule for network: {}", route.network())),
+            old => {
+                self.record(*route.network(), old, None, ChangeReason::Removed);
+                Ok(())
+            }
         }
     }
 
     /// Result if no entry for specified network.
+    /// Every change of the route is recorded in the history.
+    /// Metric offset of the `sender` is added to the advertised `distance`.
     pub fn update(&mut self, network: Network, distance: Distance, sender: Ipv4Addr) {
-        match self.entries.entry(network) {
-            Entry::Occupied(mut entry) => {
-                let &mut (old_distance, connection_type) = entry.get_mut();
-                match connection_type {
-                    ConnectionType::Direct => { panic!("distance to directly connected network must not change") }
-                    ConnectionType::Via(router_ip) => {
-                        if router_ip == sender { /* Whatever the distance update */
-                            entry.insert((distance, connection_type));
-                        } else {
-                            if distance < old_distance {
-                                entry.insert((distance, ConnectionType::Via(sender)));
-                            }
-                        }
-                    }
-                }
-            }
-            Entry::Vacant(mut entry) => {
-                entry.insert((distance, ConnectionType::Via(sender)));
+        let distance = distance.offset(self.metric_offset(sender));
+        let old = self.entries.get(&network).copied();
+        let reason = match old {
+            Some((_, ConnectionType::Direct)) => { panic!("distance to directly connected network must not change") }
+            Some((_, ConnectionType::BlackHole | ConnectionType::Reject)) => { /* static routes are never replaced */ return }
+            /* Whatever the distance update */
+            Some((_, ConnectionType::Via(router_ip))) if router_ip == sender => ChangeReason::NextHopAdvertisement,
+            Some((old_distance, ConnectionType::Via(_))) if distance < old_distance => ChangeReason::BetterPath,
+            Some(_) => return,
+            None => ChangeReason::Learned,
+        };
+        let new = (distance, ConnectionType::Via(sender));
+        self.entries.insert(network, new);
+        self.record(network, old, Some(new), reason);
+    }
+
+    /// Adds a route exported from another run, see `TableRoutes`. Routes already in the table are kept,
+    /// unless they are learned and longer than the imported learned route. Returns whether the table changed.
+    pub fn import(&mut self, network: Network, distance: Distance, connection_type: ConnectionType) -> bool {
+        debug_assert!(connection_type != ConnectionType::Direct);
+        let old = self.entries.get(&network).copied();
+        match (old, connection_type) {
+            (None, _) => {}
+            (Some((old_distance, Via(_))), Via(_)) if distance < old_distance => {}
+            _ => return false,
+        }
+        let new = (distance, connection_type);
+        self.entries.insert(network, new);
+        self.record(network, old, Some(new), ChangeReason::Imported);
+        true
+    }
+
+    /// Poisons routes through `neighbour` - they are advertised as unreachable, so the
+    /// neighbours stop using them, until a path is advertised again or they expire.
+    pub fn neighbour_lost(&mut self, neighbour: Ipv4Addr) {
+        let networks = self.entries
+            .iter()
+            .filter(|(_, (distance, connection_type))| {
+                *connection_type == Via(neighbour) && *distance != Distance::Infinite
+            })
+            .map(|(network, _)| *network)
+            .collect::<Vec<_>>();
+        for network in networks {
+            let new = (Distance::Infinite, Via(neighbour));
+            let old = self.entries.insert(network, new);
+            self.record(network, old, Some(new), ChangeReason::NeighbourLost);
+        }
+    }
+
+    fn expire_stalled_routes(&mut self) {
+        let stalled = self.entries
+            .iter()
+            .filter(|(_, (distance, connection_type))| {
+                matches!(connection_type, Via(_)) && *distance == Distance::Infinite
+            })
+            .map(|(network, _)| *network)
+            .collect::<HashSet<_>>();
+        let stall_turns = &mut self.connection_error_registry.0;
+        /* routes that became reachable again start counting from zero */
+        stall_turns.retain(|network, _| stalled.contains(network));
+        let mut expired = Vec::new();
+        for network in stalled {
+            let turns = stall_turns.entry(network).or_default();
+            *turns += 1;
+            if *turns as usize > ConnectionErrorRegistry::MAX_STALL_TURNS {
+                stall_turns.remove(&network);
+                expired.push(network);
             }
         }
+        for network in expired {
+            let old = self.entries.remove(&network);
+            self.record(network, old, None, ChangeReason::Removed);
+        }
     }
 
+    /// All entries of the table, including the ones that are not advertised.
+    pub fn routes(&self) -> impl Iterator<Item=(Network, Distance, ConnectionType)> + '_ {
+        self.entries
+            .iter()
+            .map(|(&network, &(distance, connection_type))| (network, distance, connection_type))
+    }
+
+    /// Routes advertised to the neighbours, black hole routes are kept local.
     pub fn entries(&self) -> impl Iterator<Item=Route> + '_ {
-        self.entries.iter().map(|entry| {
-            let (&network, &(distance, _)) = entry.clone();
-            Route::new(network, distance)
-        })
+        self.entries
+            .iter()
+            .filter(|(_, (_, connection_type))| *connection_type != ConnectionType::BlackHole)
+            .map(|entry| {
+                let (&network, &(distance, _)) = entry;
+                Route::new(network, distance)
+            })
     }
 }
 
@@ -307,9 +488,135 @@ impl Display for RoutingTable {
 
 #[cfg(test)]
 mod tests {
-    use super::RoutingTable;
+    use super::*;
 
     use std::env;
     use std::fs::File;
 
+    #[test]
+    fn test_static_routes_are_not_replaced() {
+        let black_hole = Network::try_from("192.168.0.0/16").unwrap();
+        let reject = Network::try_from("172.16.0.0/12").unwrap();
+        let mut table = RoutingTable::default();
+        table.add_static_route(black_hole, ConnectionType::BlackHole).unwrap();
+        table.add_static_route(reject, ConnectionType::Reject).unwrap();
+
+        let neighbour = Ipv4Addr::new(10, 0, 0, 2);
+        table.update(black_hole, Distance::new(1), neighbour);
+        table.update(reject, Distance::new(1), neighbour);
+        assert_eq!(table.entries[&black_hole], (Distance::Infinite, ConnectionType::BlackHole));
+        assert_eq!(table.entries[&reject], (Distance::Infinite, ConnectionType::Reject));
+
+        let advertised = table.entries().collect::<Vec<_>>();
+        assert_eq!(advertised.len(), 1);
+        assert_eq!(advertised[0].to_string(), "172.16.0.0/12 unreachable");
+        assert!(table.to_string().contains("192.168.0.0/16 unreachable black hole"));
+    }
+
+    #[test]
+    fn test_metric_offset_penalizes_neighbour() {
+        let network = Network::try_from("172.16.0.0/16").unwrap();
+        let (penalized, other) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+        let mut table = RoutingTable::default();
+        table.set_metric_offset(penalized, 5);
+        table.update(network, Distance::new(2), penalized);
+        assert_eq!(table.entries[&network], (Distance::new(7), ConnectionType::Via(penalized)));
+        /* longer path advertised by the other neighbour is now the better one */
+        table.update(network, Distance::new(4), other);
+        assert_eq!(table.entries[&network], (Distance::new(4), ConnectionType::Via(other)));
+        table.update(network, Distance::new(1), penalized);
+        assert_eq!(table.entries[&network], (Distance::new(4), ConnectionType::Via(other)));
+    }
+
+    #[test]
+    fn test_churn_per_turn() {
+        let direct = Route::new(Network::try_from("10.0.0.0/8").unwrap(), Distance::new(1));
+        let mut table = RoutingTable::with_direct_connections(vec![direct]);
+        assert_eq!(table.end_turn(), Churn { added: 1, removed: 0, changed: 0 });
+
+        let learned = Network::try_from("172.16.0.0/16").unwrap();
+        let neighbour = Ipv4Addr::new(10, 0, 0, 2);
+        table.update(learned, Distance::new(3), neighbour);
+        table.update(learned, Distance::new(3), neighbour);
+        table.update(learned, Distance::new(4), neighbour);
+        table.remove(&Route::new(learned, Distance::new(4))).unwrap();
+        let statistics = table.statistics();
+        assert_eq!(statistics.current_turn, Churn { added: 1, removed: 1, changed: 1 });
+        assert_eq!(statistics.turns, 1);
+        assert_eq!(statistics.entries, 1);
+        assert!(statistics.approximate_bytes >= mem::size_of::<(Network, (Distance, ConnectionType))>());
+
+        table.end_turn();
+        let statistics = table.statistics();
+        assert_eq!(statistics.last_turn.total(), 2);
+        assert_eq!(statistics.current_turn, Churn::default());
+    }
+
+    #[test]
+    fn test_lost_neighbour_routes_are_poisoned_then_removed() {
+        let direct = Route::new(Network::try_from("10.0.0.0/8").unwrap(), Distance::new(1));
+        let (lost, other) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+        let (stalled, recovered) = (Network::try_from("172.16.0.0/16").unwrap(), Network::try_from("192.168.1.0/24").unwrap());
+        let mut table = RoutingTable::with_direct_connections(vec![direct]);
+        table.update(stalled, Distance::new(3), lost);
+        table.update(recovered, Distance::new(2), lost);
+        table.update(Network::try_from("192.168.2.0/24").unwrap(), Distance::new(2), other);
+
+        table.neighbour_lost(lost);
+        assert_eq!(table.entries[&stalled], (Distance::Infinite, Via(lost)));
+        assert_eq!(table.history().of(&stalled).last().unwrap().reason, ChangeReason::NeighbourLost);
+        /* poisoned routes are advertised, so the neighbours learn about the loss */
+        assert_eq!(table.entries().filter(|route| route.distance == Distance::Infinite).count(), 2);
+
+        table.end_turn();
+        table.update(recovered, Distance::new(4), other);
+        for _ in 0..ConnectionErrorRegistry::MAX_STALL_TURNS - 1 {
+            table.end_turn();
+            assert!(table.entries.contains_key(&stalled));
+        }
+        assert_eq!(table.end_turn().removed, 1);
+        assert!(!table.entries.contains_key(&stalled));
+        assert_eq!(table.entries[&recovered], (Distance::new(4), Via(other)));
+        assert_eq!(table.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_history() {
+        let network = Network::try_from("172.16.0.0/16").unwrap();
+        let (first, second) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+        let mut table = RoutingTable::default().with_history_capacity(3);
+        table.update(network, Distance::new(5), first);
+        /* longer path through another neighbour is ignored, so is repeated advertisement */
+        table.update(network, Distance::new(7), second);
+        table.update(network, Distance::new(5), first);
+        table.update(network, Distance::new(3), second);
+        table.update(network, Distance::new(4), second);
+        let changes = table.history().of(&network).collect::<Vec<_>>();
+        let reasons = changes.iter().map(|change| change.reason).collect::<Vec<_>>();
+        assert_eq!(reasons, [ChangeReason::Learned, ChangeReason::BetterPath, ChangeReason::NextHopAdvertisement]);
+        assert_eq!(changes[1].old, Some((Distance::new(5), ConnectionType::Via(first))));
+        assert_eq!(changes[1].new, Some((Distance::new(3), ConnectionType::Via(second))));
+    }
+
+    #[test]
+    fn test_import_keeps_better_routes() {
+        let direct = Network::try_from("10.0.0.0/8").unwrap();
+        let (shorter, longer, new) = (
+            Network::try_from("172.16.0.0/16").unwrap(),
+            Network::try_from("172.17.0.0/16").unwrap(),
+            Network::try_from("172.18.0.0/16").unwrap(),
+        );
+        let neighbour = Ipv4Addr::new(10, 0, 0, 2);
+        let mut table = RoutingTable::with_direct_connections(vec![Route::new(direct, Distance::new(1))]);
+        table.update(shorter, Distance::new(2), neighbour);
+        table.update(longer, Distance::new(6), neighbour);
+
+        assert!(!table.import(direct, Distance::Infinite, ConnectionType::Reject));
+        assert!(!table.import(shorter, Distance::new(3), Via(Ipv4Addr::new(10, 0, 0, 3))));
+        assert!(table.import(longer, Distance::new(3), Via(Ipv4Addr::new(10, 0, 0, 3))));
+        assert!(table.import(new, Distance::Infinite, ConnectionType::BlackHole));
+        assert_eq!(table.entries[&direct], (Distance::new(1), ConnectionType::Direct));
+        assert_eq!(table.entries[&longer], (Distance::new(3), Via(Ipv4Addr::new(10, 0, 0, 3))));
+        assert_eq!(table.history().of(&new).last().unwrap().reason, ChangeReason::Imported);
+    }
 }
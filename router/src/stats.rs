@@ -0,0 +1,72 @@
+use std::fmt::{Display, Formatter};
+
+/// Changes of the routing table entries, counted per turn.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Churn {
+    pub added: usize,
+    pub removed: usize,
+    /// Entries whose distance or next hop changed.
+    pub changed: usize,
+}
+
+impl Churn {
+    pub fn count<T>(&mut self, old: &Option<T>, new: &Option<T>) {
+        match (old, new) {
+            (None, Some(_)) => self.added += 1,
+            (Some(_), None) => self.removed += 1,
+            (Some(_), Some(_)) => self.changed += 1,
+            (None, None) => {}
+        }
+    }
+
+    /// Number of entries that appeared in or disappeared from the table.
+    pub fn total(&self) -> usize {
+        self.added + self.removed
+    }
+}
+
+impl Display for Churn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} added, {} removed, {} changed", self.added, self.removed, self.changed)
+    }
+}
+
+/// Size of the routing table and the churn of its entries.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct TableStatistics {
+    pub entries: usize,
+    /// Memory held by the entries, their history and metric offsets, allocator overhead not included.
+    pub approximate_bytes: usize,
+    /// Number of finished turns.
+    pub turns: u64,
+    pub last_turn: Churn,
+    pub current_turn: Churn,
+}
+
+impl Display for TableStatistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "entries: {}", self.entries)?;
+        writeln!(f, "approximate memory: {} bytes", self.approximate_bytes)?;
+        writeln!(f, "turns: {}", self.turns)?;
+        writeln!(f, "last turn: {}", self.last_turn)?;
+        writeln!(f, "current turn: {}", self.current_turn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count() {
+        let mut churn = Churn::default();
+        churn.count(&None, &Some(1));
+        churn.count(&None, &Some(2));
+        churn.count(&Some(1), &Some(3));
+        churn.count(&Some(2), &None);
+        churn.count::<u32>(&None, &None);
+        assert_eq!(churn, Churn { added: 2, removed: 1, changed: 1 });
+        assert_eq!(churn.total(), 3);
+        assert_eq!(churn.to_string(), "2 added, 1 removed, 1 changed");
+    }
+}
@@ -40,7 +40,12 @@ impl SubNetMask {
     }
 
     pub fn address_range(&self) -> u32 {
-        1 << self.0
+        !self.bits()
+    }
+
+    /// Returns the mask in its dotted-quad bit representation, eg. `/24` yields `0xffffff00`.
+    pub fn bits(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.0 as u32).unwrap_or(0)
     }
 }
 
@@ -109,13 +114,13 @@ mod tests {
     #[test]
     fn test_subnet_valid() {
         for i in 0..=32 {
-            assert!(matches!(SubNetMask::new(i), Some(_)));
+            assert!(SubNetMask::new(i).is_some());
         }
     }
 
     #[test]
     fn test_subnet_invalid() {
-        assert!(matches!(SubNetMask::new(33), None));
+        assert!(SubNetMask::new(33).is_none());
     }
 
     #[test]
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+
+use crate::route::{Distance, Network};
+use crate::routing_table::{ConnectionType, RoutingTable};
+
+/// Network as seen from this router, reconstructed from the routing table.
+///
+/// Directly connected networks hang off the router. Every next hop is placed in the
+/// directly connected network that contains its address, networks learned from it hang off it.
+/// Next hops outside of all directly connected networks are shown separately.
+#[derive(Debug, Default)]
+pub struct Topology {
+    direct: Vec<(Network, Distance)>,
+    /// Networks reached through every next hop.
+    neighbours: BTreeMap<Ipv4Addr, Vec<(Network, Distance)>>,
+    /// Black hole and reject routes.
+    unreachable: Vec<(Network, ConnectionType)>,
+}
+
+impl Topology {
+    const ROUTER: &'static str = "this router";
+
+    pub fn of(table: &RoutingTable) -> Self {
+        let mut topology = Self::default();
+        for (network, distance, connection_type) in table.routes() {
+            match connection_type {
+                ConnectionType::Direct => topology.direct.push((network, distance)),
+                ConnectionType::Via(next_hop) => topology.neighbours.entry(next_hop).or_default().push((network, distance)),
+                ConnectionType::BlackHole | ConnectionType::Reject => topology.unreachable.push((network, connection_type)),
+            }
+        }
+        let key = |network: &Network| (u32::from(network.prefix()), u8::from(network.subnet_mask()));
+        topology.direct.sort_by_key(|(network, _)| key(network));
+        topology.unreachable.sort_by_key(|(network, _)| key(network));
+        for networks in topology.neighbours.values_mut() {
+            networks.sort_by_key(|(network, _)| key(network));
+        }
+        topology
+    }
+
+    fn neighbours_in<'a>(&'a self, network: &'a Network) -> impl Iterator<Item=(&'a Ipv4Addr, &'a Vec<(Network, Distance)>)> {
+        self.neighbours.iter().filter(|(next_hop, _)| network.contains(**next_hop))
+    }
+
+    fn unattached_neighbours(&self) -> impl Iterator<Item=(&Ipv4Addr, &Vec<(Network, Distance)>)> {
+        self.neighbours
+            .iter()
+            .filter(|(next_hop, _)| !self.direct.iter().any(|(network, _)| network.contains(**next_hop)))
+    }
+
+    /// Graph in graphviz DOT format, eg. `dot -Tpng topology.dot -o topology.png`.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("graph topology {\n");
+        dot.push_str(&format!("  \"{}\" [shape=box, style=bold];\n", Self::ROUTER));
+        let learned = |dot: &mut String, next_hop: &Ipv4Addr, networks: &[(Network, Distance)]| {
+            dot.push_str(&format!("  \"{}\" [shape=box];\n", next_hop));
+            for (network, distance) in networks {
+                dot.push_str(&format!("  \"{}\" -- \"{}\" [label=\"{}\", style=dashed];\n", next_hop, network, distance));
+            }
+        };
+        for (network, distance) in &self.direct {
+            dot.push_str(&format!("  \"{}\" -- \"{}\" [label=\"{}\"];\n", Self::ROUTER, network, distance));
+            for (next_hop, networks) in self.neighbours_in(network) {
+                dot.push_str(&format!("  \"{}\" -- \"{}\";\n", network, next_hop));
+                learned(&mut dot, next_hop, networks);
+            }
+        }
+        for (next_hop, networks) in self.unattached_neighbours() {
+            dot.push_str(&format!("  \"{}\" -- \"{}\" [style=dotted];\n", Self::ROUTER, next_hop));
+            learned(&mut dot, next_hop, networks);
+        }
+        for (network, connection_type) in &self.unreachable {
+            dot.push_str(&format!("  \"{}\" [color=red];\n", network));
+            dot.push_str(&format!("  \"{}\" -- \"{}\" [label=\"{}\", color=red];\n", Self::ROUTER, network, connection_type));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Tree rooted at this router.
+impl Display for Topology {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", Self::ROUTER)?;
+        let mut branches = Vec::<(String, Vec<(Ipv4Addr, &Vec<(Network, Distance)>)>)>::new();
+        for (network, distance) in &self.direct {
+            let neighbours = self.neighbours_in(network).map(|(next_hop, networks)| (*next_hop, networks)).collect();
+            branches.push((format!("{} {} connected directly", network, distance), neighbours));
+        }
+        for (next_hop, networks) in self.unattached_neighbours() {
+            branches.push(("unknown network".to_owned(), vec![(*next_hop, networks)]));
+        }
+        for (network, connection_type) in &self.unreachable {
+            branches.push((format!("{} {}", network, connection_type), Vec::new()));
+        }
+
+        let branch_count = branches.len();
+        for (index, (label, neighbours)) in branches.into_iter().enumerate() {
+            let last = index + 1 == branch_count;
+            writeln!(f, "{}{}", if last { "└── " } else { "├── " }, label)?;
+            let indent = if last { "    " } else { "│   " };
+            let neighbour_count = neighbours.len();
+            for (index, (next_hop, networks)) in neighbours.into_iter().enumerate() {
+                let last = index + 1 == neighbour_count;
+                writeln!(f, "{}{}router {}", indent, if last { "└── " } else { "├── " }, next_hop)?;
+                let indent = format!("{}{}", indent, if last { "    " } else { "│   " });
+                for (index, (network, distance)) in networks.iter().enumerate() {
+                    let last = index + 1 == networks.len();
+                    writeln!(f, "{}{}{} {}", indent, if last { "└── " } else { "├── " }, network, distance)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::Route;
+
+    fn table() -> RoutingTable {
+        let direct = ["10.0.0.0/8 distance 3", "192.168.5.0/24 distance 2"]
+            .map(|repr| Route::try_from(repr).unwrap());
+        let mut table = RoutingTable::new(direct.into());
+        table.update(Network::try_from("172.16.0.0/16").unwrap(), Distance::new(7), Ipv4Addr::new(10, 0, 1, 2));
+        table.update(Network::try_from("192.168.2.0/24").unwrap(), Distance::new(4), Ipv4Addr::new(192, 168, 5, 5));
+        table.update(Network::try_from("192.168.3.0/24").unwrap(), Distance::new(5), Ipv4Addr::new(192, 168, 5, 5));
+        table.add_static_route(Network::try_from("192.168.0.0/16").unwrap(), ConnectionType::BlackHole).unwrap();
+        table
+    }
+
+    #[test]
+    fn test_ascii() {
+        let expected = "\
+this router
+├── 10.0.0.0/8 distance 3 connected directly
+│   └── router 10.0.1.2
+│       └── 172.16.0.0/16 distance 7
+├── 192.168.5.0/24 distance 2 connected directly
+│   └── router 192.168.5.5
+│       ├── 192.168.2.0/24 distance 4
+│       └── 192.168.3.0/24 distance 5
+└── 192.168.0.0/16 black hole
+";
+        assert_eq!(Topology::of(&table()).to_string(), expected);
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = Topology::of(&table()).to_dot();
+        assert!(dot.starts_with("graph topology {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("  \"this router\" -- \"10.0.0.0/8\" [label=\"distance 3\"];\n"));
+        assert!(dot.contains("  \"10.0.0.0/8\" -- \"10.0.1.2\";\n"));
+        assert!(dot.contains("  \"10.0.1.2\" -- \"172.16.0.0/16\" [label=\"distance 7\", style=dashed];\n"));
+        assert!(dot.contains("  \"this router\" -- \"192.168.0.0/16\" [label=\"black hole\", color=red];\n"));
+    }
+}
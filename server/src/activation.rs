@@ -0,0 +1,154 @@
+//! Mikołaj Depta 328690
+//!
+//! Support for the systemd socket activation protocol.
+//!
+//! Service manager binds listening sockets itself and passes them to the process starting
+//! from file descriptor 3. Their count is stored in `LISTEN_FDS` and the process they are meant
+//! for in `LISTEN_PID`. See sd_listen_fds(3).
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process;
+
+use crate::registry::syscall;
+
+pub const LISTEN_FDS_START: RawFd = 3;
+
+const LISTEN_PID: &str = "LISTEN_PID";
+const LISTEN_FDS: &str = "LISTEN_FDS";
+const LISTEN_FDNAMES: &str = "LISTEN_FDNAMES";
+
+#[derive(Debug)]
+pub enum ActivationError {
+    InvalidListenPid(String),
+    InvalidListenFds(String),
+    NotListeningSocket(RawFd),
+    Io(RawFd, io::Error),
+}
+
+impl Display for ActivationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidListenPid(repr) => write!(f, "invalid {} value: {}", LISTEN_PID, repr),
+            Self::InvalidListenFds(repr) => write!(f, "invalid {} value: {}", LISTEN_FDS, repr),
+            Self::NotListeningSocket(fd) => {
+                write!(f, "file descriptor {} is not a listening stream socket", fd)
+            }
+            Self::Io(fd, err) => write!(f, "could not inspect file descriptor {}: {}", fd, err),
+        }
+    }
+}
+
+/// Number of descriptors passed to the process with given `pid`.
+///
+/// Zero means the process was not socket activated - variables are absent
+/// or they were meant for a different process (eg. our parent).
+fn passed_fds_count(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<usize, ActivationError> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0),
+    };
+    let target = listen_pid
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| ActivationError::InvalidListenPid(listen_pid.to_owned()))?;
+    if target != pid {
+        return Ok(0);
+    }
+    listen_fds
+        .trim()
+        .parse()
+        .map_err(|_| ActivationError::InvalidListenFds(listen_fds.to_owned()))
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> Result<libc::c_int, ActivationError> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    syscall!(getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        option,
+        &mut value as *mut libc::c_int as *mut libc::c_void,
+        &mut len,
+    )).map_err(|err| ActivationError::Io(fd, err))?;
+    Ok(value)
+}
+
+/// Takes ownership of `fd` after checking that it is a listening TCP socket.
+///
+/// Descriptor is marked close-on-exec so it does not leak into child processes.
+fn listener_from_fd(fd: RawFd) -> Result<TcpListener, ActivationError> {
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM
+        || socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+        return Err(ActivationError::NotListeningSocket(fd));
+    }
+    let flags = syscall!(fcntl(fd, libc::F_GETFD)).map_err(|err| ActivationError::Io(fd, err))?;
+    syscall!(fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC))
+        .map_err(|err| ActivationError::Io(fd, err))?;
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// Listening sockets passed by the service manager, empty if the process was not socket activated.
+///
+/// Environment variables are removed so they are not inherited by child processes.
+pub fn listen_fds() -> Result<Vec<TcpListener>, ActivationError> {
+    let listen_pid = env::var(LISTEN_PID).ok();
+    let listen_fds = env::var(LISTEN_FDS).ok();
+    for variable in [LISTEN_PID, LISTEN_FDS, LISTEN_FDNAMES] {
+        env::remove_var(variable);
+    }
+    let count = passed_fds_count(listen_pid.as_deref(), listen_fds.as_deref(), process::id())?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count as RawFd)
+        .map(listener_from_fd)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+    #[test]
+    fn test_passed_fds_count() {
+        assert_eq!(passed_fds_count(None, None, 42).unwrap(), 0);
+        assert_eq!(passed_fds_count(Some("42"), None, 42).unwrap(), 0);
+        assert_eq!(passed_fds_count(Some("41"), Some("2"), 42).unwrap(), 0);
+        assert_eq!(passed_fds_count(Some("42"), Some("2"), 42).unwrap(), 2);
+        assert!(matches!(
+            passed_fds_count(Some("pid"), Some("2"), 42),
+            Err(ActivationError::InvalidListenPid(_))
+        ));
+        assert!(matches!(
+            passed_fds_count(Some("42"), Some("-1"), 42),
+            Err(ActivationError::InvalidListenFds(_))
+        ));
+    }
+
+    #[test]
+    fn test_listener_from_fd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let activated = listener_from_fd(listener.into_raw_fd()).unwrap();
+        assert_eq!(activated.local_addr().unwrap(), address);
+        let flags = syscall!(fcntl(activated.as_raw_fd(), libc::F_GETFD)).unwrap();
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+    }
+
+    #[test]
+    fn test_datagram_socket_is_rejected() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(
+            listener_from_fd(socket.as_raw_fd()),
+            Err(ActivationError::NotListeningSocket(_))
+        ));
+    }
+}
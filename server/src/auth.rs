@@ -0,0 +1,392 @@
+//! Mikołaj Depta 328690
+//!
+//! Access control with the `Basic` and `Bearer` HTTP authentication schemes.
+//!
+//! Policy is loaded from the file named by `SERVER_AUTH`, one rule per line, empty lines
+//! and `#` comments are ignored:
+//! ```text
+//! basic <path prefix> <realm> <htpasswd file>
+//! bearer <path prefix> <realm> <token file>
+//! basic /admin "Admin Area" admin.htpasswd
+//! ```
+//! Realm containing spaces is written in double quotes, it cannot contain quotes itself.
+//! Files named in the rules are relative to the policy file. Htpasswd file holds `user:password`
+//! lines, password either as plain text or as a `{SHA}` hash made with `htpasswd -s`. Token
+//! file holds one token per line. Request below more than one prefix is checked by the rule
+//! with the longest one. Requests without valid credentials are answered with 401.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use common::fs::RealFs;
+use crate::http::base64;
+use crate::http::headers::request_header::Credentials;
+use crate::http::headers::response_header::{AuthScheme, Challenge};
+use crate::http::request::Request;
+use crate::routing::Router;
+use crate::sanitizer::PathSanitizer;
+use crate::util;
+
+#[derive(Debug)]
+pub enum LoadAuthError {
+    Io(PathBuf, io::Error),
+    /// Line of the policy, htpasswd or token file that cannot be used.
+    Parse(PathBuf, usize, String),
+}
+
+impl Display for LoadAuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            Self::Parse(path, line, reason) => write!(f, "{}:{}: {}", path.display(), line, reason),
+        }
+    }
+}
+
+impl std::error::Error for LoadAuthError {}
+
+/// Stored password of a `Basic` user.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Password {
+    Plain(String),
+    Sha1([u8; 20]),
+}
+
+impl Password {
+    const SHA1_PREFIX: &'static str = "{SHA}";
+
+    fn parse(repr: &str) -> Result<Self, String> {
+        if let Some(hash) = repr.strip_prefix(Self::SHA1_PREFIX) {
+            let digest = base64::decode(hash).and_then(|digest| <[u8; 20]>::try_from(digest).ok());
+            return digest.map(Self::Sha1).ok_or_else(|| String::from("invalid {SHA} hash"));
+        }
+        if repr.starts_with('$') {
+            return Err(String::from("only {SHA} and plain text passwords are supported"));
+        }
+        Ok(Self::Plain(repr.to_owned()))
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        match self {
+            Self::Plain(stored) => constant_time_eq(stored.as_bytes(), password.as_bytes()),
+            Self::Sha1(digest) => constant_time_eq(digest, &sha1(password.as_bytes())),
+        }
+    }
+}
+
+/// Credentials a rule accepts.
+#[derive(Debug, Clone)]
+enum Accepted {
+    Users(HashMap<String, Password>),
+    Tokens(HashSet<String>),
+}
+
+/// Credentials required for requests below the path prefix.
+#[derive(Debug, Clone)]
+pub struct AuthRule {
+    prefix: String,
+    realm: String,
+    accepted: Accepted,
+}
+
+impl AuthRule {
+    /// `Basic` authentication with the users of the `htpasswd` file contents.
+    pub fn basic(prefix: &str, realm: &str, htpasswd: &str) -> Result<Self, (usize, String)> {
+        let mut users = HashMap::new();
+        for (index, line) in Self::lines(htpasswd) {
+            let (user, password) = line.split_once(':').ok_or((index, String::from("expected user:password")))?;
+            users.insert(user.to_owned(), Password::parse(password).map_err(|reason| (index, reason))?);
+        }
+        Ok(Self { prefix: prefix.to_owned(), realm: realm.to_owned(), accepted: Accepted::Users(users) })
+    }
+
+    /// `Bearer` authentication with the tokens of the `tokens` file contents.
+    pub fn bearer(prefix: &str, realm: &str, tokens: &str) -> Self {
+        let tokens = Self::lines(tokens).map(|(_, token)| token.to_owned()).collect();
+        Self { prefix: prefix.to_owned(), realm: realm.to_owned(), accepted: Accepted::Tokens(tokens) }
+    }
+
+    /// Non-empty lines which are not comments, numbered from 1.
+    /// Comments take whole lines, since `#` may be a part of a password or token.
+    fn lines(repr: &str) -> impl Iterator<Item=(usize, &str)> {
+        repr.lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// What the client is asked for when its credentials are missing or wrong.
+    pub fn challenge(&self) -> Challenge {
+        let scheme = match self.accepted {
+            Accepted::Users(_) => AuthScheme::Basic,
+            Accepted::Tokens(_) => AuthScheme::Bearer,
+        };
+        Challenge { scheme, realm: self.realm.clone() }
+    }
+
+    fn accepts(&self, credentials: Option<&Credentials>) -> bool {
+        match (&self.accepted, credentials) {
+            (Accepted::Users(users), Some(Credentials::Basic { user, password })) => {
+                users.get(user).is_some_and(|stored| stored.matches(password))
+            }
+            (Accepted::Tokens(tokens), Some(Credentials::Bearer(token))) => {
+                /* every token is compared, so the time taken does not tell which one is close */
+                tokens.iter().fold(false, |found, known| constant_time_eq(known.as_bytes(), token.as_bytes()) | found)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Rules protecting parts of the site, requests below none of their prefixes need no credentials.
+#[derive(Debug, Clone, Default)]
+pub struct AuthPolicy {
+    /// Longest prefix first, so that the most specific rule is found first.
+    rules: Vec<AuthRule>,
+}
+
+impl AuthPolicy {
+    pub const ENV_VARIABLE: &'static str = "SERVER_AUTH";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Policy from the file named by `SERVER_AUTH`, empty if it is not set.
+    /// Server does not start if the policy cannot be loaded, rather than serving unprotected content.
+    pub fn from_env() -> Self {
+        let Some(path) = env::var_os(Self::ENV_VARIABLE) else {
+            return Self::new();
+        };
+        Self::load(Path::new(&path))
+            .unwrap_or_else(|err| util::fail_with_message(&format!("{}: {}", Self::ENV_VARIABLE, err)))
+    }
+
+    /// Reads the policy file at `path` and the files its rules name, see the module documentation.
+    pub fn load(path: &Path) -> Result<Self, LoadAuthError> {
+        let read = |path: &Path| fs::read_to_string(path).map_err(|err| LoadAuthError::Io(path.to_owned(), err));
+        let base = path.parent().unwrap_or(Path::new("."));
+        let mut policy = Self::new();
+        for (index, line) in AuthRule::lines(&read(path)?) {
+            let invalid = |reason: &str| LoadAuthError::Parse(path.to_owned(), index, reason.to_owned());
+            let [scheme, prefix, realm, file] = Self::fields(line).map_err(invalid)?;
+            if !prefix.starts_with('/') {
+                return Err(invalid("path prefix has to start with /"));
+            }
+            let file = base.join(file);
+            let rule = match scheme.to_ascii_lowercase().as_str() {
+                "basic" => AuthRule::basic(prefix, realm, &read(&file)?)
+                    .map_err(|(line, reason)| LoadAuthError::Parse(file.clone(), line, reason))?,
+                "bearer" => AuthRule::bearer(prefix, realm, &read(&file)?),
+                _ => return Err(invalid(&format!("unknown scheme {scheme}, expected basic or bearer"))),
+            };
+            policy = policy.with_rule(rule);
+        }
+        Ok(policy)
+    }
+
+    /// Scheme, path prefix, realm and file of the policy `line`, realm may be quoted.
+    fn fields(line: &str) -> Result<[&str; 4], &'static str> {
+        const EXPECTED: &str = "expected <scheme> <path prefix> <realm> <file>";
+        let (scheme, rest) = line.split_once(char::is_whitespace).ok_or(EXPECTED)?;
+        let (prefix, rest) = rest.trim_start().split_once(char::is_whitespace).ok_or(EXPECTED)?;
+        let rest = rest.trim_start();
+        let (realm, file) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let (realm, file) = quoted.split_once('"').ok_or("realm is missing its closing quote")?;
+                match file.starts_with(char::is_whitespace) {
+                    true => (realm, file),
+                    false => return Err(EXPECTED),
+                }
+            }
+            None => rest.split_once(char::is_whitespace).ok_or(EXPECTED)?,
+        };
+        let file = file.trim_start();
+        if realm.contains('"') {
+            return Err("realm cannot contain quotes");
+        }
+        if file.contains(char::is_whitespace) {
+            return Err(EXPECTED);
+        }
+        Ok([scheme, prefix, realm, file])
+    }
+
+    /// Adds the `rule`, replacing the one with the same prefix.
+    pub fn with_rule(mut self, rule: AuthRule) -> Self {
+        self.rules.retain(|present| present.prefix != rule.prefix);
+        let position = self.rules.partition_point(|present| present.prefix.len() >= rule.prefix.len());
+        self.rules.insert(position, rule);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Rule protecting `path`, query is not a part of the path.
+    ///
+    /// Files are served from the normalized target, see `PathSanitizer::normalize`, so rules
+    /// are matched against it, eg. `//admin` and `/%61dmin` are both below `/admin`. Routes
+    /// see the target as it was sent, so a rule matching only the raw path still applies.
+    pub fn rule(&self, path: &Path) -> Option<&AuthRule> {
+        let path = path.to_str()?.split('?').next().unwrap_or_default();
+        let find = |path: &str| self.rules.iter().find(|rule| Router::is_below(path, &rule.prefix));
+        let normalized = PathSanitizer::<RealFs>::normalize(Path::new(path))
+            .ok()
+            .and_then(|normalized| normalized.to_str().map(|normalized| format!("/{normalized}")));
+        normalized.as_deref().and_then(find).or_else(|| find(path))
+    }
+
+    /// `Err` holds the challenge to answer the `request` with, if it lacks valid credentials.
+    pub fn check(&self, request: &Request) -> Result<(), Challenge> {
+        match self.rule(request.start_line().url()) {
+            Some(rule) if !rule.accepts(request.headers().authorization()) => Err(rule.challenge()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn constant_time_eq(lhs: &[u8], rhs: &[u8]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().zip(rhs).fold(0, |difference, (lhs, rhs)| difference | (lhs ^ rhs)) == 0
+}
+
+/// SHA-1 digest, used only to check `{SHA}` passwords of htpasswd files.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (index, word) in block.chunks(4).enumerate() {
+            words[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for index in 16..80 {
+            words[index] = (words[index - 3] ^ words[index - 8] ^ words[index - 14] ^ words[index - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (index, word) in words.iter().enumerate() {
+            let (f, k) = match index {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::common::Method;
+    use common::fs::TempDir;
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(base64::encode(&sha1(b"")), "2jmj7l5rSw0yVb/vlWAYkK/YBwk=");
+        assert_eq!(base64::encode(&sha1(b"password")), "W6ph5Mm5Pz8GgiULbPgzG37mj9g=");
+        assert_eq!(base64::encode(&sha1(&[b'a'; 1000])), "KR6abGaZSUm1e6XmUDYemPw2sbo=");
+    }
+
+    #[test]
+    fn test_policy_checks_credentials_of_the_most_specific_rule() {
+        let policy = AuthPolicy::new()
+            .with_rule(AuthRule::basic("/admin", "Admin area", "alice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\nbob:hunter#2\n").unwrap())
+            .with_rule(AuthRule::bearer("/admin/api", "API", "# tokens\nsecret-token\n"));
+        let alice = format!("Authorization: Basic {}\r\n", base64::encode(b"alice:password"));
+        let bob = format!("Authorization: Basic {}\r\n", base64::encode(b"bob:hunter#2"));
+        let wrong = format!("Authorization: Basic {}\r\n", base64::encode(b"bob:password"));
+        let bearer = "Authorization: Bearer secret-token\r\n";
+
+        assert!(policy.check(&Request::fixture(Method::GET, "/public", "", None)).is_ok());
+        assert!(policy.check(&Request::fixture(Method::GET, "/administrator", "", None)).is_ok());
+        assert!(policy.check(&Request::fixture(Method::GET, "/admin/index.html", &alice, None)).is_ok());
+        assert!(policy.check(&Request::fixture(Method::GET, "/admin?page=1", &bob, None)).is_ok());
+        assert!(policy.check(&Request::fixture(Method::GET, "/admin/api/users", bearer, None)).is_ok());
+        let challenge = policy.check(&Request::fixture(Method::GET, "/admin", &wrong, None)).unwrap_err();
+        assert_eq!(challenge.to_string(), "Basic realm=\"Admin area\"");
+        let challenge = policy.check(&Request::fixture(Method::GET, "/admin/api/users", &alice, None)).unwrap_err();
+        assert_eq!(challenge.to_string(), "Bearer realm=\"API\"");
+        assert!(policy.check(&Request::fixture(Method::GET, "/admin", bearer, None)).is_err());
+        assert!(policy.check(&Request::fixture(Method::GET, "/admin/api", "Authorization: Bearer other\r\n", None)).is_err());
+        assert!(AuthRule::basic("/", "x", "carol:$apr1$salt$hash\n").is_err());
+    }
+
+    #[test]
+    fn test_rules_match_normalized_targets() {
+        let policy = AuthPolicy::new().with_rule(AuthRule::bearer("/admin", "Admin area", "secret-token\n"));
+        for target in ["/admin/secret.html", "//admin/secret.html", "/%61dmin/secret.html", "/x/../admin/secret.html", "/./admin/secret.html"] {
+            assert!(policy.check(&Request::fixture(Method::GET, target, "", None)).is_err(), "{target}");
+            assert!(policy.check(&Request::fixture(Method::GET, target, "Authorization: Bearer secret-token\r\n", None)).is_ok(), "{target}");
+        }
+        assert!(policy.check(&Request::fixture(Method::GET, "/admin/../public.html", "", None)).is_err());
+        assert!(policy.check(&Request::fixture(Method::GET, "/public/%2e%2e/index.html", "", None)).is_ok());
+    }
+
+    #[test]
+    fn test_policy_file() {
+        let dir = TempDir::new("server-auth").unwrap();
+        dir.create_file("htpasswd", b"alice:wonderland\n").unwrap();
+        dir.create_file("tokens", b"t0ken\n").unwrap();
+        let path = dir.create_file("policy", b"# protected parts\nbasic /private Private htpasswd\nbearer /api API tokens\n").unwrap();
+        let policy = AuthPolicy::load(&path).unwrap();
+        assert_eq!(policy.rule(Path::new("/private/a")).map(AuthRule::prefix), Some("/private"));
+        assert_eq!(policy.rule(Path::new("/api")).map(AuthRule::prefix), Some("/api"));
+
+        dir.create_file("policy", b"digest /private Private htpasswd\n").unwrap();
+        assert!(matches!(AuthPolicy::load(&path), Err(LoadAuthError::Parse(_, 1, _))));
+        dir.create_file("policy", b"basic /private Private missing\n").unwrap();
+        assert!(matches!(AuthPolicy::load(&path), Err(LoadAuthError::Io(..))));
+    }
+
+    #[test]
+    fn test_realms_with_spaces_are_quoted() {
+        let dir = TempDir::new("server-auth").unwrap();
+        dir.create_file("tokens", b"t0ken\n").unwrap();
+        let path = dir.create_file("policy", b"bearer  /admin  \"Admin Area\"  tokens\nbearer /api API tokens\n").unwrap();
+        let policy = AuthPolicy::load(&path).unwrap();
+        let realm = |target: &str| policy.rule(Path::new(target)).map(|rule| rule.challenge().realm);
+        assert_eq!(realm("/admin").as_deref(), Some("Admin Area"));
+        assert_eq!(realm("/api").as_deref(), Some("API"));
+
+        for (line, reason) in [
+            ("bearer /admin \"Admin Area tokens\n", "realm is missing its closing quote"),
+            ("bearer /admin \"Admin\"tokens\n", "expected <scheme> <path prefix> <realm> <file>"),
+            ("bearer /admin Admin Area tokens\n", "expected <scheme> <path prefix> <realm> <file>"),
+            ("bearer /admin Ad\"min tokens\n", "realm cannot contain quotes"),
+            ("bearer /admin Admin\n", "expected <scheme> <path prefix> <realm> <file>"),
+        ] {
+            dir.create_file("policy", line.as_bytes()).unwrap();
+            match AuthPolicy::load(&path) {
+                Err(LoadAuthError::Parse(_, 1, found)) => assert_eq!(found, reason, "{line}"),
+                other => panic!("{line}: {other:?}"),
+            }
+        }
+    }
+}
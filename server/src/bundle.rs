@@ -0,0 +1,313 @@
+//! Mikołaj Depta 328690
+//!
+//! Resources served from a single archive read into memory when the server starts.
+//!
+//! Bundle lets the server run on a read-only filesystem without the document roots, and every
+//! asset is checked once at startup rather than on the first request for it. Archive starts with
+//! the `SRVBUNDL` magic, followed by entries until the end of the file:
+//!
+//! - path relative to the catalog, eg. `localhost/index.html`, preceded by its length as u16,
+//! - contents preceded by their length as u64,
+//!
+//! both lengths big endian. Directories are not stored, they are implied by the paths of files.
+//! Bundle is selected with `SERVER_BUNDLE`.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::http::headers::entity_header::ContentCoding;
+use crate::logger::{log, Level};
+use crate::resources::{Domains, LoadResourceError, Resource, ResourceLoader, ResourceValidator, ValidationResourceError, Version};
+use crate::vhost::VirtualHosts;
+
+const MAGIC: &[u8] = b"SRVBUNDL";
+
+#[derive(Debug)]
+pub enum LoadBundleError {
+    Io(io::Error),
+    BadMagic,
+    /// Archive ended in the middle of the entry starting at this offset.
+    Truncated(usize),
+    InvalidPath(String),
+    DuplicateEntry(String),
+    /// Path is stored as a file, but other entries are stored under it.
+    FileAndDirectory(String),
+}
+
+impl Display for LoadBundleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read bundle: {err}"),
+            Self::BadMagic => write!(f, "not a bundle, magic number is missing"),
+            Self::Truncated(offset) => write!(f, "bundle truncated in the entry at offset {offset}"),
+            Self::InvalidPath(path) => write!(f, "invalid entry path '{path}'"),
+            Self::DuplicateEntry(path) => write!(f, "entry '{path}' stored more than once"),
+            Self::FileAndDirectory(path) => write!(f, "entry '{path}' is both a file and a directory"),
+        }
+    }
+}
+
+impl std::error::Error for LoadBundleError { }
+
+struct BundledFile {
+    data: Box<[u8]>,
+    version: Version,
+}
+
+/// Contents of the bundle, with paths of files and directories within the `catalog`.
+pub struct Bundle {
+    files: HashMap<PathBuf, BundledFile>,
+    directories: HashSet<PathBuf>,
+}
+
+impl Bundle {
+    pub const ENV_VARIABLE: &'static str = "SERVER_BUNDLE";
+
+    /// Bundle from `SERVER_BUNDLE`, `None` if it is not set. Invalid bundle ends the process,
+    /// serving only a part of the assets would be worse than not starting.
+    pub fn from_env(catalog: &Path) -> Option<Self> {
+        let path = env::var_os(Self::ENV_VARIABLE)?;
+        match Self::load(catalog, Path::new(&path)) {
+            Ok(bundle) => {
+                log!(Level::Info, "serving {} files from bundle {}", bundle.len(), Path::new(&path).display());
+                Some(bundle)
+            }
+            Err(err) => crate::util::fail_with_message(&format!("{}: {}", Self::ENV_VARIABLE, err)),
+        }
+    }
+
+    pub fn load(catalog: &Path, path: &Path) -> Result<Self, LoadBundleError> {
+        let archive = fs::read(path).map_err(LoadBundleError::Io)?;
+        Self::parse(catalog, &archive)
+    }
+
+    /// Validates the whole `archive`, paths of the entries are placed in the `catalog`.
+    pub fn parse(catalog: &Path, archive: &[u8]) -> Result<Self, LoadBundleError> {
+        let mut rest = archive.strip_prefix(MAGIC).ok_or(LoadBundleError::BadMagic)?;
+        let mut files = HashMap::new();
+        let mut directories = HashSet::from([catalog.to_owned()]);
+        while !rest.is_empty() {
+            let offset = archive.len() - rest.len();
+            let truncated = || LoadBundleError::Truncated(offset);
+            let (path, tail) = take_prefixed(rest, 2).ok_or_else(truncated)?;
+            let (data, tail) = take_prefixed(tail, 8).ok_or_else(truncated)?;
+            rest = tail;
+            let relative = std::str::from_utf8(path)
+                .map_err(|_| LoadBundleError::InvalidPath(String::from_utf8_lossy(path).into_owned()))?;
+            if !is_valid_path(relative) {
+                return Err(LoadBundleError::InvalidPath(relative.to_owned()));
+            }
+            let path = catalog.join(relative);
+            directories.extend(path.ancestors().skip(1).take_while(|ancestor| ancestor.starts_with(catalog)).map(Path::to_path_buf));
+            let file = BundledFile { data: Box::from(data), version: Version::of_content(data) };
+            if files.insert(path, file).is_some() {
+                return Err(LoadBundleError::DuplicateEntry(relative.to_owned()));
+            }
+        }
+        if let Some(path) = files.keys().find(|path| directories.contains(*path)) {
+            let relative = path.strip_prefix(catalog).unwrap_or(path);
+            return Err(LoadBundleError::FileAndDirectory(relative.display().to_string()));
+        }
+        Ok(Self { files, directories })
+    }
+
+    /// Archive with the `entries`, given as paths relative to the catalog and their contents.
+    pub fn pack<'a>(entries: impl IntoIterator<Item=(&'a str, &'a [u8])>) -> Vec<u8> {
+        let mut archive = MAGIC.to_vec();
+        for (path, data) in entries {
+            archive.extend_from_slice(&(path.len() as u16).to_be_bytes());
+            archive.extend_from_slice(path.as_bytes());
+            archive.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            archive.extend_from_slice(data);
+        }
+        archive
+    }
+
+    /// Number of files in the bundle.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    pub fn is_directory(&self, path: &Path) -> bool {
+        self.directories.contains(path)
+    }
+
+    fn paths(&self) -> impl Iterator<Item=&Path> {
+        self.files.keys().map(PathBuf::as_path)
+    }
+}
+
+/// Splits the field preceded by its big endian length of `width` bytes off of `input`.
+fn take_prefixed(input: &[u8], width: usize) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = input.split_at_checked(width)?;
+    let len = len.iter().fold(0u64, |len, &byte| len << 8 | byte as u64);
+    rest.split_at_checked(usize::try_from(len).ok()?)
+}
+
+/// Relative path of plain segments separated with `/`, it cannot leave the catalog.
+fn is_valid_path(path: &str) -> bool {
+    path.split('/').all(|segment| !matches!(segment, "" | "." | "..") && !segment.contains(['\\', '\0']))
+}
+
+/// Loads resources from the bundle, the filesystem is never touched.
+#[derive(Clone)]
+pub struct BundleLoader {
+    bundle: Arc<Bundle>,
+}
+
+impl BundleLoader {
+    pub fn new(bundle: Arc<Bundle>) -> Self {
+        Self { bundle }
+    }
+}
+
+impl ResourceLoader for BundleLoader {
+    type LoadError = LoadResourceError;
+
+    fn load(&self, resource: &Path) -> Result<Resource, Self::LoadError> {
+        match self.bundle.files.get(resource) {
+            Some(file) => Ok(Resource { data: file.data.clone(), version: file.version }),
+            None => Err(LoadResourceError::NotFound(resource.to_owned())),
+        }
+    }
+
+    fn is_directory(&self, resource: &Path) -> bool {
+        self.bundle.is_directory(resource)
+    }
+
+    fn precompressed(&self, resource: &Path, coding: ContentCoding) -> Option<PathBuf> {
+        let mut name = resource.file_name()?.to_owned();
+        name.push(".");
+        name.push(coding.extension());
+        let variant = resource.with_file_name(name);
+        self.bundle.is_file(&variant).then_some(variant)
+    }
+}
+
+/// Allows access to the files of the bundle within document roots of the `domains`.
+pub struct BundleValidator {
+    bundle: Arc<Bundle>,
+    domains: RwLock<Domains>,
+}
+
+impl BundleValidator {
+    pub fn new(bundle: Arc<Bundle>, domains: Domains) -> Self {
+        Self { bundle, domains: RwLock::new(domains) }
+    }
+
+    /// Validator that allows access to document roots of all `hosts`.
+    /// Files of the bundle outside of all of them are reported, no request can reach them.
+    pub fn from_virtual_hosts(bundle: Arc<Bundle>, hosts: &VirtualHosts) -> Self {
+        let domains: HashSet<_> = hosts.roots().map(Path::to_path_buf).collect();
+        for path in bundle.paths().filter(|path| !domains.iter().any(|domain| path.starts_with(domain))) {
+            log!(Level::Warn, "bundled file {} is outside of every document root", path.display());
+        }
+        Self::new(bundle, Arc::new(domains))
+    }
+}
+
+impl ResourceValidator for BundleValidator {
+    type ValidationError = ValidationResourceError;
+
+    /// Paths of the bundle contain no links, so they are compared as they are.
+    fn validate(&self, resource_path: &Path) -> Result<(), Self::ValidationError> {
+        let domains = self.domains.read().unwrap().clone();
+        if domains.contains(resource_path) {
+            return Err(ValidationResourceError::OutdatedResourcePath(resource_path.to_owned()));
+        }
+        if !domains.iter().any(|domain| resource_path.starts_with(domain)) {
+            return Err(ValidationResourceError::UnauthorizedResourceAccess(resource_path.to_owned()));
+        }
+        match self.bundle.is_file(resource_path) {
+            true => Ok(()),
+            false => Err(ValidationResourceError::NotFound(resource_path.to_owned())),
+        }
+    }
+
+    fn set_domains(&self, domains: Domains) {
+        *self.domains.write().unwrap() = domains;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle() -> Bundle {
+        let archive = Bundle::pack([
+            ("localhost/index.html", &b"<p>hello</p>"[..]),
+            ("localhost/css/style.css", b"p {}"),
+            ("localhost/css/style.css.gz", b"\x1f\x8b"),
+            ("private/key", b"secret"),
+        ]);
+        Bundle::parse(Path::new("/srv"), &archive).unwrap()
+    }
+
+    #[test]
+    fn test_bundle_is_parsed() {
+        let bundle = bundle();
+        assert_eq!(bundle.len(), 4);
+        assert!(bundle.is_file(Path::new("/srv/localhost/css/style.css")));
+        for directory in ["/srv", "/srv/localhost", "/srv/localhost/css", "/srv/private"] {
+            assert!(bundle.is_directory(Path::new(directory)), "{directory}");
+        }
+        assert!(!bundle.is_directory(Path::new("/")));
+        assert!(!bundle.is_file(Path::new("/srv/localhost")));
+
+        let loader = BundleLoader::new(Arc::new(bundle));
+        let resource = loader.load(Path::new("/srv/localhost/index.html")).unwrap();
+        assert_eq!(&*resource.data, b"<p>hello</p>");
+        let again = loader.load(Path::new("/srv/localhost/index.html")).unwrap();
+        assert_eq!(resource.version, again.version);
+        assert!(matches!(loader.load(Path::new("/srv/localhost/missing.html")), Err(LoadResourceError::NotFound(_))));
+        assert_eq!(
+            loader.precompressed(Path::new("/srv/localhost/css/style.css"), ContentCoding::Gzip),
+            Some(PathBuf::from("/srv/localhost/css/style.css.gz"))
+        );
+        assert_eq!(loader.precompressed(Path::new("/srv/localhost/index.html"), ContentCoding::Gzip), None);
+    }
+
+    #[test]
+    fn test_malformed_bundles_are_rejected() {
+        let parse = |archive: &[u8]| Bundle::parse(Path::new("/srv"), archive).err().map(|err| err.to_string());
+        assert_eq!(parse(b"BUNDLE"), Some(LoadBundleError::BadMagic.to_string()));
+        let archive = Bundle::pack([("a", &b"abc"[..]), ("b", b"def")]);
+        assert_eq!(parse(&archive[..archive.len() - 1]), Some(LoadBundleError::Truncated(22).to_string()));
+        for path in ["../etc/passwd", "/etc/passwd", "a//b", "a/./b", "a\\b", ""] {
+            let expected = LoadBundleError::InvalidPath(path.to_owned()).to_string();
+            assert_eq!(parse(&Bundle::pack([(path, &b""[..])])), Some(expected), "{path}");
+        }
+        let duplicate = Bundle::pack([("a/b", &b""[..]), ("a/b", b"")]);
+        assert_eq!(parse(&duplicate), Some(LoadBundleError::DuplicateEntry(String::from("a/b")).to_string()));
+        let conflict = Bundle::pack([("a", &b""[..]), ("a/b", b"")]);
+        assert_eq!(parse(&conflict), Some(LoadBundleError::FileAndDirectory(String::from("a")).to_string()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let domains = Arc::new(HashSet::from([PathBuf::from("/srv/localhost")]));
+        let validator = BundleValidator::new(Arc::new(bundle()), domains);
+        assert!(validator.validate(Path::new("/srv/localhost/index.html")).is_ok());
+        assert!(matches!(
+            validator.validate(Path::new("/srv/localhost")),
+            Err(ValidationResourceError::OutdatedResourcePath(_))
+        ));
+        assert!(matches!(
+            validator.validate(Path::new("/srv/private/key")),
+            Err(ValidationResourceError::UnauthorizedResourceAccess(_))
+        ));
+        assert!(matches!(
+            validator.validate(Path::new("/srv/localhost/missing.html")),
+            Err(ValidationResourceError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,268 @@
+//! Mikołaj Depta 328690
+//!
+//! In-memory cache of loaded resources.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use common::fs::{Fs, RealFs};
+use common::units;
+use crate::http::headers::entity_header::ContentCoding;
+use crate::logger::{log, Level};
+use crate::resources::{OpenResource, Resource, ResourceLoader, Version};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CacheConfig {
+    /// Upper bound of the total size of cached resources.
+    pub max_bytes: usize,
+}
+
+impl CacheConfig {
+    pub const ENV_VARIABLE: &'static str = "SERVER_CACHE_BYTES";
+
+    /// Cache is enabled by setting `SERVER_CACHE_BYTES` to a positive size, eg. `64MiB`.
+    pub fn from_env() -> Option<Self> {
+        let repr = env::var(Self::ENV_VARIABLE).ok()?;
+        match units::parse_size(&repr) {
+            Ok(0) => None,
+            Ok(max_bytes) => Some(Self { max_bytes }),
+            Err(err) => {
+                log!(Level::Warn, "{}: invalid cache size '{}': {}", Self::ENV_VARIABLE, repr, err);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct CacheStatistics {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct CacheEntry {
+    data: Box<[u8]>,
+    version: Version,
+    last_used: u64,
+}
+
+/// Entries are ordered by `last_used` tick, the least recently used one is evicted first.
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<PathBuf, CacheEntry>,
+    usage: BTreeMap<u64, PathBuf>,
+    total_bytes: usize,
+    tick: u64,
+}
+
+impl Lru {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, path: &Path, version: Version) -> Option<Box<[u8]>> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(path)?;
+        if entry.version != version {
+            self.remove(path);
+            return None;
+        }
+        self.usage.remove(&entry.last_used);
+        entry.last_used = tick;
+        self.usage.insert(tick, path.to_owned());
+        Some(entry.data.clone())
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.usage.remove(&entry.last_used);
+            self.total_bytes -= entry.data.len();
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, data: Box<[u8]>, version: Version, max_bytes: usize) {
+        self.remove(&path);
+        if data.len() > max_bytes {
+            return;
+        }
+        while self.total_bytes + data.len() > max_bytes {
+            let Some((_, evicted)) = self.usage.pop_first() else { break };
+            log!(Level::Debug, "evicting {}", evicted.display());
+            if let Some(entry) = self.entries.remove(&evicted) {
+                self.total_bytes -= entry.data.len();
+            }
+        }
+        let last_used = self.next_tick();
+        self.total_bytes += data.len();
+        self.usage.insert(last_used, path.clone());
+        self.entries.insert(path, CacheEntry { data, version, last_used });
+    }
+}
+
+/// Loader that keeps recently loaded resources in memory.
+///
+/// Entries are keyed by canonical path, so every path leading to the file shares one entry.
+/// Entry is used only if file metadata still matches the version it was loaded from.
+/// Only absolute paths are cached. Resources that `inner` streams are never cached.
+pub struct CachingLoader<L, F: Fs = RealFs> {
+    inner: L,
+    fs: F,
+    config: CacheConfig,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<L: ResourceLoader> CachingLoader<L> {
+    pub fn new(inner: L, config: CacheConfig) -> Self {
+        Self::with_fs(inner, config, RealFs)
+    }
+}
+
+impl<L: ResourceLoader, F: Fs> CachingLoader<L, F> {
+    pub fn with_fs(inner: L, config: CacheConfig, fs: F) -> Self {
+        Self {
+            inner,
+            fs,
+            config,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        CacheStatistics { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+
+    /// Cached size in bytes.
+    pub fn size(&self) -> usize {
+        self.lru.lock().unwrap_or_else(|err| err.into_inner()).total_bytes
+    }
+
+    /// Canonical path and current version of the resource, `None` if it cannot be cached.
+    fn key(&self, resource: &Path) -> Option<(PathBuf, Version)> {
+        if resource.is_relative() {
+            return None;
+        }
+        let path = self.fs.canonicalize(resource).ok()?;
+        let metadata = self.fs.metadata(&path).ok()?;
+        Some((path, Version::from(&metadata)))
+    }
+}
+
+impl<L: ResourceLoader, F: Fs> ResourceLoader for CachingLoader<L, F> {
+    type LoadError = L::LoadError;
+
+    /// On a miss the resource is loaded by `inner` and stored with the version `inner` returned,
+    /// which describes exactly the loaded data.
+    fn load(&self, resource: &Path) -> Result<Resource, Self::LoadError> {
+        let key = self.key(resource);
+        if let Some((path, version)) = &key {
+            let cached = self.lru.lock().unwrap_or_else(|err| err.into_inner()).get(path, *version);
+            if let Some(data) = cached {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Resource { data, version: *version });
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let loaded = self.inner.load(resource)?;
+        if let Some((path, _)) = key {
+            self.lru.lock().unwrap_or_else(|err| err.into_inner())
+                .insert(path, loaded.data.clone(), loaded.version, self.config.max_bytes);
+        }
+        Ok(loaded)
+    }
+
+    fn open(&self, resource: &Path) -> Result<Option<OpenResource>, Self::LoadError> {
+        self.inner.open(resource)
+    }
+
+    fn precompressed(&self, resource: &Path, coding: ContentCoding) -> Option<PathBuf> {
+        self.inner.precompressed(resource, coding)
+    }
+
+    fn is_directory(&self, resource: &Path) -> bool {
+        self.inner.is_directory(resource)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::{LoadResourceError, StaticLoader};
+    use common::fs::TempDir;
+    use std::fs::File;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    fn loader(dir: &TempDir, max_bytes: usize) -> CachingLoader<StaticLoader> {
+        let inner = StaticLoader::new(Arc::from(dir.path())).with_stream_threshold(None);
+        CachingLoader::new(inner, CacheConfig { max_bytes })
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let dir = TempDir::new("server-cache").unwrap();
+        dir.create_file("a.html", b"aaaa").unwrap();
+        let loader = loader(&dir, 100);
+        let path = dir.path().join("a.html");
+        assert_eq!(loader.load(&path).unwrap().data.as_ref(), b"aaaa");
+        assert_eq!(loader.load(&path).unwrap().data.as_ref(), b"aaaa");
+        /* different path to the same file */
+        assert_eq!(loader.load(&dir.path().join("./a.html")).unwrap().data.as_ref(), b"aaaa");
+        assert_eq!(loader.statistics(), CacheStatistics { hits: 2, misses: 1 });
+        assert_eq!(loader.size(), 4);
+    }
+
+    #[test]
+    fn test_modified_file_is_reloaded() {
+        let dir = TempDir::new("server-cache").unwrap();
+        dir.create_file("a.html", b"aaaa").unwrap();
+        let loader = loader(&dir, 100);
+        let path = dir.path().join("a.html");
+        loader.load(&path).unwrap();
+        std::fs::write(&path, b"AAAA").unwrap();
+        File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)).unwrap();
+        assert_eq!(loader.load(&path).unwrap().data.as_ref(), b"AAAA");
+        assert_eq!(loader.statistics(), CacheStatistics { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let dir = TempDir::new("server-cache").unwrap();
+        for name in ["a", "b", "c"] {
+            dir.create_file(name, b"1234").unwrap();
+        }
+        dir.create_file("large", &[0; 11]).unwrap();
+        let loader = loader(&dir, 10);
+        let path = |name: &str| dir.path().join(name);
+        loader.load(&path("a")).unwrap();
+        loader.load(&path("b")).unwrap();
+        loader.load(&path("a")).unwrap();
+        /* evicts "b", "a" was used more recently */
+        loader.load(&path("c")).unwrap();
+        assert_eq!(loader.size(), 8);
+        /* larger than the whole cache, not stored */
+        loader.load(&path("large")).unwrap();
+        assert_eq!(loader.size(), 8);
+        let before = loader.statistics();
+        loader.load(&path("a")).unwrap();
+        loader.load(&path("b")).unwrap();
+        assert_eq!(loader.statistics().hits, before.hits + 1);
+        assert_eq!(loader.statistics().misses, before.misses + 1);
+    }
+
+    #[test]
+    fn test_errors_are_not_cached() {
+        let dir = TempDir::new("server-cache").unwrap();
+        let loader = loader(&dir, 10);
+        assert!(matches!(loader.load(&dir.path().join("missing")), Err(LoadResourceError::NotFound(_))));
+        assert_eq!(loader.size(), 0);
+    }
+}
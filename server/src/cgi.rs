@@ -0,0 +1,631 @@
+//! Mikołaj Depta 328690
+//!
+//! Execution of CGI scripts.
+//!
+//! Requests below the configured prefix, eg. `/cgi-bin/hello/extra?name=x`, run the script
+//! `hello` from the configured directory. Request is described to the script with the CGI
+//! meta-variables in its environment and the body is written to its standard input. Script
+//! answers on its standard output with CGI headers, an empty line and the body, which are
+//! translated into the response.
+//!
+//! Pipes of the script are watched by the event registry of the worker, so writing the body
+//! and reading the output never blocks the worker for longer than the script timeout.
+//! Response head is sent once the script finishes its header section, the body is passed on
+//! as the script writes it, in chunked transfer coding or until the connection is closed for
+//! HTTP/1.0 clients.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::http::common::{Body, Version};
+use crate::http::headers::entity_header::{ContentType, EntityHeader};
+use crate::http::headers::general_header::{ConnectionType, GeneralHeader};
+use crate::http::headers::response_header::{ResponseHeader, TransferCoding};
+use crate::http::headers::Header;
+use crate::http::request::Request;
+use crate::http::response::{Response, StatusCode};
+use crate::logger::{log, Level};
+use crate::registry::{syscall, EventType, Notification, Registry, TimeoutDuration};
+use crate::routing::Router;
+
+#[derive(Debug)]
+pub enum CgiError {
+    /// There is no script with the requested name.
+    NotFound,
+    /// Script exists but cannot be executed.
+    NotExecutable,
+    Spawn(io::Error),
+    /// Script did not finish its output in time.
+    TimedOut,
+    /// Script output is not a valid CGI response.
+    MalformedOutput(String),
+    Io(io::Error),
+}
+
+impl CgiError {
+    /// Status the client is answered with.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NotFound,
+            Self::NotExecutable => StatusCode::Forbidden,
+            Self::Spawn(_) | Self::Io(_) => StatusCode::InternalServerError,
+            Self::TimedOut => StatusCode::GatewayTimeout,
+            Self::MalformedOutput(_) => StatusCode::BadGateway,
+        }
+    }
+}
+
+impl Display for CgiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "script not found"),
+            Self::NotExecutable => write!(f, "script is not executable"),
+            Self::Spawn(err) => write!(f, "could not start the script: {err}"),
+            Self::TimedOut => write!(f, "script did not finish in time"),
+            Self::MalformedOutput(reason) => write!(f, "malformed script output: {reason}"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<io::Error> for CgiError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Runs scripts from a directory for requests below the path prefix.
+#[derive(Debug, Clone)]
+pub struct CgiHandler {
+    prefix: String,
+    directory: PathBuf,
+    /// Time the script has to finish its output, counted from its start.
+    timeout: Duration,
+}
+
+impl CgiHandler {
+    pub const ENV_VARIABLE: &'static str = "SERVER_CGI_DIR";
+    pub const DEFAULT_PREFIX: &'static str = "/cgi-bin";
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+    const GATEWAY_INTERFACE: &'static str = "CGI/1.1";
+    const SERVER_SOFTWARE: &'static str = concat!("server/", env!("CARGO_PKG_VERSION"));
+    /// Longest header section accepted from a script.
+    const MAX_HEAD_LEN: usize = 64 * 1024;
+    const BUFFER_SIZE: usize = 16 * 1024;
+
+    /// Runs scripts from `directory` for requests for `prefix` and everything below it.
+    pub fn new(prefix: &str, directory: impl Into<PathBuf>) -> Self {
+        Self { prefix: prefix.trim_end_matches('/').to_owned(), directory: directory.into(), timeout: Self::DEFAULT_TIMEOUT }
+    }
+
+    /// Scripts are run from the directory in `SERVER_CGI_DIR` for requests below `/cgi-bin`.
+    pub fn from_env() -> Option<Self> {
+        let directory = PathBuf::from(env::var_os(Self::ENV_VARIABLE)?);
+        if !directory.is_dir() {
+            log!(Level::Warn, "{}: {} is not a directory", Self::ENV_VARIABLE, directory.display());
+            return None;
+        }
+        Some(Self::new(Self::DEFAULT_PREFIX, directory))
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Requests for `path` run one of the scripts.
+    pub fn matches(&self, path: &Path) -> bool {
+        path.to_str().is_some_and(|path| {
+            let path = path.split('?').next().unwrap_or_default();
+            Router::is_below(path, &self.prefix)
+        })
+    }
+
+    /// Starts the script `request` is for, with the meta-variables describing it.
+    /// `peer` and `local` are the addresses of both ends of the client connection.
+    pub fn spawn(&self, request: &Request, peer: Option<SocketAddr>, local: Option<SocketAddr>) -> Result<Child, CgiError> {
+        let target = request.start_line().url().to_str().ok_or(CgiError::NotFound)?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let rest = path.strip_prefix(&self.prefix).ok_or(CgiError::NotFound)?.trim_start_matches('/');
+        let (name, path_info) = match rest.find('/') {
+            Some(end) => (&rest[..end], &rest[end..]),
+            None => (rest, ""),
+        };
+        let script = self.script(name)?;
+
+        let mut command = Command::new(&script);
+        command
+            .env_clear()
+            .current_dir(&self.directory)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        if let Some(search_path) = env::var_os("PATH") {
+            command.env("PATH", search_path);
+        }
+        let (host, port) = request.headers().host().unwrap_or(("localhost", None));
+        let server_port = port.or(local.map(|local| local.port())).unwrap_or(80);
+        let variables = [
+            ("GATEWAY_INTERFACE", Self::GATEWAY_INTERFACE.to_owned()),
+            ("SERVER_SOFTWARE", Self::SERVER_SOFTWARE.to_owned()),
+            ("SERVER_PROTOCOL", request.start_line().version().to_string()),
+            ("SERVER_NAME", host.to_owned()),
+            ("SERVER_PORT", server_port.to_string()),
+            ("REQUEST_METHOD", request.start_line().method().to_string()),
+            ("SCRIPT_NAME", format!("{}/{}", self.prefix, name)),
+            ("PATH_INFO", path_info.to_owned()),
+            ("QUERY_STRING", query.to_owned()),
+            ("REMOTE_ADDR", peer.map_or(String::new(), |peer| peer.ip().to_string())),
+        ];
+        command.envs(variables);
+        if let Some(body) = request.body() {
+            command.env("CONTENT_LENGTH", body.len().to_string());
+        }
+        if let Some(content_type) = request.headers().get("Content-Type") {
+            command.env("CONTENT_TYPE", Self::value(content_type));
+        }
+        for header in request.headers().iter() {
+            let name = header.name();
+            /* `Proxy` would become HTTP_PROXY, which scripts take for their proxy configuration */
+            if ["Content-Type", "Content-Length", "Authorization", "Proxy-Authorization", "Proxy"]
+                .iter()
+                .any(|excluded| name.eq_ignore_ascii_case(excluded))
+            {
+                continue;
+            }
+            command.env(format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_")), Self::value(header));
+        }
+        // safety: only async-signal-safe calls are made between fork and exec.
+        unsafe {
+            command.pre_exec(|| {
+                /* signals the server watches are blocked in its threads and the mask survives exec */
+                let mut set = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                match libc::pthread_sigmask(libc::SIG_SETMASK, &set, std::ptr::null_mut()) {
+                    0 => Ok(()),
+                    err => Err(io::Error::from_raw_os_error(err)),
+                }
+            });
+        }
+        command.spawn().map_err(CgiError::Spawn)
+    }
+
+    /// Value of the `header` as it is sent.
+    fn value(header: &Header) -> String {
+        let line = header.to_string();
+        line.split_once(':').map_or(String::new(), |(_, value)| value.trim().to_owned())
+    }
+
+    /// Executable regular file `name` directly in the script directory.
+    fn script(&self, name: &str) -> Result<PathBuf, CgiError> {
+        if name.is_empty() || name.starts_with('.') || name.contains('\\') {
+            return Err(CgiError::NotFound);
+        }
+        let script = self.directory.join(name);
+        let metadata = script.metadata().map_err(|_| CgiError::NotFound)?;
+        if !metadata.is_file() {
+            return Err(CgiError::NotFound);
+        }
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(CgiError::NotExecutable);
+        }
+        Ok(script)
+    }
+
+    /// Script started with `spawn` that gets the request `body` and whose output is read, see `Script`.
+    /// Interest in the `client` connection is suspended while the script is waited for.
+    pub fn start<'a>(&self, mut child: Child, client: &TcpStream, body: Option<&'a Body>) -> Result<Script<'a>, CgiError> {
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let body = body.map(|body| Box::new(body.reader()) as Box<dyn Read + 'a>);
+        /* script that gets no body sees the end of its input right away */
+        let stdin = stdin.filter(|_| body.is_some());
+        let Some(stdout) = stdout else {
+            Script::reap(&mut child);
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe).into());
+        };
+        let script = Script {
+            child,
+            stdin,
+            stdout,
+            body,
+            pending: Vec::new(),
+            written: 0,
+            output: Vec::new(),
+            framing: Framing::Empty,
+            client: client.as_raw_fd(),
+            deadline: Instant::now() + self.timeout,
+        };
+        for fd in script.stdin.iter().map(AsRawFd::as_raw_fd).chain([script.stdout.as_raw_fd()]) {
+            let flags = syscall!(fcntl(fd, libc::F_GETFL))?;
+            syscall!(fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+        }
+        Ok(script)
+    }
+
+    /// Response head described by the header section `head` of the script output in reply to the `request`,
+    /// and the way the rest of the output is sent as its body.
+    ///
+    /// `Status` sets the status, `Location` without it redirects with 302 and other headers are
+    /// passed on. Output has a body only if it has a `Content-Type`. Length of the body is not known
+    /// up front, so `Content-Length` of the script is ignored.
+    fn head(request: &Request, head: &[u8]) -> Result<(Response, Framing), CgiError> {
+        let malformed = |reason: &str| CgiError::MalformedOutput(reason.to_owned());
+        let head = std::str::from_utf8(head).map_err(|_| malformed("header section is not valid UTF-8"))?;
+
+        let mut status_code = None;
+        let mut location = None;
+        let mut content_type = None;
+        let mut headers = Vec::new();
+        for line in head.split('\n').map(|line| line.trim_end_matches('\r')) {
+            let (name, value) = line.split_once(':').ok_or_else(|| malformed(&format!("header without a colon: {line}")))?;
+            let (name, value) = (name.trim(), value.trim());
+            match name.to_ascii_lowercase().as_str() {
+                "status" => {
+                    let code = value.split_whitespace().next().and_then(|code| code.parse().ok());
+                    status_code = Some(code.and_then(StatusCode::from_code).ok_or_else(|| malformed(&format!("unsupported status: {value}")))?);
+                }
+                "location" => location = Some(PathBuf::from(value)),
+                "content-type" => content_type = Some(value.parse().unwrap_or_else(|_| ContentType::Other(value.to_owned()))),
+                "content-length" | "connection" | "transfer-encoding" => {}
+                _ => headers.push(Header::Unknown(name.to_owned().into(), value.to_owned())),
+            }
+        }
+        if status_code.is_none() && location.is_none() && content_type.is_none() {
+            return Err(malformed("none of Status, Location and Content-Type is present"));
+        }
+        let status_code = match (status_code, &location) {
+            (Some(status_code), _) => status_code,
+            (None, Some(_)) => StatusCode::Found,
+            (None, None) => StatusCode::Ok,
+        };
+
+        let mut builder = Response::builder(status_code).in_reply_to(request).with_headers(headers);
+        if let Some(location) = location {
+            builder = builder.with_header(ResponseHeader::Location(location));
+        }
+        let framing = match content_type {
+            None => Framing::Empty,
+            Some(content_type) => {
+                builder = builder.with_header(EntityHeader::ContentType(content_type));
+                /* HTTP/1.0 clients do not understand chunked transfer coding */
+                if matches!(request.start_line().version(), Version::V1) {
+                    builder = builder.with_header(GeneralHeader::Connection(ConnectionType::Close));
+                    Framing::Close
+                } else {
+                    builder = builder.with_header(ResponseHeader::TransferEncoding(TransferCoding::Chunked));
+                    Framing::Chunked
+                }
+            }
+        };
+        Ok((builder.build(), framing))
+    }
+}
+
+/// How the output that follows the header section is sent.
+enum Framing {
+    /// Response has no body, rest of the output is dropped.
+    Empty,
+    Chunked,
+    /// Body ends when the connection is closed.
+    Close,
+}
+
+/// Running script, the body is written to it while its output is read, both pipes watched at once
+/// so that a script writing before it reads the whole body cannot dead-lock with the server.
+/// Script that did not exit by the time it is dropped is killed.
+pub struct Script<'a> {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+    body: Option<Box<dyn Read + 'a>>,
+    /// Part of the body read but not yet written to the script.
+    pending: Vec<u8>,
+    written: usize,
+    /// Output read but not yet passed on.
+    output: Vec<u8>,
+    framing: Framing,
+    /// Connection the script answers, not watched while the script is waited for.
+    client: RawFd,
+    deadline: Instant,
+}
+
+impl Script<'_> {
+    /// Reads the output up to the end of its header section and translates it into the response head,
+    /// see `CgiHandler::head`. Pipes are watched with the `registry`.
+    pub fn response(&mut self, registry: &mut Registry, request: &Request) -> Result<Response, CgiError> {
+        let malformed = |reason: &str| CgiError::MalformedOutput(reason.to_owned());
+        let (head_len, separator_len) = loop {
+            let end = [&b"\r\n\r\n"[..], b"\n\n"]
+                .into_iter()
+                .filter_map(|separator| self.output.windows(separator.len()).position(|window| window == separator).map(|at| (at, separator.len())))
+                .min();
+            if let Some(end) = end {
+                break end;
+            }
+            if self.output.len() > CgiHandler::MAX_HEAD_LEN {
+                return Err(malformed("header section is too long"));
+            }
+            if !self.read(registry)? {
+                return Err(malformed("header section is not terminated with an empty line"));
+            }
+        };
+        let (response, framing) = CgiHandler::head(request, &self.output[..head_len])?;
+        self.output.drain(..head_len + separator_len);
+        self.framing = framing;
+        Ok(response)
+    }
+
+    /// Next part of the body as it is sent, waiting for the script to write it. `None` once the body ended.
+    pub fn next_part(&mut self, registry: &mut Registry) -> Result<Option<Vec<u8>>, CgiError> {
+        let is_open = match self.framing {
+            Framing::Empty => return Ok(None),
+            _ => !self.output.is_empty() || self.read(registry)?,
+        };
+        let data = std::mem::take(&mut self.output);
+        match (&self.framing, is_open) {
+            (Framing::Chunked, true) => {
+                let mut part = format!("{:X}\r\n", data.len()).into_bytes();
+                part.extend_from_slice(&data);
+                part.extend_from_slice(b"\r\n");
+                Ok(Some(part))
+            }
+            (Framing::Chunked, false) => {
+                self.framing = Framing::Empty;
+                Ok(Some(b"0\r\n\r\n".to_vec()))
+            }
+            (_, true) => Ok(Some(data)),
+            (_, false) => {
+                self.framing = Framing::Empty;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads what the script wrote so far, waiting for it if there is nothing yet. `false` once the output ended.
+    fn read(&mut self, registry: &mut Registry) -> Result<bool, CgiError> {
+        let mut buffer = [0; CgiHandler::BUFFER_SIZE];
+        loop {
+            if Instant::now() >= self.deadline {
+                return Err(CgiError::TimedOut);
+            }
+            self.write_body()?;
+            match self.stdout.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(count) => {
+                    self.output.extend_from_slice(&buffer[..count]);
+                    return Ok(true);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.wait(registry)?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Waits for the pipes until the deadline. They are watched only for the wait, so that the worker
+    /// sending the output does not wake up for them, and the client is not watched meanwhile.
+    fn wait(&mut self, registry: &mut Registry) -> Result<(), CgiError> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        let pipes = [(EventType::Read, self.stdout.as_raw_fd())]
+            .into_iter()
+            .chain(self.stdin.as_ref().map(|stdin| (EventType::Write, stdin.as_raw_fd())))
+            .collect::<Vec<_>>();
+        registry.delete_interest(EventType::Read, self.client)?;
+        let mut watched = 0;
+        let mut notification = Ok(Notification::Timeout);
+        for &(event_type, fd) in &pipes {
+            if let Err(err) = registry.add_interest(event_type, fd) {
+                notification = Err(err);
+                break;
+            }
+            watched += 1;
+        }
+        if notification.is_ok() {
+            notification = registry.await_event(&TimeoutDuration::Finite(remaining));
+        }
+        let unwatched = pipes[..watched].iter().try_for_each(|&(event_type, fd)| registry.delete_interest(event_type, fd));
+        let restored = registry.add_interest(EventType::Read, self.client);
+        if let Notification::Timeout = notification? {
+            return Err(CgiError::TimedOut);
+        }
+        unwatched?;
+        restored?;
+        Ok(())
+    }
+
+    /// Writes as much of the body as the pipe takes, closing it once the whole body is written.
+    /// Script that exits without reading its input is not an error, the output still counts.
+    fn write_body(&mut self) -> io::Result<()> {
+        let Some(stdin) = self.stdin.as_mut() else { return Ok(()) };
+        loop {
+            if self.written == self.pending.len() {
+                self.pending.resize(CgiHandler::BUFFER_SIZE, 0);
+                let count = match self.body.as_mut() {
+                    Some(body) => body.read(&mut self.pending)?,
+                    None => 0,
+                };
+                self.pending.truncate(count);
+                self.written = 0;
+                if count == 0 {
+                    self.stdin = None;
+                    return Ok(());
+                }
+            }
+            match stdin.write(&self.pending[self.written..]) {
+                Ok(count) => self.written += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                    self.stdin = None;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Kills the `child` unless it already exited and waits for it.
+    fn reap(child: &mut Child) {
+        if !matches!(child.try_wait(), Ok(Some(_))) {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for Script<'_> {
+    /// Output is complete once stdout is closed, script which lingers after that is not waited for.
+    fn drop(&mut self) {
+        Self::reap(&mut self.child);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::chunked::ChunkedDecoder;
+    use crate::http::common::Method;
+    use crate::http::request::StartLine;
+    use common::fs::TempDir;
+    use std::fs;
+    use std::net::TcpListener;
+
+    fn script(dir: &TempDir, name: &str, source: &str) {
+        let path = dir.create_file(name, source.as_bytes()).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Runs the script for `request` with a registry watching a connected client, like a worker does.
+    /// Body is returned decoded from the chunked transfer coding.
+    fn execute(handler: &CgiHandler, request: &Request) -> Result<(Response, String), CgiError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        let mut registry = Registry::new().unwrap();
+        registry.add_interest(EventType::Read, stream.as_raw_fd()).unwrap();
+        let child = handler.spawn(request, Some(peer), stream.local_addr().ok())?;
+        let mut script = handler.start(child, &stream, request.body())?;
+        let response = script.response(&mut registry, request)?;
+        let mut decoder = ChunkedDecoder::new();
+        let mut body = Vec::new();
+        while let Some(part) = script.next_part(&mut registry)? {
+            assert_eq!(decoder.decode(&part, &mut body).unwrap(), part.len());
+        }
+        assert!(body.is_empty() || decoder.is_done());
+        Ok((response, String::from_utf8(body).unwrap()))
+    }
+
+    #[test]
+    fn test_output_is_translated_into_response() {
+        let request = Request::fixture(Method::GET, "/cgi-bin/x", "Host: localhost\r\n", None);
+        /* date of the response is the only header the script does not control */
+        let without_date = |(response, _): (Response, Framing)| {
+            let date = format!("{}\r\n", response.headers().get("Date").unwrap());
+            String::from_utf8_lossy(response.as_ref()).replacen(&date, "", 1)
+        };
+        let head = CgiHandler::head(&request, b"Content-Type: text/plain\nX-Script: yes").unwrap();
+        assert_eq!(
+            without_date(head),
+            "HTTP/1.1 200 OK\r\nX-Script: yes\r\nContent-Type: text/plain; charset=utf-8\r\nTransfer-Encoding: chunked\r\n\r\n"
+        );
+        let head = CgiHandler::head(&request, b"Location: /elsewhere\r\nContent-Length: 0").unwrap();
+        assert!(matches!(head.1, Framing::Empty));
+        assert_eq!(without_date(head), "HTTP/1.1 302 Found\r\nLocation: /elsewhere\r\n\r\n");
+        let start_line = StartLine::new(Method::GET, Path::new("/cgi-bin/x"), Version::V1);
+        let legacy = Request::new(start_line, request.headers().clone(), None);
+        let head = CgiHandler::head(&legacy, b"Content-Type: text/plain").unwrap();
+        assert!(matches!(head.1, Framing::Close));
+        assert!(without_date(head).ends_with(" 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nConnection: close\r\n\r\n"));
+        let (response, _) = CgiHandler::head(&request, b"Status: 404 Not Here\nContent-Type: text/html").unwrap();
+        assert_eq!(response.status_line().status_code().code(), 404);
+        for head in [&b"hello"[..], b"X-Only: custom", b"Status: 299 Odd"] {
+            assert!(matches!(CgiHandler::head(&request, head), Err(CgiError::MalformedOutput(_))));
+        }
+    }
+
+    #[test]
+    fn test_script_gets_meta_variables_and_body() {
+        let dir = TempDir::new("server-cgi").unwrap();
+        script(&dir, "echo", "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\n\
+            echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING $CONTENT_LENGTH $REMOTE_ADDR $HTTP_X_TOKEN [$HTTP_PROXY]\"\ncat\n");
+        let handler = CgiHandler::new("/cgi-bin", dir.path());
+        assert!(handler.matches(Path::new("/cgi-bin/echo?x")) && !handler.matches(Path::new("/cgi-binary")));
+        let headers = "Host: localhost\r\nX-Token: t\r\nProxy: http://attacker.example:8080\r\n";
+        let request = Request::fixture(Method::POST, "/cgi-bin/echo/extra/path?a=1&b=2", headers, Some("posted body".as_bytes()));
+        let (_, body) = execute(&handler, &request).unwrap();
+        assert_eq!(body, "POST /cgi-bin/echo /extra/path a=1&b=2 11 127.0.0.1 t []\nposted body");
+    }
+
+    #[test]
+    fn test_scripts_start_with_no_signals_blocked() {
+        let dir = TempDir::new("server-cgi").unwrap();
+        script(&dir, "mask", "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\ngrep SigBlk /proc/self/status\n");
+        let handler = CgiHandler::new("/cgi-bin", dir.path());
+        let request = Request::fixture(Method::GET, "/cgi-bin/mask", "Host: localhost\r\n", None);
+        /* like the server threads, which block the signals they receive through the registry */
+        let mut blocked = unsafe { std::mem::zeroed() };
+        let mut previous = unsafe { std::mem::zeroed() };
+        unsafe {
+            libc::sigemptyset(&mut blocked);
+            libc::sigaddset(&mut blocked, libc::SIGTERM);
+            assert_eq!(libc::pthread_sigmask(libc::SIG_BLOCK, &blocked, &mut previous), 0);
+        }
+        let result = execute(&handler, &request);
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut()) };
+        assert_eq!(result.unwrap().1, "SigBlk:\t0000000000000000\n");
+    }
+
+    #[test]
+    fn test_output_is_streamed_as_it_is_written() {
+        let dir = TempDir::new("server-cgi").unwrap();
+        script(&dir, "slow", "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\nfirst'\nsleep 1\nprintf second\n");
+        let handler = CgiHandler::new("/cgi-bin", dir.path());
+        let request = Request::fixture(Method::GET, "/cgi-bin/slow", "Host: localhost\r\n", None);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut registry = Registry::new().unwrap();
+        registry.add_interest(EventType::Read, stream.as_raw_fd()).unwrap();
+        let started = Instant::now();
+        let mut script = handler.start(handler.spawn(&request, None, None).unwrap(), &stream, None).unwrap();
+        script.response(&mut registry, &request).unwrap();
+        assert_eq!(script.next_part(&mut registry).unwrap().unwrap(), b"5\r\nfirst\r\n");
+        assert!(started.elapsed() < Duration::from_millis(900));
+        assert_eq!(script.next_part(&mut registry).unwrap().unwrap(), b"6\r\nsecond\r\n");
+        assert_eq!(script.next_part(&mut registry).unwrap().unwrap(), b"0\r\n\r\n");
+        assert!(script.next_part(&mut registry).unwrap().is_none());
+        /* client is watched again once the script is not waited for */
+        assert!(registry.delete_interest(EventType::Read, stream.as_raw_fd()).is_ok());
+    }
+
+    #[test]
+    fn test_script_failures() {
+        let dir = TempDir::new("server-cgi").unwrap();
+        dir.create_file("plain.txt", b"not a script").unwrap();
+        script(&dir, "slow", "#!/bin/sh\nsleep 5\n");
+        script(&dir, "silent", "#!/bin/sh\nexit 1\n");
+        script(&dir, "unterminated", "#!/bin/sh\nprintf 'Content-Type: text/plain\\n'\n");
+        let handler = CgiHandler::new("/cgi-bin/", dir.path()).with_timeout(Duration::from_millis(200));
+        let status = |target: &str| {
+            let request = Request::fixture(Method::GET, target, "Host: localhost\r\n", None);
+            execute(&handler, &request).err().map(|err| err.status_code().code())
+        };
+        assert_eq!(status("/cgi-bin/missing"), Some(404));
+        assert_eq!(status("/cgi-bin/../cgi-bin/plain.txt"), Some(404));
+        assert_eq!(status("/cgi-bin/plain.txt"), Some(403));
+        assert_eq!(status("/cgi-bin/silent"), Some(502));
+        assert_eq!(status("/cgi-bin/unterminated"), Some(502));
+        let started = Instant::now();
+        assert_eq!(status("/cgi-bin/slow"), Some(504));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
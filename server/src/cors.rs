@@ -0,0 +1,145 @@
+//! Mikołaj Depta 328690
+//!
+//! Cross-origin resource sharing, so that pages served from other origins can fetch resources.
+//!
+//! Allowed origins are read from `SERVER_CORS_ORIGINS` as a comma separated list, eg.
+//! `http://localhost:3000, http://lab.example`, `*` allows every origin. Responses to requests
+//! with an allowed `Origin` carry `Access-Control-Allow-Origin`. Preflights - `OPTIONS` requests
+//! with `Access-Control-Request-Method` - are also told the methods the server supports and the
+//! request headers listed in `SERVER_CORS_HEADERS`. Requests from other origins are served
+//! as usual, it is the browser that keeps the response from the page.
+
+use std::env;
+
+use crate::http::common::Method;
+use crate::http::headers::response_header::ResponseHeader;
+use crate::http::headers::Header;
+use crate::http::request::Request;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CorsPolicy {
+    /// Empty unless CORS is enabled, `*` allows every origin.
+    origins: Vec<String>,
+    headers: Vec<String>,
+}
+
+impl CorsPolicy {
+    pub const ENV_VARIABLE: &'static str = "SERVER_CORS_ORIGINS";
+    pub const HEADERS_ENV_VARIABLE: &'static str = "SERVER_CORS_HEADERS";
+    const ANY_ORIGIN: &'static str = "*";
+    const REQUEST_METHOD_HEADER: &'static str = "Access-Control-Request-Method";
+    const ORIGIN_HEADER: &'static str = "Origin";
+
+    /// Policy that allows no origin.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_env() -> Self {
+        let list = |variable| env::var(variable).unwrap_or_default();
+        let origins = list(Self::ENV_VARIABLE);
+        let headers = list(Self::HEADERS_ENV_VARIABLE);
+        let policy = Self::items(&origins).fold(Self::new(), Self::with_origin);
+        Self::items(&headers).fold(policy, Self::with_allowed_header)
+    }
+
+    /// `origin` is compared with the `Origin` of requests ignoring case, `*` allows every origin.
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Request header, other than the ones always allowed by the browsers, that pages may send.
+    pub fn with_allowed_header(mut self, name: &str) -> Self {
+        self.headers.push(name.to_owned());
+        self
+    }
+
+    /// Preflight is sent by the browser before a request that is not a simple one,
+    /// asking whether the method and the headers of the actual request are allowed.
+    pub fn is_preflight(&self, request: &Request) -> bool {
+        let headers = request.headers();
+        *request.start_line().method() == Method::OPTIONS
+            && headers.origin().is_some()
+            && headers.unknown(Self::REQUEST_METHOD_HEADER).is_some()
+    }
+
+    /// Headers to add to the response to `request`, none if it does not come from an allowed origin.
+    pub fn headers(&self, request: &Request) -> Vec<Header> {
+        let Some(origin) = request.headers().origin() else {
+            return Vec::new();
+        };
+        let mut headers = Vec::new();
+        if self.origins.iter().any(|allowed| allowed == Self::ANY_ORIGIN) {
+            headers.push(ResponseHeader::AccessControlAllowOrigin(Self::ANY_ORIGIN.to_owned()).into());
+        } else if self.origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            headers.push(ResponseHeader::AccessControlAllowOrigin(origin.to_owned()).into());
+            headers.push(ResponseHeader::Vary(vec![Self::ORIGIN_HEADER.to_owned()]).into());
+        } else {
+            return headers;
+        }
+        let requested = request.headers().unknown(Self::REQUEST_METHOD_HEADER).and_then(|method| method.parse().ok());
+        if self.is_preflight(request) && requested.is_some_and(|method: Method| method.is_supported()) {
+            headers.push(ResponseHeader::AccessControlAllowMethods(Method::SUPPORTED.to_vec()).into());
+            if !self.headers.is_empty() {
+                headers.push(ResponseHeader::AccessControlAllowHeaders(self.headers.clone()).into());
+            }
+        }
+        headers
+    }
+
+    fn items(list: &str) -> impl Iterator<Item=&str> {
+        list.split(',').map(str::trim).filter(|item| !item.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(headers: Vec<Header>) -> String {
+        headers.iter().map(|header| format!("{header}\r\n")).collect()
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let policy = CorsPolicy::new().with_origin("http://localhost:3000/").with_origin("http://lab.example");
+        let allowed = Request::fixture(Method::GET, "/data.json", "Origin: http://localhost:3000\r\n", None);
+        assert!(!policy.is_preflight(&allowed));
+        assert_eq!(
+            render(policy.headers(&allowed)),
+            "Access-Control-Allow-Origin: http://localhost:3000\r\nVary: Origin\r\n"
+        );
+        assert!(policy.headers(&Request::fixture(Method::GET, "/data.json", "Origin: http://evil.example\r\n", None)).is_empty());
+        assert!(policy.headers(&Request::fixture(Method::GET, "/data.json", "", None)).is_empty());
+        assert!(CorsPolicy::new().headers(&allowed).is_empty());
+
+        let any = CorsPolicy::new().with_origin("*");
+        assert_eq!(render(any.headers(&allowed)), "Access-Control-Allow-Origin: *\r\n");
+    }
+
+    #[test]
+    fn test_preflight() {
+        let policy = CorsPolicy::new().with_origin("*").with_allowed_header("X-Requested-With");
+        let preflight = Request::fixture(
+            Method::OPTIONS,
+            "/data.json",
+            "Origin: http://localhost:3000\r\nAccess-Control-Request-Method: GET\r\n",
+            None,
+        );
+        assert!(policy.is_preflight(&preflight));
+        assert_eq!(
+            render(policy.headers(&preflight)),
+            "Access-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\n\
+             Access-Control-Allow-Headers: X-Requested-With\r\n"
+        );
+        let unsupported = Request::fixture(
+            Method::OPTIONS,
+            "/data.json",
+            "Origin: http://localhost:3000\r\nAccess-Control-Request-Method: DELETE\r\n",
+            None,
+        );
+        assert_eq!(render(policy.headers(&unsupported)), "Access-Control-Allow-Origin: *\r\n");
+        assert!(!policy.is_preflight(&Request::fixture(Method::OPTIONS, "/data.json", "Origin: http://localhost:3000\r\n", None)));
+    }
+}
@@ -0,0 +1,316 @@
+//! Mikołaj Depta 328690
+//!
+//! Cache of open file descriptors of streamed resources.
+//!
+//! Streamed responses are sent from the descriptor with explicit offsets, so a single open file
+//! can back any number of responses at once. Keeping descriptors of hot files open saves
+//! an open/close pair per request.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::fs::{Fs, RealFs};
+use crate::cache::CacheStatistics;
+use crate::http::headers::entity_header::ContentCoding;
+use crate::logger::{log, Level};
+use crate::registry::syscall;
+use crate::reload::ReloadWatch;
+use crate::resources::{OpenResource, Resource, ResourceLoader, Version};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DescriptorCacheConfig {
+    /// Upper bound of the number of descriptors kept open, see `DescriptorCacheConfig::limit`.
+    pub max_descriptors: usize,
+}
+
+impl DescriptorCacheConfig {
+    pub const ENV_VARIABLE: &'static str = "SERVER_DESCRIPTOR_CACHE";
+
+    /// Descriptors left for sockets, logs and files opened outside of the cache are
+    /// the larger part of the process limit.
+    const RLIMIT_SHARE_DIVISOR: u64 = 4;
+
+    /// Cache is enabled by setting `SERVER_DESCRIPTOR_CACHE` to a positive number of descriptors.
+    pub fn from_env() -> Option<Self> {
+        let repr = env::var(Self::ENV_VARIABLE).ok()?;
+        match repr.trim().parse() {
+            Ok(0) => None,
+            Ok(max_descriptors) => Some(Self { max_descriptors }),
+            Err(err) => {
+                log!(Level::Warn, "{}: invalid descriptor count '{}': {}", Self::ENV_VARIABLE, repr, err);
+                None
+            }
+        }
+    }
+
+    /// Configured maximum capped to a quarter of the soft `RLIMIT_NOFILE`,
+    /// so the cache never starves the process of descriptors.
+    pub fn limit(&self) -> usize {
+        match open_files_limit() {
+            Ok(limit) => self.max_descriptors.min((limit / Self::RLIMIT_SHARE_DIVISOR) as usize),
+            Err(err) => {
+                log!(Level::Warn, "could not read RLIMIT_NOFILE: {}", err);
+                self.max_descriptors
+            }
+        }
+    }
+}
+
+fn open_files_limit() -> io::Result<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    syscall!(getrlimit(libc::RLIMIT_NOFILE, &mut limit))?;
+    Ok(limit.rlim_cur)
+}
+
+struct OpenEntry {
+    file: Arc<File>,
+    version: Version,
+    last_used: u64,
+}
+
+/// Entries are ordered by `last_used` tick, the least recently used one is closed first.
+#[derive(Default)]
+struct OpenFiles {
+    entries: HashMap<PathBuf, OpenEntry>,
+    usage: BTreeMap<u64, PathBuf>,
+    tick: u64,
+}
+
+impl OpenFiles {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, path: &Path, version: Version) -> Option<Arc<File>> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(path)?;
+        if entry.version != version {
+            self.remove(path);
+            return None;
+        }
+        self.usage.remove(&entry.last_used);
+        entry.last_used = tick;
+        self.usage.insert(tick, path.to_owned());
+        Some(entry.file.clone())
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.usage.remove(&entry.last_used);
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, file: Arc<File>, version: Version, limit: usize) {
+        self.remove(&path);
+        if limit == 0 {
+            return;
+        }
+        while self.entries.len() >= limit {
+            let Some((_, evicted)) = self.usage.pop_first() else { break };
+            log!(Level::Debug, "closing cached descriptor of {}", evicted.display());
+            self.entries.remove(&evicted);
+        }
+        let last_used = self.next_tick();
+        self.usage.insert(last_used, path.clone());
+        self.entries.insert(path, OpenEntry { file, version, last_used });
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.usage.clear();
+    }
+}
+
+/// Loader that keeps descriptors of streamed resources open between requests.
+///
+/// Entry is used only if metadata of the path still matches the version of the open file,
+/// so a file replaced or modified in place is opened again. Only absolute paths are cached.
+/// Descriptors still used by responses in flight stay open until those responses are sent.
+/// All cached descriptors are closed when reload is requested with SIGHUP.
+pub struct DescriptorCache<L, F: Fs = RealFs> {
+    inner: L,
+    fs: F,
+    limit: usize,
+    files: Mutex<OpenFiles>,
+    reload: Option<ReloadWatch>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<L: ResourceLoader> DescriptorCache<L> {
+    pub fn new(inner: L, config: DescriptorCacheConfig) -> Self {
+        Self::with_fs(inner, config, RealFs)
+    }
+}
+
+impl<L: ResourceLoader, F: Fs> DescriptorCache<L, F> {
+    pub fn with_fs(inner: L, config: DescriptorCacheConfig, fs: F) -> Self {
+        Self {
+            inner,
+            fs,
+            limit: config.limit(),
+            files: Mutex::new(OpenFiles::default()),
+            reload: Some(ReloadWatch::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        CacheStatistics { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) }
+    }
+
+    /// Number of descriptors kept open by the cache.
+    pub fn len(&self) -> usize {
+        self.files().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Closes all cached descriptors.
+    pub fn clear(&self) {
+        self.files().clear();
+    }
+
+    fn files(&self) -> std::sync::MutexGuard<'_, OpenFiles> {
+        let mut files = self.files.lock().unwrap_or_else(|err| err.into_inner());
+        if self.reload.as_ref().is_some_and(ReloadWatch::reloaded) {
+            log!(Level::Info, "reload requested, closing {} cached descriptors", files.entries.len());
+            files.clear();
+        }
+        files
+    }
+}
+
+impl<L: ResourceLoader, F: Fs> ResourceLoader for DescriptorCache<L, F> {
+    type LoadError = L::LoadError;
+
+    fn load(&self, resource: &Path) -> Result<Resource, Self::LoadError> {
+        self.inner.load(resource)
+    }
+
+    /// Whether the resource is streamed is decided by `inner`, on a miss the descriptor
+    /// it opened is stored with the version read from that descriptor.
+    fn open(&self, resource: &Path) -> Result<Option<OpenResource>, Self::LoadError> {
+        if resource.is_relative() {
+            return self.inner.open(resource);
+        }
+        let version = self.fs.metadata(resource).map(|metadata| Version::from(&metadata));
+        if let Ok(version) = version {
+            if let Some(file) = self.files().get(resource, version) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(OpenResource { file, version }));
+            }
+        }
+        let opened = self.inner.open(resource)?;
+        if let Some(OpenResource { file, version }) = &opened {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.files().insert(resource.to_owned(), file.clone(), *version, self.limit);
+        }
+        Ok(opened)
+    }
+
+    fn precompressed(&self, resource: &Path, coding: ContentCoding) -> Option<PathBuf> {
+        self.inner.precompressed(resource, coding)
+    }
+
+    fn is_directory(&self, resource: &Path) -> bool {
+        self.inner.is_directory(resource)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reload;
+    use crate::resources::StaticLoader;
+    use common::fs::TempDir;
+    use std::time::{Duration, SystemTime};
+
+    fn cache(dir: &TempDir, max_descriptors: usize) -> DescriptorCache<StaticLoader> {
+        let inner = StaticLoader::new(Arc::from(dir.path())).with_stream_threshold(Some(4));
+        let mut cache = DescriptorCache::new(inner, DescriptorCacheConfig { max_descriptors });
+        /* reloads requested by other tests must not interfere */
+        cache.reload = None;
+        cache
+    }
+
+    #[test]
+    fn test_descriptor_is_reused() {
+        let dir = TempDir::new("server-descriptors").unwrap();
+        dir.create_file("a.bin", b"aaaa").unwrap();
+        dir.create_file("small", b"a").unwrap();
+        let cache = cache(&dir, 8);
+        let path = dir.path().join("a.bin");
+        let first = cache.open(&path).unwrap().unwrap();
+        let second = cache.open(&path).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first.file, &second.file));
+        assert_eq!(second.len(), 4);
+        /* not streamed, nothing to cache */
+        assert!(cache.open(&dir.path().join("small")).unwrap().is_none());
+        assert_eq!(cache.statistics(), CacheStatistics { hits: 1, misses: 1 });
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_modified_file_is_reopened() {
+        let dir = TempDir::new("server-descriptors").unwrap();
+        dir.create_file("a.bin", b"aaaa").unwrap();
+        let cache = cache(&dir, 8);
+        let path = dir.path().join("a.bin");
+        let first = cache.open(&path).unwrap().unwrap();
+        std::fs::write(&path, b"AAAAA").unwrap();
+        File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)).unwrap();
+        let second = cache.open(&path).unwrap().unwrap();
+        assert!(!Arc::ptr_eq(&first.file, &second.file));
+        assert_eq!(second.len(), 5);
+        assert_eq!(cache.statistics(), CacheStatistics { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn test_least_recently_used_is_closed() {
+        let dir = TempDir::new("server-descriptors").unwrap();
+        for name in ["a", "b", "c"] {
+            dir.create_file(name, b"1234").unwrap();
+        }
+        let cache = cache(&dir, 2);
+        let path = |name: &str| dir.path().join(name);
+        cache.open(&path("a")).unwrap();
+        cache.open(&path("b")).unwrap();
+        cache.open(&path("a")).unwrap();
+        /* closes "b", "a" was used more recently */
+        cache.open(&path("c")).unwrap();
+        assert_eq!(cache.len(), 2);
+        let before = cache.statistics();
+        cache.open(&path("a")).unwrap();
+        cache.open(&path("b")).unwrap();
+        assert_eq!(cache.statistics().hits, before.hits + 1);
+        assert_eq!(cache.statistics().misses, before.misses + 1);
+    }
+
+    #[test]
+    fn test_reload_closes_descriptors() {
+        let dir = TempDir::new("server-descriptors").unwrap();
+        dir.create_file("a.bin", b"aaaa").unwrap();
+        let mut cache = cache(&dir, 8);
+        cache.reload = Some(ReloadWatch::new());
+        cache.open(&dir.path().join("a.bin")).unwrap();
+        reload::request();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_limit_is_capped_by_rlimit() {
+        let config = DescriptorCacheConfig { max_descriptors: usize::MAX };
+        assert_eq!(config.limit() as u64, open_files_limit().unwrap() / DescriptorCacheConfig::RLIMIT_SHARE_DIVISOR);
+    }
+}
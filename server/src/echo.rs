@@ -0,0 +1,91 @@
+//! Mikołaj Depta 328690
+//!
+//! Echo of requests as the server parsed them, for checking the parser against real clients.
+//!
+//! Disabled unless `SERVER_DEBUG_ECHO=1`. Once enabled, requests for `/debug/echo` are answered
+//! with their start line, headers and body as `text/plain`, whatever their method, and `TRACE`
+//! requests for any target with their start line and headers as `message/http`, see RFC 9110
+//! section 9.3.8. Headers carrying credentials are left out of the latter, proxies may be in between.
+
+use std::env;
+use std::io::Read;
+
+use crate::http::entity::Entity;
+use crate::http::headers::entity_header::ContentType;
+use crate::http::request::Request;
+
+pub const ENV_VARIABLE: &str = "SERVER_DEBUG_ECHO";
+pub const ECHO_PATH: &str = "/debug/echo";
+
+/// Longer bodies are cut short, echo is for looking at the request, not for transferring data.
+const MAX_ECHOED_BODY: usize = 64 * 1024;
+const CREDENTIAL_HEADERS: [&str; 3] = ["Authorization", "Proxy-Authorization", "Cookie"];
+const TRACE_CONTENT_TYPE: &str = "message/http";
+
+/// Echo was enabled by the operator.
+pub fn is_enabled() -> bool {
+    env::var(ENV_VARIABLE).is_ok_and(|value| value.trim() == "1")
+}
+
+/// Start line, headers and body of the request.
+pub fn echo(request: &Request) -> Entity {
+    let mut data = head(request, |_| true).into_bytes();
+    if let Some(body) = request.body() {
+        let start = data.len();
+        /* read errors only shorten the echo */
+        let _ = body.reader().take(MAX_ECHOED_BODY as u64).read_to_end(&mut data);
+        let missing = body.len() - (data.len() - start);
+        if missing > 0 {
+            data.extend_from_slice(format!("\n[{missing} more bytes]\n").as_bytes());
+        }
+    }
+    Entity::new(data.into_boxed_slice(), ContentType::Txt)
+}
+
+/// Start line and headers of the `TRACE` request, without credentials.
+pub fn trace(request: &Request) -> Entity {
+    let head = head(request, |name| !CREDENTIAL_HEADERS.iter().any(|credential| credential.eq_ignore_ascii_case(name)));
+    Entity::new(head.into_bytes().into_boxed_slice(), ContentType::Other(TRACE_CONTENT_TYPE.to_owned()))
+}
+
+fn head(request: &Request, is_echoed: impl Fn(&str) -> bool) -> String {
+    let mut head = request.start_line().to_string();
+    for header in request.headers().iter().filter(|header| is_echoed(header.name())) {
+        head.push_str(&format!("{header}\r\n"));
+    }
+    head.push_str("\r\n");
+    head
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::common::Method;
+    use crate::http::headers::entity_header::EntityHeader;
+
+    #[test]
+    fn test_echo() {
+        let request = Request::fixture(Method::POST, "/debug/echo?x=1", "Host: localhost\r\nAuthorization: Basic YTpi\r\n", Some(&b"a=1&b=2"[..]));
+        let entity = echo(&request);
+        assert_eq!(
+            entity.as_ref(),
+            b"POST /debug/echo?x=1 HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic YTpi\r\n\r\na=1&b=2"
+        );
+        assert!(matches!(&entity.headers()[0], EntityHeader::ContentType(ContentType::Txt)));
+    }
+
+    #[test]
+    fn test_long_bodies_are_cut_short() {
+        let body = vec![b'a'; MAX_ECHOED_BODY + 10];
+        let entity = echo(&Request::fixture(Method::POST, "/debug/echo?x=1", "Host: localhost\r\n", Some(&body)));
+        assert!(entity.as_ref().ends_with(b"aaa\n[10 more bytes]\n"));
+    }
+
+    #[test]
+    fn test_trace_leaves_out_credentials() {
+        let request = Request::fixture(Method::TRACE, "/debug/echo?x=1", "Host: localhost\r\nCookie: id=1\r\nAccept: */*\r\n", None);
+        let entity = trace(&request);
+        assert_eq!(entity.as_ref(), b"TRACE /debug/echo?x=1 HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n");
+        assert!(matches!(&entity.headers()[0], EntityHeader::ContentType(ContentType::Other(value)) if value == "message/http"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Mikołaj Depta 328690
+//!
+//! Errors of serving a single request.
+//!
+//! None of them is fatal: the client gets an error response, or the connection the error happened
+//! on is closed, and the server keeps serving the other ones. Only misconfiguration found while
+//! the server starts ends the process, see `util::fail_with_message`.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::http::response::StatusCode;
+use crate::resources::{LoadResourceError, ValidationResourceError};
+
+#[derive(Debug)]
+pub enum ServerError {
+    Load(LoadResourceError),
+    Validation(ValidationResourceError),
+    Io(io::Error),
+    /// Handler panicked while producing the response, with the panic message.
+    Panic(String),
+}
+
+impl ServerError {
+    /// Error out of the payload of a caught panic.
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload.downcast_ref::<&str>().map_or("unknown cause", |message| message).to_owned(),
+        };
+        Self::Panic(message)
+    }
+
+    /// Status the client is answered with.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Load(LoadResourceError::NotFound(_)) | Self::Validation(ValidationResourceError::NotFound(_)) => StatusCode::NotFound,
+            Self::Load(LoadResourceError::PermissionDenied(_))
+            | Self::Validation(ValidationResourceError::UnauthorizedResourceAccess(_)) => StatusCode::Forbidden,
+            Self::Validation(ValidationResourceError::OutdatedResourcePath(_)) => StatusCode::MovedPermanently,
+            Self::Io(err) if err.kind() == io::ErrorKind::NotFound => StatusCode::NotFound,
+            Self::Io(err) if err.kind() == io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+            _ => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Load(err) => write!(f, "{err}"),
+            Self::Validation(err) => write!(f, "{err}"),
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Panic(message) => write!(f, "handler panicked: {message}"),
+        }
+    }
+}
+
+impl From<LoadResourceError> for ServerError {
+    fn from(err: LoadResourceError) -> Self {
+        Self::Load(err)
+    }
+}
+
+impl From<ValidationResourceError> for ServerError {
+    fn from(err: ValidationResourceError) -> Self {
+        Self::Validation(err)
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_status_codes() {
+        let path = PathBuf::from("/index.html");
+        assert_eq!(ServerError::from(LoadResourceError::NotFound(path.clone())).status_code().code(), 404);
+        assert_eq!(ServerError::from(LoadResourceError::PermissionDenied(path.clone())).status_code().code(), 403);
+        assert_eq!(ServerError::from(LoadResourceError::Unstable(path.clone())).status_code().code(), 500);
+        assert_eq!(ServerError::from(ValidationResourceError::UnauthorizedResourceAccess(path)).status_code().code(), 403);
+        assert_eq!(ServerError::from(io::Error::from(io::ErrorKind::BrokenPipe)).status_code().code(), 500);
+    }
+
+    #[test]
+    fn test_panic_messages() {
+        let payload = panic::catch_unwind(|| panic!("index {} out of bounds", 3)).unwrap_err();
+        assert_eq!(ServerError::from_panic(payload).to_string(), "handler panicked: index 3 out of bounds");
+        let payload = panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(ServerError::from_panic(payload).to_string(), "handler panicked: static");
+    }
+}
@@ -0,0 +1,132 @@
+//! Mikołaj Depta 328690
+//!
+//! Error pages provided by the operator.
+//!
+//! Body of an error response is read from `<status code>.html`, eg. `404.html`, looked up in
+//! the document root of the requested host and then in the error pages directory.
+//! Pages are read on every error, so they can be changed without restarting the server.
+//! Built-in plain text messages are used if no page can be read.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use common::fs::{Fs, RealFs};
+use crate::http::entity::Entity;
+use crate::http::headers::entity_header::ContentType;
+use crate::http::response::StatusCode;
+use crate::logger::{log, Level};
+
+pub struct ErrorPages<F: Fs = RealFs> {
+    directory: Option<PathBuf>,
+    fs: F,
+}
+
+impl ErrorPages {
+    pub const ENV_VARIABLE: &'static str = "SERVER_ERROR_PAGES";
+
+    /// Pages are looked up only in document roots.
+    pub fn new() -> Self {
+        Self::with_fs(RealFs)
+    }
+
+    /// Directory shared by all hosts is read from `SERVER_ERROR_PAGES`.
+    pub fn from_env() -> Self {
+        match env::var_os(Self::ENV_VARIABLE) {
+            Some(directory) => Self::new().with_directory(PathBuf::from(directory)),
+            None => Self::new(),
+        }
+    }
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Fs> ErrorPages<F> {
+    pub fn with_fs(fs: F) -> Self {
+        Self { directory: None, fs }
+    }
+
+    /// Pages missing in the document root are looked up in `directory`.
+    pub fn with_directory(mut self, directory: PathBuf) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    /// Body for the response with `status_code`, for the host served from `document_root`.
+    pub fn entity(&self, status_code: &StatusCode, document_root: Option<&Path>) -> Entity {
+        let file_name = format!("{}.html", status_code.code());
+        let page = document_root
+            .into_iter()
+            .chain(self.directory.as_deref())
+            .find_map(|directory| self.read(&directory.join(&file_name)));
+        match page {
+            Some(data) => Entity::new(data.into_boxed_slice(), ContentType::Html),
+            None => Self::built_in(status_code),
+        }
+    }
+
+    fn read(&self, page: &Path) -> Option<Vec<u8>> {
+        match self.fs.read(page) {
+            Ok(data) => Some(data),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                log!(Level::Warn, "could not read error page {}: {}", page.display(), err);
+                None
+            }
+        }
+    }
+
+    fn built_in(status_code: &StatusCode) -> Entity {
+        match status_code {
+            StatusCode::BadRequest => Entity::bad_request(),
+            StatusCode::Unauthorized => Entity::unauthorized(),
+            StatusCode::Forbidden => Entity::morbidden(),
+            StatusCode::MethodNotAllowed => Entity::method_not_allowed(),
+            StatusCode::RequestTimeout => Entity::request_timeout(),
+            StatusCode::MisdirectedRequest => Entity::misdirected_request(),
+            StatusCode::TooManyRequests => Entity::too_many_requests(),
+            StatusCode::InternalServerError => Entity::internal_error(),
+            StatusCode::NotImplemented => Entity::not_implemented(),
+            StatusCode::BadGateway => Entity::bad_gateway(),
+            StatusCode::GatewayTimeout => Entity::gateway_timeout(),
+            StatusCode::InsufficientStorage => Entity::insufficient_storage(),
+            _ => Entity::not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::{Fault, FaultyFs, Operation, TempDir};
+
+    #[test]
+    fn test_document_root_page_wins() {
+        let dir = TempDir::new("server-error-pages").unwrap();
+        dir.create_file("www/404.html", b"<p>host</p>").unwrap();
+        dir.create_file("errors/404.html", b"<p>shared</p>").unwrap();
+        dir.create_file("errors/403.html", b"<p>denied</p>").unwrap();
+        let pages = ErrorPages::new().with_directory(dir.path().join("errors"));
+        let root = dir.path().join("www");
+        assert_eq!(pages.entity(&StatusCode::NotFound, Some(&root)).as_ref(), b"<p>host</p>");
+        assert_eq!(pages.entity(&StatusCode::Forbidden, Some(&root)).as_ref(), b"<p>denied</p>");
+        assert_eq!(pages.entity(&StatusCode::NotFound, None).as_ref(), b"<p>shared</p>");
+    }
+
+    #[test]
+    fn test_built_in_fallback() {
+        let dir = TempDir::new("server-error-pages").unwrap();
+        dir.create_file("404.html", b"<p>missing</p>").unwrap();
+        let fs = FaultyFs::new([Fault::permission_denied(Operation::Read)]);
+        let pages = ErrorPages::with_fs(fs);
+        assert_eq!(pages.entity(&StatusCode::NotFound, Some(dir.path())).as_ref(), Entity::not_found().as_ref());
+        let pages = ErrorPages::new();
+        assert_eq!(
+            pages.entity(&StatusCode::InternalServerError, Some(dir.path())).as_ref(),
+            Entity::internal_error().as_ref()
+        );
+    }
+}
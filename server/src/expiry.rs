@@ -0,0 +1,164 @@
+//! Mikołaj Depta 328690
+//!
+//! Freshness lifetimes of served files, advertised with `Cache-Control`, `Date` and `Expires`.
+//!
+//! Lifetimes are configured per media type with `SERVER_CACHE_TTL`, a comma separated list of
+//! `<media type>=<duration>` entries, eg. `text/html=0s, image/*=24h, */*=10m`. Exact media type
+//! takes precedence over `type/*`, which takes precedence over `*/*`. Files of other types
+//! are served without caching metadata, clients fall back to heuristic freshness then.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use common::units::{self, ParseUnitError};
+use crate::http::date::HttpDate;
+use crate::http::headers::entity_header::ContentType;
+use crate::http::headers::general_header::{CacheDirective, GeneralHeader};
+use crate::logger::{log, Level};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseExpiryError {
+    MissingSeparator(String),
+    InvalidMediaType(String),
+    InvalidDuration(String, ParseUnitError),
+}
+
+impl Display for ParseExpiryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSeparator(entry) => write!(f, "expected <media type>=<duration>, eg. image/*=24h, got {entry}"),
+            Self::InvalidMediaType(media_type) => write!(f, "invalid media type: {media_type}"),
+            Self::InvalidDuration(media_type, err) => write!(f, "invalid duration for {media_type}: {err}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ExpiryPolicy {
+    /// Lowercase media type patterns with their lifetimes, in the order they were added.
+    lifetimes: Vec<(String, Duration)>,
+}
+
+impl ExpiryPolicy {
+    pub const ENV_VARIABLE: &'static str = "SERVER_CACHE_TTL";
+    const WILDCARD: &'static str = "*";
+
+    /// Policy without lifetimes, responses carry no caching metadata.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_env() -> Self {
+        let Ok(repr) = env::var(Self::ENV_VARIABLE) else {
+            return Self::new();
+        };
+        match Self::try_from(repr.as_str()) {
+            Ok(policy) => policy,
+            Err(err) => {
+                log!(Level::Warn, "{}: {}, responses are sent without caching metadata", Self::ENV_VARIABLE, err);
+                Self::new()
+            }
+        }
+    }
+
+    /// Files of `media_type`, which may be `type/*` or `*/*`, stay fresh for `lifetime`.
+    /// Replaces the lifetime set for the same pattern before.
+    pub fn with_lifetime(mut self, media_type: &str, lifetime: Duration) -> Self {
+        let media_type = media_type.trim().to_lowercase();
+        self.lifetimes.retain(|(pattern, _)| *pattern != media_type);
+        self.lifetimes.push((media_type, lifetime));
+        self
+    }
+
+    /// Lifetime of the most specific pattern matching `content_type`, parameters such as `charset` are ignored.
+    pub fn lifetime(&self, content_type: &ContentType) -> Option<Duration> {
+        let content_type = content_type.to_string();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+        let main_type = media_type.split('/').next().unwrap_or_default();
+        let lookup = |pattern: &str| {
+            self.lifetimes.iter().find(|(other, _)| other == pattern).map(|&(_, lifetime)| lifetime)
+        };
+        lookup(&media_type)
+            .or_else(|| lookup(&format!("{main_type}/{}", Self::WILDCARD)))
+            .or_else(|| lookup(&format!("{0}/{0}", Self::WILDCARD)))
+    }
+
+    /// Caching metadata of a file of `content_type` served at `now`, none if its type has no lifetime.
+    pub fn headers(&self, content_type: &ContentType, now: HttpDate) -> Vec<GeneralHeader> {
+        let Some(lifetime) = self.lifetime(content_type) else {
+            return Vec::new();
+        };
+        let directive = match lifetime.as_secs() {
+            0 => CacheDirective::NoCache,
+            seconds => CacheDirective::MaxAge(seconds),
+        };
+        vec![
+            GeneralHeader::CacheControl(vec![directive]),
+            GeneralHeader::Date(now),
+            GeneralHeader::Expires(now.after(Duration::from_secs(lifetime.as_secs()))),
+        ]
+    }
+}
+
+impl TryFrom<&str> for ExpiryPolicy {
+    type Error = ParseExpiryError;
+
+    fn try_from(repr: &str) -> Result<Self, Self::Error> {
+        let mut policy = Self::new();
+        for entry in repr.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (media_type, lifetime) = entry
+                .split_once('=')
+                .ok_or_else(|| ParseExpiryError::MissingSeparator(entry.to_owned()))?;
+            let media_type = media_type.trim();
+            let is_valid = media_type
+                .split_once('/')
+                .is_some_and(|(main_type, subtype)| !main_type.is_empty() && !subtype.is_empty() && !subtype.contains('/'));
+            if !is_valid {
+                return Err(ParseExpiryError::InvalidMediaType(media_type.to_owned()));
+            }
+            let lifetime = units::parse_duration(lifetime)
+                .map_err(|err| ParseExpiryError::InvalidDuration(media_type.to_owned(), err))?;
+            policy = policy.with_lifetime(media_type, lifetime);
+        }
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_most_specific_lifetime() {
+        let policy = ExpiryPolicy::try_from("text/html=0s, image/*=24h, */*=10m").unwrap();
+        assert_eq!(policy.lifetime(&ContentType::Html), Some(Duration::ZERO));
+        assert_eq!(policy.lifetime(&ContentType::Png), Some(Duration::from_secs(24 * 3600)));
+        assert_eq!(policy.lifetime(&ContentType::Other(String::from("Image/WebP"))), Some(Duration::from_secs(24 * 3600)));
+        assert_eq!(policy.lifetime(&ContentType::Css), Some(Duration::from_secs(600)));
+        assert_eq!(ExpiryPolicy::new().lifetime(&ContentType::Css), None);
+
+        assert!(matches!(ExpiryPolicy::try_from("text/html"), Err(ParseExpiryError::MissingSeparator(_))));
+        assert!(matches!(ExpiryPolicy::try_from("html=1h"), Err(ParseExpiryError::InvalidMediaType(_))));
+        assert!(matches!(ExpiryPolicy::try_from("text/html=1"), Err(ParseExpiryError::InvalidDuration(..))));
+    }
+
+    #[test]
+    fn test_headers() {
+        let now = HttpDate::new(UNIX_EPOCH + Duration::from_secs(784_111_777));
+        let policy = ExpiryPolicy::new().with_lifetime("image/*", Duration::from_secs(3600)).with_lifetime("text/html", Duration::ZERO);
+        let render = |content_type| {
+            policy.headers(&content_type, now).iter().map(|header| format!("{header}\r\n")).collect::<String>()
+        };
+        assert_eq!(
+            render(ContentType::Jpeg),
+            "Cache-Control: max-age=3600\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\nExpires: Sun, 06 Nov 1994 09:49:37 GMT\r\n"
+        );
+        assert_eq!(
+            render(ContentType::Html),
+            "Cache-Control: no-cache\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\nExpires: Sun, 06 Nov 1994 08:49:37 GMT\r\n"
+        );
+        assert_eq!(render(ContentType::Pdf), "");
+    }
+}
@@ -0,0 +1,215 @@
+//! Mikołaj Depta 328690
+//!
+//! Response headers added by the operator, eg. security headers every page should carry.
+//!
+//! Rules are read from a file named by `SERVER_EXTRA_HEADERS`, one rule per line, empty lines
+//! and lines starting with `#` are ignored:
+//!
+//! ```text
+//! # <host> <path prefix> <name>: <value>
+//! *          /         X-Frame-Options: DENY
+//! localhost  /         Strict-Transport-Security: max-age=31536000
+//! *          /api/     Cache-Control: no-store
+//! ```
+//!
+//! Host is a configured virtual host, requests served by the default host match its name, or `*`
+//! for every host. Headers of all matching rules are added once the response is built, a later rule
+//! replaces the value of an earlier one with the same name. Headers the handler set itself are kept.
+//! Headers describing the connection or the body of the message cannot be added.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::http::headers::{Header, HeaderScope, Headers};
+use crate::logger::{log, Level};
+
+const ANY_HOST: &str = "*";
+/// Set by the server from the body, never by the operator.
+const BODY_HEADERS: [&str; 2] = ["Content-Length", "Content-Type"];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct HeaderRule {
+    /// Lowercase host name, `None` for every host.
+    host: Option<String>,
+    prefix: String,
+    name: String,
+    value: String,
+}
+
+impl HeaderRule {
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(rule), Some(host)) => rule.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        };
+        host_matches && path.starts_with(self.prefix.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ExtraHeaders {
+    rules: Vec<HeaderRule>,
+}
+
+impl ExtraHeaders {
+    pub const ENV_VARIABLE: &'static str = "SERVER_EXTRA_HEADERS";
+
+    /// Rules from the file named by `SERVER_EXTRA_HEADERS`, none if there is none or it is invalid.
+    pub fn from_env() -> Self {
+        let Some(path) = env::var_os(Self::ENV_VARIABLE) else {
+            return Self::default();
+        };
+        match Self::load(Path::new(&path)) {
+            Ok(rules) => rules,
+            Err(err) => {
+                log!(Level::Warn, "{}: {}, no headers are added", Self::ENV_VARIABLE, err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, LoadExtraHeadersError> {
+        let repr = fs::read_to_string(path).map_err(LoadExtraHeadersError::Io)?;
+        repr.parse().map_err(LoadExtraHeadersError::Parse)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Headers to add to the response `present` headers of the request for `target` on `host`,
+    /// the configured host name the request is served by.
+    pub fn headers(&self, host: Option<&str>, target: &Path, present: &Headers) -> Vec<Header> {
+        let Some(path) = target.to_str().and_then(|target| target.split('?').next()) else {
+            return Vec::new();
+        };
+        let mut headers: Vec<&HeaderRule> = Vec::new();
+        for rule in self.rules.iter().filter(|rule| rule.matches(host, path)) {
+            headers.retain(|added| !added.name.eq_ignore_ascii_case(&rule.name));
+            headers.push(rule);
+        }
+        headers
+            .into_iter()
+            .filter(|rule| !present.iter().any(|header| header.name().eq_ignore_ascii_case(&rule.name)))
+            .map(|rule| Header::Unknown(rule.name.clone().into(), rule.value.clone()))
+            .collect()
+    }
+
+    fn is_token(name: &str) -> bool {
+        !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+    }
+}
+
+impl std::str::FromStr for ExtraHeaders {
+    type Err = ParseExtraHeadersError;
+
+    fn from_str(repr: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (index, line) in repr.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = || ParseExtraHeadersError::MalformedRule(number, line.to_owned());
+            let (host, rest) = line.split_once(char::is_whitespace).ok_or_else(malformed)?;
+            let (prefix, header) = rest.trim_start().split_once(char::is_whitespace).ok_or_else(malformed)?;
+            let (name, value) = header.trim_start().split_once(':').ok_or_else(malformed)?;
+            if !prefix.starts_with('/') {
+                return Err(ParseExtraHeadersError::InvalidPrefix(number, prefix.to_owned()));
+            }
+            let forbidden = HeaderScope::of(name) == HeaderScope::HopByHop
+                || BODY_HEADERS.iter().any(|body| body.eq_ignore_ascii_case(name));
+            if !Self::is_token(name) || forbidden {
+                return Err(ParseExtraHeadersError::InvalidName(number, name.to_owned()));
+            }
+            rules.push(HeaderRule {
+                host: (host != ANY_HOST).then(|| host.to_lowercase()),
+                prefix: prefix.to_owned(),
+                name: name.to_owned(),
+                value: value.trim().to_owned(),
+            });
+        }
+        Ok(Self { rules })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseExtraHeadersError {
+    MalformedRule(usize, String),
+    InvalidPrefix(usize, String),
+    /// Not a valid field name, or a header the server has to set itself.
+    InvalidName(usize, String),
+}
+
+impl Display for ParseExtraHeadersError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MalformedRule(line, repr) => write!(f, "line {line}: expected '<host> <path prefix> <name>: <value>', got '{repr}'"),
+            Self::InvalidPrefix(line, repr) => write!(f, "line {line}: path prefix has to start with '/', got '{repr}'"),
+            Self::InvalidName(line, repr) => write!(f, "line {line}: header '{repr}' cannot be added"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadExtraHeadersError {
+    Io(io::Error),
+    Parse(ParseExtraHeadersError),
+}
+
+impl Display for LoadExtraHeadersError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read header rules: {err}"),
+            Self::Parse(err) => write!(f, "invalid header rules: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::SimpleHeaderParser;
+
+    fn headers(rules: &ExtraHeaders, host: Option<&str>, target: &str, present: &str) -> Vec<String> {
+        let present = Headers::parse::<SimpleHeaderParser>(present).unwrap();
+        rules.headers(host, Path::new(target), &present).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules: ExtraHeaders = "# security headers\n\
+            *          /        X-Frame-Options: DENY\n\
+            localhost  /        Strict-Transport-Security: max-age=31536000\n\
+            *          /embed/  X-Frame-Options: SAMEORIGIN\n\
+            *          /api/    Cache-Control: no-store\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            headers(&rules, Some("localhost"), "/index.html", ""),
+            ["X-Frame-Options: DENY", "Strict-Transport-Security: max-age=31536000"]
+        );
+        assert_eq!(headers(&rules, Some("lab108-18"), "/", ""), ["X-Frame-Options: DENY"]);
+        /* the later rule replaces the value */
+        assert_eq!(headers(&rules, None, "/embed/player?id=1", ""), ["X-Frame-Options: SAMEORIGIN"]);
+        /* headers set by the handler are kept */
+        assert_eq!(headers(&rules, None, "/api/items", "Cache-Control: max-age=60\r\n"), ["X-Frame-Options: DENY"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |repr: &str| repr.parse::<ExtraHeaders>();
+        assert_eq!(parse("* /"), Err(ParseExtraHeadersError::MalformedRule(1, String::from("* /"))));
+        assert_eq!(parse("\n* / X-Frame-Options DENY"), Err(ParseExtraHeadersError::MalformedRule(2, String::from("* / X-Frame-Options DENY"))));
+        assert_eq!(parse("* api X-A: 1"), Err(ParseExtraHeadersError::InvalidPrefix(1, String::from("api"))));
+        assert_eq!(parse("* / Connection: close"), Err(ParseExtraHeadersError::InvalidName(1, String::from("Connection"))));
+        assert_eq!(parse("* / content-length: 0"), Err(ParseExtraHeadersError::InvalidName(1, String::from("content-length"))));
+        assert_eq!(parse("* / X(A): 1"), Err(ParseExtraHeadersError::InvalidName(1, String::from("X(A)"))));
+        assert!(parse("* / Content-Security-Policy: default-src 'self'; img-src *").is_ok());
+    }
+}
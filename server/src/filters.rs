@@ -0,0 +1,212 @@
+//! Mikołaj Depta 328690
+//!
+//! Transformations of response bodies applied while the bodies are sent.
+//!
+//! Body filter decides which responses it applies to and starts a chunk filter for each of them.
+//! Chunk filters see the body one chunk at a time, in order, so a file body is never loaded
+//! into memory as a whole. Length of a filtered body is not known before it is sent, so such
+//! responses drop `Content-Length`, see `Response::with_body_filter`.
+
+use std::sync::Arc;
+
+use crate::http::headers::entity_header::ContentType;
+use crate::http::request::Request;
+
+/// Transformation of a single response body.
+pub trait ChunkFilter: Send {
+    /// Appends the transformed `chunk` to `output`. Part of the chunk can be held back,
+    /// eg. the beginning of a match that may continue in the next chunk.
+    fn transform(&mut self, chunk: &[u8], output: &mut Vec<u8>);
+
+    /// Body ended, appends whatever was held back to `output`.
+    fn finish(&mut self, output: &mut Vec<u8>);
+}
+
+/// Source of chunk filters, one per filtered response.
+pub trait BodyFilter: Send + Sync {
+    /// Filter is applied to the body of type `content_type` sent in response to `request`.
+    fn applies_to(&self, request: &Request, content_type: &ContentType) -> bool;
+
+    fn start(&self) -> Box<dyn ChunkFilter>;
+}
+
+/// Output of every filter is the input of the next one.
+struct Chain(Vec<Box<dyn ChunkFilter>>);
+
+impl ChunkFilter for Chain {
+    fn transform(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+        let mut input = chunk.to_vec();
+        for filter in &mut self.0 {
+            let mut transformed = Vec::with_capacity(input.len());
+            filter.transform(&input, &mut transformed);
+            input = transformed;
+        }
+        output.extend_from_slice(&input);
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        /* data held back by a filter still has to pass through the filters after it */
+        let mut input = Vec::new();
+        for filter in &mut self.0 {
+            let mut transformed = Vec::new();
+            filter.transform(&input, &mut transformed);
+            filter.finish(&mut transformed);
+            input = transformed;
+        }
+        output.extend_from_slice(&input);
+    }
+}
+
+/// Body filters of the server, applied in the order they were added.
+#[derive(Clone, Default)]
+pub struct BodyFilters(Vec<Arc<dyn BodyFilter>>);
+
+impl BodyFilters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_filter(mut self, filter: impl BodyFilter + 'static) -> Self {
+        self.0.push(Arc::new(filter));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Chained filters that apply to the response, `None` if none of them does.
+    pub fn start(&self, request: &Request, content_type: &ContentType) -> Option<Box<dyn ChunkFilter>> {
+        let filters = self.0
+            .iter()
+            .filter(|filter| filter.applies_to(request, content_type))
+            .map(|filter| filter.start())
+            .collect::<Vec<_>>();
+        match filters.len() {
+            0 => None,
+            1 => filters.into_iter().next(),
+            _ => Some(Box::new(Chain(filters))),
+        }
+    }
+}
+
+/// Replaces every occurrence of a pattern, including the ones split between chunks,
+/// in bodies of the given media types - `text/html` unless set otherwise.
+pub struct Substitution {
+    pattern: Arc<[u8]>,
+    replacement: Arc<[u8]>,
+    media_types: Vec<String>,
+}
+
+impl Substitution {
+    /// # Panics
+    ///
+    /// Panics if the `pattern` is empty.
+    pub fn new(pattern: impl AsRef<[u8]>, replacement: impl AsRef<[u8]>) -> Self {
+        assert!(!pattern.as_ref().is_empty(), "substituted pattern must not be empty");
+        Self {
+            pattern: Arc::from(pattern.as_ref()),
+            replacement: Arc::from(replacement.as_ref()),
+            media_types: vec!["text/html".to_owned()],
+        }
+    }
+
+    /// Inserts `banner` right after the `<body>` tag of HTML pages.
+    pub fn banner(banner: &str) -> Self {
+        Self::new("<body>", format!("<body>{banner}"))
+    }
+
+    pub fn for_media_types(mut self, media_types: &[&str]) -> Self {
+        self.media_types = media_types.iter().map(|media_type| media_type.to_lowercase()).collect();
+        self
+    }
+}
+
+impl BodyFilter for Substitution {
+    fn applies_to(&self, _: &Request, content_type: &ContentType) -> bool {
+        let content_type = content_type.to_string();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        self.media_types.iter().any(|accepted| accepted.eq_ignore_ascii_case(media_type))
+    }
+
+    fn start(&self) -> Box<dyn ChunkFilter> {
+        Box::new(SubstitutionFilter {
+            pattern: self.pattern.clone(),
+            replacement: self.replacement.clone(),
+            pending: Vec::new(),
+        })
+    }
+}
+
+struct SubstitutionFilter {
+    pattern: Arc<[u8]>,
+    replacement: Arc<[u8]>,
+    /// End of the data seen so far that may be the beginning of a match.
+    pending: Vec<u8>,
+}
+
+impl ChunkFilter for SubstitutionFilter {
+    fn transform(&mut self, chunk: &[u8], output: &mut Vec<u8>) {
+        self.pending.extend_from_slice(chunk);
+        let mut start = 0;
+        while let Some(position) = self.pending[start..]
+            .windows(self.pattern.len())
+            .position(|window| window == &*self.pattern)
+        {
+            output.extend_from_slice(&self.pending[start..start + position]);
+            output.extend_from_slice(&self.replacement);
+            start += position + self.pattern.len();
+        }
+        let held_back = self.pending.len().saturating_sub(self.pattern.len() - 1).max(start);
+        output.extend_from_slice(&self.pending[start..held_back]);
+        self.pending.drain(..held_back);
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        output.append(&mut self.pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::common::Method;
+
+    fn filter_chunks(filter: &mut dyn ChunkFilter, chunks: &[&str]) -> String {
+        let mut output = Vec::new();
+        for chunk in chunks {
+            filter.transform(chunk.as_bytes(), &mut output);
+        }
+        filter.finish(&mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_substitution_across_chunks() {
+        let substitution = Substitution::new("http://backend", "https://example.com");
+        let chunks = ["<a href=\"http://back", "end/a\">http://backend", "</a> http://b"];
+        assert_eq!(
+            filter_chunks(substitution.start().as_mut(), &chunks),
+            "<a href=\"https://example.com/a\">https://example.com</a> http://b"
+        );
+        /* every byte in its own chunk */
+        let page = "<body><body>";
+        let bytes = page.split("").filter(|byte| !byte.is_empty()).collect::<Vec<_>>();
+        assert_eq!(filter_chunks(Substitution::banner("!").start().as_mut(), &bytes), "<body>!<body>!");
+    }
+
+    #[test]
+    fn test_filters_are_chained_by_media_type() {
+        let filters = BodyFilters::new()
+            .with_filter(Substitution::new("a", "bb"))
+            .with_filter(Substitution::new("bbb", "c"))
+            .with_filter(Substitution::new("b", "x").for_media_types(&["text/css"]));
+        let request = Request::fixture(Method::GET, "/", "", None);
+        assert!(filters.start(&request, &ContentType::Jpeg).is_none());
+        let mut chain = filters.start(&request, &ContentType::Html).unwrap();
+        /* output held back by the first filter reaches the second one when the body ends */
+        assert_eq!(filter_chunks(chain.as_mut(), &["ab", "a"]), "cbb");
+        let mut css = filters.start(&request, &"text/css".parse().unwrap()).unwrap();
+        assert_eq!(filter_chunks(css.as_mut(), &["abc"]), "axc");
+    }
+}
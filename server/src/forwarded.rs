@@ -0,0 +1,280 @@
+//! Mikołaj Depta 328690
+//!
+//! Addresses of clients connecting through proxies, eg. the crate's own proxy or haproxy.
+//!
+//! Behind a proxy every connection comes from the proxy. Proxies listed in `SERVER_TRUSTED_PROXIES`,
+//! eg. `127.0.0.1,10.0.0.2`, are believed to tell who the client is, either for every request
+//! in `X-Forwarded-For` or once for the whole connection in a PROXY protocol v1 line sent before
+//! the first request, see <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>:
+//!
+//! ```text
+//! PROXY TCP4 192.0.2.7 198.51.100.1 56324 80\r\n
+//! ```
+//!
+//! The line is expected on every connection from a trusted proxy once `SERVER_PROXY_PROTOCOL=1`.
+//! Other peers are served as they are, anyone can send the header or the line.
+//!
+//! Client address is then used for rate limiting, CGI and diagnostics instead of the proxy one.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::str;
+use std::time::{Duration, Instant};
+
+use crate::logger::{log, Level};
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Forwarding {
+    trusted: Vec<IpAddr>,
+    proxy_protocol: bool,
+}
+
+impl Forwarding {
+    pub const ENV_VARIABLE: &'static str = "SERVER_TRUSTED_PROXIES";
+    pub const PROXY_PROTOCOL_ENV_VARIABLE: &'static str = "SERVER_PROXY_PROTOCOL";
+
+    /// Addresses forwarded by the `trusted` proxies are believed.
+    pub fn new(trusted: Vec<IpAddr>) -> Self {
+        Self { trusted, proxy_protocol: false }
+    }
+
+    /// Connections from the trusted proxies start with a PROXY protocol line.
+    pub fn with_proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Proxies listed in `SERVER_TRUSTED_PROXIES`, invalid addresses are skipped.
+    /// Nothing is forwarded if the variable is not set.
+    pub fn from_env() -> Self {
+        let trusted = env::var(Self::ENV_VARIABLE)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .filter_map(|address| match address.parse() {
+                Ok(address) => Some(address),
+                Err(err) => {
+                    log!(Level::Warn, "{}: invalid address '{}': {}", Self::ENV_VARIABLE, address, err);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let proxy_protocol = env::var(Self::PROXY_PROTOCOL_ENV_VARIABLE).is_ok_and(|value| value.trim() == "1");
+        if proxy_protocol && trusted.is_empty() {
+            log!(Level::Warn, "{}: no proxy is trusted, see {}", Self::PROXY_PROTOCOL_ENV_VARIABLE, Self::ENV_VARIABLE);
+        }
+        Self::new(trusted).with_proxy_protocol(proxy_protocol)
+    }
+
+    pub fn is_trusted(&self, peer: IpAddr) -> bool {
+        self.trusted.contains(&peer)
+    }
+
+    /// Connection from the `peer` has to start with a PROXY protocol line.
+    pub fn expects_proxy_header(&self, peer: Option<SocketAddr>) -> bool {
+        self.proxy_protocol && peer.is_some_and(|peer| self.is_trusted(peer.ip()))
+    }
+
+    /// Address of the client the `peer` sent the request for, given the addresses the request was
+    /// `forwarded_for`, client first. The last address not added by a trusted proxy is taken,
+    /// the ones before it could have been made up by the client.
+    pub fn client(&self, peer: IpAddr, forwarded_for: impl DoubleEndedIterator<Item=IpAddr>) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for address in forwarded_for.rev() {
+            client = address;
+            if !self.is_trusted(address) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseProxyHeaderError {
+    /// No CRLF within the longest line the protocol allows.
+    TooLong,
+    Malformed(String),
+}
+
+impl Display for ParseProxyHeaderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLong => write!(f, "PROXY protocol line longer than {} bytes", MAX_PROXY_HEADER),
+            Self::Malformed(line) => write!(f, "malformed PROXY protocol line: {line:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseProxyHeaderError {}
+
+/// Longest PROXY protocol v1 line, including the CRLF.
+const MAX_PROXY_HEADER: usize = 107;
+
+/// Source address of the connection carried by the PROXY protocol `line` (without the CRLF),
+/// `None` for the `UNKNOWN` protocol, whose connections are served as if they were the proxy ones.
+pub fn parse_proxy_header(line: &str) -> Result<Option<SocketAddr>, ParseProxyHeaderError> {
+    let malformed = || ParseProxyHeaderError::Malformed(line.to_owned());
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(malformed());
+    }
+    let protocol = fields.next().ok_or_else(malformed)?;
+    if protocol == "UNKNOWN" {
+        return Ok(None);
+    }
+    let fields = fields.collect::<Vec<_>>();
+    let [source, destination, source_port, destination_port] = fields[..] else {
+        return Err(malformed());
+    };
+    let source = source.parse::<IpAddr>().map_err(|_| malformed())?;
+    let destination = destination.parse::<IpAddr>().map_err(|_| malformed())?;
+    let valid = match protocol {
+        "TCP4" => source.is_ipv4() && destination.is_ipv4(),
+        "TCP6" => source.is_ipv6() && destination.is_ipv6(),
+        _ => false,
+    };
+    let source_port = source_port.parse::<u16>().map_err(|_| malformed())?;
+    if !valid || destination_port.parse::<u16>().is_err() {
+        return Err(malformed());
+    }
+    Ok(Some(SocketAddr::new(source, source_port)))
+}
+
+/// Reads the PROXY protocol line the connection starts with, leaving the request after it unread.
+/// Blocks until the whole line arrives, for at most `timeout`.
+pub fn read_proxy_header(mut stream: &TcpStream, timeout: Duration) -> io::Result<Option<SocketAddr>> {
+    let deadline = Instant::now() + timeout;
+    let mut line = Vec::with_capacity(MAX_PROXY_HEADER);
+    let mut chunk = [0; MAX_PROXY_HEADER];
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        /* bytes are only taken up to the end of the line, the request behind it is left in the socket */
+        let peeked = stream.peek(&mut chunk[..MAX_PROXY_HEADER - line.len()])?;
+        if peeked == 0 {
+            break Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let end = chunk[..peeked].iter().position(|byte| *byte == b'\n');
+        let taken = end.map_or(peeked, |end| end + 1);
+        stream.read_exact(&mut chunk[..taken])?;
+        line.extend_from_slice(&chunk[..taken]);
+        if end.is_some() {
+            let parsed = line
+                .strip_suffix(b"\r\n")
+                .and_then(|line| str::from_utf8(line).ok())
+                .ok_or_else(|| ParseProxyHeaderError::Malformed(String::from_utf8_lossy(&line).into_owned()))
+                .and_then(parse_proxy_header);
+            break parsed.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        if line.len() == MAX_PROXY_HEADER {
+            break Err(io::Error::new(io::ErrorKind::InvalidData, ParseProxyHeaderError::TooLong));
+        }
+    };
+    stream.set_read_timeout(None)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn address(repr: &str) -> IpAddr {
+        repr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_proxy_header() {
+        assert_eq!(
+            parse_proxy_header("PROXY TCP4 192.0.2.7 198.51.100.1 56324 80"),
+            Ok(Some("192.0.2.7:56324".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_proxy_header("PROXY TCP6 2001:db8::7 2001:db8::1 56324 443"),
+            Ok(Some("[2001:db8::7]:56324".parse().unwrap()))
+        );
+        assert_eq!(parse_proxy_header("PROXY UNKNOWN"), Ok(None));
+        for line in [
+            "PROXY TCP4 2001:db8::7 198.51.100.1 56324 80",
+            "PROXY TCP4 192.0.2.7 198.51.100.1 56324",
+            "PROXY TCP4 192.0.2.7 198.51.100.1 65536 80",
+            "PROXY UDP4 192.0.2.7 198.51.100.1 56324 80",
+            "GET / HTTP/1.1",
+        ] {
+            assert!(parse_proxy_header(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_client() {
+        let forwarding = Forwarding::new(vec![address("10.0.0.1"), address("10.0.0.2")]);
+        let forwarded = |list: &[&str]| list.iter().map(|repr| address(repr)).collect::<Vec<_>>().into_iter();
+        /* untrusted peers are taken at their word */
+        assert_eq!(forwarding.client(address("192.0.2.9"), forwarded(&["192.0.2.7"])), address("192.0.2.9"));
+        assert_eq!(forwarding.client(address("10.0.0.1"), forwarded(&[])), address("10.0.0.1"));
+        assert_eq!(forwarding.client(address("10.0.0.1"), forwarded(&["192.0.2.7"])), address("192.0.2.7"));
+        /* client made up the first address, the trusted proxies added the rest */
+        assert_eq!(
+            forwarding.client(address("10.0.0.1"), forwarded(&["1.1.1.1", "192.0.2.7", "10.0.0.2"])),
+            address("192.0.2.7")
+        );
+        assert_eq!(forwarding.client(address("10.0.0.1"), forwarded(&["10.0.0.2"])), address("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_expects_proxy_header() {
+        let forwarding = Forwarding::new(vec![address("127.0.0.1")]);
+        let peer = Some("127.0.0.1:4000".parse().unwrap());
+        assert!(!forwarding.expects_proxy_header(peer));
+        let forwarding = forwarding.with_proxy_protocol(true);
+        assert!(forwarding.expects_proxy_header(peer));
+        assert!(!forwarding.expects_proxy_header(Some("192.0.2.7:4000".parse().unwrap())));
+        assert!(!forwarding.expects_proxy_header(None));
+    }
+
+    #[test]
+    fn test_proxy_header_is_read_up_to_the_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let writer = thread::spawn(move || {
+            client.write_all(b"PROXY TCP4 192.0.2.7 127.0.0.1 56324").unwrap();
+            thread::sleep(Duration::from_millis(20));
+            client.write_all(b" 80\r\nGET / HTTP/1.1\r\n\r\n").unwrap();
+            client
+        });
+        let source = read_proxy_header(&stream, Duration::from_secs(5)).unwrap();
+        assert_eq!(source, Some("192.0.2.7:56324".parse().unwrap()));
+        let _client = writer.join().unwrap();
+        let mut request = [0; 18];
+        (&stream).read_exact(&mut request).unwrap();
+        assert_eq!(&request, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_connections_without_proxy_header_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let err = read_proxy_header(&stream, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let _silent = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let err = read_proxy_header(&stream, Duration::from_millis(20)).unwrap_err();
+        assert!(matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{err}");
+    }
+}
@@ -0,0 +1,145 @@
+//! Mikołaj Depta 328690
+//!
+//! Strict validation of the request head, against request smuggling.
+//!
+//! Proxy in front of the server may frame a request differently than the server does, when
+//! the head is ambiguous. Bytes the proxy took for the body of one request are then taken by
+//! the server for another request, which bypasses the checks of the proxy, see RFC 9112 section 11.2.
+//!
+//! Requests a lenient parser accepts but some other implementation could read differently are
+//! rejected with 400 once `SERVER_STRICT_FRAMING=1`:
+//!
+//! - line ending with a bare LF, or a CR not followed by LF,
+//! - whitespace in a field name or between it and the colon, see RFC 9112 section 5.1,
+//! - field value continued on the next line (obsolete line folding),
+//! - `Content-Length` that is repeated, even with the same value, or is not a plain decimal number,
+//! - `Content-Length` together with `Transfer-Encoding`, which is rejected in any mode.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::http::chunked::TRANSFER_ENCODING_HEADER;
+
+pub const ENV_VARIABLE: &str = "SERVER_STRICT_FRAMING";
+const CONTENT_LENGTH_HEADER: &str = "Content-Length";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FramingError {
+    BareLineFeed,
+    BareCarriageReturn,
+    WhitespaceInFieldName(String),
+    ObsoleteLineFolding,
+    DuplicateContentLength,
+    InvalidContentLength(String),
+    ContentLengthWithTransferEncoding,
+}
+
+impl Display for FramingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BareLineFeed => write!(f, "line ended with a bare LF"),
+            Self::BareCarriageReturn => write!(f, "CR not followed by LF"),
+            Self::WhitespaceInFieldName(name) => write!(f, "whitespace in field name '{name}'"),
+            Self::ObsoleteLineFolding => write!(f, "field value folded over several lines"),
+            Self::DuplicateContentLength => write!(f, "Content-Length repeated"),
+            Self::InvalidContentLength(value) => write!(f, "invalid Content-Length '{value}'"),
+            Self::ContentLengthWithTransferEncoding => write!(f, "both Content-Length and Transfer-Encoding are present"),
+        }
+    }
+}
+
+impl std::error::Error for FramingError { }
+
+impl From<FramingError> for io::Error {
+    fn from(err: FramingError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+}
+
+/// Strict framing was enabled by the operator.
+pub fn is_enabled() -> bool {
+    env::var(ENV_VARIABLE).is_ok_and(|value| value.trim() == "1")
+}
+
+/// Rejects bare LF in the beginning of a head that has not fully arrived yet, such head
+/// would otherwise be buffered until the size limit, since its end is never found.
+pub fn check_line_endings(received: &[u8]) -> Result<(), FramingError> {
+    match received.iter().enumerate().any(|(index, &byte)| byte == b'\n' && (index == 0 || received[index - 1] != b'\r')) {
+        true => Err(FramingError::BareLineFeed),
+        false => Ok(()),
+    }
+}
+
+/// Validates the start line and the header section of the request, each line ended with CRLF.
+pub fn validate(head: &[u8]) -> Result<(), FramingError> {
+    check_line_endings(head)?;
+    let bare_cr = head.iter().enumerate().any(|(index, &byte)| byte == b'\r' && head.get(index + 1) != Some(&b'\n'));
+    if bare_cr {
+        return Err(FramingError::BareCarriageReturn);
+    }
+    let head = String::from_utf8_lossy(head);
+    let mut content_length = None;
+    let mut transfer_encoding = false;
+    for line in head.split_terminator("\r\n").skip(1) {
+        if line.starts_with([' ', '\t']) {
+            return Err(FramingError::ObsoleteLineFolding);
+        }
+        /* line without a colon is left to the header parser */
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.contains(|char: char| char.is_ascii_whitespace()) {
+            return Err(FramingError::WhitespaceInFieldName(name.to_owned()));
+        }
+        if name.eq_ignore_ascii_case(CONTENT_LENGTH_HEADER) {
+            let value = value.trim_matches([' ', '\t']);
+            if content_length.replace(value).is_some() {
+                return Err(FramingError::DuplicateContentLength);
+            }
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(FramingError::InvalidContentLength(value.to_owned()));
+            }
+        }
+        transfer_encoding |= name.eq_ignore_ascii_case(TRANSFER_ENCODING_HEADER);
+    }
+    match content_length.is_some() && transfer_encoding {
+        true => Err(FramingError::ContentLengthWithTransferEncoding),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(headers: &str) -> Vec<u8> {
+        format!("POST / HTTP/1.1\r\nHost: localhost\r\n{headers}").into_bytes()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(&head("Content-Length: 5\r\nAccept: */*\r\n")), Ok(()));
+        assert_eq!(validate(&head("Transfer-Encoding: chunked\r\n")), Ok(()));
+        assert_eq!(validate(&head("X: a\nContent-Length: 5\r\n")), Err(FramingError::BareLineFeed));
+        assert_eq!(validate(&head("X: a\rContent-Length: 5\r\n")), Err(FramingError::BareCarriageReturn));
+        assert_eq!(
+            validate(&head("Content-Length : 5\r\n")),
+            Err(FramingError::WhitespaceInFieldName(String::from("Content-Length ")))
+        );
+        assert_eq!(validate(&head("X: a\r\n\tContent-Length: 5\r\n")), Err(FramingError::ObsoleteLineFolding));
+        assert_eq!(validate(&head("Content-Length: 5\r\ncontent-length: 5\r\n")), Err(FramingError::DuplicateContentLength));
+        assert_eq!(validate(&head("Content-Length: +5\r\n")), Err(FramingError::InvalidContentLength(String::from("+5"))));
+        assert_eq!(
+            validate(&head("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n")),
+            Err(FramingError::ContentLengthWithTransferEncoding)
+        );
+    }
+
+    #[test]
+    fn test_bare_line_feed_is_found_before_the_head_ends() {
+        assert_eq!(check_line_endings(b"GET / HTTP/1.1\r\nHost: localhost\r"), Ok(()));
+        assert_eq!(check_line_endings(b"GET / HTTP/1.1\r\nHost: localhost\n"), Err(FramingError::BareLineFeed));
+        assert_eq!(check_line_endings(b"\n"), Err(FramingError::BareLineFeed));
+    }
+}
@@ -0,0 +1,117 @@
+//! Mikołaj Depta 328690
+//!
+//! Health check for load balancers at `GET /healthz`.
+//!
+//! Server answers `OK` once it accepts connections and every document root can be listed,
+//! `NOT_READY` with 503 while it is starting, shutting down or a document root is unreadable.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+pub const HEALTH_PATH: &str = "/healthz";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum State {
+    Starting,
+    Ready,
+    ShuttingDown,
+}
+
+impl State {
+    fn from_repr(repr: u8) -> Self {
+        match repr {
+            0 => State::Starting,
+            1 => State::Ready,
+            _ => State::ShuttingDown,
+        }
+    }
+
+    fn repr(self) -> u8 {
+        match self {
+            State::Starting => 0,
+            State::Ready => 1,
+            State::ShuttingDown => 2,
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let repr = match self {
+            State::Starting => "starting",
+            State::Ready => "ready",
+            State::ShuttingDown => "shutting down",
+        };
+        write!(f, "{repr}")
+    }
+}
+
+/// Lifecycle state of the server shared by the accepting thread and the handlers.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    state: Arc<AtomicU8>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> State {
+        State::from_repr(self.state.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, state: State) {
+        self.state.store(state.repr(), Ordering::Relaxed);
+    }
+
+    /// Server is ready if it accepts connections and can read all of the `roots`.
+    pub fn check<'a>(&self, mut roots: impl Iterator<Item=&'a Path>) -> Result<(), NotReady> {
+        match self.state() {
+            State::Ready => {}
+            state => return Err(NotReady::State(state)),
+        }
+        match roots.find_map(|root| fs::read_dir(root).err().map(|err| (root, err))) {
+            Some((root, err)) => Err(NotReady::UnreadableRoot(root.to_owned(), err)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NotReady {
+    State(State),
+    UnreadableRoot(PathBuf, io::Error),
+}
+
+impl Display for NotReady {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotReady::State(state) => write!(f, "server is {state}"),
+            NotReady::UnreadableRoot(root, err) => write!(f, "cannot read document root {}: {}", root.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::TempDir;
+
+    #[test]
+    fn test_ready_only_after_start_and_with_readable_roots() {
+        let dir = TempDir::new("server-health").unwrap();
+        let readiness = Readiness::new();
+        assert!(matches!(readiness.check([dir.path()].into_iter()), Err(NotReady::State(State::Starting))));
+        readiness.clone().set(State::Ready);
+        assert!(readiness.check([dir.path()].into_iter()).is_ok());
+        let missing = dir.path().join("missing");
+        assert!(matches!(readiness.check([dir.path(), &missing].into_iter()), Err(NotReady::UnreadableRoot(..))));
+        readiness.set(State::ShuttingDown);
+        assert!(matches!(readiness.check([dir.path()].into_iter()), Err(NotReady::State(State::ShuttingDown))));
+    }
+}
@@ -0,0 +1,240 @@
+//! Mikołaj Depta 328690
+//!
+//! Decoder of request bodies sent in chunked transfer coding, see RFC 9112 section 7.1.
+//!
+//! Body is a sequence of chunks, each preceded by its size in hex, ended by a chunk of size zero
+//! and an optional trailer section. Chunk extensions and trailer fields are accepted and dropped,
+//! the server does not use any of them.
+//!
+//! Request carrying both `Transfer-Encoding` and `Content-Length` is rejected, intermediaries could
+//! disagree on where its body ends and the rest would be taken for another request, see RFC 9112
+//! section 6.3. So is a request in a transfer coding other than `chunked` alone.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::http::headers::{Header, Headers};
+
+pub const TRANSFER_ENCODING_HEADER: &str = "Transfer-Encoding";
+const CHUNKED_CODING: &str = "chunked";
+const CRLF: &[u8] = b"\r\n";
+
+/// Chunk size line, with its extensions, no longer than this.
+const MAX_SIZE_LINE: usize = 1024;
+/// Trailer section no longer than this, trailer fields are discarded anyway.
+const MAX_TRAILER_SECTION: usize = 8192;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseChunkedError {
+    /// Both `Transfer-Encoding` and `Content-Length` delimit the body.
+    ConflictingFraming,
+    UnsupportedCoding(String),
+    InvalidChunkSize(String),
+    /// Chunk data not followed by CRLF.
+    MissingChunkEnd,
+    SizeLineTooLong,
+    TrailerSectionTooLong,
+}
+
+impl Display for ParseChunkedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConflictingFraming => write!(f, "both Transfer-Encoding and Content-Length are present"),
+            Self::UnsupportedCoding(codings) => write!(f, "unsupported transfer coding '{codings}'"),
+            Self::InvalidChunkSize(size) => write!(f, "invalid chunk size '{size}'"),
+            Self::MissingChunkEnd => write!(f, "chunk data is not followed by CRLF"),
+            Self::SizeLineTooLong => write!(f, "chunk size line too long"),
+            Self::TrailerSectionTooLong => write!(f, "trailer section too long"),
+        }
+    }
+}
+
+impl std::error::Error for ParseChunkedError { }
+
+impl From<ParseChunkedError> for io::Error {
+    fn from(err: ParseChunkedError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+}
+
+/// Body of the request with `headers` is sent in chunked transfer coding.
+pub fn is_chunked(headers: &Headers) -> Result<bool, ParseChunkedError> {
+    let codings = headers
+        .iter()
+        .filter_map(|header| match header {
+            Header::Unknown(name, value) if name.eq_ignore_ascii_case(TRANSFER_ENCODING_HEADER) => Some(value.as_str()),
+            _ => None,
+        })
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect::<Vec<_>>();
+    match codings.as_slice() {
+        [] => Ok(false),
+        _ if headers.content_length().is_some() => Err(ParseChunkedError::ConflictingFraming),
+        [coding] if coding.eq_ignore_ascii_case(CHUNKED_CODING) => Ok(true),
+        codings => Err(ParseChunkedError::UnsupportedCoding(codings.join(", "))),
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Size,
+    /// Bytes of the current chunk yet to be received.
+    Data(usize),
+    DataEnd,
+    /// Bytes of the trailer section received so far.
+    Trailers(usize),
+    Done,
+}
+
+/// Decodes the body as it arrives, input may be split anywhere.
+#[derive(Debug, Clone)]
+pub struct ChunkedDecoder {
+    state: State,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self { state: State::Size }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Appends data of the chunks in `input` to `output`. Returns the number of bytes of `input`
+    /// consumed, the rest is an incomplete line to be passed again with the bytes that follow it,
+    /// or the beginning of the next message once the body ended.
+    pub fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<usize, ParseChunkedError> {
+        let mut position = 0;
+        loop {
+            let rest = &input[position..];
+            match self.state {
+                State::Size => {
+                    let Some(end) = find_crlf(rest) else {
+                        if rest.len() > MAX_SIZE_LINE {
+                            return Err(ParseChunkedError::SizeLineTooLong);
+                        }
+                        return Ok(position);
+                    };
+                    if end > MAX_SIZE_LINE {
+                        return Err(ParseChunkedError::SizeLineTooLong);
+                    }
+                    self.state = match parse_size(&rest[..end])? {
+                        0 => State::Trailers(0),
+                        size => State::Data(size),
+                    };
+                    position += end + CRLF.len();
+                }
+                State::Data(remaining) => {
+                    let count = remaining.min(rest.len());
+                    output.extend_from_slice(&rest[..count]);
+                    position += count;
+                    match remaining - count {
+                        0 => self.state = State::DataEnd,
+                        remaining => {
+                            self.state = State::Data(remaining);
+                            return Ok(position);
+                        }
+                    }
+                }
+                State::DataEnd => {
+                    if rest.len() < CRLF.len() {
+                        return Ok(position);
+                    }
+                    if !rest.starts_with(CRLF) {
+                        return Err(ParseChunkedError::MissingChunkEnd);
+                    }
+                    self.state = State::Size;
+                    position += CRLF.len();
+                }
+                State::Trailers(received) => {
+                    let Some(end) = find_crlf(rest) else {
+                        if received + rest.len() > MAX_TRAILER_SECTION {
+                            return Err(ParseChunkedError::TrailerSectionTooLong);
+                        }
+                        return Ok(position);
+                    };
+                    let received = received + end + CRLF.len();
+                    if received > MAX_TRAILER_SECTION {
+                        return Err(ParseChunkedError::TrailerSectionTooLong);
+                    }
+                    /* empty line ends the trailer section */
+                    self.state = if end == 0 { State::Done } else { State::Trailers(received) };
+                    position += end + CRLF.len();
+                }
+                State::Done => return Ok(position),
+            }
+        }
+    }
+}
+
+fn find_crlf(data: &[u8]) -> Option<usize> {
+    data.windows(CRLF.len()).position(|window| window == CRLF)
+}
+
+/// Size in hex, followed by extensions that are ignored.
+fn parse_size(line: &[u8]) -> Result<usize, ParseChunkedError> {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or_default().trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ParseChunkedError::InvalidChunkSize(size.to_owned()));
+    }
+    usize::from_str_radix(size, 16).map_err(|_| ParseChunkedError::InvalidChunkSize(size.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::headers::SimpleHeaderParser;
+
+    const BODY: &[u8] = b"4\r\nWiki\r\n7;name=value\r\npedia i\r\nB\r\nn \r\nchunks.\r\n0\r\nExpires: never\r\n\r\nGET /";
+
+    #[test]
+    fn test_decode() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut output = Vec::new();
+        assert_eq!(decoder.decode(BODY, &mut output), Ok(BODY.len() - 5));
+        assert!(decoder.is_done());
+        assert_eq!(output, b"Wikipedia in \r\nchunks.");
+    }
+
+    #[test]
+    fn test_decode_input_split_anywhere() {
+        for split in 0..BODY.len() {
+            let mut decoder = ChunkedDecoder::new();
+            let mut output = Vec::new();
+            let consumed = decoder.decode(&BODY[..split], &mut output).unwrap();
+            let mut rest = BODY[consumed..split].to_vec();
+            rest.extend_from_slice(&BODY[split..]);
+            assert_eq!(consumed + decoder.decode(&rest, &mut output).unwrap(), BODY.len() - 5, "{split}");
+            assert!(decoder.is_done());
+            assert_eq!(output, b"Wikipedia in \r\nchunks.", "{split}");
+        }
+    }
+
+    #[test]
+    fn test_malformed_bodies() {
+        let decode = |input: &[u8]| ChunkedDecoder::new().decode(input, &mut Vec::new());
+        assert_eq!(decode(b"x\r\n"), Err(ParseChunkedError::InvalidChunkSize(String::from("x"))));
+        assert_eq!(decode(b"-1\r\n"), Err(ParseChunkedError::InvalidChunkSize(String::from("-1"))));
+        assert!(matches!(decode(b"10000000000000000\r\n"), Err(ParseChunkedError::InvalidChunkSize(_))));
+        assert_eq!(decode(b"2\r\nabc\r\n"), Err(ParseChunkedError::MissingChunkEnd));
+        assert_eq!(decode(&[b'1'; MAX_SIZE_LINE + 1]), Err(ParseChunkedError::SizeLineTooLong));
+        let trailers = [&b"0\r\n"[..], &[b'a'; MAX_TRAILER_SECTION + 1]].concat();
+        assert_eq!(decode(&trailers), Err(ParseChunkedError::TrailerSectionTooLong));
+    }
+
+    #[test]
+    fn test_is_chunked() {
+        let is_chunked = |headers: &str| is_chunked(&Headers::parse::<SimpleHeaderParser>(headers).unwrap());
+        assert_eq!(is_chunked("Host: localhost\r\n"), Ok(false));
+        assert_eq!(is_chunked("Transfer-Encoding: Chunked\r\n"), Ok(true));
+        assert_eq!(is_chunked("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n"), Err(ParseChunkedError::ConflictingFraming));
+        assert_eq!(
+            is_chunked("Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n"),
+            Err(ParseChunkedError::UnsupportedCoding(String::from("gzip, chunked")))
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Mikołaj Depta 328690
+//!
+//! Minimal HTTP/1.1 client, for end-to-end tests of the server and for talking to other servers.
+//!
+//! `Client` sends a `Request` over a new connection and reads the whole response into a `Response`,
+//! either blocking or with a non-blocking `Exchange` advanced whenever the registry reports its
+//! socket ready. Every request asks the server to close the connection afterwards. Response body is
+//! delimited by `Content-Length`, chunked transfer coding or the end of the connection, chunked
+//! bodies are decoded and described by their length instead.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::http::chunked::{self, ChunkedDecoder, ParseChunkedError};
+use crate::http::common::{Body, Method, Version, CRLF};
+use crate::http::entity::Entity;
+use crate::http::headers::entity_header::EntityHeader;
+use crate::http::headers::general_header::{ConnectionType, GeneralHeader};
+use crate::http::headers::request_header::RequestHeader;
+use crate::http::headers::{Headers, ParseHeaderError, SimpleHeaderParser};
+use crate::http::request::{Request, StartLine};
+use crate::http::response::{Response, StatusCode, StatusLine};
+use crate::registry::{EventType, Notification, Registry, TimeoutDuration};
+
+/// Status line and headers no longer than this.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const SECTION_SEP: &[u8] = b"\r\n\r\n";
+
+#[derive(Debug)]
+pub enum ParseResponseError {
+    HeadTooLong,
+    InvalidStatusLine(String),
+    /// Status code the `StatusCode` type has no variant for.
+    UnsupportedStatus(usize),
+    InvalidHeaders(String),
+    Chunked(ParseChunkedError),
+    /// Connection closed before the end of the message.
+    Incomplete,
+}
+
+impl Display for ParseResponseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HeadTooLong => write!(f, "response head too long"),
+            Self::InvalidStatusLine(line) => write!(f, "invalid status line '{line}'"),
+            Self::UnsupportedStatus(code) => write!(f, "unsupported status code {code}"),
+            Self::InvalidHeaders(err) => write!(f, "invalid response headers: {err}"),
+            Self::Chunked(err) => write!(f, "invalid chunked body: {err}"),
+            Self::Incomplete => write!(f, "connection closed before the end of the response"),
+        }
+    }
+}
+
+impl std::error::Error for ParseResponseError { }
+
+impl From<ParseResponseError> for io::Error {
+    fn from(err: ParseResponseError) -> Self {
+        let kind = match err {
+            ParseResponseError::Incomplete => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+struct Head {
+    status_line: StatusLine,
+    headers: Headers,
+}
+
+/// How the end of the response body is found, see RFC 9112 section 6.3.
+enum Framing {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+    UntilClose,
+}
+
+/// Parses a response as it arrives, input may be split anywhere.
+pub struct ResponseParser {
+    /// Response to a HEAD request has no body, whatever its headers say.
+    head_request: bool,
+    received: Vec<u8>,
+    head: Option<(Head, Framing)>,
+    body: Vec<u8>,
+}
+
+impl ResponseParser {
+    pub fn new(method: &Method) -> Self {
+        Self { head_request: *method == Method::HEAD, received: Vec::new(), head: None, body: Vec::new() }
+    }
+
+    /// Takes the next part of the response, the whole response once it is complete.
+    pub fn push(&mut self, data: &[u8]) -> Result<Option<Response>, ParseResponseError> {
+        self.received.extend_from_slice(data);
+        if self.head.is_none() {
+            let Some(end) = self.received.windows(SECTION_SEP.len()).position(|window| window == SECTION_SEP) else {
+                if self.received.len() > MAX_HEAD_SIZE {
+                    return Err(ParseResponseError::HeadTooLong);
+                }
+                return Ok(None);
+            };
+            let head = Self::parse_head(&self.received[..end + CRLF.len()])?;
+            let framing = self.framing(&head)?;
+            self.received.drain(..end + SECTION_SEP.len());
+            self.head = Some((head, framing));
+        }
+        let Some((_, framing)) = &mut self.head else {
+            return Ok(None);
+        };
+        let done = match framing {
+            Framing::Length(len) => {
+                let count = (*len - self.body.len()).min(self.received.len());
+                self.body.extend(self.received.drain(..count));
+                self.body.len() == *len
+            }
+            Framing::Chunked(decoder) => {
+                let consumed = decoder.decode(&self.received, &mut self.body).map_err(ParseResponseError::Chunked)?;
+                self.received.drain(..consumed);
+                decoder.is_done()
+            }
+            Framing::UntilClose => {
+                self.body.append(&mut self.received);
+                false
+            }
+        };
+        Ok(done.then(|| self.response()))
+    }
+
+    /// Connection was closed, which ends a body delimited by the end of the connection.
+    pub fn finish(&mut self) -> Result<Response, ParseResponseError> {
+        match &self.head {
+            Some((_, Framing::UntilClose)) => Ok(self.response()),
+            _ => Err(ParseResponseError::Incomplete),
+        }
+    }
+
+    fn parse_head(head: &[u8]) -> Result<Head, ParseResponseError> {
+        let head = String::from_utf8_lossy(head);
+        let (status_line, headers) = head.split_once("\r\n").unwrap_or((&head, ""));
+        let invalid = || ParseResponseError::InvalidStatusLine(status_line.to_owned());
+        let mut parts = status_line.splitn(3, ' ');
+        let (Some(version), Some(code)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        /* HTTP/1.0 servers name their version in full */
+        let version = match version {
+            "HTTP/1.0" => Version::V1,
+            version => version.parse().map_err(|_| invalid())?,
+        };
+        let code = code.parse::<usize>().map_err(|_| invalid())?;
+        let status_code = StatusCode::from_code(code).ok_or(ParseResponseError::UnsupportedStatus(code))?;
+        let headers = Headers::parse::<SimpleHeaderParser>(headers)
+            .map_err(|err: ParseHeaderError| ParseResponseError::InvalidHeaders(err.to_string()))?;
+        Ok(Head { status_line: StatusLine::new(version, status_code), headers })
+    }
+
+    fn framing(&self, head: &Head) -> Result<Framing, ParseResponseError> {
+        let code = head.status_line.status_code().code();
+        if self.head_request || (100..200).contains(&code) || matches!(code, 204 | 304) {
+            return Ok(Framing::Length(0));
+        }
+        match chunked::is_chunked(&head.headers).map_err(ParseResponseError::Chunked)? {
+            true => Ok(Framing::Chunked(ChunkedDecoder::new())),
+            false => Ok(head.headers.content_length().map_or(Framing::UntilClose, Framing::Length)),
+        }
+    }
+
+    fn response(&mut self) -> Response {
+        let (Head { status_line, mut headers }, framing) = self.head.take().expect("response head was parsed");
+        if let Framing::Chunked(_) = framing {
+            headers.remove(chunked::TRANSFER_ENCODING_HEADER);
+            headers.append(EntityHeader::ContentLength(self.body.len()));
+        }
+        let data = std::mem::take(&mut self.body).into_boxed_slice();
+        let body = (!data.is_empty()).then(|| {
+            Body::SingleSource(Entity::new(data, headers.content_type().unwrap_or_default()))
+        });
+        Response::new(status_line, headers, body)
+    }
+}
+
+/// Sends requests over blocking connections, each waiting no longer than the `timeout`.
+#[derive(Debug, Copy, Clone)]
+pub struct Client {
+    timeout: Duration,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self { timeout: Duration::from_secs(10) }
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// GET request for `path` on the server at `address`.
+    pub fn get(address: SocketAddr, path: &str) -> io::Result<Response> {
+        Self::new().send(address, &Self::request(Method::GET, address, path))
+    }
+
+    /// Request with the `method` for the `path` on the server at `address`, naming it in `Host`.
+    pub fn request(method: Method, address: SocketAddr, path: &str) -> Request {
+        let host = match address {
+            SocketAddr::V4(address) => address.ip().to_string(),
+            SocketAddr::V6(address) => format!("[{}]", address.ip()),
+        };
+        let headers = Headers::new().with_header(RequestHeader::Host(host, Some(address.port())));
+        Request::new(StartLine::new(method, Path::new(path), Version::V1_1), headers, None)
+    }
+
+    pub fn send(&self, address: SocketAddr, request: &Request) -> io::Result<Response> {
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(&serialize(request)?)?;
+        let mut parser = ResponseParser::new(request.start_line().method());
+        let mut buffer = vec![0; Exchange::BUFFER_SIZE];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => return Ok(parser.finish()?),
+                Ok(count) => if let Some(response) = parser.push(&buffer[..count])? {
+                    return Ok(response);
+                },
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Same as `send`, but the socket is non-blocking and waited for with the `registry`,
+    /// which must not watch anything else meanwhile.
+    pub fn send_with(&self, registry: &mut Registry, address: SocketAddr, request: &Request) -> io::Result<Response> {
+        let mut exchange = Exchange::start(address, request, self.timeout)?;
+        let deadline = Instant::now() + self.timeout;
+        let mut interest = exchange.interest();
+        registry.add_interest(interest, exchange.as_raw_fd())?;
+        let result = loop {
+            match exchange.advance() {
+                Ok(Some(response)) => break Ok(response),
+                Ok(None) => {}
+                Err(err) => break Err(err),
+            }
+            if exchange.interest() != interest {
+                registry.modify_interest(interest, exchange.as_raw_fd(), exchange.interest())?;
+                interest = exchange.interest();
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match registry.await_event(&TimeoutDuration::Finite(remaining)) {
+                Ok(Notification::Timeout) => break Err(io::Error::from(io::ErrorKind::TimedOut)),
+                Ok(Notification::Event(..)) => {}
+                Err(err) => break Err(err),
+            }
+        };
+        registry.delete_interest(interest, exchange.as_raw_fd())?;
+        result
+    }
+}
+
+/// Request and response exchanged over a non-blocking connection.
+pub struct Exchange {
+    stream: TcpStream,
+    /// Serialized request, the part that was not sent yet.
+    outgoing: Vec<u8>,
+    parser: ResponseParser,
+}
+
+impl Exchange {
+    const BUFFER_SIZE: usize = 16 * 1024;
+
+    /// Connects to the server, blocking for at most `timeout`, the request is sent by `advance`.
+    pub fn start(address: SocketAddr, request: &Request, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_nonblocking(true)?;
+        let parser = ResponseParser::new(request.start_line().method());
+        Ok(Self { stream, outgoing: serialize(request)?, parser })
+    }
+
+    /// Event the socket has to be awaited for before the next `advance`.
+    pub fn interest(&self) -> EventType {
+        match self.outgoing.is_empty() {
+            true => EventType::Read,
+            false => EventType::Write,
+        }
+    }
+
+    /// Sends and receives whatever the socket lets through, `None` if the response is not complete yet.
+    pub fn advance(&mut self) -> io::Result<Option<Response>> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(count) => { self.outgoing.drain(..count); }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+        let mut buffer = vec![0; Self::BUFFER_SIZE];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(Some(self.parser.finish()?)),
+                Ok(count) => if let Some(response) = self.parser.push(&buffer[..count])? {
+                    return Ok(Some(response));
+                },
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Exchange {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// Head of the `request` with `Connection: close`, followed by its body described by its length.
+fn serialize(request: &Request) -> io::Result<Vec<u8>> {
+    let mut headers = request.headers().clone();
+    headers.remove("Connection");
+    headers.remove("Content-Length");
+    if let Some(body) = request.body() {
+        headers.append(EntityHeader::ContentLength(body.len()));
+    }
+    headers.append(GeneralHeader::Connection(ConnectionType::Close));
+    let mut serialized = format!("{}{}{}", request.start_line(), headers, CRLF).into_bytes();
+    if let Some(body) = request.body() {
+        body.reader().read_to_end(&mut serialized)?;
+    }
+    Ok(serialized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(method: Method, parts: &[&[u8]]) -> Result<Option<Response>, ParseResponseError> {
+        let mut parser = ResponseParser::new(&method);
+        let mut parsed = None;
+        for part in parts {
+            parsed = parser.push(part)?;
+        }
+        Ok(parsed)
+    }
+
+    fn body(response: &Response) -> &[u8] {
+        match response.body() {
+            Some(Body::SingleSource(entity)) => entity.as_ref(),
+            _ => &[],
+        }
+    }
+
+    #[test]
+    fn test_parse_responses() {
+        let response = parse(Method::GET, &[b"HTTP/1.1 200 OK\r\nContent-Le", b"ngth: 5\r\n\r\nhel", b"lo"]).unwrap().unwrap();
+        assert_eq!(response.status_line().status_code().code(), 200);
+        assert_eq!(body(&response), b"hello");
+
+        let chunked: &[&[u8]] = &[b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n", b"0\r\n\r\n"];
+        let response = parse(Method::GET, chunked).unwrap().unwrap();
+        assert_eq!(response.status_line().status_code().code(), 404);
+        assert_eq!(response.headers().content_length(), Some(3));
+        assert!(!response.headers().contains("Transfer-Encoding"));
+        assert_eq!(body(&response), b"abc");
+
+        let response = parse(Method::HEAD, &[b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"]).unwrap().unwrap();
+        assert!(response.body().is_none());
+
+        let mut parser = ResponseParser::new(&Method::GET);
+        assert!(parser.push(b"HTTP/1.0 200 OK\r\n\r\nuntil ").unwrap().is_none());
+        assert!(parser.push(b"close").unwrap().is_none());
+        assert_eq!(body(&parser.finish().unwrap()), b"until close");
+    }
+
+    #[test]
+    fn test_malformed_responses() {
+        assert!(matches!(parse(Method::GET, &[b"HTTP/1.1 2x0 OK\r\n\r\n"]), Err(ParseResponseError::InvalidStatusLine(_))));
+        assert!(matches!(parse(Method::GET, &[b"HTTP/1.1 299 Odd\r\n\r\n"]), Err(ParseResponseError::UnsupportedStatus(299))));
+        assert!(matches!(parse(Method::GET, &[&[b'a'; MAX_HEAD_SIZE + 1]]), Err(ParseResponseError::HeadTooLong)));
+        let mut parser = ResponseParser::new(&Method::GET);
+        assert!(parser.push(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel").unwrap().is_none());
+        assert!(matches!(parser.finish(), Err(ParseResponseError::Incomplete)));
+    }
+
+    #[test]
+    fn test_serialize_asks_to_close_the_connection() {
+        let address: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let request = Client::request(Method::GET, address, "/index.html");
+        assert_eq!(
+            String::from_utf8(serialize(&request).unwrap()).unwrap(),
+            "GET /index.html HTTP/1.1\r\nHost: 127.0.0.1:8080\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
@@ -1,8 +1,13 @@
 //! Mikołaj Depta 328690
 
 use super::entity::Entity;
+use crate::spool::SpoolFile;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
 use std::str::FromStr;
+use std::sync::Arc;
 
 pub const CRLF: &str = "\r\n";
 
@@ -62,18 +67,67 @@ impl FromStr for Version {
 #[non_exhaustive]
 pub enum Body {
     SingleSource(Entity),
+    /// `len` bytes of the file starting at the offset, sent straight from the file descriptor.
+    /// Descriptor may be shared with other responses, so it is only read at explicit offsets.
+    File(Arc<File>, u64, usize),
+    /// Request body written to an anonymous temporary file as it arrived, see `spool`.
+    Spooled(SpoolFile, usize),
 }
 
-pub struct ParseBodyError;
-
-impl AsRef<[u8]> for Body {
-    fn as_ref(&self) -> &[u8] {
+impl Body {
+    pub fn len(&self) -> usize {
         match self {
-            Body::SingleSource(entity) => entity.as_ref(),
+            Body::SingleSource(entity) => entity.as_ref().len(),
+            Body::File(_, _, len) | Body::Spooled(_, len) => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the body from the start, wherever it is kept.
+    pub fn reader(&self) -> BodyReader<'_> {
+        BodyReader { body: self, offset: 0 }
+    }
+
+    /// Reads the part of the body starting at `offset`, fails if a file ends before the body does.
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> io::Result<usize> {
+        let count = buf.len().min(self.len().saturating_sub(offset));
+        if count == 0 {
+            return Ok(0);
+        }
+        let bytes_read = match self {
+            Body::SingleSource(entity) => {
+                buf[..count].copy_from_slice(&entity.as_ref()[offset..offset + count]);
+                count
+            }
+            Body::File(file, start, _) => file.read_at(&mut buf[..count], start + offset as u64)?,
+            Body::Spooled(file, _) => file.read_at(&mut buf[..count], offset as u64)?,
+        };
+        if bytes_read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body file is shorter than the body"));
         }
+        Ok(bytes_read)
     }
 }
 
+/// Files are read at explicit offsets, so any number of readers can read the same body.
+pub struct BodyReader<'a> {
+    body: &'a Body,
+    offset: usize,
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.body.read_at(buf, self.offset)?;
+        self.offset += bytes_read;
+        Ok(bytes_read)
+    }
+}
+
+pub struct ParseBodyError;
+
 impl TryFrom<&[u8]> for Body {
     type Error = ParseBodyError;
 
@@ -84,20 +138,65 @@ impl TryFrom<&[u8]> for Body {
 
 /// Type of http method.
 #[non_exhaustive]
-#[derive(Debug)]
-#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum Method {
     GET,
+    HEAD,
+    POST,
+    PUT,
+    DELETE,
+    CONNECT,
+    OPTIONS,
+    TRACE,
+    PATCH,
+    /// Syntactically valid method the server knows nothing about.
+    Extension(String),
 }
 
 impl Method {
     const GET_REPR: &'static str = "GET";
+    const HEAD_REPR: &'static str = "HEAD";
+    const POST_REPR: &'static str = "POST";
+    const PUT_REPR: &'static str = "PUT";
+    const DELETE_REPR: &'static str = "DELETE";
+    const CONNECT_REPR: &'static str = "CONNECT";
+    const OPTIONS_REPR: &'static str = "OPTIONS";
+    const TRACE_REPR: &'static str = "TRACE";
+    const PATCH_REPR: &'static str = "PATCH";
+    /// Characters allowed in a method token besides alphanumerics, see RFC 9110 section 5.6.2.
+    const TOKEN_SPECIALS: &'static str = "!#$%&'*+-.^_`|~";
+
+    /// Methods the server can handle, advertised in the `Allow` header.
+    pub const SUPPORTED: [Method; 2] = [Method::GET, Method::OPTIONS];
+
+    /// Methods defined by the HTTP specification are recognized even if the server does not handle them.
+    pub fn is_recognized(&self) -> bool {
+        !matches!(self, Method::Extension(_))
+    }
+
+    pub fn is_supported(&self) -> bool {
+        Self::SUPPORTED.contains(self)
+    }
+
+    fn is_token(s: &str) -> bool {
+        !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || Self::TOKEN_SPECIALS.contains(c))
+    }
 }
 
 impl Display for Method {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let repr = match self {
             Method::GET => Self::GET_REPR,
+            Method::HEAD => Self::HEAD_REPR,
+            Method::POST => Self::POST_REPR,
+            Method::PUT => Self::PUT_REPR,
+            Method::DELETE => Self::DELETE_REPR,
+            Method::CONNECT => Self::CONNECT_REPR,
+            Method::OPTIONS => Self::OPTIONS_REPR,
+            Method::TRACE => Self::TRACE_REPR,
+            Method::PATCH => Self::PATCH_REPR,
+            Method::Extension(method) => method,
         };
         write!(f, "{repr}")
     }
@@ -105,12 +204,27 @@ impl Display for Method {
 
 pub struct ParseMethodError(String);
 
+impl Display for ParseMethodError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid http method: {}", self.0)
+    }
+}
+
 impl FromStr for Method {
     type Err = ParseMethodError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             Self::GET_REPR => Ok(Self::GET),
+            Self::HEAD_REPR => Ok(Self::HEAD),
+            Self::POST_REPR => Ok(Self::POST),
+            Self::PUT_REPR => Ok(Self::PUT),
+            Self::DELETE_REPR => Ok(Self::DELETE),
+            Self::CONNECT_REPR => Ok(Self::CONNECT),
+            Self::OPTIONS_REPR => Ok(Self::OPTIONS),
+            Self::TRACE_REPR => Ok(Self::TRACE),
+            Self::PATCH_REPR => Ok(Self::PATCH),
+            _ if Self::is_token(s) => Ok(Self::Extension(s.to_owned())),
             _ => Err(ParseMethodError(s.to_owned())),
         }
     }
@@ -0,0 +1,75 @@
+//! Mikołaj Depta 328690
+//!
+//! Timestamps in the preferred HTTP date format of RFC 9110 section 5.6.7, the one of RFC 1123,
+//! eg. `Sun, 06 Nov 1994 08:49:37 GMT`.
+
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct HttpDate(SystemTime);
+
+impl HttpDate {
+    const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+    const WEEKDAYS: [&'static str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    /// Times before the epoch are clamped to it, fractions of a second are dropped when formatting.
+    pub fn new(time: SystemTime) -> Self {
+        Self(time.max(UNIX_EPOCH))
+    }
+
+    pub fn now() -> Self {
+        Self::new(SystemTime::now())
+    }
+
+    pub fn time(&self) -> SystemTime {
+        self.0
+    }
+
+    /// Date `duration` later.
+    pub fn after(&self, duration: Duration) -> Self {
+        Self(self.0 + duration)
+    }
+
+    /// Year, month and day of the civil calendar, see http://howardhinnant.github.io/date_algorithms.html.
+    fn civil_date(days_since_epoch: u64) -> (u64, usize, u64) {
+        let days = days_since_epoch + 719_468;
+        let (era, day_of_era) = (days / 146_097, days % 146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
+        (year, month as usize, day)
+    }
+}
+
+impl Display for HttpDate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.0.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let (days, time_of_day) = (seconds / Self::SECONDS_PER_DAY, seconds % Self::SECONDS_PER_DAY);
+        let (year, month, day) = Self::civil_date(days);
+        write!(
+            f, "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+            Self::WEEKDAYS[(days % 7) as usize], day, Self::MONTHS[month - 1], year,
+            time_of_day / 3600, time_of_day / 60 % 60, time_of_day % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let date = |seconds| HttpDate::new(UNIX_EPOCH + Duration::from_secs(seconds)).to_string();
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(date(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(date(1_700_000_000), "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert_eq!(HttpDate::new(UNIX_EPOCH - Duration::from_secs(1)).to_string(), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+}
@@ -1,6 +1,6 @@
 //! Mikołaj Depta 328690
 
-use std::rc::Rc;
+use std::sync::Arc;
 use super::headers::entity_header::{ContentType, EntityHeader, EntityHeaders};
 
 pub struct Entity {
@@ -10,10 +10,10 @@ pub struct Entity {
 
 impl Entity {
     pub fn new(data: Box<[u8]>, content_type: ContentType) -> Self {
-        let headers = Rc::from([
+        let headers = Arc::from([
             EntityHeader::ContentType(content_type),
             EntityHeader::ContentLength(data.len()),
-        ]).into_boxed_slice();
+        ]);
         Self { data, headers }
     }
 
@@ -21,27 +21,64 @@ impl Entity {
         self.headers.clone()
     }
 
+    fn plain_text(message: &str) -> Self {
+        Self::new(Box::from(message.as_bytes()), ContentType::Txt)
+    }
+
     pub fn not_found() -> Self {
-        let data = String::from("Page not found").into_boxed_bytes();
-        Self {
-            data,
-            headers: Rc::from([EntityHeader::ContentType(ContentType::Txt), EntityHeader::ContentLength(data.len())]).into_boxed_slice()
-        }
+        Self::plain_text("Page not found")
+    }
+
+    pub fn bad_request() -> Self {
+        Self::plain_text("Malformed request")
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::plain_text("Authentication required")
     }
 
     pub fn morbidden() -> Self {
-        let data = String::from("Access denied").into_boxed_bytes();
-        Self { data, headers: Rc::from([EntityHeader::ContentType(ContentType::Txt), EntityHeader::ContentLength(data.len())]).into_boxed_slice() }
+        Self::plain_text("Access denied")
     }
 
     pub fn redirect() -> Self {
-        let data = String::from("Redirecting...").into_boxed_bytes();
-        Self { data, headers: Rc::from([EntityHeader::ContentType(ContentType::Txt), EntityHeader::ContentLength(data.len())]).into_boxed_slice() }
+        Self::plain_text("Redirecting...")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::plain_text("Method not allowed")
+    }
+
+    pub fn request_timeout() -> Self {
+        Self::plain_text("Request was not received in time")
+    }
+
+    pub fn misdirected_request() -> Self {
+        Self::plain_text("Host is not served by this server")
+    }
+
+    pub fn too_many_requests() -> Self {
+        Self::plain_text("Too many requests, try again later")
+    }
+
+    pub fn internal_error() -> Self {
+        Self::plain_text("Internal server error")
     }
 
     pub fn not_implemented() -> Self {
-        let data = String::from("Unrecognized http message").into_boxed_bytes();
-        Self { data, headers: Rc::from([EntityHeader::ContentType(ContentType::Txt), EntityHeader::ContentLength(data.len())]).into_boxed_slice() }
+        Self::plain_text("Unrecognized http message")
+    }
+
+    pub fn bad_gateway() -> Self {
+        Self::plain_text("Upstream server could not be reached")
+    }
+
+    pub fn gateway_timeout() -> Self {
+        Self::plain_text("Upstream server did not answer in time")
+    }
+
+    pub fn insufficient_storage() -> Self {
+        Self::plain_text("Not enough space to store the request")
     }
 }
 
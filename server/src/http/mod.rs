@@ -2,8 +2,13 @@
 //!
 //! Limited facilities for working with HTTP/1.1 protocol.
 
+pub mod base64;
+pub mod chunked;
+pub mod client;
 pub mod common;
+pub mod date;
 pub mod entity;
 pub mod headers;
+pub mod multipart;
 pub mod request;
 pub mod response;
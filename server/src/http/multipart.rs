@@ -0,0 +1,485 @@
+//! Mikołaj Depta 328690
+//!
+//! Parser of `multipart/form-data` bodies, the format browsers upload files in, see RFC 7578.
+//!
+//! Body is read in chunks, so it does not have to be in memory, eg. when it was spooled.
+//! Parts are separated by the boundary from the `Content-Type` header of the request.
+//! File parts, the ones with a `filename`, are streamed to files in the upload directory
+//! as they are read, values of the other fields are kept in memory.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::common::Body;
+use super::headers::entity_header::ContentType;
+use super::headers::{Headers, ParseHeaderError, SimpleHeaderParser};
+use super::request::Request;
+
+const FORM_DATA_TYPE: &str = "multipart/form-data";
+const CONTENT_DISPOSITION: &str = "Content-Disposition";
+const FORM_DATA_DISPOSITION: &str = "form-data";
+/// Longest boundary allowed by RFC 2046 section 5.1.1.
+const MAX_BOUNDARY_LEN: usize = 70;
+/// Values of fields other than files are kept in memory, so they are limited.
+const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum MultipartError {
+    /// Body is not `multipart/form-data` or its boundary is missing.
+    NotMultipart,
+    /// Body ended before the closing delimiter.
+    Truncated,
+    /// Delimiter is followed by something else than a line break or the closing `--`.
+    InvalidDelimiter,
+    InvalidHeaders(ParseHeaderError),
+    /// Part is not `Content-Disposition: form-data` with a field name.
+    NameMissing,
+    /// Header section of a part or value of a field is longer than the limit.
+    TooLarge(usize),
+    Io(io::Error),
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ParseHeaderError> for MultipartError {
+    fn from(err: ParseHeaderError) -> Self {
+        Self::InvalidHeaders(err)
+    }
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotMultipart => write!(f, "body is not {FORM_DATA_TYPE} with a boundary"),
+            Self::Truncated => write!(f, "body ended before the closing delimiter"),
+            Self::InvalidDelimiter => write!(f, "invalid delimiter line"),
+            Self::InvalidHeaders(err) => write!(f, "invalid part headers: {err}"),
+            Self::NameMissing => write!(f, "part without a form-data field name"),
+            Self::TooLarge(limit) => write!(f, "part exceeds the limit of {limit} bytes"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// File uploaded in a part, the file is removed when this is dropped unless it is persisted.
+#[derive(Debug)]
+pub struct UploadedFile {
+    path: PathBuf,
+    len: usize,
+    persisted: bool,
+}
+
+impl UploadedFile {
+    /// Unique file in the `directory`, the name the client gave is never used on disk.
+    fn create(directory: &Path) -> io::Result<(File, Self)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(".upload-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = directory.join(name);
+        let file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+        Ok((file, Self { path, len: 0, persisted: false }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Moves the file to the `destination`, which has to be on the same file system.
+    pub fn persist(mut self, destination: &Path) -> io::Result<()> {
+        fs::rename(&self.path, destination)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PartContent {
+    Value(Vec<u8>),
+    File(UploadedFile),
+}
+
+/// Single field of the form.
+#[derive(Debug)]
+pub struct Part {
+    name: String,
+    file_name: Option<String>,
+    headers: Headers,
+    content: PartContent,
+}
+
+impl Part {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the uploaded file as the client gave it, it must not be trusted as a path.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    /// Parts that do not specify their type are plain text, see RFC 7578 section 4.4.
+    pub fn content_type(&self) -> ContentType {
+        self.headers.content_type().unwrap_or(ContentType::Txt)
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn content(&self) -> &PartContent {
+        &self.content
+    }
+
+    pub fn into_content(self) -> PartContent {
+        self.content
+    }
+}
+
+/// Boundary of the `multipart/form-data` content type, `None` for other types.
+pub fn boundary(content_type: &ContentType) -> Option<String> {
+    let ContentType::Other(value) = content_type else { return None };
+    let media_type = value.split(';').next().unwrap_or_default().trim();
+    if !media_type.eq_ignore_ascii_case(FORM_DATA_TYPE) {
+        return None;
+    }
+    parameters(value)
+        .into_iter()
+        .find_map(|(name, value)| (name == "boundary").then_some(value))
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= MAX_BOUNDARY_LEN)
+}
+
+/// Parts of the `multipart/form-data` body of the request, file parts are written to `upload_directory`.
+pub fn parse(request: &Request, upload_directory: &Path) -> Result<Vec<Part>, MultipartError> {
+    let boundary = request.headers().content_type().as_ref().and_then(boundary).ok_or(MultipartError::NotMultipart)?;
+    let body = request.body().ok_or(MultipartError::Truncated)?;
+    parse_body(body, &boundary, upload_directory)
+}
+
+/// Parts of the `body` separated by the `boundary`, file parts are written to `upload_directory`.
+pub fn parse_body(body: &Body, boundary: &str, upload_directory: &Path) -> Result<Vec<Part>, MultipartError> {
+    Parser::new(body.reader(), boundary, upload_directory).parts()
+}
+
+/// Parameters of a header value such as `form-data; name="file"; filename="a.txt"`,
+/// names are lowercase and quoted values are unescaped.
+fn parameters(value: &str) -> Vec<(String, String)> {
+    let mut parameters = Vec::new();
+    let mut chars = value.chars().skip_while(|&c| c != ';').peekable();
+    /* every iteration starts at a semicolon */
+    while chars.next().is_some() {
+        let name = chars.by_ref().take_while(|&c| c != '=').collect::<String>();
+        while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+        let mut parameter = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => parameter.extend(chars.next()),
+                    '"' => break,
+                    c => parameter.push(c),
+                }
+            }
+            while chars.next_if(|&c| c != ';').is_some() {}
+        } else {
+            while let Some(c) = chars.next_if(|&c| c != ';') {
+                parameter.push(c);
+            }
+            parameter.truncate(parameter.trim_end().len());
+        }
+        parameters.push((name.trim().to_ascii_lowercase(), parameter));
+    }
+    parameters
+}
+
+/// Where the content of a part goes while it is read.
+enum Sink {
+    Discard,
+    Memory(Vec<u8>),
+    File(File, UploadedFile),
+}
+
+impl Sink {
+    fn write(&mut self, data: &[u8]) -> Result<(), MultipartError> {
+        match self {
+            Sink::Discard => {}
+            Sink::Memory(value) if value.len() + data.len() > MAX_VALUE_SIZE => {
+                return Err(MultipartError::TooLarge(MAX_VALUE_SIZE));
+            }
+            Sink::Memory(value) => value.extend_from_slice(data),
+            Sink::File(file, upload) => {
+                file.write_all(data)?;
+                upload.len += data.len();
+            }
+        }
+        Ok(())
+    }
+
+    fn into_content(self) -> PartContent {
+        match self {
+            Sink::File(_, upload) => PartContent::File(upload),
+            Sink::Memory(value) => PartContent::Value(value),
+            Sink::Discard => PartContent::Value(Vec::new()),
+        }
+    }
+}
+
+struct Parser<'a, R> {
+    reader: R,
+    /// Part of the body read but not consumed yet.
+    buffer: Vec<u8>,
+    /// Line break followed by `--` and the boundary.
+    delimiter: Vec<u8>,
+    upload_directory: &'a Path,
+}
+
+impl<'a, R: Read> Parser<'a, R> {
+    const CHUNK_SIZE: usize = 8192;
+    const MAX_HEADER_SIZE: usize = 8192;
+
+    fn new(reader: R, boundary: &str, upload_directory: &'a Path) -> Self {
+        /* first delimiter may start the body, the line break in front of it is implied */
+        let buffer = b"\r\n".to_vec();
+        let delimiter = format!("\r\n--{boundary}").into_bytes();
+        Self { reader, buffer, delimiter, upload_directory }
+    }
+
+    /// Reads the next chunk of the body, `false` once the body ended.
+    fn fill(&mut self) -> io::Result<bool> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + Self::CHUNK_SIZE, 0);
+        match self.reader.read(&mut self.buffer[start..]) {
+            Ok(count) => {
+                self.buffer.truncate(start + count);
+                Ok(count > 0)
+            }
+            Err(err) => {
+                self.buffer.truncate(start);
+                Err(err)
+            }
+        }
+    }
+
+    /// Makes sure at least `len` bytes are buffered.
+    fn fill_to(&mut self, len: usize) -> Result<(), MultipartError> {
+        while self.buffer.len() < len {
+            if !self.fill()? {
+                return Err(MultipartError::Truncated);
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes everything up to the next delimiter to the `sink` and consumes the delimiter.
+    fn read_until_delimiter(&mut self, sink: &mut Sink) -> Result<(), MultipartError> {
+        loop {
+            if let Some(position) = find(&self.buffer, &self.delimiter) {
+                sink.write(&self.buffer[..position])?;
+                self.buffer.drain(..position + self.delimiter.len());
+                return Ok(());
+            }
+            /* delimiter may begin in the tail and end in the next chunk */
+            let complete = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+            sink.write(&self.buffer[..complete])?;
+            self.buffer.drain(..complete);
+            if !self.fill()? {
+                return Err(MultipartError::Truncated);
+            }
+        }
+    }
+
+    /// Consumes the rest of the delimiter line, `false` if it was the closing delimiter.
+    fn read_delimiter_end(&mut self) -> Result<bool, MultipartError> {
+        self.fill_to(2)?;
+        if self.buffer.starts_with(b"--") {
+            return Ok(false);
+        }
+        /* transport padding, see RFC 2046 section 5.1.1 */
+        loop {
+            self.fill_to(2)?;
+            match self.buffer[0] {
+                b' ' | b'\t' => drop(self.buffer.drain(..1)),
+                _ if self.buffer.starts_with(b"\r\n") => break,
+                _ => return Err(MultipartError::InvalidDelimiter),
+            }
+        }
+        self.buffer.drain(..2);
+        Ok(true)
+    }
+
+    fn read_headers(&mut self) -> Result<Headers, MultipartError> {
+        self.fill_to(2)?;
+        if self.buffer.starts_with(b"\r\n") {
+            self.buffer.drain(..2);
+            return Ok(Headers::new());
+        }
+        let end = loop {
+            if let Some(position) = find(&self.buffer, Request::SECTION_SEP) {
+                break position;
+            }
+            if self.buffer.len() > Self::MAX_HEADER_SIZE {
+                return Err(MultipartError::TooLarge(Self::MAX_HEADER_SIZE));
+            }
+            if !self.fill()? {
+                return Err(MultipartError::Truncated);
+            }
+        };
+        /* every header, the last one included, ends with a line break */
+        let headers = Headers::parse::<SimpleHeaderParser>(&String::from_utf8_lossy(&self.buffer[..end + 2]))?;
+        self.buffer.drain(..end + Request::SECTION_SEP.len());
+        Ok(headers)
+    }
+
+    fn parts(mut self) -> Result<Vec<Part>, MultipartError> {
+        /* preamble */
+        self.read_until_delimiter(&mut Sink::Discard)?;
+        let mut parts = Vec::new();
+        while self.read_delimiter_end()? {
+            let headers = self.read_headers()?;
+            let (name, file_name) = disposition(&headers).ok_or(MultipartError::NameMissing)?;
+            let mut sink = match file_name {
+                Some(_) => {
+                    let (file, upload) = UploadedFile::create(self.upload_directory)?;
+                    Sink::File(file, upload)
+                }
+                None => Sink::Memory(Vec::new()),
+            };
+            self.read_until_delimiter(&mut sink)?;
+            parts.push(Part { name, file_name, headers, content: sink.into_content() });
+        }
+        /* epilogue is ignored */
+        Ok(parts)
+    }
+}
+
+/// Field name and file name from the `Content-Disposition` header of a part.
+fn disposition(headers: &Headers) -> Option<(String, Option<String>)> {
+    let value = headers.unknown(CONTENT_DISPOSITION)?;
+    let disposition_type = value.split(';').next().unwrap_or_default().trim();
+    if !disposition_type.eq_ignore_ascii_case(FORM_DATA_DISPOSITION) {
+        return None;
+    }
+    let mut name = None;
+    let mut file_name = None;
+    for (parameter, value) in parameters(value) {
+        match parameter.as_str() {
+            "name" => name = Some(value),
+            "filename" => file_name = Some(value),
+            _ => {}
+        }
+    }
+    Some((name?, file_name))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::common::Method;
+    use crate::http::entity::Entity;
+    use common::fs::TempDir;
+
+    const BOUNDARY: &str = "----formboundary7MA4YWxk";
+
+    fn body(data: &str) -> Body {
+        Body::SingleSource(Entity::new(Box::from(data.as_bytes()), ContentType::default()))
+    }
+
+    #[test]
+    fn test_boundary() {
+        let content_type = |value: &str| ContentType::Other(value.to_owned());
+        assert_eq!(boundary(&content_type("multipart/form-data; boundary=abc")).as_deref(), Some("abc"));
+        assert_eq!(boundary(&content_type("Multipart/Form-Data; charset=utf-8; boundary=\"a b;c\"")).as_deref(), Some("a b;c"));
+        assert_eq!(boundary(&content_type("multipart/mixed; boundary=abc")), None);
+        assert_eq!(boundary(&content_type("multipart/form-data")), None);
+        assert_eq!(boundary(&ContentType::Json), None);
+        assert_eq!(
+            parameters(r#"form-data; name="file"; filename="say \"hi\".txt""#),
+            [("name".to_owned(), "file".to_owned()), ("filename".to_owned(), "say \"hi\".txt".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_fields_and_files() {
+        let dir = TempDir::new("multipart").unwrap();
+        let data = format!(
+            "preamble\r\n--{BOUNDARY}\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+             two\r\nlines\r\n--{BOUNDARY}  \r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"notes.bin\"\r\n\
+             Content-Type: image/gif\r\n\r\n\
+             {}\r\n--{BOUNDARY}--\r\nepilogue",
+            "-".repeat(3 * Parser::<&[u8]>::CHUNK_SIZE)
+        );
+        let parts = parse_body(&body(&data), BOUNDARY, dir.path()).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name(), "title");
+        assert_eq!(parts[0].file_name(), None);
+        assert_eq!(parts[0].content_type(), ContentType::Txt);
+        assert!(matches!(parts[0].content(), PartContent::Value(value) if value == b"two\r\nlines"));
+
+        assert_eq!(parts[1].name(), "upload");
+        assert_eq!(parts[1].file_name(), Some("notes.bin"));
+        assert_eq!(parts[1].content_type(), ContentType::Other("image/gif".to_owned()));
+        let PartContent::File(upload) = parts.into_iter().nth(1).unwrap().into_content() else { panic!("file expected") };
+        assert_eq!(upload.len(), 3 * Parser::<&[u8]>::CHUNK_SIZE);
+        assert_eq!(fs::read(upload.path()).unwrap(), "-".repeat(upload.len()).as_bytes());
+        let destination = dir.path().join("notes.bin");
+        upload.persist(&destination).unwrap();
+        assert!(destination.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_malformed_bodies() {
+        let dir = TempDir::new("multipart-malformed").unwrap();
+        let parse = |data: &str| parse_body(&body(data), BOUNDARY, dir.path());
+        let field = "Content-Disposition: form-data; name=\"a\"\r\n\r\n";
+        let file = "Content-Disposition: form-data; name=\"a\"; filename=\"a\"\r\n\r\n";
+        assert!(parse(&format!("--{BOUNDARY}--")).unwrap().is_empty());
+        assert!(matches!(parse(&format!("--{BOUNDARY}\r\n{file}abc")), Err(MultipartError::Truncated)));
+        assert!(matches!(parse(&format!("--{BOUNDARY}x\r\n{field}\r\n--{BOUNDARY}--")), Err(MultipartError::InvalidDelimiter)));
+        assert!(matches!(parse(&format!("--{BOUNDARY}\r\nContent-Disposition: inline\r\n\r\n\r\n--{BOUNDARY}--")), Err(MultipartError::NameMissing)));
+        let large = "a".repeat(MAX_VALUE_SIZE + 1);
+        assert!(matches!(parse(&format!("--{BOUNDARY}\r\n{field}{large}\r\n--{BOUNDARY}--")), Err(MultipartError::TooLarge(_))));
+        /* files of parts that failed are not left behind */
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_request_with_multipart_content_type() {
+        let dir = TempDir::new("multipart-request").unwrap();
+        let data = format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--{BOUNDARY}--\r\n");
+        let headers = format!("Content-Type: multipart/form-data; boundary={BOUNDARY}\r\n");
+        let parts = parse(&Request::fixture(Method::POST, "/upload", &headers, Some(data.as_bytes())), dir.path()).unwrap();
+        assert!(matches!(parts[0].content(), PartContent::Value(value) if value == b"1"));
+
+        let request = Request::fixture(Method::POST, "/upload", "", Some(data.as_bytes()));
+        assert!(matches!(parse(&request, dir.path()), Err(MultipartError::NotMultipart)));
+    }
+}
@@ -1,12 +1,30 @@
 //! Mikołaj Depta 328690
 
-#[allow(dead_code, unused)]
+#![allow(dead_code, unused)]
 
 use super::common::{Body, Version};
-use super::headers::{general_header::GeneralHeader, response_header::ResponseHeader};
 use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::sync::Arc;
 use crate::http::common;
-use crate::http::headers::Headers;
+use crate::http::date::HttpDate;
+use crate::http::entity::Entity;
+use crate::filters::ChunkFilter;
+use crate::sse::EventStream;
+use crate::http::headers::entity_header::{ContentType, EntityHeader};
+use crate::http::headers::general_header::{CacheDirective, ConnectionType, GeneralHeader};
+use crate::http::headers::response_header::{ResponseHeader, TransferCoding};
+use crate::http::headers::{Header, Headers};
+use crate::http::request::Request;
+
+/// Body sent after the serialized head, when it is not a part of it.
+pub enum BodyPart {
+    /// `len` bytes of the file starting at the offset.
+    File(Arc<File>, u64, usize),
+    /// Body passed through the filter as it is sent, in chunked transfer coding if `chunked`
+    /// or delimited by closing the connection otherwise.
+    Filtered { body: Body, filter: Box<dyn ChunkFilter>, chunked: bool },
+}
 
 pub struct StatusLine {
     version: Version,
@@ -17,6 +35,10 @@ impl StatusLine {
     pub fn new(version: Version, status_code: StatusCode) -> Self {
         Self { version, status_code }
     }
+
+    pub fn status_code(&self) -> &StatusCode {
+        &self.status_code
+    }
 }
 
 impl Display for StatusLine {
@@ -28,40 +50,169 @@ impl Display for StatusLine {
 #[non_exhaustive]
 pub enum StatusCode {
     Ok,
+    Created,
+    NoContent,
+    /// Only the requested range of the file is sent, see `range`.
+    PartialContent,
     MovedPermanently,
+    Found,
+    TemporaryRedirect,
+    BadRequest,
+    Unauthorized,
     Forbidden,
     NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    /// Request for a host the server is not configured to serve.
+    MisdirectedRequest,
+    RangeNotSatisfiable,
+    TooManyRequests,
+    InternalServerError,
     NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    InsufficientStorage,
 }
 
 impl StatusCode {
     const OK_CODE: usize = 200;
+    const CREATED_CODE: usize = 201;
+    const NO_CONTENT_CODE: usize = 204;
+    const PARTIAL_CONTENT_CODE: usize = 206;
     const MOVED_PERMANENTLY_CODE: usize = 301;
+    const FOUND_CODE: usize = 302;
+    const TEMPORARY_REDIRECT_CODE: usize = 307;
+    const BAD_REQUEST_CODE: usize = 400;
+    const UNAUTHORIZED_CODE: usize = 401;
     const FORBIDDEN_CODE: usize = 403;
     const NOT_FOUND_CODE: usize = 404;
+    const METHOD_NOT_ALLOWED_CODE: usize = 405;
+    const REQUEST_TIMEOUT_CODE: usize = 408;
+    const RANGE_NOT_SATISFIABLE_CODE: usize = 416;
+    const MISDIRECTED_REQUEST_CODE: usize = 421;
+    const TOO_MANY_REQUESTS_CODE: usize = 429;
+    const INTERNAL_SERVER_ERROR_CODE: usize = 500;
     const NOT_IMPLEMENTED_CODE: usize = 501;
+    const BAD_GATEWAY_CODE: usize = 502;
+    const SERVICE_UNAVAILABLE_CODE: usize = 503;
+    const GATEWAY_TIMEOUT_CODE: usize = 504;
+    const INSUFFICIENT_STORAGE_CODE: usize = 507;
 
     const OK_MESSAGE: &'static str = "OK";
+    const CREATED_MESSAGE: &'static str = "Created";
+    const NO_CONTENT_MESSAGE: &'static str = "No Content";
+    const PARTIAL_CONTENT_MESSAGE: &'static str = "Partial Content";
     const MOVED_PERMANENTLY_MESSAGE: &'static str = "Moved Permanently";
+    const FOUND_MESSAGE: &'static str = "Found";
+    const TEMPORARY_REDIRECT_MESSAGE: &'static str = "Temporary Redirect";
+    const BAD_REQUEST_MESSAGE: &'static str = "Bad Request";
+    const UNAUTHORIZED_MESSAGE: &'static str = "Unauthorized";
     const FORBIDDEN_MESSAGE: &'static str = "Forbidden";
     const NOT_FOUND_MESSAGE: &'static str = "Not Found";
+    const METHOD_NOT_ALLOWED_MESSAGE: &'static str = "Method Not Allowed";
+    const REQUEST_TIMEOUT_MESSAGE: &'static str = "Request Timeout";
+    const RANGE_NOT_SATISFIABLE_MESSAGE: &'static str = "Range Not Satisfiable";
+    const MISDIRECTED_REQUEST_MESSAGE: &'static str = "Misdirected Request";
+    const TOO_MANY_REQUESTS_MESSAGE: &'static str = "Too Many Requests";
+    const INTERNAL_SERVER_ERROR_MESSAGE: &'static str = "Internal Server Error";
     const NOT_IMPLEMENTED_MESSAGE: &'static str = "Not Implemented";
-}
+    const BAD_GATEWAY_MESSAGE: &'static str = "Bad Gateway";
+    const SERVICE_UNAVAILABLE_MESSAGE: &'static str = "Service Unavailable";
+    const GATEWAY_TIMEOUT_MESSAGE: &'static str = "Gateway Timeout";
+    const INSUFFICIENT_STORAGE_MESSAGE: &'static str = "Insufficient Storage";
 
-impl Display for StatusCode {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let (code, message) = match &self {
+    /// Numeric code and reason phrase.
+    fn parts(&self) -> (usize, &'static str) {
+        match &self {
             StatusCode::Ok => (Self::OK_CODE, Self::OK_MESSAGE),
+            StatusCode::Created => (Self::CREATED_CODE, Self::CREATED_MESSAGE),
+            StatusCode::NoContent => (Self::NO_CONTENT_CODE, Self::NO_CONTENT_MESSAGE),
+            StatusCode::PartialContent => (Self::PARTIAL_CONTENT_CODE, Self::PARTIAL_CONTENT_MESSAGE),
             StatusCode::MovedPermanently => (
                 Self::MOVED_PERMANENTLY_CODE,
                 Self::MOVED_PERMANENTLY_MESSAGE,
             ),
+            StatusCode::Found => (Self::FOUND_CODE, Self::FOUND_MESSAGE),
+            StatusCode::TemporaryRedirect => {
+                (Self::TEMPORARY_REDIRECT_CODE, Self::TEMPORARY_REDIRECT_MESSAGE)
+            }
+            StatusCode::BadRequest => (Self::BAD_REQUEST_CODE, Self::BAD_REQUEST_MESSAGE),
+            StatusCode::Unauthorized => (Self::UNAUTHORIZED_CODE, Self::UNAUTHORIZED_MESSAGE),
             StatusCode::Forbidden => (Self::FORBIDDEN_CODE, Self::FORBIDDEN_MESSAGE),
             StatusCode::NotFound => (Self::NOT_FOUND_CODE, Self::NOT_FOUND_MESSAGE),
+            StatusCode::MethodNotAllowed => {
+                (Self::METHOD_NOT_ALLOWED_CODE, Self::METHOD_NOT_ALLOWED_MESSAGE)
+            }
+            StatusCode::RequestTimeout => {
+                (Self::REQUEST_TIMEOUT_CODE, Self::REQUEST_TIMEOUT_MESSAGE)
+            }
+            StatusCode::RangeNotSatisfiable => {
+                (Self::RANGE_NOT_SATISFIABLE_CODE, Self::RANGE_NOT_SATISFIABLE_MESSAGE)
+            }
+            StatusCode::MisdirectedRequest => {
+                (Self::MISDIRECTED_REQUEST_CODE, Self::MISDIRECTED_REQUEST_MESSAGE)
+            }
+            StatusCode::TooManyRequests => {
+                (Self::TOO_MANY_REQUESTS_CODE, Self::TOO_MANY_REQUESTS_MESSAGE)
+            }
+            StatusCode::InternalServerError => {
+                (Self::INTERNAL_SERVER_ERROR_CODE, Self::INTERNAL_SERVER_ERROR_MESSAGE)
+            }
             StatusCode::NotImplemented => {
                 (Self::NOT_IMPLEMENTED_CODE, Self::NOT_IMPLEMENTED_MESSAGE)
             }
+            StatusCode::BadGateway => (Self::BAD_GATEWAY_CODE, Self::BAD_GATEWAY_MESSAGE),
+            StatusCode::ServiceUnavailable => {
+                (Self::SERVICE_UNAVAILABLE_CODE, Self::SERVICE_UNAVAILABLE_MESSAGE)
+            }
+            StatusCode::GatewayTimeout => {
+                (Self::GATEWAY_TIMEOUT_CODE, Self::GATEWAY_TIMEOUT_MESSAGE)
+            }
+            StatusCode::InsufficientStorage => {
+                (Self::INSUFFICIENT_STORAGE_CODE, Self::INSUFFICIENT_STORAGE_MESSAGE)
+            }
+        }
+    }
+
+    pub fn code(&self) -> usize {
+        self.parts().0
+    }
+
+    /// Status with the numeric `code`, if it is one the server knows.
+    pub fn from_code(code: usize) -> Option<Self> {
+        let status_code = match code {
+            Self::OK_CODE => StatusCode::Ok,
+            Self::CREATED_CODE => StatusCode::Created,
+            Self::NO_CONTENT_CODE => StatusCode::NoContent,
+            Self::PARTIAL_CONTENT_CODE => StatusCode::PartialContent,
+            Self::MOVED_PERMANENTLY_CODE => StatusCode::MovedPermanently,
+            Self::FOUND_CODE => StatusCode::Found,
+            Self::TEMPORARY_REDIRECT_CODE => StatusCode::TemporaryRedirect,
+            Self::BAD_REQUEST_CODE => StatusCode::BadRequest,
+            Self::UNAUTHORIZED_CODE => StatusCode::Unauthorized,
+            Self::FORBIDDEN_CODE => StatusCode::Forbidden,
+            Self::NOT_FOUND_CODE => StatusCode::NotFound,
+            Self::METHOD_NOT_ALLOWED_CODE => StatusCode::MethodNotAllowed,
+            Self::REQUEST_TIMEOUT_CODE => StatusCode::RequestTimeout,
+            Self::RANGE_NOT_SATISFIABLE_CODE => StatusCode::RangeNotSatisfiable,
+            Self::MISDIRECTED_REQUEST_CODE => StatusCode::MisdirectedRequest,
+            Self::TOO_MANY_REQUESTS_CODE => StatusCode::TooManyRequests,
+            Self::INTERNAL_SERVER_ERROR_CODE => StatusCode::InternalServerError,
+            Self::NOT_IMPLEMENTED_CODE => StatusCode::NotImplemented,
+            Self::BAD_GATEWAY_CODE => StatusCode::BadGateway,
+            Self::SERVICE_UNAVAILABLE_CODE => StatusCode::ServiceUnavailable,
+            Self::GATEWAY_TIMEOUT_CODE => StatusCode::GatewayTimeout,
+            Self::INSUFFICIENT_STORAGE_CODE => StatusCode::InsufficientStorage,
+            _ => return None,
         };
+        Some(status_code)
+    }
+}
+
+impl Display for StatusCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (code, message) = self.parts();
         write!(f, "{} {}", code, message)
     }
 }
@@ -70,29 +221,123 @@ pub struct Response {
     status_line: StatusLine,
     headers: Headers,
     body: Option<Body>,
+    filter: Option<Box<dyn ChunkFilter>>,
+    /// Events sent after the head for as long as the connection lasts, see `sse`.
+    events: Option<Box<EventStream>>,
     buffer: Vec<u8>,
 }
 
 impl Response {
     const SECTION_SEP: &'static str = "\r\n\r\n";
+    const DATE_HEADER: &'static str = "Date";
+
+    /// HTTP/1.1 response with `status_code`, see `ResponseBuilder`.
+    pub fn builder(status_code: StatusCode) -> ResponseBuilder {
+        ResponseBuilder::new(status_code)
+    }
 
+    /// Responses carry the time they were generated at, `Date` is added unless `headers` have one.
     pub fn new(
         status_line: StatusLine,
-        headers: Headers,
+        mut headers: Headers,
         body: Option<Body>,
     ) -> Self {
+        if !headers.contains(Self::DATE_HEADER) {
+            headers.append(GeneralHeader::Date(HttpDate::now()));
+        }
         let mut instance = Self {
             status_line,
             headers,
             body,
+            filter: None,
+            events: None,
             buffer: Vec::new(),
         };
-        instance.buffer.extend_from_slice(instance.to_string().as_bytes());
-        if let Some(body) = &instance.body {
-            instance.buffer.extend_from_slice(body.as_ref())
-        }
+        instance.serialize();
         instance
     }
+
+    fn serialize(&mut self) {
+        self.buffer = self.to_string().into_bytes();
+        if let (Some(Body::SingleSource(entity)), None) = (&self.body, &self.filter) {
+            self.buffer.extend_from_slice(entity.as_ref())
+        }
+    }
+
+    /// Passes the body through the `filter` as it is sent. Length of the filtered body is not known
+    /// up front, so `Content-Length` is dropped - HTTP/1.1 bodies are sent in chunked transfer
+    /// coding and HTTP/1.0 ones end when the connection is closed.
+    pub fn with_body_filter(mut self, filter: Box<dyn ChunkFilter>) -> Self {
+        if self.body.is_none() {
+            return self;
+        }
+        self.headers.remove("Content-Length");
+        if self.is_chunked() {
+            self.headers.insert(ResponseHeader::TransferEncoding(TransferCoding::Chunked));
+        } else {
+            self.headers.insert(GeneralHeader::Connection(ConnectionType::Close));
+        }
+        self.filter = Some(filter);
+        self.serialize();
+        self
+    }
+
+    /// Adds `headers` to the response that was already built, after the present ones.
+    pub fn with_headers<H: Into<Header>>(mut self, headers: impl IntoIterator<Item=H>) -> Self {
+        let len = self.headers.len();
+        self.headers.extend(headers);
+        if self.headers.len() != len {
+            self.serialize();
+        }
+        self
+    }
+
+    /// Stream of events to send once the head is sent, see `sse`.
+    pub fn take_events(&mut self) -> Option<EventStream> {
+        self.events.take().map(|events| *events)
+    }
+
+    /// HTTP/1.0 clients do not understand chunked transfer coding.
+    fn is_chunked(&self) -> bool {
+        !matches!(self.status_line.version, Version::V1)
+    }
+
+    /// Length of the whole message including the body, before the body filter is applied.
+    pub fn len(&self) -> usize {
+        match (&self.body, &self.filter) {
+            (Some(Body::File(_, _, len)), _) => self.buffer.len() + len,
+            (Some(body), Some(_)) => self.buffer.len() + body.len(),
+            _ => self.buffer.len(),
+        }
+    }
+
+    /// Serialized message and the body that has to be sent after it, if it is not a part of the message.
+    pub fn into_parts(self) -> (Box<[u8]>, Option<BodyPart>) {
+        let chunked = self.is_chunked();
+        let body = match (self.body, self.filter) {
+            (Some(body), Some(filter)) => Some(BodyPart::Filtered { body, filter, chunked }),
+            (Some(Body::File(file, offset, len)), None) => Some(BodyPart::File(file, offset, len)),
+            _ => None,
+        };
+        (self.buffer.into_boxed_slice(), body)
+    }
+
+    pub fn status_line(&self) -> &StatusLine {
+        &self.status_line
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn body(&self) -> Option<&Body> {
+        self.body.as_ref()
+    }
+
+    /// Connection has to be closed once the response is sent.
+    pub fn closes_connection(&self) -> bool {
+        self.headers.connection() == Some(ConnectionType::Close)
+    }
 }
 
 impl Display for Response {
@@ -106,3 +351,167 @@ impl AsRef<[u8]> for Response {
         self.buffer.as_ref()
     }
 }
+
+/// Assembles a response from its status, typed headers and body.
+///
+/// Headers are serialized in the order they were added. `Content-Type` and `Content-Length`
+/// are added together with the body, so they always describe what is actually sent.
+pub struct ResponseBuilder {
+    version: Version,
+    status_code: StatusCode,
+    headers: Headers,
+    body: Option<Body>,
+    events: Option<Box<EventStream>>,
+}
+
+impl ResponseBuilder {
+    const EVENT_STREAM_TYPE: &'static str = "text/event-stream";
+
+    pub fn new(status_code: StatusCode) -> Self {
+        Self { version: Version::V1_1, status_code, headers: Headers::new(), body: None, events: None }
+    }
+
+    /// Replaces the status the builder was created with.
+    pub fn with_status_code(mut self, status_code: StatusCode) -> Self {
+        self.status_code = status_code;
+        self
+    }
+
+    pub fn with_version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Answers with the version of the `request` and echoes its general headers back.
+    pub fn in_reply_to(self, request: &Request) -> Self {
+        /* headers named in `Connection` concern the request's connection, they are not echoed */
+        let general_headers = request.headers().general_headers().filter_map(|header| match header {
+            GeneralHeader::ConnectionOptions(connection_type, _) => connection_type.clone().map(GeneralHeader::Connection),
+            header => Some(header.clone()),
+        });
+        self.with_version(*request.start_line().version())
+            .with_headers(general_headers)
+    }
+
+    pub fn with_header(mut self, header: impl Into<Header>) -> Self {
+        self.headers.append(header);
+        self
+    }
+
+    pub fn with_headers<H: Into<Header>>(mut self, headers: impl IntoIterator<Item=H>) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// In-memory body, replaces the one set before.
+    pub fn with_entity(mut self, entity: Entity) -> Self {
+        self.headers.extend(entity.headers().iter().cloned());
+        self.body = Some(Body::SingleSource(entity));
+        self
+    }
+
+    /// First `len` bytes of the `file` sent as the body, replaces the one set before.
+    pub fn with_file(self, file: Arc<File>, len: usize, content_type: ContentType) -> Self {
+        self.with_file_part(file, 0, len, content_type)
+    }
+
+    /// `len` bytes of the `file` starting at `offset` sent as the body, replaces the one set before.
+    pub fn with_file_part(mut self, file: Arc<File>, offset: u64, len: usize, content_type: ContentType) -> Self {
+        self.headers.append(EntityHeader::ContentType(content_type));
+        self.headers.append(EntityHeader::ContentLength(len));
+        self.body = Some(Body::File(file, offset, len));
+        self
+    }
+
+    /// Body of unknown length made of the events of the `stream`, it ends when the connection is closed.
+    pub fn with_event_stream(mut self, stream: EventStream) -> Self {
+        self.headers.append(EntityHeader::ContentType(ContentType::Other(Self::EVENT_STREAM_TYPE.to_owned())));
+        self.headers.append(GeneralHeader::CacheControl(vec![CacheDirective::NoCache]));
+        self.headers.insert(GeneralHeader::Connection(ConnectionType::Close));
+        self.events = Some(Box::new(stream));
+        self
+    }
+
+    pub fn build(self) -> Response {
+        let mut response = Response::new(StatusLine::new(self.version, self.status_code), self.headers, self.body);
+        response.events = self.events;
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::{BodyFilter, Substitution};
+    use crate::http::common::Method;
+    use crate::http::request::StartLine;
+    use std::path::Path;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_builder_serializes_headers_in_order() {
+        let start_line = StartLine::new(Method::GET, Path::new("/"), Version::V1);
+        let headers = Headers::new().with_header(GeneralHeader::Connection(ConnectionType::Close));
+        let request = Request::new(start_line, headers, None);
+        let response = Response::builder(StatusCode::NotFound)
+            .in_reply_to(&request)
+            .with_header(ResponseHeader::RetryAfter(5))
+            .with_entity(Entity::new(Box::from(&b"missing"[..]), ContentType::Txt))
+            .build();
+        let date = response.headers().get("Date").unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(response.as_ref()),
+            format!(
+                "HTTP/1 404 Not Found\r\nConnection: close\r\nRetry-After: 5\r\n\
+                 Content-Type: text/plain; charset=utf-8\r\nContent-Length: 7\r\n{date}\r\n\r\nmissing"
+            )
+        );
+        assert_eq!(response.len(), response.as_ref().len());
+    }
+
+    #[test]
+    fn test_headers_named_in_connection_are_not_echoed() {
+        let start_line = StartLine::new(Method::GET, Path::new("/"), Version::V1_1);
+        let headers = Headers::new()
+            .with_header(GeneralHeader::ConnectionOptions(Some(ConnectionType::Close), vec![String::from("X-Secret")]))
+            .with_header(GeneralHeader::ConnectionOptions(None, vec![String::from("Upgrade")]));
+        let request = Request::new(start_line, headers, None);
+        let response = Response::builder(StatusCode::NoContent).in_reply_to(&request).build();
+        assert_eq!(response.headers().get_all("Connection").map(ToString::to_string).collect::<Vec<_>>(), ["Connection: close"]);
+    }
+
+    #[test]
+    fn test_builder_with_file_body() {
+        let file = Arc::new(File::open("Cargo.toml").unwrap());
+        let response = Response::builder(StatusCode::Ok).with_file(file, 100, ContentType::Txt).build();
+        let head = String::from_utf8_lossy(response.as_ref()).into_owned();
+        let date = response.headers().get("Date").unwrap();
+        assert_eq!(head, format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 100\r\n{date}\r\n\r\n"));
+        assert_eq!(response.len(), head.len() + 100);
+        assert!(matches!(response.into_parts().1, Some(BodyPart::File(_, 0, 100))));
+    }
+
+    #[test]
+    fn test_filtered_body_drops_content_length() {
+        let entity = || Entity::new(Box::from(&b"<body>"[..]), ContentType::Html);
+        let filter = || Substitution::banner("hello").start();
+        let date = GeneralHeader::Date(HttpDate::new(UNIX_EPOCH));
+        let response = Response::builder(StatusCode::Ok).with_header(date).with_entity(entity()).build().with_body_filter(filter());
+        assert_eq!(
+            String::from_utf8_lossy(response.as_ref()),
+            "HTTP/1.1 200 OK\r\nDate: Thu, 01 Jan 1970 00:00:00 GMT\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Transfer-Encoding: chunked\r\n\r\n"
+        );
+        assert_eq!(response.headers().get_all("Date").count(), 1);
+        assert!(!response.closes_connection());
+        assert!(matches!(response.into_parts().1, Some(BodyPart::Filtered { chunked: true, .. })));
+        let response = Response::builder(StatusCode::Ok)
+            .with_version(Version::V1)
+            .with_entity(entity())
+            .build()
+            .with_body_filter(filter());
+        assert!(response.closes_connection());
+        assert!(!response.headers().contains("Content-Length"));
+        assert!(matches!(response.into_parts().1, Some(BodyPart::Filtered { chunked: false, .. })));
+    }
+}
@@ -0,0 +1,300 @@
+//! Mikołaj Depta 328690
+//!
+//! Integration tests driving a running server over TCP.
+//!
+//! Every test starts its own server on an ephemeral port of the loopback interface, serving
+//! a fresh document root with a pool of workers, and talks to it with raw `TcpStream` clients,
+//! byte by byte as a browser or a misbehaving client would. Servers keep running until the test
+//! binary exits, their document roots are removed when the tests end.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::fs::TempDir;
+use crate::http::client;
+use crate::http::common::{Body, Method};
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::registry::Registry;
+use crate::metrics::Metrics;
+use crate::server::{HttpDownloader, HttpSender, HttpServer};
+
+const WORKERS: usize = 2;
+/// Known request smuggling payloads, each has to be rejected with 400 in strict framing mode.
+const SMUGGLING_CORPUS: [&str; 12] = [
+    /* CL.CL: repeated length, front end and back end may pick different ones */
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello",
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nContent-Length: 44\r\n\r\n",
+    /* CL.TE and TE.CL */
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nG",
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n8\r\nSMUGGLED\r\n0\r\n\r\n",
+    /* TE.TE: coding hidden from one of the parsers */
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: xchunked\r\n\r\n0\r\n\r\n",
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n\r\n0\r\n\r\n",
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding : chunked\r\n\r\n0\r\n\r\n",
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding:\r\n chunked\r\n\r\n0\r\n\r\n",
+    /* line endings some parsers end a line at and others do not */
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nX-Padding: a\nContent-Length: 5\r\n\r\nhello",
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nX-Padding: a\rContent-Length: 5\r\n\r\nhello",
+    "GET /index.html HTTP/1.1\nHost: localhost\n\n",
+    /* length parsers disagree on */
+    "POST /index.html HTTP/1.1\r\nHost: localhost\r\nContent-Length: +5\r\n\r\nhello",
+];
+/// Clients give up on a silent server instead of hanging the test run.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Server = HttpServer<HttpDownloader<TcpStream>, HttpSender<TcpStream>>;
+
+struct TestServer {
+    address: SocketAddr,
+    metrics: Arc<Metrics>,
+    _root: TempDir,
+}
+
+impl TestServer {
+    /// Server for `localhost` with the `files` in its document root.
+    fn start(files: &[(&str, &[u8])]) -> Self {
+        Self::start_with(files, |server| server)
+    }
+
+    /// Server for `localhost` with the `files` in its document root, set up by `configure`
+    /// before its workers start.
+    fn start_with(files: &[(&str, &[u8])], configure: impl FnOnce(Server) -> Server) -> Self {
+        let root = TempDir::new("server-integration").unwrap();
+        for (path, content) in files {
+            root.create_file(Path::new("localhost").join(path), content).unwrap();
+        }
+        let catalog: Arc<Path> = Arc::from(root.path());
+        let mut server = configure(Server::new("127.0.0.1:0".parse().unwrap(), catalog)).with_workers(WORKERS);
+        let address = server.address();
+        let metrics = server.metrics().clone();
+        thread::spawn(move || server.start());
+        Self { address, metrics, _root: root }
+    }
+
+    fn connect(&self) -> Client {
+        let stream = TcpStream::connect(self.address).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        Client { stream, buffered: Vec::new() }
+    }
+}
+
+/// Response as received, its head is expected to be text.
+struct Received {
+    head: String,
+    body: Vec<u8>,
+}
+
+impl Received {
+    fn status(&self) -> &str {
+        self.head.split(' ').nth(1).unwrap_or_default()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.head
+            .split("\r\n")
+            .filter_map(|line| line.split_once(": "))
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    /// Bytes read past the end of the previous response.
+    buffered: Vec<u8>,
+}
+
+impl Client {
+    fn send(&mut self, data: &[u8]) {
+        self.stream.write_all(data).unwrap();
+    }
+
+    fn get(&mut self, target: &str) -> Received {
+        self.send(format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes());
+        self.receive()
+    }
+
+    /// Reads a single response delimited by `Content-Length`.
+    fn receive(&mut self) -> Received {
+        let head_end = loop {
+            if let Some(index) = self.buffered.windows(4).position(|window| window == b"\r\n\r\n") {
+                break index;
+            }
+            assert!(self.fill() > 0, "connection closed before the end of the head: {:?}", String::from_utf8_lossy(&self.buffered));
+        };
+        let head = String::from_utf8(self.buffered[..head_end].to_vec()).unwrap();
+        self.buffered.drain(..head_end + 4);
+        let mut received = Received { head, body: Vec::new() };
+        let length = received.header("Content-Length").map_or(0, |length| length.parse().unwrap());
+        while self.buffered.len() < length {
+            assert!(self.fill() > 0, "connection closed before the end of the body");
+        }
+        received.body = self.buffered.drain(..length).collect();
+        received
+    }
+
+    fn fill(&mut self) -> usize {
+        let mut chunk = [0; 4096];
+        let read = self.stream.read(&mut chunk).unwrap();
+        self.buffered.extend_from_slice(&chunk[..read]);
+        read
+    }
+
+    /// Server closed the connection and sent nothing more.
+    fn is_closed(&mut self) -> bool {
+        self.buffered.is_empty() && self.fill() == 0
+    }
+}
+
+#[test]
+fn test_files_are_served() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>")]);
+    let response = server.connect().get("/index.html");
+    assert_eq!(response.status(), "200", "{}", response.head);
+    assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(response.body, b"<p>hello</p>");
+}
+
+#[test]
+fn test_missing_files_are_not_found() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>")]);
+    assert_eq!(server.connect().get("/missing.html").status(), "404");
+}
+
+#[test]
+fn test_targets_outside_of_the_root_are_forbidden() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>")]);
+    let mut client = server.connect();
+    assert_eq!(client.get("/%2e%2e/%2e%2e/etc/passwd").status(), "403");
+    /* rejected target does not end the connection */
+    assert_eq!(client.get("/index.html").status(), "200");
+}
+
+#[test]
+fn test_connections_are_kept_alive() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>"), ("style.css", b"p {}")]);
+    let mut client = server.connect();
+    for target in ["/index.html", "/missing.html", "/style.css"] {
+        let response = client.get(target);
+        assert_ne!(response.header("Connection"), Some("close"), "{}", response.head);
+    }
+    assert_eq!(client.get("/style.css").body, b"p {}");
+    assert_eq!(server.metrics.accepted_connections(), 1);
+}
+
+#[test]
+fn test_request_written_in_pieces() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>")]);
+    let mut client = server.connect();
+    for piece in ["GE", "T /index.html HTTP/1.1\r", "\nHost: loc", "alhost\r\n", "\r\n"] {
+        client.send(piece.as_bytes());
+        thread::sleep(Duration::from_millis(20));
+    }
+    let response = client.receive();
+    assert_eq!(response.status(), "200", "{}", response.head);
+    assert_eq!(response.body, b"<p>hello</p>");
+}
+
+#[test]
+fn test_oversized_requests_are_rejected() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>")]);
+    let mut client = server.connect();
+    let padding = "a".repeat(Request::MAX_GET_SIZE);
+    client.send(format!("GET /index.html HTTP/1.1\r\nHost: localhost\r\nX-Padding: {padding}\r\n\r\n").as_bytes());
+    let response = client.receive();
+    assert_eq!(response.status(), "400", "{}", response.head);
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert!(client.is_closed());
+}
+
+#[test]
+fn test_slow_client_does_not_stall_other_workers() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>")]);
+    /* connections are dealt to the workers in turns, the slow one keeps the first busy */
+    let mut slow = server.connect();
+    slow.send(b"GET /index.html HTTP/1.1\r\n");
+    let mut client = server.connect();
+    assert_eq!(client.get("/index.html").body, b"<p>hello</p>");
+    slow.send(b"Host: localhost\r\n\r\n");
+    assert_eq!(slow.receive().status(), "200");
+}
+
+#[test]
+fn test_byte_ranges_are_served() {
+    let large = (0..100 * 1024).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>"), ("large.bin", &large)]);
+    let mut client = server.connect();
+    let mut get = |target: &str, headers: &str| {
+        client.send(format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n").as_bytes());
+        client.receive()
+    };
+
+    let response = get("/index.html", "Range: bytes=3-7\r\n");
+    assert_eq!(response.status(), "206", "{}", response.head);
+    assert_eq!(response.header("Content-Range"), Some("bytes 3-7/12"));
+    assert_eq!(response.body, b"hello");
+    /* large files are sent from the descriptor, starting at the offset */
+    let response = get("/large.bin", "Range: bytes=-20000\r\n");
+    assert_eq!(response.header("Content-Range"), Some("bytes 82400-102399/102400"));
+    assert_eq!(response.body, &large[82400..]);
+    let response = get("/index.html", "Range: bytes=12-\r\n");
+    assert_eq!(response.status(), "416", "{}", response.head);
+    assert_eq!(response.header("Content-Range"), Some("bytes */12"));
+}
+
+#[test]
+fn test_stale_if_range_falls_back_to_the_whole_file() {
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>")]);
+    let mut client = server.connect();
+    let tag = client.get("/index.html").header("ETag").unwrap().to_owned();
+    for (validator, status, body) in [(tag.as_str(), "206", &b"<p>"[..]), ("\"stale\"", "200", b"<p>hello</p>")] {
+        client.send(format!("GET /index.html HTTP/1.1\r\nHost: localhost\r\nRange: bytes=0-2\r\nIf-Range: {validator}\r\n\r\n").as_bytes());
+        let response = client.receive();
+        assert_eq!(response.status(), status, "{}", response.head);
+        assert_eq!(response.body, body);
+    }
+}
+
+#[test]
+fn test_http_client_receives_whole_responses() {
+    let large = vec![b'x'; 100 * 1024];
+    let server = TestServer::start(&[("index.html", b"<p>hello</p>"), ("large.bin", &large)]);
+    let request = |method: Method, target: &str| Request::fixture(method, target, "Host: localhost\r\n", None);
+    let body = |response: &Response| match response.body() {
+        Some(Body::SingleSource(entity)) => entity.as_ref().to_vec(),
+        _ => Vec::new(),
+    };
+
+    let client = client::Client::new().with_timeout(READ_TIMEOUT);
+    let response = client.send(server.address, &request(Method::GET, "/index.html")).unwrap();
+    assert_eq!(response.status_line().status_code().code(), 200);
+    assert_eq!(body(&response), b"<p>hello</p>");
+    let response = client.send(server.address, &request(Method::GET, "/missing.html")).unwrap();
+    assert_eq!(response.status_line().status_code().code(), 404);
+    /* the host is not served, yet the response is complete */
+    let response = client::Client::get(server.address, "/index.html").unwrap();
+    assert_eq!(response.status_line().status_code().code(), 421);
+
+    let mut registry = Registry::new().unwrap();
+    let response = client.send_with(&mut registry, server.address, &request(Method::GET, "/large.bin")).unwrap();
+    assert_eq!(body(&response), large);
+}
+
+#[test]
+fn test_smuggling_payloads_are_rejected_in_strict_framing_mode() {
+    let server = TestServer::start_with(&[("index.html", b"<p>hello</p>")], |server| server.with_strict_framing(true));
+    for payload in SMUGGLING_CORPUS {
+        let mut client = server.connect();
+        client.send(payload.as_bytes());
+        let response = client.receive();
+        assert_eq!(response.status(), "400", "{payload:?}: {}", response.head);
+        assert_eq!(response.header("Connection"), Some("close"), "{payload:?}");
+        assert!(client.is_closed(), "{payload:?}");
+    }
+    assert_eq!(server.connect().get("/index.html").status(), "200");
+}
@@ -0,0 +1,123 @@
+//! Mikołaj Depta 328690
+//!
+//! Listening sockets of the server, and the ones it connects to other servers with.
+//!
+//! Server may listen on several addresses at once, eg. `0.0.0.0:8080` and `[::]:8080`. IPv6
+//! sockets are bound with `IPV6_V6ONLY`, otherwise the wildcard one would also claim the IPv4
+//! port and binding both would fail.
+
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use crate::registry::syscall;
+
+const BACKLOG: libc::c_int = 128;
+
+/// Binds a listening socket to `address`, with `SO_REUSEADDR` set like the standard library does.
+pub fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let domain = match address {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = syscall!(socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0))?;
+    // safety: descriptor was just created and is owned by nothing else.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    if address.is_ipv6() {
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?;
+    }
+    let (storage, len) = socket_address(&address);
+    syscall!(bind(fd, &storage as *const libc::sockaddr_storage as *const libc::sockaddr, len))?;
+    syscall!(listen(fd, BACKLOG))?;
+    Ok(listener)
+}
+
+/// Non-blocking socket connecting to `address`. Connection is usually still being established
+/// when it is returned, the socket becomes writable once it is, see `connect(2)`.
+pub fn connect(address: SocketAddr) -> io::Result<TcpStream> {
+    let domain = match address {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = syscall!(socket(domain, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0))?;
+    // safety: descriptor was just created and is owned by nothing else.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let (storage, len) = socket_address(&address);
+    match syscall!(connect(fd, &storage as *const libc::sockaddr_storage as *const libc::sockaddr, len)) {
+        Err(err) if err.raw_os_error() != Some(libc::EINPROGRESS) => Err(err),
+        _ => Ok(stream),
+    }
+}
+
+fn set_option(fd: RawFd, level: libc::c_int, option: libc::c_int) -> io::Result<()> {
+    let enabled: libc::c_int = 1;
+    syscall!(setsockopt(
+        fd,
+        level,
+        option,
+        &enabled as *const libc::c_int as *const libc::c_void,
+        mem::size_of::<libc::c_int>() as libc::socklen_t,
+    ))?;
+    Ok(())
+}
+
+fn socket_address(address: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // safety: all zeroes is a valid value of every socket address structure.
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match address {
+        SocketAddr::V4(address) => {
+            let raw = &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in;
+            unsafe {
+                (*raw).sin_family = libc::AF_INET as libc::sa_family_t;
+                (*raw).sin_port = address.port().to_be();
+                (*raw).sin_addr = libc::in_addr { s_addr: u32::from(*address.ip()).to_be() };
+            }
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let raw = &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6;
+            unsafe {
+                (*raw).sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                (*raw).sin6_port = address.port().to_be();
+                (*raw).sin6_flowinfo = address.flowinfo();
+                (*raw).sin6_addr = libc::in6_addr { s6_addr: address.ip().octets() };
+                (*raw).sin6_scope_id = address.scope_id();
+            }
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_ipv4_and_ipv6_share_the_port() {
+        let ipv4 = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = ipv4.local_addr().unwrap().port();
+        let ipv6 = bind(SocketAddr::new("::".parse().unwrap(), port)).unwrap();
+        assert_eq!(ipv6.local_addr().unwrap().port(), port);
+        TcpStream::connect(("::1", port)).unwrap();
+        assert!(ipv6.accept().unwrap().1.is_ipv6());
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(ipv4.accept().unwrap().1.is_ipv4());
+    }
+
+    #[test]
+    fn test_connect_does_not_wait_for_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = connect(listener.local_addr().unwrap()).unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut received = [0; 4];
+        accepted.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+        let mut buffer = [0; 1];
+        assert_eq!(stream.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
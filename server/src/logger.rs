@@ -1,11 +1,33 @@
 //! Mikołaj Depta 328690
 //!
 //! Logging utilities.
+//!
+//! Besides the `Logger` sinks the module provides leveled diagnostics through the `log!` macro.
+//! Records are filtered by level per target - module path relative to the crate root
+//! (eg. `http::request`). Filter is read from `SERVER_LOG` environment variable or set with
+//! `set_filter`, its format is a comma separated list of `[<target>=]<level>` directives:
+//! `warn,resources=debug,http=trace`.
+//!
+//! Records that pass the filter are routed by severity: errors and warnings go to stderr and
+//! to the error log file, other diagnostics only to stderr. Records with the `access` target
+//! go to the access log file alone. Log files are configured with `set_error_log` and
+//! `set_access_log` or `SERVER_ERROR_LOG` and `SERVER_ACCESS_LOG` environment variables.
 
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::Cell;
+use std::env;
+use std::fmt::{self, Display, Formatter};
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{BufWriter, Stdout, prelude::*};
+use std::net::SocketAddr;
+use std::panic;
 use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub trait Logger {
     fn log<T: AsRef<str>>(&mut self, message: &T);
@@ -38,12 +60,21 @@ struct FileLogger(BufWriter<File>);
 
 impl FileLogger {
     pub fn new(log_file_path: &Path) -> Self {
+        Self::open(log_file_path).expect("log file creation failed")
+    }
+
+    pub fn open(log_file_path: &Path) -> io::Result<Self> {
         let handle = OpenOptions::new()
             .append(true)
             .create(true)
-            .open(log_file_path)
-            .expect("log file creation failed");
-        Self(BufWriter::new(handle))
+            .open(log_file_path)?;
+        Ok(Self(BufWriter::new(handle)))
+    }
+
+    /// Writes complete line and flushes it right away so nothing is lost if the process dies.
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.0, "{line}")?;
+        self.0.flush()
     }
 }
 
@@ -54,3 +85,445 @@ impl Logger for FileLogger {
             .expect("write to log file failed");
     }
 }
+
+// region Levels
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ERROR_REPR: &'static str = "error";
+    const WARN_REPR: &'static str = "warn";
+    const INFO_REPR: &'static str = "info";
+    const DEBUG_REPR: &'static str = "debug";
+    const TRACE_REPR: &'static str = "trace";
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let repr = match self {
+            Level::Error => Self::ERROR_REPR,
+            Level::Warn => Self::WARN_REPR,
+            Level::Info => Self::INFO_REPR,
+            Level::Debug => Self::DEBUG_REPR,
+            Level::Trace => Self::TRACE_REPR,
+        };
+        write!(f, "{}", repr.to_uppercase())
+    }
+}
+
+impl FromStr for Level {
+    type Err = ParseFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            Self::ERROR_REPR => Ok(Level::Error),
+            Self::WARN_REPR => Ok(Level::Warn),
+            Self::INFO_REPR => Ok(Level::Info),
+            Self::DEBUG_REPR => Ok(Level::Debug),
+            Self::TRACE_REPR => Ok(Level::Trace),
+            _ => Err(ParseFilterError::InvalidLevel(s.to_owned())),
+        }
+    }
+}
+// endregion
+
+// region Filter
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseFilterError {
+    InvalidLevel(String),
+    EmptyTarget(String),
+}
+
+impl Display for ParseFilterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLevel(repr) => write!(f, "invalid log level: {repr}"),
+            Self::EmptyTarget(directive) => write!(f, "missing log target in directive: {directive}"),
+        }
+    }
+}
+
+/// Maximal level of records logged for every target.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LevelFilter {
+    default: Level,
+    targets: Vec<(String, Level)>,
+}
+
+impl LevelFilter {
+    pub const ENV_VARIABLE: &'static str = "SERVER_LOG";
+
+    pub const fn new(default: Level) -> Self {
+        Self { default, targets: Vec::new() }
+    }
+
+    pub fn with_target(mut self, target: &str, level: Level) -> Self {
+        self.targets.push((target.to_owned(), level));
+        self
+    }
+
+    /// Most specific directive wins - `http::request` takes precedence over `http`.
+    pub fn level(&self, target: &str) -> Level {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || target.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        level <= self.level(target)
+    }
+}
+
+impl Default for LevelFilter {
+    fn default() -> Self {
+        Self::new(Level::Info)
+    }
+}
+
+impl FromStr for LevelFilter {
+    type Err = ParseFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::default();
+        for directive in s.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, _)) if target.trim().is_empty() => {
+                    return Err(ParseFilterError::EmptyTarget(directive.to_owned()));
+                }
+                Some((target, level)) => filter.targets.push((target.trim().to_owned(), level.parse()?)),
+                None => filter.default = directive.parse()?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+static FILTER: RwLock<LevelFilter> = RwLock::new(LevelFilter::new(Level::Info));
+
+pub fn set_filter(filter: LevelFilter) {
+    *FILTER.write().unwrap_or_else(|err| err.into_inner()) = filter;
+}
+
+/// Reads filter and log files from the environment, invalid values are reported and ignored.
+pub fn init_from_env() {
+    if let Ok(repr) = env::var(LevelFilter::ENV_VARIABLE) {
+        match repr.parse() {
+            Ok(filter) => set_filter(filter),
+            Err(err) => eprintln!("{}: {}", LevelFilter::ENV_VARIABLE, err),
+        }
+    }
+    for (variable, set_log) in [
+        (ERROR_LOG_VARIABLE, set_error_log as fn(&Path) -> io::Result<()>),
+        (ACCESS_LOG_VARIABLE, set_access_log),
+    ] {
+        if let Ok(path) = env::var(variable) {
+            if let Err(err) = set_log(Path::new(&path)) {
+                eprintln!("{variable}: could not open {path}: {err}");
+            }
+        }
+    }
+}
+
+pub fn enabled(target: &str, level: Level) -> bool {
+    FILTER.read().unwrap_or_else(|err| err.into_inner()).enabled(target, level)
+}
+
+/// Module path without the crate name, used as the default target.
+pub fn target_of(module_path: &'static str) -> &'static str {
+    module_path.split_once("::").map_or("", |(_, target)| target)
+}
+
+/// Formats the record and writes it to every destination of its route.
+pub fn write_record(level: Level, target: &str, args: fmt::Arguments<'_>) {
+    let record = match current_context() {
+        Some(context) => format!("[{:<5} {}] [{}] {}", level, target, context, args),
+        None => format!("[{:<5} {}] {}", level, target, args),
+    };
+    let route = Route::of(level, target);
+    let mut sinks = SINKS.lock().unwrap_or_else(|err| err.into_inner());
+    if route.stderr {
+        eprintln!("{record}");
+    }
+    if route.error_log {
+        if let Some(error_log) = &mut sinks.error_log {
+            /* there is nowhere else to report failure of the error log */
+            let _ = error_log.write_line(&record);
+        }
+    }
+    if route.access_log {
+        match &mut sinks.access_log {
+            Some(access_log) => { let _ = access_log.write_line(&record); }
+            None => println!("{record}"),
+        }
+    }
+}
+
+/// Logs a record if it passes the runtime filter, message is formatted only in that case.
+///
+/// ```ignore
+/// log!(Level::Debug, "loading {}", path.display());
+/// log!(target: "http", Level::Warn, "malformed request");
+/// ```
+macro_rules! log {
+    (target: $target: expr, $level: expr, $($arg: tt)+) => {{
+        let level = $level;
+        let target = $target;
+        if $crate::logger::enabled(target, level) {
+            $crate::logger::write_record(level, target, format_args!($($arg)+));
+        }
+    }};
+    ($level: expr, $($arg: tt)+) => {
+        $crate::logger::log!(target: $crate::logger::target_of(module_path!()), $level, $($arg)+)
+    };
+}
+
+pub(crate) use log;
+// endregion
+
+// region Routing
+/// Target of records describing served requests.
+pub const ACCESS_TARGET: &str = "access";
+pub const ERROR_LOG_VARIABLE: &str = "SERVER_ERROR_LOG";
+pub const ACCESS_LOG_VARIABLE: &str = "SERVER_ACCESS_LOG";
+
+/// Destinations of a single record.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Route {
+    pub stderr: bool,
+    pub error_log: bool,
+    /// Access records are written to stdout if there is no access log.
+    pub access_log: bool,
+}
+
+impl Route {
+    pub fn of(level: Level, target: &str) -> Self {
+        if target == ACCESS_TARGET {
+            Self { access_log: true, ..Self::default() }
+        } else {
+            Self { stderr: true, error_log: level <= Level::Warn, access_log: false }
+        }
+    }
+}
+
+struct Sinks {
+    error_log: Option<FileLogger>,
+    access_log: Option<FileLogger>,
+}
+
+static SINKS: Mutex<Sinks> = Mutex::new(Sinks { error_log: None, access_log: None });
+
+/// Appends errors and warnings to the file at `path` in addition to stderr.
+pub fn set_error_log(path: &Path) -> io::Result<()> {
+    let logger = FileLogger::open(path)?;
+    SINKS.lock().unwrap_or_else(|err| err.into_inner()).error_log = Some(logger);
+    Ok(())
+}
+
+/// Appends access records to the file at `path` instead of stdout.
+pub fn set_access_log(path: &Path) -> io::Result<()> {
+    let logger = FileLogger::open(path)?;
+    SINKS.lock().unwrap_or_else(|err| err.into_inner()).access_log = Some(logger);
+    Ok(())
+}
+// endregion
+
+// region Context
+/// Identifier of a single request, sent back in the `X-Request-Id` header of its response.
+///
+/// Prefix differs between runs of the server and the sequence number between requests of a run,
+/// so log records of a request can be told apart from the ones of any other. Ids are always
+/// 21 characters long.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct RequestId {
+    prefix: u32,
+    sequence: u64,
+}
+
+impl RequestId {
+    pub const HEADER: &'static str = "X-Request-Id";
+
+    pub fn next() -> Self {
+        static PREFIX: OnceLock<u32> = OnceLock::new();
+        static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+        let prefix = *PREFIX.get_or_init(|| {
+            let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            (started.as_nanos() as u32) ^ process::id().rotate_left(16)
+        });
+        Self { prefix, sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed) }
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}-{:012x}", self.prefix, self.sequence)
+    }
+}
+
+/// Connection handled by the current thread and the request being served on it, attached to
+/// every record logged while it is entered.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TraceContext {
+    pub token: usize,
+    pub peer: Option<SocketAddr>,
+    pub request: Option<RequestId>,
+}
+
+impl TraceContext {
+    pub fn new(token: usize, peer: Option<SocketAddr>) -> Self {
+        Self { token, peer, request: None }
+    }
+
+    pub fn with_request(mut self, request: RequestId) -> Self {
+        self.request = Some(request);
+        self
+    }
+
+    /// Makes this the current context until the returned guard is dropped.
+    pub fn enter(self) -> ContextGuard {
+        ContextGuard { previous: CONTEXT.replace(Some(self)) }
+    }
+}
+
+impl Display for TraceContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "connection {} from {}", self.token, peer)?,
+            None => write!(f, "connection {}", self.token)?,
+        }
+        match self.request {
+            Some(request) => write!(f, ", request {}", request),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Restores the previous context on drop.
+pub struct ContextGuard {
+    previous: Option<TraceContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT.set(self.previous.take());
+    }
+}
+
+thread_local! {
+    static CONTEXT: Cell<Option<TraceContext>> = const { Cell::new(None) };
+}
+
+pub fn current_context() -> Option<TraceContext> {
+    CONTEXT.get()
+}
+
+/// Replaces the default panic message with an error record, so the panic lands in the error log
+/// together with the connection that was being handled.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::capture();
+        match backtrace.status() {
+            BacktraceStatus::Captured => write_record(Level::Error, "panic", format_args!("{info}\n{backtrace}")),
+            _ => write_record(Level::Error, "panic", format_args!("{info}")),
+        }
+    }));
+}
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        let filter: LevelFilter = "warn, resources=debug,http::request=trace".parse().unwrap();
+        assert_eq!(
+            filter,
+            LevelFilter::new(Level::Warn)
+                .with_target("resources", Level::Debug)
+                .with_target("http::request", Level::Trace)
+        );
+        assert_eq!("".parse::<LevelFilter>(), Ok(LevelFilter::default()));
+        assert_eq!("verbose".parse::<LevelFilter>(), Err(ParseFilterError::InvalidLevel("verbose".to_owned())));
+        assert!(matches!("=info".parse::<LevelFilter>(), Err(ParseFilterError::EmptyTarget(_))));
+    }
+
+    #[test]
+    fn test_most_specific_target_wins() {
+        let filter = LevelFilter::new(Level::Error)
+            .with_target("http", Level::Info)
+            .with_target("http::request", Level::Trace);
+        assert!(filter.enabled("http::request", Level::Trace));
+        assert!(filter.enabled("http::headers", Level::Info));
+        assert!(!filter.enabled("http::headers", Level::Debug));
+        assert!(!filter.enabled("https", Level::Info));
+        assert!(!filter.enabled("server", Level::Warn));
+        assert!(filter.enabled("server", Level::Error));
+    }
+
+    #[test]
+    fn test_route() {
+        assert_eq!(Route::of(Level::Error, "http"), Route { stderr: true, error_log: true, access_log: false });
+        assert_eq!(Route::of(Level::Warn, ""), Route { stderr: true, error_log: true, access_log: false });
+        assert_eq!(Route::of(Level::Info, "http"), Route { stderr: true, error_log: false, access_log: false });
+        assert_eq!(Route::of(Level::Info, ACCESS_TARGET), Route { stderr: false, error_log: false, access_log: true });
+    }
+
+    #[test]
+    fn test_context_is_restored() {
+        assert_eq!(current_context(), None);
+        let outer = TraceContext::new(1, None);
+        let inner = TraceContext::new(2, Some("127.0.0.1:8080".parse().unwrap()));
+        {
+            let _outer = outer.enter();
+            {
+                let _inner = inner.enter();
+                assert_eq!(current_context(), Some(inner));
+            }
+            assert_eq!(current_context(), Some(outer));
+        }
+        assert_eq!(current_context(), None);
+        assert_eq!(inner.to_string(), "connection 2 from 127.0.0.1:8080");
+    }
+
+    #[test]
+    fn test_request_ids_are_unique() {
+        let (first, second) = (RequestId::next(), RequestId::next());
+        assert_ne!(first, second);
+        assert_eq!(first.prefix, second.prefix);
+        let context = TraceContext::new(3, None).with_request(first);
+        assert_eq!(context.to_string(), format!("connection 3, request {first}"));
+        assert_eq!(first.to_string().len(), 21);
+    }
+
+    #[test]
+    fn test_errors_reach_error_log() {
+        let dir = common::fs::TempDir::new("server-logger").unwrap();
+        let path = dir.path().join("error.log");
+        set_error_log(&path).unwrap();
+        {
+            let _context = TraceContext::new(7, None).enter();
+            write_record(Level::Error, "logger-test", format_args!("disk on fire"));
+        }
+        write_record(Level::Info, "logger-test", format_args!("all good"));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("[ERROR logger-test] [connection 7] disk on fire"));
+        assert!(!contents.contains("all good"));
+    }
+
+    #[test]
+    fn test_target_of() {
+        assert_eq!(target_of("server::http::request"), "http::request");
+        assert_eq!(target_of("server"), "");
+    }
+}
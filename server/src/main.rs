@@ -1,14 +1,57 @@
 //! Mikołaj Depta 328690
 #![allow(dead_code)]
 
+mod activation;
+mod auth;
+mod bundle;
+mod cache;
+mod cgi;
+mod cors;
+mod descriptors;
+mod echo;
+mod error;
+mod error_pages;
+mod expiry;
+mod extra_headers;
+mod filters;
+mod forwarded;
+mod framing;
+mod health;
 mod http;
+#[cfg(test)]
+mod integration;
+mod listen;
 mod logger;
+mod metrics;
+mod mime;
+mod proxy;
+mod range;
 mod resources;
+mod routing;
+mod sanitizer;
 mod util;
 mod server;
+mod shutdown;
+#[cfg(test)]
+mod snapshots;
+mod spool;
+mod sse;
+mod stats;
+mod throttle;
+mod trace;
+mod upload;
+mod privileges;
+mod quota;
+mod ratelimit;
+mod redirect;
 mod registry;
-
-use libc;
+mod reload;
+mod rewrite;
+mod settings;
+mod timeouts;
+mod upgrade;
+mod vhost;
+mod worker;
 
 /* Resources:
 Max accepted size of GET request: https://stackoverflow.com/questions/2659952/maximum-length-of-http-get-request
@@ -18,5 +61,8 @@ RFC TCP: https://datatracker.ietf.org/doc/html/rfc793
 
 
 fn main() {
+    trace::init_from_env();
+    logger::init_from_env();
+    logger::install_panic_hook();
     println!("Hello, world!");
 }
@@ -0,0 +1,247 @@
+//! Mikołaj Depta 328690
+//!
+//! Server statistics exposed in the Prometheus text format at `GET /metrics`.
+//!
+//! Connections are counted by the accepting server, requests and sent bytes by the connections
+//! that served them and handler latency by the `RequestHandler`. Counters of the virtual hosts
+//! are not exposed, they are persisted by `stats`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Upper bounds of the latency histogram buckets in seconds, `+Inf` bucket is implicit.
+const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Histogram {
+    /// Observations per bucket, not cumulative, last one is `+Inf`.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: Duration,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += latency;
+        self.count += 1;
+    }
+}
+
+/// Responses sent for requests to a single virtual host.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct HostCounters {
+    pub requests: u64,
+    pub bytes: u64,
+    /// Responses with 4xx and 5xx status codes.
+    pub errors: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    accepted_connections: AtomicU64,
+    active_connections: AtomicU64,
+    bytes_sent: AtomicU64,
+    requests: Mutex<BTreeMap<usize, u64>>,
+    latency: Mutex<BTreeMap<String, Histogram>>,
+    hosts: Mutex<BTreeMap<String, HostCounters>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connection_accepted(&self) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the connection as active until the returned guard is dropped.
+    pub fn connection_opened(self: &Arc<Self>) -> ActiveConnection {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection { metrics: self.clone() }
+    }
+
+    /// Records response with `status_code` that was sent in full.
+    pub fn response_sent(&self, status_code: usize, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        *self.lock_requests().entry(status_code).or_default() += 1;
+    }
+
+    /// Records response for a request to the virtual `host`, in addition to `response_sent`.
+    pub fn host_served(&self, host: &str, status_code: usize, bytes: usize) {
+        let mut hosts = self.lock_hosts();
+        let counters = match hosts.get_mut(host) {
+            Some(counters) => counters,
+            None => hosts.entry(host.to_owned()).or_default(),
+        };
+        counters.requests += 1;
+        counters.bytes += bytes as u64;
+        counters.errors += u64::from(status_code >= 400);
+    }
+
+    /// Records time it took `handler` to produce a response.
+    pub fn observe_latency(&self, handler: &str, latency: Duration) {
+        let mut histograms = self.latency.lock().unwrap_or_else(|err| err.into_inner());
+        match histograms.get_mut(handler) {
+            Some(histogram) => histogram.observe(latency),
+            None => {
+                let mut histogram = Histogram::default();
+                histogram.observe(latency);
+                histograms.insert(handler.to_owned(), histogram);
+            }
+        }
+    }
+
+    pub fn accepted_connections(&self) -> u64 {
+        self.accepted_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Number of responses sent with `status_code`.
+    pub fn requests(&self, status_code: usize) -> u64 {
+        self.lock_requests().get(&status_code).copied().unwrap_or_default()
+    }
+
+    /// Counters of every virtual host that was served at least once.
+    pub fn hosts(&self) -> BTreeMap<String, HostCounters> {
+        self.lock_hosts().clone()
+    }
+
+    fn lock_hosts(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, HostCounters>> {
+        self.hosts.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn lock_requests(&self) -> std::sync::MutexGuard<'_, BTreeMap<usize, u64>> {
+        self.requests.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Statistics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        Self::render_metric(&mut out, "server_connections_accepted_total", "counter", "Connections accepted by the listener.");
+        let _ = writeln!(out, "server_connections_accepted_total {}", self.accepted_connections());
+        Self::render_metric(&mut out, "server_connections_active", "gauge", "Connections currently being served.");
+        let _ = writeln!(out, "server_connections_active {}", self.active_connections());
+        Self::render_metric(&mut out, "server_sent_bytes_total", "counter", "Bytes of responses sent in full.");
+        let _ = writeln!(out, "server_sent_bytes_total {}", self.bytes_sent());
+
+        Self::render_metric(&mut out, "server_requests_total", "counter", "Responses sent by status code.");
+        for (code, count) in self.lock_requests().iter() {
+            let _ = writeln!(out, "server_requests_total{{code=\"{code}\"}} {count}");
+        }
+
+        Self::render_metric(&mut out, "server_handler_duration_seconds", "histogram", "Time spent producing responses.");
+        let histograms = self.latency.lock().unwrap_or_else(|err| err.into_inner());
+        for (handler, histogram) in histograms.iter() {
+            let handler = handler.replace('\\', "\\\\").replace('"', "\\\"");
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "server_handler_duration_seconds_bucket{{handler=\"{handler}\",le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "server_handler_duration_seconds_bucket{{handler=\"{handler}\",le=\"+Inf\"}} {}", histogram.count);
+            let _ = writeln!(out, "server_handler_duration_seconds_sum{{handler=\"{handler}\"}} {}", histogram.sum.as_secs_f64());
+            let _ = writeln!(out, "server_handler_duration_seconds_count{{handler=\"{handler}\"}} {}", histogram.count);
+        }
+        out
+    }
+
+    fn render_metric(out: &mut String, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+    }
+}
+
+/// Keeps the connection counted as active, see `Metrics::connection_opened`.
+#[derive(Debug)]
+pub struct ActiveConnection {
+    metrics: Arc<Metrics>,
+}
+
+impl ActiveConnection {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_connections() {
+        let metrics = Arc::new(Metrics::new());
+        let first = metrics.connection_opened();
+        let second = metrics.connection_opened();
+        assert_eq!(metrics.active_connections(), 2);
+        drop(first);
+        assert_eq!(metrics.active_connections(), 1);
+        drop(second);
+        assert_eq!(metrics.active_connections(), 0);
+    }
+
+    #[test]
+    fn test_host_counters() {
+        let metrics = Metrics::new();
+        metrics.host_served("localhost", 200, 100);
+        metrics.host_served("localhost", 404, 5);
+        metrics.host_served("example.com", 500, 10);
+        let hosts = metrics.hosts();
+        assert_eq!(hosts["localhost"], HostCounters { requests: 2, bytes: 105, errors: 1 });
+        assert_eq!(hosts["example.com"], HostCounters { requests: 1, bytes: 10, errors: 1 });
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.connection_accepted();
+        metrics.response_sent(200, 100);
+        metrics.response_sent(200, 20);
+        metrics.response_sent(404, 5);
+        metrics.observe_latency("static", Duration::from_micros(200));
+        metrics.observe_latency("static", Duration::from_millis(20));
+        metrics.observe_latency("static", Duration::from_secs(2));
+        let rendered = metrics.render();
+        for line in [
+            "# TYPE server_connections_accepted_total counter",
+            "server_connections_accepted_total 1",
+            "server_connections_active 0",
+            "server_sent_bytes_total 125",
+            "server_requests_total{code=\"200\"} 2",
+            "server_requests_total{code=\"404\"} 1",
+            "# TYPE server_handler_duration_seconds histogram",
+            "server_handler_duration_seconds_bucket{handler=\"static\",le=\"0.0005\"} 1",
+            "server_handler_duration_seconds_bucket{handler=\"static\",le=\"0.01\"} 1",
+            "server_handler_duration_seconds_bucket{handler=\"static\",le=\"0.025\"} 2",
+            "server_handler_duration_seconds_bucket{handler=\"static\",le=\"1\"} 2",
+            "server_handler_duration_seconds_bucket{handler=\"static\",le=\"+Inf\"} 3",
+            "server_handler_duration_seconds_count{handler=\"static\"} 3",
+        ] {
+            assert!(rendered.lines().any(|rendered| rendered == line), "missing {line} in\n{rendered}");
+        }
+    }
+}
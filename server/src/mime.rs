@@ -0,0 +1,220 @@
+//! Mikołaj Depta 328690
+//!
+//! Media types of served files, looked up by file extension.
+//!
+//! Built-in table covers common web content. Operator can override and extend it with a file
+//! in the `mime.types` format, named by `SERVER_MIME_TYPES`. Textual types are sent with
+//! `charset=utf-8`, everything else without parameters.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::http::headers::entity_header::ContentType;
+use crate::logger::{log, Level};
+
+/// Media type and the extensions mapped to it.
+const BUILT_IN: &[(&str, &[&str])] = &[
+    ("text/plain", &["txt", "text", "log"]),
+    ("text/html", &["html", "htm"]),
+    ("text/css", &["css"]),
+    ("text/csv", &["csv"]),
+    ("text/markdown", &["md"]),
+    ("text/javascript", &["js", "mjs"]),
+    ("application/json", &["json", "map"]),
+    ("application/manifest+json", &["webmanifest"]),
+    ("application/xml", &["xml"]),
+    ("application/pdf", &["pdf"]),
+    ("application/wasm", &["wasm"]),
+    ("application/zip", &["zip"]),
+    ("application/gzip", &["gz"]),
+    ("application/x-tar", &["tar"]),
+    ("image/jpeg", &["jpg", "jpeg"]),
+    ("image/png", &["png"]),
+    ("image/gif", &["gif"]),
+    ("image/webp", &["webp"]),
+    ("image/avif", &["avif"]),
+    ("image/svg+xml", &["svg"]),
+    ("image/x-icon", &["ico"]),
+    ("font/woff", &["woff"]),
+    ("font/woff2", &["woff2"]),
+    ("font/ttf", &["ttf"]),
+    ("font/otf", &["otf"]),
+    ("audio/mpeg", &["mp3"]),
+    ("audio/ogg", &["ogg", "oga"]),
+    ("audio/wav", &["wav"]),
+    ("video/mp4", &["mp4"]),
+    ("video/webm", &["webm"]),
+];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MimeTypes {
+    /// Media type by lowercase extension.
+    by_extension: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    pub const ENV_VARIABLE: &'static str = "SERVER_MIME_TYPES";
+
+    pub fn new() -> Self {
+        let by_extension = BUILT_IN
+            .iter()
+            .flat_map(|(media_type, extensions)| extensions.iter().map(|extension| (extension.to_string(), media_type.to_string())))
+            .collect();
+        Self { by_extension }
+    }
+
+    /// Built-in table overridden by the file named by `SERVER_MIME_TYPES`, if it can be loaded.
+    pub fn from_env() -> Self {
+        let Some(path) = env::var_os(Self::ENV_VARIABLE) else {
+            return Self::new();
+        };
+        match Self::new().with_overrides_from(Path::new(&path)) {
+            Ok(types) => types,
+            Err(err) => {
+                log!(Level::Warn, "{}: {}, using built-in media types", Self::ENV_VARIABLE, err);
+                Self::new()
+            }
+        }
+    }
+
+    /// Reads mappings from `path`, they replace built-in mappings of the same extensions.
+    pub fn with_overrides_from(self, path: &Path) -> Result<Self, LoadMimeTypesError> {
+        let repr = fs::read_to_string(path).map_err(LoadMimeTypesError::Io)?;
+        self.with_overrides(&repr).map_err(LoadMimeTypesError::Parse)
+    }
+
+    /// Expected input format, the one of `/etc/mime.types` - one media type per line followed by
+    /// its extensions, empty lines and `#` comments are ignored:
+    /// ```text
+    /// <media type> <extension> [<extension> ...]
+    /// ```
+    pub fn with_overrides(mut self, repr: &str) -> Result<Self, ParseMimeTypesError> {
+        for (index, line) in repr.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut tokens = line.split_whitespace();
+            let Some(media_type) = tokens.next() else { continue };
+            if !Self::is_media_type(media_type) {
+                return Err(ParseMimeTypesError::InvalidMediaType(index + 1, media_type.to_owned()));
+            }
+            for extension in tokens {
+                let extension = extension.trim_start_matches('.').to_lowercase();
+                self.by_extension.insert(extension, media_type.to_lowercase());
+            }
+        }
+        Ok(self)
+    }
+
+    fn is_media_type(repr: &str) -> bool {
+        let valid = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_graphic() && byte != b'/');
+        matches!(repr.split_once('/'), Some((kind, subtype)) if valid(kind) && valid(subtype))
+    }
+
+    /// Media type of the file, `None` if its extension is not known.
+    pub fn media_type(&self, file: &Path) -> Option<&str> {
+        let extension = file.extension().and_then(OsStr::to_str)?.to_lowercase();
+        self.by_extension.get(&extension).map(String::as_str)
+    }
+
+    /// Content type the file is served with, `application/octet-stream` for unknown extensions.
+    pub fn content_type(&self, file: &Path) -> ContentType {
+        match self.media_type(file) {
+            Some(media_type) => Self::with_charset(media_type),
+            None => ContentType::default(),
+        }
+    }
+
+    fn with_charset(media_type: &str) -> ContentType {
+        if let Ok(known) = media_type.parse::<ContentType>() {
+            return known;
+        }
+        if Self::is_textual(media_type) {
+            ContentType::Other(format!("{media_type}; charset=utf-8"))
+        } else {
+            ContentType::Other(media_type.to_owned())
+        }
+    }
+
+    fn is_textual(media_type: &str) -> bool {
+        media_type.starts_with("text/")
+            || media_type.ends_with("+json")
+            || media_type.ends_with("+xml")
+            || matches!(media_type, "application/json" | "application/xml" | "application/javascript")
+    }
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseMimeTypesError {
+    InvalidMediaType(usize, String),
+}
+
+impl Display for ParseMimeTypesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMediaType(line, repr) => write!(f, "line {line}: expected '<type>/<subtype>', got '{repr}'"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadMimeTypesError {
+    Io(io::Error),
+    Parse(ParseMimeTypesError),
+}
+
+impl Display for LoadMimeTypesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read media types: {err}"),
+            Self::Parse(err) => write!(f, "invalid media types: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_type(types: &MimeTypes, file: &str) -> String {
+        types.content_type(Path::new(file)).to_string()
+    }
+
+    #[test]
+    fn test_built_in_types() {
+        let types = MimeTypes::new();
+        assert_eq!(types.content_type(Path::new("index.HTML")), ContentType::Html);
+        assert_eq!(types.content_type(Path::new("photo.jpg")), ContentType::Jpeg);
+        assert_eq!(content_type(&types, "app.js"), "text/javascript; charset=utf-8");
+        assert_eq!(content_type(&types, "icon.svg"), "image/svg+xml; charset=utf-8");
+        assert_eq!(content_type(&types, "font.woff2"), "font/woff2");
+        assert_eq!(content_type(&types, "clip.mp4"), "video/mp4");
+        assert_eq!(content_type(&types, "module.wasm"), "application/wasm");
+        assert_eq!(content_type(&types, "archive.unknown"), "application/octet-stream");
+        assert_eq!(content_type(&types, "Makefile"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let types = MimeTypes::new()
+            .with_overrides("# site specific\napplication/javascript js\n\ntext/x-rust rs .RLIB\n")
+            .unwrap();
+        assert_eq!(content_type(&types, "app.js"), "application/javascript; charset=utf-8");
+        assert_eq!(content_type(&types, "main.rs"), "text/x-rust; charset=utf-8");
+        assert_eq!(content_type(&types, "lib.rlib"), "text/x-rust; charset=utf-8");
+        assert_eq!(content_type(&types, "style.css"), "text/css; charset=utf-8");
+        assert_eq!(
+            MimeTypes::new().with_overrides("text/plain txt\njavascript js\n"),
+            Err(ParseMimeTypesError::InvalidMediaType(2, "javascript".to_owned()))
+        );
+    }
+}
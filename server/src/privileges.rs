@@ -0,0 +1,194 @@
+//! Mikołaj Depta 328690
+//!
+//! Dropping root privileges once the listening sockets are bound.
+//!
+//! Binding ports below 1024 needs root, serving files does not. With `SERVER_USER` set the server
+//! switches to that user right after binding, and to its primary group unless `SERVER_GROUP` names
+//! another one. Both take names as well as numeric ids. With `SERVER_CHROOT=1` the server first
+//! confines itself to the catalog, so document roots have to lie within it and files named by other
+//! settings, eg. `SERVER_MIME_TYPES`, are looked up inside it.
+
+use std::env;
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use crate::logger::{log, Level};
+use crate::registry::syscall;
+
+/// Large enough for any reasonable passwd or group entry.
+const ENTRY_BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Privileges {
+    user: Option<String>,
+    group: Option<String>,
+    chroot: bool,
+}
+
+impl Privileges {
+    pub const USER_VARIABLE: &'static str = "SERVER_USER";
+    pub const GROUP_VARIABLE: &'static str = "SERVER_GROUP";
+    pub const CHROOT_VARIABLE: &'static str = "SERVER_CHROOT";
+
+    pub fn from_env() -> Self {
+        let variable = |name| env::var(name).ok().map(|value| value.trim().to_owned()).filter(|value| !value.is_empty());
+        Self {
+            user: variable(Self::USER_VARIABLE),
+            group: variable(Self::GROUP_VARIABLE),
+            chroot: env::var(Self::CHROOT_VARIABLE).is_ok_and(|value| value.trim() == "1"),
+        }
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_owned());
+        self
+    }
+
+    pub fn with_group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_owned());
+        self
+    }
+
+    pub fn with_chroot(mut self, chroot: bool) -> Self {
+        self.chroot = chroot;
+        self
+    }
+
+    /// Nothing is dropped, the server keeps running as it was started.
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none() && self.group.is_none() && !self.chroot
+    }
+
+    /// Path of the `catalog` once privileges are dropped.
+    pub fn catalog(&self, catalog: &Path) -> PathBuf {
+        match self.chroot {
+            true => PathBuf::from("/"),
+            false => catalog.to_path_buf(),
+        }
+    }
+
+    /// User and group ids to switch to, `None` where the current one is kept.
+    fn credentials(&self) -> Result<(Option<libc::uid_t>, Option<libc::gid_t>), PrivilegesError> {
+        let user = match &self.user {
+            Some(user) => Some(lookup_user(user)?.ok_or_else(|| PrivilegesError::UnknownUser(user.clone()))?),
+            None => None,
+        };
+        let group = match &self.group {
+            Some(group) => Some(lookup_group(group)?.ok_or_else(|| PrivilegesError::UnknownGroup(group.clone()))?),
+            None => user.map(|(_, primary_group)| primary_group),
+        };
+        Ok((user.map(|(uid, _)| uid), group))
+    }
+
+    /// Confines the process to the `catalog` if enabled, then switches to the configured group and user.
+    /// Users and groups are looked up before, their databases are not reachable from the new root.
+    pub fn drop(&self, catalog: &Path) -> Result<(), PrivilegesError> {
+        let (uid, gid) = self.credentials()?;
+        if self.chroot {
+            let path = CString::new(catalog.as_os_str().as_bytes())
+                .map_err(|err| PrivilegesError::Syscall("chroot", err.into()))?;
+            syscall!(chroot(path.as_ptr())).map_err(|err| PrivilegesError::Syscall("chroot", err))?;
+            syscall!(chdir(c"/".as_ptr())).map_err(|err| PrivilegesError::Syscall("chdir", err))?;
+            if uid.is_none() {
+                log!(Level::Warn, "{} without {}, root can leave the new root", Self::CHROOT_VARIABLE, Self::USER_VARIABLE);
+            }
+        }
+        if let Some(gid) = gid {
+            /* supplementary groups of root would be kept otherwise */
+            syscall!(setgroups(1, &gid)).map_err(|err| PrivilegesError::Syscall("setgroups", err))?;
+            syscall!(setgid(gid)).map_err(|err| PrivilegesError::Syscall("setgid", err))?;
+        }
+        if let Some(uid) = uid {
+            syscall!(setuid(uid)).map_err(|err| PrivilegesError::Syscall("setuid", err))?;
+        }
+        log!(Level::Info, "dropped privileges, running as uid {} gid {}", unsafe { libc::getuid() }, unsafe { libc::getgid() });
+        Ok(())
+    }
+}
+
+/// User id and primary group id of the `user`, given by name or id.
+fn lookup_user(user: &str) -> Result<Option<(libc::uid_t, libc::gid_t)>, PrivilegesError> {
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut found: *mut libc::passwd = ptr::null_mut();
+    let result = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) },
+        Err(_) => {
+            let name = CString::new(user).map_err(|_| PrivilegesError::UnknownUser(user.to_owned()))?;
+            unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) }
+        }
+    };
+    if result != 0 {
+        return Err(PrivilegesError::Syscall("getpwnam_r", io::Error::from_raw_os_error(result)));
+    }
+    Ok((!found.is_null()).then_some((entry.pw_uid, entry.pw_gid)))
+}
+
+/// Id of the `group`, given by name or id. Numeric ids are taken as they are.
+fn lookup_group(group: &str) -> Result<Option<libc::gid_t>, PrivilegesError> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(Some(gid));
+    }
+    let name = CString::new(group).map_err(|_| PrivilegesError::UnknownGroup(group.to_owned()))?;
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut found: *mut libc::group = ptr::null_mut();
+    let result = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    if result != 0 {
+        return Err(PrivilegesError::Syscall("getgrnam_r", io::Error::from_raw_os_error(result)));
+    }
+    Ok((!found.is_null()).then_some(entry.gr_gid))
+}
+
+#[derive(Debug)]
+pub enum PrivilegesError {
+    UnknownUser(String),
+    UnknownGroup(String),
+    Syscall(&'static str, io::Error),
+}
+
+impl Display for PrivilegesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownUser(user) => write!(f, "{}: no user '{}'", Privileges::USER_VARIABLE, user),
+            Self::UnknownGroup(group) => write!(f, "{}: no group '{}'", Privileges::GROUP_VARIABLE, group),
+            Self::Syscall(call, err) => write!(f, "could not drop privileges, {} failed: {}", call, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials() {
+        assert_eq!(Privileges::default().credentials().unwrap(), (None, None));
+        assert_eq!(Privileges::default().with_user("root").credentials().unwrap(), (Some(0), Some(0)));
+        assert_eq!(Privileges::default().with_user("0").credentials().unwrap(), (Some(0), Some(0)));
+        /* explicit group replaces the primary one */
+        assert_eq!(Privileges::default().with_user("root").with_group("4242").credentials().unwrap(), (Some(0), Some(4242)));
+        assert_eq!(Privileges::default().with_group("root").credentials().unwrap(), (None, Some(0)));
+        assert!(matches!(
+            Privileges::default().with_user("no-such-user-328690").credentials(),
+            Err(PrivilegesError::UnknownUser(_))
+        ));
+        assert!(matches!(
+            Privileges::default().with_group("no-such-group-328690").credentials(),
+            Err(PrivilegesError::UnknownGroup(_))
+        ));
+    }
+
+    #[test]
+    fn test_catalog_after_chroot() {
+        let privileges = Privileges::default().with_user("nobody");
+        assert!(!privileges.is_empty());
+        assert_eq!(privileges.catalog(Path::new("/srv/www")), Path::new("/srv/www"));
+        assert_eq!(privileges.with_chroot(true).catalog(Path::new("/srv/www")), Path::new("/"));
+    }
+}
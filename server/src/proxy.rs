@@ -0,0 +1,711 @@
+//! Mikołaj Depta 328690
+//!
+//! Relaying of requests to other servers, used in the proxy exercises of the course.
+//!
+//! In the forward proxy mode GET request with an absolute `http://` target is not served from
+//! the document roots, it is rewritten to the origin form and sent to the origin server.
+//! `ProxyHandler` works as a reverse proxy instead, requests below its path prefix are sent
+//! to a pool of upstream servers taking turns.
+//!
+//! Either way the request goes over a new connection, watched by the same event registry as
+//! the client one, and the response is streamed back to the client as it arrives. Response body
+//! is not parsed, so it ends when the server closes its connection and the client connection is
+//! closed after it.
+//!
+//! Both messages are rewritten on the way: their hop-by-hop headers are dropped and the proxy adds
+//! itself to `Via`, see `Outgoing` for requests and `relay` for responses. Response keeps its
+//! `Transfer-Encoding`, since the body is relayed in the coding the server sent it in.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use common::{dns, units};
+use crate::http::common::{Body, Method, CRLF};
+use crate::http::headers::entity_header::EntityHeader;
+use crate::http::headers::general_header::{ConnectionType, GeneralHeader};
+use crate::http::headers::request_header::RequestHeader;
+use crate::http::headers::{Header, HeaderParser, Headers, ParseHeaderError};
+use crate::http::request::{Request, StartLine};
+use crate::listen;
+use crate::logger::{log, Level};
+use crate::registry::{EventType, Notification, Registry, TimeoutDuration, Token};
+use crate::routing::Router;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ProxyConfig {
+    /// Time allowed for connecting to the server and sending it the request, every server
+    /// of the pool gets it anew.
+    pub connect_timeout: Duration,
+    /// Time the server can stay silent while its response is relayed.
+    pub idle_timeout: Duration,
+}
+
+impl ProxyConfig {
+    pub const ENV_VARIABLE: &'static str = "SERVER_PROXY";
+
+    /// Proxy mode is enabled by setting `SERVER_PROXY` to the connect timeout, eg. `5s`.
+    pub fn from_env() -> Option<Self> {
+        let repr = env::var(Self::ENV_VARIABLE).ok()?;
+        match units::parse_duration(&repr) {
+            Ok(timeout) if !timeout.is_zero() => Some(Self { connect_timeout: timeout, ..Self::default() }),
+            Ok(_) => {
+                log!(Level::Warn, "{}: timeout must be positive", Self::ENV_VARIABLE);
+                None
+            }
+            Err(err) => {
+                log!(Level::Warn, "{}: invalid timeout '{}': {}", Self::ENV_VARIABLE, repr, err);
+                None
+            }
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self { connect_timeout: Duration::from_secs(5), idle_timeout: Duration::from_secs(30) }
+    }
+}
+
+/// Request rewritten for the server it is relayed to.
+pub struct Outgoing<'a> {
+    /// Servers tried in order until one accepts the connection, as host and port.
+    servers: Vec<(String, u16)>,
+    head: Vec<u8>,
+    body: Option<&'a Body>,
+    config: ProxyConfig,
+}
+
+/// Name the proxy goes by in `Via`.
+const PSEUDONYM: &str = env!("CARGO_PKG_NAME");
+
+/// `Via` entry the proxy adds to a message it received with `version`, see RFC 9110 section 7.6.3.
+fn via(version: &str) -> Header {
+    /* protocol name is left out when it is HTTP */
+    let received = version.trim_start_matches("HTTP/");
+    Header::Unknown("Via".into(), format!("{received} {PSEUDONYM}"))
+}
+
+impl<'a> Outgoing<'a> {
+    /// `request` sent to the first of the `servers` that accepts the connection, with `start_line`.
+    /// Headers concerning only the client connection are dropped, the body is described by its length
+    /// and the server is asked to close the connection after the response, since that is how
+    /// the relayed response ends. The proxy adds itself to `Via`, see RFC 9110 section 7.6.3.
+    /// `headers` adjusts the rest.
+    fn new(
+        servers: Vec<(String, u16)>,
+        request: &'a Request,
+        start_line: &StartLine,
+        config: ProxyConfig,
+        headers: impl FnOnce(&mut Headers),
+    ) -> Self {
+        let mut relayed_headers = request.headers().clone();
+        relayed_headers.retain_end_to_end();
+        relayed_headers.remove("Content-Length");
+        relayed_headers.append(via(&request.start_line().version().to_string()));
+        if let Some(body) = request.body() {
+            relayed_headers.append(EntityHeader::ContentLength(body.len()));
+        }
+        headers(&mut relayed_headers);
+        relayed_headers.append(GeneralHeader::Connection(ConnectionType::Close));
+        let head = format!("{start_line}{relayed_headers}{CRLF}").into_bytes();
+        Self { servers, head, body: request.body(), config }
+    }
+
+    /// Time the server can stay silent while its response is relayed.
+    pub fn idle_timeout(&self) -> Duration {
+        self.config.idle_timeout
+    }
+
+    /// Connects to the first server that accepts the connection and sends it the request.
+    /// Host names are resolved with `common::dns`, so the event loop is not stalled by `getaddrinfo`.
+    ///
+    /// Sockets are non-blocking, the `registry` watches the one being connected or written to instead
+    /// of the `client`, which is watched for reading again afterwards. Returned stream is ready to be
+    /// relayed with `relay`.
+    pub fn connect(&self, registry: &mut Registry, client: &TcpStream) -> io::Result<TcpStream> {
+        registry.delete_interest(EventType::Read, client.as_raw_fd())?;
+        let connected = self.try_servers(registry);
+        let restored = registry.add_interest(EventType::Read, client.as_raw_fd());
+        let upstream = connected?;
+        restored?;
+        Ok(upstream)
+    }
+
+    fn try_servers(&self, registry: &mut Registry) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{self} has no address"));
+        for (host, port) in &self.servers {
+            let addresses = match dns::system().resolve(host) {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    last_error = err.into();
+                    continue;
+                }
+            };
+            for address in addresses {
+                /* connecting and sending the request share the timeout, measured by a registry timer */
+                let deadline = registry.add_timer(self.config.connect_timeout)?;
+                let sent = match Self::connect_to(registry, SocketAddr::new(address, *port), deadline) {
+                    Ok(upstream) => Some(self.send(registry, upstream, deadline)),
+                    Err(err) => {
+                        last_error = err;
+                        None
+                    }
+                };
+                registry.delete_timer(deadline)?;
+                if let Some(sent) = sent {
+                    return sent;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn connect_to(registry: &mut Registry, address: SocketAddr, deadline: Token) -> io::Result<TcpStream> {
+        let upstream = listen::connect(address)?;
+        await_ready(registry, &upstream, EventType::Write, deadline)?;
+        /* outcome of the connection attempt is reported as the pending error of the socket */
+        match upstream.take_error()? {
+            Some(err) => Err(err),
+            None => Ok(upstream),
+        }
+    }
+
+    fn send(&self, registry: &mut Registry, mut upstream: TcpStream, deadline: Token) -> io::Result<TcpStream> {
+        write_all(registry, &mut upstream, &self.head, deadline)?;
+        if let Some(body) = self.body {
+            let mut reader = body.reader();
+            let mut buffer = vec![0; Relay::BUFFER_SIZE];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(count) => write_all(registry, &mut upstream, &buffer[..count], deadline)?,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(upstream)
+    }
+}
+
+/// Writes all of the `data` to the non-blocking `upstream`, waiting for room in its send buffer until the `deadline` timer expires.
+fn write_all(registry: &mut Registry, upstream: &mut TcpStream, mut data: &[u8], deadline: Token) -> io::Result<()> {
+    while !data.is_empty() {
+        match upstream.write(data) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(count) => data = &data[count..],
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => await_ready(registry, upstream, EventType::Write, deadline)?,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Watches `upstream` for `event_type` until it is ready, failing with `TimedOut` if the `deadline` timer expires first.
+fn await_ready(registry: &mut Registry, upstream: &TcpStream, event_type: EventType, deadline: Token) -> io::Result<()> {
+    let token = registry.add_interest(event_type, upstream.as_raw_fd())?;
+    let waited = loop {
+        match registry.await_events(&TimeoutDuration::Infinite) {
+            Ok(ready) if ready.iter().any(|event| event.token == token) => break Ok(()),
+            Ok(ready) if ready.iter().any(|event| event.token == deadline) => {
+                break Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            Ok(_) => continue,
+            Err(err) => break Err(err),
+        }
+    };
+    registry.delete_interest(event_type, upstream.as_raw_fd())?;
+    waited
+}
+
+impl Display for Outgoing<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let servers = self.servers.iter().map(|(host, port)| format!("{host}:{port}")).collect::<Vec<_>>();
+        write!(f, "{}", servers.join(", "))
+    }
+}
+
+/// Origin server of a request and the target in the origin form.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Upstream {
+    /// Host as written in the target, IPv6 addresses in brackets.
+    host: String,
+    /// Port if it was given in the target.
+    explicit_port: Option<u16>,
+    port: u16,
+    /// Path and query, `/` if the target has none.
+    path: String,
+}
+
+impl Upstream {
+    const SCHEME: &'static str = "http://";
+    const DEFAULT_PORT: u16 = 80;
+
+    /// Origin server of the GET `request` with an absolute `http://` target, `None` for other requests.
+    pub fn of(request: &Request) -> Option<Self> {
+        match request.start_line().method() {
+            Method::GET => Self::from_target(request.start_line().url().to_str()?),
+            _ => None,
+        }
+    }
+
+    fn from_target(target: &str) -> Option<Self> {
+        if !target.get(..Self::SCHEME.len())?.eq_ignore_ascii_case(Self::SCHEME) {
+            return None;
+        }
+        let rest = &target[Self::SCHEME.len()..];
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(start) if rest[start..].starts_with('?') => (&rest[..start], format!("/{}", &rest[start..])),
+            Some(start) => (&rest[..start], rest[start..].to_owned()),
+            None => (rest, String::from("/")),
+        };
+        /* credentials in the target are not supported */
+        if authority.contains('@') {
+            return None;
+        }
+        let (host, explicit_port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, Some(port.parse().ok()?)),
+            _ => (authority, None),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self { host: host.to_owned(), explicit_port, port: explicit_port.unwrap_or(Self::DEFAULT_PORT), path })
+    }
+
+    /// Host without the brackets around IPv6 addresses.
+    fn address(&self) -> &str {
+        self.host.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    /// `request` in the origin form, with `Host` naming the origin server.
+    pub fn outgoing<'a>(&self, request: &'a Request, config: ProxyConfig) -> Outgoing<'a> {
+        let start_line = StartLine::new(Method::GET, Path::new(&self.path), *request.start_line().version());
+        let host = RequestHeader::Host(self.host.clone(), self.explicit_port);
+        let servers = vec![(self.address().to_owned(), self.port)];
+        Outgoing::new(servers, request, &start_line, config, |headers| headers.insert(host))
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Reverse proxy relaying requests below the path prefix to a pool of upstream servers.
+///
+/// Every request goes to the next server of the pool, round-robin. Servers that do not accept
+/// the connection are skipped, so the request fails only if the whole pool is down.
+/// Client address is passed on in `X-Forwarded-For`.
+pub struct ProxyHandler {
+    prefix: String,
+    upstreams: Vec<SocketAddr>,
+    next: AtomicUsize,
+    config: ProxyConfig,
+}
+
+impl ProxyHandler {
+    /// Relays requests for `prefix` and everything below it, see `Router::prefix`.
+    ///
+    /// # Panics
+    /// If there are no `upstreams`.
+    pub fn new(prefix: &str, upstreams: Vec<SocketAddr>) -> Self {
+        assert!(!upstreams.is_empty(), "proxy for {prefix} needs at least one upstream server");
+        Self { prefix: prefix.to_owned(), upstreams, next: AtomicUsize::new(0), config: ProxyConfig::default() }
+    }
+
+    pub fn with_config(mut self, config: ProxyConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Requests for `path` are relayed by this proxy.
+    pub fn matches(&self, path: &Path) -> bool {
+        path.to_str().is_some_and(|path| Router::is_below(path, &self.prefix))
+    }
+
+    /// `request` for the next server of the pool, the rest of the pool follows in case it is down.
+    /// `peer` is appended to the addresses the request was forwarded for.
+    pub fn outgoing<'a>(&self, request: &'a Request, peer: Option<IpAddr>) -> Outgoing<'a> {
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % self.upstreams.len();
+        let servers = self.upstreams[first..]
+            .iter()
+            .chain(&self.upstreams[..first])
+            .map(|upstream| (upstream.ip().to_string(), upstream.port()))
+            .collect();
+        let forwarded_for = request.headers().forwarded_for().chain(peer).collect::<Vec<_>>();
+        Outgoing::new(servers, request, request.start_line(), self.config, |headers| {
+            if !forwarded_for.is_empty() {
+                headers.insert(RequestHeader::ForwardedFor(forwarded_for));
+            }
+        })
+    }
+}
+
+/// Summary of the response relayed to the client.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Relayed {
+    /// Bytes passed on to the client, the response head counted as rewritten by the proxy.
+    pub bytes: usize,
+    /// Beginning of the response, enough to read the status code from.
+    head: Vec<u8>,
+}
+
+impl Relayed {
+    const STATUS_LINE_PREFIX_SIZE: usize = "HTTP/1.1 200".len();
+
+    fn record(&mut self, data: &[u8]) {
+        let missing = Self::STATUS_LINE_PREFIX_SIZE.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&data[..missing.min(data.len())]);
+        self.bytes += data.len();
+    }
+
+    /// Status code of the relayed response, if it starts with a status line.
+    pub fn status_code(&self) -> Option<usize> {
+        let head = std::str::from_utf8(&self.head).ok()?;
+        let (_, code) = head.strip_prefix("HTTP/")?.split_once(' ')?;
+        code.get(..3)?.parse().ok()
+    }
+}
+
+/// Streams what the server sends on `upstream` to the `client` until it closes the connection.
+///
+/// Response head is held back until it is complete and passed on rewritten: hop-by-hop headers
+/// are dropped, the proxy is added to `Via` and the client is told the connection closes after
+/// the response. Response with a malformed head fails before anything is sent.
+/// `client` has to be the stream the `registry` watches, not its clone. Only one socket is watched
+/// at a time, the upstream one while waiting for data and the client one while its send buffer is full.
+/// Client is watched for reading again afterwards. Summary is returned even if relaying failed,
+/// so the caller knows whether anything was sent.
+pub fn relay(
+    registry: &mut Registry,
+    client: &TcpStream,
+    upstream: &mut TcpStream,
+    idle_timeout: Duration,
+) -> (Relayed, io::Result<()>) {
+    let mut relay = Relay {
+        registry,
+        client,
+        upstream,
+        timeout: TimeoutDuration::Finite(idle_timeout),
+        relayed: Relayed::default(),
+    };
+    let result = relay.watch_upstream().and_then(|_| relay.run());
+    /* registrations are swapped back even if relaying failed half way */
+    let _ = relay.registry.delete_interest(EventType::Read, relay.upstream.as_raw_fd());
+    let _ = relay.registry.delete_interest(EventType::Read, relay.client.as_raw_fd());
+    let restored = relay.registry.add_interest(EventType::Read, relay.client.as_raw_fd());
+    (relay.relayed, result.and(restored.map(|_| ())))
+}
+
+struct Relay<'a> {
+    registry: &'a mut Registry,
+    client: &'a TcpStream,
+    upstream: &'a mut TcpStream,
+    timeout: TimeoutDuration,
+    relayed: Relayed,
+}
+
+impl Relay<'_> {
+    const BUFFER_SIZE: usize = 16 * 1024;
+    /// Longest response head the server can send.
+    const MAX_HEAD_LEN: usize = 64 * 1024;
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut buffer = vec![0; Self::BUFFER_SIZE];
+        if !self.pass_head(&mut buffer)? {
+            return Ok(());
+        }
+        while let Some(count) = self.receive(&mut buffer)? {
+            self.pass(&buffer[..count])?;
+        }
+        Ok(())
+    }
+
+    /// Reads the response head and passes it on rewritten, with the part of the body read along with it.
+    /// `false` if the server closed the connection without sending anything.
+    fn pass_head(&mut self, buffer: &mut [u8]) -> io::Result<bool> {
+        let mut head = Vec::new();
+        loop {
+            let Some(count) = self.receive(buffer)? else {
+                return match head.is_empty() {
+                    true => Ok(false),
+                    false => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection in the response head")),
+                };
+            };
+            head.extend_from_slice(&buffer[..count]);
+            if let Some(separator) = Request::section_sep_pos(&head) {
+                let mut rewritten = rewrite_head(&head[..separator + CRLF.len()])?;
+                rewritten.extend_from_slice(&head[separator + Request::SECTION_SEP.len()..]);
+                self.pass(&rewritten)?;
+                return Ok(true);
+            }
+            if head.len() > Self::MAX_HEAD_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "response head is too long"));
+            }
+        }
+    }
+
+    /// Reads what the server sent, waiting while it has nothing to read. `None` once it closed the connection.
+    fn receive(&mut self, buffer: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            match self.upstream.read(buffer) {
+                Ok(0) => return Ok(None),
+                Ok(count) => return Ok(Some(count)),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.wait()?,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn pass(&mut self, data: &[u8]) -> io::Result<()> {
+        self.relayed.record(data);
+        self.send(data)
+    }
+
+    /// Writes all of the `data` to the client, watching it instead of the upstream while it cannot take more.
+    fn send(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let mut client = self.client;
+            match client.write(data) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(count) => data = &data[count..],
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.registry.delete_interest(EventType::Read, self.upstream.as_raw_fd())?;
+                    self.registry.add_interest(EventType::Write, self.client.as_raw_fd())?;
+                    let waited = self.wait();
+                    self.registry.delete_interest(EventType::Write, self.client.as_raw_fd())?;
+                    self.registry.add_interest(EventType::Read, self.upstream.as_raw_fd())?;
+                    waited?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn watch_upstream(&mut self) -> io::Result<()> {
+        self.registry.delete_interest(EventType::Read, self.client.as_raw_fd())?;
+        self.registry.add_interest(EventType::Read, self.upstream.as_raw_fd())?;
+        Ok(())
+    }
+
+    fn wait(&mut self) -> io::Result<()> {
+        match self.registry.await_event(&self.timeout)? {
+            Notification::Timeout => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            Notification::Event(..) => Ok(()),
+        }
+    }
+}
+
+/// Response `head`, the status line and headers each ending with CRLF, as passed on to the client.
+fn rewrite_head(head: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let head = std::str::from_utf8(head).map_err(|err| invalid(format!("response head is not valid utf-8: {err}")))?;
+    let (status_line, headers) = head.split_once(CRLF).ok_or_else(|| invalid(String::from("status line missing")))?;
+    let version = status_line
+        .split(' ')
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))
+        .ok_or_else(|| invalid(format!("invalid status line: {status_line}")))?;
+    let mut headers = Headers::parse::<RelayedHeaderParser>(headers).map_err(|err| invalid(err.to_string()))?;
+    /* body is not decoded, it has to keep the coding it was sent in */
+    let transfer_codings = headers.remove("Transfer-Encoding");
+    headers.retain_end_to_end();
+    headers.extend(transfer_codings);
+    headers.append(via(version));
+    headers.append(GeneralHeader::Connection(ConnectionType::Close));
+    Ok(format!("{status_line}{CRLF}{headers}{CRLF}").into_bytes())
+}
+
+/// Parser for relayed response headers. Only `Connection` is interpreted, for the headers it names,
+/// the rest are passed on as the server wrote them.
+#[derive(Default)]
+struct RelayedHeaderParser;
+
+impl HeaderParser for RelayedHeaderParser {
+    fn parse(&self, line: &str) -> Result<Header, ParseHeaderError> {
+        let (name, value) = Self::generic_parse(line).map_err(ParseHeaderError::from)?;
+        match GeneralHeader::parse(name, value) {
+            Ok(connection) => Ok(connection.into()),
+            Err(_) => Ok(Header::Unknown(name.to_owned().into(), value.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_absolute_targets() {
+        let upstream = Upstream::from_target("http://example.com:8080/a/b?c=d").unwrap();
+        assert_eq!(upstream.to_string(), "example.com:8080");
+        assert_eq!(upstream.path, "/a/b?c=d");
+        let upstream = Upstream::from_target("HTTP://example.com?q").unwrap();
+        assert_eq!((upstream.port, upstream.path.as_str()), (80, "/?q"));
+        let upstream = Upstream::from_target("http://[::1]:81").unwrap();
+        assert_eq!((upstream.address(), upstream.port, upstream.path.as_str()), ("::1", 81, "/"));
+        assert_eq!(Upstream::from_target("http://[::1]").unwrap().port, 80);
+        for target in ["/index.html", "https://example.com/", "http:///path", "http://user@example.com/", "http://host:port/"] {
+            assert_eq!(Upstream::from_target(target), None, "{target}");
+        }
+        let post = Request::fixture(Method::POST, "http://example.com/", "", None);
+        assert_eq!(Upstream::of(&post), None);
+    }
+
+    #[test]
+    fn test_request_is_rewritten_to_origin_form() {
+        let request = Request::fixture(
+            Method::GET,
+            "http://example.com:8080/page",
+            "Host: example.com:8080\r\nProxy-Connection: keep-alive\r\nConnection: keep-alive\r\nAccept: */*\r\n",
+            None,
+        );
+        let outgoing = Upstream::of(&request).unwrap().outgoing(&request, ProxyConfig::default());
+        assert_eq!(outgoing.to_string(), "example.com:8080");
+        assert_eq!(
+            String::from_utf8(outgoing.head).unwrap(),
+            "GET /page HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\nVia: 1.1 server\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_headers_named_in_connection_are_not_relayed() {
+        let request = Request::fixture(
+            Method::GET,
+            "http://example.com/page",
+            "Host: example.com\r\nConnection: keep-alive, X-Secret\r\nx-secret: 42\r\nAccept: */*\r\n",
+            None,
+        );
+        let outgoing = Upstream::of(&request).unwrap().outgoing(&request, ProxyConfig::default());
+        assert_eq!(
+            String::from_utf8(outgoing.head).unwrap(),
+            "GET /page HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nVia: 1.1 server\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_reverse_proxy_takes_turns() {
+        let upstreams = vec!["127.0.0.1:8001".parse().unwrap(), "127.0.0.1:8002".parse().unwrap(), "[::1]:8003".parse().unwrap()];
+        let proxy = ProxyHandler::new("/api", upstreams);
+        assert!(proxy.matches(Path::new("/api")) && proxy.matches(Path::new("/api/users")));
+        assert!(!proxy.matches(Path::new("/apis")) && !proxy.matches(Path::new("/")));
+        let request = Request::fixture(Method::GET, "/api/users", "Host: localhost\r\n", None);
+        let order = (0..4).map(|_| proxy.outgoing(&request, None).to_string()).collect::<Vec<_>>();
+        assert_eq!(order, [
+            "127.0.0.1:8001, 127.0.0.1:8002, ::1:8003",
+            "127.0.0.1:8002, ::1:8003, 127.0.0.1:8001",
+            "::1:8003, 127.0.0.1:8001, 127.0.0.1:8002",
+            "127.0.0.1:8001, 127.0.0.1:8002, ::1:8003",
+        ]);
+    }
+
+    #[test]
+    fn test_reverse_proxy_forwards_client_address() {
+        let proxy = ProxyHandler::new("/", vec!["127.0.0.1:8001".parse().unwrap()]);
+        let peer = "10.0.0.7".parse().ok();
+        let keep_alive = Request::fixture(Method::GET, "/index.html", "Host: localhost\r\nConnection: keep-alive\r\n", None);
+        assert_eq!(
+            String::from_utf8(proxy.outgoing(&keep_alive, peer).head).unwrap(),
+            "GET /index.html HTTP/1.1\r\nHost: localhost\r\nVia: 1.1 server\r\nX-Forwarded-For: 10.0.0.7\r\nConnection: close\r\n\r\n"
+        );
+        let forwarded = Request::fixture(Method::GET, "/index.html", "Host: localhost\r\nX-Forwarded-For: 192.168.1.2\r\n", None);
+        assert_eq!(
+            String::from_utf8(proxy.outgoing(&forwarded, peer).head).unwrap(),
+            "GET /index.html HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 192.168.1.2, 10.0.0.7\r\nVia: 1.1 server\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_request_goes_to_the_first_server_accepting_it() {
+        use std::net::TcpListener;
+
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = ProxyHandler::new("/", vec![dead, live.local_addr().unwrap()]);
+        let body = vec![b'x'; 4 * 1024 * 1024];
+        let request = Request::fixture(Method::POST, "/upload", "Host: localhost\r\n", Some(&body));
+        let origin = std::thread::spawn(move || {
+            let (mut stream, _) = live.accept().unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+        let clients = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(clients.local_addr().unwrap()).unwrap();
+        let (client, _) = clients.accept().unwrap();
+        let mut registry = Registry::new().unwrap();
+        registry.add_interest(EventType::Read, client.as_raw_fd()).unwrap();
+
+        /* body does not fit in the socket buffers, so sending it waits for the server to read */
+        let upstream = proxy.outgoing(&request, None).connect(&mut registry, &client).unwrap();
+        drop(upstream);
+        let received = origin.join().unwrap();
+        assert!(received.starts_with(b"POST /upload HTTP/1.1\r\n"));
+        assert!(received.ends_with(&body));
+        /* client is watched for reading again, so it cannot be added twice */
+        assert!(registry.add_interest(EventType::Read, client.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn test_sending_to_a_stalled_server_times_out() {
+        use std::net::TcpListener;
+
+        let stalled = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig { connect_timeout: Duration::from_millis(100), ..ProxyConfig::default() };
+        let proxy = ProxyHandler::new("/", vec![stalled.local_addr().unwrap()]).with_config(config);
+        let body = vec![b'x'; 64 * 1024 * 1024];
+        let request = Request::fixture(Method::POST, "/upload", "Host: localhost\r\n", Some(&body));
+        let clients = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(clients.local_addr().unwrap()).unwrap();
+        let (client, _) = clients.accept().unwrap();
+        let mut registry = Registry::new().unwrap();
+        registry.add_interest(EventType::Read, client.as_raw_fd()).unwrap();
+
+        /* server accepts the connection but never reads the request */
+        let err = proxy.outgoing(&request, None).connect(&mut registry, &client).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_status_code_of_relayed_response() {
+        let mut relayed = Relayed::default();
+        relayed.record(b"HTTP/1.1 2");
+        assert_eq!(relayed.status_code(), None);
+        relayed.record(b"04 No Content\r\n\r\n");
+        assert_eq!((relayed.status_code(), relayed.bytes), (Some(204), 27));
+    }
+
+    #[test]
+    fn test_response_head_is_rewritten() {
+        let rewrite = |head: &str| rewrite_head(head.as_bytes()).map(|head| String::from_utf8(head).unwrap());
+        assert_eq!(
+            rewrite("HTTP/1.1 200 OK\r\nConnection: keep-alive, X-Hop\r\nx-hop: 1\r\nKeep-Alive: timeout=5\r\n\
+                Transfer-Encoding: chunked\r\nContent-Type: text/plain\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n").unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n\
+                Transfer-Encoding: chunked\r\nVia: 1.1 server\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(
+            rewrite("HTTP/1.0 204 No Content\r\n").unwrap(),
+            "HTTP/1.0 204 No Content\r\nVia: 1.0 server\r\nConnection: close\r\n\r\n"
+        );
+        for head in ["garbage\r\n", "HTTP/1.1 200 OK\r\nno colon\r\n", "HTTP/1.1 200 OK"] {
+            assert_eq!(rewrite(head).unwrap_err().kind(), io::ErrorKind::InvalidData, "{head:?}");
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Mikołaj Depta 328690
+//!
+//! Disk quota of the catalog, for files written by uploads.
+//!
+//! Space taken by the files of the catalog may be limited with `SERVER_UPLOAD_QUOTA`, eg. `512MiB`.
+//! `StaticWriter` reserves space as the body of an upload is written and fails with `StorageFull`,
+//! answered with 507, once the upload would exceed the quota. Replaced and deleted files give
+//! their space back.
+//!
+//! Usage counted this way drifts from the disk when files are changed by anyone but the server,
+//! so the catalog is measured again every `SERVER_UPLOAD_QUOTA_INTERVAL` minutes, 10 by default.
+//! Measurements are timed by a registry timer the server watches together with its listening sockets.
+//!
+//! Spooled request bodies have a quota of their own, see `spool`. Server writes nothing else
+//! to disk: compressed variants are prepared by the operator and resources are cached in memory,
+//! see `cache`.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::units;
+use crate::logger::{log, Level};
+
+/// Space taken by the files below a directory, shared by everything that writes to it.
+#[derive(Debug, Clone)]
+pub struct DiskQuota {
+    directory: Arc<Path>,
+    limit: usize,
+    interval: Duration,
+    used: Arc<AtomicUsize>,
+}
+
+impl DiskQuota {
+    pub const ENV_VARIABLE: &'static str = "SERVER_UPLOAD_QUOTA";
+    pub const INTERVAL_ENV_VARIABLE: &'static str = "SERVER_UPLOAD_QUOTA_INTERVAL";
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+    /// Quota of `limit` bytes for the `directory`, measured right away and every `interval`.
+    pub fn new(directory: Arc<Path>, limit: usize, interval: Duration) -> Self {
+        let quota = Self { directory, limit, interval, used: Arc::new(AtomicUsize::new(0)) };
+        if let Err(err) = quota.reconcile() {
+            log!(Level::Warn, "could not measure {}: {}, its usage starts at 0", quota.directory.display(), err);
+        }
+        quota
+    }
+
+    /// Quota of the `directory` set with `SERVER_UPLOAD_QUOTA`, `None` if the variable is not set or invalid.
+    /// Invalid interval is replaced with the default one.
+    pub fn from_env(directory: Arc<Path>) -> Option<Self> {
+        let repr = env::var(Self::ENV_VARIABLE).ok()?;
+        let limit = units::parse_size(&repr)
+            .inspect_err(|err| log!(Level::Warn, "{}: invalid size '{}': {}", Self::ENV_VARIABLE, repr, err))
+            .ok()?;
+        let interval = match env::var(Self::INTERVAL_ENV_VARIABLE) {
+            Err(_) => Self::DEFAULT_INTERVAL,
+            Ok(repr) => match repr.trim().parse::<u64>() {
+                Ok(minutes) if minutes > 0 => Duration::from_secs(minutes * 60),
+                _ => {
+                    log!(
+                        Level::Warn, "{}: expected a positive number of minutes, got '{}', using {:?}",
+                        Self::INTERVAL_ENV_VARIABLE, repr, Self::DEFAULT_INTERVAL
+                    );
+                    Self::DEFAULT_INTERVAL
+                }
+            },
+        };
+        Some(Self::new(directory, limit, interval))
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Bytes taken, as last measured and adjusted by the writes since.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Counts `length` more bytes, fails with `StorageFull` if that would exceed the quota.
+    pub fn reserve(&self, length: usize) -> io::Result<()> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(length).filter(|&total| total <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|used| io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("{length} bytes exceed quota of {} of {} bytes, {used} bytes are in use", self.directory.display(), self.limit),
+            ))
+    }
+
+    /// Gives back `length` bytes of files that were removed or never finished.
+    pub fn release(&self, length: usize) {
+        /* usage measured in the meantime may already leave the file out */
+        let _ = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| Some(used.saturating_sub(length)));
+    }
+
+    /// Measures the directory again and replaces the counted usage with the result.
+    pub fn reconcile(&self) -> io::Result<usize> {
+        let measured = Self::measure(&self.directory)?;
+        self.used.store(measured, Ordering::Release);
+        Ok(measured)
+    }
+
+    /// Total length of the files below `directory`. Symbolic links are not followed, what they
+    /// point to is counted where it is, if at all.
+    fn measure(directory: &Path) -> io::Result<usize> {
+        let mut total = 0usize;
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                /* removed after the directory was listed */
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let length = match metadata.is_dir() {
+                true => Self::measure(&entry.path())?,
+                false if metadata.is_file() => metadata.len() as usize,
+                false => 0,
+            };
+            total = total.saturating_add(length);
+        }
+        Ok(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::TempDir;
+
+    #[test]
+    fn test_quota_counts_reservations_until_measured_again() {
+        let dir = TempDir::new("server-quota").unwrap();
+        dir.create_file("localhost/index.html", b"0123456789").unwrap();
+        dir.create_file("example.com/a/b.txt", b"01234").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("localhost/index.html"), dir.path().join("link.html")).unwrap();
+        let quota = DiskQuota::new(Arc::from(dir.path()), 20, Duration::from_secs(60));
+        assert_eq!(quota.used(), 15);
+
+        quota.reserve(5).unwrap();
+        let err = quota.reserve(1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(quota.used(), 20);
+        quota.release(8);
+        assert_eq!(quota.used(), 12);
+
+        /* files changed behind the back of the server are noticed by the next measurement */
+        fs::remove_file(dir.path().join("localhost/index.html")).unwrap();
+        assert_eq!(quota.reconcile().unwrap(), 5);
+        quota.release(10);
+        assert_eq!(quota.used(), 0);
+    }
+}
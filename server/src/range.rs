@@ -0,0 +1,148 @@
+//! Mikołaj Depta 328690
+//!
+//! Byte range requests for files, see RFC 9110 section 14.
+//!
+//! GET request with a single `Range: bytes=first-last` range is answered with 206 Partial Content
+//! and that part of the file, range starting past the end of the file with 416. Requests for several
+//! ranges at once and ranges the server does not understand are answered with the whole file,
+//! which the RFC allows.
+//!
+//! Client resuming a download sends `If-Range` with the entity tag of the part it already has,
+//! the range is only honored if the file still has that tag, otherwise the whole new file is sent.
+//! The server sends no `Last-Modified`, so a date in `If-Range` never matches.
+
+use crate::http::common::Method;
+use crate::http::headers::response_header::{ContentRange, EntityTag};
+use crate::http::request::Request;
+
+pub const RANGE_HEADER: &str = "Range";
+pub const IF_RANGE_HEADER: &str = "If-Range";
+const BYTES_UNIT: &str = "bytes";
+
+/// Bytes from `first` to `last` inclusive.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ByteRange {
+    first: usize,
+    last: usize,
+}
+
+impl ByteRange {
+    pub fn offset(&self) -> usize {
+        self.first
+    }
+
+    pub fn len(&self) -> usize {
+        self.last - self.first + 1
+    }
+
+    /// `Content-Range` of the part of the representation of `len` bytes.
+    pub fn content_range(&self, len: usize) -> ContentRange {
+        ContentRange::new(self.first, self.last, len)
+    }
+}
+
+/// Part of the representation the request is answered with.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Selection {
+    Whole,
+    Part(ByteRange),
+    /// Range lies past the end of the representation.
+    Unsatisfiable,
+}
+
+/// Part of the representation of `len` bytes with the entity `tag` the `request` asks for.
+pub fn select(request: &Request, tag: &EntityTag, len: usize) -> Selection {
+    if request.start_line().method() != &Method::GET {
+        return Selection::Whole;
+    }
+    let Some(range) = request.headers().unknown(RANGE_HEADER) else {
+        return Selection::Whole;
+    };
+    match request.headers().unknown(IF_RANGE_HEADER) {
+        Some(validator) if !is_current(validator, tag) => Selection::Whole,
+        _ => parse(range, len),
+    }
+}
+
+/// Validator of `If-Range` identifies the current representation, only strong entity tags do.
+fn is_current(validator: &str, tag: &EntityTag) -> bool {
+    validator.trim() == tag.to_string()
+}
+
+fn parse(range: &str, len: usize) -> Selection {
+    let Some((unit, spec)) = range.trim().split_once('=') else {
+        return Selection::Whole;
+    };
+    if !unit.trim().eq_ignore_ascii_case(BYTES_UNIT) || spec.contains(',') {
+        return Selection::Whole;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Selection::Whole;
+    };
+    let position = |repr: &str| repr.bytes().all(|byte| byte.is_ascii_digit()).then(|| repr.parse::<usize>().ok()).flatten();
+    let (first, last) = match (first.trim(), last.trim()) {
+        ("", "") => return Selection::Whole,
+        /* last `suffix` bytes */
+        ("", suffix) => match position(suffix) {
+            Some(0) => return Selection::Unsatisfiable,
+            Some(suffix) => (len.saturating_sub(suffix), usize::MAX),
+            None => return Selection::Whole,
+        },
+        (first, "") => match position(first) {
+            Some(first) => (first, usize::MAX),
+            None => return Selection::Whole,
+        },
+        (first, last) => match (position(first), position(last)) {
+            (Some(first), Some(last)) if first <= last => (first, last),
+            _ => return Selection::Whole,
+        },
+    };
+    if first >= len {
+        return Selection::Unsatisfiable;
+    }
+    Selection::Part(ByteRange { first, last: last.min(len - 1) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(first: usize, last: usize) -> Selection {
+        Selection::Part(ByteRange { first, last })
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("bytes=0-99", 1000), part(0, 99));
+        assert_eq!(parse("bytes=900-", 1000), part(900, 999));
+        assert_eq!(parse("bytes=-100", 1000), part(900, 999));
+        assert_eq!(parse("bytes=-5000", 1000), part(0, 999));
+        assert_eq!(parse("Bytes = 990 - 5000", 1000), part(990, 999));
+        assert_eq!(parse("bytes=1000-", 1000), Selection::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 1000), Selection::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Selection::Unsatisfiable);
+        for ignored in ["bytes=0-9,20-29", "bytes=9-0", "bytes=-", "bytes=+1-2", "items=0-9", "bytes 0-9"] {
+            assert_eq!(parse(ignored, 1000), Selection::Whole, "{ignored}");
+        }
+    }
+
+    #[test]
+    fn test_if_range() {
+        let tag = EntityTag::new(String::from("1-2-3"));
+        let select = |headers: &str| select(&Request::fixture(Method::GET, "/movie.mp4", headers, None), &tag, 1000);
+        assert_eq!(select("Range: bytes=0-9\r\nIf-Range: \"1-2-3\"\r\n"), part(0, 9));
+        assert_eq!(select("Range: bytes=0-9\r\nIf-Range: \"1-2-4\"\r\n"), Selection::Whole);
+        assert_eq!(select("Range: bytes=0-9\r\nIf-Range: W/\"1-2-3\"\r\n"), Selection::Whole);
+        assert_eq!(select("Range: bytes=0-9\r\nIf-Range: Wed, 21 Oct 2015 07:28:00 GMT\r\n"), Selection::Whole);
+        /* stale validator wins over a range that could not be satisfied anyway */
+        assert_eq!(select("Range: bytes=5000-\r\nIf-Range: \"0-0-0\"\r\n"), Selection::Whole);
+        assert_eq!(select("If-Range: \"1-2-3\"\r\n"), Selection::Whole);
+    }
+
+    #[test]
+    fn test_only_get_requests_are_ranged() {
+        let tag = EntityTag::new(String::from("1-2-3"));
+        assert_eq!(select(&Request::fixture(Method::HEAD, "/movie.mp4", "Range: bytes=0-9\r\n", None), &tag, 1000), Selection::Whole);
+        assert_eq!(select(&Request::fixture(Method::GET, "/movie.mp4", "Range: bytes=0-9\r\n", None), &tag, 1000), part(0, 9));
+    }
+}
@@ -0,0 +1,178 @@
+//! Mikołaj Depta 328690
+//!
+//! Token bucket rate limiting of requests keyed by the peer IP address.
+//!
+//! Every address has a bucket holding up to `requests` tokens that refills at `requests`
+//! per `period`. Each request takes a token, request arriving at an empty bucket is answered
+//! with 429 Too Many Requests and told how long to wait in `Retry-After`.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use common::units::{self, ParseUnitError};
+use crate::logger::{log, Level};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RateLimitConfig {
+    /// Number of requests allowed in a burst and refilled every `period`.
+    pub requests: u32,
+    pub period: Duration,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseRateLimitError {
+    MissingSeparator(String),
+    InvalidCount(String),
+    InvalidPeriod(ParseUnitError),
+}
+
+impl Display for ParseRateLimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingSeparator(repr) => write!(f, "expected <requests>/<period>, eg. 20/1s, got {repr}"),
+            Self::InvalidCount(repr) => write!(f, "invalid number of requests: {repr}"),
+            Self::InvalidPeriod(err) => write!(f, "invalid period: {err}"),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub const ENV_VARIABLE: &'static str = "SERVER_RATE_LIMIT";
+
+    /// Limiting is enabled by setting `SERVER_RATE_LIMIT` to `<requests>/<period>`, eg. `100/1m`.
+    pub fn from_env() -> Option<Self> {
+        let repr = env::var(Self::ENV_VARIABLE).ok()?;
+        match Self::try_from(repr.as_str()) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                log!(Level::Warn, "{}: invalid rate limit '{}': {}", Self::ENV_VARIABLE, repr, err);
+                None
+            }
+        }
+    }
+
+    fn tokens_per_second(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl TryFrom<&str> for RateLimitConfig {
+    type Error = ParseRateLimitError;
+
+    fn try_from(repr: &str) -> Result<Self, Self::Error> {
+        let (requests, period) = repr
+            .split_once('/')
+            .ok_or_else(|| ParseRateLimitError::MissingSeparator(repr.to_owned()))?;
+        let requests = match requests.trim().parse() {
+            Ok(0) | Err(_) => return Err(ParseRateLimitError::InvalidCount(requests.trim().to_owned())),
+            Ok(requests) => requests,
+        };
+        let period = units::parse_duration(period).map_err(ParseRateLimitError::InvalidPeriod)?;
+        if period.is_zero() {
+            return Err(ParseRateLimitError::InvalidPeriod(ParseUnitError::InvalidNumber(repr.to_owned())));
+        }
+        Ok(Self { requests, period })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Buckets of idle addresses are dropped once there are more than this many.
+    const PRUNE_THRESHOLD: usize = 4096;
+
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self { config, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Takes a token for the request from `peer`, `Err` holds the time until one is available.
+    pub fn check(&self, peer: IpAddr) -> Result<(), Duration> {
+        self.check_at(peer, Instant::now())
+    }
+
+    fn check_at(&self, peer: IpAddr, now: Instant) -> Result<(), Duration> {
+        let capacity = self.config.requests as f64;
+        let rate = self.config.tokens_per_second();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        if buckets.len() >= Self::PRUNE_THRESHOLD {
+            /* full buckets carry no state, they are the same as a fresh one */
+            buckets.retain(|_, bucket| Self::refilled(bucket, now, rate, capacity) < capacity);
+        }
+        let bucket = buckets.entry(peer).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = Self::refilled(bucket, now, rate, capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    fn refilled(bucket: &Bucket, now: Instant, rate: f64, capacity: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * rate).min(capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            RateLimitConfig::try_from("100/1m"),
+            Ok(RateLimitConfig { requests: 100, period: Duration::from_secs(60) })
+        );
+        assert_eq!(
+            RateLimitConfig::try_from(" 5 / 500ms "),
+            Ok(RateLimitConfig { requests: 5, period: Duration::from_millis(500) })
+        );
+        assert!(matches!(RateLimitConfig::try_from("100"), Err(ParseRateLimitError::MissingSeparator(_))));
+        assert!(matches!(RateLimitConfig::try_from("0/1s"), Err(ParseRateLimitError::InvalidCount(_))));
+        assert!(matches!(RateLimitConfig::try_from("x/1s"), Err(ParseRateLimitError::InvalidCount(_))));
+        assert!(matches!(RateLimitConfig::try_from("1/s"), Err(ParseRateLimitError::InvalidPeriod(_))));
+        assert!(matches!(RateLimitConfig::try_from("1/0s"), Err(ParseRateLimitError::InvalidPeriod(_))));
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let limiter = RateLimiter::new(RateLimitConfig { requests: 2, period: Duration::from_secs(1) });
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let start = Instant::now();
+        assert_eq!(limiter.check_at(peer, start), Ok(()));
+        assert_eq!(limiter.check_at(peer, start), Ok(()));
+        assert_eq!(limiter.check_at(peer, start), Err(Duration::from_millis(500)));
+        /* other addresses have their own buckets */
+        assert_eq!(limiter.check_at(other, start), Ok(()));
+        let later = start + Duration::from_millis(250);
+        assert_eq!(limiter.check_at(peer, later), Err(Duration::from_millis(250)));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(peer, later), Ok(()));
+        /* bucket never holds more than the burst */
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(limiter.check_at(peer, much_later), Ok(()));
+        assert_eq!(limiter.check_at(peer, much_later), Ok(()));
+        assert!(limiter.check_at(peer, much_later).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Mikołaj Depta 328690
+//!
+//! Plain HTTP listener that sends clients to the `https://` equivalent of the URL they asked for.
+//!
+//! The server does not terminate TLS itself, HTTPS is served by a terminating proxy in front of it.
+//! With `SERVER_HTTPS_REDIRECT` set to the port HTTPS is served on, eg. `443`, every request is
+//! answered with `301 Moved Permanently` to the same host, path and query over HTTPS. Only hosts
+//! of the virtual host table are redirected, so arbitrary `Host` headers cannot turn the server
+//! into an open redirector.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::logger::{log, Level};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HttpsRedirect {
+    port: u16,
+}
+
+impl HttpsRedirect {
+    pub const ENV_VARIABLE: &'static str = "SERVER_HTTPS_REDIRECT";
+    const DEFAULT_PORT: u16 = 443;
+
+    /// Redirects to HTTPS served on `port`, the port is left out of the URLs if it is the default one.
+    pub fn new(port: u16) -> Self {
+        Self { port }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        let repr = env::var(Self::ENV_VARIABLE).ok()?;
+        match repr.trim().parse::<u16>() {
+            Ok(port) if port != 0 => Some(Self::new(port)),
+            _ => {
+                log!(Level::Warn, "{}: invalid port '{}', requests are not redirected", Self::ENV_VARIABLE, repr);
+                None
+            }
+        }
+    }
+
+    /// URL of the `target`, path with the query, on `host` over HTTPS.
+    pub fn location(&self, host: &str, target: &Path) -> PathBuf {
+        let target = target.to_string_lossy();
+        /* asterisk form of OPTIONS has no HTTPS equivalent other than the root */
+        let target = if target.starts_with('/') { target.as_ref() } else { "/" };
+        match self.port {
+            Self::DEFAULT_PORT => PathBuf::from(format!("https://{host}{target}")),
+            port => PathBuf::from(format!("https://{host}:{port}{target}")),
+        }
+    }
+}
+
+impl Default for HttpsRedirect {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_PORT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_keeps_host_path_and_query() {
+        let redirect = HttpsRedirect::default();
+        assert_eq!(redirect.location("lab.example", Path::new("/docs/a b.html?page=2")), Path::new("https://lab.example/docs/a b.html?page=2"));
+        assert_eq!(redirect.location("lab.example", Path::new("*")), Path::new("https://lab.example/"));
+        assert_eq!(HttpsRedirect::new(8443).location("localhost", Path::new("/")), Path::new("https://localhost:8443/"));
+    }
+}
@@ -0,0 +1,59 @@
+//! Mikołaj Depta 328690
+//!
+//! Reload requests delivered with SIGHUP.
+//!
+//! Accepting server receives SIGHUP through its registry, see `shutdown`, and only bumps the
+//! reload generation. Components holding state that should be dropped on reload remember the
+//! generation they last saw and compare it with `generation` the next time they are used.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Number of reloads requested so far.
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+/// Requests reload as if SIGHUP was received.
+pub fn request() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Watches for reloads requested since the last check.
+#[derive(Debug)]
+pub struct ReloadWatch {
+    seen: AtomicU64,
+}
+
+impl ReloadWatch {
+    pub fn new() -> Self {
+        Self { seen: AtomicU64::new(generation()) }
+    }
+
+    /// `true` once for every batch of reloads requested since the previous call.
+    pub fn reloaded(&self) -> bool {
+        let current = generation();
+        self.seen.swap(current, Ordering::Relaxed) != current
+    }
+}
+
+impl Default for ReloadWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requested_reload_is_noticed() {
+        let watch = ReloadWatch::new();
+        let before = generation();
+        request();
+        assert!(generation() > before);
+        assert!(watch.reloaded());
+    }
+}
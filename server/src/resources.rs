@@ -2,46 +2,377 @@
 //!
 //! Abstractions for working with server resources.
 
-use crate::util;
+use common::fs::{Fs, RealFs};
+use crate::vhost::VirtualHosts;
+use crate::logger::{log, Level};
+use crate::quota::DiskQuota;
+use crate::http::headers::entity_header::ContentCoding;
+use crate::http::headers::response_header::EntityTag;
 use std::collections::HashSet;
-use std::fs;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, Metadata, OpenOptions};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[non_exhaustive]
 #[derive(Debug, Clone)]
 pub enum LoadResourceError {
     NotFound(PathBuf),
+    PermissionDenied(PathBuf),
+    Io(PathBuf, io::ErrorKind),
+    /// Resource kept changing while it was being read.
+    Unstable(PathBuf),
+}
+
+impl Display for LoadResourceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(path) => write!(f, "{} not found", path.display()),
+            Self::PermissionDenied(path) => write!(f, "permission to read {} denied", path.display()),
+            Self::Io(path, kind) => write!(f, "could not read {}: {}", path.display(), kind),
+            Self::Unstable(path) => write!(f, "{} kept changing while it was read", path.display()),
+        }
+    }
+}
+
+/// Identifies a single version of the file.
+/// File replaced by another one or modified in place gets a different version.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct Version {
+    inode: u64,
+    len: u64,
+    modified_ns: i128,
+}
+
+impl Version {
+    pub fn entity_tag(&self) -> EntityTag {
+        EntityTag::new(format!("{:x}-{:x}-{:x}", self.inode, self.len, self.modified_ns))
+    }
+
+    /// Version of `data` that is not backed by a file and never changes, eg. a bundled one.
+    /// Hash of the contents stands in for the inode.
+    pub fn of_content(data: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        Self { inode: hasher.finish(), len: data.len() as u64, modified_ns: 0 }
+    }
+}
+
+impl From<&Metadata> for Version {
+    fn from(metadata: &Metadata) -> Self {
+        let modified_ns = metadata.mtime() as i128 * 1_000_000_000 + metadata.mtime_nsec() as i128;
+        Self { inode: metadata.ino(), len: metadata.size(), modified_ns }
+    }
+}
+
+/// Contents of the resource together with the version of the file they were read from.
+pub struct Resource {
+    pub data: Box<[u8]>,
+    pub version: Version,
+}
+
+/// Resource that is sent straight from the open file instead of being loaded into memory.
+pub struct OpenResource {
+    pub file: Arc<File>,
+    pub version: Version,
+}
+
+impl OpenResource {
+    pub fn len(&self) -> usize {
+        self.version.len as usize
+    }
 }
 
 pub trait ResourceLoader {
     type LoadError;
 
-    fn load(&self, resource: &Path) -> Result<Box<[u8]>, Self::LoadError>;
+    /// Loads the resource, returned `version` always describes exactly the returned `data`.
+    fn load(&self, resource: &Path) -> Result<Resource, Self::LoadError>;
+
+    /// Opens the resource if it should be streamed, `None` means it should be `load`ed instead.
+    fn open(&self, _resource: &Path) -> Result<Option<OpenResource>, Self::LoadError> {
+        Ok(None)
+    }
+
+    /// Variant of the resource compressed with `coding` stored next to it, eg. `app.js.gz`
+    /// for `app.js`, if there is one. It is loaded like any other resource.
+    fn precompressed(&self, _resource: &Path, _coding: ContentCoding) -> Option<PathBuf> {
+        None
+    }
+
+    /// Resource is a directory, whose index document is served instead.
+    fn is_directory(&self, resource: &Path) -> bool {
+        resource.is_dir()
+    }
 }
 
-pub struct StaticLoader {
-    catalog: Rc<Path>,
+pub struct StaticLoader<F: Fs = RealFs> {
+    catalog: Arc<Path>,
+    fs: F,
+    stream_threshold: Option<u64>,
 }
 
 impl StaticLoader {
-    pub fn new(catalog: Rc<Path>) -> Self {
-        Self { catalog }
+    pub fn new(catalog: Arc<Path>) -> Self {
+        Self::with_fs(catalog, RealFs)
     }
 }
 
-impl ResourceLoader for StaticLoader {
-    type LoadError = LoadResourceError;
+impl<F: Fs> StaticLoader<F> {
+    const MAX_READ_ATTEMPTS: usize = 3;
+    const DEFAULT_STREAM_THRESHOLD: u64 = 64 * 1024;
+
+    pub fn with_fs(catalog: Arc<Path>, fs: F) -> Self {
+        Self { catalog, fs, stream_threshold: Some(Self::DEFAULT_STREAM_THRESHOLD) }
+    }
 
-    fn load(&self, resource: &Path) -> Result<Box<[u8]>, Self::LoadError> {
+    /// Files of at least `threshold` bytes are streamed, `None` loads every file into memory.
+    pub fn with_stream_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.stream_threshold = threshold;
+        self
+    }
+
+    fn classify(resource: &Path, err: io::Error) -> LoadResourceError {
         use std::io::ErrorKind;
-        match fs::read(self.catalog.join(resource)) {
-            Ok(data) => Ok(data.into_boxed_slice()),
-            Err(err) if err.kind() == ErrorKind::NotFound => {
-                Err(LoadResourceError::NotFound(resource.to_owned()))
+        match err.kind() {
+            ErrorKind::NotFound => LoadResourceError::NotFound(resource.to_owned()),
+            ErrorKind::PermissionDenied => LoadResourceError::PermissionDenied(resource.to_owned()),
+            kind => LoadResourceError::Io(resource.to_owned(), kind),
+        }
+    }
+}
+
+impl<F: Fs> ResourceLoader for StaticLoader<F> {
+    type LoadError = LoadResourceError;
+
+    /// File is read between two metadata lookups, if they differ the file was modified
+    /// during the read and the data may be a mix of both versions, so the read is repeated.
+    fn load(&self, resource: &Path) -> Result<Resource, Self::LoadError> {
+        // Resource may disappear or change permissions between validation and loading,
+        // so none of those errors can be treated as fatal.
+        let path = self.catalog.join(resource);
+        let metadata = |path: &Path| {
+            self.fs.metadata(path).map(|metadata| Version::from(&metadata))
+        };
+        for _ in 0..Self::MAX_READ_ATTEMPTS {
+            let before = metadata(&path).map_err(|err| Self::classify(resource, err))?;
+            let data = self.fs.read(&path).map_err(|err| Self::classify(resource, err))?;
+            let after = metadata(&path).map_err(|err| Self::classify(resource, err))?;
+            if before == after && data.len() as u64 == after.len {
+                return Ok(Resource { data: data.into_boxed_slice(), version: after });
             }
-            Err(err) => util::fail_with_message(err.to_string().as_ref()),
+            log!(Level::Debug, "{} changed while being read, retrying", resource.display());
+        }
+        log!(Level::Warn, "{} kept changing, giving up after {} reads", resource.display(), Self::MAX_READ_ATTEMPTS);
+        Err(LoadResourceError::Unstable(resource.to_owned()))
+    }
+
+    /// Version is read from the open descriptor, so it describes the file that will be sent
+    /// even if the path is replaced in the meantime.
+    fn open(&self, resource: &Path) -> Result<Option<OpenResource>, Self::LoadError> {
+        let Some(threshold) = self.stream_threshold else {
+            return Ok(None);
+        };
+        let path = self.catalog.join(resource);
+        let metadata = self.fs.metadata(&path).map_err(|err| Self::classify(resource, err))?;
+        if metadata.len() < threshold {
+            return Ok(None);
         }
+        /* sending from the descriptor requires a real file, so streamed files bypass `fs` */
+        let file = File::open(&path).map_err(|err| Self::classify(resource, err))?;
+        let metadata = file.metadata().map_err(|err| Self::classify(resource, err))?;
+        Ok(Some(OpenResource { file: Arc::new(file), version: Version::from(&metadata) }))
+    }
+
+    fn precompressed(&self, resource: &Path, coding: ContentCoding) -> Option<PathBuf> {
+        let mut name = resource.file_name()?.to_owned();
+        name.push(".");
+        name.push(coding.extension());
+        let variant = resource.with_file_name(name);
+        let metadata = self.fs.metadata(&self.catalog.join(&variant)).ok()?;
+        metadata.is_file().then_some(variant)
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub enum WriteResourceError {
+    NotFound(PathBuf),
+    PermissionDenied(PathBuf),
+    /// Directories are neither written nor removed.
+    IsDirectory(PathBuf),
+    Io(PathBuf, io::ErrorKind),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WriteOutcome {
+    Created,
+    Replaced,
+}
+
+/// Counterpart of `ResourceLoader` for requests that change resources.
+pub trait ResourceWriter {
+    type WriteError;
+
+    /// Creates the resource, or replaces all of its contents, with everything read from the `body`.
+    fn put(&self, resource: &Path, body: &mut dyn Read) -> Result<WriteOutcome, Self::WriteError>;
+
+    fn delete(&self, resource: &Path) -> Result<(), Self::WriteError>;
+}
+
+/// Writes files of the catalog, used for `PUT` and `DELETE` requests once enabled with
+/// `SERVER_ENABLE_UPLOADS=1`. Body is written to a temporary file next to the resource
+/// which then replaces it, so the resource is never seen partially written and a failed
+/// upload leaves the previous contents in place. Missing directories are not created.
+/// Written files may be counted towards a quota of the catalog, see `quota`.
+pub struct StaticWriter<F: Fs = RealFs> {
+    catalog: Arc<Path>,
+    fs: F,
+    quota: Option<DiskQuota>,
+}
+
+impl StaticWriter {
+    pub const ENV_VARIABLE: &'static str = "SERVER_ENABLE_UPLOADS";
+
+    pub fn new(catalog: Arc<Path>) -> Self {
+        Self::with_fs(catalog, RealFs)
+    }
+
+    /// Writer of the `catalog` if the operator enabled uploads.
+    pub fn from_env(catalog: Arc<Path>) -> Option<Self> {
+        env::var(Self::ENV_VARIABLE)
+            .is_ok_and(|value| value.trim() == "1")
+            .then(|| Self::new(catalog))
+    }
+}
+
+impl<F: Fs> StaticWriter<F> {
+    pub fn with_fs(catalog: Arc<Path>, fs: F) -> Self {
+        Self { catalog, fs, quota: None }
+    }
+
+    /// Uploads that would take more space than `quota` leaves fail with `StorageFull`.
+    pub fn with_quota(mut self, quota: DiskQuota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Dotfile in the directory of `path`, so it is not served while it is being written.
+    fn temporary_path(path: &Path) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+        path.with_file_name(format!(".{}.{}-{}.upload", name, process::id(), counter))
+    }
+
+    /// Fails unless something other than a directory, or nothing at all, is at `path`.
+    /// Length of the resource if it exists.
+    fn existing(&self, resource: &Path, path: &Path) -> Result<Option<u64>, WriteResourceError> {
+        match self.fs.metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Err(WriteResourceError::IsDirectory(resource.to_owned())),
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Self::classify(resource, err)),
+        }
+    }
+
+    /// Gives back the space of a file that is gone.
+    fn release(&self, length: u64) {
+        if let Some(quota) = &self.quota {
+            quota.release(length as usize);
+        }
+    }
+
+    fn classify(resource: &Path, err: io::Error) -> WriteResourceError {
+        use std::io::ErrorKind;
+        match err.kind() {
+            ErrorKind::NotFound => WriteResourceError::NotFound(resource.to_owned()),
+            ErrorKind::PermissionDenied => WriteResourceError::PermissionDenied(resource.to_owned()),
+            ErrorKind::IsADirectory => WriteResourceError::IsDirectory(resource.to_owned()),
+            kind => WriteResourceError::Io(resource.to_owned(), kind),
+        }
+    }
+}
+
+impl<F: Fs> ResourceWriter for StaticWriter<F> {
+    type WriteError = WriteResourceError;
+
+    fn put(&self, resource: &Path, body: &mut dyn Read) -> Result<WriteOutcome, Self::WriteError> {
+        let path = self.catalog.join(resource);
+        let existing = self.existing(resource, &path)?;
+        let temporary = Self::temporary_path(&path);
+        let mut space = UploadSpace { credit: existing.unwrap_or(0), reserved: 0 };
+        let replaced = self.fs
+            .open(&temporary, OpenOptions::new().write(true).create_new(true))
+            .and_then(|file| {
+                let mut file = QuotaWriter { inner: file, quota: self.quota.as_ref(), space: &mut space };
+                io::copy(body, &mut file).and_then(|_| file.flush())
+            })
+            .and_then(|_| self.fs.rename(&temporary, &path));
+        if let Err(err) = replaced {
+            /* nothing was created if opening failed, removal fails then and changes nothing */
+            let _ = self.fs.remove_file(&temporary);
+            self.release(space.reserved);
+            return Err(Self::classify(resource, err));
+        }
+        /* part of the replaced file the new contents did not take over */
+        self.release(space.credit);
+        Ok(if existing.is_some() { WriteOutcome::Replaced } else { WriteOutcome::Created })
+    }
+
+    fn delete(&self, resource: &Path) -> Result<(), Self::WriteError> {
+        let path = self.catalog.join(resource);
+        let Some(length) = self.existing(resource, &path)? else {
+            return Err(WriteResourceError::NotFound(resource.to_owned()));
+        };
+        self.fs.remove_file(&path).map_err(|err| Self::classify(resource, err))?;
+        self.release(length);
+        Ok(())
+    }
+}
+
+/// Space an upload takes from the quota. Contents up to the length of the file it replaces
+/// take over the space of that file, the rest is reserved as it is written.
+struct UploadSpace {
+    credit: u64,
+    reserved: u64,
+}
+
+/// File of an upload, space for every write is taken from the `quota` before it is made.
+struct QuotaWriter<'a, W> {
+    inner: W,
+    quota: Option<&'a DiskQuota>,
+    space: &'a mut UploadSpace,
+}
+
+impl<W: Write> Write for QuotaWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let covered = (buf.len() as u64).min(self.space.credit);
+        let needed = buf.len() - covered as usize;
+        if let Some(quota) = self.quota {
+            quota.reserve(needed)?;
+        }
+        let result = self.inner.write(buf);
+        let count = *result.as_ref().unwrap_or(&0) as u64;
+        let credited = count.min(covered);
+        let reserved = count - credited;
+        if let Some(quota) = self.quota {
+            quota.release(needed - reserved as usize);
+        }
+        self.space.credit -= credited;
+        self.space.reserved += reserved;
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
@@ -50,60 +381,174 @@ impl ResourceLoader for StaticLoader {
 pub enum ValidationResourceError {
     OutdatedResourcePath(PathBuf),
     UnauthorizedResourceAccess(PathBuf),
+    NotFound(PathBuf),
+}
+
+impl Display for ValidationResourceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutdatedResourcePath(path) => write!(f, "{} is a directory", path.display()),
+            Self::UnauthorizedResourceAccess(path) => write!(f, "{} is outside of the document root", path.display()),
+            Self::NotFound(path) => write!(f, "{} not found", path.display()),
+        }
+    }
 }
 
 pub trait ResourceValidator {
     type ValidationError;
 
     fn validate(&self, resource_path: &Path) -> Result<(), Self::ValidationError>;
+
+    /// Replaces the directories resources may come from, eg. once virtual hosts were reloaded.
+    fn set_domains(&self, _domains: Domains) {}
+}
+
+pub type Domains = Arc<HashSet<PathBuf>>;
+
+/// Which symbolic links within document roots are followed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Path of the resource must not go through any link below the document root.
+    Never,
+    /// Links are followed as long as the resource stays within the document root of its host.
+    #[default]
+    SameRoot,
+    /// Links are followed anywhere, for document roots assembled from links by the operator.
+    Always,
+}
+
+impl SymlinkPolicy {
+    pub const ENV_VARIABLE: &'static str = "SERVER_SYMLINKS";
+    const NEVER_REPR: &'static str = "never";
+    const SAME_ROOT_REPR: &'static str = "same-root";
+    const ALWAYS_REPR: &'static str = "always";
+
+    /// Policy from `SERVER_SYMLINKS`, `SameRoot` if it is not set or invalid.
+    pub fn from_env() -> Self {
+        let Ok(repr) = env::var(Self::ENV_VARIABLE) else {
+            return Self::default();
+        };
+        repr.parse().unwrap_or_else(|err| {
+            log!(Level::Warn, "{}: {}, links are followed within the document root", Self::ENV_VARIABLE, err);
+            Self::default()
+        })
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = ParseSymlinkPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            Self::NEVER_REPR => Ok(Self::Never),
+            Self::SAME_ROOT_REPR => Ok(Self::SameRoot),
+            Self::ALWAYS_REPR => Ok(Self::Always),
+            _ => Err(ParseSymlinkPolicyError(s.to_owned())),
+        }
+    }
 }
 
-pub type Domains = Rc<HashSet<PathBuf>>;
+#[derive(Debug, Eq, PartialEq)]
+pub struct ParseSymlinkPolicyError(String);
 
-pub struct StaticValidator {
-    catalog: Rc<Path>,
-    domains: Domains,
+impl Display for ParseSymlinkPolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected one of 'never', 'same-root' or 'always', got '{}'", self.0)
+    }
+}
+
+pub struct StaticValidator<F: Fs = RealFs> {
+    catalog: Arc<Path>,
+    domains: RwLock<Domains>,
+    fs: F,
+    symlinks: SymlinkPolicy,
 }
 
 impl StaticValidator {
-    pub fn new(catalog: Rc<Path>, domains: Domains) -> Self {
-        Self { catalog, domains }
+    pub fn new(catalog: Arc<Path>, domains: Domains) -> Self {
+        Self::with_fs(catalog, domains, RealFs)
     }
 
-    pub fn default_config(catalog: Rc<Path>) -> Self {
-        let mut base_dir = PathBuf::from(&catalog);
-        let directories = HashSet::from(
-            ["localhost", "lab108-18"].map(|domain| {
-                let mut domain_dir = base_dir.clone();
-                domain_dir.push(domain);
-                domain_dir
-            })
-        );
-        Self { catalog, domains: Rc::new(directories) }
+    pub fn default_config(catalog: Arc<Path>) -> Self {
+        let hosts = VirtualHosts::default_config(&catalog);
+        Self::from_virtual_hosts(catalog, &hosts)
+    }
+
+    /// Validator that allows access to document roots of all `hosts`.
+    pub fn from_virtual_hosts(catalog: Arc<Path>, hosts: &VirtualHosts) -> Self {
+        let directories = hosts.roots().map(Path::to_path_buf).collect();
+        Self::new(catalog, Arc::new(directories))
+    }
+}
+
+impl<F: Fs> StaticValidator<F> {
+    pub fn with_fs(catalog: Arc<Path>, domains: Domains, fs: F) -> Self {
+        Self { catalog, domains: RwLock::new(domains), fs, symlinks: SymlinkPolicy::default() }
+    }
+
+    pub fn with_symlink_policy(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Fails if any component of the `resource_path` below the `domain` is a symbolic link.
+    /// Link anywhere on the way could point outside, even if the resource path does not.
+    fn check_no_links(&self, domain: &Path, resource_path: &Path) -> Result<(), ValidationResourceError> {
+        let below_domain = resource_path.ancestors().take_while(|ancestor| *ancestor != domain);
+        for component in below_domain {
+            match self.fs.symlink_metadata(component) {
+                Ok(metadata) if metadata.is_symlink() => {
+                    log!(Level::Info, "refused to follow link {}", component.display());
+                    return Err(ValidationResourceError::UnauthorizedResourceAccess(resource_path.to_owned()));
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Err(ValidationResourceError::NotFound(resource_path.to_owned()));
+                }
+                Err(_) => return Err(ValidationResourceError::UnauthorizedResourceAccess(resource_path.to_owned())),
+            }
+        }
+        Ok(())
     }
 }
 
-impl ResourceValidator for StaticValidator {
+impl<F: Fs> ResourceValidator for StaticValidator<F> {
     type ValidationError = ValidationResourceError;
 
+    /// Resource has to lie within the document root of one of the `domains`. Where it may end up
+    /// after following symbolic links depends on the `SymlinkPolicy`.
     fn validate(&self, resource_path: &Path) -> Result<(), Self::ValidationError>  {
-        if self.domains.contains(resource_path) {
+        let domains = self.domains.read().unwrap().clone();
+        if domains.contains(resource_path) {
             return Err(ValidationResourceError::OutdatedResourcePath(
                 resource_path.to_owned(),
             ));
         }
-        return match resource_path.canonicalize() {
+        let containing: Vec<_> = domains.iter().filter(|domain| resource_path.starts_with(domain)).collect();
+        /* innermost document root, if they are nested */
+        let Some(domain) = containing.iter().max_by_key(|domain| domain.components().count()) else {
+            return Err(ValidationResourceError::UnauthorizedResourceAccess(resource_path.to_owned()));
+        };
+        if self.symlinks == SymlinkPolicy::Never {
+            self.check_no_links(domain, resource_path)?;
+        }
+        match self.fs.canonicalize(resource_path) {
+            Ok(_) if self.symlinks == SymlinkPolicy::Always => Ok(()),
             Ok(absolute_path) => {
-                for domain in self.domains {
-                    let mut domain_path = self.catalog.clone();
-                    domain_path.push(domain);
-                    if absolute_path.starts_with(domain_path) {
-                        return Ok(());
-                    }
+                let is_within_domain = containing
+                    .iter()
+                    .filter_map(|domain| self.fs.canonicalize(domain).ok())
+                    .any(|domain_path| absolute_path.starts_with(domain_path));
+                if is_within_domain {
+                    Ok(())
+                } else {
+                    Err(ValidationResourceError::UnauthorizedResourceAccess(
+                        resource_path.to_owned(),
+                    ))
                 }
-                Err(ValidationResourceError::UnauthorizedResourceAccess(
-                    resource_path.to_owned(),
-                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(ValidationResourceError::NotFound(resource_path.to_owned()))
             }
             Err(_) => {
                 Err(ValidationResourceError::UnauthorizedResourceAccess(
@@ -112,4 +557,291 @@ impl ResourceValidator for StaticValidator {
             }
         }
     }
+
+    fn set_domains(&self, domains: Domains) {
+        *self.domains.write().unwrap() = domains;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::{Fault, FaultyFs, Operation, RealFs, TempDir, Trigger};
+    use std::cell::Cell;
+    use std::fs::{self, File, OpenOptions};
+    use std::time::{Duration, SystemTime};
+
+    /// Rewrites the file after it was read, `rewrites` times - simulates a concurrent writer.
+    struct RacingFs {
+        rewrites: Cell<usize>,
+        content: &'static [u8],
+    }
+
+    impl RacingFs {
+        fn rewrite(path: &Path, content: &[u8], generation: usize) {
+            fs::write(path, content).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + generation as u64);
+            File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+        }
+    }
+
+    impl Fs for RacingFs {
+        type File = File;
+
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            let data = RealFs.read(path)?;
+            if self.rewrites.get() > 0 {
+                self.rewrites.set(self.rewrites.get() - 1);
+                Self::rewrite(path, self.content, self.rewrites.get());
+            }
+            Ok(data)
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+            RealFs.metadata(path)
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+            RealFs.symlink_metadata(path)
+        }
+
+        fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+            RealFs.canonicalize(path)
+        }
+
+        fn open(&self, path: &Path, options: &OpenOptions) -> io::Result<Self::File> {
+            RealFs.open(path, options)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            RealFs.rename(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            RealFs.remove_file(path)
+        }
+    }
+
+    fn catalog() -> (TempDir, Arc<Path>, Domains) {
+        let dir = TempDir::new("server-resources").unwrap();
+        dir.create_file("localhost/index.html", b"<html></html>").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let domains = Arc::new(HashSet::from([catalog.join("localhost")]));
+        (dir, catalog, domains)
+    }
+
+    #[test]
+    fn test_load_permission_denied() {
+        let (_dir, catalog, _) = catalog();
+        let fs = FaultyFs::new([Fault::permission_denied(Operation::Read)]);
+        let loader = StaticLoader::with_fs(catalog.clone(), fs);
+        let resource = catalog.join("localhost/index.html");
+        assert!(matches!(loader.load(&resource), Err(LoadResourceError::PermissionDenied(_))));
+    }
+
+    #[test]
+    fn test_resource_removed_after_validation() {
+        let (_dir, catalog, domains) = catalog();
+        let resource = catalog.join("localhost/index.html");
+        let validator = StaticValidator::new(catalog.clone(), domains);
+        let loader = StaticLoader::with_fs(catalog, FaultyFs::new([Fault::not_found(Operation::Read)]));
+        assert!(validator.validate(&resource).is_ok());
+        assert!(matches!(loader.load(&resource), Err(LoadResourceError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_load_other_io_error() {
+        let (_dir, catalog, _) = catalog();
+        let fs = FaultyFs::new([
+            Fault::new(Operation::Read, io::ErrorKind::Interrupted).with_trigger(Trigger::Nth(1))
+        ]);
+        let loader = StaticLoader::with_fs(catalog.clone(), fs);
+        let resource = catalog.join("localhost/index.html");
+        assert!(matches!(
+            loader.load(&resource),
+            Err(LoadResourceError::Io(_, io::ErrorKind::Interrupted))
+        ));
+        assert_eq!(loader.load(&resource).unwrap().data.as_ref(), b"<html></html>");
+    }
+
+    #[test]
+    fn test_missing_resource_is_not_found() {
+        let (_dir, catalog, domains) = catalog();
+        let validator = StaticValidator::new(catalog.clone(), domains);
+        assert!(matches!(
+            validator.validate(&catalog.join("localhost/missing.html")),
+            Err(ValidationResourceError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_canonicalize_failure_is_unauthorized() {
+        let (_dir, catalog, domains) = catalog();
+        let resource = catalog.join("localhost/index.html");
+        let fs = FaultyFs::new([Fault::permission_denied(Operation::Canonicalize).with_path(&resource)]);
+        let validator = StaticValidator::with_fs(catalog, domains, fs);
+        assert!(matches!(
+            validator.validate(&resource),
+            Err(ValidationResourceError::UnauthorizedResourceAccess(_))
+        ));
+    }
+
+    #[test]
+    fn test_symlink_policies() {
+        let (dir, catalog, _) = catalog();
+        dir.create_file("localhost/sub/page.html", b"").unwrap();
+        dir.create_file("lab108-18/other.html", b"").unwrap();
+        dir.create_file("secret", b"").unwrap();
+        let root = catalog.join("localhost");
+        let link = |target: &Path, name: &str| std::os::unix::fs::symlink(target, root.join(name)).unwrap();
+        link(&root.join("index.html"), "inner.html");
+        link(&root.join("sub"), "linked");
+        link(&catalog.join("lab108-18/other.html"), "cross.html");
+        link(&catalog.join("secret"), "outside");
+        let domains = Arc::new(HashSet::from([root.clone(), catalog.join("lab108-18")]));
+        let validate = |symlinks: SymlinkPolicy, resource: &str| {
+            StaticValidator::new(catalog.clone(), domains.clone())
+                .with_symlink_policy(symlinks)
+                .validate(&root.join(resource))
+        };
+        let unauthorized = |result| matches!(result, Err(ValidationResourceError::UnauthorizedResourceAccess(_)));
+
+        assert!(validate(SymlinkPolicy::Never, "sub/page.html").is_ok());
+        assert!(unauthorized(validate(SymlinkPolicy::Never, "inner.html")));
+        assert!(unauthorized(validate(SymlinkPolicy::Never, "linked/page.html")));
+        assert!(matches!(validate(SymlinkPolicy::Never, "missing.html"), Err(ValidationResourceError::NotFound(_))));
+
+        assert!(validate(SymlinkPolicy::SameRoot, "inner.html").is_ok());
+        assert!(validate(SymlinkPolicy::SameRoot, "linked/page.html").is_ok());
+        assert!(unauthorized(validate(SymlinkPolicy::SameRoot, "cross.html")));
+        assert!(unauthorized(validate(SymlinkPolicy::SameRoot, "outside")));
+
+        for resource in ["inner.html", "linked/page.html", "cross.html", "outside"] {
+            assert!(validate(SymlinkPolicy::Always, resource).is_ok(), "{resource}");
+        }
+    }
+
+    #[test]
+    fn test_parse_symlink_policy() {
+        assert_eq!("never".parse(), Ok(SymlinkPolicy::Never));
+        assert_eq!(" Same-Root".parse(), Ok(SymlinkPolicy::SameRoot));
+        assert_eq!("always".parse(), Ok(SymlinkPolicy::Always));
+        assert_eq!("sometimes".parse::<SymlinkPolicy>(), Err(ParseSymlinkPolicyError(String::from("sometimes"))));
+    }
+
+    fn current_version(path: &Path) -> Version {
+        Version::from(&fs::metadata(path).unwrap())
+    }
+
+    #[test]
+    fn test_modified_during_read_is_reread() {
+        let (_dir, catalog, _) = catalog();
+        let resource = catalog.join("localhost/index.html");
+        let loader = StaticLoader::with_fs(catalog, RacingFs { rewrites: Cell::new(1), content: b"<html>v2</html>" });
+        let loaded = loader.load(&resource).unwrap();
+        assert_eq!(loaded.data.as_ref(), b"<html>v2</html>");
+        assert_eq!(loaded.version, current_version(&resource));
+    }
+
+    #[test]
+    fn test_same_size_rewrite_changes_entity_tag() {
+        let (_dir, catalog, _) = catalog();
+        let resource = catalog.join("localhost/index.html");
+        let loader = StaticLoader::new(catalog);
+        let first = loader.load(&resource).unwrap();
+        RacingFs::rewrite(&resource, b"<HTML></HTML>", 7);
+        let second = loader.load(&resource).unwrap();
+        assert_eq!(first.data.len(), second.data.len());
+        assert_ne!(first.data, second.data);
+        assert_ne!(first.version.entity_tag(), second.version.entity_tag());
+    }
+
+    #[test]
+    fn test_constantly_modified_resource_is_not_served() {
+        let (_dir, catalog, _) = catalog();
+        let resource = catalog.join("localhost/index.html");
+        let fs = RacingFs { rewrites: Cell::new(100), content: b"<html>v2</html>" };
+        let loader = StaticLoader::with_fs(catalog, fs);
+        assert!(matches!(loader.load(&resource), Err(LoadResourceError::Unstable(_))));
+    }
+
+    #[test]
+    fn test_removed_during_read_is_not_served() {
+        let (_dir, catalog, _) = catalog();
+        let resource = catalog.join("localhost/index.html");
+        let fs = FaultyFs::new([Fault::not_found(Operation::Metadata).with_trigger(Trigger::Nth(2))]);
+        let loader = StaticLoader::with_fs(catalog, fs);
+        assert!(matches!(loader.load(&resource), Err(LoadResourceError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_only_large_resources_are_opened() {
+        let (dir, catalog, _) = catalog();
+        dir.create_file("localhost/large.bin", &[1; 1024]).unwrap();
+        let loader = StaticLoader::new(catalog.clone()).with_stream_threshold(Some(1024));
+        assert!(loader.open(&catalog.join("localhost/index.html")).unwrap().is_none());
+        let opened = loader.open(&catalog.join("localhost/large.bin")).unwrap().unwrap();
+        assert_eq!(opened.len(), 1024);
+        assert_eq!(opened.version, current_version(&catalog.join("localhost/large.bin")));
+        assert!(matches!(loader.open(&catalog.join("localhost/missing.bin")), Err(LoadResourceError::NotFound(_))));
+
+        let loader = loader.with_stream_threshold(None);
+        assert!(loader.open(&catalog.join("localhost/large.bin")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_put_creates_and_replaces_files() {
+        let (_dir, catalog, _) = catalog();
+        let writer = StaticWriter::new(catalog.clone());
+        let resource = catalog.join("localhost/notes.txt");
+        assert_eq!(writer.put(&resource, &mut &b"first"[..]).unwrap(), WriteOutcome::Created);
+        assert_eq!(writer.put(&resource, &mut &b"second"[..]).unwrap(), WriteOutcome::Replaced);
+        assert_eq!(fs::read(&resource).unwrap(), b"second");
+        assert!(matches!(writer.put(&catalog.join("localhost/missing/notes.txt"), &mut &b""[..]), Err(WriteResourceError::NotFound(_))));
+        assert!(matches!(writer.put(&catalog.join("localhost"), &mut &b""[..]), Err(WriteResourceError::IsDirectory(_))));
+        /* only the resource itself is left behind */
+        assert_eq!(fs::read_dir(catalog.join("localhost")).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_failed_put_keeps_previous_contents() {
+        let (_dir, catalog, _) = catalog();
+        let writer = StaticWriter::with_fs(catalog.clone(), FaultyFs::new([Fault::disk_full().with_trigger(Trigger::ByteLimit(4))]));
+        let resource = catalog.join("localhost/index.html");
+        assert!(matches!(writer.put(&resource, &mut &b"<p>replaced</p>"[..]), Err(WriteResourceError::Io(_, io::ErrorKind::StorageFull))));
+        assert_eq!(fs::read(&resource).unwrap(), b"<html></html>");
+        assert_eq!(fs::read_dir(catalog.join("localhost")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_failed_put_gives_its_space_back() {
+        let (_dir, catalog, _) = catalog();
+        let quota = DiskQuota::new(catalog.clone(), 100, std::time::Duration::from_secs(60));
+        assert_eq!(quota.used(), 13);
+        let writer = StaticWriter::with_fs(catalog.clone(), FaultyFs::new([Fault::disk_full().with_trigger(Trigger::ByteLimit(4))]))
+            .with_quota(quota.clone());
+        let resource = catalog.join("localhost/notes.txt");
+        assert!(writer.put(&resource, &mut &b"never written"[..]).is_err());
+        assert_eq!(quota.used(), 13);
+
+        let writer = StaticWriter::new(catalog.clone()).with_quota(quota.clone());
+        let err = writer.put(&resource, &mut &[0; 88][..]).unwrap_err();
+        assert!(matches!(err, WriteResourceError::Io(_, io::ErrorKind::StorageFull)));
+        assert!(!resource.exists());
+        assert_eq!((quota.used(), fs::read_dir(catalog.join("localhost")).unwrap().count()), (13, 1));
+    }
+
+    #[test]
+    fn test_delete_removes_only_files() {
+        let (dir, catalog, _) = catalog();
+        dir.create_file("localhost/docs/old.txt", b"old").unwrap();
+        let writer = StaticWriter::new(catalog.clone());
+        writer.delete(&catalog.join("localhost/docs/old.txt")).unwrap();
+        assert!(!catalog.join("localhost/docs/old.txt").exists());
+        assert!(matches!(writer.delete(&catalog.join("localhost/docs/old.txt")), Err(WriteResourceError::NotFound(_))));
+        assert!(matches!(writer.delete(&catalog.join("localhost/docs")), Err(WriteResourceError::IsDirectory(_))));
+        let writer = StaticWriter::with_fs(catalog.clone(), FaultyFs::new([Fault::permission_denied(Operation::Remove)]));
+        assert!(matches!(writer.delete(&catalog.join("localhost/index.html")), Err(WriteResourceError::PermissionDenied(_))));
+        assert!(catalog.join("localhost/index.html").exists());
+    }
 }
@@ -0,0 +1,233 @@
+//! Mikołaj Depta 328690
+//!
+//! Redirects of moved content, declared in a rewrite map.
+//!
+//! Map is a file named by `SERVER_REWRITE_MAP`, one rule per line, empty lines and `#` comments
+//! are ignored:
+//!
+//! ```text
+//! # <pattern> <target> [301|302|307]
+//! /old.html   /new.html
+//! /docs/*     /manual/*                302
+//! /blog/*     https://blog.example/*   307
+//! ```
+//!
+//! Pattern is either an exact path or a prefix ending with `*`, whatever the star matched
+//! replaces the star of the target. Rules are tried in order, the first matching one wins.
+//! Query of the request is kept unless the target has its own. Status defaults to 301.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::http::response::StatusCode;
+use crate::logger::{log, Level};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum RedirectStatus {
+    Permanent,
+    Found,
+    Temporary,
+}
+
+impl RedirectStatus {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Permanent => StatusCode::MovedPermanently,
+            Self::Found => StatusCode::Found,
+            Self::Temporary => StatusCode::TemporaryRedirect,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct RewriteRule {
+    /// Path, or its prefix if the pattern ended with `*`.
+    pattern: String,
+    is_prefix: bool,
+    target: String,
+    status: RedirectStatus,
+}
+
+impl RewriteRule {
+    /// Location the `path` is moved to, if the rule covers it.
+    fn apply(&self, path: &str) -> Option<String> {
+        let matched = if self.is_prefix {
+            path.strip_prefix(self.pattern.as_str())?
+        } else if path == self.pattern {
+            ""
+        } else {
+            return None;
+        };
+        Some(self.target.replacen('*', matched, 1))
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RewriteMap {
+    rules: Vec<RewriteRule>,
+}
+
+impl RewriteMap {
+    pub const ENV_VARIABLE: &'static str = "SERVER_REWRITE_MAP";
+
+    /// Map from the file named by `SERVER_REWRITE_MAP`, empty if there is none or it is invalid.
+    pub fn from_env() -> Self {
+        let Some(path) = env::var_os(Self::ENV_VARIABLE) else {
+            return Self::default();
+        };
+        match Self::load(Path::new(&path)) {
+            Ok(map) => map,
+            Err(err) => {
+                log!(Level::Warn, "{}: {}, requests are not rewritten", Self::ENV_VARIABLE, err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, LoadRewriteMapError> {
+        let repr = fs::read_to_string(path).map_err(LoadRewriteMapError::Io)?;
+        repr.parse().map_err(LoadRewriteMapError::Parse)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Status and location of the redirect for the request `target`, path with the query.
+    pub fn redirect(&self, target: &Path) -> Option<(StatusCode, PathBuf)> {
+        let target = target.to_str()?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+        self.rules.iter().find_map(|rule| {
+            let location = rule.apply(path)?;
+            let location = match query {
+                Some(query) if !location.contains('?') => format!("{location}?{query}"),
+                _ => location,
+            };
+            Some((rule.status.status_code(), PathBuf::from(location)))
+        })
+    }
+
+    fn is_target(repr: &str) -> bool {
+        let path = ["http://", "https://"]
+            .iter()
+            .find_map(|scheme| repr.strip_prefix(scheme))
+            .map_or(repr, |rest| rest.find('/').map_or("/", |index| &rest[index..]));
+        path.starts_with('/') && repr.matches('*').count() <= 1
+    }
+}
+
+impl std::str::FromStr for RewriteMap {
+    type Err = ParseRewriteMapError;
+
+    fn from_str(repr: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (index, line) in repr.lines().enumerate() {
+            let number = index + 1;
+            let line = line.split('#').next().unwrap_or_default();
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            let (pattern, target, status) = match tokens[..] {
+                [] => continue,
+                [pattern, target] => (pattern, target, None),
+                [pattern, target, status] => (pattern, target, Some(status)),
+                _ => return Err(ParseRewriteMapError::MalformedRule(number, line.trim().to_owned())),
+            };
+            let (pattern, is_prefix) = match pattern.strip_suffix('*') {
+                Some(prefix) => (prefix, true),
+                None => (pattern, false),
+            };
+            if !pattern.starts_with('/') || pattern.contains('*') {
+                return Err(ParseRewriteMapError::InvalidPattern(number, pattern.to_owned()));
+            }
+            if !Self::is_target(target) {
+                return Err(ParseRewriteMapError::InvalidTarget(number, target.to_owned()));
+            }
+            let status = match status {
+                None | Some("301") => RedirectStatus::Permanent,
+                Some("302") => RedirectStatus::Found,
+                Some("307") => RedirectStatus::Temporary,
+                Some(other) => return Err(ParseRewriteMapError::UnsupportedStatus(number, other.to_owned())),
+            };
+            rules.push(RewriteRule { pattern: pattern.to_owned(), is_prefix, target: target.to_owned(), status });
+        }
+        Ok(Self { rules })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseRewriteMapError {
+    MalformedRule(usize, String),
+    InvalidPattern(usize, String),
+    InvalidTarget(usize, String),
+    UnsupportedStatus(usize, String),
+}
+
+impl Display for ParseRewriteMapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MalformedRule(line, repr) => write!(f, "line {line}: expected '<pattern> <target> [<status>]', got '{repr}'"),
+            Self::InvalidPattern(line, repr) => write!(f, "line {line}: pattern has to be a path, optionally ending with '*', got '{repr}'"),
+            Self::InvalidTarget(line, repr) => write!(f, "line {line}: target has to be a path or an http(s) URL, got '{repr}'"),
+            Self::UnsupportedStatus(line, repr) => write!(f, "line {line}: expected status 301, 302 or 307, got '{repr}'"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadRewriteMapError {
+    Io(io::Error),
+    Parse(ParseRewriteMapError),
+}
+
+impl Display for LoadRewriteMapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read rewrite map: {err}"),
+            Self::Parse(err) => write!(f, "invalid rewrite map: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(map: &RewriteMap, target: &str) -> Option<(usize, String)> {
+        map.redirect(Path::new(target)).map(|(status, location)| (status.code(), location.display().to_string()))
+    }
+
+    #[test]
+    fn test_rules() {
+        let map: RewriteMap = "# moved content\n\
+            /old.html /new.html\n\
+            /docs/* /manual/* 302\n\
+            /docs/legacy /gone.html\n\
+            /blog/* https://blog.example/*?from=site 307\n"
+            .parse()
+            .unwrap();
+        assert_eq!(redirect(&map, "/old.html"), Some((301, "/new.html".to_owned())));
+        assert_eq!(redirect(&map, "/old.html?x=1"), Some((301, "/new.html?x=1".to_owned())));
+        assert_eq!(redirect(&map, "/old.htm"), None);
+        /* the first matching rule wins */
+        assert_eq!(redirect(&map, "/docs/legacy"), Some((302, "/manual/legacy".to_owned())));
+        assert_eq!(redirect(&map, "/docs/"), Some((302, "/manual/".to_owned())));
+        assert_eq!(redirect(&map, "/blog/2024/post?page=2"), Some((307, "https://blog.example/2024/post?from=site".to_owned())));
+        assert_eq!(redirect(&map, "/index.html"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!("/a".parse::<RewriteMap>(), Err(ParseRewriteMapError::MalformedRule(1, "/a".to_owned())));
+        assert_eq!("\nold /new".parse::<RewriteMap>(), Err(ParseRewriteMapError::InvalidPattern(2, "old".to_owned())));
+        assert_eq!("/a*b /c".parse::<RewriteMap>(), Err(ParseRewriteMapError::InvalidPattern(1, "/a*b".to_owned())));
+        assert_eq!("/a ftp://host/".parse::<RewriteMap>(), Err(ParseRewriteMapError::InvalidTarget(1, "ftp://host/".to_owned())));
+        assert_eq!("/a /b 308".parse::<RewriteMap>(), Err(ParseRewriteMapError::UnsupportedStatus(1, "308".to_owned())));
+        assert!("https://host".parse::<RewriteMap>().is_err());
+        assert!("/a https://host".parse::<RewriteMap>().unwrap().redirect(Path::new("/a")).is_some());
+    }
+}
@@ -0,0 +1,129 @@
+//! Mikołaj Depta 328690
+//!
+//! Dispatching of requests to handlers registered for paths.
+//!
+//! Handlers are registered either for an exact path or for a path prefix. Exact match wins,
+//! otherwise the longest matching prefix is used. Requests matching no route are served
+//! from the document roots.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::http::request::Request;
+use crate::http::response::Response;
+
+/// Produces responses for requests routed to it.
+///
+/// Handlers are shared by all threads serving connections, so they take `&self`
+/// and keep mutable state behind synchronization of their choice.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Router {
+    exact: HashMap<String, Arc<dyn Handler>>,
+    prefixes: Vec<(String, Arc<dyn Handler>)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes requests for exactly `path` to `handler`.
+    pub fn exact(mut self, path: &str, handler: impl Handler + 'static) -> Self {
+        self.exact.insert(path.to_owned(), Arc::new(handler));
+        self
+    }
+
+    /// Routes requests for `prefix` and everything below it to `handler`.
+    /// Prefix matches whole path segments, `/api` matches `/api/users` but not `/apiary`.
+    pub fn prefix(mut self, prefix: &str, handler: impl Handler + 'static) -> Self {
+        self.prefixes.retain(|(registered, _)| registered != prefix);
+        self.prefixes.push((prefix.to_owned(), Arc::new(handler)));
+        /* longest prefix first, so the first match is the most specific one */
+        self.prefixes.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.prefixes.is_empty()
+    }
+
+    /// Handler responsible for `path`, the target without its query, if any.
+    pub fn find(&self, path: &Path) -> Option<&dyn Handler> {
+        self.route(path).map(|(_, handler)| handler)
+    }
+
+    /// Path or prefix the matching handler was registered for, together with the handler.
+    pub fn route(&self, path: &Path) -> Option<(&str, &dyn Handler)> {
+        let path = path.to_str()?;
+        if let Some((route, handler)) = self.exact.get_key_value(path) {
+            return Some((route, handler.as_ref()));
+        }
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| Self::is_below(path, prefix))
+            .map(|(prefix, handler)| (prefix.as_str(), handler.as_ref()))
+    }
+
+    /// `path` is the `prefix` itself or lies below it, prefixes match whole path segments only.
+    pub fn is_below(path: &str, prefix: &str) -> bool {
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::common::Method;
+    use crate::http::entity::Entity;
+    use crate::http::headers::entity_header::ContentType;
+    use crate::http::response::StatusCode;
+
+    fn named(name: &'static str) -> impl Handler {
+        move |_: &Request| {
+            let entity = Entity::new(Box::from(name.as_bytes()), ContentType::Txt);
+            Response::builder(StatusCode::Ok).with_entity(entity).build()
+        }
+    }
+
+    fn routed(router: &Router, path: &str) -> Option<String> {
+        let request = Request::fixture(Method::GET, path, "", None);
+        let response = router.find(Path::new(path))?.handle(&request);
+        let response = String::from_utf8_lossy(response.as_ref()).into_owned();
+        Some(response.rsplit("\r\n").next().unwrap().to_owned())
+    }
+
+    #[test]
+    fn test_exact_and_prefix_routes() {
+        let router = Router::new()
+            .exact("/status", named("status"))
+            .prefix("/api", named("api"))
+            .prefix("/api/v2/", named("v2"))
+            .exact("/api/health", named("health"));
+        assert_eq!(routed(&router, "/status").as_deref(), Some("status"));
+        assert_eq!(routed(&router, "/status/more"), None);
+        assert_eq!(routed(&router, "/api").as_deref(), Some("api"));
+        assert_eq!(routed(&router, "/api/users").as_deref(), Some("api"));
+        assert_eq!(routed(&router, "/api/v2/users").as_deref(), Some("v2"));
+        assert_eq!(routed(&router, "/api/health").as_deref(), Some("health"));
+        assert_eq!(routed(&router, "/apiary"), None);
+        assert_eq!(routed(&router, "/index.html"), None);
+    }
+}
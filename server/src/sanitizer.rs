@@ -0,0 +1,404 @@
+//! Mikołaj Depta 328690
+//!
+//! Mapping of request targets to filesystem paths that cannot escape the document root.
+//!
+//! Targets matching one of the deny patterns, eg. dotfiles or editor backups, are refused
+//! as if they did not exist, so responses do not reveal what is present in the document root.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use common::fs::{Fs, RealFs};
+use crate::resources::SymlinkPolicy;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SanitizeError {
+    /// Target refers to something outside of the document root or is malformed.
+    Forbidden(PathBuf),
+    /// Target is a valid path within the document root but nothing exists there.
+    NotFound(PathBuf),
+    /// Target matches a deny pattern, it has to be reported the same way as `NotFound`.
+    Denied(PathBuf),
+}
+
+impl Display for SanitizeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Forbidden(path) => write!(f, "access to {} is forbidden", path.display()),
+            Self::NotFound(path) => write!(f, "{} does not exist", path.display()),
+            Self::Denied(path) => write!(f, "{} matches a deny pattern", path.display()),
+        }
+    }
+}
+
+/// Glob matched against a single path segment, `*` matches any sequence of characters
+/// and `?` a single one. Pattern ending with `/` only matches directories on the way
+/// to the target, eg. `.git/` matches `/.git/config` but not a file named `.git`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DenyPattern {
+    glob: String,
+    directory_only: bool,
+}
+
+impl DenyPattern {
+    pub fn new(pattern: &str) -> Self {
+        match pattern.strip_suffix('/') {
+            Some(glob) => Self { glob: glob.to_owned(), directory_only: true },
+            None => Self { glob: pattern.to_owned(), directory_only: false },
+        }
+    }
+
+    fn matches(&self, segment: &str, is_last: bool) -> bool {
+        !(self.directory_only && is_last) && Self::glob_matches(self.glob.as_bytes(), segment.as_bytes())
+    }
+
+    fn glob_matches(glob: &[u8], text: &[u8]) -> bool {
+        match (glob.split_first(), text.split_first()) {
+            (None, None) => true,
+            (Some((b'*', rest)), _) => {
+                Self::glob_matches(rest, text) || (!text.is_empty() && Self::glob_matches(glob, &text[1..]))
+            }
+            (Some((b'?', rest)), Some((_, text))) => Self::glob_matches(rest, text),
+            (Some((expected, rest)), Some((actual, text))) => expected == actual && Self::glob_matches(rest, text),
+            _ => false,
+        }
+    }
+}
+
+impl Display for DenyPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.glob, if self.directory_only { "/" } else { "" })
+    }
+}
+
+/// Deny patterns applied to every segment of the normalized target.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DenyList {
+    patterns: Vec<DenyPattern>,
+}
+
+impl DenyList {
+    pub const ENV_VARIABLE: &'static str = "SERVER_DENY_PATTERNS";
+    const DEFAULT_PATTERNS: [&'static str; 4] = [".*", ".git/", "*.bak", "*~"];
+
+    pub fn new(patterns: impl IntoIterator<Item=DenyPattern>) -> Self {
+        Self { patterns: patterns.into_iter().collect() }
+    }
+
+    /// Nothing is denied.
+    pub fn empty() -> Self {
+        Self::new([])
+    }
+
+    /// Comma separated patterns in `SERVER_DENY_PATTERNS` replace the defaults,
+    /// set it to an empty string to deny nothing.
+    pub fn from_env() -> Self {
+        match env::var(Self::ENV_VARIABLE) {
+            Ok(repr) => Self::new(
+                repr.split(',').map(str::trim).filter(|pattern| !pattern.is_empty()).map(DenyPattern::new)
+            ),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn patterns(&self) -> &[DenyPattern] {
+        &self.patterns
+    }
+
+    /// Whether any segment of the normalized `path` matches one of the patterns.
+    pub fn is_denied(&self, path: &Path) -> bool {
+        let segments = path.iter().filter_map(|segment| segment.to_str()).collect::<Vec<_>>();
+        segments.iter().enumerate().any(|(index, segment)| {
+            let is_last = index + 1 == segments.len();
+            self.patterns.iter().any(|pattern| pattern.matches(segment, is_last))
+        })
+    }
+}
+
+/// Dotfiles, which includes `.git` and `.htaccess`, and editor backups.
+impl Default for DenyList {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_PATTERNS.map(DenyPattern::new))
+    }
+}
+
+pub struct PathSanitizer<F: Fs = RealFs> {
+    fs: F,
+    deny_list: DenyList,
+    /// Paths are only normalized, not resolved on the filesystem.
+    lexical: bool,
+    symlinks: SymlinkPolicy,
+}
+
+impl PathSanitizer {
+    pub fn new() -> Self {
+        Self::with_fs(RealFs)
+    }
+}
+
+impl Default for PathSanitizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Fs> PathSanitizer<F> {
+    pub fn with_fs(fs: F) -> Self {
+        Self { fs, deny_list: DenyList::default(), lexical: false, symlinks: SymlinkPolicy::default() }
+    }
+
+    /// With `SymlinkPolicy::Always` links may lead out of the root, the rest is left to the validator.
+    pub fn with_symlink_policy(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Sanitizer for resources that are not stored on the filesystem, eg. bundled ones.
+    /// They contain no links to follow and missing ones are reported by the loader.
+    pub fn lexical(mut self) -> Self {
+        self.lexical = true;
+        self
+    }
+
+    pub fn with_deny_list(mut self, deny_list: DenyList) -> Self {
+        self.deny_list = deny_list;
+        self
+    }
+
+    /// Normalizes request target lexically, without touching the filesystem.
+    ///
+    /// Segments are percent-decoded, `.` segments are dropped and `..` removes the preceding one.
+    /// Result is relative to the document root. Targets that would climb above the root,
+    /// contain NUL bytes or encoded separators are rejected.
+    pub fn normalize(target: &Path) -> Result<PathBuf, SanitizeError> {
+        let forbidden = || SanitizeError::Forbidden(target.to_owned());
+        let repr = target.to_str().ok_or_else(forbidden)?;
+        let mut segments: Vec<String> = Vec::new();
+        for segment in repr.split('/') {
+            let segment = percent_decode(segment).ok_or_else(forbidden)?;
+            if segment.contains(['/', '\\', '\0']) {
+                return Err(forbidden());
+            }
+            match segment.as_str() {
+                "" | "." => continue,
+                ".." => { segments.pop().ok_or_else(forbidden)?; }
+                _ => segments.push(segment),
+            }
+        }
+        Ok(segments.iter().collect())
+    }
+
+    /// Path of the `target` within `root`.
+    ///
+    /// Resolved path has to stay inside of `root` after following symbolic links,
+    /// unless links are followed anywhere.
+    /// Deny patterns are checked before the filesystem is touched.
+    pub fn sanitize(&self, root: &Path, target: &Path) -> Result<PathBuf, SanitizeError> {
+        let normalized = Self::normalize(target)?;
+        if self.deny_list.is_denied(&normalized) {
+            return Err(SanitizeError::Denied(target.to_owned()));
+        }
+        let path = root.join(normalized);
+        if self.lexical {
+            return Ok(path);
+        }
+        let canonical_root = self.fs.canonicalize(root)
+            .map_err(|err| Self::classify(err, target))?;
+        let canonical_path = self.fs.canonicalize(&path)
+            .map_err(|err| Self::classify(err, target))?;
+        if self.symlinks == SymlinkPolicy::Always || canonical_path.starts_with(canonical_root) {
+            Ok(path)
+        } else {
+            Err(SanitizeError::Forbidden(target.to_owned()))
+        }
+    }
+
+    /// Same as `sanitize`, for a `target` that is about to be written and may not exist yet.
+    ///
+    /// Target that does not exist has to be placed in an existing directory inside of `root`.
+    /// Root itself cannot be written.
+    pub fn sanitize_new(&self, root: &Path, target: &Path) -> Result<PathBuf, SanitizeError> {
+        let normalized = Self::normalize(target)?;
+        if self.deny_list.is_denied(&normalized) {
+            return Err(SanitizeError::Denied(target.to_owned()));
+        }
+        if normalized.as_os_str().is_empty() {
+            return Err(SanitizeError::Forbidden(target.to_owned()));
+        }
+        let path = root.join(normalized);
+        let canonical_root = self.fs.canonicalize(root)
+            .map_err(|err| Self::classify(err, target))?;
+        let canonical_path = match self.fs.canonicalize(&path) {
+            Ok(canonical_path) => canonical_path,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let parent = path.parent().unwrap_or(root);
+                self.fs.canonicalize(parent).map_err(|err| Self::classify(err, target))?
+            }
+            Err(err) => return Err(Self::classify(err, target)),
+        };
+        if canonical_path.starts_with(canonical_root) {
+            Ok(path)
+        } else {
+            Err(SanitizeError::Forbidden(target.to_owned()))
+        }
+    }
+
+    fn classify(err: io::Error, target: &Path) -> SanitizeError {
+        match err.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => SanitizeError::NotFound(target.to_owned()),
+            _ => SanitizeError::Forbidden(target.to_owned()),
+        }
+    }
+}
+
+/// Decodes `%XX` escape sequences. `None` if the sequence is malformed or not UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::{Fault, FaultyFs, Operation, TempDir};
+
+    #[test]
+    fn test_normalize() {
+        let normalize = |target: &str| PathSanitizer::<RealFs>::normalize(Path::new(target));
+        assert_eq!(normalize("/a/./b/../c.html"), Ok(PathBuf::from("a/c.html")));
+        assert_eq!(normalize("//a%20b/"), Ok(PathBuf::from("a b")));
+        assert_eq!(normalize("/"), Ok(PathBuf::new()));
+        assert!(matches!(normalize("/../etc/passwd"), Err(SanitizeError::Forbidden(_))));
+        assert!(matches!(normalize("/a/%2e%2e/%2E%2E/etc"), Err(SanitizeError::Forbidden(_))));
+        assert!(matches!(normalize("/a%2f..%2f..%2fetc"), Err(SanitizeError::Forbidden(_))));
+        assert!(matches!(normalize("/a%00.html"), Err(SanitizeError::Forbidden(_))));
+        assert!(matches!(normalize("/a%zz"), Err(SanitizeError::Forbidden(_))));
+    }
+
+    #[test]
+    fn test_new_targets_stay_within_root() {
+        let dir = TempDir::new("server-sanitizer").unwrap();
+        dir.create_file("localhost/docs/index.html", b"").unwrap();
+        dir.create_file("secret.txt", b"").unwrap();
+        let root = dir.path().join("localhost");
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), root.join("link.txt")).unwrap();
+        let sanitizer = PathSanitizer::new();
+        let sanitize_new = |target: &str| sanitizer.sanitize_new(&root, Path::new(target));
+        assert_eq!(sanitize_new("/docs/new.html"), Ok(root.join("docs/new.html")));
+        assert_eq!(sanitize_new("/docs/index.html"), Ok(root.join("docs/index.html")));
+        assert!(matches!(sanitize_new("/missing/new.html"), Err(SanitizeError::NotFound(_))));
+        assert!(matches!(sanitize_new("/link.txt"), Err(SanitizeError::Forbidden(_))));
+        assert!(matches!(sanitize_new("/../new.html"), Err(SanitizeError::Forbidden(_))));
+        assert!(matches!(sanitize_new("/"), Err(SanitizeError::Forbidden(_))));
+        assert!(matches!(sanitize_new("/docs/.env"), Err(SanitizeError::Denied(_))));
+    }
+
+    #[test]
+    fn test_not_found_is_not_forbidden() {
+        let dir = TempDir::new("server-sanitizer").unwrap();
+        dir.create_file("localhost/index.html", b"").unwrap();
+        let root = dir.path().join("localhost");
+        let sanitizer = PathSanitizer::new();
+        assert_eq!(
+            sanitizer.sanitize(&root, Path::new("/index.html")),
+            Ok(root.join("index.html"))
+        );
+        assert!(matches!(
+            sanitizer.sanitize(&root, Path::new("/missing.html")),
+            Err(SanitizeError::NotFound(_))
+        ));
+        assert!(matches!(
+            sanitizer.sanitize(&root, Path::new("/index.html/child")),
+            Err(SanitizeError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_lexical_sanitizer_does_not_touch_the_filesystem() {
+        let sanitizer = PathSanitizer::new().lexical();
+        let root = Path::new("/nonexistent/localhost");
+        assert_eq!(sanitizer.sanitize(root, Path::new("/a/../b.html")), Ok(root.join("b.html")));
+        assert!(matches!(sanitizer.sanitize(root, Path::new("/../b.html")), Err(SanitizeError::Forbidden(_))));
+    }
+
+    #[test]
+    fn test_deny_patterns() {
+        let dir = TempDir::new("server-sanitizer").unwrap();
+        for file in ["index.html", ".env", ".git/config", "notes.bak", "index.html~", "docs/.hidden/a", "git/config"] {
+            dir.create_file(format!("localhost/{file}"), b"").unwrap();
+        }
+        let root = dir.path().join("localhost");
+        let sanitizer = PathSanitizer::new();
+        let denied = |target: &str| matches!(sanitizer.sanitize(&root, Path::new(target)), Err(SanitizeError::Denied(_)));
+        assert!(!denied("/index.html"));
+        assert!(!denied("/git/config"));
+        assert!(denied("/.env"));
+        assert!(denied("/.git/config"));
+        assert!(denied("/notes.bak"));
+        assert!(denied("/index.html~"));
+        assert!(denied("/docs/.hidden/a"));
+        /* missing files are denied the same way as existing ones */
+        assert!(denied("/.missing"));
+        /* rules see the decoded and normalized target */
+        assert!(denied("/%2eenv"));
+        assert!(denied("/%2Egit/config"));
+        assert!(denied("/docs/../.git/./config"));
+        assert!(denied("/index.html%7E"));
+        assert!(denied("/notes.b%61k"));
+    }
+
+    #[test]
+    fn test_configured_deny_list() {
+        let dir = TempDir::new("server-sanitizer").unwrap();
+        dir.create_file("localhost/.well-known/a", b"").unwrap();
+        dir.create_file("localhost/private/a", b"").unwrap();
+        let root = dir.path().join("localhost");
+        let sanitizer = PathSanitizer::new().with_deny_list(DenyList::new([DenyPattern::new("priv?te/")]));
+        assert!(sanitizer.sanitize(&root, Path::new("/.well-known/a")).is_ok());
+        assert!(matches!(sanitizer.sanitize(&root, Path::new("/private/a")), Err(SanitizeError::Denied(_))));
+        assert_eq!(DenyList::empty().patterns(), []);
+        assert!(!DenyList::empty().is_denied(Path::new(".git/config")));
+    }
+
+    #[test]
+    fn test_symlink_escape() {
+        let dir = TempDir::new("server-sanitizer").unwrap();
+        dir.create_file("secret", b"").unwrap();
+        dir.create_file("localhost/index.html", b"").unwrap();
+        let root = dir.path().join("localhost");
+        std::os::unix::fs::symlink(dir.path().join("secret"), root.join("link")).unwrap();
+        assert!(matches!(
+            PathSanitizer::new().sanitize(&root, Path::new("/link")),
+            Err(SanitizeError::Forbidden(_))
+        ));
+        let sanitizer = PathSanitizer::new().with_symlink_policy(SymlinkPolicy::Always);
+        assert_eq!(sanitizer.sanitize(&root, Path::new("/link")), Ok(root.join("link")));
+    }
+
+    #[test]
+    fn test_permission_denied_is_forbidden() {
+        let dir = TempDir::new("server-sanitizer").unwrap();
+        dir.create_file("localhost/index.html", b"").unwrap();
+        let root = dir.path().join("localhost");
+        let fs = FaultyFs::new([Fault::permission_denied(Operation::Canonicalize).with_path(root.join("index.html"))]);
+        assert!(matches!(
+            PathSanitizer::with_fs(fs).sanitize(&root, Path::new("/index.html")),
+            Err(SanitizeError::Forbidden(_))
+        ));
+    }
+}
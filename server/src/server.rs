@@ -1,138 +1,1599 @@
 //! Mikołaj Depta 328690
 
 
-use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io;
-use std::io::{Read, Write, BufWriter, BufReader};
-use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::io::{Read, Write, BufWriter, BufReader, IoSlice};
+use std::net::{IpAddr, TcpListener, TcpStream, SocketAddr};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
-use std::time::Duration;
-use crate::http::common::Body;
-use crate::http::headers::{general_header::GeneralHeader, Headers, response_header::ResponseHeader};
-use crate::http::request::{Request, RequestMetaData};
-use crate::http::response::{Response, StatusCode, StatusLine};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use crate::http::common::{Body, Method, Version};
+use crate::http::date::HttpDate;
+use crate::http::headers::response_header::{Challenge, ContentRange, EntityTag, ResponseHeader};
+use crate::http::headers::general_header::{ConnectionType, GeneralHeader};
+use crate::http::request::{Request, RequestMetaData, StartLine};
+use crate::http::chunked::{self, ChunkedDecoder};
+use crate::http::response::{BodyPart, Response, ResponseBuilder, StatusCode};
 use crate::http::entity::Entity;
-use crate::http::headers::entity_header::ContentType;
-use crate::http::headers::response_header::ResponseHeaders;
+use crate::http::headers::entity_header::{ContentCoding, ContentType, EntityHeader};
+use crate::http::headers::{Header, Headers};
 
-use crate::resources::{StaticValidator, StaticLoader, ResourceLoader, ResourceValidator, ValidationResourceError};
-use crate::registry::{Registry, TimeoutDuration};
+use crate::range::{self, ByteRange, Selection};
+use crate::resources::{OpenResource, Resource, StaticValidator, SymlinkPolicy, StaticLoader, ResourceLoader, ResourceValidator, LoadResourceError, ValidationResourceError};
+use crate::resources::{ResourceWriter, StaticWriter, WriteOutcome, WriteResourceError};
+use crate::registry::{self, syscall, EventType, Registry, Signal, TimeoutDuration};
 use crate::util::OrFailWithMessage;
+use crate::{activation, echo, framing, listen, reload, shutdown, upgrade, util, worker};
+use crate::vhost::{DirectoryPolicy, VirtualHosts};
+use crate::trace::trace;
+use crate::logger::{self, log, Level, RequestId, TraceContext};
+use crate::worker::WorkerPool;
+use crate::bundle::{Bundle, BundleLoader, BundleValidator};
+use crate::cache::{CacheConfig, CachingLoader};
+use crate::descriptors::{DescriptorCache, DescriptorCacheConfig};
+use crate::upload::{self, UploadGuard, UploadTracker};
+use std::fmt::{Display, Formatter};
+use crate::sanitizer::{DenyList, PathSanitizer, SanitizeError};
+use crate::error_pages::ErrorPages;
+use crate::forwarded::Forwarding;
+use crate::routing::Router;
+use crate::metrics::{self, ActiveConnection, Metrics};
+use crate::health::{self, Readiness, State};
+use crate::ratelimit::{RateLimitConfig, RateLimiter};
+use crate::timeouts::{ReadDeadline, ReadTimeouts};
+use crate::mime::MimeTypes;
+use crate::spool::{SpoolConfig, SpoolFile, SpoolUsage};
+use crate::throttle::{BandwidthLimit, Throttle};
+use crate::upgrade::UpgradeState;
+use crate::filters::{BodyFilter, BodyFilters, ChunkFilter};
+use crate::proxy::{Outgoing, ProxyConfig, ProxyHandler, Relayed, Upstream};
+use crate::cgi::{CgiError, CgiHandler};
+use crate::error::ServerError;
+use crate::auth::AuthPolicy;
+use crate::cors::CorsPolicy;
+use crate::extra_headers::ExtraHeaders;
+use crate::expiry::ExpiryPolicy;
+use crate::redirect::HttpsRedirect;
+use crate::rewrite::RewriteMap;
+use crate::privileges::Privileges;
+use crate::reload::ReloadWatch;
+use crate::settings::{LoadSettingsError, Settings, SettingsFiles};
+use crate::quota::DiskQuota;
+use crate::stats::StatsDump;
+use crate::sse::{self, EventStreamHandler};
 
 
+/// Accepts connections and hands them to the threads serving them.
+///
+/// Shutdown, reload and upgrade signals are received through the registry of the accepting thread,
+/// see `shutdown`. They are blocked in the thread that creates the server, in the workers it starts
+/// and in the thread that calls `start`. Threads the program started before creating the server do
+/// not block them and a signal delivered to one of them takes its default action, so the server has
+/// to be created before any other thread is started, or such threads have to call `Signal::block` first.
 pub struct HttpServer<D, S, L = StaticLoader, V = StaticValidator,>
 where
     D: Downloader,
     S: Sender,
-    L: ResourceLoader,
-    V: ResourceValidator,
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
 {
-    address: SocketAddr,
-    loader: L,
-    validator: V,
-    listener: TcpListener,
+    handler: Arc<RequestHandler<L, V>>,
+    /// Connections are accepted from all of them, the first one is the main address.
+    listeners: Vec<TcpListener>,
+    /// Readiness of the listeners and expirations of the stats and quota timers.
+    acceptor: Registry,
     registry: Registry,
-    catalog: Rc<Path>,
+    catalog: Arc<Path>,
+    /// Connections are served on the calling thread if there is no pool.
+    workers: Option<WorkerPool>,
+    next_token: Token,
+    /// Number of upgrades this server went through, see `upgrade`.
+    generation: u32,
+    /// SIGUSR2 re-executes the server, see `upgrade`.
+    upgrades: bool,
+    /// Shutdown, reload and upgrade requests received by the acceptor, see `shutdown`, `reload` and `upgrade`.
+    signals: Option<registry::Token>,
+    /// Statistics are dumped whenever the timer expires and on shutdown, see `stats`.
+    stats: Option<(StatsDump, registry::Token)>,
+    /// Usage of the upload quota is measured again whenever the timer expires, see `quota`.
+    quota: Option<(DiskQuota, registry::Token)>,
     connections: Vec<Connection<D, S>>,
 }
 
+impl<D, S> HttpServer<D, S>
+where
+    D: Downloader,
+    S: Sender,
+{
+    pub fn new(address: SocketAddr, dir: Arc<Path>) -> Self {
+        let virtual_hosts = VirtualHosts::default_config(&dir);
+        Self::with_virtual_hosts(address, dir, virtual_hosts)
+    }
+
+    /// Server that serves every host from its document root, see `VirtualHosts::load`.
+    pub fn with_virtual_hosts(address: SocketAddr, dir: Arc<Path>, virtual_hosts: VirtualHosts) -> Self {
+        let (listeners, dir, virtual_hosts) = Self::bind_unprivileged(address, dir, virtual_hosts);
+        let loader = StaticLoader::new(dir.clone());
+        let validator = StaticValidator::from_virtual_hosts(dir.clone(), &virtual_hosts)
+            .with_symlink_policy(SymlinkPolicy::from_env());
+        Self::with_listeners(listeners, dir, Arc::new(virtual_hosts), loader, validator)
+    }
+}
+
+impl<D, S> HttpServer<D, S, CachingLoader<StaticLoader>>
+where
+    D: Downloader,
+    S: Sender,
+{
+    /// Same as `with_virtual_hosts`, but loaded resources are kept in memory.
+    pub fn with_cache(address: SocketAddr, dir: Arc<Path>, virtual_hosts: VirtualHosts, config: CacheConfig) -> Self {
+        let (listeners, dir, virtual_hosts) = Self::bind_unprivileged(address, dir, virtual_hosts);
+        let loader = CachingLoader::new(StaticLoader::new(dir.clone()), config);
+        let validator = StaticValidator::from_virtual_hosts(dir.clone(), &virtual_hosts)
+            .with_symlink_policy(SymlinkPolicy::from_env());
+        Self::with_listeners(listeners, dir, Arc::new(virtual_hosts), loader, validator)
+    }
+}
+
+impl<D, S> HttpServer<D, S, DescriptorCache<StaticLoader>>
+where
+    D: Downloader,
+    S: Sender,
+{
+    /// Same as `with_virtual_hosts`, but descriptors of streamed files are kept open.
+    pub fn with_descriptor_cache(
+        address: SocketAddr,
+        dir: Arc<Path>,
+        virtual_hosts: VirtualHosts,
+        config: DescriptorCacheConfig,
+    ) -> Self {
+        let (listeners, dir, virtual_hosts) = Self::bind_unprivileged(address, dir, virtual_hosts);
+        let loader = DescriptorCache::new(StaticLoader::new(dir.clone()), config);
+        let validator = StaticValidator::from_virtual_hosts(dir.clone(), &virtual_hosts)
+            .with_symlink_policy(SymlinkPolicy::from_env());
+        Self::with_listeners(listeners, dir, Arc::new(virtual_hosts), loader, validator)
+    }
+}
+
+impl<D, S> HttpServer<D, S, BundleLoader, BundleValidator>
+where
+    D: Downloader,
+    S: Sender,
+{
+    /// Same as `with_virtual_hosts`, but resources are served from the `bundle`,
+    /// document roots in `dir` do not have to exist.
+    pub fn with_bundle(address: SocketAddr, dir: Arc<Path>, virtual_hosts: VirtualHosts, bundle: Bundle) -> Self {
+        let bundle = Arc::new(bundle);
+        let loader = BundleLoader::new(bundle.clone());
+        let validator = BundleValidator::from_virtual_hosts(bundle, &virtual_hosts);
+        let mut server = Self::with_resources(address, dir, Arc::new(virtual_hosts), loader, validator);
+        let handler = Arc::get_mut(&mut server.handler)
+            .or_fail_with_message("bundle has to be set up before the handler is shared");
+        handler.sanitizer = std::mem::take(&mut handler.sanitizer).lexical();
+        server
+    }
+}
+
 impl<D, S, L, V> HttpServer<D, S, L, V>
 where
     D: Downloader,
     S: Sender,
-    L: ResourceLoader,
-    V: ResourceValidator,
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
 {
     const MAX_CONNECTIONS: usize = 1;
+    /// How long listeners are awaited before checking for shutdown and upgrade requests made without a signal.
+    const ACCEPT_TIMEOUT: Duration = Duration::from_secs(1);
 
-    pub fn new(address: SocketAddr, dir: Rc<Path>) -> Self {
-        let listener = TcpListener::bind(address)
-            .or_fail_with_message(format!("could not bind tcp socket to {}", address).as_str());
-        let loader = StaticLoader::new(dir.clone());
-        let validator = StaticValidator::default_config(dir.clone());
-        let registry = Registry::new()
+    /// Uses listening sockets passed by the service manager if the process was socket activated,
+    /// otherwise binds a new one to `address`.
+    pub fn with_resources(
+        address: SocketAddr,
+        dir: Arc<Path>,
+        virtual_hosts: Arc<VirtualHosts>,
+        loader: L,
+        validator: V,
+    ) -> Self {
+        Self::with_listeners(Self::listeners(address), dir, virtual_hosts, loader, validator)
+    }
+
+    pub fn with_listener(
+        listener: TcpListener,
+        dir: Arc<Path>,
+        virtual_hosts: Arc<VirtualHosts>,
+        loader: L,
+        validator: V,
+    ) -> Self {
+        Self::with_listeners(vec![listener], dir, virtual_hosts, loader, validator)
+    }
+
+    /// Accepts connections from all `listeners`, the first one is reported as the `address`.
+    pub fn with_listeners(
+        listeners: Vec<TcpListener>,
+        dir: Arc<Path>,
+        virtual_hosts: Arc<VirtualHosts>,
+        loader: L,
+        validator: V,
+    ) -> Self {
+        if listeners.is_empty() {
+            util::fail_with_message("server needs at least one listening socket");
+        }
+        let registry = registry::from_env()
+            .or_fail_with_message("could not create an epoll event queue");
+        let mut acceptor = registry::from_env()
             .or_fail_with_message("could not create an epoll event queue");
-        Self { address, loader, validator, listener, registry, catalog: dir, connections: Vec::new() }
+        let signals = acceptor.add_signals(&Self::signals())
+            .inspect_err(|err| log!(Level::Warn, "could not watch for signals, they terminate the server: {}", err))
+            .ok();
+        let mut handler = RequestHandler::new(loader, validator, virtual_hosts)
+            .with_error_pages(ErrorPages::from_env())
+            .with_deny_list(DenyList::from_env())
+            .with_symlink_policy(SymlinkPolicy::from_env())
+            .with_read_timeouts(ReadTimeouts::from_env())
+            .with_mime_types(MimeTypes::from_env())
+            .with_spool(SpoolConfig::from_env())
+            .with_auth(AuthPolicy::from_env())
+            .with_cors(CorsPolicy::from_env())
+            .with_extra_headers(ExtraHeaders::from_env())
+            .with_expiry(ExpiryPolicy::from_env())
+            .with_rewrites(RewriteMap::from_env())
+            .with_settings_files(SettingsFiles::from_env(dir.clone()))
+            .with_echo(echo::is_enabled())
+            .with_forwarding(Forwarding::from_env())
+            .with_strict_framing(framing::is_enabled());
+        if let Some(config) = RateLimitConfig::from_env() {
+            handler = handler.with_rate_limit(config);
+        }
+        if let Some(config) = ProxyConfig::from_env() {
+            handler = handler.with_proxy(config);
+        }
+        if let Some(cgi) = CgiHandler::from_env() {
+            handler = handler.with_cgi(cgi);
+        }
+        if let Some(redirect) = HttpsRedirect::from_env() {
+            handler = handler.with_https_redirect(redirect);
+        }
+        let quota = DiskQuota::from_env(dir.clone());
+        if let Some(mut writer) = StaticWriter::from_env(dir.clone()) {
+            if let Some(quota) = &quota {
+                writer = writer.with_quota(quota.clone());
+            }
+            handler = handler.with_writer(writer);
+        } else if quota.is_some() {
+            log!(Level::Warn, "{}: uploads are disabled, the quota has no effect", DiskQuota::ENV_VARIABLE);
+        }
+        if let Some(limit) = BandwidthLimit::from_env() {
+            handler = handler.with_bandwidth_limit(limit);
+        }
+        let handler = Arc::new(handler);
+        let state = upgrade::inherited_state().unwrap_or_default();
+        let mut server = Self {
+            handler,
+            listeners: Vec::new(),
+            acceptor,
+            registry,
+            catalog: dir,
+            workers: None,
+            next_token: state.next_token,
+            generation: state.generation,
+            upgrades: false,
+            signals,
+            stats: None,
+            quota: None,
+            connections: Vec::new(),
+        };
+        for listener in listeners {
+            server = server.with_additional_listener(listener);
+        }
+        if let Some(dump) = StatsDump::from_env() {
+            server = server.with_stats(dump);
+        }
+        if let Some(quota) = quota {
+            server = server.with_quota_reconciliation(quota);
+        }
+        server.upgrades = upgrade::is_enabled() && server.prepare_upgrades();
+        server
+    }
+
+    /// Listening sockets passed by the service manager, or a new one bound to `address`.
+    fn listeners(address: SocketAddr) -> Vec<TcpListener> {
+        match activation::listen_fds() {
+            Ok(listeners) if !listeners.is_empty() => listeners,
+            Ok(_) => vec![Self::bind(address)],
+            Err(err) => util::fail_with_message(err.to_string().as_str()),
+        }
+    }
+
+    /// Same as `listeners`, but privileges are dropped once they are bound, see `privileges`.
+    /// Catalog and virtual hosts are returned as the server sees them afterwards.
+    fn bind_unprivileged(address: SocketAddr, dir: Arc<Path>, virtual_hosts: VirtualHosts) -> (Vec<TcpListener>, Arc<Path>, VirtualHosts) {
+        let listeners = Self::listeners(address);
+        let privileges = Privileges::from_env();
+        if privileges.is_empty() {
+            return (listeners, dir, virtual_hosts);
+        }
+        let catalog = privileges.catalog(&dir);
+        let virtual_hosts = virtual_hosts.rebased(&dir, &catalog).unwrap_or_else(|root| {
+            util::fail_with_message(format!("document root {} is outside of the catalog {}", root.display(), dir.display()).as_str())
+        });
+        if let Err(err) = privileges.drop(&dir) {
+            util::fail_with_message(err.to_string().as_str());
+        }
+        (listeners, Arc::from(catalog), virtual_hosts)
+    }
+
+    fn bind(address: SocketAddr) -> TcpListener {
+        listen::bind(address)
+            .or_fail_with_message(format!("could not bind tcp socket to {}", address).as_str())
+    }
+
+    /// Signals the acceptor watches, SIGUSR2 keeps its default action unless upgrades are enabled.
+    fn signals() -> Vec<Signal> {
+        let mut signals = vec![Signal::Terminate, Signal::Interrupt, Signal::Hangup];
+        if upgrade::is_enabled() {
+            signals.push(Signal::User2);
+        }
+        signals
+    }
+
+    /// Only a single listening socket is handed over to the new program, see `upgrade::exec`.
+    /// SIGUSR2 is received through the acceptor, upgrades are disabled if it cannot be watched.
+    fn prepare_upgrades(&self) -> bool {
+        if self.listeners.len() > 1 {
+            log!(Level::Warn, "{}: upgrades disabled, server listens on {} addresses", upgrade::ENV_VARIABLE, self.listeners.len());
+            return false;
+        }
+        if self.signals.is_none() {
+            log!(Level::Warn, "{}: upgrades disabled, signals are not watched", upgrade::ENV_VARIABLE);
+            return false;
+        }
+        true
+    }
+
+    /// Also accepts connections on `address`, eg. `[::]:8080` next to `0.0.0.0:8080`.
+    pub fn with_additional_address(self, address: SocketAddr) -> Self {
+        let listener = Self::bind(address);
+        self.with_additional_listener(listener)
+    }
+
+    /// Also accepts connections from `listener`. Disables upgrades, see `prepare_upgrades`.
+    pub fn with_additional_listener(mut self, listener: TcpListener) -> Self {
+        /* readiness may be taken away by another process sharing the socket, accept must not block then */
+        listener.set_nonblocking(true)
+            .or_fail_with_message("could not make the listening socket non-blocking");
+        self.acceptor.add_interest(EventType::Read, listener.as_raw_fd())
+            .or_fail_with_message("could not register the listening socket");
+        self.listeners.push(listener);
+        if self.upgrades {
+            log!(Level::Warn, "{}: upgrades disabled, server listens on {} addresses", upgrade::ENV_VARIABLE, self.listeners.len());
+            self.upgrades = false;
+        }
+        self
+    }
+
+    /// Dumps statistics of the virtual hosts every `dump.interval()` and on shutdown, see `stats`.
+    pub fn with_stats(mut self, dump: StatsDump) -> Self {
+        let timer = self.acceptor.add_timer(dump.interval())
+            .or_fail_with_message("could not create the statistics timer");
+        self.stats = Some((dump, timer));
+        self
+    }
+
+    /// Measures the directory of the `quota` every `quota.interval()`, so that usage counted by
+    /// the writers sharing it follows changes made by others, see `quota`.
+    pub fn with_quota_reconciliation(mut self, quota: DiskQuota) -> Self {
+        let timer = self.acceptor.add_timer(quota.interval())
+            .or_fail_with_message("could not create the quota timer");
+        self.quota = Some((quota, timer));
+        self
+    }
+
+    /// Hands accepted connections to `count` worker threads, each with its own event queue.
+    pub fn with_workers(mut self, count: usize) -> Self
+    where
+        L: Send + Sync + 'static,
+        V: Send + Sync + 'static,
+    {
+        let pool = WorkerPool::new(count, self.handler.clone())
+            .or_fail_with_message("could not start worker threads");
+        self.workers = Some(pool);
+        self
+    }
+
+    /// Mounts custom endpoints, see `Router`. Has to be called before `with_workers`.
+    pub fn with_routes(mut self, routes: Router) -> Self {
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("routes have to be set up before the handler is shared");
+        handler.routes = routes;
+        self
+    }
+
+    /// Streams `stats` events with the counters of the server to clients of `path` every `interval`,
+    /// see `sse`. Has to be called before `with_workers`.
+    pub fn with_stats_events(mut self, path: &str, interval: Duration) -> Self {
+        let metrics = self.handler.metrics().clone();
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("event streams have to be set up before the handler is shared");
+        let stats = EventStreamHandler::new(interval, move |_: &Request| sse::stats(metrics.clone()));
+        handler.routes = std::mem::take(&mut handler.routes).exact(path, stats);
+        self
+    }
+
+    /// Passes matching response bodies through the `filter`, see `filters`.
+    /// Has to be called before `with_workers`.
+    pub fn with_body_filter(mut self, filter: impl BodyFilter + 'static) -> Self {
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("body filters have to be set up before the handler is shared");
+        handler.filters = std::mem::take(&mut handler.filters).with_filter(filter);
+        self
+    }
+
+    /// Limits the rate of requests per peer address. Has to be called before `with_workers`.
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("rate limit has to be set up before the handler is shared");
+        handler.rate_limiter = Some(RateLimiter::new(config));
+        self
+    }
+
+    /// Relays GET requests with absolute `http://` targets to the origin servers, see `proxy`.
+    /// Has to be called before `with_workers`.
+    pub fn with_proxy(mut self, config: ProxyConfig) -> Self {
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("proxy mode has to be set up before the handler is shared");
+        handler.proxy = Some(config);
+        self
+    }
+
+    /// Relays requests below the prefix of the `proxy` to its upstream servers, see `ProxyHandler`.
+    /// Has to be called before `with_workers`.
+    pub fn with_reverse_proxy(mut self, proxy: ProxyHandler) -> Self {
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("reverse proxy has to be set up before the handler is shared");
+        handler.add_reverse_proxy(proxy);
+        self
+    }
+
+    /// Runs CGI scripts for requests below the prefix of the `cgi` handler, see `cgi`.
+    /// Has to be called before `with_workers`.
+    pub fn with_cgi(mut self, cgi: CgiHandler) -> Self {
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("CGI has to be set up before the handler is shared");
+        handler.cgi = Some(cgi);
+        self
+    }
+
+    /// Rejects ambiguous request heads, see `framing`. Has to be called before `with_workers`.
+    pub fn with_strict_framing(mut self, enabled: bool) -> Self {
+        let handler = Arc::get_mut(&mut self.handler)
+            .or_fail_with_message("strict framing has to be set up before the handler is shared");
+        handler.strict_framing = enabled;
+        self
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.addresses()[0]
+    }
+
+    /// Addresses of all listening sockets, in the order they were added.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.local_addr().or_fail_with_message("could not read address of the listening socket"))
+            .collect()
+    }
+
+    /// Number of upgrades the server went through, zero for a freshly started one.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Statistics of connections and requests served so far, also available at `GET /metrics`.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        self.handler.metrics()
+    }
+
+    /// State reported at `GET /healthz`, clones of it can mark the server as shutting down.
+    pub fn readiness(&self) -> &Readiness {
+        self.handler.readiness()
+    }
+
+    /// Reports the server as shutting down and waits until workers finish their connections.
+    pub fn shutdown(self) {
+        self.readiness().set(State::ShuttingDown);
+        if let Some(workers) = self.workers {
+            workers.join();
+        }
     }
 
     fn connection_limit_exceeded(&self) -> bool {
-        self.connections.len() < Self::MAX_CONNECTIONS
+        self.connections.len() >= Self::MAX_CONNECTIONS
+    }
+
+    fn handle_request(&self, request: &Request) -> Response {
+        self.handler.handle(request)
+    }
+
+    pub fn process_connections(&mut self) {
+        for _connection in &mut self.connections {
+
+        }
+    }
+
+    /// Accepts connections until shutdown is requested, see `shutdown`. Without a worker pool
+    /// every connection is served to completion before the next one is accepted.
+    ///
+    /// Signals the server receives are blocked in the calling thread, which may be another one
+    /// than the thread that created the server, see `HttpServer`.
+    pub fn start(&mut self) {
+        if self.signals.is_some() {
+            if let Err(err) = Signal::block(&Self::signals()) {
+                log!(Level::Warn, "could not block signals, they may terminate the server: {}", err);
+            }
+        }
+        self.readiness().set(State::Ready);
+        loop {
+            if shutdown::is_requested() {
+                self.shut_down();
+                return;
+            }
+            if self.upgrades && upgrade::take_request() {
+                self.upgrade();
+            }
+            let Some(listener) = self.await_listener() else { continue };
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                /* connection was taken by another process or accept was interrupted by a signal */
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => continue,
+                Err(err) => {
+                    log!(Level::Warn, "could not accept connection: {}", err);
+                    continue;
+                }
+            };
+            self.handler.metrics().connection_accepted();
+            let token = self.next_token;
+            self.next_token += 1;
+            match &mut self.workers {
+                Some(workers) => workers.dispatch(token, stream),
+                None => worker::serve(&mut self.registry, &self.handler, token, stream),
+            }
+        }
+    }
+
+    /// Listener with a pending connection, `None` if there was none within `ACCEPT_TIMEOUT`,
+    /// so shutdown and upgrade requests made by another thread are noticed.
+    /// Statistics are dumped and the upload quota measured meanwhile if their timers expired, received signals are handled.
+    fn await_listener(&mut self) -> Option<&TcpListener> {
+        let ready = match self.acceptor.await_events(&TimeoutDuration::Finite(Self::ACCEPT_TIMEOUT)) {
+            Ok(ready) => ready,
+            Err(err) => {
+                log!(Level::Error, "could not wait for connections: {}", err);
+                return None;
+            }
+        };
+        if self.stats.as_ref().is_some_and(|(_, timer)| ready.iter().any(|event| event.token == *timer)) {
+            self.dump_stats();
+        }
+        if self.quota.as_ref().is_some_and(|(_, timer)| ready.iter().any(|event| event.token == *timer)) {
+            self.reconcile_quota();
+        }
+        if let Some(signals) = self.signals.filter(|signals| ready.iter().any(|event| event.token == *signals)) {
+            self.handle_signals(signals);
+        }
+        ready
+            .iter()
+            .filter_map(|event| self.acceptor.interest(event.token))
+            .find_map(|(_, fd)| self.listeners.iter().find(|listener| listener.as_raw_fd() == fd))
+    }
+
+    fn handle_signals(&mut self, signals: registry::Token) {
+        let received = match self.acceptor.received_signals(signals) {
+            Ok(received) => received,
+            Err(err) => {
+                log!(Level::Warn, "could not read the received signals: {}", err);
+                return;
+            }
+        };
+        for signal in received {
+            match signal {
+                Signal::Hangup => reload::request(),
+                Signal::User2 if self.upgrades => upgrade::request(),
+                Signal::User2 => log!(Level::Warn, "upgrade requested, but upgrades are disabled"),
+                _ => shutdown::request(),
+            }
+        }
+    }
+
+    fn dump_stats(&self) {
+        if let Some((dump, _)) = &self.stats {
+            if let Err(err) = dump.write(self.handler.metrics()) {
+                log!(Level::Warn, "could not write statistics to {}: {}", dump.path().display(), err);
+            }
+        }
+    }
+
+    fn reconcile_quota(&self) {
+        if let Some((quota, _)) = &self.quota {
+            match quota.reconcile() {
+                Ok(used) => log!(Level::Debug, "{} bytes of the upload quota are in use", used),
+                Err(err) => log!(Level::Warn, "could not measure {}: {}", quota.directory().display(), err),
+            }
+        }
+    }
+
+    /// Finishes connections being served and persists the statistics.
+    fn shut_down(&mut self) {
+        log!(Level::Info, "shutdown requested, finishing {} connection(s)", self.handler.metrics().active_connections());
+        self.readiness().set(State::ShuttingDown);
+        if let Some(workers) = self.workers.take() {
+            workers.join();
+        }
+        self.dump_stats();
+    }
+
+    /// Finishes connections being served and executes the server again, see `upgrade::exec`.
+    /// If the executable cannot be started the server keeps running, without the worker pool
+    /// if it was already stopped.
+    fn upgrade(&mut self) {
+        let program = match upgrade::executable() {
+            Ok(program) => program,
+            Err(err) => {
+                log!(Level::Error, "upgrade requested, but the executable cannot be started: {}", err);
+                return;
+            }
+        };
+        log!(Level::Info, "upgrade requested, finishing {} connection(s)", self.handler.metrics().active_connections());
+        self.readiness().set(State::ShuttingDown);
+        if let Some(workers) = self.workers.take() {
+            workers.join();
+        }
+        /* counters start over in the new program, the last ones are kept */
+        self.dump_stats();
+        let state = UpgradeState { next_token: self.next_token, generation: self.generation + 1 };
+        let err = upgrade::exec(&program, &self.listeners[0], &state);
+        log!(Level::Error, "could not execute {}: {}, serving connections on the main thread", program.display(), err);
+        self.readiness().set(State::Ready);
+    }
+}
+
+
+/// Produces responses for requests. Handler is shared by all threads serving connections.
+pub struct RequestHandler<L = StaticLoader, V = StaticValidator>
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    loader: L,
+    validator: V,
+    /// Virtual hosts, media types and rewrite rules, replaced as a whole on reload.
+    settings: RwLock<Arc<Settings>>,
+    settings_files: SettingsFiles,
+    reload: ReloadWatch,
+    sanitizer: PathSanitizer,
+    uploads: UploadTracker,
+    error_pages: ErrorPages,
+    routes: Router,
+    metrics: Arc<Metrics>,
+    readiness: Readiness,
+    rate_limiter: Option<RateLimiter>,
+    read_timeouts: ReadTimeouts,
+    spool: SpoolConfig,
+    /// Space taken by spool files of all connections, shared by the workers.
+    spool_usage: SpoolUsage,
+    filters: BodyFilters,
+    proxy: Option<ProxyConfig>,
+    /// Longest prefix first, so that the most specific proxy is found first.
+    reverse_proxies: Vec<ProxyHandler>,
+    cgi: Option<CgiHandler>,
+    auth: AuthPolicy,
+    cors: CorsPolicy,
+    extra_headers: ExtraHeaders,
+    expiry: ExpiryPolicy,
+    /// Every request is redirected to HTTPS if set, see `redirect`.
+    https_redirect: Option<HttpsRedirect>,
+    /// Files are created, replaced and removed with `PUT` and `DELETE` requests if set.
+    writer: Option<Box<dyn ResourceWriter<WriteError = WriteResourceError> + Send + Sync>>,
+    /// Requests are echoed back for debugging if set, see `echo`.
+    echo: bool,
+    /// Proxies trusted to tell the client address, see `forwarded`.
+    forwarding: Forwarding,
+    /// Ambiguous request heads are rejected if set, see `framing`.
+    strict_framing: bool,
+    /// Responses are throttled to this bandwidth if set, see `throttle`.
+    bandwidth_limit: Option<BandwidthLimit>,
+}
+
+impl<L, V> RequestHandler<L, V>
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    /// Document served for a directory.
+    const INDEX_DOCUMENT: &'static str = "index.html";
+
+    pub fn new(loader: L, validator: V, virtual_hosts: Arc<VirtualHosts>) -> Self {
+        Self {
+            loader,
+            validator,
+            settings: RwLock::new(Arc::new(Settings::new(virtual_hosts))),
+            settings_files: SettingsFiles::default(),
+            reload: ReloadWatch::new(),
+            sanitizer: PathSanitizer::new(),
+            uploads: UploadTracker::new(),
+            error_pages: ErrorPages::new(),
+            routes: Router::new(),
+            metrics: Arc::new(Metrics::new()),
+            readiness: Readiness::new(),
+            rate_limiter: None,
+            read_timeouts: ReadTimeouts::default(),
+            spool: SpoolConfig::default(),
+            spool_usage: SpoolUsage::new(),
+            filters: BodyFilters::new(),
+            proxy: None,
+            reverse_proxies: Vec::new(),
+            cgi: None,
+            auth: AuthPolicy::new(),
+            cors: CorsPolicy::new(),
+            extra_headers: ExtraHeaders::default(),
+            expiry: ExpiryPolicy::new(),
+            https_redirect: None,
+            writer: None,
+            echo: false,
+            forwarding: Forwarding::default(),
+            strict_framing: false,
+            bandwidth_limit: None,
+        }
+    }
+
+    /// Requests matching one of the `routes` are handled by the route handler
+    /// instead of being served from the document root.
+    pub fn with_routes(mut self, routes: Router) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Requests from a peer over the limit are answered with 429, see `RateLimiter`.
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limiter = Some(RateLimiter::new(config));
+        self
+    }
+
+    /// Requests protected by the `auth` policy without valid credentials are answered with 401, see `auth`.
+    pub fn with_auth(mut self, auth: AuthPolicy) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Responses to requests from the origins allowed by `cors` can be read by their pages, see `cors`.
+    pub fn with_cors(mut self, cors: CorsPolicy) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    /// Headers configured per host and path are added to every response, see `extra_headers`.
+    pub fn with_extra_headers(mut self, extra_headers: ExtraHeaders) -> Self {
+        self.extra_headers = extra_headers;
+        self
+    }
+
+    /// Files are served with the caching metadata of their media type, see `ExpiryPolicy`.
+    pub fn with_expiry(mut self, expiry: ExpiryPolicy) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Redirects every request for a configured host to its HTTPS equivalent instead of serving it,
+    /// see `HttpsRedirect`.
+    pub fn with_https_redirect(mut self, redirect: HttpsRedirect) -> Self {
+        self.https_redirect = Some(redirect);
+        self
+    }
+
+    /// `PUT` requests for the document roots create or replace files with the `writer`
+    /// and `DELETE` requests remove them. Without it both are answered with 405.
+    pub fn with_writer(mut self, writer: impl ResourceWriter<WriteError = WriteResourceError> + Send + Sync + 'static) -> Self {
+        self.writer = Some(Box::new(writer));
+        self
+    }
+
+    /// Targets matching a rule of the `rewrites` map are redirected before files are looked up,
+    /// see `rewrite`.
+    pub fn with_rewrites(mut self, rewrites: RewriteMap) -> Self {
+        self.settings_mut().rewrites = rewrites;
+        self
+    }
+
+    /// Debugging echo of requests at `/debug/echo` and of `TRACE` requests, see `echo`.
+    pub fn with_echo(mut self, enabled: bool) -> Self {
+        self.echo = enabled;
+        self
+    }
+
+    /// Client addresses forwarded by the trusted proxies are used instead of the proxy ones,
+    /// see `forwarded`.
+    pub fn with_forwarding(mut self, forwarding: Forwarding) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
+    pub fn forwarding(&self) -> &Forwarding {
+        &self.forwarding
+    }
+
+    /// Requests whose heads could be framed differently by other implementations are rejected
+    /// with 400, see `framing`.
+    pub fn with_strict_framing(mut self, enabled: bool) -> Self {
+        self.strict_framing = enabled;
+        self
+    }
+
+    pub fn strict_framing(&self) -> bool {
+        self.strict_framing
+    }
+
+    /// Every response is written no faster than the `limit`, see `throttle`.
+    pub fn with_bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.bandwidth_limit = Some(limit);
+        self
+    }
+
+    pub fn bandwidth_limit(&self) -> Option<BandwidthLimit> {
+        self.bandwidth_limit
+    }
+
+    /// Address of the client the `peer` sent the `request` for, see `Forwarding::client`.
+    pub fn client_address(&self, request: &Request, peer: Option<IpAddr>) -> Option<IpAddr> {
+        peer.map(|peer| self.forwarding.client(peer, request.headers().forwarded_for()))
+    }
+
+    /// Targets matching one of the patterns are answered with 404, see `DenyList`.
+    pub fn with_deny_list(mut self, deny_list: DenyList) -> Self {
+        self.sanitizer = std::mem::take(&mut self.sanitizer).with_deny_list(deny_list);
+        self
+    }
+
+    /// Lets targets lead out of the document root through links, if the policy allows it.
+    /// Validator has to be given the same policy, see `StaticValidator::with_symlink_policy`.
+    pub fn with_symlink_policy(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.sanitizer = std::mem::take(&mut self.sanitizer).with_symlink_policy(symlinks);
+        self
+    }
+
+    /// Requests received too slowly are answered with 408, see `ReadTimeouts`.
+    pub fn with_read_timeouts(mut self, read_timeouts: ReadTimeouts) -> Self {
+        self.read_timeouts = read_timeouts;
+        self
+    }
+
+    pub fn read_timeouts(&self) -> &ReadTimeouts {
+        &self.read_timeouts
+    }
+
+    /// Large request bodies are spooled to temporary files, see `SpoolConfig`.
+    pub fn with_spool(mut self, spool: SpoolConfig) -> Self {
+        self.spool = spool;
+        self
+    }
+
+    pub fn spool(&self) -> &SpoolConfig {
+        &self.spool
+    }
+
+    pub fn spool_usage(&self) -> &SpoolUsage {
+        &self.spool_usage
+    }
+
+    /// Matching response bodies are passed through the `filter`, after the filters added before.
+    pub fn with_body_filter(mut self, filter: impl BodyFilter + 'static) -> Self {
+        self.filters = self.filters.with_filter(filter);
+        self
+    }
+
+    /// GET requests with absolute `http://` targets are relayed to the origin servers, see `proxy`.
+    pub fn with_proxy(mut self, config: ProxyConfig) -> Self {
+        self.proxy = Some(config);
+        self
+    }
+
+    pub fn proxy(&self) -> Option<&ProxyConfig> {
+        self.proxy.as_ref()
+    }
+
+    /// Requests below the prefix of the `proxy` are relayed to its upstream servers, see `ProxyHandler`.
+    pub fn with_reverse_proxy(mut self, proxy: ProxyHandler) -> Self {
+        self.add_reverse_proxy(proxy);
+        self
+    }
+
+    fn add_reverse_proxy(&mut self, proxy: ProxyHandler) {
+        let position = self
+            .reverse_proxies
+            .partition_point(|added| added.prefix().len() >= proxy.prefix().len());
+        self.reverse_proxies.insert(position, proxy);
+    }
+
+    /// Requests below the prefix of the `cgi` handler run its scripts, see `cgi`.
+    pub fn with_cgi(mut self, cgi: CgiHandler) -> Self {
+        self.cgi = Some(cgi);
+        self
+    }
+
+    /// Handler of the CGI scripts, if `request` runs one of them.
+    pub fn cgi(&self, request: &Request) -> Option<&CgiHandler> {
+        self.cgi.as_ref().filter(|cgi| cgi.matches(request.start_line().path()))
+    }
+
+    /// `request` rewritten for the server it is relayed to, `None` if it is served locally.
+    /// Reverse proxies take precedence over the proxy mode.
+    pub fn proxied<'a>(&self, request: &'a Request, peer: Option<IpAddr>) -> Option<Outgoing<'a>> {
+        let path = request.start_line().path();
+        if let Some(proxy) = self.reverse_proxies.iter().find(|proxy| proxy.matches(path)) {
+            return Some(proxy.outgoing(request, peer));
+        }
+        let config = self.proxy?;
+        Upstream::of(request).map(|upstream| upstream.outgoing(request, config))
+    }
+
+    /// Files are served with media types looked up in `mime_types`, see `MimeTypes`.
+    pub fn with_mime_types(mut self, mime_types: MimeTypes) -> Self {
+        self.settings_mut().mime_types = mime_types;
+        self
+    }
+
+    /// Settings are read again from the `files` once a reload is requested, see `settings`.
+    pub fn with_settings_files(mut self, files: SettingsFiles) -> Self {
+        self.settings_files = files;
+        self
+    }
+
+    fn settings_mut(&mut self) -> &mut Settings {
+        Arc::make_mut(self.settings.get_mut().unwrap())
+    }
+
+    /// Settings requests are served with, read again first if a reload was requested since.
+    fn settings(&self) -> Arc<Settings> {
+        if self.reload.reloaded() && !self.settings_files.is_empty() {
+            if let Err(err) = self.reload_settings() {
+                log!(Level::Warn, "{}, keeping the previous configuration", err);
+            }
+        }
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Replaces settings with the ones read from their files, unless any of them is invalid.
+    pub fn reload_settings(&self) -> Result<(), LoadSettingsError> {
+        let current = self.settings.read().unwrap().clone();
+        let settings = self.settings_files.load(&current)?;
+        if settings.virtual_hosts != current.virtual_hosts {
+            self.validator.set_domains(Arc::new(settings.virtual_hosts.roots().map(Path::to_path_buf).collect()));
+        }
+        *self.settings.write().unwrap() = Arc::new(settings);
+        log!(Level::Info, "configuration reloaded");
+        Ok(())
+    }
+
+    pub fn with_error_pages(mut self, error_pages: ErrorPages) -> Self {
+        self.error_pages = error_pages;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
     }
 
-    fn handle_request(&mut self, request: &Request) -> Response {
-        let domain = request.host();
-        let resource_path = request.start_line().url();
-        let mut full_resource_path = PathBuf::from(&self.catalog);
+    /// Configured virtual host the request is served by, see `VirtualHosts::served_host`.
+    pub fn virtual_host(&self, request: &Request) -> Option<String> {
+        let settings = self.settings();
+        request.host().and_then(|host| settings.virtual_hosts.served_host(host)).map(str::to_owned)
+    }
+
+    pub fn readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+
+    /// Progress of uploads reported by the connections, see `upload`.
+    pub fn uploads(&self) -> &UploadTracker {
+        &self.uploads
+    }
+
+    fn upload_progress_response(&self, request: &Request, id: &str) -> Response {
+        match self.uploads.progress(id) {
+            Some(progress) => {
+                let entity = Entity::new(Box::from(progress.to_json(id).as_bytes()), ContentType::Json);
+                Self::entity_response(request, StatusCode::Ok, entity)
+            }
+            None => self.error_response(request, StatusCode::NotFound),
+        }
+    }
+
+    fn metrics_response(&self, request: &Request) -> Response {
+        let entity = Entity::new(Box::from(self.metrics.render().as_bytes()), ContentType::Txt);
+        Self::entity_response(request, StatusCode::Ok, entity)
+    }
+
+    fn health_response(&self, request: &Request) -> Response {
+        let settings = self.settings();
+        let (status_code, message) = match self.readiness.check(settings.virtual_hosts.roots()) {
+            Ok(()) => (StatusCode::Ok, "OK\n"),
+            Err(reason) => {
+                log!(Level::Info, "health check failed: {}", reason);
+                (StatusCode::ServiceUnavailable, "NOT_READY\n")
+            }
+        };
+        let entity = Entity::new(Box::from(message.as_bytes()), ContentType::Txt);
+        Self::entity_response(request, status_code, entity)
+    }
+
+    /// Response to a request over the rate limit, `retry_after` is rounded up to whole seconds.
+    fn too_many_requests_response(&self, request: &Request, retry_after: Duration) -> Response {
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        let entity = self.error_pages.entity(&StatusCode::TooManyRequests, self.document_root(request).as_deref());
+        Response::builder(StatusCode::TooManyRequests)
+            .in_reply_to(request)
+            .with_header(ResponseHeader::RetryAfter(seconds.max(1)))
+            .with_entity(entity)
+            .build()
+    }
+
+    /// Moves the client to HTTPS, requests for unknown hosts were answered with 421 by `admit` already.
+    fn https_redirect_response(&self, request: &Request) -> Response {
+        let host = request.host().unwrap_or_default();
+        let location = self.https_redirect.unwrap_or_default().location(host, request.start_line().url());
+        Response::builder(StatusCode::MovedPermanently)
+            .in_reply_to(request)
+            .with_header(ResponseHeader::Location(location))
+            .with_entity(Entity::redirect())
+            .build()
+    }
+
+    fn unauthorized_response(&self, request: &Request, challenge: Challenge) -> Response {
+        let entity = self.error_pages.entity(&StatusCode::Unauthorized, self.document_root(request).as_deref());
+        Response::builder(StatusCode::Unauthorized)
+            .in_reply_to(request)
+            .with_header(ResponseHeader::WwwAuthenticate(challenge))
+            .with_entity(entity)
+            .build()
+    }
+
+    /// Methods the resources support, `PUT` and `DELETE` only if files can be written.
+    fn allowed_methods(&self) -> Vec<Method> {
+        let mut methods = Method::SUPPORTED.to_vec();
+        if self.writer.is_some() {
+            methods.extend([Method::PUT, Method::DELETE]);
+        }
+        methods
+    }
 
-        let http_version = request.start_line().version().clone();
+    /// Every resource supports the same methods, so the answer does not depend on the target,
+    /// be it a path or `*` asking about the server as a whole.
+    fn options_response(&self, request: &Request) -> Response {
+        Response::builder(StatusCode::NoContent)
+            .in_reply_to(request)
+            .with_header(ResponseHeader::Allow(self.allowed_methods()))
+            .build()
+    }
+
+    /// Recognized method the resource does not support is answered with 405 listing the ones it does,
+    /// method the server does not know at all with 501.
+    fn unsupported_method_response(&self, request: &Request) -> Response {
+        let method = request.start_line().method();
+        if !method.is_recognized() {
+            log!(Level::Info, "unrecognized method {}", method);
+            return self.error_response(request, StatusCode::NotImplemented);
+        }
+        let entity = self.error_pages.entity(&StatusCode::MethodNotAllowed, self.document_root(request).as_deref());
+        Response::builder(StatusCode::MethodNotAllowed)
+            .in_reply_to(request)
+            .with_header(ResponseHeader::Allow(self.allowed_methods()))
+            .with_entity(entity)
+            .build()
+    }
+
+    /// Stores the body of the request, 201 if the file was created and 204 if it was replaced.
+    /// Target is checked the same way as for reading, except that it may not exist yet.
+    fn put_response(&self, request: &Request, writer: &dyn ResourceWriter<WriteError = WriteResourceError>, document_root: &Path) -> Response {
+        let target = request.start_line().path();
+        let path = match self.sanitizer.sanitize_new(document_root, target) {
+            Ok(path) => path,
+            Err(err) => return self.rejected_target_response(request, err),
+        };
+        let written = match request.body() {
+            Some(body) => writer.put(&path, &mut body.reader()),
+            None => writer.put(&path, &mut io::empty()),
+        };
+        match written {
+            Ok(WriteOutcome::Created) => {
+                log!(Level::Info, "created {}", path.display());
+                Response::builder(StatusCode::Created)
+                    .in_reply_to(request)
+                    .with_header(ResponseHeader::Location(target.to_owned()))
+                    .build()
+            }
+            Ok(WriteOutcome::Replaced) => {
+                log!(Level::Info, "replaced {}", path.display());
+                Response::builder(StatusCode::NoContent).in_reply_to(request).build()
+            }
+            Err(err) => self.write_failure_response(request, err),
+        }
+    }
+
+    fn delete_response(&self, request: &Request, writer: &dyn ResourceWriter<WriteError = WriteResourceError>, document_root: &Path) -> Response {
+        let path = match self.sanitizer.sanitize(document_root, request.start_line().path()) {
+            Ok(path) => path,
+            Err(err) => return self.rejected_target_response(request, err),
+        };
+        match writer.delete(&path) {
+            Ok(()) => {
+                log!(Level::Info, "removed {}", path.display());
+                Response::builder(StatusCode::NoContent).in_reply_to(request).build()
+            }
+            Err(err) => self.write_failure_response(request, err),
+        }
+    }
+
+    /// Denied targets are reported as missing, so responses do not reveal what is there.
+    fn rejected_target_response(&self, request: &Request, err: SanitizeError) -> Response {
+        match err {
+            SanitizeError::NotFound(_) | SanitizeError::Denied(_) => self.error_response(request, StatusCode::NotFound),
+            SanitizeError::Forbidden(_) => {
+                log!(Level::Warn, "rejected request target {}", request.start_line().url().display());
+                self.error_response(request, StatusCode::Forbidden)
+            }
+        }
+    }
+
+    fn write_failure_response(&self, request: &Request, err: WriteResourceError) -> Response {
+        match err {
+            WriteResourceError::NotFound(_) => self.error_response(request, StatusCode::NotFound),
+            WriteResourceError::PermissionDenied(_) | WriteResourceError::IsDirectory(_) => {
+                self.error_response(request, StatusCode::Forbidden)
+            }
+            WriteResourceError::Io(_, io::ErrorKind::StorageFull) => {
+                self.error_response(request, StatusCode::InsufficientStorage)
+            }
+            err => {
+                log!(Level::Error, "could not write resource: {:?}", err);
+                self.error_response(request, StatusCode::InternalServerError)
+            }
+        }
+    }
+
+    fn document_root(&self, request: &Request) -> Option<PathBuf> {
+        let settings = self.settings();
+        settings.virtual_hosts.document_root(request.host().unwrap_or_default()).map(Path::to_path_buf)
+    }
+
+    /// Response with the error page for `status_code` as the body, see `ErrorPages`.
+    fn error_response(&self, request: &Request, status_code: StatusCode) -> Response {
+        let entity = self.error_pages.entity(&status_code, self.document_root(request).as_deref());
+        Self::entity_response(request, status_code, entity)
+    }
+
+    /// Response to a request that could not be served, server errors are logged.
+    pub fn failure_response(&self, request: &Request, err: ServerError) -> Response {
+        let status_code = err.status_code();
+        match status_code.code() {
+            500.. => log!(Level::Error, "could not serve {}: {}", request.start_line().url().display(), err),
+            _ => log!(Level::Debug, "could not serve {}: {}", request.start_line().url().display(), err),
+        }
+        self.error_response(request, status_code)
+    }
 
-        full_resource_path.push(domain);
-        full_resource_path.push(resource_path);
+    /// Response to a request that could not be parsed, connection is closed after it is sent.
+    pub fn bad_request(&self) -> Response {
+        self.closing_response(StatusCode::BadRequest)
+    }
+
+    /// Response to a request whose handler failed unexpectedly, connection is closed after it is sent.
+    pub fn internal_server_error(&self) -> Response {
+        self.closing_response(StatusCode::InternalServerError)
+    }
+
+    /// Response to a request that was not received in time, connection is closed after it is sent.
+    pub fn request_timeout(&self) -> Response {
+        self.closing_response(StatusCode::RequestTimeout)
+    }
+
+    /// Response to a request whose origin server could not be reached, connection is closed after it is sent.
+    pub fn bad_gateway(&self) -> Response {
+        self.closing_response(StatusCode::BadGateway)
+    }
+
+    /// Response to a request whose origin server did not answer in time, connection is closed after it is sent.
+    pub fn gateway_timeout(&self) -> Response {
+        self.closing_response(StatusCode::GatewayTimeout)
+    }
+
+    /// Response to a request whose body does not fit into the spool, connection is closed after it is sent.
+    pub fn insufficient_storage(&self) -> Response {
+        self.closing_response(StatusCode::InsufficientStorage)
+    }
+
+    fn closing_response(&self, status_code: StatusCode) -> Response {
+        let entity = self.error_pages.entity(&status_code, None);
+        Response::builder(status_code)
+            .with_header(GeneralHeader::Connection(ConnectionType::Close))
+            .with_entity(entity)
+            .build()
+    }
+
+    /// Response with `entity` as the body, general headers of the request are echoed back.
+    fn entity_response(request: &Request, status_code: StatusCode, entity: Entity) -> Response {
+        Response::builder(status_code).in_reply_to(request).with_entity(entity).build()
+    }
+
+    /// Name under which latency of the handler responsible for `path` is reported.
+    fn handler_name(&self, path: &Path) -> &str {
+        if self.https_redirect.is_some() {
+            return "https-redirect";
+        }
+        if let Some((route, _)) = self.routes.route(path) {
+            return route;
+        }
+        match path.to_str() {
+            Some(metrics::METRICS_PATH) => "metrics",
+            Some(health::HEALTH_PATH) => "healthz",
+            Some(path) if path.starts_with(upload::PROGRESS_PATH_PREFIX) => "upload-progress",
+            _ => "static",
+        }
+    }
+
+    /// Headers configured for the host and the path of the `request` that the `response` lacks.
+    fn add_extra_headers(&self, request: &Request, response: Response) -> Response {
+        if self.extra_headers.is_empty() {
+            return response;
+        }
+        let host = self.virtual_host(request);
+        let headers = self.extra_headers.headers(host.as_deref(), request.start_line().url(), response.headers());
+        response.with_headers(headers)
+    }
+
+    /// Responds to the request and records it in the access log.
+    pub fn handle(&self, request: &Request) -> Response {
+        self.handle_from(None, request)
+    }
+
+    /// Same as `handle`, but requests from `peer` are subject to the rate limit.
+    pub fn handle_from(&self, peer: Option<IpAddr>, request: &Request) -> Response {
+        match self.admit(peer, request) {
+            Ok(()) => self.serve(request),
+            Err(response) => response,
+        }
+    }
+
+    /// Checks every request passes before it is dispatched, whether it is served from files and routes,
+    /// relayed to another server or runs a script. `Err` holds the response to send instead, already
+    /// recorded in the access log. CORS preflights are answered here as well.
+    pub fn admit(&self, peer: Option<IpAddr>, request: &Request) -> Result<(), Response> {
+        let started = Instant::now();
+        let limited = match (&self.rate_limiter, peer) {
+            (Some(limiter), Some(peer)) => limiter.check(peer).err().map(|retry_after| (peer, retry_after)),
+            _ => None,
+        };
+        let response = match limited {
+            Some((peer, retry_after)) => {
+                log!(Level::Info, "rate limit exceeded by {}", peer);
+                self.too_many_requests_response(request, retry_after)
+            }
+            /* HTTP/1.0 predates the header, such requests are served by the default host */
+            None if request.host().is_none() && !matches!(request.start_line().version(), Version::V1) => {
+                log!(Level::Info, "request for {} without Host", request.start_line().url().display());
+                self.error_response(request, StatusCode::BadRequest)
+            }
+            None if self.is_misdirected(request) => {
+                log!(Level::Info, "no document root for host '{}'", request.host().unwrap_or_default());
+                self.error_response(request, StatusCode::MisdirectedRequest)
+            }
+            None if self.https_redirect.is_some() => self.https_redirect_response(request),
+            /* browsers never send credentials with a preflight, so it must not reach routes or files */
+            None if self.cors.is_preflight(request) => self.options_response(request),
+            None => match self.auth.check(request) {
+                Ok(()) => return Ok(()),
+                Err(challenge) => {
+                    log!(Level::Info, "missing or invalid credentials for {}", request.start_line().url().display());
+                    self.unauthorized_response(request, challenge)
+                }
+            },
+        };
+        Err(self.finish(request, response, started))
+    }
+
+    /// Request for a host none of the virtual hosts serves. Requests relayed in the proxy mode name
+    /// their origin in the target, their `Host` is not one of the virtual hosts.
+    fn is_misdirected(&self, request: &Request) -> bool {
+        if self.proxy.is_some() && Upstream::of(request).is_some() {
+            return false;
+        }
+        self.settings().virtual_hosts.document_root(request.host().unwrap_or_default()).is_none()
+    }
+
+    /// Responds to the request that was admitted and records it in the access log.
+    pub fn serve(&self, request: &Request) -> Response {
+        let started = Instant::now();
+        let response = self.respond(request);
+        self.finish(request, response, started)
+    }
+
+    /// Adds the headers every response gets, filters its body and records it in the access log.
+    fn finish(&self, request: &Request, response: Response, started: Instant) -> Response {
+        let response = response.with_headers(self.cors.headers(request));
+        let response = self.add_extra_headers(request, response);
+        let response = self.filter_body(request, response);
+        let start_line = request.start_line();
+        self.metrics.observe_latency(self.handler_name(start_line.path()), started.elapsed());
+        log!(
+            target: logger::ACCESS_TARGET, Level::Info,
+            "{} [{}] \"{} {} {}\" {} {}",
+            request.host().unwrap_or("-"), HttpDate::now(), start_line.method(), start_line.url().display(), start_line.version(),
+            response.status_line().status_code(), response.len()
+        );
+        response
+    }
+
+    /// Records the request relayed to another server in the access log, see `proxy`.
+    pub fn forwarded(&self, request: &Request, relayed: &Relayed, elapsed: Duration) {
+        let start_line = request.start_line();
+        self.metrics.observe_latency("proxy", elapsed);
+        log!(
+            target: logger::ACCESS_TARGET, Level::Info,
+            "{} [{}] \"{} {} {}\" {} {}",
+            request.host().unwrap_or("-"), HttpDate::now(), start_line.method(), start_line.url().display(), start_line.version(),
+            relayed.status_code().map_or(String::from("-"), |code| code.to_string()), relayed.bytes
+        );
+    }
+
+    /// Response produced by a CGI script, or the error page if running it failed.
+    /// Records the request in the access log, see `cgi`.
+    pub fn executed(&self, request: &Request, result: Result<Response, CgiError>, elapsed: Duration) -> Response {
+        let response = result.unwrap_or_else(|err| {
+            log!(Level::Info, "CGI request {} failed: {}", request.start_line().url().display(), err);
+            self.error_response(request, err.status_code())
+        });
+        let response = response.with_headers(self.cors.headers(request));
+        let response = self.add_extra_headers(request, response);
+        let start_line = request.start_line();
+        self.metrics.observe_latency("cgi", elapsed);
+        log!(
+            target: logger::ACCESS_TARGET, Level::Info,
+            "{} [{}] \"{} {} {}\" {} {}",
+            request.host().unwrap_or("-"), HttpDate::now(), start_line.method(), start_line.url().display(), start_line.version(),
+            response.status_line().status_code(), response.len()
+        );
+        response
+    }
+
+    /// Index document of the directory, if the `path` is one and the directory policy of the host
+    /// lets it be served for this target. Otherwise `Err` holds the response to send instead.
+    fn resolve_directory(&self, request: &Request, domain: &str, path: PathBuf) -> Result<PathBuf, Response> {
+        if !self.loader.is_directory(&path) {
+            return Ok(path);
+        }
+        let target = request.start_line().path();
+        if !target.to_str().is_some_and(|target| target.ends_with('/')) {
+            match self.settings().virtual_hosts.directory_policy(domain) {
+                DirectoryPolicy::Redirect => {
+                    let query = request.start_line().url().to_str().and_then(|url| url.split_once('?')).map(|(_, query)| query);
+                    let location = match query {
+                        Some(query) => PathBuf::from(format!("{}/?{}", target.display(), query)),
+                        None => PathBuf::from(format!("{}/", target.display())),
+                    };
+                    return Err(Response::builder(StatusCode::MovedPermanently)
+                        .in_reply_to(request)
+                        .with_header(ResponseHeader::Location(location))
+                        .with_entity(Entity::redirect())
+                        .build());
+                }
+                DirectoryPolicy::NotFound => return Err(self.error_response(request, StatusCode::NotFound)),
+                DirectoryPolicy::ServeIndex => {}
+            }
+        }
+        Ok(path.join(Self::INDEX_DOCUMENT))
+    }
+
+    /// Precompressed variant of the file the client accepts, in the order of `ContentCoding::PREFERENCE`.
+    /// Also tells whether the file has any variants, responses for it depend on `Accept-Encoding` then.
+    fn precompressed_variant(&self, request: &Request, path: &Path) -> (bool, Option<(PathBuf, ContentCoding)>) {
+        let accepted = request.headers().accepted_codings();
+        let mut has_variants = false;
+        for coding in ContentCoding::PREFERENCE {
+            let Some(variant) = self.loader.precompressed(path, coding) else { continue };
+            has_variants = true;
+            if accepted.as_ref().is_some_and(|accepted| accepted.accepts(coding.name())) {
+                log!(Level::Debug, "serving {} for {}", variant.display(), path.display());
+                return (true, Some((variant, coding)));
+            }
+        }
+        (has_variants, None)
+    }
+
+    /// Compressed bodies are passed through as they are, filters work on the plain content.
+    fn filter_body(&self, request: &Request, response: Response) -> Response {
+        if self.filters.is_empty() || response.headers().content_encoding().is_some() {
+            return response;
+        }
+        /* filters transform whole bodies, a part of one would come out garbled */
+        if response.status_line().status_code().code() == StatusCode::PartialContent.code() {
+            return response;
+        }
+        let Some(content_type) = response.headers().content_type() else {
+            return response;
+        };
+        match self.filters.start(request, &content_type) {
+            Some(filter) => response.with_body_filter(filter),
+            None => response,
+        }
+    }
+
+    /// Response with the file of `len` bytes tagged with `tag`, or the part of it the request asks for,
+    /// see `range`. Body is added by `with_body`, given the part to send or `None` for the whole file.
+    fn file_response(
+        &self,
+        request: &Request,
+        builder: ResponseBuilder,
+        tag: EntityTag,
+        len: usize,
+        with_body: impl FnOnce(ResponseBuilder, Option<ByteRange>) -> ResponseBuilder,
+    ) -> Response {
+        let range = match range::select(request, &tag, len) {
+            Selection::Whole => None,
+            Selection::Part(range) => Some(range),
+            Selection::Unsatisfiable => {
+                return self
+                    .error_response(request, StatusCode::RangeNotSatisfiable)
+                    .with_headers([ResponseHeader::ContentRange(ContentRange::unsatisfied(len))]);
+            }
+        };
+        let mut builder = builder.with_header(ResponseHeader::ETag(tag));
+        if let Some(range) = range {
+            builder = builder
+                .with_status_code(StatusCode::PartialContent)
+                .with_header(ResponseHeader::ContentRange(range.content_range(len)));
+        }
+        with_body(builder, range).build()
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        let domain = request.host().unwrap_or_default();
+        let target = request.start_line().url();
+        /* routes and files are selected by the path alone, the query is left to them */
+        let resource_path = request.start_line().path();
+        log!(Level::Debug, "request for {} on host '{}'", target.display(), domain);
+
+        if let Some(handler) = self.routes.find(resource_path) {
+            return handler.handle(request);
+        }
+
+        if self.echo {
+            if request.start_line().method() == &Method::TRACE {
+                return Self::entity_response(request, StatusCode::Ok, echo::trace(request));
+            }
+            /* queries are part of what is being debugged */
+            if resource_path == Path::new(echo::ECHO_PATH) {
+                return Self::entity_response(request, StatusCode::Ok, echo::echo(request));
+            }
+        }
+
+        match request.start_line().method() {
+            Method::OPTIONS => return self.options_response(request),
+            method if !self.allowed_methods().contains(method) => return self.unsupported_method_response(request),
+            _ => {}
+        }
+
+        if resource_path == Path::new(metrics::METRICS_PATH) {
+            return self.metrics_response(request);
+        }
+
+        if resource_path == Path::new(health::HEALTH_PATH) {
+            return self.health_response(request);
+        }
+
+        if let Some(id) = resource_path.to_str().and_then(|path| path.strip_prefix(upload::PROGRESS_PATH_PREFIX)) {
+            return self.upload_progress_response(request, id);
+        }
+
+        let settings = self.settings();
+        let Some(document_root) = settings.virtual_hosts.document_root(domain) else {
+            log!(Level::Info, "no document root for host '{}'", domain);
+            return self.error_response(request, StatusCode::MisdirectedRequest);
+        };
+        if let Some((status_code, location)) = settings.rewrites.redirect(target) {
+            log!(Level::Debug, "rewriting {} to {}", target.display(), location.display());
+            return Response::builder(status_code)
+                .in_reply_to(request)
+                .with_header(ResponseHeader::Location(location))
+                .with_entity(Entity::redirect())
+                .build();
+        }
+        if let Some(writer) = &self.writer {
+            match request.start_line().method() {
+                Method::PUT => return self.put_response(request, writer.as_ref(), document_root),
+                Method::DELETE => return self.delete_response(request, writer.as_ref(), document_root),
+                _ => {}
+            }
+        }
+
+        let full_resource_path = match self.sanitizer.sanitize(document_root, resource_path) {
+            Ok(path) => path,
+            Err(SanitizeError::NotFound(_)) => {
+                return self.error_response(request, StatusCode::NotFound);
+            }
+            Err(SanitizeError::Denied(_)) => {
+                log!(Level::Info, "denied request target {}", target.display());
+                return self.error_response(request, StatusCode::NotFound);
+            }
+            Err(SanitizeError::Forbidden(_)) => {
+                log!(Level::Warn, "rejected request target {}", target.display());
+                return self.error_response(request, StatusCode::Forbidden);
+            }
+        };
+        let full_resource_path = match self.resolve_directory(request, domain, full_resource_path) {
+            Ok(path) => path,
+            Err(response) => return response,
+        };
         match self.validator.validate(&full_resource_path) {
             Ok(_) => {
-                match self.loader.load(&full_resource_path) {
-                    Ok(data) => {
-                        let status_line = StatusLine::new(http_version, StatusCode::Ok);
-                        let entity = Entity::new(data, request.headers().content_type().unwrap_or_default());
-                        let headers = Headers::new(
-                            request.headers().general_headers(),
-                            None,
-                            None,
-                            Some(entity.headers()),
-                        );
-                        Response::new(status_line, headers, Some(Body::SingleSource(entity)))
+                let content_type = settings.mime_types.content_type(&full_resource_path);
+                let mut builder = Response::builder(StatusCode::Ok)
+                    .in_reply_to(request)
+                    .with_headers(self.expiry.headers(&content_type, HttpDate::now()));
+                let (has_variants, variant) = self.precompressed_variant(request, &full_resource_path);
+                if has_variants {
+                    builder = builder.with_header(ResponseHeader::Vary(vec![String::from("Accept-Encoding")]));
+                }
+                let full_resource_path = match variant {
+                    Some((path, coding)) => {
+                        builder = builder.with_header(EntityHeader::ContentEncoding(coding));
+                        path
+                    }
+                    None => full_resource_path,
+                };
+                let loaded = self.loader.open(&full_resource_path).and_then(|opened| match opened {
+                    Some(opened) => Ok(Err(opened)),
+                    None => self.loader.load(&full_resource_path).map(Ok),
+                });
+                match loaded {
+                    Ok(Ok(Resource { data, version })) => {
+                        let len = data.len();
+                        self.file_response(request, builder, version.entity_tag(), len, |builder, range| match range {
+                            Some(range) => {
+                                let part = Box::from(&data[range.offset()..range.offset() + range.len()]);
+                                builder.with_entity(Entity::new(part, content_type))
+                            }
+                            None => builder.with_entity(Entity::new(data, content_type)),
+                        })
                     }
-                    Err(_) => {
-                        let status_line = StatusLine::new(http_version, StatusCode::NotFound);
-                        let entity = Entity::not_found();
-                        let headers = Headers::new(
-                            request.headers().general_headers(),
-                            None,
-                            None,
-                            Some(entity.headers()),
-                        );
-                        Response::new(status_line, headers, Some(Body::SingleSource(entity)))
+                    Ok(Err(opened)) => {
+                        let len = opened.len();
+                        let OpenResource { file, version } = opened;
+                        self.file_response(request, builder, version.entity_tag(), len, |builder, range| match range {
+                            Some(range) => builder.with_file_part(file, range.offset() as u64, range.len(), content_type),
+                            None => builder.with_file(file, len, content_type),
+                        })
                     }
+                    Err(err) => self.failure_response(request, err.into()),
                 }
             }
-            Err(ValidationResourceError::UnauthorizedResourceAccess(_)) => {
-                // prepare 403 message
-                let status_line = StatusLine::new(http_version, StatusCode::Forbidden);
-                let entity = Entity::morbidden();
-                let headers = Headers::new(
-                    request.headers().general_headers(),
-                    None,
-                    None,
-                    Some(entity.headers()),
-                );
-                Response::new(status_line, headers, Some(Body::SingleSource(entity)))
-            }
-            Err(ValidationResourceError::OutdatedResourcePath(path)) => {
+            Err(ValidationResourceError::OutdatedResourcePath(_)) => {
                 // prepare 301 message
-                let status_line = StatusLine::new(http_version, StatusCode::MovedPermanently);
-                let entity = Entity::redirect();
-                let mut new_path = PathBuf::from(path);
-                new_path.push("/index.html");
-                let headers = Headers::new(
-                    request.headers().general_headers(),
-                    None,
-                    Some(ResponseHeaders::from([ResponseHeader::Location(new_path)])),
-                    Some(entity.headers()),
-                );
-                Response::new(status_line, headers, Some(Body::SingleSource(entity)))
+                let new_path = Path::new("/").join(resource_path).join(Self::INDEX_DOCUMENT);
+                Response::builder(StatusCode::MovedPermanently)
+                    .in_reply_to(request)
+                    .with_header(ResponseHeader::Location(new_path))
+                    .with_entity(Entity::redirect())
+                    .build()
             }
-            other => { panic!("some wierd edge case") }
-        }
-    }
-    
-    pub fn process_connections(&mut self) { 
-        for connection in self.connections {
-
+            Err(err) => self.failure_response(request, err.into()),
         }
     }
-
-    pub fn start(&mut self) { }
 }
 
 
@@ -156,129 +1617,465 @@ pub trait Downloader : Action<Output=Option<Request>> { }
 
 pub trait Sender : Action<Output=()> { }
 
+/// Destination of the file bodies.
+pub trait FileSink : Write {
+    /// Sends up to `count` bytes of the `file` starting at `offset`, returns the number of bytes sent.
+    /// Default implementation copies the data through a buffer.
+    fn send_file(&mut self, file: &File, offset: u64, count: usize) -> io::Result<usize> {
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        let mut buffer = vec![0; count.min(CHUNK_SIZE)];
+        match file.read_at(&mut buffer, offset)? {
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            bytes_read => self.write(&buffer[..bytes_read]),
+        }
+    }
+}
+
+/// Data is copied by the kernel without passing through the user space.
+impl FileSink for TcpStream {
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &File, offset: u64, count: usize) -> io::Result<usize> {
+        let mut offset = offset as libc::off_t;
+        let bytes_sent = syscall!(sendfile(self.as_raw_fd(), file.as_raw_fd(), &mut offset, count))?;
+        match bytes_sent {
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            bytes_sent => Ok(bytes_sent as usize),
+        }
+    }
+}
+
+impl FileSink for Vec<u8> { }
+
 
 // region Downloader
 /// Provides functionality of downloading HTTP Request until end of header section.
 /// HTTP Entity event if present will be ignored.
-struct HttpDownloader<R> where R: Read {
+pub struct HttpDownloader<R> where R: Read {
     reader: BufReader<R>,
     timeout: TimeoutDuration,
     store: Vec<u8>,
-    download_buffer: Vec<u8>,
+    download_buffer: Box<[u8]>,
     is_finished: bool,
     request_metadata: Option<RequestMetaData>,
     content_length: Option<usize>,
+    /// Body is sent in chunked transfer coding, see `chunked`.
+    chunked: Option<ChunkedDecoder>,
+    /// Data of the chunks received so far that was not spooled.
+    decoded: Vec<u8>,
     body: Option<Body>,
+    uploads: Option<UploadTracker>,
+    /// Progress of the body being received, if the client asked for it to be tracked.
+    upload: Option<UploadGuard>,
+    deadline: Option<ReadDeadline>,
+    /// Deadline is restarted once the next request starts arriving.
+    idle: bool,
+    spool: SpoolConfig,
+    spool_usage: SpoolUsage,
+    /// File the body is spooled into and the number of body bytes written to it so far.
+    spooled: Option<(SpoolFile, usize)>,
+    /// Ambiguous heads are rejected, see `framing`.
+    strict_framing: bool,
+    /// Requests that arrived complete behind the current one, oldest first.
+    pipelined: VecDeque<Request>,
 }
 
 impl<R> HttpDownloader<R> where R: Read {
+    const DOWNLOAD_BUFFER_SIZE: usize = 4096;
+    /// Longest method the server knows is `OPTIONS`, anything much longer is garbage.
+    const MAX_METHOD_SIZE: usize = 32;
+    /// Minimal request line length servers should support, see RFC 9112 section 3.
+    const MAX_START_LINE_SIZE: usize = 8000;
+
     pub fn new(reader: R) -> Self {
         Self {
-            reader,
+            reader: BufReader::new(reader),
             timeout: TimeoutDuration::Infinite,
             store: Vec::new(),
-            download_buffer: Vec::new(),
+            download_buffer: vec![0; Self::DOWNLOAD_BUFFER_SIZE].into_boxed_slice(),
             is_finished: false,
             request_metadata: None,
             content_length: None,
-            body: None
+            chunked: None,
+            decoded: Vec::new(),
+            body: None,
+            uploads: None,
+            upload: None,
+            deadline: None,
+            idle: false,
+            spool: SpoolConfig::default(),
+            spool_usage: SpoolUsage::new(),
+            spooled: None,
+            strict_framing: false,
+            pipelined: VecDeque::new(),
         }
     }
 
+    /// Bodies longer than the threshold of `spool` are written to temporary files, see `SpoolConfig`.
+    pub fn with_spool(mut self, spool: SpoolConfig) -> Self {
+        self.spool = spool;
+        self
+    }
+
+    /// Spool files of the connection count towards the quota of the spool together with the ones
+    /// of other connections sharing `usage`. Body over the quota fails `advance` with `StorageFull`.
+    pub fn with_spool_usage(mut self, usage: SpoolUsage) -> Self {
+        self.spool_usage = usage;
+        self
+    }
+
+    /// Heads that other implementations could frame differently fail `advance` with `InvalidData`,
+    /// see `framing`.
+    pub fn with_strict_framing(mut self, enabled: bool) -> Self {
+        self.strict_framing = enabled;
+        self
+    }
+
+    /// Request has to be received within `timeouts`, otherwise `advance` fails with `TimedOut`.
+    pub fn with_read_timeouts(mut self, timeouts: ReadTimeouts) -> Self {
+        self.timeout = TimeoutDuration::Finite(timeouts.headers);
+        self.deadline = Some(ReadDeadline::new(timeouts));
+        self
+    }
+
+    /// Part of a request was received, but not all of it.
+    pub fn is_receiving(&self) -> bool {
+        !self.is_finished && (!self.store.is_empty() || self.request_metadata.is_some())
+    }
+
+    /// Reports progress of request bodies labeled with `X-Upload-Id` header to `uploads`.
+    pub fn with_upload_tracker(mut self, uploads: UploadTracker) -> Self {
+        self.uploads = Some(uploads);
+        self
+    }
+
+    /// Requests that arrived complete behind the one returned last, they are returned by
+    /// the following calls to `advance` without waiting for the socket.
+    pub fn pipelined(&self) -> &VecDeque<Request> {
+        &self.pipelined
+    }
+
+    /// Prepares for the next request. Bytes received past the end of the previous request
+    /// are its beginning, so they are kept together with the pipelined requests.
     pub fn reset(&mut self, reader: R) {
+        self.store.extend_from_slice(self.reader.buffer());
         self.reader = BufReader::new(reader);
-        self.download_buffer.clear();
-        self.store.clear();
         self.is_finished = false;
         self.request_metadata = None;
         self.content_length = None;
+        self.chunked = None;
+        self.decoded.clear();
         self.body = None;
+        self.upload = None;
+        self.spooled = None;
+        self.idle = true;
+        self.queue_pipelined();
+    }
+
+    fn is_expired(&self) -> bool {
+        self.deadline.as_ref().is_some_and(|deadline| deadline.is_expired(Instant::now()))
+    }
+
+    /// Registry waits no longer than until the deadline of the request.
+    fn update_timeout(&mut self) {
+        if let Some(deadline) = &self.deadline {
+            self.timeout = match deadline.remaining(Instant::now()) {
+                Some(remaining) => TimeoutDuration::Finite(remaining),
+                None => TimeoutDuration::Infinite,
+            };
+        }
     }
 }
 
 impl<R> HttpDownloader<R> where R: Read {
+    fn read_chunk(&mut self) -> io::Result<usize> {
+        match self.reader.read(&mut self.download_buffer)? {
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            bytes_read => {
+                self.store.extend_from_slice(&self.download_buffer[..bytes_read]);
+                Ok(bytes_read)
+            }
+        }
+    }
+
     fn download_metadata(&mut self) -> io::Result<()> {
         loop {
-            self.download_buffer.clear();
-            match self.reader.read(&mut self.download_buffer)? {
-                0 => Ok(()),
-                bytes_read=> {
-                    // section separator can be downloaded in two separate messages eg:
-                    // [.., b"\r", b"\n", b"\r"]
-                    // [b"\n", ..]
-                    // so we must first add data to the store buffer, check if stored section sep
-                    // and move excessive data.
-                    let prev_store_len = self.store.len();
-                    let sep_range = prev_store_len.saturating_sub(3)..prev_store_len + bytes_read;
-                    self.store.extend_from_slice(&self.download_buffer);
-                    if let Some(pos) = Request::section_sep_pos(&self.store[sep_range]) {
-                        /* addition of Request::SECTION_SEP.len() / 2 adds CRLF at the end, final header wouldn't be valid otherwise.  */
-                        self.store.resize(prev_store_len.saturating_sub(3) + pos + Request::SECTION_SEP.len() / 2, 0);
-                        self.download_buffer.drain(..pos + Request::SECTION_SEP.len() - 3);
-                        self.request_metadata = Some(RequestMetaData::try_from(&self.store)?);
-                        
-                        let metadata_ref = &self.request_metadata.unwrap();
-                        if let Some(content_length) = metadata_ref.headers.content_length() {
-                            /* once metadata section was parsed store can be reused for payload download. */
-                            self.store.clear();
-                            /* move all the remaining bytes from download buffer into store */
-                            let payload_range = 0..self.download_buffer.len().min(content_length);
-                            self.store.extend(self.download_buffer.drain(payload_range));
-                        } else {
-                            /* if no content-type information is present request is ready */
-                            self.is_finished = true;
-                        }
-                        Ok(())
+            /* store may already hold the beginning of the request, it arrived behind the previous one.
+               section separator can also be split between two reads, so the whole store is searched. */
+            self.check_start_line()?;
+            self.check_framing()?;
+            if let Some(sep_pos) = Request::section_sep_pos(&self.store) {
+                /* metadata may arrive in a single read, limit applies wherever its end was found. */
+                if sep_pos > Request::MAX_GET_SIZE {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "request metadata too large"));
+                }
+                /* addition of Request::SECTION_SEP.len() / 2 adds CRLF at the end, final header wouldn't be valid otherwise.  */
+                let metadata = RequestMetaData::try_from(&self.store[..sep_pos + Request::SECTION_SEP.len() / 2])?;
+                /* once metadata section was parsed store can be reused for payload download. */
+                self.store.drain(..sep_pos + Request::SECTION_SEP.len());
+                self.content_length = metadata.headers.content_length();
+                if chunked::is_chunked(&metadata.headers)? {
+                    self.chunked = Some(ChunkedDecoder::new());
+                }
+                if let Some(deadline) = &mut self.deadline {
+                    deadline.headers_received(Instant::now());
+                }
+                self.upload = self.start_upload(&metadata);
+                self.request_metadata = Some(metadata);
+                if self.chunked.is_some() {
+                    if self.decode_chunks()? {
+                        self.finish_payload();
                     }
+                    return Ok(());
                 }
+                if let Some(content_length) = self.content_length.filter(|length| self.spool.should_spool(*length)) {
+                    self.spooled = Some((self.spool.spool(&self.spool_usage, content_length)?, 0));
+                    self.spill(content_length)?;
+                }
+                match self.content_length {
+                    Some(content_length) if self.body_received() >= content_length => self.finish_payload(),
+                    Some(_) => {}
+                    /* if no content-length information is present request is ready */
+                    None => self.is_finished = true,
+                }
+                return Ok(());
+            }
+            if self.store.len() > Request::MAX_GET_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request metadata too large"));
+            }
+            self.read_chunk()?;
+        }
+    }
+
+    /// Moves requests that are already complete in `store` to the pipelined ones.
+    /// Malformed request is left in `store`, it is rejected once its turn comes.
+    fn queue_pipelined(&mut self) {
+        while let Ok(Some(request)) = self.take_complete_request() {
+            self.pipelined.push_back(request);
+        }
+    }
+
+    fn take_complete_request(&mut self) -> io::Result<Option<Request>> {
+        let Some(sep_pos) = Request::section_sep_pos(&self.store) else {
+            return Ok(None);
+        };
+        if sep_pos > Request::MAX_GET_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request metadata too large"));
+        }
+        self.check_start_line()?;
+        self.check_framing()?;
+        let RequestMetaData { start_line, headers } =
+            RequestMetaData::try_from(&self.store[..sep_pos + Request::SECTION_SEP.len() / 2])?;
+        let body_start = sep_pos + Request::SECTION_SEP.len();
+        if chunked::is_chunked(&headers)? {
+            let mut decoder = ChunkedDecoder::new();
+            let mut decoded = Vec::new();
+            let consumed = decoder.decode(&self.store[body_start..], &mut decoded)?;
+            if !decoder.is_done() {
+                return Ok(None);
+            }
+            self.store.drain(..body_start + consumed);
+            let entity = Entity::new(decoded.into_boxed_slice(), headers.content_type().unwrap_or_default());
+            return Ok(Some(Self::dechunked(start_line, headers, Body::SingleSource(entity))));
+        }
+        let content_length = headers.content_length();
+        let body_end = body_start + content_length.unwrap_or_default();
+        if self.store.len() < body_end {
+            return Ok(None);
+        }
+        let body = content_length.map(|_| {
+            let entity = Entity::new(Box::from(&self.store[body_start..body_end]), headers.content_type().unwrap_or_default());
+            Body::SingleSource(entity)
+        });
+        self.store.drain(..body_end);
+        Ok(Some(Request::new(start_line, headers, body)))
+    }
+
+    /// Request whose chunked `body` was decoded. Its length is known now, so it is described
+    /// by `Content-Length`, as if it was sent that way.
+    fn dechunked(start_line: StartLine, mut headers: Headers, body: Body) -> Request {
+        headers.remove(chunked::TRANSFER_ENCODING_HEADER);
+        headers.append(Header::Entity(EntityHeader::ContentLength(body.len())));
+        Request::new(start_line, headers, Some(body))
+    }
+
+    /// Rejects start line that cannot become valid no matter what arrives next,
+    /// so garbage is not buffered until the header section limit is reached.
+    fn check_start_line(&self) -> io::Result<()> {
+        let invalid = |reason: &str| Err(io::Error::new(io::ErrorKind::InvalidData, reason.to_owned()));
+        let line = match self.store.windows(2).position(|window| window == b"\r\n") {
+            Some(end) => &self.store[..end],
+            /* CR of the terminating CRLF may have arrived without the LF */
+            None => self.store.strip_suffix(b"\r").unwrap_or(&self.store),
+        };
+        if line.iter().any(|&byte| byte.is_ascii_control()) {
+            return invalid("control character in request line");
+        }
+        if line.len() > Self::MAX_METHOD_SIZE && !line[..=Self::MAX_METHOD_SIZE].contains(&b' ') {
+            return invalid("request method too long");
+        }
+        if line.len() > Self::MAX_START_LINE_SIZE {
+            return invalid("request line too long");
+        }
+        Ok(())
+    }
+
+    /// Validates the head in `store` in strict framing mode, as much of it as arrived.
+    fn check_framing(&self) -> io::Result<()> {
+        if !self.strict_framing {
+            return Ok(());
+        }
+        match Request::section_sep_pos(&self.store) {
+            Some(sep_pos) => framing::validate(&self.store[..sep_pos + Request::SECTION_SEP.len() / 2])?,
+            None => framing::check_line_endings(&self.store)?,
+        }
+        Ok(())
+    }
+
+    fn start_upload(&self, metadata: &RequestMetaData) -> Option<UploadGuard> {
+        let id = metadata.headers.unknown(upload::UPLOAD_ID_HEADER)?;
+        let upload = self.uploads.as_ref()?.start(id, self.content_length?)?;
+        /* part of the body may have arrived together with the headers */
+        upload.update(self.store.len());
+        Some(upload)
+    }
+
+    fn download_payload(&mut self) -> io::Result<()> {
+        if self.chunked.is_some() {
+            return self.download_chunks();
+        }
+        let content_length = self.content_length.unwrap_or_default();
+        while self.body_received() < content_length {
+            self.read_chunk()?;
+            self.spill(content_length)?;
+            let received = self.body_received();
+            if let Some(upload) = &self.upload {
+                upload.update(received);
+            }
+            if let Some(deadline) = &mut self.deadline {
+                deadline.body_progress(received);
+            }
+        }
+        self.finish_payload();
+        Ok(())
+    }
+
+    fn download_chunks(&mut self) -> io::Result<()> {
+        while !self.decode_chunks()? {
+            self.read_chunk()?;
+            let received = self.body_received();
+            if let Some(deadline) = &mut self.deadline {
+                deadline.body_progress(received);
             }
         }
+        self.finish_payload();
+        Ok(())
+    }
+
+    /// Decodes chunks received so far, `true` once the body ended. Data is spooled once
+    /// there is more of it than the spool threshold, see `SpoolConfig::extend`.
+    fn decode_chunks(&mut self) -> io::Result<bool> {
+        let Some(decoder) = &mut self.chunked else {
+            return Ok(true);
+        };
+        let consumed = decoder.decode(&self.store, &mut self.decoded)?;
+        let is_done = decoder.is_done();
+        self.store.drain(..consumed);
+        if self.spooled.is_none() && self.spool.should_spool(self.decoded.len()) {
+            self.spooled = Some((self.spool.spool(&self.spool_usage, self.decoded.len())?, 0));
+        }
+        if let Some((file, written)) = &mut self.spooled {
+            self.spool.extend(file, *written + self.decoded.len())?;
+            file.write_all(&self.decoded)?;
+            *written += self.decoded.len();
+            self.decoded.clear();
+        }
+        Ok(is_done)
+    }
+
+    /// Number of body bytes received so far, spooled or not. Chunked body counts decoded data only.
+    fn body_received(&self) -> usize {
+        let pending = if self.chunked.is_some() { self.decoded.len() } else { self.store.len() };
+        self.spooled.as_ref().map_or(0, |(_, written)| *written) + pending
+    }
+
+    /// Moves the part of the body in `store` to the spool file, if the body is being spooled.
+    /// Bytes past the end of the body belong to the next request, they stay in `store`.
+    fn spill(&mut self, content_length: usize) -> io::Result<()> {
+        let Some((file, written)) = &mut self.spooled else {
+            return Ok(());
+        };
+        let count = self.store.len().min(content_length - *written);
+        file.write_all(&self.store[..count])?;
+        *written += count;
+        self.store.drain(..count);
+        Ok(())
     }
 
-    fn download_payload(&mut self) -> io::Result<()> {
-        loop {
-            self.download_buffer.clear();
-            match self.reader.read(&mut self.download_buffer)? {
-                0 => Ok(()),
-                _ => {
-                    self.store.extend_from_slice(&self.download_buffer);
-                    match (self.store.len()).cmp(&self.content_length.unwrap()) {
-                        Ordering::Less => {
-                            continue;
-                        }
-                        _ => {
-                            let overhead = self.store.len() - self.content_length.unwrap();
-                            self.body = Some(Body::SingleSource(
-                                Entity::new(
-                                    Vec::from(&self.store[..overhead]).into_boxed_slice(),
-                                    self.request_metadata.unwrap().headers.content_type().unwrap_or_default(),
-                                )
-                            ));
-                            self.is_finished = true;
-                        }
-                    }
-                    Ok(())
-                }
+    fn finish_payload(&mut self) {
+        let content_length = self.content_length.unwrap_or_default();
+        self.body = match self.spooled.take() {
+            Some((file, written)) => Some(Body::Spooled(file, written)),
+            None => {
+                let content_type = self.request_metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.headers.content_type())
+                    .unwrap_or_default();
+                let body = match self.chunked {
+                    Some(_) => std::mem::take(&mut self.decoded).into_boxed_slice(),
+                    None => self.store.drain(..content_length).collect::<Box<[u8]>>(),
+                };
+                Some(Body::SingleSource(Entity::new(body, content_type)))
             }
+        };
+        self.is_finished = true;
+        if let Some(upload) = self.upload.take() {
+            upload.complete();
         }
     }
 }
 
 impl<R> Action for HttpDownloader<R> where R: Read {
     type Output = Option<Request>;
-    
+
     fn advance(&mut self) -> io::Result<Self::Output> {
+        if let Some(request) = self.pipelined.pop_front() {
+            self.idle = false;
+            self.is_finished = true;
+            return Ok(Some(request));
+        }
+        if self.idle {
+            self.idle = false;
+            if let Some(deadline) = &mut self.deadline {
+                deadline.restart(Instant::now());
+            }
+        }
         if !self.is_finished {
-            if self.request_metadata.is_none() {
-                self.download_metadata()?;
-            } else {
-                self.download_payload()?;
+            let downloaded = match self.request_metadata {
+                None => self.download_metadata().and_then(|_| {
+                    if self.is_finished { Ok(()) } else { self.download_payload() }
+                }),
+                Some(_) => self.download_payload(),
+            };
+            self.update_timeout();
+            match downloaded {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && self.is_expired() => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "request was not received in time"));
+                }
+                result => result?,
             }
-        } else {
-            let RequestMetaData { start_line, headers } = self.request_metadata.take().unwrap();
-            Ok(Some(Request::new(start_line, headers, self.body.take())))
         }
-        Ok(None)
+        match self.request_metadata.take() {
+            Some(RequestMetaData { start_line, headers }) => {
+                self.queue_pipelined();
+                let request = match (self.chunked.take(), self.body.take()) {
+                    (Some(_), Some(body)) => Self::dechunked(start_line, headers, body),
+                    (_, body) => Request::new(start_line, headers, body),
+                };
+                Ok(Some(request))
+            }
+            None => Ok(None),
+        }
     }
 
     fn is_finished(&self) -> bool {
@@ -295,37 +2092,294 @@ impl<R> Downloader for HttpDownloader<R> where R: Read { }
 
 
 // region Sender
-struct HttpSender<W> where W: Write {
+/// Part of the file sent after the in-memory data, `offset` is the position of the next byte to send
+/// and `end` the position right after the last one.
+struct FileBody {
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+}
+
+/// Body passed through a filter as it is sent, see `filters`.
+struct FilteredBody {
+    body: Body,
+    /// Position of the next byte of the body to filter.
+    offset: usize,
+    filter: Box<dyn ChunkFilter>,
+    chunked: bool,
+    /// Encoded output of the filter, first `written` bytes of it were already sent.
+    output: Vec<u8>,
+    written: usize,
+    is_finished: bool,
+}
+
+impl FilteredBody {
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    /// Replaces sent output with the next part of the body, returns `false` once the whole body was produced.
+    fn produce(&mut self) -> io::Result<bool> {
+        if self.is_finished {
+            return Ok(false);
+        }
+        let mut filtered = Vec::new();
+        if self.offset < self.body.len() {
+            let mut chunk = vec![0; Self::CHUNK_SIZE.min(self.body.len() - self.offset)];
+            let bytes_read = self.body.read_at(&mut chunk, self.offset)?;
+            self.offset += bytes_read;
+            self.filter.transform(&chunk[..bytes_read], &mut filtered);
+        } else {
+            self.filter.finish(&mut filtered);
+            self.is_finished = true;
+        }
+        self.output.clear();
+        self.written = 0;
+        if !self.chunked {
+            self.output = filtered;
+            return Ok(true);
+        }
+        /* empty chunk would end the body prematurely */
+        if !filtered.is_empty() {
+            write!(self.output, "{:X}\r\n", filtered.len())?;
+            self.output.extend_from_slice(&filtered);
+            self.output.extend_from_slice(b"\r\n");
+        }
+        if self.is_finished {
+            self.output.extend_from_slice(b"0\r\n\r\n");
+        }
+        Ok(true)
+    }
+}
+
+/// Sends the serialized response followed by its body.
+///
+/// Beginning of the body is sent in the same vectored write as the head, so small responses
+/// take a single system call even if their body is a file or passes through a filter.
+pub struct HttpSender<W> where W: FileSink {
     writer: BufWriter<W>,
     data: Box<[u8]>,
+    file: Option<FileBody>,
+    filtered: Option<FilteredBody>,
+    /// Beginning of the file body read into memory to be sent together with the head.
+    leading: Vec<u8>,
+    leading_sent: usize,
     timeout: TimeoutDuration,
     bytes_sent: usize,
+    /// Bytes of the head and the body written so far.
+    written: usize,
+    /// Body of unknown length pushed after the response was sent, eg. events of a stream.
+    streamed: Vec<u8>,
+    streamed_sent: usize,
+    /// Bandwidth of the response is limited if set, see `throttle`.
+    throttle: Option<Throttle>,
+    is_started: bool,
     is_finished: bool,
 }
 
-impl<W> HttpSender<W> where W: Write {
+impl<W> HttpSender<W> where W: FileSink {
+    /// Longest part of the file body read into memory to be sent together with the head,
+    /// the rest is left to `FileSink::send_file`.
+    const LEADING_FILE_LEN: usize = 16 * 1024;
+
     pub fn new(writer: W, data: Box<[u8]>) -> Self {
         Self {
-            writer,
+            writer: BufWriter::new(writer),
             data,
+            file: None,
+            filtered: None,
+            leading: Vec::new(),
+            leading_sent: 0,
             timeout: TimeoutDuration::Infinite,
             bytes_sent: 0,
+            written: 0,
+            streamed: Vec::new(),
+            streamed_sent: 0,
+            throttle: None,
+            is_started: false,
+            is_finished: false,
+        }
+    }
+
+    /// Sends first `len` bytes of the `file` once `data` is sent.
+    pub fn with_file(self, file: Arc<File>, len: usize) -> Self {
+        self.with_file_part(file, 0, len)
+    }
+
+    /// Sends `len` bytes of the `file` starting at `offset` once `data` is sent.
+    pub fn with_file_part(mut self, file: Arc<File>, offset: u64, len: usize) -> Self {
+        self.file = Some(FileBody { file, offset, end: offset + len as u64 });
+        self
+    }
+
+    /// Sends the `body` passed through the `filter` once `data` is sent, in chunks if `chunked`.
+    pub fn with_filtered_body(mut self, body: Body, filter: Box<dyn ChunkFilter>, chunked: bool) -> Self {
+        self.filtered = Some(FilteredBody {
+            body,
+            offset: 0,
+            filter,
+            chunked,
+            output: Vec::new(),
+            written: 0,
             is_finished: false,
+        });
+        self
+    }
+
+    /// Sends the body described by the response part once `data` is sent.
+    pub fn with_body_part(self, part: Option<BodyPart>) -> Self {
+        match part {
+            Some(BodyPart::File(file, offset, len)) => self.with_file_part(file, offset, len),
+            Some(BodyPart::Filtered { body, filter, chunked }) => self.with_filtered_body(body, filter, chunked),
+            None => self,
+        }
+    }
+
+    /// Writes no more than the `limit` allows, `advance` fails with `WouldBlock` once it was
+    /// reached, see `throttled_for`.
+    pub fn with_bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.throttle = Some(Throttle::new(limit));
+        self
+    }
+
+    /// Response is abandoned once the client accepts nothing for `timeout`, see `Action::timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = TimeoutDuration::Finite(timeout);
+        self
+    }
+
+    /// Bytes written so far, grows whenever `advance` makes progress.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Time to wait before calling `advance` again, if it stopped because of the bandwidth limit
+    /// rather than because the socket was not writable.
+    pub fn throttled_for(&self) -> Option<Duration> {
+        self.throttle.as_ref().and_then(Throttle::throttled_for)
+    }
+
+    /// Bytes the next write may take.
+    fn allowance(throttle: &mut Option<Throttle>) -> io::Result<usize> {
+        throttle.as_mut().map_or(Ok(usize::MAX), Throttle::allowance)
+    }
+
+    /// Records `bytes` that were written.
+    fn consume(throttle: &mut Option<Throttle>, written: &mut usize, bytes: usize) {
+        *written += bytes;
+        if let Some(throttle) = throttle {
+            throttle.consume(bytes);
         }
     }
+
+    /// Appends `data` to the body, it is sent by the following calls to `advance`.
+    /// Lets the body grow for as long as the connection lasts, see `sse`.
+    pub fn push(&mut self, data: &[u8]) {
+        self.streamed.drain(..self.streamed_sent);
+        self.streamed_sent = 0;
+        self.streamed.extend_from_slice(data);
+        self.is_finished = false;
+    }
+
+    /// Prepares the beginning of the body to be sent with the head.
+    fn start(&mut self) -> io::Result<()> {
+        if let Some(body) = &mut self.file {
+            let mut leading = vec![0; ((body.end - body.offset) as usize).min(Self::LEADING_FILE_LEN)];
+            let bytes_read = body.file.read_at(&mut leading, body.offset)?;
+            leading.truncate(bytes_read);
+            body.offset += bytes_read as u64;
+            self.leading = leading;
+        }
+        if let Some(body) = &mut self.filtered {
+            body.produce()?;
+        }
+        self.is_started = true;
+        Ok(())
+    }
 }
 
-impl<W> Action for HttpSender<W> where W: Write {
+impl<W> Action for HttpSender<W> where W: FileSink {
     type Output = ();
     
     fn advance(&mut self) -> io::Result<Self::Output> {
+        if !self.is_started {
+            self.start()?;
+        }
+        /* head goes out together with the beginning of the body, in as few writes as the socket allows */
         while self.bytes_sent < self.data.len() {
-            let bytes_written = self.writer.write(self.bytes_sent[self.bytes_sent..])?;
-            self.bytes_sent += bytes_written;
-            if self.bytes_sent == self.data.len() {
-                self.is_finished = true;
+            let allowance = Self::allowance(&mut self.throttle)?;
+            let head = &self.data[self.bytes_sent..];
+            let head = &head[..head.len().min(allowance)];
+            let leading = match &self.filtered {
+                Some(body) => &body.output[body.written..],
+                None => &self.leading[self.leading_sent..],
+            };
+            let leading = &leading[..leading.len().min(allowance - head.len())];
+            let bytes_written = self.writer.get_mut().write_vectored(&[IoSlice::new(head), IoSlice::new(leading)])?;
+            if bytes_written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            let head_written = bytes_written.min(head.len());
+            self.bytes_sent += head_written;
+            match &mut self.filtered {
+                Some(body) => body.written += bytes_written - head_written,
+                None => self.leading_sent += bytes_written - head_written,
+            }
+            Self::consume(&mut self.throttle, &mut self.written, bytes_written);
+        }
+        while self.leading_sent < self.leading.len() {
+            let allowance = Self::allowance(&mut self.throttle)?;
+            let end = self.leading.len().min(self.leading_sent.saturating_add(allowance));
+            let bytes_written = self.writer.get_mut().write(&self.leading[self.leading_sent..end])?;
+            if bytes_written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            self.leading_sent += bytes_written;
+            Self::consume(&mut self.throttle, &mut self.written, bytes_written);
+        }
+        if let Some(body) = &mut self.file {
+            /* nothing was written through the buffer, so file contents can bypass it */
+            while body.offset < body.end {
+                let count = ((body.end - body.offset) as usize).min(Self::allowance(&mut self.throttle)?);
+                let bytes_sent = self.writer.get_mut().send_file(&body.file, body.offset, count)?;
+                body.offset += bytes_sent as u64;
+                Self::consume(&mut self.throttle, &mut self.written, bytes_sent);
+            }
+        }
+        if let Some(body) = &mut self.filtered {
+            loop {
+                while body.written < body.output.len() {
+                    let allowance = match Self::allowance(&mut self.throttle) {
+                        Ok(allowance) => allowance,
+                        Err(err) => {
+                            /* buffered part of the slice goes out before the wait */
+                            self.writer.flush()?;
+                            return Err(err);
+                        }
+                    };
+                    let end = body.output.len().min(body.written.saturating_add(allowance));
+                    let bytes_written = self.writer.write(&body.output[body.written..end])?;
+                    if bytes_written == 0 {
+                        return Err(io::Error::from(io::ErrorKind::WriteZero));
+                    }
+                    body.written += bytes_written;
+                    Self::consume(&mut self.throttle, &mut self.written, bytes_written);
+                }
+                if !body.produce()? {
+                    break;
+                }
             }
+            self.writer.flush()?;
         }
+        while self.streamed_sent < self.streamed.len() {
+            let allowance = Self::allowance(&mut self.throttle)?;
+            let end = self.streamed.len().min(self.streamed_sent.saturating_add(allowance));
+            let bytes_written = self.writer.get_mut().write(&self.streamed[self.streamed_sent..end])?;
+            if bytes_written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            self.streamed_sent += bytes_written;
+            Self::consume(&mut self.throttle, &mut self.written, bytes_written);
+        }
+        self.is_finished = true;
         Ok(())
     }
 
@@ -338,11 +2392,14 @@ impl<W> Action for HttpSender<W> where W: Write {
     }
 }
 
-impl<W> Sender for HttpSender<W> where W: Write { }
+impl<W> Sender for HttpSender<W> where W: FileSink { }
 // endregion
 
 
 // region Connection
+/// Connection goes through the states in order:
+/// reading the request, handling it, writing the response and waiting for the next request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ActionStatus {
     DownloadPending,
     DownloadFinished,
@@ -350,13 +2407,46 @@ pub enum ActionStatus {
     SendFinished,
 }
 
+impl Display for ActionStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ActionStatus::DownloadPending => "Reading",
+                ActionStatus::DownloadFinished => "Handling",
+                ActionStatus::SendPending => "Writing",
+                ActionStatus::SendFinished => "Idle",
+            }
+        )
+    }
+}
+
+/// Identifier of the connection used in diagnostics.
+pub type Token = usize;
+
 pub struct Connection<D, S>
 where
     D: Downloader,
     S: Sender,
 {
     tcp_stream: TcpStream,
+    token: Token,
+    peer: Option<SocketAddr>,
     status: ActionStatus,
+    /// Request being served, diagnostics refer to it as well.
+    request: Option<RequestId>,
+    /// Virtual host of the request being served, its response is counted for it.
+    host: Option<String>,
+    /// Connection without a request in progress is closed once idle for this long.
+    idle_timeout: TimeoutDuration,
+    /// Responses are abandoned once the client accepts nothing for this long.
+    send_timeout: Duration,
+    /// Moment the current action is overdue, see `time_left`.
+    deadline: Option<Instant>,
+    /// Responses are throttled to this bandwidth if set, see `throttle`.
+    bandwidth_limit: Option<BandwidthLimit>,
+    active: Option<ActiveConnection>,
     pub downloader: D,
     pub sender: S,
 }
@@ -366,18 +2456,137 @@ where
     D: Downloader,
     S: Sender,
 {
-    const STALE_CONNECTION_TIMEOUT: TimeoutDuration = TimeoutDuration::Finite(Duration::from_millis(500));
-
-    pub fn new(mut tcp_stream: TcpStream, downloader: D, sender: S) -> Self {
-        tcp_stream.set_nonblocking(true).unwrap();
-        Self {
+    pub fn new(tcp_stream: TcpStream, token: Token, downloader: D, sender: S) -> io::Result<Self> {
+        tcp_stream.set_nonblocking(true)?;
+        let peer = tcp_stream.peer_addr().ok();
+        trace!(token, "accepted connection from {:?}", peer);
+        let mut connection = Self {
             tcp_stream,
+            token,
+            peer,
             status: ActionStatus::DownloadPending,
+            request: None,
+            host: None,
+            idle_timeout: TimeoutDuration::Finite(ReadTimeouts::default().keep_alive),
+            send_timeout: ReadTimeouts::default().send,
+            deadline: None,
+            bandwidth_limit: None,
+            active: None,
             downloader,
             sender
+        };
+        connection.postpone_deadline(Instant::now());
+        Ok(connection)
+    }
+
+    /// Connection was made on behalf of the `peer` by a proxy, see `forwarded`.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        trace!(self.token, "proxied for {}", peer);
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Idle connection is kept open for the next request for `timeout`, see `ReadTimeouts::keep_alive`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = TimeoutDuration::Finite(timeout);
+        self
+    }
+
+    /// Response the client accepts nothing of for `timeout` is abandoned, see `ReadTimeouts::send`.
+    pub fn with_send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = timeout;
+        self
+    }
+
+    pub fn send_timeout(&self) -> Duration {
+        self.send_timeout
+    }
+
+    /// Responses are written no faster than the `limit`, if there is one, see `HttpSender::with_bandwidth_limit`.
+    pub fn with_bandwidth_limit(mut self, limit: Option<BandwidthLimit>) -> Self {
+        self.bandwidth_limit = limit;
+        self
+    }
+
+    pub fn bandwidth_limit(&self) -> Option<BandwidthLimit> {
+        self.bandwidth_limit
+    }
+
+    /// Connection is counted as active in `metrics` until it is dropped.
+    pub fn with_metrics(mut self, metrics: &Arc<Metrics>) -> Self {
+        self.active = Some(metrics.connection_opened());
+        self
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Records response that was sent in full, for the virtual host of the request too.
+    pub fn response_sent(&mut self, status_code: usize, bytes: usize) {
+        let host = self.host.take();
+        if let Some(active) = &self.active {
+            active.metrics().response_sent(status_code, bytes);
+            if let Some(host) = host {
+                active.metrics().host_served(&host, status_code, bytes);
+            }
+        }
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.tcp_stream
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn status(&self) -> ActionStatus {
+        self.status
+    }
+
+    pub fn transition(&mut self, status: ActionStatus) {
+        trace!(self.token, "{} -> {}", self.status, status);
+        self.status = status;
+        self.postpone_deadline(Instant::now());
+    }
+
+    /// Current action made progress at `now`, it is overdue once its timeout passes without more.
+    pub fn postpone_deadline(&mut self, now: Instant) {
+        self.deadline = match self.timeout() {
+            TimeoutDuration::Finite(timeout) => Some(now + *timeout),
+            TimeoutDuration::Infinite => None,
+        };
+    }
+
+    /// Deadline of the current action passed at `now`. Waits end up to a millisecond early,
+    /// so their timeouts alone do not tell.
+    pub fn is_overdue(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Time left until the current action is overdue, zero once it is. Waiting for the connection
+    /// does not move the deadline, only progress does.
+    pub fn time_left(&self, now: Instant) -> TimeoutDuration {
+        match self.deadline {
+            Some(deadline) => TimeoutDuration::Finite(deadline.saturating_duration_since(now)),
+            None => TimeoutDuration::Infinite,
         }
     }
 
+    /// Checks if connection was inactive for longer than allowed in its current state.
+    pub fn is_expired(&self, inactive_for: Duration) -> bool {
+        let expired = match self.timeout() {
+            TimeoutDuration::Infinite => false,
+            TimeoutDuration::Finite(timeout) => inactive_for >= *timeout,
+        };
+        trace!(
+            self.token, "timeout check in state {}: inactive for {:?}, limit {:?}, expired: {}",
+            self.status, inactive_for, self.timeout(), expired
+        );
+        expired
+    }
+
     pub fn yield_resources(self) -> (D, S) {
         let Self { downloader, sender, .. } = self;
         (downloader, sender)
@@ -385,17 +2594,880 @@ where
 
     pub fn timeout(&self) -> &TimeoutDuration {
         match self.status {
-            ActionStatus::Pending => self.action.timeout(),
-            ActionStatus::Finished => &Self::STALE_CONNECTION_TIMEOUT
+            ActionStatus::DownloadPending => self.downloader.timeout(),
+            ActionStatus::SendPending => self.sender.timeout(),
+            ActionStatus::DownloadFinished | ActionStatus::SendFinished => &self.idle_timeout
+        }
+    }
+
+    /// Assigns an identifier to the request that was just received, see `RequestId`.
+    pub fn begin_request(&mut self, host: Option<&str>) -> RequestId {
+        let request = RequestId::next();
+        trace!(self.token, "serving request {}", request);
+        self.request = Some(request);
+        self.host = host.map(str::to_owned);
+        request
+    }
+
+    /// Diagnostics logged while the connection is being advanced refer to it,
+    /// and to the request being served if there is one.
+    pub fn context(&self) -> TraceContext {
+        let context = TraceContext::new(self.token, self.peer);
+        match self.request {
+            Some(request) => context.with_request(request),
+            None => context,
         }
     }
 
     pub fn advance_send(&mut self) -> io::Result<()> {
+        let _context = self.context().enter();
         self.sender.advance()
     }
 
     pub fn advance_download(&mut self) -> io::Result<Option<Request>> {
+        let _context = self.context().enter();
         self.downloader.advance()
     }
 }
 // endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthRule;
+    use crate::filters::Substitution;
+    use crate::http::common::Version;
+    use crate::http::headers::{Headers, SimpleHeaderParser};
+    use crate::http::request::StartLine;
+    use common::fs::TempDir;
+    use crate::reload;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    /// Handler serving the hosts of the default configuration from `catalog`.
+    fn handler(catalog: &Path) -> RequestHandler {
+        handler_for(catalog, VirtualHosts::default_config(catalog))
+    }
+
+    /// Handler serving the document roots of the `hosts` from `catalog`.
+    fn handler_for(catalog: &Path, hosts: VirtualHosts) -> RequestHandler {
+        let catalog: Arc<Path> = Arc::from(catalog);
+        let validator = StaticValidator::from_virtual_hosts(catalog.clone(), &hosts);
+        RequestHandler::new(StaticLoader::new(catalog), validator, Arc::new(hosts))
+    }
+
+    fn large_file(dir: &TempDir) -> (Arc<File>, Vec<u8>) {
+        let content = (0..200_000).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+        dir.create_file("large.bin", &content).unwrap();
+        (Arc::new(File::open(dir.path().join("large.bin")).unwrap()), content)
+    }
+
+    /// Reader returning queued chunks, `WouldBlock` when there is nothing queued.
+    struct Chunks(Arc<std::sync::Mutex<Vec<&'static [u8]>>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut chunks = self.0.lock().unwrap();
+            if chunks.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let chunk = chunks.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn test_upload_progress() {
+        let dir = TempDir::new("server-upload").unwrap();
+        let handler = handler(dir.path());
+        let progress = |id: &str| {
+            let target = format!("{}{id}", upload::PROGRESS_PATH_PREFIX);
+            let response = handler.handle(&Request::fixture(Method::GET, &target, "Host: localhost\r\n", None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        let chunks = Arc::new(std::sync::Mutex::new(vec![
+            &b"GET /upload HTTP/1.1\r\nX-Upload-Id: up-1\r\nContent-Length: 10\r\n\r\n12345"[..]
+        ]));
+        let mut downloader = HttpDownloader::new(Chunks(chunks.clone())).with_upload_tracker(handler.uploads().clone());
+        assert!(matches!(downloader.advance(), Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+        assert!(progress("up-1").ends_with(r#"{"id":"up-1","received":5,"total":10,"state":"receiving"}"#));
+
+        chunks.lock().unwrap().push(b"67890");
+        assert!(downloader.advance().unwrap().is_some());
+        assert!(progress("up-1").ends_with(r#""received":10,"total":10,"state":"complete"}"#));
+        assert!(progress("up-2").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_connections_are_accepted_on_every_address() {
+        let dir = TempDir::new("server-listeners").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let server = HttpServer::<HttpDownloader<TcpStream>, HttpSender<TcpStream>>::new("127.0.0.1:0".parse().unwrap(), Arc::from(dir.path()));
+        let port = server.address().port();
+        let mut server = server.with_additional_address(SocketAddr::new("::1".parse().unwrap(), port)).with_workers(1);
+        let addresses = server.addresses();
+        assert_eq!(addresses.len(), 2);
+        thread::spawn(move || server.start());
+        for address in addresses {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.ends_with("<p>hello</p>"), "{address}: {response}");
+        }
+    }
+
+    #[test]
+    fn test_bundle_is_served_without_document_roots() {
+        let catalog: Arc<Path> = Arc::from(Path::new("/nonexistent/catalog"));
+        let archive = Bundle::pack([("localhost/index.html", &b"<p>bundled</p>"[..]), ("localhost/docs/a.txt", b"a")]);
+        let bundle = Bundle::parse(&catalog, &archive).unwrap();
+        let virtual_hosts = VirtualHosts::default_config(&catalog);
+        let mut server = HttpServer::<HttpDownloader<TcpStream>, HttpSender<TcpStream>, _, _>::with_bundle(
+            "127.0.0.1:0".parse().unwrap(), catalog, virtual_hosts, bundle,
+        ).with_workers(1);
+        let address = server.address();
+        thread::spawn(move || server.start());
+
+        let get = |target: &str| {
+            let mut client = TcpStream::connect(address).unwrap();
+            write!(client, "GET {target} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/");
+        assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("<p>bundled</p>"), "{response}");
+        assert!(get("/docs/a.txt").ends_with("\r\n\r\na"));
+        assert!(get("/docs").starts_with("HTTP/1.1 301"));
+        assert!(get("/missing.html").starts_with("HTTP/1.1 404"));
+        assert!(get("/../localhost/index.html").starts_with("HTTP/1.1 403"));
+    }
+
+    #[test]
+    fn test_statistics_are_dumped_periodically() {
+        let dir = TempDir::new("server-stats").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let stats = dir.path().join("stats.json");
+        let mut server = HttpServer::<HttpDownloader<TcpStream>, HttpSender<TcpStream>>::new("127.0.0.1:0".parse().unwrap(), Arc::from(dir.path()))
+            .with_stats(StatsDump::new(&stats, Duration::from_millis(50)))
+            .with_workers(1);
+        let address = server.address();
+        thread::spawn(move || server.start());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\nGET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        client.read_to_end(&mut Vec::new()).unwrap();
+        let expected = r#""hosts":{"localhost":{"requests":2,"#;
+        let dumped = (0..100).find_map(|_| {
+            thread::sleep(Duration::from_millis(20));
+            std::fs::read_to_string(&stats).ok().filter(|dump| dump.contains(expected))
+        });
+        let dump = dumped.unwrap_or_else(|| panic!("no dump with {expected} in {}", stats.display()));
+        assert!(dump.contains(r#""errors":1}"#), "{dump}");
+    }
+
+    #[test]
+    fn test_large_bodies_are_spooled() {
+        let dir = TempDir::new("server-spool").unwrap();
+        let spool = SpoolConfig { threshold: 8, directory: dir.path().to_owned(), quota: None };
+        let chunks = Arc::new(std::sync::Mutex::new(vec![
+            &b"POST /upload HTTP/1.1\r\nContent-Length: 20\r\n\r\n0123456"[..],
+        ]));
+        let mut downloader = HttpDownloader::new(Chunks(chunks.clone())).with_spool(spool);
+        assert!(matches!(downloader.advance(), Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+        /* nothing beyond the header section is kept in memory */
+        assert!(downloader.store.is_empty());
+        chunks.lock().unwrap().push(b"789abcdefghij");
+        let request = downloader.advance().unwrap().unwrap();
+        let body = request.body().unwrap();
+        assert!(matches!(body, Body::Spooled(_, 20)));
+        let mut content = String::new();
+        body.reader().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "0123456789abcdefghij");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        chunks.lock().unwrap().push(b"GET / HTTP/1.1\r\nContent-Length: 8\r\n\r\n01234567");
+        downloader.reset(Chunks(chunks));
+        let request = downloader.advance().unwrap().unwrap();
+        assert!(matches!(request.body(), Some(Body::SingleSource(_))));
+        let mut content = Vec::new();
+        request.body().unwrap().reader().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"01234567");
+    }
+
+    #[test]
+    fn test_chunked_bodies_are_decoded() {
+        let dir = TempDir::new("server-chunked").unwrap();
+        let spool = SpoolConfig { threshold: 8, directory: dir.path().to_owned(), quota: None };
+        let chunks = Arc::new(std::sync::Mutex::new(vec![
+            &b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n01234\r\n"[..],
+        ]));
+        let mut downloader = HttpDownloader::new(Chunks(chunks.clone())).with_spool(spool);
+        assert!(matches!(downloader.advance(), Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+        chunks.lock().unwrap().push(b"F;ext=1\r\n56789abcdefghij\r\n0\r\nChecksum: 1\r\n\r\nGET /next HTTP/1.1\r\n");
+        let request = downloader.advance().unwrap().unwrap();
+        assert!(request.headers().unknown(chunked::TRANSFER_ENCODING_HEADER).is_none());
+        assert_eq!(request.headers().content_length(), Some(20));
+        let body = request.body().unwrap();
+        assert!(matches!(body, Body::Spooled(_, 20)));
+        let mut content = String::new();
+        body.reader().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "0123456789abcdefghij");
+        /* bytes past the last chunk belong to the next request */
+        assert_eq!(downloader.store, b"GET /next HTTP/1.1\r\n");
+
+        chunks.lock().unwrap().push(b"\r\nPOST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n");
+        downloader.reset(Chunks(chunks.clone()));
+        assert_eq!(downloader.advance().unwrap().unwrap().start_line().url(), Path::new("/next"));
+        assert_eq!(downloader.pipelined().len(), 1);
+        downloader.reset(Chunks(chunks));
+        let request = downloader.advance().unwrap().unwrap();
+        let mut content = String::new();
+        request.body().unwrap().reader().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "abc");
+    }
+
+    #[test]
+    fn test_ambiguous_body_length_is_rejected() {
+        for head in [
+            &b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n0\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+        ] {
+            let mut downloader = HttpDownloader::new(Chunks(Arc::new(std::sync::Mutex::new(vec![head]))));
+            assert!(matches!(downloader.advance(), Err(err) if err.kind() == io::ErrorKind::InvalidData));
+        }
+    }
+
+    #[test]
+    fn test_directory_policies() {
+        let dir = TempDir::new("server-directories").unwrap();
+        for host in ["redirect", "index", "missing"] {
+            dir.create_file(format!("{host}/index.html"), b"root").unwrap();
+            dir.create_file(format!("{host}/docs/index.html"), b"docs").unwrap();
+            dir.create_file(format!("{host}/empty/page.html"), b"page").unwrap();
+        }
+        let catalog = dir.path();
+        let hosts = VirtualHosts::try_from("redirect redirect\nindex index index\nmissing missing 404\n")
+            .unwrap()
+            .resolved(catalog);
+        let handler = handler_for(catalog, hosts);
+        let get = |host: &str, target: &str| {
+            let response = handler.handle(&Request::fixture(Method::GET, target, &format!("Host: {host}\r\n"), None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        for host in ["redirect", "index", "missing"] {
+            assert!(get(host, "/").ends_with("\r\n\r\nroot"), "{host}");
+            assert!(get(host, "/docs/").ends_with("\r\n\r\ndocs"), "{host}");
+            assert!(get(host, "/empty/").starts_with("HTTP/1.1 404"), "{host}");
+        }
+        let redirect = get("redirect", "/docs");
+        assert!(redirect.starts_with("HTTP/1.1 301"), "{redirect}");
+        assert!(redirect.contains("\r\nLocation: /docs/\r\n"), "{redirect}");
+        let redirect = get("redirect", "/docs?page=2");
+        assert!(redirect.contains("\r\nLocation: /docs/?page=2\r\n"), "{redirect}");
+        assert!(get("index", "/docs").ends_with("\r\n\r\ndocs"));
+        assert!(get("missing", "/docs").starts_with("HTTP/1.1 404"));
+        assert!(get("missing", "/docs/index.html").ends_with("\r\n\r\ndocs"));
+    }
+
+    #[test]
+    fn test_query_does_not_select_the_file() {
+        let dir = TempDir::new("server-query").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        dir.create_file("localhost/docs/index.html", b"docs").unwrap();
+        let handler = handler(dir.path());
+        let get = |target: &str| {
+            let response = handler.handle(&Request::fixture(Method::GET, target, "Host: localhost\r\n", None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+        assert!(get("/index.html?v=1").ends_with("\r\n\r\n<p>hello</p>"));
+        assert!(get("/?utm_source=mail&x=..%2f..").ends_with("\r\n\r\n<p>hello</p>"));
+        assert!(get("/docs/?page=2").ends_with("\r\n\r\ndocs"));
+        assert!(get("/missing.html?v=1").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_kept() {
+        let chunks = Arc::new(std::sync::Mutex::new(vec![
+            &b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /c HTTP/1.1\r\nContent-Le"[..],
+        ]));
+        let mut downloader = HttpDownloader::new(Chunks(chunks.clone()));
+        let url = |request: Request| request.start_line().url().to_owned();
+        assert_eq!(url(downloader.advance().unwrap().unwrap()), Path::new("/a"));
+        assert_eq!(downloader.pipelined().len(), 1);
+        downloader.reset(Chunks(chunks.clone()));
+        let request = downloader.advance().unwrap().unwrap();
+        let mut body = String::new();
+        request.body().unwrap().reader().read_to_string(&mut body).unwrap();
+        assert_eq!((url(request), body.as_str()), (PathBuf::from("/b"), "abc"));
+        assert!(downloader.pipelined().is_empty());
+
+        /* request split between the reads continues from the bytes kept in store */
+        downloader.reset(Chunks(chunks.clone()));
+        assert!(matches!(downloader.advance(), Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+        assert!(downloader.is_receiving());
+        chunks.lock().unwrap().push(b"ngth: 2\r\n\r\nde");
+        let request = downloader.advance().unwrap().unwrap();
+        assert_eq!(request.body().unwrap().len(), 2);
+        assert_eq!(url(request), Path::new("/c"));
+    }
+
+    #[test]
+    fn test_https_redirect_covers_configured_hosts() {
+        let dir = TempDir::new("server-redirect").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let handler = handler(dir.path())
+            .with_https_redirect(HttpsRedirect::new(8443));
+        let respond = |target: &str, headers: &str| {
+            handler.handle(&Request::fixture(Method::GET, target, headers, None))
+        };
+
+        let response = respond("/docs/missing.html?page=2", "Host: localhost:8080\r\n");
+        assert_eq!(response.status_line().status_code().code(), 301);
+        assert_eq!(response.headers().location(), Some(Path::new("https://localhost:8443/docs/missing.html?page=2")));
+        let response = respond("/index.html", "Host: evil.example\r\n");
+        assert_eq!(response.status_line().status_code().code(), 421);
+        assert_eq!(response.headers().location(), None);
+    }
+
+    #[test]
+    fn test_rewrite_map_redirects_moved_content() {
+        let dir = TempDir::new("server-rewrite").unwrap();
+        dir.create_file("localhost/old.html", b"still here").unwrap();
+        dir.create_file("localhost/index.html", b"home").unwrap();
+        let handler = handler(dir.path())
+            .with_rewrites("/old.html /new.html\n/docs/* /manual/* 307\n".parse().unwrap());
+        let respond = |target: &str| {
+            handler.handle(&Request::fixture(Method::GET, target, "Host: localhost\r\n", None))
+        };
+
+        /* rules take precedence over files that are still there */
+        let response = respond("/old.html");
+        assert_eq!(response.status_line().status_code().code(), 301);
+        assert_eq!(response.headers().location(), Some(Path::new("/new.html")));
+        let response = respond("/docs/intro.html?lang=en");
+        assert!(String::from_utf8_lossy(response.as_ref()).starts_with("HTTP/1.1 307 Temporary Redirect\r\n"));
+        assert_eq!(response.headers().location(), Some(Path::new("/manual/intro.html?lang=en")));
+        assert_eq!(respond("/index.html").status_line().status_code().code(), 200);
+    }
+
+    #[test]
+    fn test_settings_are_reloaded_unless_invalid() {
+        let dir = TempDir::new("server-reload").unwrap();
+        dir.create_file("localhost/index.html", b"home").unwrap();
+        dir.create_file("example/index.html", b"example").unwrap();
+        let rewrites = dir.create_file("rewrites", b"/old.html /new.html\n").unwrap();
+        let hosts = dir.create_file("vhosts", b"localhost localhost\n").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let files = SettingsFiles::default()
+            .with_virtual_hosts(hosts.clone(), catalog.clone())
+            .with_rewrites(rewrites.clone());
+        let handler = handler_for(&catalog, VirtualHosts::load(&hosts, &catalog).unwrap())
+            .with_rewrites(RewriteMap::load(&rewrites).unwrap())
+            .with_settings_files(files);
+        let respond = |host: &str, target: &str| {
+            let request = Request::fixture(Method::GET, target, &format!("Host: {host}\r\n"), None);
+            handler.handle(&request).status_line().status_code().code()
+        };
+        assert_eq!(respond("localhost", "/old.html"), 301);
+        assert_eq!(respond("example.org", "/index.html"), 421);
+
+        std::fs::write(&rewrites, "/moved.html /index.html 307\n").unwrap();
+        std::fs::write(&hosts, "localhost localhost\nexample.org example\n").unwrap();
+        reload::request();
+        assert_eq!(respond("localhost", "/old.html"), 404);
+        assert_eq!(respond("localhost", "/moved.html"), 307);
+        assert_eq!(respond("example.org", "/index.html"), 200);
+
+        /* invalid rewrite map keeps the previous hosts as well */
+        std::fs::write(&hosts, "localhost localhost\n").unwrap();
+        std::fs::write(&rewrites, "/moved.html /index.html 200\n").unwrap();
+        reload::request();
+        assert_eq!(respond("example.org", "/index.html"), 200);
+        assert!(handler.reload_settings().is_err());
+    }
+
+    #[test]
+    fn test_requests_are_echoed_only_if_enabled() {
+        let dir = TempDir::new("server-echo").unwrap();
+        dir.create_file("localhost/index.html", b"home").unwrap();
+        let handler = |echo: bool| handler(dir.path()).with_echo(echo);
+        let request = |method: Method, target: &str| Request::fixture(method, target, "Host: localhost\r\nCookie: id=1\r\n", None);
+
+        let enabled = handler(true);
+        let response = enabled.handle(&request(Method::GET, "/debug/echo?x=1"));
+        assert_eq!(response.status_line().status_code().code(), 200);
+        assert!(String::from_utf8_lossy(response.as_ref())
+            .ends_with("\r\n\r\nGET /debug/echo?x=1 HTTP/1.1\r\nHost: localhost\r\nCookie: id=1\r\n\r\n"));
+        let response = enabled.handle(&request(Method::TRACE, "/index.html"));
+        let response = String::from_utf8_lossy(response.as_ref()).into_owned();
+        assert!(response.contains("\r\nContent-Type: message/http\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nTRACE /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n"), "{response}");
+
+        let disabled = handler(false);
+        assert_eq!(disabled.handle(&request(Method::GET, "/debug/echo")).status_line().status_code().code(), 404);
+        assert_ne!(disabled.handle(&request(Method::TRACE, "/index.html")).status_line().status_code().code(), 200);
+    }
+
+    #[test]
+    fn test_precompressed_variants_are_negotiated() {
+        let dir = TempDir::new("server-precompressed").unwrap();
+        dir.create_file("localhost/app.js", b"plain").unwrap();
+        dir.create_file("localhost/app.js.gz", b"gzipped").unwrap();
+        dir.create_file("localhost/app.js.br", b"brotli").unwrap();
+        dir.create_file("localhost/style.css", b"body {}").unwrap();
+        let handler = handler(dir.path());
+        let respond = |target: &str, accept_encoding: &str| {
+            let headers = format!("Host: localhost\r\nAccept-Encoding: {accept_encoding}\r\n");
+            let response = handler.handle(&Request::fixture(Method::GET, target, &headers, None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        let response = respond("/app.js", "gzip, deflate");
+        assert!(response.contains("\r\nVary: Accept-Encoding\r\nContent-Encoding: gzip\r\n"), "{response}");
+        assert!(response.contains("\r\nContent-Type: text/javascript; charset=utf-8\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ngzipped"), "{response}");
+        assert!(respond("/app.js", "gzip, br").ends_with("\r\n\r\nbrotli"));
+        let response = respond("/app.js", "br;q=0, deflate");
+        assert!(response.contains("\r\nVary: Accept-Encoding\r\n") && !response.contains("Content-Encoding"), "{response}");
+        assert!(response.ends_with("\r\n\r\nplain"), "{response}");
+        let response = respond("/style.css", "gzip");
+        assert!(!response.contains("Vary") && !response.contains("Content-Encoding"), "{response}");
+    }
+
+    #[test]
+    fn test_host_is_required_unless_http_1_0() {
+        let dir = TempDir::new("server-host").unwrap();
+        dir.create_file("localhost/index.html", b"home").unwrap();
+        let catalog = dir.path();
+        let hosts = VirtualHosts::try_from("localhost localhost\n").unwrap().resolved(catalog);
+        let respond = |hosts: &VirtualHosts, version: Version, headers: &str| {
+            let handler = handler_for(catalog, hosts.clone());
+            let start_line = StartLine::new(Method::GET, Path::new("/index.html"), version);
+            let request = Request::new(start_line, Headers::parse::<SimpleHeaderParser>(headers).unwrap(), None);
+            handler.handle(&request).status_line().status_code().code()
+        };
+
+        assert_eq!(respond(&hosts, Version::V1_1, ""), 400);
+        assert_eq!(respond(&hosts, Version::V1_1, "Host: other.example\r\n"), 421);
+        assert_eq!(respond(&hosts, Version::V1, ""), 421);
+        let hosts = hosts.with_default_host("localhost");
+        assert_eq!(respond(&hosts, Version::V1_1, ""), 400);
+        assert_eq!(respond(&hosts, Version::V1_1, "Host: other.example\r\n"), 200);
+        assert_eq!(respond(&hosts, Version::V1, ""), 200);
+    }
+
+    #[test]
+    fn test_options_lists_allowed_methods() {
+        let dir = TempDir::new("server-options").unwrap();
+        let handler = handler(dir.path());
+        let response = handler.handle(&Request::fixture(Method::OPTIONS, "*", "Host: localhost\r\n", None));
+        let date = response.headers().get("Date").unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(response.as_ref()),
+            format!("HTTP/1.1 204 No Content\r\nAllow: GET, OPTIONS\r\n{date}\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_cors_preflight_skips_authentication() {
+        let dir = TempDir::new("server-cors").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let handler = handler(dir.path())
+            .with_auth(AuthPolicy::new().with_rule(AuthRule::bearer("/", "Lab", "t0ken\n")))
+            .with_cors(CorsPolicy::new().with_origin("http://localhost:3000"));
+        let respond = |method: Method, headers: &str| {
+            String::from_utf8_lossy(handler.handle(&Request::fixture(method, "/index.html", headers, None)).as_ref()).into_owned()
+        };
+
+        let response = respond(
+            Method::OPTIONS,
+            "Host: localhost\r\nOrigin: http://localhost:3000\r\nAccess-Control-Request-Method: GET\r\n",
+        );
+        let (head, cors) = response.split_once("\r\nAccess-Control").unwrap();
+        assert!(head.starts_with("HTTP/1.1 204 No Content\r\nAllow: GET, OPTIONS\r\n"), "{response}");
+        assert_eq!(
+            cors,
+            "-Allow-Origin: http://localhost:3000\r\nVary: Origin\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\n\r\n"
+        );
+        let response = respond(
+            Method::GET,
+            "Host: localhost\r\nOrigin: http://localhost:3000\r\nAuthorization: Bearer t0ken\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: http://localhost:3000\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n<p>hello</p>"), "{response}");
+        let response = respond(Method::GET, "Host: localhost\r\nOrigin: http://localhost:3000\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{response}");
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: http://localhost:3000\r\n"), "{response}");
+    }
+
+    #[test]
+    fn test_cors_preflight_does_not_reach_routes() {
+        let dir = TempDir::new("server-cors-routes").unwrap();
+        let called = Arc::new(AtomicBool::new(false));
+        let route = {
+            let called = called.clone();
+            move |request: &Request| {
+                called.store(true, Ordering::SeqCst);
+                RequestHandler::<StaticLoader>::entity_response(request, StatusCode::Ok, Entity::new(Box::from(&b"done"[..]), ContentType::Txt))
+            }
+        };
+        let handler = handler(dir.path())
+            .with_routes(Router::new().prefix("/api", route))
+            .with_auth(AuthPolicy::new().with_rule(AuthRule::bearer("/api", "API", "t0ken\n")))
+            .with_cors(CorsPolicy::new().with_origin("http://localhost:3000"));
+        let headers = "Host: localhost\r\nOrigin: http://localhost:3000\r\nAccess-Control-Request-Method: GET\r\n";
+        let response = handler.handle(&Request::fixture(Method::OPTIONS, "/api/delete-everything", headers, None));
+        let response = String::from_utf8_lossy(response.as_ref()).into_owned();
+
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{response}");
+        assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\n"), "{response}");
+        assert!(!called.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_non_canonical_targets_do_not_bypass_authentication() {
+        let dir = TempDir::new("server-auth-targets").unwrap();
+        dir.create_file("localhost/admin/secret.html", b"secret").unwrap();
+        let handler = handler(dir.path())
+            .with_auth(AuthPolicy::new().with_rule(AuthRule::bearer("/admin", "Admin area", "t0ken\n")));
+        let respond = |target: &str, headers: &str| {
+            let request = Request::fixture(Method::GET, target, &format!("Host: localhost\r\n{headers}"), None);
+            String::from_utf8_lossy(handler.handle(&request).as_ref()).into_owned()
+        };
+
+        for target in ["/admin/secret.html", "//admin/secret.html", "/%61dmin/secret.html", "/x/../admin/secret.html", "/./admin/secret.html"] {
+            let response = respond(target, "");
+            assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{target}: {response}");
+            let response = respond(target, "Authorization: Bearer t0ken\r\n");
+            assert!(response.ends_with("\r\n\r\nsecret"), "{target}: {response}");
+        }
+    }
+
+    #[test]
+    fn test_extra_headers_are_added_per_host_and_path() {
+        let dir = TempDir::new("server-extra-headers").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        dir.create_file("localhost/embed/player.html", b"<p>player</p>").unwrap();
+        let catalog = dir.path();
+        let hosts = VirtualHosts::default_config(catalog).with_default_host("localhost");
+        let rules = "localhost / X-Frame-Options: DENY\n* /embed/ X-Frame-Options: SAMEORIGIN\nlab108-18 / X-Lab: 1\n";
+        let handler = handler_for(catalog, hosts)
+            .with_extra_headers(rules.parse().unwrap());
+        let respond = |target: &str, host: &str| {
+            let request = Request::fixture(Method::GET, target, &format!("Host: {host}\r\n"), None);
+            String::from_utf8_lossy(handler.handle(&request).as_ref()).into_owned()
+        };
+
+        let response = respond("/index.html", "localhost");
+        assert!(response.contains("\r\nX-Frame-Options: DENY\r\n"), "{response}");
+        /* served by the default host */
+        let response = respond("/missing.html", "alias.example");
+        assert!(response.starts_with("HTTP/1.1 404") && response.contains("\r\nX-Frame-Options: DENY\r\n"), "{response}");
+        let response = respond("/embed/player.html", "localhost");
+        assert!(response.contains("\r\nX-Frame-Options: SAMEORIGIN\r\n") && !response.contains("DENY"), "{response}");
+        assert!(!respond("/", "lab108-18").contains("X-Frame-Options"));
+        assert!(respond("/", "lab108-18").contains("\r\nX-Lab: 1\r\n"));
+    }
+
+    #[test]
+    fn test_files_carry_caching_metadata_of_their_type() {
+        let dir = TempDir::new("server-expiry").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        dir.create_file("localhost/style.css", b"p {}").unwrap();
+        let handler = handler(dir.path())
+            .with_expiry(ExpiryPolicy::try_from("text/css=1h").unwrap());
+        let respond = |target: &str| {
+            String::from_utf8_lossy(handler.handle(&Request::fixture(Method::GET, target, "Host: localhost\r\n", None)).as_ref()).into_owned()
+        };
+
+        let response = respond("/style.css");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nCache-Control: max-age=3600\r\nDate: "), "{response}");
+        assert!(response.contains(" GMT\r\nExpires: "), "{response}");
+        let response = respond("/index.html");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(!response.contains("Cache-Control") && !response.contains("Expires"), "{response}");
+    }
+
+    #[test]
+    fn test_unsupported_methods() {
+        let dir = TempDir::new("server-methods").unwrap();
+        let handler = handler(dir.path());
+        let respond = |method: &str| {
+            let Ok(method) = method.parse() else {
+                panic!("{method} should parse");
+            };
+            let response = handler.handle(&Request::fixture(method, "/index.html", "Host: localhost\r\n", None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        let response = respond("DELETE");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, OPTIONS\r\n"), "{response}");
+        let response = respond("BREW");
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{response}");
+        assert!(!response.contains("Allow:"), "{response}");
+        assert!("GE T".parse::<Method>().is_err());
+    }
+
+    #[test]
+    fn test_uploads_create_replace_and_remove_files() {
+        let dir = TempDir::new("server-uploads").unwrap();
+        dir.create_file("localhost/index.html", b"index").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let handler = handler(&catalog)
+            .with_writer(StaticWriter::new(catalog));
+        let respond = |method: Method, target: &str, body: Option<&str>| {
+            let response = handler.handle(&Request::fixture(method, target, "Host: localhost\r\n", body.map(str::as_bytes)));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        let response = respond(Method::PUT, "/notes.txt", Some("first"));
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{response}");
+        assert!(response.contains("\r\nLocation: /notes.txt\r\n"), "{response}");
+        let response = respond(Method::PUT, "/notes.txt", Some("second"));
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{response}");
+        assert!(respond(Method::GET, "/notes.txt", None).ends_with("\r\n\r\nsecond"));
+        assert!(respond(Method::PUT, "/missing/notes.txt", Some("lost")).starts_with("HTTP/1.1 404"));
+        assert!(respond(Method::PUT, "/.hidden", Some("secret")).starts_with("HTTP/1.1 404"));
+
+        let response = respond(Method::OPTIONS, "*", None);
+        assert!(response.contains("\r\nAllow: GET, OPTIONS, PUT, DELETE\r\n"), "{response}");
+        assert!(respond(Method::DELETE, "/notes.txt", None).starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(respond(Method::DELETE, "/notes.txt", None).starts_with("HTTP/1.1 404"));
+        assert!(respond(Method::GET, "/notes.txt", None).starts_with("HTTP/1.1 404"));
+        assert!(respond(Method::GET, "/index.html", None).ends_with("\r\n\r\nindex"));
+    }
+
+    #[test]
+    fn test_uploads_over_the_quota_are_refused() {
+        let dir = TempDir::new("server-upload-quota").unwrap();
+        dir.create_file("localhost/index.html", b"index").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let quota = DiskQuota::new(catalog.clone(), 16, Duration::from_secs(60));
+        let handler = handler(&catalog)
+            .with_writer(StaticWriter::new(catalog).with_quota(quota.clone()));
+        let respond = |method: Method, target: &str, body: Option<&str>| {
+            let response = handler.handle(&Request::fixture(method, target, "Host: localhost\r\n", body.map(str::as_bytes)));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        assert!(respond(Method::PUT, "/notes.txt", Some("0123456789")).starts_with("HTTP/1.1 201 Created\r\n"));
+        let response = respond(Method::PUT, "/more.txt", Some("0123456789"));
+        assert!(response.starts_with("HTTP/1.1 507 Insufficient Storage\r\n"), "{response}");
+        assert!(respond(Method::GET, "/more.txt", None).starts_with("HTTP/1.1 404"));
+        assert_eq!(quota.used(), 15);
+        /* replaced and deleted files give their space back */
+        assert!(respond(Method::PUT, "/notes.txt", Some("01")).starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(respond(Method::DELETE, "/index.html", None).starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert_eq!(quota.used(), 2);
+        assert!(respond(Method::PUT, "/more.txt", Some("0123456789")).starts_with("HTTP/1.1 201 Created\r\n"));
+    }
+
+    #[test]
+    fn test_custom_error_page() {
+        let dir = TempDir::new("server-error-pages").unwrap();
+        dir.create_file("localhost/404.html", b"<h1>gone</h1>").unwrap();
+        let handler = handler(dir.path());
+        let respond = |host: &str| {
+            let response = handler.handle(&Request::fixture(Method::GET, "/missing.html", &format!("Host: {host}\r\n"), None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        let response = respond("localhost");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+        assert!(response.contains("Content-Type: text/html"), "{response}");
+        assert!(response.ends_with("<h1>gone</h1>"), "{response}");
+        assert!(respond("lab108-18").ends_with("Page not found"));
+    }
+
+    #[test]
+    fn test_invalid_start_line_is_rejected_early() {
+        let rejected = |data: &'static [u8]| {
+            let chunks = Arc::new(std::sync::Mutex::new(vec![data]));
+            let mut downloader = HttpDownloader::new(Chunks(chunks));
+            matches!(downloader.advance(), Err(err) if err.kind() == io::ErrorKind::InvalidData)
+        };
+        assert!(rejected(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03"));
+        assert!(rejected(b"GET /index.html\x00 HTTP/1.1\r\n"));
+        assert!(rejected(b"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"));
+        assert!(!rejected(b"GET /index.html HTTP/1.1\r"));
+        assert!(!rejected(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n"));
+    }
+
+    #[test]
+    fn test_health_check_follows_readiness() {
+        let dir = TempDir::new("server-health").unwrap();
+        dir.create_file("localhost/index.html", b"index").unwrap();
+        dir.create_file("lab108-18/index.html", b"index").unwrap();
+        let handler = handler(dir.path());
+        let check = || {
+            let response = handler.handle(&Request::fixture(Method::GET, health::HEALTH_PATH, "Host: localhost\r\n", None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        let response = check();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nNOT_READY\n"), "{response}");
+        handler.readiness().set(State::Ready);
+        let response = check();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nOK\n"), "{response}");
+        std::fs::remove_file(dir.path().join("localhost/index.html")).unwrap();
+        std::fs::remove_dir(dir.path().join("localhost")).unwrap();
+        assert!(check().starts_with("HTTP/1.1 503"));
+    }
+
+    #[test]
+    fn test_routes_take_precedence_over_files() {
+        let dir = TempDir::new("server-routes").unwrap();
+        dir.create_file("localhost/status", b"file").unwrap();
+        let echo = |request: &Request| {
+            let url = request.start_line().url().display().to_string();
+            RequestHandler::<StaticLoader>::entity_response(request, StatusCode::Ok, Entity::new(Box::from(url.as_bytes()), ContentType::Txt))
+        };
+        let handler = handler(dir.path())
+            .with_routes(Router::new().exact("/status", echo).prefix("/echo", echo));
+        let respond = |method: Method, path: &str| {
+            let response = handler.handle(&Request::fixture(method, path, "Host: localhost\r\n", None));
+            String::from_utf8_lossy(response.as_ref()).into_owned()
+        };
+
+        assert!(respond(Method::GET, "/status").ends_with("\r\n\r\n/status"));
+        assert!(respond(Method::POST, "/echo/a/b").ends_with("\r\n\r\n/echo/a/b"));
+        assert!(respond(Method::GET, "/missing").starts_with("HTTP/1.1 404"));
+        /* routes are matched by the path, handlers still see the whole target */
+        assert!(respond(Method::GET, "/status?verbose=1").ends_with("\r\n\r\n/status?verbose=1"));
+        assert!(respond(Method::GET, "/echo/items?page=2").ends_with("\r\n\r\n/echo/items?page=2"));
+        assert!(respond(Method::GET, "/metrics?x").contains("server_requests_total"));
+        let health = respond(Method::GET, "/healthz?x");
+        assert!(health.ends_with("\r\n\r\nOK\n") || health.ends_with("\r\n\r\nNOT_READY\n"), "{health}");
+    }
+
+    #[test]
+    fn test_send_file_through_buffer() {
+        let dir = TempDir::new("server-sender").unwrap();
+        let (file, content) = large_file(&dir);
+        let mut sender = HttpSender::new(Vec::new(), Box::from(&b"head\r\n\r\n"[..])).with_file(file, content.len() - 1);
+        sender.advance().unwrap();
+        assert!(sender.is_finished());
+        let sent = sender.writer.get_ref();
+        assert_eq!(&sent[..8], b"head\r\n\r\n");
+        assert_eq!(&sent[8..], &content[..content.len() - 1]);
+    }
+
+    #[test]
+    fn test_bandwidth_limit_holds_back_writes() {
+        let dir = TempDir::new("server-sender").unwrap();
+        let (file, content) = large_file(&dir);
+        let mut sender = HttpSender::new(Vec::new(), Box::from(&b"head\r\n\r\n"[..]))
+            .with_file(file, content.len())
+            .with_bandwidth_limit(BandwidthLimit::new(10_000));
+        let err = sender.advance().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(!sender.is_finished());
+        /* a tenth of a second worth of bytes, head included */
+        assert_eq!(sender.writer.get_ref().len(), 1000);
+        assert!(sender.throttled_for().is_some_and(|delay| delay <= Duration::from_millis(100)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(sender.advance().is_err());
+        let sent = sender.writer.get_ref().len();
+        assert!((1400..=1600).contains(&sent), "{sent}");
+    }
+
+    #[test]
+    fn test_filtered_body_is_sent_in_chunks() {
+        let dir = TempDir::new("server-filters").unwrap();
+        let page = format!("<html><body>{}</body></html>", "x".repeat(40_000));
+        dir.create_file("localhost/index.html", page.as_bytes()).unwrap();
+        let handler = handler(dir.path())
+            .with_body_filter(Substitution::banner("<p>banner</p>"));
+        let (data, body) = handler.handle(&Request::fixture(Method::GET, "/index.html", "Host: localhost\r\n", None)).into_parts();
+        let mut sender = HttpSender::new(Vec::new(), data).with_body_part(body);
+        sender.advance().unwrap();
+        let sent = String::from_utf8(sender.writer.into_inner().unwrap()).unwrap();
+
+        let (head, mut chunks) = sent.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+        let mut received = String::new();
+        loop {
+            let (size, rest) = chunks.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                assert_eq!(rest, "\r\n");
+                break;
+            }
+            received.push_str(&rest[..size]);
+            chunks = rest[size..].strip_prefix("\r\n").unwrap();
+        }
+        assert_eq!(received, page.replace("<body>", "<body><p>banner</p>"));
+    }
+
+    #[test]
+    fn test_send_file_to_socket() {
+        let dir = TempDir::new("server-sender").unwrap();
+        let (file, content) = large_file(&dir);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let receiver = thread::spawn(move || {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).unwrap();
+            received
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut sender = HttpSender::new(stream, Box::from(&b"head\r\n\r\n"[..])).with_file(file, content.len());
+        sender.advance().unwrap();
+        drop(sender);
+        let received = receiver.join().unwrap();
+        assert_eq!(&received[..8], b"head\r\n\r\n");
+        assert_eq!(&received[8..], content.as_slice());
+    }
+
+    /// Sink counting the calls that would be system calls on a socket.
+    #[derive(Default)]
+    struct CountingSink {
+        sent: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for CountingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.sent.write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.writes += 1;
+            self.sent.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl FileSink for CountingSink { }
+
+    #[test]
+    fn test_head_and_body_start_share_a_write() {
+        let dir = TempDir::new("server-sender").unwrap();
+        let path = dir.create_file("small.txt", b"small body").unwrap();
+        let file = Arc::new(File::open(path).unwrap());
+        let mut sender = HttpSender::new(CountingSink::default(), Box::from(&b"head\r\n\r\n"[..])).with_file(file, 10);
+        sender.advance().unwrap();
+        let sink = sender.writer.get_ref();
+        assert_eq!((sink.sent.as_slice(), sink.writes), (&b"head\r\n\r\nsmall body"[..], 1));
+
+        let (file, content) = large_file(&dir);
+        let mut sender = HttpSender::new(CountingSink::default(), Box::from(&b"head\r\n\r\n"[..])).with_file(file, content.len());
+        sender.advance().unwrap();
+        let sink = sender.writer.get_ref();
+        assert_eq!(&sink.sent[8..], content.as_slice());
+    }
+}
@@ -0,0 +1,137 @@
+//! Mikołaj Depta 328690
+//!
+//! Configuration that can be changed while the server runs.
+//!
+//! Once a reload is requested with SIGHUP, see `reload`, virtual hosts, media type overrides and
+//! rewrite rules are read again from the files named by `SERVER_VIRTUAL_HOSTS`, `SERVER_MIME_TYPES`
+//! and `SERVER_REWRITE_MAP`. Either all of them are valid and replace the current configuration
+//! at once, or the server goes on with the previous one. Connections are not touched, requests
+//! that are already being served finish with the configuration they started with.
+
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::mime::{LoadMimeTypesError, MimeTypes};
+use crate::rewrite::{LoadRewriteMapError, RewriteMap};
+use crate::vhost::{LoadVirtualHostsError, VirtualHosts};
+
+/// Part of the handler configuration that is replaced on reload.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub virtual_hosts: Arc<VirtualHosts>,
+    pub mime_types: MimeTypes,
+    pub rewrites: RewriteMap,
+}
+
+impl Settings {
+    pub fn new(virtual_hosts: Arc<VirtualHosts>) -> Self {
+        Self { virtual_hosts, mime_types: MimeTypes::new(), rewrites: RewriteMap::default() }
+    }
+}
+
+/// Files settings are read from on reload, settings without a file are kept as they are.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsFiles {
+    /// Configuration of virtual hosts and the catalog their relative document roots are resolved against.
+    virtual_hosts: Option<(PathBuf, Arc<Path>)>,
+    mime_types: Option<PathBuf>,
+    rewrites: Option<PathBuf>,
+}
+
+impl SettingsFiles {
+    pub fn from_env(catalog: Arc<Path>) -> Self {
+        let path = |variable| env::var_os(variable).map(PathBuf::from);
+        Self {
+            virtual_hosts: path(VirtualHosts::ENV_VARIABLE).map(|path| (path, catalog)),
+            mime_types: path(MimeTypes::ENV_VARIABLE),
+            rewrites: path(RewriteMap::ENV_VARIABLE),
+        }
+    }
+
+    pub fn with_virtual_hosts(mut self, path: PathBuf, catalog: Arc<Path>) -> Self {
+        self.virtual_hosts = Some((path, catalog));
+        self
+    }
+
+    pub fn with_mime_types(mut self, path: PathBuf) -> Self {
+        self.mime_types = Some(path);
+        self
+    }
+
+    pub fn with_rewrites(mut self, path: PathBuf) -> Self {
+        self.rewrites = Some(path);
+        self
+    }
+
+    /// Whether reloading would change anything.
+    pub fn is_empty(&self) -> bool {
+        self.virtual_hosts.is_none() && self.mime_types.is_none() && self.rewrites.is_none()
+    }
+
+    /// Reads settings from the files, those without a file are taken from the `current` ones.
+    /// Fails on the first file that cannot be loaded, there are no partial reloads.
+    pub fn load(&self, current: &Settings) -> Result<Settings, LoadSettingsError> {
+        let virtual_hosts = match &self.virtual_hosts {
+            Some((path, catalog)) => Arc::new(VirtualHosts::load(path, catalog).map_err(LoadSettingsError::VirtualHosts)?),
+            None => current.virtual_hosts.clone(),
+        };
+        let mime_types = match &self.mime_types {
+            Some(path) => MimeTypes::new().with_overrides_from(path).map_err(LoadSettingsError::MimeTypes)?,
+            None => current.mime_types.clone(),
+        };
+        let rewrites = match &self.rewrites {
+            Some(path) => RewriteMap::load(path).map_err(LoadSettingsError::Rewrites)?,
+            None => current.rewrites.clone(),
+        };
+        Ok(Settings { virtual_hosts, mime_types, rewrites })
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadSettingsError {
+    VirtualHosts(LoadVirtualHostsError),
+    MimeTypes(LoadMimeTypesError),
+    Rewrites(LoadRewriteMapError),
+}
+
+impl Display for LoadSettingsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::VirtualHosts(err) => write!(f, "{}: {}", VirtualHosts::ENV_VARIABLE, err),
+            Self::MimeTypes(err) => write!(f, "{}: {}", MimeTypes::ENV_VARIABLE, err),
+            Self::Rewrites(err) => write!(f, "{}: {}", RewriteMap::ENV_VARIABLE, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_invalid_file_fails_the_whole_reload() {
+        let dir = TempDir::new("settings-reload").unwrap();
+        let hosts = dir.create_file("vhosts", b"example.org example\n").unwrap();
+        let rewrites = dir.create_file("rewrites", b"/old.html /new.html\n").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let files = SettingsFiles::default()
+            .with_virtual_hosts(hosts.clone(), catalog.clone())
+            .with_rewrites(rewrites.clone());
+        let current = Settings::new(Arc::new(VirtualHosts::default_config(&catalog)));
+
+        let loaded = files.load(&current).unwrap();
+        assert_eq!(loaded.virtual_hosts.document_root("example.org"), Some(catalog.join("example").as_path()));
+        assert!(loaded.virtual_hosts.document_root("localhost").is_none());
+        assert!(!loaded.rewrites.is_empty());
+        assert_eq!(loaded.mime_types, current.mime_types);
+
+        fs::write(&rewrites, "/old.html /new.html 404\n").unwrap();
+        assert!(matches!(files.load(&current), Err(LoadSettingsError::Rewrites(_))));
+        fs::remove_file(&hosts).unwrap();
+        assert!(matches!(files.load(&current), Err(LoadSettingsError::VirtualHosts(_))));
+    }
+}
@@ -0,0 +1,23 @@
+//! Mikołaj Depta 328690
+//!
+//! Graceful shutdown requested with SIGTERM or SIGINT.
+//!
+//! Accepting server watches for both signals in its registry, see `Registry::add_signals`, and
+//! notices them right away. No handler is installed: the signals are blocked in the thread that
+//! creates the server, in its workers and in the thread that starts it, so they are only received
+//! through the registry, see `HttpServer`. Server then finishes connections being served, persists
+//! its statistics and returns from `start`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Requests shutdown as if SIGTERM was received.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Shutdown was requested, the request stays in effect.
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,164 @@
+//! Mikołaj Depta 328690
+//!
+//! Snapshots of the responses the server generates itself, compared byte for byte with the
+//! golden files in `snapshots/`, so changes to the pages and to the header serialization
+//! cannot go unnoticed.
+//!
+//! Fields that differ between runs - latencies, entity tags and dates - are normalized first.
+//! Run the tests with `UPDATE_SNAPSHOTS=1` to write the current output as the new golden files.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::fs::TempDir;
+use crate::auth::{AuthPolicy, AuthRule};
+use crate::health::{self, State};
+use crate::http::common::Method;
+use crate::http::request::Request;
+use crate::http::response::Response;
+use crate::metrics;
+use crate::ratelimit::RateLimitConfig;
+use crate::resources::{StaticLoader, StaticValidator};
+use crate::server::RequestHandler;
+use crate::upload;
+use crate::vhost::VirtualHosts;
+
+const UPDATE_ENV_VARIABLE: &str = "UPDATE_SNAPSHOTS";
+
+fn golden_file(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots").join(format!("{name}.http"))
+}
+
+/// Response with CRLF line endings of the head shown as plain line feeds,
+/// `Content-Length` is checked against the body and describes the normalized one.
+fn render(response: &Response) -> String {
+    let raw = String::from_utf8(response.as_ref().to_vec()).expect("generated responses are text");
+    let (head, body) = raw.split_once("\r\n\r\n").expect("response has a header section");
+    assert!(!head.replace("\r\n", "").contains('\n'), "bare line feed in the header section:\n{head}");
+    let normalized_body = normalize_body(body);
+    let head = head
+        .split("\r\n")
+        .map(|line| match line.split_once(": ") {
+            Some(("Content-Length", length)) => {
+                assert_eq!(length.parse::<usize>().unwrap(), body.len(), "Content-Length does not match the body");
+                format!("Content-Length: {}", normalized_body.len())
+            }
+            Some(("ETag", _)) => "ETag: \"<etag>\"".to_owned(),
+            Some(("Date", _)) => "Date: <date>".to_owned(),
+            _ => line.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("{head}\n\n{normalized_body}")
+}
+
+/// Replaces latency histogram buckets and sums, they depend on how fast the requests were handled.
+fn normalize_body(body: &str) -> String {
+    body
+        .split_inclusive('\n')
+        .map(|line| {
+            let is_latency_bucket = line.starts_with("server_handler_duration_seconds_bucket") && !line.contains("le=\"+Inf\"");
+            let is_latency_sum = line.starts_with("server_handler_duration_seconds_sum");
+            match line.rsplit_once(' ') {
+                Some((series, _)) if is_latency_bucket => format!("{series} <count>\n"),
+                Some((series, _)) if is_latency_sum => format!("{series} <seconds>\n"),
+                _ => line.to_owned(),
+            }
+        })
+        .collect()
+}
+
+fn assert_snapshot(name: &str, response: &Response) {
+    let rendered = render(response);
+    let path = golden_file(name);
+    if env::var_os(UPDATE_ENV_VARIABLE).is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &rendered).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("could not read {}: {err}, run with {UPDATE_ENV_VARIABLE}=1 to create it", path.display()));
+    assert!(
+        rendered == expected,
+        "response differs from {}, run with {UPDATE_ENV_VARIABLE}=1 if the change is intended\n\
+         --- expected\n{expected}\n--- actual\n{rendered}",
+        path.display()
+    );
+}
+
+struct Site {
+    /// Document roots live here, removed with the handler.
+    _dir: TempDir,
+    handler: RequestHandler,
+}
+
+impl Site {
+    /// Host `localhost` with an index in `docs/` and a custom 404 page, host `plain` without pages.
+    /// Everything below `/private` requires a bearer token.
+    fn new() -> Self {
+        let dir = TempDir::new("server-snapshots").unwrap();
+        dir.create_file("localhost/index.html", b"<p>home</p>").unwrap();
+        dir.create_file("localhost/docs/index.html", b"<p>docs</p>").unwrap();
+        dir.create_file("localhost/404.html", b"<h1>Nothing here</h1>\n").unwrap();
+        dir.create_file("plain/index.html", b"<p>plain</p>").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let hosts = VirtualHosts::try_from("localhost localhost\nplain plain\n").unwrap().resolved(&catalog);
+        let validator = StaticValidator::from_virtual_hosts(catalog.clone(), &hosts);
+        let handler = RequestHandler::new(StaticLoader::new(catalog), validator, Arc::new(hosts))
+            .with_rate_limit(RateLimitConfig { requests: 1, period: Duration::from_secs(60) })
+            .with_auth(AuthPolicy::new().with_rule(AuthRule::bearer("/private", "Private area", "token\n")));
+        Self { _dir: dir, handler }
+    }
+
+    fn get(&self, host: &str, target: &str) -> Response {
+        self.handler.handle(&Request::fixture(Method::GET, target, &format!("Host: {host}\r\n"), None))
+    }
+}
+
+#[test]
+fn test_error_page_snapshots() {
+    let site = Site::new();
+    assert_snapshot("not_found_built_in", &site.get("plain", "/missing.html"));
+    assert_snapshot("not_found_custom_page", &site.get("localhost", "/missing.html"));
+    assert_snapshot("bad_request", &site.handler.bad_request());
+    assert_snapshot("request_timeout", &site.handler.request_timeout());
+    assert_snapshot("insufficient_storage", &site.handler.insufficient_storage());
+    let delete = Request::fixture(Method::DELETE, "/index.html", "Host: localhost\r\nConnection: keep-alive\r\n", None);
+    assert_snapshot("method_not_allowed", &site.handler.handle(&delete));
+    assert_snapshot("unauthorized", &site.get("plain", "/private/index.html"));
+    assert_snapshot("misdirected_request", &site.get("elsewhere.example", "/index.html"));
+    assert_snapshot("host_missing", &site.handler.handle(&Request::fixture(Method::GET, "/index.html", "", None)));
+    let peer = "127.0.0.1".parse().ok();
+    site.handler.handle_from(peer, &Request::fixture(Method::GET, "/", "Host: plain\r\n", None));
+    assert_snapshot("too_many_requests", &site.handler.handle_from(peer, &Request::fixture(Method::GET, "/", "Host: plain\r\n", None)));
+}
+
+#[test]
+fn test_generated_page_snapshots() {
+    let site = Site::new();
+    assert_snapshot("options", &site.handler.handle(&Request::fixture(Method::OPTIONS, "*", "Host: localhost\r\n", None)));
+    assert_snapshot("directory_redirect", &site.get("localhost", "/docs"));
+    assert_snapshot("health_not_ready", &site.get("localhost", health::HEALTH_PATH));
+    site.handler.readiness().set(State::Ready);
+    assert_snapshot("health_ready", &site.get("localhost", health::HEALTH_PATH));
+    let guard = site.handler.uploads().start("snapshot", 1000).unwrap();
+    guard.update(250);
+    let progress_path = format!("{}snapshot", upload::PROGRESS_PATH_PREFIX);
+    assert_snapshot("upload_progress", &site.get("localhost", &progress_path));
+}
+
+#[test]
+fn test_metrics_snapshot() {
+    let site = Site::new();
+    let metrics = site.handler.metrics().clone();
+    metrics.connection_accepted();
+    let _connection = metrics.connection_opened();
+    for target in ["/docs", "/missing.html"] {
+        let response = site.get("localhost", target);
+        metrics.response_sent(response.status_line().status_code().code(), response.len());
+    }
+    assert_snapshot("metrics", &site.get("localhost", metrics::METRICS_PATH));
+}
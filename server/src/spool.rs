@@ -0,0 +1,262 @@
+//! Mikołaj Depta 328690
+//!
+//! Request bodies too large to be kept in memory, spooled into temporary files.
+//!
+//! Body longer than the threshold is written to an anonymous file as it arrives, so memory
+//! used by a connection does not grow with the size of the request. The file has no name,
+//! its space is released once the request is dropped.
+//!
+//! Space taken by spool files of all connections together may be limited with `SERVER_SPOOL_QUOTA`.
+//! Space for the whole body is reserved before the first byte is written, so a body that would not
+//! fit is refused up front. Reservations are checked against the space actually left on the device
+//! as well, since other processes share it with the spool. Files written by uploads are limited
+//! separately, see `quota`.
+
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use common::units;
+use crate::logger::{log, Level};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SpoolConfig {
+    /// Bodies longer than this are spooled, shorter ones are kept in memory.
+    pub threshold: usize,
+    /// Directory temporary files are created in.
+    pub directory: PathBuf,
+    /// Limit of the space taken by spool files of all connections, none if only the device limits it.
+    pub quota: Option<usize>,
+}
+
+impl SpoolConfig {
+    pub const THRESHOLD_ENV_VARIABLE: &'static str = "SERVER_SPOOL_THRESHOLD";
+    pub const DIR_ENV_VARIABLE: &'static str = "SERVER_SPOOL_DIR";
+    pub const QUOTA_ENV_VARIABLE: &'static str = "SERVER_SPOOL_QUOTA";
+
+    /// Defaults overridden by `SERVER_SPOOL_THRESHOLD`, eg. `1MiB`, `SERVER_SPOOL_DIR` and `SERVER_SPOOL_QUOTA`.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(repr) = env::var(Self::THRESHOLD_ENV_VARIABLE) {
+            match units::parse_size(&repr) {
+                Ok(threshold) => config.threshold = threshold,
+                Err(err) => log!(Level::Warn, "{}: invalid size '{}': {}", Self::THRESHOLD_ENV_VARIABLE, repr, err),
+            }
+        }
+        if let Some(directory) = env::var_os(Self::DIR_ENV_VARIABLE) {
+            config.directory = PathBuf::from(directory);
+        }
+        if let Ok(repr) = env::var(Self::QUOTA_ENV_VARIABLE) {
+            match units::parse_size(&repr) {
+                Ok(quota) => config.quota = Some(quota),
+                Err(err) => log!(Level::Warn, "{}: invalid size '{}': {}", Self::QUOTA_ENV_VARIABLE, repr, err),
+            }
+        }
+        config
+    }
+
+    pub fn should_spool(&self, content_length: usize) -> bool {
+        content_length > self.threshold
+    }
+
+    /// Creates a spool file for a body of `length` bytes, with space for it reserved in `usage`.
+    /// Fails with `StorageFull` if the body would exceed the quota or the space left on the device.
+    pub fn spool(&self, usage: &SpoolUsage, length: usize) -> io::Result<SpoolFile> {
+        let reservation = usage.reserve(length, self.quota)?;
+        let available = self.available_space()?;
+        if length as u64 > available {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("body of {length} bytes does not fit into {available} bytes left in {}", self.directory.display()),
+            ));
+        }
+        Ok(SpoolFile { file: self.create_file()?, reservation })
+    }
+
+    /// Makes the reservation of `file` cover `length` bytes, for bodies whose length is not known
+    /// up front. Space is reserved at least a threshold at a time, so the device is not checked
+    /// for every read. Fails with `StorageFull` like `spool`.
+    pub fn extend(&self, file: &mut SpoolFile, length: usize) -> io::Result<()> {
+        let Some(missing) = length.checked_sub(file.reservation.length).filter(|&missing| missing > 0) else {
+            return Ok(());
+        };
+        let additional = missing.max(self.threshold);
+        let mut extension = file.reservation.usage.reserve(additional, self.quota)?;
+        let available = self.available_space()?;
+        if (length - file.reservation.length) as u64 > available {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("body of {length} bytes does not fit into {available} bytes left in {}", self.directory.display()),
+            ));
+        }
+        /* extension is merged into the reservation of the file, it gives nothing back on its own */
+        file.reservation.length += std::mem::take(&mut extension.length);
+        Ok(())
+    }
+
+    /// Space left on the device of the spool directory for unprivileged users.
+    fn available_space(&self) -> io::Result<u64> {
+        let path = CString::new(self.directory.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+        /* SAFETY: path is NUL terminated and stats is written by a successful call */
+        if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stats = unsafe { stats.assume_init() };
+        Ok(stats.f_bavail * stats.f_frsize)
+    }
+
+    /// Creates an anonymous file in the spool directory. Where the file system cannot create
+    /// unnamed files, a named one is created and unlinked right away.
+    pub fn create_file(&self) -> io::Result<File> {
+        let unnamed = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE)
+            .open(&self.directory);
+        match unnamed {
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP | libc::EISDIR)) => self.create_unlinked_file(),
+            result => result,
+        }
+    }
+
+    fn create_unlinked_file(&self) -> io::Result<File> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(".spool-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = self.directory.join(name);
+        let file = OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(file)
+    }
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self { threshold: 1024 * 1024, directory: env::temp_dir(), quota: None }
+    }
+}
+
+/// Space reserved by spool files of all connections sharing it.
+#[derive(Debug, Clone, Default)]
+pub struct SpoolUsage(Arc<AtomicUsize>);
+
+impl SpoolUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes reserved by spool files that are still alive.
+    pub fn reserved(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reserve(&self, length: usize, quota: Option<usize>) -> io::Result<Reservation> {
+        let limit = quota.unwrap_or(usize::MAX);
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |reserved| {
+                reserved.checked_add(length).filter(|&total| total <= limit)
+            })
+            .map_err(|reserved| io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("body of {length} bytes exceeds spool quota of {limit} bytes, {reserved} bytes are in use"),
+            ))?;
+        Ok(Reservation { usage: self.clone(), length })
+    }
+}
+
+/// Space of a spool file, given back once the file is dropped.
+#[derive(Debug)]
+struct Reservation {
+    usage: SpoolUsage,
+    length: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.usage.0.fetch_sub(self.length, Ordering::AcqRel);
+    }
+}
+
+/// Anonymous file of a spooled body, counted towards the spool quota while it is alive.
+#[derive(Debug)]
+pub struct SpoolFile {
+    file: File,
+    reservation: Reservation,
+}
+
+impl Deref for SpoolFile {
+    type Target = File;
+
+    fn deref(&self) -> &Self::Target {
+        &self.file
+    }
+}
+
+impl DerefMut for SpoolFile {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::TempDir;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn test_spool_files_are_anonymous() {
+        let dir = TempDir::new("server-spool").unwrap();
+        let config = SpoolConfig { threshold: 4, directory: dir.path().to_owned(), quota: None };
+        assert!(!config.should_spool(4));
+        assert!(config.should_spool(5));
+        for mut file in [config.create_file().unwrap(), config.create_unlinked_file().unwrap()] {
+            file.write_all(b"spooled body").unwrap();
+            let mut buffer = [0; 4];
+            file.read_exact_at(&mut buffer, 8).unwrap();
+            assert_eq!(&buffer, b"body");
+        }
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_quota_is_shared_by_spool_files() {
+        let dir = TempDir::new("server-spool").unwrap();
+        let config = SpoolConfig { threshold: 4, directory: dir.path().to_owned(), quota: Some(100) };
+        let usage = SpoolUsage::new();
+        let first = config.spool(&usage, 60).unwrap();
+        assert_eq!(usage.reserved(), 60);
+        let err = config.spool(&usage, 41).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(usage.reserved(), 60);
+        let second = config.spool(&usage, 40).unwrap();
+        drop(first);
+        assert_eq!(usage.reserved(), 40);
+
+        let mut third = config.spool(&usage, 10).unwrap();
+        config.extend(&mut third, 30).unwrap();
+        assert_eq!(usage.reserved(), 70);
+        let err = config.extend(&mut third, 70).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        drop(third);
+        assert_eq!(usage.reserved(), 40);
+        drop(second);
+
+        let unlimited = SpoolConfig { quota: None, ..config };
+        let err = unlimited.spool(&usage, usize::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(usage.reserved(), 0);
+    }
+}
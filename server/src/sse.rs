@@ -0,0 +1,171 @@
+//! Mikołaj Depta 328690
+//!
+//! Server-Sent Events, see https://html.spec.whatwg.org/multipage/server-sent-events.html.
+//!
+//! Event stream is a `text/event-stream` response whose body never ends on its own. Once the head
+//! is sent, the worker keeps the connection to itself and writes the next event of the source
+//! every interval, until the source runs out of events or the client goes away. Body ends with
+//! the connection, so the stream is not chunked and the connection is not reused.
+
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::http::request::Request;
+use crate::http::response::{Response, StatusCode};
+use crate::metrics::Metrics;
+use crate::routing::Handler;
+
+/// Single message of the stream, its data may span multiple lines.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Event {
+    name: Option<String>,
+    id: Option<String>,
+    data: String,
+}
+
+impl Event {
+    pub fn new(data: &str) -> Self {
+        Self { name: None, id: None, data: data.to_owned() }
+    }
+
+    /// Clients dispatch the event to the listeners of `name` instead of the `message` ones.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Clients send the id of the last event they received when they reconnect.
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_owned());
+        self
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        /* line breaks would end the field early, names and ids cannot contain them */
+        let single_line = |value: &str| value.replace(['\r', '\n'], " ");
+        if let Some(name) = &self.name {
+            writeln!(f, "event: {}", single_line(name))?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", single_line(id))?;
+        }
+        for line in self.data.split('\n') {
+            writeln!(f, "data: {}", line.trim_end_matches('\r'))?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Produces the events sent to a single client.
+pub trait EventSource: Send {
+    /// Next event, `None` ends the stream and closes the connection.
+    fn next_event(&mut self) -> Option<Event>;
+}
+
+impl<F> EventSource for F
+where
+    F: FnMut() -> Option<Event> + Send,
+{
+    fn next_event(&mut self) -> Option<Event> {
+        self()
+    }
+}
+
+/// Events of the source paced by the interval, the first one is due right away.
+pub struct EventStream {
+    source: Box<dyn EventSource>,
+    interval: Duration,
+    next_at: Instant,
+}
+
+impl EventStream {
+    pub fn new(source: impl EventSource + 'static, interval: Duration) -> Self {
+        Self { source: Box::new(source), interval, next_at: Instant::now() }
+    }
+
+    /// Time left until the next event is due, zero if it already is.
+    pub fn time_until_next(&self, now: Instant) -> Duration {
+        self.next_at.saturating_duration_since(now)
+    }
+
+    /// Next event of the source, the one after it is due an interval later.
+    pub fn next_event(&mut self, now: Instant) -> Option<Event> {
+        self.next_at = now + self.interval;
+        self.source.next_event()
+    }
+}
+
+/// Route handler answering with an event stream, a new source is created for every request.
+pub struct EventStreamHandler<F> {
+    interval: Duration,
+    new_source: F,
+}
+
+impl<F, S> EventStreamHandler<F>
+where
+    F: Fn(&Request) -> S + Send + Sync,
+    S: EventSource + 'static,
+{
+    pub fn new(interval: Duration, new_source: F) -> Self {
+        Self { interval, new_source }
+    }
+}
+
+impl<F, S> Handler for EventStreamHandler<F>
+where
+    F: Fn(&Request) -> S + Send + Sync,
+    S: EventSource + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        let stream = EventStream::new((self.new_source)(request), self.interval);
+        Response::builder(StatusCode::Ok).in_reply_to(request).with_event_stream(stream).build()
+    }
+}
+
+/// `stats` events with connection and traffic counters of the server, eg.
+/// `{"accepted":12,"active":3,"sent_bytes":48211}`.
+pub fn stats(metrics: Arc<Metrics>) -> impl EventSource {
+    move || {
+        let data = format!(
+            r#"{{"accepted":{},"active":{},"sent_bytes":{}}}"#,
+            metrics.accepted_connections(), metrics.active_connections(), metrics.bytes_sent()
+        );
+        Some(Event::new(&data).with_name("stats"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_format() {
+        assert_eq!(Event::new("hello").to_string(), "data: hello\n\n");
+        assert_eq!(
+            Event::new("first\r\nsecond").with_name("update").with_id("7").to_string(),
+            "event: update\nid: 7\ndata: first\ndata: second\n\n"
+        );
+        assert_eq!(Event::new("").with_name("a\nb").to_string(), "event: a b\ndata: \n\n");
+    }
+
+    #[test]
+    fn test_events_are_paced() {
+        let mut count = 0;
+        let mut stream = EventStream::new(
+            move || {
+                count += 1;
+                (count <= 2).then(|| Event::new(&count.to_string()))
+            },
+            Duration::from_secs(5),
+        );
+        let now = Instant::now();
+        assert_eq!(stream.time_until_next(now + Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(stream.next_event(now), Some(Event::new("1")));
+        assert_eq!(stream.time_until_next(now + Duration::from_secs(1)), Duration::from_secs(4));
+        assert_eq!(stream.next_event(now), Some(Event::new("2")));
+        assert_eq!(stream.next_event(now), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! Mikołaj Depta 328690
+//!
+//! Statistics of the virtual hosts persisted to a file.
+//!
+//! File named by `SERVER_STATS_FILE` is rewritten with the counters of every virtual host each
+//! `SERVER_STATS_INTERVAL` minutes, 5 by default, and once more when the server shuts down:
+//!
+//! ```text
+//! {"timestamp":1700000000,"connections":12,"hosts":{"localhost":{"requests":30,"bytes":4096,"errors":2}}}
+//! ```
+//!
+//! Counters are totals since the server started. New dump is written next to the file and renamed
+//! over it, the previous one is rotated to `<file>.1`, so readers never see a partial dump and
+//! two consecutive dumps are always at hand.
+//!
+//! Dumps are timed by a registry timer the server watches together with its listening sockets.
+
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logger::{log, Level};
+use crate::metrics::Metrics;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StatsDump {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl StatsDump {
+    pub const ENV_VARIABLE: &'static str = "SERVER_STATS_FILE";
+    pub const INTERVAL_ENV_VARIABLE: &'static str = "SERVER_STATS_INTERVAL";
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self { path: path.into(), interval }
+    }
+
+    /// Dump to the file named by `SERVER_STATS_FILE`, `None` if the variable is not set.
+    /// Invalid interval is replaced with the default one.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var_os(Self::ENV_VARIABLE)?;
+        let interval = match env::var(Self::INTERVAL_ENV_VARIABLE) {
+            Err(_) => Self::DEFAULT_INTERVAL,
+            Ok(repr) => match repr.trim().parse::<u64>() {
+                Ok(minutes) if minutes > 0 => Duration::from_secs(minutes * 60),
+                _ => {
+                    log!(
+                        Level::Warn, "{}: expected a positive number of minutes, got '{}', using {:?}",
+                        Self::INTERVAL_ENV_VARIABLE, repr, Self::DEFAULT_INTERVAL
+                    );
+                    Self::DEFAULT_INTERVAL
+                }
+            },
+        };
+        Some(Self::new(path, interval))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Writes current counters of the `metrics`, rotating the previous dump.
+    pub fn write(&self, metrics: &Metrics) -> io::Result<()> {
+        let staged = self.sibling(".tmp");
+        fs::write(&staged, Self::render(metrics, SystemTime::now()))?;
+        match fs::rename(&self.path, self.sibling(".1")) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        fs::rename(&staged, &self.path)
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(suffix);
+        PathBuf::from(name)
+    }
+
+    fn render(metrics: &Metrics, now: SystemTime) -> String {
+        let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut out = format!(r#"{{"timestamp":{},"connections":{},"hosts":{{"#, timestamp, metrics.accepted_connections());
+        for (index, (host, counters)) in metrics.hosts().iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            let host = host.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = write!(
+                out,
+                r#"{separator}"{host}":{{"requests":{},"bytes":{},"errors":{}}}"#,
+                counters.requests, counters.bytes, counters.errors
+            );
+        }
+        out.push_str("}}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::TempDir;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.connection_accepted();
+        metrics.host_served("localhost", 200, 100);
+        metrics.host_served("localhost", 404, 5);
+        metrics.host_served("example.com", 200, 7);
+        assert_eq!(
+            StatsDump::render(&metrics, UNIX_EPOCH + Duration::from_secs(1700000000)),
+            "{\"timestamp\":1700000000,\"connections\":1,\"hosts\":{\
+            \"example.com\":{\"requests\":1,\"bytes\":7,\"errors\":0},\
+            \"localhost\":{\"requests\":2,\"bytes\":105,\"errors\":1}}}\n"
+        );
+        assert_eq!(StatsDump::render(&Metrics::new(), UNIX_EPOCH), "{\"timestamp\":0,\"connections\":0,\"hosts\":{}}\n");
+    }
+
+    #[test]
+    fn test_dumps_are_rotated() {
+        let dir = TempDir::new("server-stats").unwrap();
+        let dump = StatsDump::new(dir.path().join("stats.json"), Duration::from_secs(60));
+        let metrics = Metrics::new();
+        dump.write(&metrics).unwrap();
+        metrics.connection_accepted();
+        dump.write(&metrics).unwrap();
+        assert!(fs::read_to_string(dump.path()).unwrap().contains("\"connections\":1"));
+        assert!(fs::read_to_string(dir.path().join("stats.json.1")).unwrap().contains("\"connections\":0"));
+        assert!(!dir.path().join("stats.json.tmp").exists());
+    }
+}
@@ -0,0 +1,140 @@
+//! Mikołaj Depta 328690
+//!
+//! Bandwidth limit of responses, for experiments with slow networks.
+//!
+//! Limit is set with `SERVER_BANDWIDTH_LIMIT` in bytes per second, eg. `64KiB`. Every response is
+//! written through a token bucket holding a tenth of a second worth of bytes, so it goes out in
+//! slices ten times a second. Once the bucket runs dry the connection stops watching the socket
+//! and waits for a timer instead, the socket would be reported writable all the time.
+
+use std::env;
+use std::io;
+use std::time::{Duration, Instant};
+
+use common::units;
+use crate::logger::{log, Level};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BandwidthLimit {
+    bytes_per_second: usize,
+}
+
+impl BandwidthLimit {
+    pub const ENV_VARIABLE: &'static str = "SERVER_BANDWIDTH_LIMIT";
+    /// Slices are sent this many times a second.
+    const SLICES_PER_SECOND: usize = 10;
+
+    pub fn new(bytes_per_second: usize) -> Self {
+        Self { bytes_per_second: bytes_per_second.max(1) }
+    }
+
+    /// Limit from `SERVER_BANDWIDTH_LIMIT`, `None` if it is not set or invalid.
+    pub fn from_env() -> Option<Self> {
+        let repr = env::var(Self::ENV_VARIABLE).ok()?;
+        match units::parse_size(&repr) {
+            Ok(0) => {
+                log!(Level::Warn, "{}: bandwidth limit has to be positive, responses are not throttled", Self::ENV_VARIABLE);
+                None
+            }
+            Ok(bytes_per_second) => Some(Self::new(bytes_per_second)),
+            Err(err) => {
+                log!(Level::Warn, "{}: invalid size '{}': {}", Self::ENV_VARIABLE, repr, err);
+                None
+            }
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_second
+    }
+
+    /// Most bytes written at once.
+    fn slice(&self) -> usize {
+        (self.bytes_per_second / Self::SLICES_PER_SECOND).max(1)
+    }
+}
+
+/// Token bucket of a single response, a token per byte.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    limit: BandwidthLimit,
+    tokens: f64,
+    updated: Instant,
+    /// Time until the bucket is full again, if the last write was held back because it was empty.
+    throttled: Option<Duration>,
+}
+
+impl Throttle {
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Self::starting_at(limit, Instant::now())
+    }
+
+    fn starting_at(limit: BandwidthLimit, now: Instant) -> Self {
+        Self { limit, tokens: limit.slice() as f64, updated: now, throttled: None }
+    }
+
+    /// Number of bytes that may be written now. Fails with `WouldBlock` if there are none,
+    /// `throttled_for` tells how long to wait then.
+    pub fn allowance(&mut self) -> io::Result<usize> {
+        self.allowance_at(Instant::now())
+    }
+
+    fn allowance_at(&mut self, now: Instant) -> io::Result<usize> {
+        let capacity = self.limit.slice() as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.bytes_per_second as f64).min(capacity);
+        self.updated = now;
+        match self.tokens as usize {
+            0 => {
+                let missing = capacity - self.tokens;
+                self.throttled = Some(Duration::from_secs_f64(missing / self.limit.bytes_per_second as f64));
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "bandwidth limit reached"))
+            }
+            available => {
+                self.throttled = None;
+                Ok(available)
+            }
+        }
+    }
+
+    /// Takes tokens for `bytes` that were written.
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens = (self.tokens - bytes as f64).max(0.0);
+    }
+
+    /// Time until a full slice may be written, if the last write was held back by the limit
+    /// rather than by the socket.
+    pub fn throttled_for(&self) -> Option<Duration> {
+        self.throttled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_at_the_limit() {
+        let start = Instant::now();
+        let mut throttle = Throttle::starting_at(BandwidthLimit::new(1000), start);
+        assert_eq!(throttle.allowance_at(start).unwrap(), 100);
+        throttle.consume(100);
+        let err = throttle.allowance_at(start).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(throttle.throttled_for(), Some(Duration::from_millis(100)));
+        assert_eq!(throttle.allowance_at(start + Duration::from_millis(30)).unwrap(), 30);
+        assert_eq!(throttle.throttled_for(), None);
+        /* idle connection does not save up more than a slice */
+        assert_eq!(throttle.allowance_at(start + Duration::from_secs(10)).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_tiny_limits_still_send() {
+        let start = Instant::now();
+        let mut throttle = Throttle::starting_at(BandwidthLimit::new(5), start);
+        assert_eq!(throttle.allowance_at(start).unwrap(), 1);
+        throttle.consume(1);
+        assert!(throttle.allowance_at(start).is_err());
+        assert_eq!(throttle.throttled_for(), Some(Duration::from_millis(200)));
+    }
+}
@@ -0,0 +1,188 @@
+//! Mikołaj Depta 328690
+//!
+//! Deadlines for receiving requests, protecting workers from clients that send them slowly.
+//!
+//! Header section has to arrive within a fixed time since the request started. Body has to
+//! keep arriving at a minimal average rate, measured since the header section was received.
+//! Connection exceeding either deadline is answered with 408 Request Timeout and closed.
+//!
+//! Connection kept alive between requests is closed once it stays idle for the keep-alive timeout,
+//! which responses advertise in the `Keep-Alive` header. Client that stops accepting a response
+//! for longer than the send timeout has its connection closed as well.
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use common::units;
+use crate::logger::{log, Level};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ReadTimeouts {
+    /// Time allowed for receiving the whole header section.
+    pub headers: Duration,
+    /// Bytes of the body per second the client has to keep up with on average, zero disables the check.
+    pub min_body_rate: usize,
+    /// Time the body can take regardless of its rate, so slow starts are not punished.
+    pub body_grace: Duration,
+    /// Time an idle connection is kept open for the next request.
+    pub keep_alive: Duration,
+    /// Time the client may go without accepting any part of the response.
+    pub send: Duration,
+}
+
+impl ReadTimeouts {
+    pub const HEADER_ENV_VARIABLE: &'static str = "SERVER_HEADER_TIMEOUT";
+    pub const RATE_ENV_VARIABLE: &'static str = "SERVER_MIN_BODY_RATE";
+    pub const KEEP_ALIVE_ENV_VARIABLE: &'static str = "SERVER_KEEP_ALIVE_TIMEOUT";
+    pub const SEND_ENV_VARIABLE: &'static str = "SERVER_SEND_TIMEOUT";
+
+    /// Defaults overridden by `SERVER_HEADER_TIMEOUT`, eg. `10s`, `SERVER_MIN_BODY_RATE`, eg. `1KiB`,
+    /// `SERVER_KEEP_ALIVE_TIMEOUT`, eg. `5s`, and `SERVER_SEND_TIMEOUT`, eg. `30s`.
+    pub fn from_env() -> Self {
+        let mut timeouts = Self::default();
+        if let Ok(repr) = env::var(Self::HEADER_ENV_VARIABLE) {
+            match units::parse_duration(&repr) {
+                Ok(headers) if !headers.is_zero() => timeouts.headers = headers,
+                Ok(_) => log!(Level::Warn, "{}: timeout must be positive", Self::HEADER_ENV_VARIABLE),
+                Err(err) => log!(Level::Warn, "{}: invalid timeout '{}': {}", Self::HEADER_ENV_VARIABLE, repr, err),
+            }
+        }
+        if let Ok(repr) = env::var(Self::RATE_ENV_VARIABLE) {
+            match units::parse_size(&repr) {
+                Ok(rate) => timeouts.min_body_rate = rate,
+                Err(err) => log!(Level::Warn, "{}: invalid rate '{}': {}", Self::RATE_ENV_VARIABLE, repr, err),
+            }
+        }
+        if let Ok(repr) = env::var(Self::KEEP_ALIVE_ENV_VARIABLE) {
+            match units::parse_duration(&repr) {
+                Ok(keep_alive) if !keep_alive.is_zero() => timeouts.keep_alive = keep_alive,
+                Ok(_) => log!(Level::Warn, "{}: timeout must be positive", Self::KEEP_ALIVE_ENV_VARIABLE),
+                Err(err) => log!(Level::Warn, "{}: invalid timeout '{}': {}", Self::KEEP_ALIVE_ENV_VARIABLE, repr, err),
+            }
+        }
+        if let Ok(repr) = env::var(Self::SEND_ENV_VARIABLE) {
+            match units::parse_duration(&repr) {
+                Ok(send) if !send.is_zero() => timeouts.send = send,
+                Ok(_) => log!(Level::Warn, "{}: timeout must be positive", Self::SEND_ENV_VARIABLE),
+                Err(err) => log!(Level::Warn, "{}: invalid timeout '{}': {}", Self::SEND_ENV_VARIABLE, repr, err),
+            }
+        }
+        timeouts
+    }
+}
+
+impl Default for ReadTimeouts {
+    fn default() -> Self {
+        Self {
+            headers: Duration::from_secs(10),
+            min_body_rate: 1024,
+            body_grace: Duration::from_secs(5),
+            keep_alive: Duration::from_secs(2),
+            send: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Phase {
+    Headers { started: Instant },
+    Body { started: Instant, received: usize },
+}
+
+/// Tracks progress of a single request against `ReadTimeouts`.
+#[derive(Debug, Clone)]
+pub struct ReadDeadline {
+    timeouts: ReadTimeouts,
+    phase: Phase,
+}
+
+impl ReadDeadline {
+    pub fn new(timeouts: ReadTimeouts) -> Self {
+        Self::started_at(timeouts, Instant::now())
+    }
+
+    fn started_at(timeouts: ReadTimeouts, now: Instant) -> Self {
+        Self { timeouts, phase: Phase::Headers { started: now } }
+    }
+
+    /// Starts measuring the next request from `now`.
+    pub fn restart(&mut self, now: Instant) {
+        self.phase = Phase::Headers { started: now };
+    }
+
+    /// Header section was received at `now`, the body is measured from here on.
+    pub fn headers_received(&mut self, now: Instant) {
+        self.phase = Phase::Body { started: now, received: 0 };
+    }
+
+    /// Total number of body bytes received so far.
+    pub fn body_progress(&mut self, received: usize) {
+        if let Phase::Body { received: total, .. } = &mut self.phase {
+            *total = received;
+        }
+    }
+
+    /// Moment the request is late if nothing more arrives until then, `None` if it is never late.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.phase {
+            Phase::Headers { started } => Some(started + self.timeouts.headers),
+            Phase::Body { .. } if self.timeouts.min_body_rate == 0 => None,
+            Phase::Body { started, received } => {
+                let earned = Duration::from_secs_f64(received as f64 / self.timeouts.min_body_rate as f64);
+                Some(started + earned.max(self.timeouts.body_grace))
+            }
+        }
+    }
+
+    /// Time left until the deadline, zero once it passed.
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.deadline().map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline().is_some_and(|deadline| now >= deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUTS: ReadTimeouts = ReadTimeouts {
+        headers: Duration::from_secs(10),
+        min_body_rate: 100,
+        body_grace: Duration::from_secs(2),
+        keep_alive: Duration::from_secs(5),
+        send: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn test_header_deadline() {
+        let start = Instant::now();
+        let deadline = ReadDeadline::started_at(TIMEOUTS, start);
+        /* dribbling bytes does not move the header deadline */
+        assert_eq!(deadline.remaining(start + Duration::from_secs(4)), Some(Duration::from_secs(6)));
+        assert!(!deadline.is_expired(start + Duration::from_millis(9999)));
+        assert!(deadline.is_expired(start + Duration::from_secs(10)));
+        assert_eq!(deadline.remaining(start + Duration::from_secs(11)), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_body_rate() {
+        let start = Instant::now();
+        let mut deadline = ReadDeadline::started_at(TIMEOUTS, start);
+        deadline.headers_received(start + Duration::from_secs(1));
+        /* grace period covers the start of the body */
+        assert_eq!(deadline.deadline(), Some(start + Duration::from_secs(3)));
+        deadline.body_progress(500);
+        assert_eq!(deadline.deadline(), Some(start + Duration::from_secs(6)));
+        assert!(!deadline.is_expired(start + Duration::from_secs(5)));
+        assert!(deadline.is_expired(start + Duration::from_secs(6)));
+        deadline.restart(start + Duration::from_secs(7));
+        assert_eq!(deadline.deadline(), Some(start + Duration::from_secs(17)));
+        /* rate check can be disabled, headers are still limited */
+        let mut deadline = ReadDeadline::started_at(ReadTimeouts { min_body_rate: 0, ..TIMEOUTS }, start);
+        deadline.headers_received(start);
+        assert!(!deadline.is_expired(start + Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,63 @@
+//! Mikołaj Depta 328690
+//!
+//! Debug tracing of the connection state machine and the event loop.
+//!
+//! Tracing is disabled by default, it is enabled by setting `SERVER_TRACE` environment variable
+//! to anything but `0` or with `set_enabled`. When disabled `trace!` costs a single atomic load,
+//! the message is not even formatted.
+
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const TRACE_VARIABLE: &str = "SERVER_TRACE";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed)
+}
+
+/// Enables tracing if requested through the environment.
+pub fn init_from_env() {
+    if let Ok(value) = env::var(TRACE_VARIABLE) {
+        set_enabled(!matches!(value.trim(), "" | "0"));
+    }
+}
+
+/// Writes trace message about the connection identified by `token` to stderr.
+/// Event loop messages that are not tied to any connection use `-` as the token.
+macro_rules! trace {
+    ($token: expr, $($arg: tt)+) => {
+        if $crate::trace::is_enabled() {
+            eprintln!("[trace] [{}] {}", $token, format_args!($($arg)+));
+        }
+    }
+}
+
+pub(crate) use trace;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_arguments_not_evaluated_when_disabled() {
+        let evaluated = Cell::new(0);
+        let argument = || {
+            evaluated.set(evaluated.get() + 1);
+            "state"
+        };
+        set_enabled(false);
+        trace!(1, "{}", argument());
+        assert_eq!(evaluated.get(), 0);
+        set_enabled(true);
+        trace!(1, "{}", argument());
+        assert_eq!(evaluated.get(), 1);
+        set_enabled(false);
+    }
+}
@@ -0,0 +1,305 @@
+//! Mikołaj Depta 328690
+//!
+//! Upgrade of the running server to a new binary without closing the listening socket.
+//!
+//! SIGUSR2 asks the server to execute its executable again, in place of the running process.
+//! It is received through the registry of the accepting server like the shutdown signals, see `shutdown`.
+//! Listening socket is passed to the new program the same way a service manager passes it,
+//! see `activation`, so connections waiting in the listen queue are accepted by the new program.
+//! Small part of the state travels in `SERVER_UPGRADE_STATE`. Upgrades are enabled by setting
+//! `SERVER_ALLOW_UPGRADE` to `1`.
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::activation::LISTEN_FDS_START;
+use crate::logger::{log, Level};
+use crate::registry::syscall;
+use crate::server::Token;
+
+pub const ENV_VARIABLE: &str = "SERVER_ALLOW_UPGRADE";
+const STATE_VARIABLE: &str = "SERVER_UPGRADE_STATE";
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Upgrades were enabled by the operator.
+pub fn is_enabled() -> bool {
+    env::var(ENV_VARIABLE).is_ok_and(|value| value.trim() == "1")
+}
+
+/// Requests an upgrade as if SIGUSR2 was received.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// `true` once for every batch of upgrade requests.
+pub fn take_request() -> bool {
+    REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// State handed over to the new program.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct UpgradeState {
+    /// Token of the next accepted connection, so tokens in diagnostics stay unique.
+    pub next_token: Token,
+    /// Number of upgrades the server went through.
+    pub generation: u32,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ParseUpgradeStateError(String);
+
+impl Display for ParseUpgradeStateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid upgrade state entry '{}'", self.0)
+    }
+}
+
+impl Display for UpgradeState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "next_token={},generation={}", self.next_token, self.generation)
+    }
+}
+
+/// Comma separated `<key>=<value>` entries. Unknown keys are ignored,
+/// so binaries of different versions can upgrade to each other.
+impl TryFrom<&str> for UpgradeState {
+    type Error = ParseUpgradeStateError;
+
+    fn try_from(repr: &str) -> Result<Self, Self::Error> {
+        let mut state = Self::default();
+        for entry in repr.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let invalid = || ParseUpgradeStateError(entry.to_owned());
+            let (key, value) = entry.split_once('=').ok_or_else(invalid)?;
+            match key.trim() {
+                "next_token" => state.next_token = value.trim().parse().map_err(|_| invalid())?,
+                "generation" => state.generation = value.trim().parse().map_err(|_| invalid())?,
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// State passed by the program that upgraded to this one, if any.
+///
+/// Environment variable is removed so it is not inherited by child processes.
+pub fn inherited_state() -> Option<UpgradeState> {
+    let repr = env::var(STATE_VARIABLE).ok()?;
+    env::remove_var(STATE_VARIABLE);
+    match UpgradeState::try_from(repr.as_str()) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            log!(Level::Warn, "{}: {}", STATE_VARIABLE, err);
+            None
+        }
+    }
+}
+
+/// Executable of the running process, checked to be executable by it. Once a new binary
+/// is installed over it, the link reports the old one as deleted, the new one is at the same path.
+pub fn executable() -> io::Result<PathBuf> {
+    let executable = env::current_exe()?;
+    let mut repr = executable.into_os_string().into_string().map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    if let Some(stripped) = repr.strip_suffix(" (deleted)") {
+        repr = stripped.to_owned();
+    }
+    let path = CString::new(repr.as_str()).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    syscall!(access(path.as_ptr(), libc::X_OK))?;
+    Ok(PathBuf::from(repr))
+}
+
+/// Replaces the process with a new instance of `program` serving `listener`,
+/// started with the same arguments. Returns only if the program could not be started,
+/// in which case the process is left as it was.
+pub fn exec(program: &Path, listener: &TcpListener, state: &UpgradeState) -> io::Error {
+    let mut command = Command::new(program);
+    command
+        .args(env::args_os().skip(1).collect::<Vec<OsString>>())
+        .env("LISTEN_PID", process::id().to_string())
+        .env("LISTEN_FDS", "1")
+        .env(STATE_VARIABLE, state.to_string());
+    let passed = match PassedListener::new(listener.as_raw_fd()) {
+        Ok(passed) => passed,
+        Err(err) => return err,
+    };
+    log!(Level::Info, "executing {} with state {}", program.display(), state);
+    let err = command.exec();
+    passed.restore();
+    err
+}
+
+/// Listener moved to the descriptor the new program expects it at, see `activation`.
+/// Every other descriptor is closed on exec, as all descriptors opened by the standard library are.
+enum PassedListener {
+    /// Listener already was at `LISTEN_FDS_START`, only its flags changed.
+    InPlace { flags: libc::c_int },
+    /// Copy of the descriptor that was at `LISTEN_FDS_START` before.
+    Replaced { saved: RawFd, flags: libc::c_int },
+    /// Nothing was at `LISTEN_FDS_START` before.
+    Vacant,
+}
+
+impl PassedListener {
+    fn new(listener: RawFd) -> io::Result<Self> {
+        if listener == LISTEN_FDS_START {
+            let flags = syscall!(fcntl(listener, libc::F_GETFD))?;
+            syscall!(fcntl(listener, libc::F_SETFD, flags & !libc::FD_CLOEXEC))?;
+            return Ok(Self::InPlace { flags });
+        }
+        let passed = match syscall!(fcntl(LISTEN_FDS_START, libc::F_GETFD)) {
+            Ok(flags) => {
+                let saved = syscall!(fcntl(LISTEN_FDS_START, libc::F_DUPFD_CLOEXEC, LISTEN_FDS_START + 1))?;
+                Self::Replaced { saved, flags }
+            }
+            Err(err) if err.raw_os_error() == Some(libc::EBADF) => Self::Vacant,
+            Err(err) => return Err(err),
+        };
+        /* duplicate does not inherit close-on-exec */
+        syscall!(dup2(listener, LISTEN_FDS_START))?;
+        Ok(passed)
+    }
+
+    /// Puts back whatever was at `LISTEN_FDS_START`.
+    fn restore(self) {
+        unsafe {
+            match self {
+                Self::InPlace { flags } => {
+                    libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, flags);
+                }
+                Self::Replaced { saved, flags } => {
+                    libc::dup2(saved, LISTEN_FDS_START);
+                    libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, flags);
+                    libc::close(saved);
+                }
+                Self::Vacant => {
+                    libc::close(LISTEN_FDS_START);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Signal;
+    use crate::server::{HttpDownloader, HttpSender, HttpServer};
+    use common::fs::TempDir;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::process::{Child, Stdio};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Directory the upgraded server serves from and reports its generations to.
+    const DIR_VARIABLE: &str = "UPGRADE_TEST_DIR";
+
+    #[test]
+    fn test_state_round_trip() {
+        let state = UpgradeState { next_token: 42, generation: 3 };
+        assert_eq!(UpgradeState::try_from(state.to_string().as_str()), Ok(state));
+        assert_eq!(UpgradeState::try_from("generation=1, added_later=x"), Ok(UpgradeState { next_token: 0, generation: 1 }));
+        assert_eq!(UpgradeState::try_from(""), Ok(UpgradeState::default()));
+        assert_eq!(UpgradeState::try_from("generation"), Err(ParseUpgradeStateError("generation".to_owned())));
+        assert_eq!(UpgradeState::try_from("next_token=-1"), Err(ParseUpgradeStateError("next_token=-1".to_owned())));
+    }
+
+    /// Server run in a child process by `test_upgrade_keeps_listening_socket`, every generation
+    /// of it appends `<generation> <address>` to the `generations` file.
+    #[test]
+    #[ignore = "run by test_upgrade_keeps_listening_socket"]
+    fn upgraded_server() {
+        let dir = PathBuf::from(env::var_os(DIR_VARIABLE).expect("directory is passed by the parent test"));
+        let catalog: Arc<Path> = Arc::from(dir.join("www"));
+        let mut server = HttpServer::<HttpDownloader<TcpStream>, HttpSender<TcpStream>>::new("127.0.0.1:0".parse().unwrap(), catalog)
+            .with_workers(2);
+        let mut generations = OpenOptions::new().create(true).append(true).open(dir.join("generations")).unwrap();
+        writeln!(generations, "{} {}", server.generation(), server.address()).unwrap();
+        server.start();
+    }
+
+    /// Server process, killed when the test ends even if it fails.
+    struct ServerProcess(Child);
+
+    impl ServerProcess {
+        fn spawn(dir: &Path) -> Self {
+            let mut command = Command::new(env::current_exe().unwrap());
+            command
+                .args(["--ignored", "--exact", "upgrade::tests::upgraded_server", "--test-threads=1"])
+                .env(DIR_VARIABLE, dir)
+                .env(ENV_VARIABLE, "1")
+                .env_remove(STATE_VARIABLE)
+                .env_remove("LISTEN_FDS")
+                .env_remove("LISTEN_PID")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null());
+            /* test harness starts its threads before the server is created, see `HttpServer` */
+            // safety: only async-signal-safe calls are made between fork and exec.
+            unsafe { command.pre_exec(|| Signal::block(&[Signal::User2])) };
+            Self(command.spawn().unwrap())
+        }
+    }
+
+    impl Drop for ServerProcess {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn await_generation(dir: &Path, generation: u32) -> SocketAddr {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(30) {
+            let generations = fs::read_to_string(dir.join("generations")).unwrap_or_default();
+            let address = generations.lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|(reported, _)| *reported == generation.to_string())
+                .map(|(_, address)| address.parse().unwrap());
+            if let Some(address) = address {
+                return address;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("generation {generation} did not start");
+    }
+
+    fn get(client: &mut TcpStream) -> String {
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_upgrade_keeps_listening_socket() {
+        let dir = TempDir::new("server-upgrade").unwrap();
+        dir.create_file("www/localhost/index.html", b"<p>hello</p>").unwrap();
+        let mut server = ServerProcess::spawn(dir.path());
+        let address = await_generation(dir.path(), 0);
+        assert!(get(&mut TcpStream::connect(address).unwrap()).starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let mut open = TcpStream::connect(address).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        syscall!(kill(server.0.id() as libc::pid_t, libc::SIGUSR2)).unwrap();
+        /* connection accepted before the upgrade is finished by the old program */
+        assert!(get(&mut open).starts_with("HTTP/1.1 200 OK\r\n"));
+        /* connection made during the upgrade is served by one of them */
+        assert!(get(&mut TcpStream::connect(address).unwrap()).ends_with("<p>hello</p>"));
+        assert_eq!(await_generation(dir.path(), 1), address);
+        assert!(get(&mut TcpStream::connect(address).unwrap()).ends_with("<p>hello</p>"));
+        /* the same process kept running */
+        assert!(server.0.try_wait().unwrap().is_none());
+    }
+}
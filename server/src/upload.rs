@@ -0,0 +1,185 @@
+//! Mikołaj Depta 328690
+//!
+//! Progress of request bodies that are still being received.
+//!
+//! Client that wants to report progress of an upload labels the request with `X-Upload-Id`
+//! header and polls `GET /.upload-progress/<id>` on another connection.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+pub const UPLOAD_ID_HEADER: &str = "X-Upload-Id";
+pub const PROGRESS_PATH_PREFIX: &str = "/.upload-progress/";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UploadState {
+    Receiving,
+    Complete,
+    /// Connection was closed before the whole body arrived.
+    Aborted,
+}
+
+impl Display for UploadState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let repr = match self {
+            UploadState::Receiving => "receiving",
+            UploadState::Complete => "complete",
+            UploadState::Aborted => "aborted",
+        };
+        write!(f, "{repr}")
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UploadProgress {
+    pub received: usize,
+    pub total: usize,
+    pub state: UploadState,
+}
+
+impl UploadProgress {
+    /// Progress as JSON object.
+    pub fn to_json(self, id: &str) -> String {
+        format!(
+            r#"{{"id":"{}","received":{},"total":{},"state":"{}"}}"#,
+            id, self.received, self.total, self.state
+        )
+    }
+}
+
+#[derive(Default)]
+struct Uploads {
+    progress: HashMap<String, UploadProgress>,
+    /// Uploads that are no longer receiving, oldest first.
+    finished: VecDeque<String>,
+}
+
+/// Progress of uploads shared by all connections.
+/// Finished uploads are remembered until `MAX_FINISHED` newer ones finish.
+#[derive(Clone, Default)]
+pub struct UploadTracker {
+    uploads: Arc<Mutex<Uploads>>,
+}
+
+impl UploadTracker {
+    const MAX_FINISHED: usize = 64;
+    const MAX_ID_LEN: usize = 64;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ids are limited to letters, digits, `-` and `_` so they can be embedded in paths and JSON.
+    pub fn is_valid_id(id: &str) -> bool {
+        !id.is_empty()
+            && id.len() <= Self::MAX_ID_LEN
+            && id.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+    }
+
+    /// Starts tracking the upload, progress is updated through the returned guard.
+    /// `None` if the id is invalid.
+    pub fn start(&self, id: &str, total: usize) -> Option<UploadGuard> {
+        if !Self::is_valid_id(id) {
+            return None;
+        }
+        let progress = UploadProgress { received: 0, total, state: UploadState::Receiving };
+        let mut uploads = self.lock();
+        uploads.finished.retain(|finished| finished != id);
+        uploads.progress.insert(id.to_owned(), progress);
+        Some(UploadGuard { tracker: self.clone(), id: id.to_owned(), finished: false })
+    }
+
+    pub fn progress(&self, id: &str) -> Option<UploadProgress> {
+        self.lock().progress.get(id).copied()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Uploads> {
+        self.uploads.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn update(&self, id: &str, received: usize) {
+        if let Some(progress) = self.lock().progress.get_mut(id) {
+            progress.received = received.min(progress.total);
+        }
+    }
+
+    fn finish(&self, id: &str, state: UploadState) {
+        let mut uploads = self.lock();
+        if let Some(progress) = uploads.progress.get_mut(id) {
+            progress.state = state;
+            if state == UploadState::Complete {
+                progress.received = progress.total;
+            }
+        }
+        uploads.finished.push_back(id.to_owned());
+        while uploads.finished.len() > Self::MAX_FINISHED {
+            if let Some(oldest) = uploads.finished.pop_front() {
+                uploads.progress.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Upload in progress, dropping the guard before `complete` marks the upload as aborted.
+pub struct UploadGuard {
+    tracker: UploadTracker,
+    id: String,
+    finished: bool,
+}
+
+impl UploadGuard {
+    pub fn update(&self, received: usize) {
+        self.tracker.update(&self.id, received);
+    }
+
+    pub fn complete(mut self) {
+        self.finished = true;
+        self.tracker.finish(&self.id, UploadState::Complete);
+    }
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.tracker.finish(&self.id, UploadState::Aborted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let tracker = UploadTracker::new();
+        let guard = tracker.start("upload-1", 100).unwrap();
+        guard.update(40);
+        assert_eq!(tracker.progress("upload-1"), Some(UploadProgress { received: 40, total: 100, state: UploadState::Receiving }));
+        guard.complete();
+        assert_eq!(
+            tracker.progress("upload-1").unwrap().to_json("upload-1"),
+            r#"{"id":"upload-1","received":100,"total":100,"state":"complete"}"#
+        );
+        assert_eq!(tracker.progress("other"), None);
+    }
+
+    #[test]
+    fn test_dropped_guard_aborts() {
+        let tracker = UploadTracker::new();
+        drop(tracker.start("a", 10));
+        assert_eq!(tracker.progress("a").unwrap().state, UploadState::Aborted);
+        assert!(tracker.start("a\"b", 10).is_none());
+    }
+
+    #[test]
+    fn test_finished_uploads_are_forgotten() {
+        let tracker = UploadTracker::new();
+        for index in 0..=UploadTracker::MAX_FINISHED {
+            tracker.start(&index.to_string(), 1).unwrap().complete();
+        }
+        assert_eq!(tracker.progress("0"), None);
+        assert!(tracker.progress("1").is_some());
+    }
+}
@@ -0,0 +1,314 @@
+//! Mikołaj Depta 328690
+//!
+//! Virtual host configuration - mapping of `Host` header values to document roots.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How a request for a directory without the trailing slash, eg. `GET /docs`, is answered.
+/// Requests with the trailing slash are always answered with the index document of the directory.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum DirectoryPolicy {
+    /// 301 to the target with the trailing slash, so relative links on the index resolve within the directory.
+    #[default]
+    Redirect,
+    /// Index document of the directory is served under the target as it is.
+    ServeIndex,
+    /// Directory is not found unless requested with the trailing slash.
+    NotFound,
+}
+
+impl DirectoryPolicy {
+    const REDIRECT_REPR: &'static str = "redirect";
+    const SERVE_INDEX_REPR: &'static str = "index";
+    const NOT_FOUND_REPR: &'static str = "404";
+}
+
+impl FromStr for DirectoryPolicy {
+    type Err = ParseDirectoryPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            Self::REDIRECT_REPR => Ok(Self::Redirect),
+            Self::SERVE_INDEX_REPR => Ok(Self::ServeIndex),
+            Self::NOT_FOUND_REPR => Ok(Self::NotFound),
+            _ => Err(ParseDirectoryPolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl Display for DirectoryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let repr = match self {
+            Self::Redirect => Self::REDIRECT_REPR,
+            Self::ServeIndex => Self::SERVE_INDEX_REPR,
+            Self::NotFound => Self::NOT_FOUND_REPR,
+        };
+        write!(f, "{repr}")
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ParseDirectoryPolicyError(String);
+
+impl Display for ParseDirectoryPolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected one of 'redirect', 'index' or '404', got '{}'", self.0)
+    }
+}
+
+/// Document roots of the hosts served by the server.
+///
+/// Host names are case-insensitive. Requests for hosts that are not configured
+/// are served from the default host, if there is one.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VirtualHosts {
+    roots: HashMap<String, PathBuf>,
+    /// Hosts with a directory policy other than the default one.
+    directory_policies: HashMap<String, DirectoryPolicy>,
+    default_host: Option<String>,
+}
+
+impl VirtualHosts {
+    /// Configuration read again on reload, see `settings`.
+    pub const ENV_VARIABLE: &'static str = "SERVER_VIRTUAL_HOSTS";
+    const DEFAULT_KEYWORD: &'static str = "default";
+    const COMMENT: char = '#';
+
+    /// Configuration used before virtual hosts were configurable -
+    /// `localhost` and `lab108-18` served from subdirectories of `catalog` with the same names.
+    pub fn default_config(catalog: &Path) -> Self {
+        let roots = ["localhost", "lab108-18"]
+            .map(|host| (host.to_owned(), catalog.join(host)));
+        Self { roots: HashMap::from(roots), directory_policies: HashMap::new(), default_host: None }
+    }
+
+    /// Reads configuration from `path`.
+    /// Relative document roots are resolved against `catalog`.
+    pub fn load(path: &Path, catalog: &Path) -> Result<Self, LoadVirtualHostsError> {
+        let repr = fs::read_to_string(path).map_err(LoadVirtualHostsError::Io)?;
+        let hosts = Self::try_from(repr.as_str()).map_err(LoadVirtualHostsError::Parse)?;
+        Ok(hosts.resolved(catalog))
+    }
+
+    /// Makes all relative document roots relative to `catalog`.
+    pub fn resolved(mut self, catalog: &Path) -> Self {
+        for root in self.roots.values_mut() {
+            if root.is_relative() {
+                *root = catalog.join(&root);
+            }
+        }
+        self
+    }
+
+    /// Same hosts once `from` is seen as `to`, eg. after the server changed its root directory.
+    /// Fails with the first document root outside of `from`.
+    pub fn rebased(mut self, from: &Path, to: &Path) -> Result<Self, PathBuf> {
+        for root in self.roots.values_mut() {
+            let relative = root.strip_prefix(from).map_err(|_| root.clone())?;
+            *root = to.join(relative);
+        }
+        Ok(self)
+    }
+
+    pub fn with_default_host(mut self, host: &str) -> Self {
+        self.default_host = Some(host.to_lowercase());
+        self
+    }
+
+    pub fn with_directory_policy(mut self, host: &str, policy: DirectoryPolicy) -> Self {
+        self.directory_policies.insert(host.to_lowercase(), policy);
+        self
+    }
+
+    /// Document root for the `host`, falls back to the default host.
+    pub fn document_root(&self, host: &str) -> Option<&Path> {
+        self.served_host(host).map(|host| self.roots[host].as_path())
+    }
+
+    /// Directory policy of the host the `host` is served by.
+    pub fn directory_policy(&self, host: &str) -> DirectoryPolicy {
+        self.served_host(host)
+            .and_then(|host| self.directory_policies.get(host))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Configured host name the `host` is served by.
+    pub fn served_host(&self, host: &str) -> Option<&str> {
+        let host = host.to_lowercase();
+        match self.roots.get_key_value(&host) {
+            Some((host, _)) => Some(host.as_str()),
+            None => self.default_host.as_deref().filter(|default| self.roots.contains_key(*default)),
+        }
+    }
+
+    pub fn roots(&self) -> impl Iterator<Item=&Path> {
+        self.roots.values().map(PathBuf::as_path)
+    }
+}
+
+/// Expected input format - one entry per line, empty lines and `#` comments are ignored:
+/// ```text
+/// <host> <document root> [redirect | index | 404]
+/// default <host>
+/// ```
+/// Optional last token is the directory policy of the host, see `DirectoryPolicy`.
+impl TryFrom<&str> for VirtualHosts {
+    type Error = ParseVirtualHostsError;
+
+    fn try_from(repr: &str) -> Result<Self, Self::Error> {
+        let mut hosts = Self::default();
+        let mut default_host = None;
+        for (index, line) in repr.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split(Self::COMMENT).next().unwrap_or_default();
+            let tokens = line.split_whitespace().collect::<Vec<_>>();
+            match tokens.as_slice() {
+                [] => continue,
+                [Self::DEFAULT_KEYWORD, host] => {
+                    if default_host.is_some() {
+                        return Err(ParseVirtualHostsError::DuplicateDefault(line_number));
+                    }
+                    default_host = Some((line_number, host.to_lowercase()));
+                }
+                [host, root, policy @ ..] if policy.len() <= 1 => {
+                    let host = host.to_lowercase();
+                    if hosts.roots.insert(host.clone(), PathBuf::from(root)).is_some() {
+                        return Err(ParseVirtualHostsError::DuplicateHost(line_number, host));
+                    }
+                    if let [policy] = policy {
+                        let policy = policy
+                            .parse()
+                            .map_err(|err| ParseVirtualHostsError::InvalidDirectoryPolicy(line_number, err))?;
+                        hosts.directory_policies.insert(host, policy);
+                    }
+                }
+                _ => return Err(ParseVirtualHostsError::InvalidFormat(line_number, line.trim().to_owned())),
+            }
+        }
+        if let Some((line_number, host)) = default_host {
+            if !hosts.roots.contains_key(&host) {
+                return Err(ParseVirtualHostsError::UnknownDefault(line_number, host));
+            }
+            hosts.default_host = Some(host);
+        }
+        Ok(hosts)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ParseVirtualHostsError {
+    InvalidFormat(usize, String),
+    DuplicateHost(usize, String),
+    DuplicateDefault(usize),
+    UnknownDefault(usize, String),
+    InvalidDirectoryPolicy(usize, ParseDirectoryPolicyError),
+}
+
+impl Display for ParseVirtualHostsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidFormat(line, repr) => {
+                write!(f, "line {line}: expected '<host> <document root>', got '{repr}'")
+            }
+            Self::DuplicateHost(line, host) => write!(f, "line {line}: host {host} already configured"),
+            Self::DuplicateDefault(line) => write!(f, "line {line}: default host already set"),
+            Self::UnknownDefault(line, host) => {
+                write!(f, "line {line}: default host {host} has no document root")
+            }
+            Self::InvalidDirectoryPolicy(line, err) => write!(f, "line {line}: invalid directory policy: {err}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadVirtualHostsError {
+    Io(io::Error),
+    Parse(ParseVirtualHostsError),
+}
+
+impl Display for LoadVirtualHostsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "could not read virtual host configuration: {err}"),
+            Self::Parse(err) => write!(f, "invalid virtual host configuration: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let hosts = VirtualHosts::try_from(
+            "# hosts served in the lab\nlocalhost www/localhost\n\nExample.com /srv/example # absolute\ndefault localhost\n"
+        ).unwrap().resolved(Path::new("/catalog"));
+        assert_eq!(hosts.document_root("LOCALHOST"), Some(Path::new("/catalog/www/localhost")));
+        assert_eq!(hosts.document_root("example.com"), Some(Path::new("/srv/example")));
+        assert_eq!(hosts.document_root("unknown"), Some(Path::new("/catalog/www/localhost")));
+        assert_eq!(hosts.roots().count(), 2);
+        assert_eq!(hosts.directory_policy("localhost"), DirectoryPolicy::Redirect);
+    }
+
+    #[test]
+    fn test_directory_policies() {
+        let hosts = VirtualHosts::try_from("a /a index
+b /b 404
+c /c REDIRECT
+default b
+").unwrap();
+        assert_eq!(hosts.directory_policy("A"), DirectoryPolicy::ServeIndex);
+        assert_eq!(hosts.directory_policy("c"), DirectoryPolicy::Redirect);
+        assert_eq!(hosts.directory_policy("unknown"), DirectoryPolicy::NotFound);
+        assert_eq!(VirtualHosts::default().directory_policy("a"), DirectoryPolicy::Redirect);
+        assert_eq!(
+            VirtualHosts::try_from("a /a list
+"),
+            Err(ParseVirtualHostsError::InvalidDirectoryPolicy(1, ParseDirectoryPolicyError("list".to_owned())))
+        );
+        assert_eq!(
+            VirtualHosts::try_from("a /a index extra
+"),
+            Err(ParseVirtualHostsError::InvalidFormat(1, "a /a index extra".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_no_default_host() {
+        let hosts = VirtualHosts::default_config(Path::new("/catalog"));
+        assert_eq!(hosts.document_root("lab108-18"), Some(Path::new("/catalog/lab108-18")));
+        assert_eq!(hosts.document_root("unknown"), None);
+    }
+
+    #[test]
+    fn test_rebased() {
+        let hosts = VirtualHosts::default_config(Path::new("/srv/www"));
+        let rebased = hosts.clone().rebased(Path::new("/srv/www"), Path::new("/")).unwrap();
+        assert_eq!(rebased.document_root("localhost"), Some(Path::new("/localhost")));
+        let outside = hosts.with_default_host("localhost").rebased(Path::new("/srv/www/localhost"), Path::new("/"));
+        assert_eq!(outside, Err(PathBuf::from("/srv/www/lab108-18")));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert_eq!(
+            VirtualHosts::try_from("localhost\n"),
+            Err(ParseVirtualHostsError::InvalidFormat(1, "localhost".to_owned()))
+        );
+        assert_eq!(
+            VirtualHosts::try_from("a /a\nA /b\n"),
+            Err(ParseVirtualHostsError::DuplicateHost(2, "a".to_owned()))
+        );
+        assert_eq!(
+            VirtualHosts::try_from("a /a\ndefault b\n"),
+            Err(ParseVirtualHostsError::UnknownDefault(2, "b".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,1180 @@
+//! Mikołaj Depta 328690
+//!
+//! Serving of accepted connections, either on the calling thread or by a pool of worker threads.
+//!
+//! Every worker owns an epoll `Registry` and serves connections it receives one at a time,
+//! so the pool size is the number of connections served concurrently.
+
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::cgi::{CgiHandler, Script};
+use crate::error::ServerError;
+use crate::forwarded;
+use crate::http::headers::Header;
+use crate::http::headers::response_header::ResponseHeader;
+use crate::http::headers::general_header::ConnectionType;
+use crate::http::request::Request;
+use crate::http::response::{Response, StatusCode};
+use crate::logger::{log, Level, RequestId};
+use crate::proxy::{self, Outgoing};
+use crate::registry::{self, EventType, Notification, Registry, TimeoutDuration, Timer};
+use crate::resources::{LoadResourceError, ResourceLoader, ResourceValidator, ValidationResourceError};
+use crate::server::{ActionStatus, Connection, HttpDownloader, HttpSender, RequestHandler, Token};
+use crate::sse::EventStream;
+use crate::trace::trace;
+
+type HttpConnection = Connection<HttpDownloader<TcpStream>, HttpSender<TcpStream>>;
+
+struct Worker {
+    connections: mpsc::Sender<(Token, TcpStream)>,
+    thread: JoinHandle<()>,
+}
+
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+    next: usize,
+}
+
+impl WorkerPool {
+    pub fn new<L, V>(size: usize, handler: Arc<RequestHandler<L, V>>) -> io::Result<Self>
+    where
+        L: ResourceLoader<LoadError = LoadResourceError> + Send + Sync + 'static,
+        V: ResourceValidator<ValidationError = ValidationResourceError> + Send + Sync + 'static,
+    {
+        let mut workers = Vec::with_capacity(size);
+        for index in 0..size.max(1) {
+            let (sender, receiver) = mpsc::channel::<(Token, TcpStream)>();
+            let mut registry = registry::from_env()?;
+            let handler = handler.clone();
+            let thread = thread::Builder::new()
+                .name(format!("worker-{index}"))
+                .spawn(move || {
+                    for (token, stream) in receiver {
+                        serve(&mut registry, &handler, token, stream);
+                    }
+                })?;
+            workers.push(Worker { connections: sender, thread });
+        }
+        Ok(Self { workers, next: 0 })
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Hands the connection to the next worker, round-robin.
+    pub fn dispatch(&mut self, token: Token, stream: TcpStream) {
+        let worker = &self.workers[self.next];
+        self.next = (self.next + 1) % self.workers.len();
+        trace!(token, "dispatched to {:?}", worker.thread.thread().name());
+        if worker.connections.send((token, stream)).is_err() {
+            log!(Level::Error, "worker {:?} is gone, dropping connection {}", worker.thread.thread().name(), token);
+        }
+    }
+
+    /// Stops accepting new connections and waits until workers finish the ones they have.
+    pub fn join(self) {
+        for Worker { connections, thread } in self.workers {
+            drop(connections);
+            if thread.join().is_err() {
+                log!(Level::Error, "worker thread panicked");
+            }
+        }
+    }
+}
+
+/// Serves requests on the connection until the client closes it, asks for it to be closed
+/// or stays idle for too long. Panic while serving it drops only this connection.
+pub fn serve<L, V>(registry: &mut Registry, handler: &RequestHandler<L, V>, token: Token, stream: TcpStream)
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    /* sockets are closed while unwinding, which also removes them from the registry */
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| serve_connection(registry, handler, token, stream))) {
+        log!(Level::Error, "dropping connection {}: {}", token, ServerError::from_panic(payload));
+    }
+}
+
+fn serve_connection<L, V>(registry: &mut Registry, handler: &RequestHandler<L, V>, token: Token, stream: TcpStream)
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    let peer = stream.peer_addr().ok();
+    let mut forwarded_for = None;
+    if handler.forwarding().expects_proxy_header(peer) {
+        match forwarded::read_proxy_header(&stream, handler.read_timeouts().headers) {
+            Ok(source) => forwarded_for = source,
+            Err(err) => {
+                log!(Level::Info, "rejecting connection {} from {:?}: {}", token, peer, err);
+                return;
+            }
+        }
+    }
+    let connection = stream.try_clone()
+        .and_then(|reader| Ok((reader, stream.try_clone()?)))
+        .and_then(|(reader, writer)| {
+            let downloader = HttpDownloader::new(reader)
+                .with_upload_tracker(handler.uploads().clone())
+                .with_read_timeouts(*handler.read_timeouts())
+                .with_spool(handler.spool().clone())
+                .with_spool_usage(handler.spool_usage().clone())
+                .with_strict_framing(handler.strict_framing());
+            let connection = Connection::new(stream, token, downloader, HttpSender::new(writer, Box::from([])))?
+                .with_idle_timeout(handler.read_timeouts().keep_alive)
+                .with_send_timeout(handler.read_timeouts().send)
+                .with_bandwidth_limit(handler.bandwidth_limit())
+                .with_metrics(handler.metrics());
+            Ok(match forwarded_for {
+                Some(client) => connection.with_peer(client),
+                None => connection,
+            })
+        });
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(err) => {
+            log!(Level::Warn, "could not set up connection {}: {}", token, err);
+            return;
+        }
+    };
+    if let Err(err) = registry.add_interest(EventType::Read, connection.stream().as_raw_fd()) {
+        log!(Level::Warn, "could not watch connection {}: {}", token, err);
+        return;
+    }
+    if let Err(err) = drive(registry, handler, &mut connection) {
+        log!(Level::Debug, "closing connection {}: {}", token, err);
+    }
+    /* registration goes away with the socket anyway, failure here changes nothing */
+    let _ = registry.delete_interest(EventType::Read, connection.stream().as_raw_fd());
+    trace!(token, "connection closed");
+}
+
+/// Runs the connection state machine, `Ok` once the connection should be closed gracefully.
+fn drive<L, V>(registry: &mut Registry, handler: &RequestHandler<L, V>, connection: &mut HttpConnection) -> io::Result<()>
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    loop {
+        let timeout = connection.time_left(Instant::now());
+        match await_connection(registry, connection, &timeout)? {
+            Notification::Timeout if !connection.is_overdue(Instant::now()) => continue,
+            Notification::Timeout => {
+                trace!(connection.token(), "timed out in state {}", connection.status());
+                if connection.status() == ActionStatus::DownloadPending && connection.downloader.is_receiving() {
+                    return request_timeout(registry, handler, connection);
+                }
+                return Ok(());
+            }
+            Notification::Event(EventType::Read, _) => loop {
+                if connection.status() == ActionStatus::SendFinished {
+                    connection.transition(ActionStatus::DownloadPending);
+                }
+                let downloaded = connection.advance_download();
+                /* downloader measures the request against its own deadline */
+                connection.postpone_deadline(Instant::now());
+                let request = match downloaded {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(err) if is_transient(&err) => break,
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                    Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                        return request_timeout(registry, handler, connection);
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                        log!(Level::Info, "rejecting request on connection {}: {}", connection.token(), err);
+                        connection.transition(ActionStatus::DownloadFinished);
+                        respond(registry, connection, handler.bad_request())?;
+                        return Ok(());
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::StorageFull => {
+                        log!(Level::Warn, "refusing request on connection {}: {}", connection.token(), err);
+                        connection.transition(ActionStatus::DownloadFinished);
+                        respond(registry, connection, handler.insufficient_storage())?;
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+                connection.transition(ActionStatus::DownloadFinished);
+                let request_id = connection.begin_request(handler.virtual_host(&request).as_deref());
+                let peer = connection.peer().map(|peer| peer.ip());
+                let client = handler.client_address(&request, peer);
+                let admitted = {
+                    let _context = connection.context().enter();
+                    handler.admit(client, &request)
+                };
+                let mut script = None;
+                let response = match admitted {
+                    /* requests turned away are answered by the server, whatever they target */
+                    Err(response) => response,
+                    Ok(()) => {
+                        if let Some(outgoing) = handler.proxied(&request, peer) {
+                            return forward(registry, handler, connection, &request, &outgoing);
+                        }
+                        match handler.cgi(&request) {
+                            Some(cgi) => {
+                                let (response, output) = execute(registry, handler, connection, &request, cgi);
+                                script = output;
+                                response
+                            }
+                            None => {
+                                let _context = connection.context().enter();
+                                /* bug in the handler fails the request and its connection, not the worker serving it */
+                                panic::catch_unwind(AssertUnwindSafe(|| handler.serve(&request))).unwrap_or_else(|payload| {
+                                    log!(Level::Error, "request on connection {} failed: {}", connection.token(), ServerError::from_panic(payload));
+                                    handler.internal_server_error()
+                                })
+                            }
+                        }
+                    }
+                };
+                let mut response = response.with_headers([Header::Unknown(RequestId::HEADER.into(), request_id.to_string())]);
+                if let Some(events) = response.take_events() {
+                    return stream_events(registry, connection, response, events);
+                }
+                let closes_connection = response.closes_connection() || request.headers().connection() == Some(ConnectionType::Close);
+                if !closes_connection {
+                    /* rounded down, so clients give up on the connection before the server does */
+                    let keep_alive = handler.read_timeouts().keep_alive.as_secs();
+                    response = response.with_headers([ResponseHeader::KeepAlive(keep_alive)]);
+                }
+                respond(registry, connection, response)?;
+                if let Some(script) = script {
+                    if !stream_output(registry, connection, script)? {
+                        return Ok(());
+                    }
+                }
+                if closes_connection {
+                    return Ok(());
+                }
+                /* pipelined requests already arrived, there is nothing to wait for */
+                if connection.downloader.pipelined().is_empty() {
+                    break;
+                }
+            },
+            Notification::Event(EventType::Write, _) => {
+                /* interest in writability is only registered for the duration of `send` */
+                continue;
+            }
+        }
+    }
+}
+
+/// Relays the request to the server it is meant for and the response back to the client, see `proxy`.
+/// Connection is closed afterwards, since the response ends only when the server closes its one.
+fn forward<L, V>(
+    registry: &mut Registry,
+    handler: &RequestHandler<L, V>,
+    connection: &mut HttpConnection,
+    request: &Request,
+    outgoing: &Outgoing,
+) -> io::Result<()>
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    let started = Instant::now();
+    let _context = connection.context().enter();
+    let mut upstream = match outgoing.connect(registry, connection.stream()) {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            log!(Level::Info, "could not reach {} for connection {}: {}", outgoing, connection.token(), err);
+            return respond(registry, connection, gateway_error(handler, &err));
+        }
+    };
+    if let Ok(address) = upstream.peer_addr() {
+        trace!(connection.token(), "relaying response from {}", address);
+    }
+    let (relayed, result) = proxy::relay(registry, connection.stream(), &mut upstream, outgoing.idle_timeout());
+    let result = result.and_then(|_| match relayed.bytes {
+        0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection without answering")),
+        _ => Ok(()),
+    });
+    if let Err(err) = result {
+        log!(Level::Info, "relaying response from {} on connection {} failed: {}", outgoing, connection.token(), err);
+        /* client can still be told what happened if nothing was sent yet */
+        if relayed.bytes == 0 {
+            return respond(registry, connection, gateway_error(handler, &err));
+        }
+        return Err(err);
+    }
+    connection.response_sent(relayed.status_code().unwrap_or(StatusCode::BadGateway.code()), relayed.bytes);
+    handler.forwarded(request, &relayed, started.elapsed());
+    Ok(())
+}
+
+/// Runs the CGI script the request is for until it writes the response head, its pipes are watched
+/// by the `registry` meanwhile, see `cgi`. Script is returned unless it failed, the rest of its output is the body.
+fn execute<'a, L, V>(
+    registry: &mut Registry,
+    handler: &RequestHandler<L, V>,
+    connection: &HttpConnection,
+    request: &'a Request,
+    cgi: &CgiHandler,
+) -> (Response, Option<Script<'a>>)
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    let started = Instant::now();
+    let _context = connection.context().enter();
+    let stream = connection.stream();
+    let result = cgi
+        .spawn(request, connection.peer(), stream.local_addr().ok())
+        .and_then(|child| cgi.start(child, stream, request.body()))
+        .and_then(|mut script| Ok((script.response(registry, request)?, script)));
+    match result {
+        Ok((response, script)) => (handler.executed(request, Ok(response), started.elapsed()), Some(script)),
+        Err(err) => (handler.executed(request, Err(err), started.elapsed()), None),
+    }
+}
+
+/// Sends the rest of the script output as the body of the response whose head was sent.
+/// `false` if the script failed before the body ended, the connection cannot be reused then.
+fn stream_output(registry: &mut Registry, connection: &mut HttpConnection, mut script: Script) -> io::Result<bool> {
+    loop {
+        let part = match script.next_part(registry) {
+            Ok(Some(part)) => part,
+            Ok(None) => return Ok(true),
+            Err(err) => {
+                log!(Level::Info, "output of the script on connection {} failed: {}", connection.token(), err);
+                return Ok(false);
+            }
+        };
+        connection.sender.push(&part);
+        connection.transition(ActionStatus::SendPending);
+        send(registry, connection)?;
+        connection.transition(ActionStatus::SendFinished);
+    }
+}
+
+/// 504 if the server did not answer in time, 502 for other failures.
+fn gateway_error<L, V>(handler: &RequestHandler<L, V>, err: &io::Error) -> Response
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    match err.kind() {
+        io::ErrorKind::TimedOut => handler.gateway_timeout(),
+        _ => handler.bad_gateway(),
+    }
+}
+
+/// Answers request that is arriving too slowly with 408, the connection is closed afterwards.
+fn request_timeout<L, V>(registry: &mut Registry, handler: &RequestHandler<L, V>, connection: &mut HttpConnection) -> io::Result<()>
+where
+    L: ResourceLoader<LoadError = LoadResourceError>,
+    V: ResourceValidator<ValidationError = ValidationResourceError>,
+{
+    log!(Level::Info, "request on connection {} was not received in time", connection.token());
+    connection.transition(ActionStatus::DownloadFinished);
+    respond(registry, connection, handler.request_timeout())
+}
+
+/// Sends the response and prepares the connection for the next request.
+fn respond(registry: &mut Registry, connection: &mut HttpConnection, response: Response) -> io::Result<()> {
+    let len = response.len();
+    let status_code = response.status_line().status_code().code();
+    let (data, body) = response.into_parts();
+    let mut sender = HttpSender::new(connection.stream().try_clone()?, data)
+        .with_body_part(body)
+        .with_timeout(connection.send_timeout());
+    if let Some(limit) = connection.bandwidth_limit() {
+        sender = sender.with_bandwidth_limit(limit);
+    }
+    connection.sender = sender;
+    connection.downloader.reset(connection.stream().try_clone()?);
+    connection.transition(ActionStatus::SendPending);
+    send(registry, connection)?;
+    connection.response_sent(status_code, len);
+    connection.transition(ActionStatus::SendFinished);
+    Ok(())
+}
+
+/// Sends the head of the response, then the events of the stream as they become due, until the
+/// stream ends or the client goes away. Connection is closed afterwards, see `sse`.
+fn stream_events(registry: &mut Registry, connection: &mut HttpConnection, response: Response, mut events: EventStream) -> io::Result<()> {
+    respond(registry, connection, response)?;
+    let mut discarded = [0; 512];
+    loop {
+        let timeout = TimeoutDuration::Finite(events.time_until_next(Instant::now()));
+        match await_connection(registry, connection, &timeout)? {
+            Notification::Timeout => {
+                let Some(event) = events.next_event(Instant::now()) else {
+                    return Ok(());
+                };
+                connection.sender.push(event.to_string().as_bytes());
+                connection.transition(ActionStatus::SendPending);
+                send(registry, connection)?;
+                connection.transition(ActionStatus::SendFinished);
+            }
+            /* clients do not send anything on an event stream, reads only tell that they went away */
+            Notification::Event(EventType::Read, _) => match (&mut connection.stream()).read(&mut discarded) {
+                Ok(0) => return Ok(()),
+                Ok(_) => continue,
+                Err(err) if is_transient(&err) => continue,
+                Err(err) => return Err(err),
+            },
+            Notification::Event(EventType::Write, _) => continue,
+        }
+    }
+}
+
+/// Writes the whole response, waiting for the socket to become writable when its buffer is full,
+/// or for the bandwidth limit to allow more. Fails with `TimedOut` once the client accepts nothing
+/// for the send timeout. Interest in reading is restored afterwards.
+fn send(registry: &mut Registry, connection: &mut HttpConnection) -> io::Result<()> {
+    let mut watching_writes = false;
+    let mut written = connection.sender.written();
+    let result = loop {
+        match connection.advance_send() {
+            Ok(()) => break Ok(()),
+            Err(err) if is_transient(&err) => {
+                if connection.sender.written() != written {
+                    written = connection.sender.written();
+                    connection.postpone_deadline(Instant::now());
+                }
+                if !watching_writes {
+                    registry.modify_interest(EventType::Read, connection.stream().as_raw_fd(), EventType::Write)?;
+                    watching_writes = true;
+                }
+                if let Some(delay) = connection.sender.throttled_for() {
+                    match await_refill(registry, connection, delay) {
+                        Ok(()) => continue,
+                        Err(err) => break Err(err),
+                    }
+                }
+                let timeout = connection.time_left(Instant::now());
+                match await_connection(registry, connection, &timeout) {
+                    Ok(Notification::Timeout) if connection.is_overdue(Instant::now()) => {
+                        break Err(io::Error::from(io::ErrorKind::TimedOut));
+                    }
+                    Ok(Notification::Timeout) => {}
+                    Ok(Notification::Event(..)) => {}
+                    Err(err) => break Err(err),
+                }
+            }
+            Err(err) => break Err(err),
+        }
+    };
+    if watching_writes {
+        registry.modify_interest(EventType::Write, connection.stream().as_raw_fd(), EventType::Read)?;
+    }
+    result
+}
+
+/// Waits for the bandwidth limit of the response to allow the next slice. Socket is writable
+/// meanwhile, so interest in writing is dropped for the wait and armed again once the timer fires.
+fn await_refill(registry: &mut Registry, connection: &HttpConnection, delay: Duration) -> io::Result<()> {
+    let fd = connection.stream().as_raw_fd();
+    let timer = Timer::once(delay)?;
+    registry.delete_interest(EventType::Write, fd)?;
+    registry.add_interest(EventType::Read, timer.as_raw_fd())?;
+    let waited = registry.await_ready(&TimeoutDuration::Infinite);
+    registry.delete_interest(EventType::Read, timer.as_raw_fd())?;
+    registry.add_interest(EventType::Write, fd)?;
+    waited.map(|_| ())
+}
+
+/// Waits for the connection the same way `Registry::await_event` does, except that a connection
+/// reset by the peer, or with another error pending, fails right away with that error
+/// instead of being reported ready and failing on the next read or write.
+fn await_connection(registry: &mut Registry, connection: &HttpConnection, timeout: &TimeoutDuration) -> io::Result<Notification> {
+    let started = Instant::now();
+    let ready = registry.await_events(timeout)?;
+    let Some(event) = ready.first() else {
+        return Ok(Notification::Timeout);
+    };
+    if event.error {
+        trace!(connection.token(), "connection failed in state {}", connection.status());
+        let err = connection.stream().take_error()?;
+        return Err(err.unwrap_or_else(|| io::Error::from(io::ErrorKind::ConnectionReset)));
+    }
+    Ok(match registry.interest(event.token) {
+        Some((event_type, _)) => Notification::Event(event_type, started.elapsed()),
+        None => Notification::Timeout,
+    })
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthPolicy, AuthRule};
+    use crate::cgi::CgiHandler;
+    use crate::forwarded::Forwarding;
+    use crate::proxy::{ProxyConfig, ProxyHandler};
+    use crate::ratelimit::RateLimitConfig;
+    use crate::redirect::HttpsRedirect;
+    use crate::routing::Router;
+    use crate::spool::SpoolConfig;
+    use crate::sse::{Event, EventStreamHandler};
+    use crate::throttle::BandwidthLimit;
+    use crate::timeouts::ReadTimeouts;
+    use crate::resources::{StaticLoader, StaticValidator};
+    use crate::vhost::VirtualHosts;
+    use common::fs::TempDir;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn handler(catalog: &Path) -> Arc<RequestHandler> {
+        let catalog: Arc<Path> = Arc::from(catalog);
+        let hosts = VirtualHosts::default_config(&catalog);
+        let validator = StaticValidator::from_virtual_hosts(catalog.clone(), &hosts);
+        Arc::new(RequestHandler::new(StaticLoader::new(catalog), validator, Arc::new(hosts)))
+    }
+
+    #[test]
+    fn test_pool_serves_concurrent_connections() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut pool = WorkerPool::new(2, handler(dir.path())).unwrap();
+        assert_eq!(pool.size(), 2);
+
+        /* first client keeps its connection open, second one has to be served by the other worker */
+        let mut idle = TcpStream::connect(address).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        for token in 0..2 {
+            pool.dispatch(token, listener.accept().unwrap().0);
+        }
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("<p>hello</p>"), "{response}");
+
+        idle.write_all(b"GET /missing.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        idle.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_metrics_count_served_requests() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handler = handler(dir.path());
+        let metrics = handler.metrics().clone();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+        let mut get = |path: &str| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            pool.dispatch(0, listener.accept().unwrap().0);
+            write!(client, "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        let page = get("/index.html");
+        get("/missing.html");
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\nserver_connections_active 1\n"), "{response}");
+        assert!(response.contains("\nserver_requests_total{code=\"200\"} 1\n"), "{response}");
+        assert!(response.contains("\nserver_requests_total{code=\"404\"} 1\n"), "{response}");
+        assert!(response.contains("server_handler_duration_seconds_count{handler=\"static\"} 2\n"), "{response}");
+        pool.join();
+        assert_eq!(metrics.requests(200), 2);
+        assert_eq!(metrics.active_connections(), 0);
+        assert!(metrics.bytes_sent() > page.len() as u64);
+    }
+
+    #[test]
+    fn test_pipelined_requests_are_answered_in_order() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler(dir.path())).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+        client.write_all(
+            b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /missing.html HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        ).unwrap();
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        let statuses = responses.match_indices("HTTP/1.1 ").map(|(index, _)| &responses[index + 9..index + 12]).collect::<Vec<_>>();
+        assert_eq!(statuses, ["200", "404", "200"], "{responses}");
+        assert!(responses.ends_with("<p>hello</p>"), "{responses}");
+        let mut ids = responses.lines().filter_map(|line| line.strip_prefix("X-Request-Id: ")).collect::<Vec<_>>();
+        ids.dedup();
+        assert_eq!(ids.len(), 3, "every request gets its own id: {responses}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_requests_over_rate_limit_are_rejected() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let hosts = VirtualHosts::default_config(&catalog);
+        let validator = StaticValidator::from_virtual_hosts(catalog.clone(), &hosts);
+        let config = RateLimitConfig { requests: 2, period: Duration::from_secs(60) };
+        let handler = RequestHandler::new(StaticLoader::new(catalog), validator, Arc::new(hosts)).with_rate_limit(config);
+        let mut pool = WorkerPool::new(1, Arc::new(handler)).unwrap();
+        let mut get = || {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            pool.dispatch(0, listener.accept().unwrap().0);
+            client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        assert!(get().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get().starts_with("HTTP/1.1 200 OK\r\n"));
+        let limited = get();
+        assert!(limited.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{limited}");
+        assert!(limited.contains("\r\nRetry-After: 30\r\n"), "{limited}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_forwarded_clients_are_rate_limited_separately() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = RateLimitConfig { requests: 1, period: Duration::from_secs(60) };
+        let forwarding = Forwarding::new(vec!["127.0.0.1".parse().unwrap()]).with_proxy_protocol(true);
+        let handler = Arc::into_inner(handler(dir.path())).unwrap().with_rate_limit(config).with_forwarding(forwarding);
+        let mut pool = WorkerPool::new(1, Arc::new(handler)).unwrap();
+        let mut get = |proxy_header: &str, forwarded_for: &str| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            pool.dispatch(0, listener.accept().unwrap().0);
+            write!(
+                client,
+                "{proxy_header}GET /index.html HTTP/1.1\r\nHost: localhost\r\n{forwarded_for}Connection: close\r\n\r\n"
+            ).unwrap();
+            let mut response = String::new();
+            /* rejected connection is reset, its request was never read */
+            let _ = client.read_to_string(&mut response);
+            response.split("\r\n").next().unwrap_or_default().to_owned()
+        };
+
+        let proxied_for = |client: &str| format!("PROXY TCP4 {client} 127.0.0.1 4000 80\r\n");
+        assert_eq!(get(&proxied_for("192.0.2.1"), ""), "HTTP/1.1 200 OK");
+        assert_eq!(get(&proxied_for("192.0.2.2"), ""), "HTTP/1.1 200 OK");
+        assert_eq!(get(&proxied_for("192.0.2.1"), ""), "HTTP/1.1 429 Too Many Requests");
+        /* address in the header is believed, the trusted proxy itself connected on behalf of the client */
+        assert_eq!(get(&proxied_for("127.0.0.1"), "X-Forwarded-For: 192.0.2.3\r\n"), "HTTP/1.1 200 OK");
+        assert_eq!(get(&proxied_for("127.0.0.1"), "X-Forwarded-For: 192.0.2.3\r\n"), "HTTP/1.1 429 Too Many Requests");
+        /* connection from a trusted proxy without the line is dropped */
+        assert_eq!(get("", ""), "");
+        pool.join();
+    }
+
+    /// Keep-alive connection requesting the same file `requests` times, served with `registry`.
+    #[test]
+    fn test_slow_requests_time_out() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let hosts = VirtualHosts::default_config(&catalog);
+        let validator = StaticValidator::from_virtual_hosts(catalog.clone(), &hosts);
+        let timeouts = ReadTimeouts {
+            headers: Duration::from_millis(300),
+            min_body_rate: 1000,
+            body_grace: Duration::from_millis(200),
+            ..ReadTimeouts::default()
+        };
+        let handler = RequestHandler::new(StaticLoader::new(catalog), validator, Arc::new(hosts)).with_read_timeouts(timeouts);
+        let mut pool = WorkerPool::new(1, Arc::new(handler)).unwrap();
+        let mut dribble = |parts: &[&str]| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            pool.dispatch(0, listener.accept().unwrap().0);
+            let started = std::time::Instant::now();
+            for part in parts {
+                client.write_all(part.as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(40));
+            }
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            (response, started.elapsed())
+        };
+
+        /* every byte arrives well within the timeout, the header section as a whole does not */
+        let (response, elapsed) = dribble(&["GET /index.html HTTP/1.1\r\n", "H", "o", "s", "t"]);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\nConnection: close\r\n"), "{response}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+
+        let (response, _) = dribble(&["POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5000\r\n\r\n", "abc"]);
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{response}");
+
+        /* connection that never sent anything is closed without a response */
+        let (response, _) = dribble(&[]);
+        assert_eq!(response, "");
+        pool.join();
+    }
+
+    #[cfg(feature = "io-uring")]
+    fn serve_static_files(handler: &RequestHandler, mut registry: Registry, body: &[u8], requests: usize) -> std::time::Duration {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        /* responses are written in parts, Nagle's algorithm would delay every one of them */
+        client.set_nodelay(true).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let started = std::time::Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(|| serve(&mut registry, handler, 0, stream));
+            let mut response = Vec::new();
+            let mut chunk = [0; 8192];
+            for index in 0..requests {
+                let connection = if index + 1 == requests { "close" } else { "keep-alive" };
+                write!(client, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: {connection}\r\n\r\n").unwrap();
+                response.clear();
+                while !response.ends_with(body) {
+                    let count = client.read(&mut chunk).unwrap();
+                    assert_ne!(count, 0, "connection closed early");
+                    response.extend_from_slice(&chunk[..count]);
+                }
+            }
+        });
+        started.elapsed()
+    }
+
+    /// Run with `cargo test --release --features io-uring -- --ignored --nocapture bench_`.
+    #[cfg(feature = "io-uring")]
+    #[test]
+    #[ignore]
+    fn bench_static_files_epoll_vs_io_uring() {
+        const REQUESTS: usize = 20_000;
+        let dir = TempDir::new("server-worker").unwrap();
+        let body = (0..4096).map(|index| b'a' + (index % 26) as u8).collect::<Vec<_>>();
+        dir.create_file("localhost/index.html", &body).unwrap();
+        let handler = handler(dir.path());
+        for (name, registry) in [("epoll", Registry::with_epoll()), ("io_uring", Registry::with_io_uring())] {
+            let elapsed = serve_static_files(&handler, registry.unwrap(), &body, REQUESTS);
+            let rate = REQUESTS as f64 / elapsed.as_secs_f64();
+            println!("{name:>8}: {REQUESTS} requests in {elapsed:?}, {rate:.0} requests/s");
+        }
+    }
+
+    #[test]
+    fn test_idle_connections_are_closed_after_keep_alive_timeout() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let timeouts = ReadTimeouts { keep_alive: Duration::from_millis(1500), ..ReadTimeouts::default() };
+        let handler = Arc::new(Arc::into_inner(handler(dir.path())).unwrap().with_read_timeouts(timeouts));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let started = Instant::now();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.contains("\r\nKeep-Alive: timeout=1\r\n"), "{response}");
+        assert!(response.ends_with("<p>hello</p>"), "{response}");
+        assert!(started.elapsed() >= Duration::from_millis(1500), "closed after {:?}", started.elapsed());
+        pool.join();
+    }
+
+    #[test]
+    fn test_responses_the_client_does_not_accept_are_abandoned() {
+        let dir = TempDir::new("server-worker").unwrap();
+        /* more than the socket buffers of both ends can take */
+        let content = vec![b'a'; 32 * 1024 * 1024];
+        dir.create_file("localhost/large.bin", &content).unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let timeouts = ReadTimeouts { send: Duration::from_millis(300), ..ReadTimeouts::default() };
+        let handler = Arc::new(Arc::into_inner(handler(dir.path())).unwrap().with_read_timeouts(timeouts));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+
+        let mut stalled = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+        stalled.write_all(b"GET /large.bin HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+        /* the only worker is free again once the stalled response is abandoned */
+        let started = Instant::now();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(1, listener.accept().unwrap().0);
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("<p>hello</p>"), "{response}");
+        assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+
+        let mut truncated = Vec::new();
+        let _ = stalled.read_to_end(&mut truncated);
+        assert!(truncated.len() < content.len(), "{}", truncated.len());
+        pool.join();
+    }
+
+    #[test]
+    fn test_reset_connections_are_closed_right_away() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler(dir.path())).unwrap();
+
+        let mut reset = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+        reset.write_all(b"GET /index.html HTTP/1.1\r\nHo").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        /* closing a socket with zero linger time resets its connection */
+        let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+        let size = std::mem::size_of::<libc::linger>() as libc::socklen_t;
+        registry::syscall!(setsockopt(reset.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER, &linger as *const libc::linger as *const libc::c_void, size)).unwrap();
+        drop(reset);
+
+        /* the only worker is free again well before the header timeout */
+        let started = Instant::now();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(1, listener.accept().unwrap().0);
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("<p>hello</p>"), "{response}");
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+        pool.join();
+    }
+
+    #[test]
+    fn test_garbage_is_rejected_before_header_terminator() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler(dir.path())).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+
+        /* no CRLF CRLF is ever sent, the connection must not wait for it */
+        client.write_all(&[b'A'; 100]).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\nConnection: close\r\n"), "{response}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_responses_are_throttled_to_the_bandwidth_limit() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let content = (0..20_000).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+        dir.create_file("localhost/large.bin", &content).unwrap();
+        let handler = Arc::new(Arc::into_inner(handler(dir.path())).unwrap().with_bandwidth_limit(BandwidthLimit::new(50_000)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+
+        let started = Instant::now();
+        client.write_all(b"GET /large.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let elapsed = started.elapsed();
+        assert!(response.ends_with(&content));
+        /* first slice goes out right away, the remaining 15kB take 0.3s */
+        assert!(elapsed >= Duration::from_millis(280), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_bodies_over_spool_quota_are_refused() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let spool = SpoolConfig { threshold: 8, directory: dir.path().to_owned(), quota: Some(16) };
+        let catalog: Arc<Path> = Arc::from(dir.path());
+        let hosts = VirtualHosts::default_config(&catalog);
+        let validator = StaticValidator::from_virtual_hosts(catalog.clone(), &hosts);
+        let handler = RequestHandler::new(StaticLoader::new(catalog), validator, Arc::new(hosts)).with_spool(spool);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, Arc::new(handler)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+
+        /* refused as soon as the header section arrives, before any of the body */
+        client.write_all(b"POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 507 Insufficient Storage\r\nConnection: close\r\n"), "{response}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_event_streams_are_written_as_events_become_due() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let events = EventStreamHandler::new(Duration::from_millis(20), |_: &Request| {
+            let mut count = 0;
+            move || {
+                count += 1;
+                (count <= 2).then(|| Event::new(&format!("tick {count}")))
+            }
+        });
+        let handler = Arc::new(Arc::into_inner(handler(dir.path())).unwrap().with_routes(Router::new().exact("/events", events)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+
+        client.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ndata: tick 1\n\ndata: tick 2\n\n"), "{response}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_panicking_handler_fails_only_its_request() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"<p>hello</p>").unwrap();
+        let broken = |_: &Request| -> Response { panic!("broken handler") };
+        let handler = Arc::new(Arc::into_inner(handler(dir.path())).unwrap().with_routes(Router::new().exact("/broken", broken)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+        client.write_all(b"GET /broken HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\n"), "{response}");
+
+        /* the only worker is still there to serve the next connection */
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(1, listener.accept().unwrap().0);
+        client.write_all(b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("<p>hello</p>"), "{response}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_absolute_targets_are_relayed_to_the_origin() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin_address = origin.local_addr().unwrap();
+        let origin = thread::spawn(move || {
+            let (mut stream, _) = origin.accept().unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            /* head arriving in parts is held back until it is complete */
+            stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\n").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(b"Content-Length: 6\r\n\r\norigin").unwrap();
+            String::from_utf8(head).unwrap()
+        });
+        let handler = handler(dir.path());
+        let handler = Arc::new(Arc::into_inner(handler).unwrap().with_proxy(ProxyConfig::default()));
+        let metrics = handler.metrics().clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+        let mut get = |target: &str| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            pool.dispatch(0, listener.accept().unwrap().0);
+            /* origin is not one of the virtual hosts, requests for it are relayed all the same */
+            write!(client, "GET {target} HTTP/1.1\r\nHost: 127.0.0.1\r\nProxy-Connection: keep-alive\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get(&format!("http://{origin_address}/page?q=1"));
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 6\r\nVia: 1.1 server\r\nConnection: close\r\n\r\norigin");
+        assert_eq!(
+            origin.join().unwrap(),
+            format!("GET /page?q=1 HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nVia: 1.1 server\r\nConnection: close\r\n\r\n", origin_address.port())
+        );
+
+        /* nothing listens on the origin port anymore */
+        let response = get(&format!("http://{origin_address}/"));
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n"), "{response}");
+        pool.join();
+        assert_eq!(metrics.requests(200), 1);
+        assert_eq!(metrics.requests(502), 1);
+    }
+
+    #[test]
+    fn test_reverse_proxy_takes_turns_between_upstreams() {
+        let dir = TempDir::new("server-worker").unwrap();
+        dir.create_file("localhost/index.html", b"local").unwrap();
+        let upstreams = ["first", "second"].map(|name| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let upstream = thread::spawn(move || {
+                let mut heads = Vec::new();
+                for _ in 0..2 {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut head = Vec::new();
+                    let mut byte = [0; 1];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        stream.read_exact(&mut byte).unwrap();
+                        head.push(byte[0]);
+                    }
+                    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{name}", name.len()).unwrap();
+                    heads.push(String::from_utf8(head).unwrap());
+                }
+                heads
+            });
+            (address, upstream)
+        });
+        let proxy = ProxyHandler::new("/api", upstreams.iter().map(|(address, _)| *address).collect());
+        let handler = Arc::new(Arc::into_inner(handler(dir.path())).unwrap().with_reverse_proxy(proxy));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+        let mut get = |target: &str| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            pool.dispatch(0, listener.accept().unwrap().0);
+            write!(client, "GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response.split_once("\r\n\r\n").unwrap().1.to_owned()
+        };
+
+        let bodies = ["/api/a", "/api/b", "/index.html", "/api/c", "/api?page=2"].map(&mut get);
+        assert_eq!(bodies, ["first", "second", "local", "first", "second"]);
+        pool.join();
+        let [(_, first), (_, second)] = upstreams;
+        let heads = first.join().unwrap().into_iter().chain(second.join().unwrap()).collect::<Vec<_>>();
+        let targets = heads.iter().map(|head| head.split(' ').nth(1).unwrap()).collect::<Vec<_>>();
+        assert_eq!(targets, ["/api/a", "/api/c", "/api/b", "/api?page=2"]);
+        assert!(heads.iter().all(|head| head.contains("\r\nX-Forwarded-For: 127.0.0.1\r\n")), "{heads:?}");
+    }
+
+    #[test]
+    fn test_cgi_responses_keep_the_connection_open() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("server-worker").unwrap();
+        let script = dir.create_file("cgi/hello", b"#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\nhello %s' \"$QUERY_STRING\"\n").unwrap();
+        std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let cgi = CgiHandler::new("/cgi-bin", dir.path().join("cgi"));
+        let handler = Arc::new(Arc::into_inner(handler(dir.path())).unwrap().with_cgi(cgi));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut pool = WorkerPool::new(1, handler).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+
+        for name in ["first", "second"] {
+            write!(client, "GET /cgi-bin/hello?{name} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let expected = "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nTransfer-Encoding: chunked\r\nDate: ";
+            let mut response = vec![0; expected.len()];
+            client.read_exact(&mut response).unwrap();
+            assert_eq!(String::from_utf8(response).unwrap(), expected);
+            /* date is always as long as `Thu, 01 Jan 1970 00:00:00 GMT`, request id as `0123abcd-000000000001` */
+            let keep_alive = "\r\nKeep-Alive: timeout=2";
+            let body = format!("{:X}\r\nhello {name}\r\n0\r\n\r\n", 6 + name.len());
+            let mut response = vec![0; 29 + 2 + "X-Request-Id: ".len() + 21 + keep_alive.len() + 4 + body.len()];
+            client.read_exact(&mut response).unwrap();
+            let response = String::from_utf8(response).unwrap();
+            assert!(response.contains(" GMT\r\nX-Request-Id: "), "{response}");
+            assert!(response.contains(keep_alive), "{response}");
+            assert!(response.ends_with(&format!("\r\n\r\n{body}")), "{response}");
+        }
+        write!(client, "GET /cgi-bin/missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{response}");
+        pool.join();
+    }
+
+    /// Pool serving the `hello` script below `/cgi-bin` and relaying `/api` to a port nothing listens on,
+    /// with the gates `configure` sets up, and the listener its clients connect to.
+    fn serve_gated(dir: &TempDir, configure: impl FnOnce(RequestHandler) -> RequestHandler) -> (WorkerPool, TcpListener) {
+        use std::os::unix::fs::PermissionsExt;
+
+        dir.create_file("localhost/index.html", b"local").unwrap();
+        let script = dir.create_file("cgi/hello", b"#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\nhello'\n").unwrap();
+        std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let handler = Arc::into_inner(handler(dir.path())).unwrap()
+            .with_cgi(CgiHandler::new("/cgi-bin", dir.path().join("cgi")))
+            .with_reverse_proxy(ProxyHandler::new("/api", vec![upstream]));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        (WorkerPool::new(1, Arc::new(configure(handler))).unwrap(), listener)
+    }
+
+    /// Sends the request `head` on a new connection and reads the whole response.
+    fn exchange(pool: &mut WorkerPool, listener: &TcpListener, head: &str) -> String {
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        pool.dispatch(0, listener.accept().unwrap().0);
+        write!(client, "{head}Connection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_relayed_requests_and_scripts_pass_rate_limit_and_auth() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let config = RateLimitConfig { requests: 2, period: Duration::from_secs(60) };
+        let (mut pool, listener) = serve_gated(&dir, |handler| handler.with_rate_limit(config));
+        let mut get = |target: &str| exchange(&mut pool, &listener, &format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n"));
+        assert!(get("/cgi-bin/hello").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("/api/a").starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        for target in ["/cgi-bin/hello", "/api/a"] {
+            let response = get(target);
+            assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{response}");
+        }
+        pool.join();
+
+        let dir = TempDir::new("server-worker").unwrap();
+        let auth = AuthPolicy::new()
+            .with_rule(AuthRule::bearer("/cgi-bin", "Scripts", "secret-token\n"))
+            .with_rule(AuthRule::bearer("/api", "API", "secret-token\n"));
+        let (mut pool, listener) = serve_gated(&dir, |handler| handler.with_auth(auth));
+        for target in ["/cgi-bin/hello", "/api/a"] {
+            let response = exchange(&mut pool, &listener, &format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n"));
+            assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{response}");
+        }
+        let authorized = "GET /cgi-bin/hello HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret-token\r\n";
+        assert!(exchange(&mut pool, &listener, authorized).ends_with("\r\n5\r\nhello\r\n0\r\n\r\n"));
+        pool.join();
+    }
+
+    #[test]
+    fn test_relayed_requests_and_scripts_need_a_served_host() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let (mut pool, listener) = serve_gated(&dir, |handler| handler);
+        for target in ["/cgi-bin/hello", "/api/a"] {
+            let response = exchange(&mut pool, &listener, &format!("GET {target} HTTP/1.1\r\n"));
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{response}");
+            let response = exchange(&mut pool, &listener, &format!("GET {target} HTTP/1.1\r\nHost: elsewhere.example\r\n"));
+            assert!(response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"), "{response}");
+        }
+        let response = exchange(&mut pool, &listener, "GET /cgi-bin/hello HTTP/1.1\r\nHost: LOCALHOST\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        pool.join();
+    }
+
+    #[test]
+    fn test_relayed_requests_and_scripts_are_redirected_to_https() {
+        let dir = TempDir::new("server-worker").unwrap();
+        let (mut pool, listener) = serve_gated(&dir, |handler| handler.with_https_redirect(HttpsRedirect::new(8443)));
+        for target in ["/cgi-bin/hello?name=x", "/api/a?page=2"] {
+            let response = exchange(&mut pool, &listener, &format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n"));
+            assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{response}");
+            assert!(response.contains(&format!("\r\nLocation: https://localhost:8443{target}\r\n")), "{response}");
+        }
+        let response = exchange(&mut pool, &listener, "GET /api/a HTTP/1.1\r\nHost: elsewhere.example\r\n");
+        assert!(response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"), "{response}");
+        pool.join();
+    }
+}
@@ -5,20 +5,22 @@
 
 #![allow(dead_code)]
 
-use std::fmt::Debug;
-use std::fs::{File, OpenOptions};
-use std::io;
+use std::fmt::{Debug, Display, Formatter};
 use std::io::Write as _;
 use std::fmt::Write as _;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::net::SocketAddrV4;
 use std::time::Duration;
+use common::{dns, units};
 
-use crate::messages::{ByteRange, Request, Response};
+use crate::file_writer::FileWriter;
+use crate::link::{Link, Notification, UdpLink};
+use crate::messages::{Abort, ByteRange, Request, Response};
+use crate::observer::{Observer, Progress};
 use crate::segment::Segment;
-use crate::registry::{EventType, Registry};
+use crate::ui::TerminalUi;
 use crate::window::Window;
-use crate::{registry, util};
+use crate::util;
 use crate::util::FailWithMessage;
 
 
@@ -51,154 +53,227 @@ impl Iterator for SegmentByteRangeIter {
     }
 }
 
-#[cfg(test)]
-mod tests_segment_byte_range_iter {
-    use super::SegmentByteRangeIter;
+/// Traffic counters of a single downloader socket.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SocketStatistics {
+    pub requests_sent: usize,
+    pub responses_received: usize,
+    pub duplicates: usize,
+    pub bytes_received: usize,
+}
 
-    #[test]
-    fn test_1() {
-        let mut seg_iter = SegmentByteRangeIter::new(1000, 300);
-        assert_eq!(Some(0..300), seg_iter.next());
-        assert_eq!(Some(300..600), seg_iter.next());
-        assert_eq!(Some(600..900), seg_iter.next());
-        assert_eq!(Some(900..1000), seg_iter.next());
-        assert_eq!(None, seg_iter.next());
-    }
-
-    #[test]
-    fn test_2() {
-        let mut seg_iter = SegmentByteRangeIter::new(100, 1000);
-        assert_eq!(Some(0..100), seg_iter.next());
-        assert_eq!(None, seg_iter.next());
+impl Display for SocketStatistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "requests sent: {}, responses received: {}, duplicates: {}, bytes received: {}",
+            self.requests_sent, self.responses_received, self.duplicates, self.bytes_received
+        )
     }
 }
 
-enum Notification {
-    Timeout,
-    ReadReady(Duration),
+/// Index of the socket that is responsible for the segment.
+///
+/// Segments are assigned round-robin by their position in the file, so retransmissions
+/// of the same segment always go through the same flow.
+fn socket_index(byte_range: &ByteRange, socket_count: usize) -> usize {
+    (byte_range.start / Segment::SIZE) % socket_count
 }
 
 
 pub struct Downloader {
-    socket: UdpSocket,
-    registry: Registry,
+    link: Box<dyn Link>,
+    statistics: Vec<SocketStatistics>,
     window: Window,
-    server_address: SocketAddrV4,
     segment_byte_ranges: SegmentByteRangeIter,
     file_size: usize,
-    file_handle: File
+    file_writer: FileWriter,
+    observers: Vec<Box<dyn Observer>>,
 }
 
 impl Downloader {
     const TIMEOUT: Duration = Duration::from_millis(1000);
+    /// Segment requested this long ago without an answer is requested again.
+    const RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(250);
 
     pub fn new(server_address: SocketAddrV4, file_name: &str, file_size: usize) -> Self {
-        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).map_err(|err| {
-            util::fail_with_message(format!("could not bind the socket: {err}").as_ref());
-        }).unwrap();
-
-        let mut registry = Registry::new().or_fail_with_message("could not create registry");
+        Self::with_sockets(server_address, file_name, file_size, 1)
+    }
 
-        let file_handle = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(file_name).map_err(|err|{
-                util::fail_with_message(format!("error occurred while opening the file {err}").as_str());
-            }).unwrap();
+    /// Downloader that spreads requests over `socket_count` sockets with distinct source ports.
+    ///
+    /// Some servers limit the rate per flow, using several flows works around that.
+    pub fn with_sockets(server_address: SocketAddrV4, file_name: &str, file_size: usize, socket_count: usize) -> Self {
+        Self::with_link(UdpLink::bind(server_address, socket_count), file_name, file_size)
+    }
 
-        registry.add_interest(EventType::Read, socket.as_raw_fd()).map_err(|err|
-            util::fail_with_message(format!("could not register interest for {}", err).as_ref())
-        ).unwrap();
+    /// Downloader that talks to the server through `link`, eg. a scripted one in tests.
+    pub fn with_link(link: impl Link + 'static, file_name: &str, file_size: usize) -> Self {
+        let file_writer = FileWriter::create(Path::new(file_name)).map_err(|err|{
+            util::fail_with_message(format!("error occurred while opening the file {err}").as_str());
+        }).unwrap();
 
         let mut segment_byte_ranges = SegmentByteRangeIter::new(file_size, Segment::SIZE);
         let window = Window::new(&mut segment_byte_ranges);
 
         Self {
-            socket,
-            registry,
+            statistics: vec![SocketStatistics::default(); link.socket_count()],
+            link: Box::new(link),
             window,
             segment_byte_ranges,
-            server_address,
             file_size,
-            file_handle,
+            file_writer,
+            observers: Vec::new(),
         }
     }
 
-    /* warning: as of current implementation there is only one item registered.
-        This function works correctly if this assumption holds.
-    */
-    fn await_socket_read_ready(&mut self, timeout: &Duration) -> Notification {
-        match self.registry.await_events(timeout) {
-            registry::Notification::Timeout => Notification::Timeout,
-            registry::Notification::Events(_, sleep_time) => Notification::ReadReady(sleep_time),
-        }
+    /// Renders progress of the download in the terminal after every round.
+    pub fn with_ui(self) -> Self {
+        self.with_observer(TerminalUi::new())
+    }
+
+    /// Subscribes the `observer` to the download, observers are called in the order they were added.
+    pub fn with_observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    pub fn statistics(&self) -> &[SocketStatistics] {
+        &self.statistics
     }
 
+    /// Requests segments that are due, see `Window::due_segments`.
     fn send_window_with_buf(&mut self, request_buffer: &mut String) {
-        for segment in self.window.unacknowledged_segments() {
+        let socket_count = self.link.socket_count();
+        let now = self.link.now();
+        for segment in self.window.due_segments(now, Self::RETRANSMISSION_TIMEOUT) {
+            let index = socket_index(segment.byte_range(), socket_count);
+            if segment.sent_at().is_some() {
+                for observer in &mut self.observers {
+                    observer.on_retransmit(segment.byte_range(), index);
+                }
+            }
+            segment.mark_sent(now);
             request_buffer.clear();
             write!(request_buffer, "{}", segment.request()).unwrap();
-            self.socket.send_to(request_buffer.as_ref(), self.server_address)
-                .or_fail_with_message("cannot send to the server");
+            self.link.send(index, request_buffer.as_bytes());
+            self.statistics[index].requests_sent += 1;
         }
     }
 
-    fn store_segments(&mut self, message_buffer: &mut [u8]) {
-        loop {
-            match self.socket.recv_from(message_buffer) {
-                Ok((message_size, SocketAddr::V4(sender))) if sender == self.server_address && Response::is_message_size_valid(message_size)  => {
-                    let response = Response::new(message_buffer);
-                    /* If segment is outside of window we ignore it. */
-                    if self.window.contains(response.byte_range()) {
-                        /* If the segment is a duplicate we ignore it. */
-                        let segment = &mut self.window[response.byte_range()];
-                        if !segment.is_received() {
-                            debug_assert_eq!(response.data().len(), response.byte_range().len());
-                            segment.write_all(response.data()).unwrap();
-                        }
+    /// Stores the segments that arrived, `Err` if the server aborted the download instead.
+    fn store_segments(&mut self, message_buffer: &mut [u8]) -> Result<(), Abort> {
+        for index in 0..self.link.socket_count() {
+            while let Some(message_size) = self.link.receive(index, message_buffer) {
+                if let Some(abort) = Abort::parse(&message_buffer[..message_size]) {
+                    return Err(abort);
+                }
+                if !Response::is_message_size_valid(message_size) {
+                    continue;
+                }
+                let response = Response::new(message_buffer);
+                let statistics = &mut self.statistics[index];
+                statistics.responses_received += 1;
+                /* If segment is outside of window we ignore it. */
+                let duplicate = if self.window.contains(response.byte_range()) {
+                    /* If the segment is a duplicate we ignore it. */
+                    let segment = &mut self.window[response.byte_range()];
+                    if !segment.is_received() {
+                        debug_assert_eq!(response.data().len(), response.byte_range().len());
+                        segment.write_all(response.data()).unwrap();
+                        statistics.bytes_received += response.data().len();
+                        false
+                    } else {
+                        true
                     }
+                } else {
+                    true
+                };
+                statistics.duplicates += duplicate as usize;
+                for observer in &mut self.observers {
+                    observer.on_segment_received(response.byte_range(), index, duplicate);
                 }
-                Ok(_) => continue,
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
-                Err(err) => util::fail_with_message(format!("error occurred while reading from the socket. {}", err).as_ref())
-            };
+            }
+        }
+        Ok(())
+    }
+
+    fn report_statistics(&self) {
+        for (index, statistics) in self.statistics.iter().enumerate() {
+            match self.link.local_addr(index) {
+                Some(address) => eprintln!("socket {} ({}): {}", index, address, statistics),
+                None => eprintln!("socket {}: {}", index, statistics),
+            }
         }
     }
 
-    pub fn download(&mut self) {
+    /// Calls `notify` for every observer with the current progress.
+    fn notify(&mut self, notify: impl Fn(&mut dyn Observer, &Progress)) {
+        let progress = Progress {
+            window: &self.window,
+            bytes_written: self.file_writer.bytes_written(),
+            file_size: self.file_size,
+            statistics: &self.statistics,
+        };
+        for observer in &mut self.observers {
+            notify(observer.as_mut(), &progress);
+        }
+    }
+
+    /// Downloads the whole file, unless the server aborts the download, see `Abort`.
+    /// Segments received before the abort are dropped, only the slid ones are in the file.
+    pub fn download(&mut self) -> Result<(), Abort> {
         let mut request_buffer = String::with_capacity(Request::MAX_SIZE);
         let mut response_buffer = vec![0; Response::MAX_SIZE].into_boxed_slice();
         let mut bytes_downloaded = 0;
         let mut timeout = Self::TIMEOUT;
+        let mut round = 0;
 
         while bytes_downloaded < self.file_size {
-            self.socket.set_nonblocking(false).or_fail_with_message("cannot set socket to blocking mode");
+            self.notify(|observer, progress| observer.on_round_start(round, progress));
+            round += 1;
             self.send_window_with_buf(&mut request_buffer);
-            self.socket.set_nonblocking(true).or_fail_with_message("cannot set socket to nonblocking mode");
-            match self.await_socket_read_ready(&timeout) {
+            /* wake up for retransmissions that become due before the round ends */
+            let wait = match self.window.next_retransmission(Self::RETRANSMISSION_TIMEOUT) {
+                Some(due) => timeout.min(due.saturating_duration_since(self.link.now())),
+                None => timeout,
+            };
+            match self.link.await_read_ready(&wait) {
                 Notification::Timeout   => {
                     timeout = Self::TIMEOUT;
                     let segments = self.window.shrink();
-                    for segment in segments {
-                        self.file_handle.write_all(segment.as_ref()).map_err(|err| {
-                            util::fail_with_message(format!("could not append to file: {err}").as_ref());
-                        }).unwrap();
-                        bytes_downloaded += segment.len();
-                    }}
+                    let slid = segments.len();
+                    self.file_writer.write_segments(segments).map_err(|err| {
+                        util::fail_with_message(format!("could not append to file: {err}").as_ref());
+                    }).unwrap();
+                    bytes_downloaded = self.file_writer.bytes_written();
+                    if slid > 0 {
+                        self.notify(|observer, progress| observer.on_slide(slid, progress));
+                    }
+                }
                 Notification::ReadReady(sleep_time) => {
                     timeout = timeout.saturating_sub(sleep_time);
-                    self.store_segments(&mut response_buffer)
+                    if let Err(abort) = self.store_segments(&mut response_buffer) {
+                        self.notify(|observer, progress| observer.on_abort(&abort, progress));
+                        return Err(abort);
+                    }
                 },
             };
             self.window.extend(&mut self.segment_byte_ranges);
         }
         debug_assert_eq!(bytes_downloaded, self.file_size);
+        self.notify(|observer, progress| observer.on_complete(progress));
+        if self.link.socket_count() > 1 {
+            self.report_statistics();
+        }
+        Ok(())
     }
 }
 
 impl From<DownloaderConfig> for Downloader {
     fn from(config: DownloaderConfig) -> Self {
-        Self::new(config.address, config.file_name.as_ref(), config.size)
+        let downloader = Self::with_sockets(config.address, config.file_name.as_ref(), config.size, config.sockets);
+        if config.ui { downloader.with_ui() } else { downloader }
     }
 }
 
@@ -207,26 +282,250 @@ pub struct DownloaderConfig {
     pub address: SocketAddrV4,
     pub file_name: String,
     pub size: usize,
+    pub sockets: usize,
+    pub verify_against: Option<PathBuf>,
+    pub ui: bool,
 }
 
 impl DownloaderConfig {
-    pub fn try_from<I>(mut iter: I) -> Self
+    const VERIFY_AGAINST_FLAG: &'static str = "--verify-against";
+    const UI_FLAG: &'static str = "--ui";
+
+    /// Expected arguments:
+    /// <program> <server ipv4 or host name> <port> <file name> <file length> [<socket count>] [--verify-against <reference file>] [--ui]
+    ///
+    /// Host name is resolved with `common::dns`, before the event loop starts.
+    /// File length is a size such as `1000`, `64KiB` or `1M`, see `common::units`.
+    pub fn try_from<I>(iter: I) -> Self
     where I: Iterator<Item=String>
     {
-        let ip_address = iter.nth(1)
-            .or_fail_with_message("server ipv4 address missing")
-            .parse()
-            .or_fail_with_message("invalid format of server ipv4 address");
+        let mut args = iter.collect::<Vec<_>>();
+        let verify_against = match args.iter().position(|arg| arg == Self::VERIFY_AGAINST_FLAG) {
+            Some(index) => {
+                let flag_and_value = args.drain(index..(index + 2).min(args.len())).collect::<Vec<_>>();
+                let reference = flag_and_value.get(1).or_fail_with_message("reference file path missing");
+                Some(PathBuf::from(reference))
+            }
+            None => None,
+        };
+        let ui = match args.iter().position(|arg| arg == Self::UI_FLAG) {
+            Some(index) => {
+                args.remove(index);
+                true
+            }
+            None => false,
+        };
+        let mut iter = args.into_iter();
+        let host = iter.nth(1).or_fail_with_message("server address missing");
+        let ip_address = dns::system()
+            .resolve_ipv4(&host)
+            .unwrap_or_else(|err| util::fail_with_message(&format!("could not resolve server address: {err}")))
+            .into_iter()
+            .next()
+            .or_fail_with_message("server has no ipv4 address");
         let port = iter.next()
             .or_fail_with_message("server port missing")
             .parse()
             .or_fail_with_message("invalid format of server port");
         let file_name = iter.next()
             .or_fail_with_message("file name missing");
-        let size = iter.next()
-            .or_fail_with_message("file length missing")
-            .parse()
+        let size = units::parse_size(&iter.next().or_fail_with_message("file length missing"))
             .or_fail_with_message("invalid format of file length");
-        Self { address: SocketAddrV4::new(ip_address, port), size, file_name }
+        let sockets = match iter.next() {
+            Some(repr) => match repr.parse() {
+                Ok(count) if count > 0 => count,
+                _ => util::fail_with_message("socket count should be a positive integer"),
+            },
+            None => 1,
+        };
+        Self { address: SocketAddrV4::new(ip_address, port), size, file_name, sockets, verify_against, ui }
+    }
+}
+
+#[cfg(test)]
+mod tests_segment_byte_range_iter {
+    use super::SegmentByteRangeIter;
+
+    #[test]
+    fn test_1() {
+        let mut seg_iter = SegmentByteRangeIter::new(1000, 300);
+        assert_eq!(Some(0..300), seg_iter.next());
+        assert_eq!(Some(300..600), seg_iter.next());
+        assert_eq!(Some(600..900), seg_iter.next());
+        assert_eq!(Some(900..1000), seg_iter.next());
+        assert_eq!(None, seg_iter.next());
+    }
+
+    #[test]
+    fn test_2() {
+        let mut seg_iter = SegmentByteRangeIter::new(100, 1000);
+        assert_eq!(Some(0..100), seg_iter.next());
+        assert_eq!(None, seg_iter.next());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::AbortCode;
+    use common::fs::TempDir;
+    use std::cell::RefCell;
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::time::Instant;
+    use std::rc::Rc;
+    use std::thread;
+
+    fn args(args: &[&str]) -> impl Iterator<Item=String> {
+        let args = args.iter().map(ToString::to_string).collect::<Vec<_>>();
+        args.into_iter()
+    }
+
+    #[test]
+    fn test_socket_count_argument() {
+        let config = DownloaderConfig::try_from(args(&["transport", "127.0.0.1", "40001", "out", "1000"]));
+        assert_eq!(config.sockets, 1);
+        let config = DownloaderConfig::try_from(args(&["transport", "127.0.0.1", "40001", "out", "1000", "4"]));
+        assert_eq!(config.sockets, 4);
+        assert_eq!(config.verify_against, None);
+    }
+
+    #[test]
+    fn test_verify_against_argument() {
+        let config = DownloaderConfig::try_from(
+            args(&["transport", "--verify-against", "reference", "127.0.0.1", "40001", "out", "1000"])
+        );
+        assert_eq!(config.verify_against, Some(PathBuf::from("reference")));
+        assert_eq!(config.file_name, "out");
+        assert_eq!(config.sockets, 1);
+        assert!(!config.ui);
+    }
+
+    #[test]
+    fn test_file_length_with_unit() {
+        let config = DownloaderConfig::try_from(args(&["transport", "127.0.0.1", "40001", "out", "64KiB"]));
+        assert_eq!(config.size, 64 * 1024);
+    }
+
+    #[test]
+    fn test_host_name_argument() {
+        let config = DownloaderConfig::try_from(args(&["transport", "localhost", "40001", "out", "1000"]));
+        assert_eq!(config.address, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 40001));
+    }
+
+    #[test]
+    fn test_ui_argument() {
+        let config = DownloaderConfig::try_from(args(&["transport", "127.0.0.1", "40001", "out", "1000", "--ui", "2"]));
+        assert!(config.ui);
+        assert_eq!(config.size, 1000);
+        assert_eq!(config.sockets, 2);
+    }
+
+    #[test]
+    fn test_segments_are_spread_over_sockets() {
+        let counts = SegmentByteRangeIter::new(10 * Segment::SIZE, Segment::SIZE)
+            .fold([0; 3], |mut counts, byte_range| {
+                counts[socket_index(&byte_range, 3)] += 1;
+                counts
+            });
+        assert_eq!(counts, [4, 3, 3]);
+        assert_eq!(socket_index(&(0..Segment::SIZE), 1), 0);
+    }
+
+    /// Records the callbacks, except for the round starts.
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Observer for Recorder {
+        fn on_segment_received(&mut self, byte_range: &ByteRange, _: usize, duplicate: bool) {
+            self.0.borrow_mut().push(format!("received {} duplicate {}", byte_range.start, duplicate));
+        }
+
+        fn on_retransmit(&mut self, byte_range: &ByteRange, _: usize) {
+            self.0.borrow_mut().push(format!("retransmit {}", byte_range.start));
+        }
+
+        fn on_slide(&mut self, segments: usize, progress: &Progress) {
+            self.0.borrow_mut().push(format!("slide {} to {}", segments, progress.bytes_written));
+        }
+
+        fn on_complete(&mut self, progress: &Progress) {
+            self.0.borrow_mut().push(format!("complete {}", progress.bytes_written));
+        }
+    }
+
+    /// Answers every request, except for the first one for the segment at `lost`,
+    /// and answers the first request for the segment at `doubled` twice.
+    /// Requests for bytes past `file_size` end the download with `AbortCode::SizeMismatch`.
+    fn serve(server: UdpSocket, file_size: usize, lost: usize, doubled: usize) {
+        server.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        let mut requests = Vec::new();
+        let mut buffer = [0; Request::MAX_SIZE];
+        while let Ok((len, client)) = server.recv_from(&mut buffer) {
+            let request = std::str::from_utf8(&buffer[..len]).unwrap();
+            let mut words = request.split_whitespace().skip(1).map(|word| word.parse::<usize>().unwrap());
+            let (start, length) = (words.next().unwrap(), words.next().unwrap());
+            if start + length > file_size {
+                let abort = Abort::new(AbortCode::SizeMismatch, &format!("file is {file_size} bytes long"));
+                server.send_to(abort.to_string().as_bytes(), client).unwrap();
+                continue;
+            }
+            let repeated = requests.contains(&start);
+            requests.push(start);
+            let copies = match start {
+                _ if start == lost && !repeated => 0,
+                _ if start == doubled && !repeated => 2,
+                _ => 1,
+            };
+            let mut response = format!("DATA {start} {length}\n").into_bytes();
+            response.extend(std::iter::repeat_n(b'x', length));
+            for _ in 0..copies {
+                server.send_to(&response, client).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_observer_follows_the_download() {
+        let dir = TempDir::new("transport-observer").unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let SocketAddr::V4(address) = server.local_addr().unwrap() else { unreachable!() };
+        let file_size = 2 * Segment::SIZE + 200;
+        thread::spawn(move || serve(server, file_size, Segment::SIZE, 2 * Segment::SIZE));
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let output = dir.path().join("out");
+        Downloader::new(address, output.to_str().unwrap(), file_size)
+            .with_observer(Recorder(events.clone()))
+            .download()
+            .unwrap();
+
+        let events = events.borrow();
+        let position = |event: &str| events.iter().position(|other| other == event).unwrap_or_else(|| panic!("{event} in {events:?}"));
+        assert_eq!(events.iter().filter(|event| event.starts_with("received")).count(), 4, "{events:?}");
+        assert!(position("received 1000 duplicate false") < position("received 1000 duplicate true"));
+        assert!(position("retransmit 500") < position("received 500 duplicate false"));
+        assert_eq!(events.iter().filter(|event| event.starts_with("retransmit")).count(), 1, "{events:?}");
+        let slid = events
+            .iter()
+            .filter_map(|event| event.strip_prefix("slide "))
+            .map(|event| event.split(' ').next().unwrap().parse::<usize>().unwrap())
+            .sum::<usize>();
+        assert_eq!(slid, 3);
+        assert!(events[events.len() - 2].ends_with(&format!(" to {file_size}")), "{events:?}");
+        assert_eq!(events[events.len() - 1], format!("complete {file_size}"));
+        assert_eq!(std::fs::read(output).unwrap().len(), file_size);
+    }
+
+    #[test]
+    fn test_server_ends_the_download() {
+        let dir = TempDir::new("transport-abort").unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let SocketAddr::V4(address) = server.local_addr().unwrap() else { unreachable!() };
+        thread::spawn(move || serve(server, Segment::SIZE, usize::MAX, usize::MAX));
+        let output = dir.path().join("out");
+        let started = Instant::now();
+        let result = Downloader::new(address, output.to_str().unwrap(), 3 * Segment::SIZE).download();
+        let Err(abort) = result else { panic!("download should be aborted") };
+        assert_eq!(abort.code, AbortCode::SizeMismatch);
+        assert_eq!(abort.message, "file is 500 bytes long");
+        assert!(started.elapsed() < Downloader::TIMEOUT, "{:?}", started.elapsed());
     }
 }
@@ -0,0 +1,104 @@
+//! Mikołaj Depta 328690
+//!
+//! This module contains the writer that appends downloaded segments to the output file.
+
+use std::io;
+use std::io::Write;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use common::fs::{Fs, RealFs};
+
+pub struct FileWriter<F: Fs = RealFs> {
+    file: F::File,
+    bytes_written: usize,
+}
+
+impl FileWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::create_with_fs(&RealFs, path)
+    }
+}
+
+impl<F: Fs> FileWriter<F> {
+    /// Creates new file, fails if the file already exists so no previous download is overwritten.
+    pub fn create_with_fs(fs: &F, path: &Path) -> io::Result<Self> {
+        let file = fs.open(path, OpenOptions::new().write(true).create_new(true))?;
+        Ok(Self { file, bytes_written: 0 })
+    }
+
+    /// Appends all segments in order.
+    /// On error `bytes_written` reflects how much of the data actually reached the file.
+    pub fn write_segments<I, S>(&mut self, segments: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item=S>,
+        S: AsRef<[u8]>,
+    {
+        for segment in segments {
+            let mut data = segment.as_ref();
+            while !data.is_empty() {
+                match self.file.write(data) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(count) => {
+                        self.bytes_written += count;
+                        data = &data[count..];
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fs::{Fault, FaultyFs, Operation, TempDir, Trigger};
+
+    #[test]
+    fn test_write_segments() {
+        let dir = TempDir::new("transport-writer").unwrap();
+        let path = dir.path().join("output");
+        let mut writer = FileWriter::create(&path).unwrap();
+        writer.write_segments([b"abc".as_slice(), b"def"]).unwrap();
+        assert_eq!(writer.bytes_written(), 6);
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdef");
+        assert!(FileWriter::create(&path).is_err());
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let dir = TempDir::new("transport-writer").unwrap();
+        let fs = FaultyFs::new([Fault::disk_full().with_trigger(Trigger::ByteLimit(4))]);
+        let mut writer = FileWriter::create_with_fs(&fs, &dir.path().join("output")).unwrap();
+        let err = writer.write_segments([b"abc".as_slice(), b"def"]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(writer.bytes_written(), 4);
+    }
+
+    #[test]
+    fn test_interrupted_write_is_retried() {
+        let dir = TempDir::new("transport-writer").unwrap();
+        let fs = FaultyFs::new([
+            Fault::new(Operation::Write, io::ErrorKind::Interrupted).with_trigger(Trigger::Nth(2))
+        ]);
+        let mut writer = FileWriter::create_with_fs(&fs, &dir.path().join("output")).unwrap();
+        writer.write_segments([b"abc".as_slice(), b"def"]).unwrap();
+        assert_eq!(writer.bytes_written(), 6);
+        assert_eq!(fs.injected(), 1);
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let dir = TempDir::new("transport-writer").unwrap();
+        let fs = FaultyFs::new([Fault::permission_denied(Operation::Open)]);
+        let err = FileWriter::create_with_fs(&fs, &dir.path().join("output")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,126 @@
+//! Mikołaj Depta 328690
+//!
+//! This module exposes the link through which the downloader talks to the server.
+//!
+//! `UdpLink` is the real one. Tests replay scripted conversations through the same interface
+//! instead, see `trace`.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::prelude::*;
+use std::time::{Duration, Instant};
+
+use reactor::{EventType, Registry, TimeoutDuration};
+
+use crate::util;
+use crate::util::FailWithMessage;
+
+pub enum Notification {
+    Timeout,
+    /// Messages are waiting, after the given time was spent waiting for them.
+    ReadReady(Duration),
+}
+
+/// Sockets of the downloader together with the clock that times its rounds.
+pub trait Link {
+    fn socket_count(&self) -> usize;
+
+    /// Current time, requests are marked as sent at it.
+    fn now(&self) -> Instant;
+
+    /// Sends the `request` through the socket with index `socket`.
+    fn send(&mut self, socket: usize, request: &[u8]);
+
+    /// Waits at most `timeout` for messages on any of the sockets.
+    fn await_read_ready(&mut self, timeout: &Duration) -> Notification;
+
+    /// Reads the next message from the server that arrived through the socket with index `socket`,
+    /// `None` once there are no more.
+    fn receive(&mut self, socket: usize, buffer: &mut [u8]) -> Option<usize>;
+
+    /// Local address of the socket, if it has one.
+    fn local_addr(&self, socket: usize) -> Option<SocketAddr>;
+}
+
+/// UDP sockets with distinct source ports, all of them talk to the same server.
+pub struct UdpLink {
+    sockets: Vec<UdpSocket>,
+    registry: Registry,
+    server_address: SocketAddrV4,
+    /// Requests are sent in blocking mode, messages are drained in non-blocking one.
+    nonblocking: bool,
+}
+
+impl UdpLink {
+    pub fn bind(server_address: SocketAddrV4, socket_count: usize) -> Self {
+        let sockets = (0..socket_count.max(1))
+            .map(|_| UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).map_err(|err| {
+                util::fail_with_message(format!("could not bind the socket: {err}").as_ref());
+            }).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut registry = Registry::new().or_fail_with_message("could not create registry");
+        if registry.backend() != "epoll" {
+            eprintln!("epoll is not available, falling back to {}", registry.backend());
+        }
+
+        for socket in &sockets {
+            registry.add_interest(EventType::Read, socket.as_raw_fd()).map_err(|err|
+                util::fail_with_message(format!("could not register interest for {}", err).as_ref())
+            ).unwrap();
+        }
+
+        Self { sockets, registry, server_address, nonblocking: false }
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) {
+        if self.nonblocking == nonblocking {
+            return;
+        }
+        for socket in &self.sockets {
+            socket.set_nonblocking(nonblocking).or_fail_with_message("cannot change socket blocking mode");
+        }
+        self.nonblocking = nonblocking;
+    }
+}
+
+impl Link for UdpLink {
+    fn socket_count(&self) -> usize {
+        self.sockets.len()
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn send(&mut self, socket: usize, request: &[u8]) {
+        self.set_nonblocking(false);
+        self.sockets[socket].send_to(request, self.server_address)
+            .or_fail_with_message("cannot send to the server");
+    }
+
+    /* note: it is not checked which of the sockets is ready, all of them are drained afterwards. */
+    fn await_read_ready(&mut self, timeout: &Duration) -> Notification {
+        match self.registry.await_event(&TimeoutDuration::Finite(*timeout)) {
+            Ok(reactor::Notification::Timeout) => Notification::Timeout,
+            Ok(reactor::Notification::Event(_, sleep_time)) => Notification::ReadReady(sleep_time),
+            Err(err) => util::fail_with_message(format!("error during {} wait: {err}", self.registry.backend()).as_ref()),
+        }
+    }
+
+    fn receive(&mut self, socket: usize, buffer: &mut [u8]) -> Option<usize> {
+        self.set_nonblocking(true);
+        loop {
+            match self.sockets[socket].recv_from(buffer) {
+                Ok((message_size, SocketAddr::V4(sender))) if sender == self.server_address => return Some(message_size),
+                Ok(_) => continue,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(err) => util::fail_with_message(format!("error occurred while reading from the socket. {}", err).as_ref())
+            }
+        }
+    }
+
+    fn local_addr(&self, socket: usize) -> Option<SocketAddr> {
+        self.sockets[socket].local_addr().ok()
+    }
+}
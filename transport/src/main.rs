@@ -1,15 +1,24 @@
 //! Mikołaj Depta 328690
 #![allow(dead_code)]
 
-mod registry;
 mod segment;
 mod util;
 mod messages;
 mod window;
 mod downloader;
+mod file_writer;
+mod verify;
+mod ui;
+mod observer;
+mod link;
+#[cfg(test)]
+mod simulation;
+#[cfg(test)]
+mod trace;
 
-use libc;
 use std::env;
+use std::path::Path;
+use std::process;
 use downloader::Downloader;
 use crate::downloader::DownloaderConfig;
 
@@ -19,6 +28,20 @@ use crate::downloader::DownloaderConfig;
 
 fn main() {
     let config = DownloaderConfig::try_from(env::args());
+    let reference = config.verify_against.clone();
+    let file_name = config.file_name.clone();
     let mut downloader = Downloader::from(config);
-    downloader.download()
+    if let Err(abort) = downloader.download() {
+        util::fail_with_message(format!("server ended the download: {}", abort.reason()).as_ref());
+    }
+
+    if let Some(reference) = reference {
+        let comparison = verify::compare_files(Path::new(&file_name), &reference).unwrap_or_else(|err| {
+            util::fail_with_message(format!("could not compare with {}: {err}", reference.display()).as_ref())
+        });
+        println!("{comparison}");
+        if !comparison.is_identical() {
+            process::exit(1);
+        }
+    }
 }
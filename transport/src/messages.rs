@@ -1,7 +1,8 @@
 //! Mikołaj Depta 328690
 //!
 //! This module defines out custom download communication protocol.
-//! It exposes two message types: Response, and Request.
+//! It exposes three message types: Response, Request and Abort, which the server
+//! sends instead of a Response when the download cannot go on.
 
 use std::ops::{Range, RangeInclusive};
 use std::fmt::{Debug, Display, Formatter};
@@ -57,7 +58,7 @@ impl<'message> Response<'message> {
     pub fn new(message_bytes: &'message [u8]) -> Self {
         let newline_index = message_bytes
             .iter()
-            .position(|&byte| byte == '\n' as u8)
+            .position(|&byte| byte == b'\n')
             .or_fail_with_message(r#"invalid response format, no Line Feed '\n') found"#);
         let (header_bytes, other ) = message_bytes.split_at(newline_index);
         let header= str::from_utf8(header_bytes).or_fail_with_message("invalid response format, header is not valid utf");
@@ -123,6 +124,105 @@ impl<'range> Request<'range> {
 
 impl Display for Request<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "GET {} {}\n", self.byte_range.start, self.byte_range.len())
+        writeln!(f, "GET {} {}", self.byte_range.start, self.byte_range.len())
+    }
+}
+
+
+/// Why the server ended the download, codes follow their HTTP counterparts.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AbortCode {
+    NotFound,
+    /// Requested bytes lie past the end of the file, so the client expects a different file length.
+    SizeMismatch,
+    Other(u16),
+}
+
+impl AbortCode {
+    const NOT_FOUND: u16 = 404;
+    const SIZE_MISMATCH: u16 = 416;
+
+    pub fn code(&self) -> u16 {
+        match self {
+            AbortCode::NotFound => Self::NOT_FOUND,
+            AbortCode::SizeMismatch => Self::SIZE_MISMATCH,
+            AbortCode::Other(code) => *code,
+        }
+    }
+}
+
+impl From<u16> for AbortCode {
+    fn from(code: u16) -> Self {
+        match code {
+            Self::NOT_FOUND => AbortCode::NotFound,
+            Self::SIZE_MISMATCH => AbortCode::SizeMismatch,
+            code => AbortCode::Other(code),
+        }
+    }
+}
+
+/// Server-initiated early termination of the download.
+///
+/// Message format: `ERR <code> <message>\n`, message may be empty.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Abort {
+    pub code: AbortCode,
+    pub message: String,
+}
+
+impl Abort {
+    const KEYWORD: &'static str = "ERR";
+
+    pub fn new(code: AbortCode, message: &str) -> Self {
+        Self { code, message: message.to_owned() }
+    }
+
+    /// `None` if `message_bytes` is not an abort message, eg. it is a `Response`.
+    pub fn parse(message_bytes: &[u8]) -> Option<Self> {
+        let line = message_bytes.split(|&byte| byte == b'\n').next()?;
+        let line = str::from_utf8(line).ok()?;
+        let (keyword, rest) = line.split_once(' ')?;
+        if keyword != Self::KEYWORD {
+            return None;
+        }
+        let (code, message) = rest.split_once(' ').unwrap_or((rest, ""));
+        let code = code.parse::<u16>().ok()?;
+        Some(Self::new(AbortCode::from(code), message.trim()))
+    }
+
+    /// Human readable description of the reason, eg. for the error message of the client.
+    pub fn reason(&self) -> String {
+        let description = match self.code {
+            AbortCode::NotFound => "file not found",
+            AbortCode::SizeMismatch => "file size mismatch",
+            AbortCode::Other(_) => "download aborted",
+        };
+        match self.message.as_str() {
+            "" => format!("{} ({})", description, self.code.code()),
+            message => format!("{} ({}): {}", description, self.code.code(), message),
+        }
+    }
+}
+
+impl Display for Abort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} {} {}", Self::KEYWORD, self.code.code(), self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abort_round_trip() {
+        let abort = Abort::new(AbortCode::SizeMismatch, "file is 1000 bytes long");
+        assert_eq!(abort.to_string(), "ERR 416 file is 1000 bytes long\n");
+        assert_eq!(Abort::parse(abort.to_string().as_bytes()), Some(abort.clone()));
+        assert_eq!(abort.reason(), "file size mismatch (416): file is 1000 bytes long");
+        assert_eq!(Abort::parse(b"ERR 404\n"), Some(Abort::new(AbortCode::NotFound, "")));
+        assert_eq!(Abort::parse(b"ERR 503 busy\n").map(|abort| abort.code), Some(AbortCode::Other(503)));
+        assert_eq!(Abort::parse(b"DATA 0 3\nERR"), None);
+        assert_eq!(Abort::parse(b"ERR code\n"), None);
     }
 }
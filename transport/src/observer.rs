@@ -0,0 +1,41 @@
+//! Mikołaj Depta 328690
+//!
+//! This module exposes the hooks that let frontends follow the download.
+//!
+//! Downloader calls every subscribed observer at the points of interest of the download,
+//! frontends such as the terminal UI implement only the callbacks they need.
+
+use crate::downloader::SocketStatistics;
+use crate::messages::{Abort, ByteRange};
+use crate::window::Window;
+
+/// State of the download at the moment of the callback.
+pub struct Progress<'a> {
+    pub window: &'a Window,
+    pub bytes_written: usize,
+    pub file_size: usize,
+    pub statistics: &'a [SocketStatistics],
+}
+
+/// Callbacks invoked by the `Downloader`, all of them do nothing by default.
+#[allow(unused_variables)]
+pub trait Observer {
+    /// Round number `round`, counted from 0, begins - due segments are about to be requested.
+    fn on_round_start(&mut self, round: usize, progress: &Progress) { }
+
+    /// Segment arrived through the socket with index `socket`. It is a `duplicate` if it was
+    /// received before or lies outside of the window, its data is dropped then.
+    fn on_segment_received(&mut self, byte_range: &ByteRange, socket: usize, duplicate: bool) { }
+
+    /// Segment was requested again through the socket with index `socket`, since its answer did not arrive in time.
+    fn on_retransmit(&mut self, byte_range: &ByteRange, socket: usize) { }
+
+    /// Window slid past `segments` received segments, their data was written to the file.
+    fn on_slide(&mut self, segments: usize, progress: &Progress) { }
+
+    /// Whole file was written.
+    fn on_complete(&mut self, progress: &Progress) { }
+
+    /// Server ended the download early with `abort`, the file is incomplete.
+    fn on_abort(&mut self, abort: &Abort, progress: &Progress) { }
+}
@@ -5,19 +5,18 @@
 #![allow(dead_code)]
 
 use std::io::{Write};
+use std::time::{Duration, Instant};
 use crate::messages::{ByteRange, Request, Response};
 
 
-#[derive(Debug, Eq, PartialEq, Clone)]
-enum Status {
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub enum Status {
+    /// Segment was not requested yet.
+    #[default]
+    Pending,
+    /// Segment was requested at least once but its data did not arrive.
+    Sent,
     Received,
-    NotReceived,
-}
-
-impl Default for Status {
-    fn default() -> Self {
-        Self::NotReceived
-    }
 }
 
 
@@ -25,6 +24,8 @@ impl Default for Status {
 pub struct Segment {
     byte_range: ByteRange,
     status: Status,
+    /// When the segment was last requested.
+    sent_at: Option<Instant>,
     data: Vec<u8>,
 }
 
@@ -37,7 +38,7 @@ impl Segment {
 
     pub fn with_buffer(byte_range: ByteRange, mut data: Vec<u8>) -> Self {
         data.clear();
-        Self { byte_range, status: Default::default(), data }
+        Self { byte_range, status: Default::default(), sent_at: None, data }
     }
 
     pub fn set_data(&mut self, data: &[u8]) {
@@ -50,6 +51,31 @@ impl Segment {
         self.status == Status::Received
     }
 
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn mark_sent(&mut self, now: Instant) {
+        if self.status == Status::Pending {
+            self.status = Status::Sent;
+        }
+        self.sent_at = Some(now);
+    }
+
+    pub fn sent_at(&self) -> Option<Instant> {
+        self.sent_at
+    }
+
+    /// Segment should be requested, either for the first time or because the last request
+    /// got no answer within `retransmission_timeout`.
+    pub fn is_due(&self, now: Instant, retransmission_timeout: Duration) -> bool {
+        match (self.status, self.sent_at) {
+            (Status::Received, _) => false,
+            (_, None) => true,
+            (_, Some(sent_at)) => now.saturating_duration_since(sent_at) >= retransmission_timeout,
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.data.len()
     }
@@ -63,7 +89,7 @@ impl Segment {
         &self.byte_range
     }
 
-    pub fn request(&self) -> Request {
+    pub fn request(&self) -> Request<'_> {
         Request::new(&self.byte_range)
     }
 }
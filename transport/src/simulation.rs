@@ -0,0 +1,144 @@
+//! Mikołaj Depta 328690
+//!
+//! Simulation of a download from a slow, lossy server, used to compare request scheduling policies.
+//!
+//! Time advances in ticks. Every tick the client sends the requests chosen by the policy,
+//! the server takes them into a bounded queue, answers a fixed number of queued requests
+//! and drops a fraction of the answers. Answers reach the client on the next tick.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::downloader::SegmentByteRangeIter;
+use crate::messages::ByteRange;
+use crate::segment::Segment;
+use crate::window::Window;
+
+/// Picks requests to send at `now` and marks them as sent.
+pub type Policy = fn(&mut Window, Instant) -> Vec<ByteRange>;
+
+pub const TICK: Duration = Duration::from_millis(10);
+pub const RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Every unacknowledged segment is requested every tick, front to back.
+pub fn resend_unacknowledged(window: &mut Window, now: Instant) -> Vec<ByteRange> {
+    window.unacknowledged_segments()
+        .map(|segment| {
+            segment.mark_sent(now);
+            segment.byte_range().clone()
+        })
+        .collect()
+}
+
+/// Only due segments are requested, oldest retransmissions first, see `Window::due_segments`.
+pub fn resend_due(window: &mut Window, now: Instant) -> Vec<ByteRange> {
+    window.due_segments(now, RETRANSMISSION_TIMEOUT)
+        .into_iter()
+        .map(|segment| {
+            segment.mark_sent(now);
+            segment.byte_range().clone()
+        })
+        .collect()
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Conditions {
+    pub segments: usize,
+    /// Requests the server answers per tick.
+    pub capacity: usize,
+    /// Requests the server can hold, requests arriving at a full queue are dropped.
+    pub queue_size: usize,
+    /// Out of 100 answers, how many are lost.
+    pub loss_percent: u64,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Outcome {
+    pub ticks: usize,
+    pub requests: usize,
+    pub duplicates: usize,
+}
+
+/// Linear congruential generator, so every run with the same seed loses the same answers.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
+
+/// Downloads the whole file under `conditions`, gives up after `max_ticks`.
+pub fn run(policy: Policy, conditions: Conditions, seed: u64, max_ticks: usize) -> Option<Outcome> {
+    let file_size = conditions.segments * Segment::SIZE;
+    let mut byte_ranges = SegmentByteRangeIter::new(file_size, Segment::SIZE);
+    let mut window = Window::new(&mut byte_ranges);
+    let mut random = Lcg(seed);
+    let mut queue = VecDeque::<ByteRange>::with_capacity(conditions.queue_size);
+    let mut in_flight = Vec::new();
+    let mut outcome = Outcome { ticks: 0, requests: 0, duplicates: 0 };
+    let mut received = 0;
+    let start = Instant::now();
+    let data = [0; Segment::SIZE];
+
+    while received < conditions.segments {
+        if outcome.ticks == max_ticks {
+            return None;
+        }
+        let now = start + TICK * outcome.ticks as u32;
+        for byte_range in in_flight.drain(..) {
+            if !window.contains(&byte_range) || window[&byte_range].is_received() {
+                outcome.duplicates += 1;
+                continue;
+            }
+            window[&byte_range].write_all(&data[..byte_range.len()]).unwrap();
+            received += 1;
+        }
+        let requests = policy(&mut window, now);
+        outcome.requests += requests.len();
+        for byte_range in requests {
+            if queue.len() < conditions.queue_size {
+                queue.push_back(byte_range);
+            }
+        }
+        for byte_range in queue.drain(..conditions.capacity.min(queue.len())) {
+            if random.next() % 100 >= conditions.loss_percent {
+                in_flight.push(byte_range);
+            }
+        }
+        window.shrink();
+        window.extend(&mut byte_ranges);
+        outcome.ticks += 1;
+    }
+    Some(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONDITIONS: Conditions = Conditions { segments: 3000, capacity: 50, queue_size: 500, loss_percent: 10 };
+
+    #[test]
+    fn test_lossless_download_completes() {
+        let conditions = Conditions { loss_percent: 0, ..CONDITIONS };
+        let outcome = run(resend_due, conditions, 1, 10_000).unwrap();
+        assert_eq!(outcome.duplicates, 0);
+        /* server answers at most `capacity` requests per tick */
+        assert!(outcome.ticks > conditions.segments / conditions.capacity, "{outcome:?}");
+        assert!(outcome.ticks < 2 * conditions.segments / conditions.capacity, "{outcome:?}");
+    }
+
+    #[test]
+    fn test_resending_due_segments_finishes_sooner_under_loss() {
+        for seed in 1..=3 {
+            let all = run(resend_unacknowledged, CONDITIONS, seed, 100_000).unwrap();
+            let due = run(resend_due, CONDITIONS, seed, 100_000).unwrap();
+            println!("seed {seed}: resending everything {all:?}, resending due segments {due:?}");
+            assert!(due.ticks < all.ticks, "{due:?} vs {all:?}");
+            assert!(due.requests < all.requests, "{due:?} vs {all:?}");
+        }
+    }
+}
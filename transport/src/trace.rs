@@ -0,0 +1,245 @@
+//! Mikołaj Depta 328690
+//!
+//! Lock-step replay of a recorded conversation with the server, used for regression tests of
+//! the window and retransmission behaviour.
+//!
+//! Trace is a text file with one step per line, `#` starts a comment:
+//!
+//! ```text
+//! size 1200
+//! send GET 0 500
+//! recv DATA 0 500
+//! delay 250ms
+//! recv ERR 416 file is 500 bytes long
+//! ```
+//!
+//! `size` is the file length the downloader is started with. `send` is the request the downloader
+//! has to send next, `recv` is a message it receives, data of `DATA` messages is filled in.
+//! `delay` lets time pass before the next step. Time is virtual, the downloader runs through the
+//! trace without sockets and without sleeping, and replay panics at the first request that
+//! differs from the trace.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use common::units;
+
+use crate::downloader::Downloader;
+use crate::link::{Link, Notification};
+use crate::messages::Abort;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Step {
+    Send(String),
+    Receive(Vec<u8>),
+    Delay(Duration),
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Send(request) => write!(f, "send {request}"),
+            Step::Receive(message) => {
+                let header = message.split(|&byte| byte == b'\n').next().unwrap_or_default();
+                write!(f, "recv {}", String::from_utf8_lossy(header))
+            }
+            Step::Delay(delay) => write!(f, "delay {delay:?}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Line {
+    number: usize,
+    step: Step,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    line: usize,
+    message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    pub size: usize,
+    steps: VecDeque<Line>,
+}
+
+impl Trace {
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut size = None;
+        let mut steps = VecDeque::new();
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let error = |message: String| ParseError { line: number, message };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (keyword, argument) = line.split_once(' ').unwrap_or((line, ""));
+            let argument = argument.trim();
+            let step = match keyword {
+                "size" => {
+                    size = Some(units::parse_size(argument).map_err(|err| error(err.to_string()))?);
+                    continue;
+                }
+                "send" => Step::Send(argument.to_owned()),
+                "recv" => Step::Receive(Self::message(argument).ok_or_else(|| error(format!("invalid message {argument}")))?),
+                "delay" => Step::Delay(units::parse_duration(argument).map_err(|err| error(err.to_string()))?),
+                other => return Err(error(format!("unknown step {other}"))),
+            };
+            steps.push_back(Line { number, step });
+        }
+        let size = size.ok_or(ParseError { line: 0, message: "size of the file missing".to_owned() })?;
+        Ok(Self { size, steps })
+    }
+
+    /// Message as sent by the server, `DATA` messages get as many bytes of data as they announce.
+    fn message(header: &str) -> Option<Vec<u8>> {
+        let mut message = format!("{header}\n").into_bytes();
+        if let Some(range) = header.strip_prefix("DATA ") {
+            let (_, length) = range.split_once(' ')?;
+            message.extend(std::iter::repeat_n(b'x', length.parse().ok()?));
+        }
+        Some(message)
+    }
+
+    /// Runs the downloader, writing to `file_name`, through the whole trace.
+    ///
+    /// Panics if the downloader sends a request the trace does not expect at that point,
+    /// or finishes before the trace does.
+    pub fn replay(self, file_name: &str) -> Result<(), Abort> {
+        let steps = Rc::new(RefCell::new(self.steps));
+        let link = TraceLink { steps: steps.clone(), clock: Instant::now() };
+        let result = Downloader::with_link(link, file_name, self.size).download();
+        if let Some(line) = steps.borrow().front() {
+            panic!("download ended before the trace, line {}: {}", line.number, line.step);
+        }
+        result
+    }
+}
+
+/// Single socket link that checks requests against the trace and delivers its messages.
+struct TraceLink {
+    steps: Rc<RefCell<VecDeque<Line>>>,
+    clock: Instant,
+}
+
+impl Link for TraceLink {
+    fn socket_count(&self) -> usize {
+        1
+    }
+
+    fn now(&self) -> Instant {
+        self.clock
+    }
+
+    fn send(&mut self, _: usize, request: &[u8]) {
+        let request = String::from_utf8_lossy(request);
+        let request = request.trim_end();
+        match self.steps.borrow_mut().pop_front() {
+            Some(Line { step: Step::Send(expected), .. }) if expected == request => {}
+            Some(line) => panic!("line {}: expected {}, downloader sent {request}", line.number, line.step),
+            None => panic!("trace ended, downloader sent {request}"),
+        }
+    }
+
+    fn await_read_ready(&mut self, timeout: &Duration) -> Notification {
+        let mut steps = self.steps.borrow_mut();
+        let mut waited = Duration::ZERO;
+        loop {
+            let left = *timeout - waited;
+            match steps.front_mut().map(|line| &mut line.step) {
+                Some(Step::Receive(_)) => return Notification::ReadReady(waited),
+                Some(Step::Delay(delay)) if *delay < left => {
+                    waited += *delay;
+                    self.clock += *delay;
+                    steps.pop_front();
+                }
+                Some(Step::Delay(delay)) => {
+                    *delay -= left;
+                    if delay.is_zero() {
+                        steps.pop_front();
+                    }
+                    self.clock += left;
+                    return Notification::Timeout;
+                }
+                /* nothing arrives before the downloader sends again */
+                Some(Step::Send(_)) | None => {
+                    self.clock += left;
+                    return Notification::Timeout;
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, _: usize, buffer: &mut [u8]) -> Option<usize> {
+        let mut steps = self.steps.borrow_mut();
+        let Some(Line { step: Step::Receive(message), .. }) = steps.front() else { return None };
+        buffer[..message.len()].copy_from_slice(message);
+        let length = message.len();
+        steps.pop_front();
+        Some(length)
+    }
+
+    fn local_addr(&self, _: usize) -> Option<SocketAddr> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::AbortCode;
+    use common::fs::TempDir;
+    use std::path::Path;
+
+    fn load(name: &str) -> Trace {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("traces").join(name);
+        let text = std::fs::read_to_string(&path).unwrap();
+        Trace::parse(&text).unwrap_or_else(|err| panic!("{}: {err}", path.display()))
+    }
+
+    #[test]
+    fn test_lost_segment_is_retransmitted_once_due() {
+        let dir = TempDir::new("transport-trace").unwrap();
+        let output = dir.path().join("out");
+        let trace = load("retransmission.trace");
+        let size = trace.size;
+        trace.replay(output.to_str().unwrap()).unwrap();
+        assert_eq!(std::fs::read(output).unwrap(), vec![b'x'; size]);
+    }
+
+    #[test]
+    fn test_abort_ends_the_replay() {
+        let dir = TempDir::new("transport-trace").unwrap();
+        let abort = load("abort.trace").replay(dir.path().join("out").to_str().unwrap()).unwrap_err();
+        assert_eq!(abort.code, AbortCode::SizeMismatch);
+    }
+
+    #[test]
+    #[should_panic(expected = "line 2: expected send GET 500 500, downloader sent GET 0 500")]
+    fn test_unexpected_request_fails_the_replay() {
+        let dir = TempDir::new("transport-trace").unwrap();
+        let trace = Trace::parse("size 1000\nsend GET 500 500\nsend GET 0 500\n");
+        let _ = trace.unwrap().replay(dir.path().join("out").to_str().unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Trace::parse("send GET 0 500").unwrap_err().to_string(), "line 0: size of the file missing");
+        assert_eq!(Trace::parse("size 10\n\nwait 1s").unwrap_err().to_string(), "line 3: unknown step wait");
+        assert!(Trace::parse("size 10\nrecv DATA 0 ten").is_err());
+    }
+}
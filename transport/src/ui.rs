@@ -0,0 +1,210 @@
+//! Mikołaj Depta 328690
+//!
+//! This module renders live progress of the download in the terminal.
+//!
+//! Every frame shows the state of each segment in the window, progress of the whole file,
+//! throughput history and socket counters. Frame is redrawn in place with ANSI escape codes.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::downloader::SocketStatistics;
+use crate::messages::Abort;
+use crate::observer::{Observer, Progress};
+use crate::segment::Status;
+
+const RESET: &str = "\x1b[0m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const GREY: &str = "\x1b[90m";
+const BOLD: &str = "\x1b[1m";
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Everything that is shown in a single frame.
+pub struct Frame<'a> {
+    pub segments: &'a [Status],
+    pub bytes_written: usize,
+    pub file_size: usize,
+    /// Bytes per second, oldest sample first.
+    pub throughput: &'a [f64],
+    pub statistics: &'a [SocketStatistics],
+}
+
+impl Frame<'_> {
+    const GRID_WIDTH: usize = 50;
+    const BAR_WIDTH: usize = 50;
+
+    pub fn render(&self) -> String {
+        let mut frame = String::new();
+        let percent = if self.file_size == 0 { 100.0 } else { 100.0 * self.bytes_written as f64 / self.file_size as f64 };
+        let filled = Self::BAR_WIDTH * self.bytes_written / self.file_size.max(1);
+        writeln!(
+            frame, "{BOLD}file{RESET}   [{GREEN}{}{GREY}{}{RESET}] {:5.1}% {} / {} B",
+            "#".repeat(filled), "-".repeat(Self::BAR_WIDTH - filled), percent, self.bytes_written, self.file_size
+        ).unwrap();
+
+        writeln!(
+            frame, "{BOLD}window{RESET} {GREEN}█{RESET} received  {YELLOW}▒{RESET} sent  {GREY}·{RESET} pending"
+        ).unwrap();
+        for row in self.segments.chunks(Self::GRID_WIDTH) {
+            frame.push_str("       ");
+            for status in row {
+                let cell = match status {
+                    Status::Received => format!("{GREEN}█"),
+                    Status::Sent => format!("{YELLOW}▒"),
+                    Status::Pending => format!("{GREY}·"),
+                };
+                frame.push_str(&cell);
+            }
+            writeln!(frame, "{RESET}").unwrap();
+        }
+
+        let current = self.throughput.last().copied().unwrap_or_default();
+        writeln!(frame, "{BOLD}rate{RESET}   {} {:.1} KiB/s", sparkline(self.throughput), current / 1024.0).unwrap();
+
+        let total = self.statistics.iter().fold(SocketStatistics::default(), |mut total, statistics| {
+            total.requests_sent += statistics.requests_sent;
+            total.responses_received += statistics.responses_received;
+            total.duplicates += statistics.duplicates;
+            total.bytes_received += statistics.bytes_received;
+            total
+        });
+        writeln!(frame, "{BOLD}total{RESET}  {}", total).unwrap();
+        frame
+    }
+}
+
+/// Samples scaled to the largest one.
+fn sparkline(samples: &[f64]) -> String {
+    let max = samples.iter().copied().fold(0.0, f64::max);
+    samples
+        .iter()
+        .map(|sample| {
+            if max <= 0.0 {
+                SPARKS[0]
+            } else {
+                SPARKS[((sample / max) * (SPARKS.len() - 1) as f64).round() as usize]
+            }
+        })
+        .collect()
+}
+
+/// Redraws frames in place, at most once per `REFRESH_INTERVAL`.
+pub struct TerminalUi<W: Write> {
+    out: W,
+    throughput: VecDeque<f64>,
+    last_render: Option<(Instant, usize)>,
+    rendered_lines: usize,
+    /// Rendering failed once, nothing is rendered anymore.
+    is_disabled: bool,
+}
+
+impl TerminalUi<io::Stderr> {
+    pub fn new() -> Self {
+        Self::with_writer(io::stderr())
+    }
+}
+
+impl<W: Write> TerminalUi<W> {
+    const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+    const THROUGHPUT_SAMPLES: usize = 50;
+
+    pub fn with_writer(out: W) -> Self {
+        Self {
+            out,
+            throughput: VecDeque::with_capacity(Self::THROUGHPUT_SAMPLES),
+            last_render: None,
+            rendered_lines: 0,
+            is_disabled: false,
+        }
+    }
+
+    /// Renders the frame unless the previous one was rendered very recently, `force` skips that check.
+    pub fn update(&mut self, progress: &Progress, force: bool) -> io::Result<()> {
+        let now = Instant::now();
+        let bytes_received = progress.statistics.iter().map(|statistics| statistics.bytes_received).sum::<usize>();
+        match self.last_render {
+            Some((last, _)) if !force && now - last < Self::REFRESH_INTERVAL => return Ok(()),
+            Some((last, last_bytes)) => {
+                let elapsed = (now - last).as_secs_f64().max(f64::EPSILON);
+                if self.throughput.len() == Self::THROUGHPUT_SAMPLES {
+                    self.throughput.pop_front();
+                }
+                self.throughput.push_back((bytes_received - last_bytes) as f64 / elapsed);
+            }
+            None => {}
+        }
+        self.last_render = Some((now, bytes_received));
+
+        let segments = progress.window.segments().map(|segment| segment.status()).collect::<Vec<_>>();
+        let frame = Frame {
+            segments: &segments,
+            bytes_written: progress.bytes_written,
+            file_size: progress.file_size,
+            throughput: self.throughput.make_contiguous(),
+            statistics: progress.statistics,
+        }.render();
+        if self.rendered_lines > 0 {
+            /* move to the beginning of the previous frame and clear it */
+            write!(self.out, "\x1b[{}A\x1b[J", self.rendered_lines)?;
+        }
+        self.out.write_all(frame.as_bytes())?;
+        self.out.flush()?;
+        self.rendered_lines = frame.lines().count();
+        Ok(())
+    }
+
+    fn render(&mut self, progress: &Progress, force: bool) {
+        if self.is_disabled {
+            return;
+        }
+        if let Err(err) = self.update(progress, force) {
+            eprintln!("could not render the progress: {err}");
+            self.is_disabled = true;
+        }
+    }
+}
+
+impl<W: Write> Observer for TerminalUi<W> {
+    fn on_round_start(&mut self, _: usize, progress: &Progress) {
+        self.render(progress, false);
+    }
+
+    fn on_complete(&mut self, progress: &Progress) {
+        self.render(progress, true);
+    }
+
+    fn on_abort(&mut self, _: &Abort, progress: &Progress) {
+        self.render(progress, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0.0, 50.0, 100.0]), "▁▅█");
+        assert_eq!(sparkline(&[0.0, 0.0]), "▁▁");
+        assert_eq!(sparkline(&[]), "");
+    }
+
+    #[test]
+    fn test_frame() {
+        let segments = [vec![Status::Received; 60], vec![Status::Sent; 30], vec![Status::Pending; 10]].concat();
+        let statistics = [SocketStatistics { requests_sent: 5, ..Default::default() }; 2];
+        let frame = Frame { segments: &segments, bytes_written: 250, file_size: 1000, throughput: &[2048.0], statistics: &statistics };
+        let rendered = frame.render();
+        /* progress bar, legend, two rows of the grid, rate and counters */
+        assert_eq!(rendered.lines().count(), 6);
+        assert!(rendered.contains(" 25.0% 250 / 1000 B"));
+        assert_eq!(rendered.matches('█').count(), 60 + 1 + 1);
+        assert_eq!(rendered.matches('▒').count(), 30 + 1);
+        assert!(rendered.contains("2.0 KiB/s"));
+        assert!(rendered.contains("requests sent: 10"));
+    }
+}
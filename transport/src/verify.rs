@@ -0,0 +1,152 @@
+//! Mikołaj Depta 328690
+//!
+//! This module compares downloaded file with a reference copy.
+
+use std::cmp;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+/// Result of byte-by-byte comparison of two files.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Comparison {
+    actual_len: u64,
+    expected_len: u64,
+    /// Maximal ranges of offsets at which the files differ.
+    /// Bytes present in only one of the files are treated as different.
+    mismatched_ranges: Vec<Range<u64>>,
+}
+
+impl Comparison {
+    pub fn is_identical(&self) -> bool {
+        self.mismatched_ranges.is_empty()
+    }
+
+    pub fn first_difference(&self) -> Option<u64> {
+        self.mismatched_ranges.first().map(|range| range.start)
+    }
+
+    pub fn mismatched_ranges(&self) -> &[Range<u64>] {
+        &self.mismatched_ranges
+    }
+
+    pub fn mismatched_bytes(&self) -> u64 {
+        self.mismatched_ranges.iter().map(|range| range.end - range.start).sum()
+    }
+
+    fn mark_mismatch(&mut self, offset: u64, len: u64) {
+        match self.mismatched_ranges.last_mut() {
+            Some(last) if last.end == offset => last.end += len,
+            _ => self.mismatched_ranges.push(offset..offset + len),
+        }
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_identical() {
+            return write!(f, "files are identical ({} bytes)", self.actual_len);
+        }
+        writeln!(
+            f,
+            "files differ: {} of {} bytes mismatched in {} ranges, first difference at offset {}",
+            self.mismatched_bytes(),
+            cmp::max(self.actual_len, self.expected_len),
+            self.mismatched_ranges.len(),
+            self.first_difference().unwrap_or_default(),
+        )?;
+        if self.actual_len != self.expected_len {
+            writeln!(f, "length mismatch: downloaded {} bytes, expected {}", self.actual_len, self.expected_len)?;
+        }
+        for range in &self.mismatched_ranges {
+            writeln!(f, "  {}..{}", range.start, range.end)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads until `buffer` is full or the end of the stream is reached.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Compares both streams chunk by chunk, neither of them is loaded into memory as a whole.
+pub fn compare(mut actual: impl Read, mut expected: impl Read) -> io::Result<Comparison> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let mut comparison = Comparison::default();
+    let mut actual_buffer = vec![0; CHUNK_SIZE];
+    let mut expected_buffer = vec![0; CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let actual_len = read_full(&mut actual, &mut actual_buffer)?;
+        let expected_len = read_full(&mut expected, &mut expected_buffer)?;
+        if actual_len == 0 && expected_len == 0 {
+            break;
+        }
+        let common_len = cmp::min(actual_len, expected_len);
+        for (index, (lhs, rhs)) in actual_buffer[..common_len].iter().zip(&expected_buffer[..common_len]).enumerate() {
+            if lhs != rhs {
+                comparison.mark_mismatch(offset + index as u64, 1);
+            }
+        }
+        let longer_len = cmp::max(actual_len, expected_len);
+        if longer_len > common_len {
+            comparison.mark_mismatch(offset + common_len as u64, (longer_len - common_len) as u64);
+        }
+        comparison.actual_len += actual_len as u64;
+        comparison.expected_len += expected_len as u64;
+        offset += longer_len as u64;
+    }
+    Ok(comparison)
+}
+
+pub fn compare_files(actual: &Path, expected: &Path) -> io::Result<Comparison> {
+    compare(File::open(actual)?, File::open(expected)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical() {
+        let data = vec![7; 200_000];
+        let comparison = compare(data.as_slice(), data.as_slice()).unwrap();
+        assert!(comparison.is_identical());
+        assert_eq!(comparison.first_difference(), None);
+    }
+
+    #[test]
+    fn test_mismatched_ranges_are_merged() {
+        let expected = vec![0u8; 100_000];
+        let mut actual = expected.clone();
+        actual[10..20].fill(1);
+        /* range spanning the chunk boundary */
+        actual[65_530..65_540].fill(1);
+        actual[99_999] = 1;
+        let comparison = compare(actual.as_slice(), expected.as_slice()).unwrap();
+        assert_eq!(comparison.mismatched_ranges(), &[10..20, 65_530..65_540, 99_999..100_000]);
+        assert_eq!(comparison.first_difference(), Some(10));
+        assert_eq!(comparison.mismatched_bytes(), 21);
+    }
+
+    #[test]
+    fn test_length_mismatch() {
+        let comparison = compare(&b"abcd"[..], &b"abXdef"[..]).unwrap();
+        assert_eq!(comparison.mismatched_ranges(), &[2..3, 4..6]);
+        assert!(comparison.to_string().contains("downloaded 4 bytes, expected 6"));
+    }
+}
@@ -7,9 +7,10 @@
 
 use std::collections::VecDeque;
 use std::ops::{Index, IndexMut, Range};
+use std::time::{Duration, Instant};
 use crate::messages::ByteRange;
 
-use crate::segment::Segment;
+use crate::segment::{Segment, Status};
 
 
 #[derive(Debug)]
@@ -33,9 +34,13 @@ impl Window {
         self.queue.iter().take_while(|segment| segment.is_received()).count()
     }
 
+    /// Slides the window past the received segments at its front and returns them.
     pub fn shrink(&mut self) -> &[Segment] {
-        self.received_buffer.extend(self.queue.drain(0..self.slide_len()));
-        self.read_seg_count += self.received_buffer.len();
+        /* segments left over once the file has no more of them were returned by the previous call */
+        self.received_buffer.clear();
+        let slide_len = self.slide_len();
+        self.received_buffer.extend(self.queue.drain(0..slide_len));
+        self.read_seg_count += slide_len;
         self.received_buffer.as_ref()
     }
 
@@ -61,16 +66,92 @@ impl Window {
             other.start < (self.read_seg_count + self.queue.len()) * Segment::SIZE
     }
 
+    pub fn segments(&self) -> impl Iterator<Item=&Segment> {
+        self.queue.iter()
+    }
+
     pub fn unacknowledged_segments(&mut self) -> impl Iterator<Item=&mut Segment> {
         self.queue.iter_mut().filter(|segment| !segment.is_received())
     }
+
+    /// Segments that should be requested at `now`, most urgent first.
+    ///
+    /// Retransmissions go first, the one waiting the longest first, since the oldest missing
+    /// segments are the ones keeping the window from sliding. Segments that were never
+    /// requested follow in file order. Segments requested recently are left alone.
+    pub fn due_segments(&mut self, now: Instant, retransmission_timeout: Duration) -> Vec<&mut Segment> {
+        let mut due = self.queue
+            .iter_mut()
+            .filter(|segment| segment.is_due(now, retransmission_timeout))
+            .collect::<Vec<_>>();
+        /* sort is stable, segments requested at the same time stay in file order */
+        due.sort_by_key(|segment| (segment.sent_at().is_none(), segment.sent_at()));
+        due
+    }
+
+    /// Earliest moment one of the requested segments becomes due again.
+    pub fn next_retransmission(&self, retransmission_timeout: Duration) -> Option<Instant> {
+        self.queue
+            .iter()
+            .filter(|segment| segment.status() == Status::Sent)
+            .filter_map(Segment::sent_at)
+            .min()
+            .map(|sent_at| sent_at + retransmission_timeout)
+    }
+}
+
+impl Index<&ByteRange> for Window {
+    type Output = Segment;
+
+    fn index(&self, seg_byte_range: &ByteRange) -> &Self::Output {
+        let seg_index = seg_byte_range.start / Segment::SIZE;
+        &self.queue[seg_index - self.read_seg_count]
+    }
+}
+
+impl IndexMut<&ByteRange> for Window {
+    fn index_mut(&mut self, seg_byte_range: &ByteRange) -> &mut Self::Output {
+        let seg_index = seg_byte_range.start / Segment::SIZE;
+        &mut self.queue[seg_index - self.read_seg_count]
+    }
+}
+
+impl IntoIterator for Window {
+    type Item = Segment;
+    type IntoIter = <VecDeque<Segment> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.queue.into_iter()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Window;
+    use super::*;
+    use std::io::Write;
     use crate::downloader::SegmentByteRangeIter;
 
+    #[test]
+    fn test_due_segments_oldest_first() {
+        const RTO: Duration = Duration::from_millis(100);
+        let mut seg_iter = SegmentByteRangeIter::new(5 * Segment::SIZE, Segment::SIZE);
+        let mut window = Window::new(&mut seg_iter);
+        let start = Instant::now();
+        let starts = |due: Vec<&mut Segment>| due.iter().map(|segment| segment.byte_range().start / Segment::SIZE).collect::<Vec<_>>();
+
+        for segment in window.due_segments(start, RTO).into_iter().take(3) {
+            segment.mark_sent(start);
+        }
+        /* segments 0, 1 and 2 are in flight */
+        assert_eq!(starts(window.due_segments(start + RTO / 2, RTO)), [3, 4]);
+        window[&(2 * Segment::SIZE..3 * Segment::SIZE)].mark_sent(start + RTO / 2);
+        window[&(0..Segment::SIZE)].write_all(&[0; Segment::SIZE]).unwrap();
+        assert_eq!(window.next_retransmission(RTO), Some(start + RTO));
+        /* segment 1 blocks the slide and was waiting the longest */
+        assert_eq!(starts(window.due_segments(start + RTO, RTO)), [1, 3, 4]);
+        assert_eq!(starts(window.due_segments(start + 2 * RTO, RTO)), [1, 2, 3, 4]);
+    }
+
     #[test]
     fn test_contains_edge_1() {
         let mut seg_iter = SegmentByteRangeIter::new(2000000, 500);
@@ -100,28 +181,3 @@ mod tests {
         assert!(!window.contains(dbg!(&seg_iter_copy.nth(1000).unwrap())));
     }
 }
-
-impl Index<&ByteRange> for Window {
-    type Output = Segment;
-
-    fn index(&self, seg_byte_range: &ByteRange) -> &Self::Output {
-        let seg_index = seg_byte_range.start / Segment::SIZE;
-        &self.queue[seg_index - self.read_seg_count]
-    }
-}
-
-impl IndexMut<&ByteRange> for Window {
-    fn index_mut(&mut self, seg_byte_range: &ByteRange) -> &mut Self::Output {
-        let seg_index = seg_byte_range.start / Segment::SIZE;
-        &mut self.queue[seg_index - self.read_seg_count]
-    }
-}
-
-impl IntoIterator for Window {
-    type Item = Segment;
-    type IntoIter = <VecDeque<Segment> as IntoIterator>::IntoIter;
-
-    fn into_iter(self) -> Self::IntoIter {
-        self.queue.into_iter()
-    }
-}